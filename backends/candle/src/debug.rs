@@ -0,0 +1,181 @@
+//! Numerical debug mode, to find the layer where the outputs of a model diverge from a reference
+//! implementation such as PyTorch.
+//! When `TEI_DEBUG_DUMP_DIR` is set, every forward pass writes to
+//! `$TEI_DEBUG_DUMP_DIR/forward-<n>/`:
+//! - `batch.json`: the inputs of the batch, to replay it in the reference implementation
+//! - `stats.jsonl`: the statistics of the embeddings, of the outputs of every layer and of the
+//!   outputs of the model, in the order they were computed
+//! - `tensors.safetensors`: the full tensors, when the batch has at most
+//!   `TEI_DEBUG_DUMP_MAX_TOKENS` tokens (16 by default)
+//!
+//! Padded models dump `(batch, max_length, hidden)` tensors and the flash attention models
+//! `(tokens, hidden)` tensors. Every dump copies the tensor to the host: never enable this mode
+//! in production.
+use candle::{DType, Device, Result, Tensor};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use text_embeddings_backend_core::Batch;
+
+struct DebugConfig {
+    dir: PathBuf,
+    max_tokens: usize,
+}
+
+fn config() -> Option<&'static DebugConfig> {
+    static CONFIG: OnceLock<Option<DebugConfig>> = OnceLock::new();
+    CONFIG
+        .get_or_init(|| {
+            let dir = PathBuf::from(std::env::var_os("TEI_DEBUG_DUMP_DIR")?);
+            let max_tokens = std::env::var("TEI_DEBUG_DUMP_MAX_TOKENS")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(16);
+            tracing::warn!("Dumping the activations of every forward pass to {dir:?}");
+            Some(DebugConfig { dir, max_tokens })
+        })
+        .as_ref()
+}
+
+/// Dump of the forward pass running on this thread
+struct Dump {
+    dir: PathBuf,
+    stats: BufWriter<File>,
+    /// Full tensors, by name prefixed with their index. `None` if the batch is too large
+    tensors: Option<HashMap<String, Tensor>>,
+    index: usize,
+}
+
+impl Dump {
+    fn write(mut self) -> Result<()> {
+        self.stats.flush()?;
+        if let Some(tensors) = self.tensors.filter(|tensors| !tensors.is_empty()) {
+            candle::safetensors::save(&tensors, self.dir.join("tensors.safetensors"))?;
+        }
+        Ok(())
+    }
+}
+
+thread_local! {
+    static DUMP: RefCell<Option<Dump>> = RefCell::new(None);
+}
+
+/// Guard of a dumped forward pass: the dump is written when it is dropped
+pub(crate) struct ForwardDump(());
+
+impl Drop for ForwardDump {
+    fn drop(&mut self) {
+        if let Some(dump) = DUMP.with(|dump| dump.borrow_mut().take()) {
+            let dir = dump.dir.clone();
+            if let Err(err) = dump.write() {
+                tracing::error!("Could not write the activations to {dir:?}: {err}");
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct BatchInputs<'a> {
+    input_ids: &'a [u32],
+    token_type_ids: &'a [u32],
+    position_ids: &'a [u32],
+    cumulative_seq_lengths: &'a [u32],
+}
+
+#[derive(Serialize)]
+struct Stats<'a> {
+    index: usize,
+    name: &'a str,
+    shape: &'a [usize],
+    dtype: &'a str,
+    min: f32,
+    max: f32,
+    mean: f64,
+    std: f64,
+    abs_mean: f64,
+    /// Number of NaN and infinite values, excluded from the other statistics
+    non_finite: usize,
+}
+
+fn to_candle_err(err: serde_json::Error) -> candle::Error {
+    candle::Error::Msg(err.to_string())
+}
+
+/// Start dumping the activations of the forward pass of `batch` on this thread.
+/// Returns `None` if the debug mode is disabled
+pub(crate) fn begin(batch: &Batch) -> Result<Option<ForwardDump>> {
+    let Some(config) = config() else {
+        return Ok(None);
+    };
+    // Replicas run on different threads and share the counter
+    static FORWARDS: AtomicUsize = AtomicUsize::new(0);
+    let forward = FORWARDS.fetch_add(1, Ordering::Relaxed);
+    let dir = config.dir.join(format!("forward-{forward}"));
+    fs::create_dir_all(&dir)?;
+
+    let inputs = BatchInputs {
+        input_ids: &batch.input_ids,
+        token_type_ids: &batch.token_type_ids,
+        position_ids: &batch.position_ids,
+        cumulative_seq_lengths: &batch.cumulative_seq_lengths,
+    };
+    fs::write(
+        dir.join("batch.json"),
+        serde_json::to_vec(&inputs).map_err(to_candle_err)?,
+    )?;
+
+    let dump = Dump {
+        stats: BufWriter::new(File::create(dir.join("stats.jsonl"))?),
+        tensors: (batch.input_ids.len() <= config.max_tokens).then(HashMap::new),
+        dir,
+        index: 0,
+    };
+    DUMP.with(|current| *current.borrow_mut() = Some(dump));
+    Ok(Some(ForwardDump(())))
+}
+
+/// Dump the activations `name` of the forward pass running on this thread, if any
+pub(crate) fn dump(name: &str, tensor: &Tensor) -> Result<()> {
+    if config().is_none() {
+        return Ok(());
+    }
+    DUMP.with(|current| {
+        let mut current = current.borrow_mut();
+        let Some(dump) = current.as_mut() else {
+            return Ok(());
+        };
+
+        let tensor = tensor.to_device(&Device::Cpu)?;
+        let values: Vec<f32> = tensor.to_dtype(DType::F32)?.flatten_all()?.to_vec1()?;
+        let finite: Vec<f32> = values.into_iter().filter(|v| v.is_finite()).collect();
+        let count = finite.len().max(1) as f64;
+        let mean = finite.iter().map(|&v| v as f64).sum::<f64>() / count;
+        let variance = finite.iter().map(|&v| (v as f64 - mean).powi(2)).sum::<f64>() / count;
+
+        let stats = Stats {
+            index: dump.index,
+            name,
+            shape: tensor.dims(),
+            dtype: tensor.dtype().as_str(),
+            min: finite.iter().copied().fold(f32::INFINITY, f32::min),
+            max: finite.iter().copied().fold(f32::NEG_INFINITY, f32::max),
+            mean,
+            std: variance.sqrt(),
+            abs_mean: finite.iter().map(|&v| v.abs() as f64).sum::<f64>() / count,
+            non_finite: tensor.elem_count() - finite.len(),
+        };
+        serde_json::to_writer(&mut dump.stats, &stats).map_err(to_candle_err)?;
+        dump.stats.write_all(b"\n")?;
+
+        if let Some(tensors) = dump.tensors.as_mut() {
+            tensors.insert(format!("{:03}.{name}", dump.index), tensor);
+        }
+        dump.index += 1;
+        Ok(())
+    })
+}
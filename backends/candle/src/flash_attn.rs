@@ -1,5 +1,6 @@
+#[cfg(feature = "cuda")]
 use crate::compute_cap::get_runtime_compute_cap;
-use candle::Tensor;
+use candle::{Device, Tensor};
 
 #[allow(clippy::too_many_arguments, unused)]
 pub(crate) fn flash_attn_varlen(
@@ -13,6 +14,43 @@ pub(crate) fn flash_attn_varlen(
     max_seqlen_k: usize,
     softmax_scale: f32,
     causal: bool,
+) -> Result<Tensor, candle::Error> {
+    match q.device() {
+        Device::Cuda(_) => {
+            #[cfg(feature = "cuda")]
+            return cuda_flash_attn_varlen(
+                q,
+                k,
+                v,
+                alibi_slopes,
+                seqlens_q,
+                seqlens_k,
+                max_seqlen_q,
+                max_seqlen_k,
+                softmax_scale,
+                causal,
+            );
+            #[cfg(not(feature = "cuda"))]
+            candle::bail!("`cuda` feature is not enabled")
+        }
+        Device::Metal(_) => candle::bail!("Use `varlen_attention` on Metal"),
+        Device::Cpu => candle::bail!("Varlen attention is not available on Cpu"),
+    }
+}
+
+#[cfg(feature = "cuda")]
+#[allow(clippy::too_many_arguments, unused)]
+fn cuda_flash_attn_varlen(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    alibi_slopes: Option<&Tensor>,
+    seqlens_q: &Tensor,
+    seqlens_k: &Tensor,
+    max_seqlen_q: usize,
+    max_seqlen_k: usize,
+    softmax_scale: f32,
+    causal: bool,
 ) -> Result<Tensor, candle::Error> {
     let runtime_compute_cap = get_runtime_compute_cap();
 
@@ -80,3 +118,41 @@ pub(crate) fn flash_attn_varlen(
         runtime_compute_cap
     );
 }
+
+/// Attention over the unpadded varlen layout computed one sequence at a time.
+/// No padding or attention mask is ever materialized, which keeps the memory footprint
+/// proportional to the longest sequence of the batch instead of `batch_size * max_length`.
+/// This is not a fused varlen kernel: it launches the kernels of every sequence separately.
+///
+/// `cu_seqlens` is the host copy of the cumulative sequence lengths of the batch so that the
+/// layers never wait on a device to host copy.
+#[cfg(feature = "metal")]
+pub(crate) fn varlen_attention(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    cu_seqlens: &[u32],
+    softmax_scale: f32,
+) -> Result<Tensor, candle::Error> {
+    let attentions = cu_seqlens
+        .windows(2)
+        .map(|w| {
+            let start = w[0] as usize;
+            let len = (w[1] - w[0]) as usize;
+
+            // [len, heads, head_size] => [heads, len, head_size]
+            let q = q.narrow(0, start, len)?.transpose(0, 1)?.contiguous()?;
+            let k = k.narrow(0, start, len)?.transpose(0, 1)?.contiguous()?;
+            let v = v.narrow(0, start, len)?.transpose(0, 1)?.contiguous()?;
+
+            let attention_scores = (q.matmul(&k.t()?)? * softmax_scale as f64)?;
+            let attention_probs = candle_nn::ops::softmax_last_dim(&attention_scores)?;
+            let attention = attention_probs.matmul(&v)?;
+
+            // [heads, len, head_size] => [len, heads, head_size]
+            attention.transpose(0, 1)
+        })
+        .collect::<Result<Vec<_>, candle::Error>>()?;
+
+    Tensor::cat(&attentions, 0)?.contiguous()
+}
@@ -0,0 +1,277 @@
+use candle::{DType, Device, Result, Tensor};
+use safetensors::{Dtype, SafeTensors};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+
+/// AWQ packs the 8 nibbles of an int32 in the [0, 2, 4, 6, 1, 3, 5, 7] column order
+const AWQ_REVERSE_ORDER: [usize; 8] = [0, 4, 1, 5, 2, 6, 3, 7];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QuantMethod {
+    Gptq,
+    Awq,
+}
+
+// https://github.com/huggingface/transformers/blob/main/src/transformers/utils/quantization_config.py
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct QuantizationConfig {
+    pub quant_method: QuantMethod,
+    pub bits: usize,
+    /// `-1` means a single group spanning the whole input dimension
+    pub group_size: i64,
+}
+
+/// Load a GPTQ or AWQ int4 safetensors checkpoint.
+/// Packed linear weights are dequantized to `{prefix}.weight` tensors with the usual
+/// `[out_features, in_features]` layout so the checkpoint can be loaded by any model.
+///
+/// This is load-time dequantization only: the weights are served in `dtype` and use as much
+/// memory as the unquantized model. There are no int4 kernels.
+pub fn load_dequantized(
+    path: &Path,
+    config: &QuantizationConfig,
+    dtype: DType,
+    device: &Device,
+) -> Result<HashMap<String, Tensor>> {
+    if config.bits != 4 {
+        candle::bail!(
+            "{:?} checkpoints with {} bits are not supported",
+            config.quant_method,
+            config.bits
+        );
+    }
+
+    let file = File::open(path)?;
+    let buffer = unsafe { memmap2::MmapOptions::new().map(&file)? };
+    let safetensors = SafeTensors::deserialize(&buffer)?;
+
+    let mut tensors = HashMap::new();
+    for (name, view) in safetensors.tensors() {
+        if let Some(prefix) = name.strip_suffix(".qweight") {
+            let weight = match config.quant_method {
+                QuantMethod::Gptq => dequantize_gptq(&safetensors, prefix, config)?,
+                QuantMethod::Awq => dequantize_awq(&safetensors, prefix, config)?,
+            };
+            tensors.insert(
+                format!("{prefix}.weight"),
+                weight.to_dtype(dtype)?.to_device(device)?,
+            );
+        } else if name.ends_with(".qzeros")
+            || name.ends_with(".scales")
+            || name.ends_with(".g_idx")
+        {
+            // Consumed with the matching `qweight`
+            continue;
+        } else if let Some(tensor) = load_float(&view)? {
+            tensors.insert(name, tensor.to_dtype(dtype)?.to_device(device)?);
+        }
+    }
+    Ok(tensors)
+}
+
+fn dequantize_gptq(
+    safetensors: &SafeTensors,
+    prefix: &str,
+    config: &QuantizationConfig,
+) -> Result<Tensor> {
+    // qweight: [in / 8, out] packed along the input dimension
+    let (qweight, qweight_shape) = load_u32(safetensors, &format!("{prefix}.qweight"))?;
+    let in_features = qweight_shape[0] * 8;
+    let out_features = qweight_shape[1];
+
+    // qzeros: [groups, out / 8] packed along the output dimension. Zeros are stored minus one.
+    let (qzeros, _) = load_u32(safetensors, &format!("{prefix}.qzeros"))?;
+    let scales = load_scales(safetensors, prefix)?;
+
+    let group_index: Vec<usize> = match safetensors.tensor(&format!("{prefix}.g_idx")) {
+        Ok(_) => load_u32(safetensors, &format!("{prefix}.g_idx"))?
+            .0
+            .into_iter()
+            .map(|g| g as usize)
+            .collect(),
+        Err(_) => default_groups(in_features, config.group_size),
+    };
+
+    let mut weight = vec![0.0_f32; in_features * out_features];
+    for (i, &group) in group_index.iter().enumerate() {
+        for j in 0..out_features {
+            let q = nibble(qweight[(i / 8) * out_features + j], i % 8);
+            let zero = nibble(qzeros[group * (out_features / 8) + j / 8], j % 8) + 1;
+            let scale = scales[group * out_features + j];
+            weight[i * out_features + j] = (q as f32 - zero as f32) * scale;
+        }
+    }
+
+    Tensor::from_vec(weight, (in_features, out_features), &Device::Cpu)?
+        .t()?
+        .contiguous()
+}
+
+fn dequantize_awq(
+    safetensors: &SafeTensors,
+    prefix: &str,
+    config: &QuantizationConfig,
+) -> Result<Tensor> {
+    // qweight: [in, out / 8] packed along the output dimension
+    let (qweight, qweight_shape) = load_u32(safetensors, &format!("{prefix}.qweight"))?;
+    let in_features = qweight_shape[0];
+    let packed_out = qweight_shape[1];
+    let out_features = packed_out * 8;
+
+    // qzeros: [groups, out / 8] packed like qweight
+    let (qzeros, _) = load_u32(safetensors, &format!("{prefix}.qzeros"))?;
+    let scales = load_scales(safetensors, prefix)?;
+    let group_index = default_groups(in_features, config.group_size);
+
+    let mut weight = vec![0.0_f32; in_features * out_features];
+    for (i, &group) in group_index.iter().enumerate() {
+        for j in 0..out_features {
+            let shift = AWQ_REVERSE_ORDER[j % 8];
+            let q = nibble(qweight[i * packed_out + j / 8], shift);
+            let zero = nibble(qzeros[group * packed_out + j / 8], shift);
+            let scale = scales[group * out_features + j];
+            weight[i * out_features + j] = (q as f32 - zero as f32) * scale;
+        }
+    }
+
+    Tensor::from_vec(weight, (in_features, out_features), &Device::Cpu)?
+        .t()?
+        .contiguous()
+}
+
+fn nibble(packed: u32, index: usize) -> u32 {
+    (packed >> (4 * index)) & 0xF
+}
+
+fn default_groups(in_features: usize, group_size: i64) -> Vec<usize> {
+    let group_size = if group_size > 0 {
+        group_size as usize
+    } else {
+        in_features
+    };
+    (0..in_features).map(|i| i / group_size).collect()
+}
+
+fn load_u32(safetensors: &SafeTensors, name: &str) -> Result<(Vec<u32>, Vec<usize>)> {
+    let view = safetensors.tensor(name)?;
+    if view.dtype() != Dtype::I32 {
+        candle::bail!("{name} must be int32, got {:?}", view.dtype());
+    }
+    let values = view
+        .data()
+        .chunks_exact(4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect();
+    Ok((values, view.shape().to_vec()))
+}
+
+fn load_scales(safetensors: &SafeTensors, prefix: &str) -> Result<Vec<f32>> {
+    let name = format!("{prefix}.scales");
+    match load_float(&safetensors.tensor(&name)?)? {
+        Some(scales) => scales.to_dtype(DType::F32)?.flatten_all()?.to_vec1(),
+        None => candle::bail!("{name} must be a float tensor"),
+    }
+}
+
+/// Load a floating point tensor on Cpu. Returns `None` for other dtypes.
+fn load_float(view: &safetensors::tensor::TensorView) -> Result<Option<Tensor>> {
+    let dtype = match view.dtype() {
+        Dtype::F16 => DType::F16,
+        Dtype::BF16 => DType::BF16,
+        Dtype::F32 => DType::F32,
+        _ => return Ok(None),
+    };
+    Tensor::from_raw_buffer(view.data(), dtype, view.shape(), &Device::Cpu).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use safetensors::tensor::TensorView;
+
+    fn serialize(tensors: Vec<(&str, Dtype, Vec<usize>, Vec<u8>)>) -> Vec<u8> {
+        let views: Vec<(&str, TensorView)> = tensors
+            .iter()
+            .map(|(name, dtype, shape, data)| {
+                (*name, TensorView::new(*dtype, shape.clone(), data).unwrap())
+            })
+            .collect();
+        safetensors::serialize(views, &None).unwrap()
+    }
+
+    fn i32_bytes(values: &[u32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_le_bytes()).collect()
+    }
+
+    fn f32_bytes(values: &[f32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn test_dequantize_gptq() {
+        let config = QuantizationConfig {
+            quant_method: QuantMethod::Gptq,
+            bits: 4,
+            group_size: -1,
+        };
+        // 8 inputs packed in one int32 per output, nibble i holding the value i.
+        // Zeros are stored minus one: 7 means 8.
+        let scales: Vec<f32> = (1..=8).map(|s| s as f32).collect();
+        let buffer = serialize(vec![
+            ("l.qweight", Dtype::I32, vec![1, 8], i32_bytes(&[0x76543210; 8])),
+            ("l.qzeros", Dtype::I32, vec![1, 1], i32_bytes(&[0x77777777])),
+            ("l.scales", Dtype::F32, vec![1, 8], f32_bytes(&scales)),
+        ]);
+        let safetensors = SafeTensors::deserialize(&buffer).unwrap();
+
+        let weight: Vec<Vec<f32>> = dequantize_gptq(&safetensors, "l", &config)
+            .unwrap()
+            .to_vec2()
+            .unwrap();
+
+        let expected: Vec<Vec<f32>> = (0..8)
+            .map(|o| (0..8).map(|i| (i as f32 - 8.0) * (o + 1) as f32).collect())
+            .collect();
+        assert_eq!(weight, expected);
+    }
+
+    #[test]
+    fn test_dequantize_awq() {
+        let config = QuantizationConfig {
+            quant_method: QuantMethod::Awq,
+            bits: 4,
+            group_size: 1,
+        };
+        // 8 outputs packed in one int32 per input in the [0, 2, 4, 6, 1, 3, 5, 7] order, output o
+        // holding the value o. The second input is shifted by one.
+        let buffer = serialize(vec![
+            (
+                "l.qweight",
+                Dtype::I32,
+                vec![2, 1],
+                i32_bytes(&[0x75316420, 0x86427531]),
+            ),
+            (
+                "l.qzeros",
+                Dtype::I32,
+                vec![2, 1],
+                i32_bytes(&[0x11111111, 0x33333333]),
+            ),
+            ("l.scales", Dtype::F32, vec![2, 8], f32_bytes(&[0.5; 16])),
+        ]);
+        let safetensors = SafeTensors::deserialize(&buffer).unwrap();
+
+        let weight: Vec<Vec<f32>> = dequantize_awq(&safetensors, "l", &config)
+            .unwrap()
+            .to_vec2()
+            .unwrap();
+
+        let expected: Vec<Vec<f32>> = (0..8)
+            .map(|o| vec![(o as f32 - 1.0) * 0.5, (o as f32 + 1.0 - 3.0) * 0.5])
+            .collect();
+        assert_eq!(weight, expected);
+    }
+}
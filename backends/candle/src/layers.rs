@@ -1,8 +1,12 @@
 #[allow(dead_code, unused)]
 mod cublaslt;
+mod dense;
+mod float8;
+mod fused;
 mod layer_norm;
 mod linear;
 
 pub use cublaslt::get_cublas_lt_wrapper;
+pub use dense::Dense;
 pub use layer_norm::LayerNorm;
-pub use linear::{HiddenAct, Linear};
+pub use linear::{HiddenAct, Linear, Quantization};
@@ -1,34 +1,41 @@
 use crate::layers::HiddenAct;
-use candle::{Device, Result, Tensor};
-use std::sync::Once;
+use candle::{Device, DeviceLocation, Result, Tensor};
+use std::sync::OnceLock;
 
 #[cfg(feature = "cuda")]
 use candle_cublaslt::{fused_batch_matmul, fused_matmul, Activation, CublasLt};
 
-static INIT: Once = Once::new();
-static mut CUBLASLT: Option<CublasLtWrapper> = None;
+/// Maximum number of GPUs with a cuBLASLt handle
+const MAX_DEVICES: usize = 16;
 
-pub fn get_cublas_lt_wrapper() -> Option<&'static CublasLtWrapper> {
-    unsafe {
-        INIT.call_once(|| {
-            CUBLASLT = match Device::cuda_if_available(0) {
-                Ok(device) => {
-                    #[cfg(feature = "cuda")]
-                    {
-                        Some(CublasLtWrapper {
-                            cublaslt: CublasLt::new(&device).unwrap(),
-                        })
-                    }
-                    #[cfg(not(feature = "cuda"))]
-                    {
-                        None
-                    }
+#[allow(clippy::declare_interior_mutable_const)]
+const UNINIT: OnceLock<Option<CublasLtWrapper>> = OnceLock::new();
+static CUBLASLT: [OnceLock<Option<CublasLtWrapper>>; MAX_DEVICES] = [UNINIT; MAX_DEVICES];
+
+/// Get the cuBLASLt handle of the GPU `device` lives on
+pub fn get_cublas_lt_wrapper(device: &Device) -> Option<&'static CublasLtWrapper> {
+    let gpu_id = match device.location() {
+        DeviceLocation::Cuda { gpu_id } if gpu_id < MAX_DEVICES => gpu_id,
+        _ => return None,
+    };
+
+    CUBLASLT[gpu_id]
+        .get_or_init(|| match Device::cuda_if_available(gpu_id) {
+            Ok(device) => {
+                #[cfg(feature = "cuda")]
+                {
+                    Some(CublasLtWrapper {
+                        cublaslt: CublasLt::new(&device).unwrap(),
+                    })
                 }
-                Err(_) => None,
-            };
-        });
-        CUBLASLT.as_ref()
-    }
+                #[cfg(not(feature = "cuda"))]
+                {
+                    None
+                }
+            }
+            Err(_) => None,
+        })
+        .as_ref()
 }
 
 #[derive(Debug, Clone)]
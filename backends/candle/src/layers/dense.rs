@@ -0,0 +1,86 @@
+use crate::layers::Linear;
+use candle::{DType, Device, Result, Tensor};
+use candle_nn::VarBuilder;
+use serde::Deserialize;
+use std::path::Path;
+
+#[derive(Debug, Deserialize)]
+struct DenseConfig {
+    in_features: usize,
+    out_features: usize,
+    #[serde(default = "default_bias")]
+    bias: bool,
+    /// Qualified name of the torch activation, e.g. `torch.nn.modules.activation.Tanh`
+    #[serde(default = "default_activation_function")]
+    activation_function: String,
+}
+
+fn default_bias() -> bool {
+    true
+}
+
+fn default_activation_function() -> String {
+    "torch.nn.modules.activation.Tanh".to_string()
+}
+
+/// sentence-transformers `Dense` module projecting the pooled embeddings
+#[derive(Debug)]
+pub struct Dense {
+    linear: Linear,
+    tanh: bool,
+    span: tracing::Span,
+}
+
+impl Dense {
+    /// Load the `2_Dense` module of the model stored in `model_path`.
+    /// Returns `None` if the model does not have one.
+    pub fn load(model_path: &Path, dtype: DType, device: &Device) -> Result<Option<Self>> {
+        let dense_path = model_path.join("2_Dense");
+        let config_path = dense_path.join("config.json");
+        if !config_path.exists() {
+            return Ok(None);
+        }
+
+        let config = std::fs::read_to_string(config_path)?;
+        let config: DenseConfig = serde_json::from_str(&config).map_err(candle::Error::wrap)?;
+
+        let tanh = match config.activation_function.rsplit('.').next() {
+            Some("Tanh") => true,
+            Some("Identity") => false,
+            _ => candle::bail!(
+                "`2_Dense` activation {} is not supported",
+                config.activation_function
+            ),
+        };
+
+        let safetensors_path = dense_path.join("model.safetensors");
+        let vb = if safetensors_path.exists() {
+            unsafe { VarBuilder::from_mmaped_safetensors(&[safetensors_path], dtype, device)? }
+        } else {
+            VarBuilder::from_pth(dense_path.join("pytorch_model.bin"), dtype, device)?
+        };
+        let vb = vb.pp("linear");
+
+        let weight = vb.get((config.out_features, config.in_features), "weight")?;
+        let bias = match config.bias {
+            true => Some(vb.get(config.out_features, "bias")?),
+            false => None,
+        };
+
+        Ok(Some(Self {
+            linear: Linear::new(weight, bias, None),
+            tanh,
+            span: tracing::span!(tracing::Level::TRACE, "dense"),
+        }))
+    }
+
+    pub fn forward(&self, pooled_embeddings: &Tensor) -> Result<Tensor> {
+        let _enter = self.span.enter();
+
+        let projected = self.linear.forward(pooled_embeddings)?;
+        match self.tanh {
+            true => projected.tanh(),
+            false => Ok(projected),
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use candle::{DType, Result, Tensor};
+
+/// Largest finite value of the e4m3fn format
+const E4M3_MAX: f32 = 448.0;
+
+/// Decode an e4m3fn byte. The NaN codes are decoded as 0.
+fn e4m3_to_f32(code: u8) -> f32 {
+    if code & 0x7F == 0x7F {
+        return 0.0;
+    }
+
+    let exponent = ((code >> 3) & 0x0F) as i32;
+    let mantissa = (code & 0x07) as f32 / 8.0;
+    let magnitude = if exponent == 0 {
+        // Subnormal
+        mantissa * 2f32.powi(-6)
+    } else {
+        (1.0 + mantissa) * 2f32.powi(exponent - 7)
+    };
+
+    if code & 0x80 != 0 {
+        -magnitude
+    } else {
+        magnitude
+    }
+}
+
+/// Round `x` to the nearest e4m3fn value. `positive` holds the decoded values of the
+/// positive codes in increasing order.
+fn f32_to_e4m3(x: f32, positive: &[f32]) -> u8 {
+    let magnitude = x.abs().min(E4M3_MAX);
+
+    let i = positive.partition_point(|&v| v < magnitude);
+    let code = if i == 0 {
+        0
+    } else if i == positive.len() || magnitude - positive[i - 1] <= positive[i] - magnitude {
+        i - 1
+    } else {
+        i
+    };
+
+    let sign = if x.is_sign_negative() && code != 0 {
+        0x80
+    } else {
+        0
+    };
+    code as u8 | sign
+}
+
+/// FP8 (e4m3) weight with a per-tensor scale.
+/// The weight is dequantized to the activation dtype on each forward.
+#[derive(Debug)]
+pub(crate) struct Float8Weight {
+    /// e4m3 codes with the shape of the original weight
+    codes: Tensor,
+    /// Decoded value of every code in the activation dtype
+    lut: Tensor,
+    scale: f64,
+}
+
+impl Float8Weight {
+    pub fn quantize(weight: &Tensor) -> Result<Self> {
+        let values = weight
+            .to_dtype(DType::F32)?
+            .flatten_all()?
+            .to_vec1::<f32>()?;
+
+        let amax = values.iter().fold(0.0_f32, |amax, v| amax.max(v.abs()));
+        let scale = if amax > 0.0 { amax / E4M3_MAX } else { 1.0 };
+
+        // Codes 0x00..=0x7E are the positive finite values in increasing order
+        let positive: Vec<f32> = (0..0x7F).map(e4m3_to_f32).collect();
+        let codes: Vec<u8> = values
+            .iter()
+            .map(|v| f32_to_e4m3(v / scale, &positive))
+            .collect();
+
+        let lut: Vec<f32> = (0..=u8::MAX).map(e4m3_to_f32).collect();
+
+        Ok(Self {
+            codes: Tensor::from_vec(codes, weight.dims(), weight.device())?,
+            lut: Tensor::from_vec(lut, 256, weight.device())?.to_dtype(weight.dtype())?,
+            scale: scale as f64,
+        })
+    }
+
+    pub fn dequantize(&self) -> Result<Tensor> {
+        let weight = self
+            .lut
+            .index_select(&self.codes.flatten_all()?, 0)?
+            .reshape(self.codes.dims())?;
+        weight * self.scale
+    }
+}
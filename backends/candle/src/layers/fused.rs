@@ -0,0 +1,146 @@
+/// Fused Cpu kernels for the elementwise sequences of the transformer layers.
+/// On Cuda, the same sequences are already fused by the cuBLASLt epilogues and
+/// `candle-layer-norm`.
+use crate::layers::HiddenAct;
+use candle::{CpuStorage, CustomOp2, DType, Device, Layout, Result, Shape, Tensor};
+use std::f32::consts::PI;
+
+/// The fused kernels only support f32 tensors on Cpu
+pub fn supports(x: &Tensor) -> bool {
+    matches!(x.device(), Device::Cpu) && x.dtype() == DType::F32
+}
+
+/// `act(x + bias)` in a single pass over `x`
+pub fn bias_act(x: &Tensor, bias: &Tensor, act: Option<&HiddenAct>) -> Result<Tensor> {
+    x.contiguous()?
+        .apply_op2_no_bwd(&bias.contiguous()?, &BiasAct { act: act.cloned() })
+}
+
+/// `layer_norm(hidden_states + residual)` in a single pass over the inputs
+pub fn add_layer_norm(
+    hidden_states: &Tensor,
+    residual: &Tensor,
+    weight: &Tensor,
+    bias: &Tensor,
+    epsilon: f32,
+) -> Result<Tensor> {
+    hidden_states.contiguous()?.apply_op2_no_bwd(
+        &residual.contiguous()?,
+        &AddLayerNorm {
+            weight: weight.to_dtype(DType::F32)?.to_vec1()?,
+            bias: bias.to_dtype(DType::F32)?.to_vec1()?,
+            epsilon,
+        },
+    )
+}
+
+fn as_slice<'a>(storage: &'a CpuStorage, layout: &Layout, op: &str) -> Result<&'a [f32]> {
+    let CpuStorage::F32(data) = storage else {
+        candle::bail!("{op} only supports f32 tensors")
+    };
+    match layout.contiguous_offsets() {
+        Some((start, end)) => Ok(&data[start..end]),
+        None => candle::bail!("{op} only supports contiguous tensors"),
+    }
+}
+
+fn last_dim(layout: &Layout) -> usize {
+    layout.shape().dims().last().copied().unwrap_or(1)
+}
+
+struct BiasAct {
+    act: Option<HiddenAct>,
+}
+
+impl CustomOp2 for BiasAct {
+    fn name(&self) -> &'static str {
+        "bias-act"
+    }
+
+    fn cpu_fwd(
+        &self,
+        s1: &CpuStorage,
+        l1: &Layout,
+        s2: &CpuStorage,
+        l2: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        let x = as_slice(s1, l1, self.name())?;
+        let bias = as_slice(s2, l2, self.name())?;
+        if bias.len() != last_dim(l1) {
+            candle::bail!(
+                "bias of size {} does not match the input shape {:?}",
+                bias.len(),
+                l1.shape()
+            );
+        }
+
+        let mut output = Vec::with_capacity(x.len());
+        for row in x.chunks_exact(bias.len()) {
+            output.extend(row.iter().zip(bias).map(|(v, b)| {
+                let v = v + b;
+                match self.act {
+                    Some(HiddenAct::Gelu) => gelu(v),
+                    Some(HiddenAct::Relu) => v.max(0.0),
+                    None => v,
+                }
+            }));
+        }
+        Ok((CpuStorage::F32(output), l1.shape().clone()))
+    }
+}
+
+/// Same tanh approximation as `Tensor::gelu`
+fn gelu(v: f32) -> f32 {
+    0.5 * v * (1.0 + f32::tanh((2.0f32 / PI).sqrt() * v * (1.0 + 0.044715 * v * v)))
+}
+
+struct AddLayerNorm {
+    weight: Vec<f32>,
+    bias: Vec<f32>,
+    epsilon: f32,
+}
+
+impl CustomOp2 for AddLayerNorm {
+    fn name(&self) -> &'static str {
+        "add-layer-norm"
+    }
+
+    fn cpu_fwd(
+        &self,
+        s1: &CpuStorage,
+        l1: &Layout,
+        s2: &CpuStorage,
+        l2: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        let hidden_states = as_slice(s1, l1, self.name())?;
+        let residual = as_slice(s2, l2, self.name())?;
+        let hidden_size = self.weight.len();
+        if l1.shape() != l2.shape() || last_dim(l1) != hidden_size {
+            candle::bail!(
+                "shapes {:?} and {:?} do not match the hidden size {hidden_size}",
+                l1.shape(),
+                l2.shape()
+            );
+        }
+
+        let mut output = Vec::with_capacity(hidden_states.len());
+        for (row, residual_row) in hidden_states
+            .chunks_exact(hidden_size)
+            .zip(residual.chunks_exact(hidden_size))
+        {
+            let start = output.len();
+            output.extend(row.iter().zip(residual_row).map(|(h, r)| h + r));
+            let row = &mut output[start..];
+
+            let mean = row.iter().sum::<f32>() / hidden_size as f32;
+            let variance = row.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>()
+                / hidden_size as f32;
+            let scale = 1.0 / (variance + self.epsilon).sqrt();
+
+            for ((v, w), b) in row.iter_mut().zip(&self.weight).zip(&self.bias) {
+                *v = (*v - mean) * scale * w + b;
+            }
+        }
+        Ok((CpuStorage::F32(output), l1.shape().clone()))
+    }
+}
@@ -1,3 +1,4 @@
+use crate::layers::fused;
 use candle::{DType, Device, Result, Tensor, D};
 use candle_nn::VarBuilder;
 
@@ -23,10 +24,28 @@ impl LayerNorm {
         })
     }
 
+    pub fn new(weight: Tensor, bias: Tensor, epsilon: f32) -> Self {
+        Self {
+            weight,
+            bias,
+            epsilon,
+            span: tracing::span!(tracing::Level::TRACE, "layer-norm"),
+        }
+    }
+
     pub fn forward(&self, hidden_states: &Tensor, residual: &Tensor) -> Result<Tensor> {
         let _enter = self.span.enter();
 
         match hidden_states.device() {
+            Device::Cpu if fused::supports(hidden_states) && residual.dtype() == DType::F32 => {
+                fused::add_layer_norm(
+                    hidden_states,
+                    residual,
+                    &self.weight,
+                    &self.bias,
+                    self.epsilon,
+                )
+            }
             Device::Cpu | Device::Metal(_) => {
                 let hidden_states = hidden_states.add(residual)?;
                 let hidden_states_dtype = hidden_states.dtype();
@@ -1,17 +1,38 @@
 use crate::layers::cublaslt::get_cublas_lt_wrapper;
-use candle::{Device, Result, Tensor};
+use crate::layers::float8::Float8Weight;
+use crate::layers::fused;
+use candle::quantized::k_quants::BlockQ8_0;
+use candle::quantized::{QMatMul, QTensor};
+use candle::{DType, Device, Module, Result, Tensor};
 use serde::Deserialize;
 
 #[derive(Debug, Deserialize, PartialEq, Clone)]
 #[serde(rename_all = "lowercase")]
 pub enum HiddenAct {
+    #[serde(alias = "gelu_pytorch_tanh", alias = "gelu_new")]
     Gelu,
     Relu,
 }
 
+/// Quantization applied to the linear layer weights after loading
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Quantization {
+    /// int8 weights with dynamically quantized activations. Only available on Cpu.
+    Int8,
+    /// FP8 (e4m3) weights with a per-tensor scale. Only available on Cuda.
+    Float8,
+}
+
+#[derive(Debug)]
+enum LinearWeight {
+    Dense(Tensor),
+    Quantized(QMatMul),
+    Float8(Float8Weight),
+}
+
 #[derive(Debug)]
 pub struct Linear {
-    weight: Tensor,
+    weight: LinearWeight,
     bias: Option<Tensor>,
     act: Option<HiddenAct>,
     span: tracing::Span,
@@ -22,21 +43,74 @@ impl Linear {
         let span = tracing::span!(tracing::Level::TRACE, "linear");
 
         Self {
-            weight,
+            weight: LinearWeight::Dense(weight),
+            bias,
+            act,
+            span,
+        }
+    }
+
+    /// Linear layer backed by a quantized weight. Only available on Cpu.
+    pub fn new_quantized(weight: QMatMul, bias: Option<Tensor>, act: Option<HiddenAct>) -> Self {
+        let span = tracing::span!(tracing::Level::TRACE, "qlinear");
+
+        Self {
+            weight: LinearWeight::Quantized(weight),
             bias,
             act,
             span,
         }
     }
 
+    pub fn quantize(&mut self, quantization: Quantization) -> Result<()> {
+        let weight = match &self.weight {
+            LinearWeight::Dense(weight) => weight,
+            _ => candle::bail!("Linear layer is already quantized"),
+        };
+
+        match quantization {
+            // Activations are quantized on the fly by the quantized matmul kernel
+            Quantization::Int8 => {
+                if !matches!(weight.device(), Device::Cpu) {
+                    candle::bail!("int8 quantization is only available on Cpu");
+                }
+                let qtensor = QTensor::quantize::<BlockQ8_0>(&weight.to_dtype(DType::F32)?)?;
+                self.weight = LinearWeight::Quantized(QMatMul::from_qtensor(qtensor)?);
+                self.span = tracing::span!(tracing::Level::TRACE, "qlinear");
+            }
+            Quantization::Float8 => {
+                if !matches!(weight.device(), Device::Cuda(_)) {
+                    candle::bail!("float8 quantization is only available on Cuda");
+                }
+                self.weight = LinearWeight::Float8(Float8Weight::quantize(weight)?);
+                self.span = tracing::span!(tracing::Level::TRACE, "f8linear");
+            }
+        }
+        Ok(())
+    }
+
     pub fn forward(&self, x: &Tensor) -> Result<Tensor> {
         let _enter = self.span.enter();
 
+        let dequantized;
+        let weight = match &self.weight {
+            LinearWeight::Dense(weight) => weight,
+            LinearWeight::Quantized(weight) => {
+                return self.bias_act(weight.forward(x)?);
+            }
+            LinearWeight::Float8(weight) => {
+                dequantized = weight.dequantize()?;
+                &dequantized
+            }
+        };
+
         #[allow(unused)]
-        if let (Device::Cuda(_), Some(cublaslt)) = (x.device(), get_cublas_lt_wrapper()) {
+        if let (Device::Cuda(_), Some(cublaslt)) =
+            (x.device(), get_cublas_lt_wrapper(x.device()))
+        {
             match x.dims() {
                 &[bsize, _, _] => cublaslt.batch_matmul(
-                    &self.weight.broadcast_left(bsize)?,
+                    &weight.broadcast_left(bsize)?,
                     x,
                     None,
                     None,
@@ -45,7 +119,7 @@ impl Linear {
                     self.act.clone(),
                 ),
                 _ => cublaslt.matmul(
-                    &self.weight,
+                    weight,
                     x,
                     None,
                     None,
@@ -56,22 +130,31 @@ impl Linear {
             }
         } else {
             let w = match x.dims() {
-                &[bsize, _, _] => self.weight.broadcast_left(bsize)?.t()?,
-                _ => self.weight.t()?,
+                &[bsize, _, _] => weight.broadcast_left(bsize)?.t()?,
+                _ => weight.t()?,
             };
-            let x = x.matmul(&w)?;
-            let x = match &self.bias {
-                None => Ok(x),
-                Some(bias) => x.broadcast_add(bias),
-            }?;
-            if let Some(act) = &self.act {
-                match act {
-                    HiddenAct::Gelu => x.gelu(),
-                    HiddenAct::Relu => x.relu(),
-                }
-            } else {
-                Ok(x)
+            self.bias_act(x.matmul(&w)?)
+        }
+    }
+
+    fn bias_act(&self, x: Tensor) -> Result<Tensor> {
+        if let Some(bias) = &self.bias {
+            if fused::supports(&x) && bias.dtype() == DType::F32 {
+                return fused::bias_act(&x, bias, self.act.as_ref());
             }
         }
+
+        let x = match &self.bias {
+            None => Ok(x),
+            Some(bias) => x.broadcast_add(bias),
+        }?;
+        if let Some(act) = &self.act {
+            match act {
+                HiddenAct::Gelu => x.gelu(),
+                HiddenAct::Relu => x.relu(),
+            }
+        } else {
+            Ok(x)
+        }
     }
 }
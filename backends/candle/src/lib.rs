@@ -1,32 +1,88 @@
 mod alibi;
 #[cfg(feature = "cuda")]
 mod compute_cap;
-#[cfg(feature = "cuda")]
+mod debug;
+#[cfg(any(feature = "cuda", feature = "metal"))]
 mod flash_attn;
+mod int4;
 mod layers;
+mod lora;
 mod models;
+mod strict;
 
 #[cfg(feature = "cuda")]
 use crate::compute_cap::{
     get_compile_compute_cap, get_runtime_compute_cap, incompatible_compute_cap,
 };
-#[cfg(feature = "cuda")]
+use crate::int4::load_dequantized;
+use crate::layers::{Dense, Quantization};
+use crate::lora::merge_lora;
+#[cfg(any(feature = "cuda", feature = "metal"))]
 use crate::models::FlashBertModel;
 #[cfg(feature = "cuda")]
 use crate::models::FlashJinaBertModel;
-use crate::models::{BertModel, JinaBertModel, Model, PositionEmbeddingType};
-use candle::{DType, Device};
+use crate::models::{
+    BertModel, JinaBertModel, Model, PositionEmbeddingType, QuantizedBertModel,
+};
+use crate::strict::TensorTracker;
+use candle::pickle::PthTensors;
+use candle::safetensors::MmapedSafetensors;
+use candle::{DType, Device, Tensor};
+use candle_nn::var_builder::SimpleBackend;
 use candle_nn::VarBuilder;
 use models::Config;
 use nohash_hasher::BuildNoHashHasher;
+use serde::Deserialize;
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 use text_embeddings_backend_core::{
-    Backend, BackendError, Batch, Embedding, Embeddings, ModelType, Predictions,
+    pack_bits, pooled_embedding, Backend, BackendError, Batch, Embedding, EmbeddingQuantization,
+    Embeddings, ModelType, Pool, Predictions, QuantizedEmbedding,
 };
 
+/// Number of visible CUDA devices
+pub fn cuda_device_count() -> usize {
+    #[cfg(feature = "cuda")]
+    {
+        use candle::cuda_backend::cudarc::driver::CudaDevice;
+        CudaDevice::count().map(|count| count as usize).unwrap_or(0)
+    }
+    #[cfg(not(feature = "cuda"))]
+    0
+}
+
+/// DType selected by `--dtype auto` and the reason for the choice
+fn auto_dtype(device: &Device) -> (&'static str, String) {
+    match device {
+        Device::Cpu => ("float32", "half precision matmuls are slow on Cpu".to_string()),
+        Device::Metal(_) => ("float16", "Metal supports float16".to_string()),
+        Device::Cuda(_) => {
+            #[cfg(feature = "cuda")]
+            {
+                let compute_cap = get_runtime_compute_cap();
+                if compute_cap >= 80 {
+                    return (
+                        "bfloat16",
+                        format!("compute capability {compute_cap} supports bfloat16"),
+                    );
+                }
+                (
+                    "float16",
+                    format!("compute capability {compute_cap} does not support bfloat16"),
+                )
+            }
+            #[cfg(not(feature = "cuda"))]
+            ("float16", "Cuda device".to_string())
+        }
+    }
+}
+
 pub struct CandleBackend {
     model: Box<dyn Model + Send>,
+    /// sentence-transformers projection applied on the pooled embeddings
+    dense: Option<Dense>,
+    classifier: bool,
 }
 
 impl CandleBackend {
@@ -34,28 +90,63 @@ impl CandleBackend {
         model_path: PathBuf,
         dtype: String,
         model_type: ModelType,
+    ) -> Result<Self, BackendError> {
+        Self::new_on_device(model_path, dtype, model_type, 0, None, false, None)
+    }
+
+    /// Load the model on the GPU with index `device_id` if one is available.
+    /// The weights of `lora_adapter` are merged into the model weights at load time.
+    /// With `strict`, loading fails unless the tensor names of the checkpoint match the model.
+    /// `max_position_embeddings` extends the positions supported by the model beyond its
+    /// configuration.
+    pub fn new_on_device(
+        model_path: PathBuf,
+        dtype: String,
+        model_type: ModelType,
+        device_id: usize,
+        lora_adapter: Option<&Path>,
+        strict: bool,
+        max_position_embeddings: Option<usize>,
     ) -> Result<Self, BackendError> {
         // Load config
         let config: String = std::fs::read_to_string(model_path.join("config.json"))
             .map_err(|err| BackendError::Start(err.to_string()))?;
-        let config: Config =
+        let mut config: Config =
             serde_json::from_str(&config).map_err(|err| BackendError::Start(err.to_string()))?;
 
+        // ALiBi biases only depend on the distance between tokens and extrapolate to longer
+        // sequences. Absolute position embeddings have a row per trained position
+        if let Some(max_position_embeddings) = max_position_embeddings {
+            if max_position_embeddings > config.max_position_embeddings {
+                if config.position_embedding_type != PositionEmbeddingType::Alibi {
+                    return Err(BackendError::Start(format!(
+                        "{:?} position embeddings cannot be extended beyond \
+                         `max_position_embeddings={}`",
+                        config.position_embedding_type, config.max_position_embeddings
+                    )));
+                }
+                config.max_position_embeddings = max_position_embeddings;
+            }
+        }
+
         // Get candle device
         let device = if candle::utils::cuda_is_available() {
-            Device::new_cuda(0)
+            Device::new_cuda(device_id)
         } else if candle::utils::metal_is_available() {
-            Device::new_metal(0)
+            Device::new_metal(device_id)
         } else {
             Ok(Device::Cpu)
         }
         .map_err(|err| BackendError::Start(err.to_string()))?;
 
+        let classifier = model_type == ModelType::Classifier;
+
         // Check model type
         if config.model_type != Some("bert".to_string())
             && config.model_type != Some("xlm-roberta".to_string())
             && config.model_type != Some("camembert".to_string())
             && config.model_type != Some("roberta".to_string())
+            && config.model_type != Some("starencoder".to_string())
         {
             return Err(BackendError::Start(format!(
                 "Model {:?} is not supported",
@@ -63,99 +154,446 @@ impl CandleBackend {
             )));
         }
 
+        // Resolve `auto` from the device capabilities
+        let dtype = if &dtype == "auto" {
+            let (dtype, reason) = auto_dtype(&device);
+            tracing::info!("Selected dtype {dtype}: {reason}");
+            dtype.to_string()
+        } else {
+            dtype
+        };
+
+        // Quantized models are loaded in float32 (int8) or float16 (float8) and their linear
+        // layers are quantized after loading
+        let quantization = match dtype.as_str() {
+            "int8" if !matches!(device, Device::Cpu) => {
+                return Err(BackendError::Start(
+                    "DType int8 is only available on Cpu".to_string(),
+                ))
+            }
+            "float8" if !matches!(device, Device::Cuda(_)) => {
+                return Err(BackendError::Start(
+                    "DType float8 is only available on Cuda".to_string(),
+                ))
+            }
+            "int8" => Some(Quantization::Int8),
+            "float8" => Some(Quantization::Float8),
+            _ => None,
+        };
+
         // Get candle dtype
-        let dtype = if &dtype == "float32" {
+        let dtype = if &dtype == "float32" || &dtype == "int8" {
             Ok(DType::F32)
-        } else if &dtype == "float16" {
+        } else if &dtype == "float16" || &dtype == "float8" {
             Ok(DType::F16)
+        } else if &dtype == "bfloat16" {
+            Ok(DType::BF16)
         } else {
             Err(BackendError::Start(format!(
                 "DType {dtype} is not supported"
             )))
         }?;
 
-        let safetensors_path = model_path.join("model.safetensors");
-        let vb = if safetensors_path.exists() {
-            unsafe {
-                VarBuilder::from_mmaped_safetensors(
-                    &[model_path.join("model.safetensors")],
-                    dtype,
-                    &device,
-                )
+        // GGUF quantized weights are served on Cpu with f32 activations
+        let safetensors = safetensors_files(&model_path)?;
+        let gguf_path = model_path.join("model.gguf");
+        if gguf_path.exists() && safetensors.is_none() {
+            if lora_adapter.is_some() {
+                return Err(BackendError::Start(
+                    "LoRA adapters cannot be merged into GGUF weights".to_string(),
+                ));
             }
-        } else {
-            VarBuilder::from_pth(model_path.join("pytorch_model.bin"), dtype, &device)
+            tracing::info!("Starting QuantizedBert model on Cpu");
+            let dense = load_dense(&model_path, &model_type, DType::F32, &Device::Cpu)?;
+            let model =
+                QuantizedBertModel::load(&gguf_path, &Device::Cpu, &config, model_type).s()?;
+            return Ok(Self {
+                model: Box::new(model),
+                dense,
+                classifier,
+            });
         }
-        .s()?;
 
-        let model: Box<dyn Model + Send> = match device {
-            Device::Cpu | Device::Metal(_) => {
-                if config.position_embedding_type == PositionEmbeddingType::Alibi {
-                    tracing::info!("Starting JinaBert model on {:?}", device);
-                    Box::new(JinaBertModel::load(vb, &config, model_type).s()?)
-                } else {
-                    tracing::info!("Starting Bert model on {:?}", device);
-                    Box::new(BertModel::load(vb, &config, model_type).s()?)
+        let (tensors, names) = if let Some(quantization_config) = &config.quantization_config {
+            let safetensors_path = match safetensors.as_deref() {
+                Some([path]) => path,
+                Some(_) => {
+                    return Err(BackendError::Start(
+                        "Quantized checkpoints must be stored in a single safetensors file"
+                            .to_string(),
+                    ))
                 }
+                None => {
+                    return Err(BackendError::Start(
+                        "Quantized checkpoints must use the safetensors format".to_string(),
+                    ))
+                }
+            };
+            // The int4 weights are dequantized once at load time
+            tracing::info!("Dequantizing {:?} checkpoint", quantization_config.quant_method);
+            let mut tensors =
+                load_dequantized(safetensors_path, quantization_config, dtype, &device).s()?;
+            if let Some(lora_adapter) = lora_adapter {
+                tracing::info!("Merging LoRA adapter {lora_adapter:?}");
+                merge_lora(&mut tensors, lora_adapter).s()?;
             }
-            Device::Cuda(_) => {
-                #[cfg(not(feature = "cuda"))]
+            let names: Vec<String> = tensors.keys().cloned().collect();
+            (Box::new(tensors) as Box<dyn SimpleBackend>, names)
+        } else if let Some(lora_adapter) = lora_adapter {
+            let Some(safetensors) = &safetensors else {
                 return Err(BackendError::Start(
-                    "`cuda` feature is not enabled".to_string(),
+                    "LoRA adapters can only be merged into safetensors checkpoints".to_string(),
                 ));
-                #[cfg(feature = "cuda")]
+            };
+            tracing::info!("Merging LoRA adapter {lora_adapter:?}");
+            let mut tensors = HashMap::new();
+            for path in safetensors {
+                tensors.extend(candle::safetensors::load(path, &device).s()?);
+            }
+            merge_lora(&mut tensors, lora_adapter).s()?;
+            let names = tensors.keys().cloned().collect();
+            (Box::new(tensors), names)
+        } else if let Some(safetensors) = &safetensors {
+            let tensors = unsafe { MmapedSafetensors::multi(safetensors) }.s()?;
+            let names = tensors.tensors().into_iter().map(|(name, _)| name).collect();
+            (Box::new(tensors), names)
+        } else {
+            let tensors = PthTensors::new(model_path.join("pytorch_model.bin")).s()?;
+            let names = tensors.tensor_infos().keys().cloned().collect();
+            (Box::new(tensors), names)
+        };
+
+        // In strict mode, every tensor of the checkpoint must be read by the model
+        let tracker = strict.then(|| TensorTracker::new(names));
+        let tensors = match &tracker {
+            Some(tracker) => tracker.wrap(tensors),
+            None => tensors,
+        };
+        let vb = VarBuilder::from_backend(tensors, dtype, device.clone());
+
+        let dense = load_dense(&model_path, &model_type, dtype, &device)?;
+
+        let model = load_model(vb, &config, model_type, &device, dtype);
+        let mut model = match tracker {
+            Some(tracker) => tracker.check(model)?,
+            None => model?,
+        };
+
+        if let Some(quantization) = quantization {
+            tracing::info!("Quantizing linear layers: {quantization:?}");
+            model.quantize(quantization).s()?;
+        }
+
+        Ok(Self {
+            model,
+            dense,
+            classifier,
+        })
+    }
+}
+
+/// L2 normalize the rows of `pooled_embeddings` listed in `normalized_indices`.
+/// Norms are computed in f32 to not lose precision with half precision models.
+fn normalize(
+    pooled_embeddings: Tensor,
+    pooled_indices: &[u32],
+    normalized_indices: &[u32],
+) -> candle::Result<Tensor> {
+    let pooled_embeddings = pooled_embeddings.to_dtype(DType::F32)?;
+
+    let mask: Vec<f32> = pooled_indices
+        .iter()
+        .map(|i| match normalized_indices.contains(i) {
+            true => 1.0,
+            false => 0.0,
+        })
+        .collect();
+    let mask = Tensor::from_vec(mask, (pooled_indices.len(), 1), pooled_embeddings.device())?;
+
+    // Rows that are not normalized are divided by 1
+    let norms = pooled_embeddings.sqr()?.sum_keepdim(1)?.sqrt()?;
+    let divisor = ((norms - 1.0)?.broadcast_mul(&mask)? + 1.0)?;
+    pooled_embeddings.broadcast_div(&divisor)
+}
+
+/// Device => Host data transfer.
+/// Half precision tensors are transferred as is and converted to f32 on the host, which halves
+/// the number of bytes crossing the PCIe bus.
+fn to_host(tensor: Tensor) -> candle::Result<Vec<Vec<f32>>> {
+    // On accelerators, the copy also waits for the kernels that are still queued
+    let start = Instant::now();
+    let results = tensor.to_device(&Device::Cpu)?.to_dtype(DType::F32)?.to_vec2();
+    metrics::histogram!("te_backend_transfer_duration", start.elapsed().as_secs_f64());
+    results
+}
+
+/// Device => Host transfer of the pooled embeddings, in the order of `pooled_indices`.
+/// On Cuda, the rows listed in `quantized_indices` are quantized on device so that only one byte
+/// per value crosses the PCIe bus. They are quantized on the host on other devices.
+fn pooled_to_host(
+    pooled_embeddings: Tensor,
+    pooled_indices: &[u32],
+    quantized_indices: &[(u32, EmbeddingQuantization)],
+) -> candle::Result<Vec<Embedding>> {
+    let quantization = |i: u32| {
+        quantized_indices
+            .iter()
+            .find(|(q, _)| *q == i)
+            .map(|(_, quantization)| *quantization)
+    };
+
+    let device = pooled_embeddings.device().clone();
+    if quantized_indices.is_empty() || !matches!(device, Device::Cuda(_)) {
+        let embeddings = to_host(pooled_embeddings)?;
+        return Ok(pooled_indices
+            .iter()
+            .zip(embeddings)
+            .map(|(i, e)| pooled_embedding(e, quantization(*i)))
+            .collect());
+    }
+
+    let mut embeddings: Vec<Option<Embedding>> = pooled_indices.iter().map(|_| None).collect();
+    for group in [
+        None,
+        Some(EmbeddingQuantization::Int8),
+        Some(EmbeddingQuantization::Binary),
+    ] {
+        let rows: Vec<u32> = (0..pooled_indices.len() as u32)
+            .filter(|row| quantization(pooled_indices[*row as usize]) == group)
+            .collect();
+        if rows.is_empty() {
+            continue;
+        }
+        let rows_tensor = Tensor::from_vec(rows.clone(), rows.len(), &device)?;
+        let selected = pooled_embeddings.index_select(&rows_tensor, 0)?;
+
+        let group_embeddings = match group {
+            None => to_host(selected)?.into_iter().map(Embedding::Pooled).collect(),
+            Some(EmbeddingQuantization::Int8) => int8_to_host(selected)?,
+            Some(EmbeddingQuantization::Binary) => binary_to_host(selected)?,
+        };
+        for (row, embedding) in rows.into_iter().zip(group_embeddings) {
+            embeddings[row as usize] = Some(embedding);
+        }
+    }
+    Ok(embeddings.into_iter().flatten().collect())
+}
+
+/// Quantize the rows of `embeddings` to int8 on device and transfer them as bytes
+fn int8_to_host(embeddings: Tensor) -> candle::Result<Vec<Embedding>> {
+    let embeddings = embeddings.to_dtype(DType::F32)?;
+    let scales = (embeddings.abs()?.max_keepdim(1)? / 127.0)?;
+    // `v / scale` is in [-127, 127]: shifted by 127.5, the truncating cast to u8 rounds it.
+    // The epsilon keeps the rows that are all zeros at zero.
+    let values = (embeddings.broadcast_div(&(&scales + 1e-12)?)? + 127.5)?.to_dtype(DType::U8)?;
+
+    let start = Instant::now();
+    let values: Vec<Vec<u8>> = values.to_device(&Device::Cpu)?.to_vec2()?;
+    let scales: Vec<Vec<f32>> = scales.to_device(&Device::Cpu)?.to_vec2()?;
+    metrics::histogram!("te_backend_transfer_duration", start.elapsed().as_secs_f64());
+
+    Ok(values
+        .into_iter()
+        .zip(scales)
+        .map(|(values, scale)| {
+            Embedding::Quantized(QuantizedEmbedding::Int8 {
+                values: values.into_iter().map(|v| (v as i16 - 127) as i8).collect(),
+                scale: if scale[0] > 0.0 { scale[0] } else { 1.0 },
+            })
+        })
+        .collect())
+}
+
+/// Compute the sign bits of the rows of `embeddings` on device and transfer them as bytes
+fn binary_to_host(embeddings: Tensor) -> candle::Result<Vec<Embedding>> {
+    let bits = embeddings.gt(&embeddings.zeros_like()?)?;
+
+    let start = Instant::now();
+    let bits: Vec<Vec<u8>> = bits.to_device(&Device::Cpu)?.to_vec2()?;
+    metrics::histogram!("te_backend_transfer_duration", start.elapsed().as_secs_f64());
+
+    Ok(bits
+        .iter()
+        .map(|bits| Embedding::Quantized(QuantizedEmbedding::Binary(pack_bits(bits))))
+        .collect())
+}
+
+/// Select the implementation of the model for `device` and load its weights from `vb`
+#[cfg_attr(not(feature = "cuda"), allow(unused_variables))]
+fn load_model(
+    vb: VarBuilder,
+    config: &Config,
+    model_type: ModelType,
+    device: &Device,
+    dtype: DType,
+) -> Result<Box<dyn Model + Send>, BackendError> {
+    // SPLADE pooling is only implemented by the padded Bert model
+    let splade = model_type == ModelType::Embedding(Pool::Splade);
+
+    let model: Box<dyn Model + Send> = match device {
+        // The varlen path runs attention one sequence at a time and has not been benchmarked
+        // against the padded model on Metal, so it is opt-in
+        Device::Metal(_)
+            if cfg!(feature = "metal")
+                && !splade
+                && config.position_embedding_type == PositionEmbeddingType::Absolute
+                && &std::env::var("USE_FLASH_ATTENTION")
+                    .unwrap_or("False".to_string())
+                    .to_lowercase()
+                    == "true" =>
+        {
+            #[cfg(feature = "metal")]
+            {
+                tracing::info!("Starting FlashBert model on {:?}", device);
+                Box::new(FlashBertModel::load(vb, config, model_type).s()?)
+            }
+            #[cfg(not(feature = "metal"))]
+            unreachable!()
+        }
+        Device::Cpu | Device::Metal(_) => {
+            if config.position_embedding_type == PositionEmbeddingType::Alibi {
+                tracing::info!("Starting JinaBert model on {:?}", device);
+                Box::new(JinaBertModel::load(vb, config, model_type).s()?)
+            } else {
+                tracing::info!("Starting Bert model on {:?}", device);
+                Box::new(BertModel::load(vb, config, model_type).s()?)
+            }
+        }
+        Device::Cuda(_) => {
+            #[cfg(not(feature = "cuda"))]
+            return Err(BackendError::Start(
+                "`cuda` feature is not enabled".to_string(),
+            ));
+            #[cfg(feature = "cuda")]
+            {
+                if incompatible_compute_cap() {
+                    return Err(BackendError::Start(format!("Runtime compute cap {} is not compatible with compile time compute cap {}", get_runtime_compute_cap(), get_compile_compute_cap())));
+                }
+
+                if cfg!(any(feature = "flash-attn", feature = "flash-attn-v1"))
+                    && !splade
+                    && matches!(dtype, DType::F16 | DType::BF16)
+                    && config.position_embedding_type == PositionEmbeddingType::Absolute
+                    // Allow disabling because of flash attention v1 precision problems
+                    // See: https://github.com/huggingface/text-embeddings-inference/issues/37
+                    && &std::env::var("USE_FLASH_ATTENTION").unwrap_or("True".to_string()).to_lowercase() == "true"
+                {
+                    tracing::info!("Starting FlashBert model on Cuda");
+                    Box::new(FlashBertModel::load(vb, config, model_type).s()?)
+                } else if cfg!(feature = "flash-attn")
+                    && matches!(dtype, DType::F16 | DType::BF16)
+                    && config.position_embedding_type == PositionEmbeddingType::Alibi
+                    && &std::env::var("USE_FLASH_ATTENTION")
+                        .unwrap_or("True".to_string())
+                        .to_lowercase()
+                        == "true"
                 {
-                    if incompatible_compute_cap() {
-                        return Err(BackendError::Start(format!("Runtime compute cap {} is not compatible with compile time compute cap {}", get_runtime_compute_cap(), get_compile_compute_cap())));
-                    }
-
-                    if cfg!(any(feature = "flash-attn", feature = "flash-attn-v1"))
-                        && dtype == DType::F16
-                        && config.position_embedding_type == PositionEmbeddingType::Absolute
-                        // Allow disabling because of flash attention v1 precision problems
-                        // See: https://github.com/huggingface/text-embeddings-inference/issues/37
-                        && &std::env::var("USE_FLASH_ATTENTION").unwrap_or("True".to_string()).to_lowercase() == "true"
-                    {
-                        tracing::info!("Starting FlashBert model on Cuda");
-                        Box::new(FlashBertModel::load(vb, &config, model_type).s()?)
-                    } else if cfg!(feature = "flash-attn")
-                        && dtype == DType::F16
-                        && config.position_embedding_type == PositionEmbeddingType::Alibi
-                        && &std::env::var("USE_FLASH_ATTENTION")
-                            .unwrap_or("True".to_string())
-                            .to_lowercase()
-                            == "true"
-                    {
-                        tracing::info!("Starting FlashJinaBertModel model on Cuda");
-                        Box::new(FlashJinaBertModel::load(vb, &config, model_type).s()?)
-                    } else if config.position_embedding_type == PositionEmbeddingType::Alibi {
-                        tracing::info!("Starting JinaBert model on Cuda");
-                        Box::new(JinaBertModel::load(vb, &config, model_type).s()?)
-                    } else {
-                        tracing::info!("Starting Bert model on Cuda");
-                        Box::new(BertModel::load(vb, &config, model_type).s()?)
-                    }
+                    tracing::info!("Starting FlashJinaBertModel model on Cuda");
+                    Box::new(FlashJinaBertModel::load(vb, config, model_type).s()?)
+                } else if config.position_embedding_type == PositionEmbeddingType::Alibi {
+                    tracing::info!("Starting JinaBert model on Cuda");
+                    Box::new(JinaBertModel::load(vb, config, model_type).s()?)
+                } else {
+                    tracing::info!("Starting Bert model on Cuda");
+                    Box::new(BertModel::load(vb, config, model_type).s()?)
                 }
             }
-        };
+        }
+    };
+
+    Ok(model)
+}
+
+/// Index of a checkpoint split in several safetensors files
+#[derive(Deserialize)]
+struct SafetensorsIndex {
+    weight_map: HashMap<String, String>,
+}
+
+/// `model.safetensors` or the shards listed in `model.safetensors.index.json`.
+/// `None` if the checkpoint does not use the safetensors format.
+fn safetensors_files(model_path: &Path) -> Result<Option<Vec<PathBuf>>, BackendError> {
+    let safetensors_path = model_path.join("model.safetensors");
+    if safetensors_path.exists() {
+        return Ok(Some(vec![safetensors_path]));
+    }
+
+    let index_path = model_path.join("model.safetensors.index.json");
+    if !index_path.exists() {
+        return Ok(None);
+    }
+    let index = std::fs::read_to_string(&index_path)
+        .map_err(|err| BackendError::Start(format!("Could not read {index_path:?}: {err}")))?;
+    let index: SafetensorsIndex = serde_json::from_str(&index)
+        .map_err(|err| BackendError::Start(format!("Failed to parse {index_path:?}: {err}")))?;
+
+    let mut shards: Vec<PathBuf> = index
+        .weight_map
+        .into_values()
+        .map(|shard| model_path.join(shard))
+        .collect();
+    shards.sort();
+    shards.dedup();
+    if let Some(missing) = shards.iter().find(|shard| !shard.exists()) {
+        return Err(BackendError::Start(format!(
+            "Shard {missing:?} listed in {index_path:?} not found"
+        )));
+    }
+    tracing::info!("Loading checkpoint from {} safetensors shards", shards.len());
+    Ok(Some(shards))
+}
 
-        Ok(Self { model })
+/// Load the `2_Dense` projection of sentence-transformers embedding models
+fn load_dense(
+    model_path: &Path,
+    model_type: &ModelType,
+    dtype: DType,
+    device: &Device,
+) -> Result<Option<Dense>, BackendError> {
+    if !matches!(model_type, ModelType::Embedding(pool) if *pool != Pool::Splade) {
+        return Ok(None);
     }
+    let dense = Dense::load(model_path, dtype, device).s()?;
+    if dense.is_some() {
+        tracing::info!("Projecting pooled embeddings with `2_Dense`");
+    }
+    Ok(dense)
 }
 
 impl Backend for CandleBackend {
+    /// Run a single token through the model so that a broken device context is detected
     fn health(&self) -> Result<(), BackendError> {
-        Ok(())
+        let batch = Batch {
+            input_ids: vec![0],
+            token_type_ids: vec![0],
+            position_ids: vec![0],
+            cumulative_seq_lengths: vec![0, 1],
+            max_length: 1,
+            pooled_indices: vec![0],
+            raw_indices: vec![],
+            normalized_indices: vec![],
+            quantized_indices: vec![],
+        };
+        match self.classifier {
+            true => self.predict(batch).map(|_| ()),
+            false => self.embed(batch).map(|_| ()),
+        }
     }
 
     fn is_padded(&self) -> bool {
         self.model.is_padded()
     }
 
+    fn has_dense(&self) -> bool {
+        self.dense.is_some()
+    }
+
     fn embed(&self, batch: Batch) -> Result<Embeddings, BackendError> {
         let batch_size = batch.len();
         let pooled_indices = batch.pooled_indices.clone();
         let raw_indices = batch.raw_indices.clone();
+        let normalized_indices = batch.normalized_indices.clone();
+        let quantized_indices = batch.quantized_indices.clone();
 
         // Used for indexing in the raw_embeddings tensor
         let input_lengths: Vec<usize> = (0..batch.len())
@@ -165,32 +603,52 @@ impl Backend for CandleBackend {
             .collect();
 
         // Run forward
+        let _dump = debug::begin(&batch).e()?;
         let (pooled_embeddings, raw_embeddings) = self.model.embed(batch).e()?;
 
+        // The projection is applied on device, before normalization
+        let pooled_embeddings = match (&self.dense, pooled_embeddings) {
+            (Some(dense), Some(pooled_embeddings)) => Some(dense.forward(&pooled_embeddings).e()?),
+            (_, pooled_embeddings) => pooled_embeddings,
+        };
+        if let Some(pooled_embeddings) = &pooled_embeddings {
+            debug::dump("pooled", pooled_embeddings).e()?;
+        }
+
+        // Normalize on device to only transfer the final values
+        let pooled_embeddings = match pooled_embeddings {
+            Some(pooled_embeddings) if !normalized_indices.is_empty() => Some(
+                normalize(pooled_embeddings, &pooled_indices, &normalized_indices).e()?,
+            ),
+            pooled_embeddings => pooled_embeddings,
+        };
+
         // Device => Host data transfer
         let pooled_embeddings = match pooled_embeddings {
             None => vec![],
-            Some(pooled_embeddings) => pooled_embeddings.to_dtype(DType::F32).e()?.to_vec2().e()?,
+            Some(pooled_embeddings) => {
+                pooled_to_host(pooled_embeddings, &pooled_indices, &quantized_indices).e()?
+            }
         };
 
         // This transfer is expensive...
         let raw_embeddings = match raw_embeddings {
             None => vec![],
-            Some(raw_embeddings) => raw_embeddings.to_dtype(DType::F32).e()?.to_vec2().e()?,
+            Some(raw_embeddings) => to_host(raw_embeddings).e()?,
         };
 
         let mut embeddings =
             HashMap::with_capacity_and_hasher(batch_size, BuildNoHashHasher::default());
         for (i, e) in pooled_indices.into_iter().zip(pooled_embeddings) {
-            embeddings.insert(i as usize, Embedding::Pooled(e));
+            embeddings.insert(i as usize, e);
         }
 
-        let mut cumulative_length = 0;
+        // Rows are moved out of the transferred tensor instead of being copied
+        let mut raw_embeddings = raw_embeddings.into_iter();
         for i in raw_indices.into_iter() {
             let length = input_lengths[i as usize];
-            let e = raw_embeddings[cumulative_length..cumulative_length + length].to_vec();
+            let e = raw_embeddings.by_ref().take(length).collect();
             embeddings.insert(i as usize, Embedding::All(e));
-            cumulative_length += length;
         }
 
         Ok(embeddings)
@@ -199,8 +657,10 @@ impl Backend for CandleBackend {
     fn predict(&self, batch: Batch) -> Result<Predictions, BackendError> {
         let batch_size = batch.len();
 
+        let _dump = debug::begin(&batch).e()?;
         let results = self.model.predict(batch).e()?;
-        let results = results.to_dtype(DType::F32).e()?.to_vec2().e()?;
+        debug::dump("scores", &results).e()?;
+        let results = to_host(results).e()?;
 
         let mut predictions =
             HashMap::with_capacity_and_hasher(batch_size, BuildNoHashHasher::default());
@@ -0,0 +1,132 @@
+use candle::{DType, Device, Result, Tensor};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+// https://github.com/huggingface/peft/blob/main/src/peft/tuners/lora/config.py
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+struct LoraConfig {
+    r: usize,
+    lora_alpha: f64,
+    #[serde(default)]
+    use_rslora: bool,
+    /// Set when the base layer stores its weight as `[in_features, out_features]`
+    #[serde(default)]
+    fan_in_fan_out: bool,
+}
+
+/// Merge a PEFT LoRA adapter into the base model weights: `W += scaling * B . A`
+pub fn merge_lora(tensors: &mut HashMap<String, Tensor>, adapter_path: &Path) -> Result<()> {
+    let config = std::fs::read_to_string(adapter_path.join("adapter_config.json"))?;
+    let config: LoraConfig = serde_json::from_str(&config).map_err(candle::Error::wrap)?;
+    let scaling = if config.use_rslora {
+        config.lora_alpha / (config.r as f64).sqrt()
+    } else {
+        config.lora_alpha / config.r as f64
+    };
+
+    let adapter = candle::safetensors::load(
+        adapter_path.join("adapter_model.safetensors"),
+        &Device::Cpu,
+    )?;
+
+    let mut merged = 0;
+    for (name, lora_a) in adapter.iter() {
+        let prefix = match name.strip_suffix(".lora_A.weight") {
+            Some(prefix) => prefix,
+            None => continue,
+        };
+        let lora_b = adapter
+            .get(&format!("{prefix}.lora_B.weight"))
+            .ok_or_else(|| candle::Error::Msg(format!("{prefix}.lora_B.weight not found")))?;
+
+        // lora_B: [out_features, r], lora_A: [r, in_features]
+        let mut delta = lora_b
+            .to_dtype(DType::F32)?
+            .matmul(&lora_a.to_dtype(DType::F32)?)?
+            .affine(scaling, 0.0)?;
+        if config.fan_in_fan_out {
+            delta = delta.t()?;
+        }
+
+        let target = base_weight_name(tensors, prefix)?;
+        let weight = &tensors[&target];
+        let merged_weight = (weight.to_device(&Device::Cpu)?.to_dtype(DType::F32)? + delta)?
+            .to_dtype(weight.dtype())?
+            .to_device(weight.device())?;
+        tensors.insert(target, merged_weight);
+        merged += 1;
+    }
+
+    if merged == 0 {
+        candle::bail!("{adapter_path:?} does not contain LoRA weights");
+    }
+    tracing::info!("Merged {merged} LoRA layers");
+    Ok(())
+}
+
+/// PEFT prefixes the layer names with `base_model.model.` while base checkpoints may or may not
+/// prefix them with the model name (`bert.`, `roberta.`...)
+fn base_weight_name(tensors: &HashMap<String, Tensor>, prefix: &str) -> Result<String> {
+    let name = format!(
+        "{}.weight",
+        prefix.strip_prefix("base_model.model.").unwrap_or(prefix)
+    );
+    if tensors.contains_key(&name) {
+        return Ok(name);
+    }
+    tensors
+        .keys()
+        .find(|k| k.ends_with(&format!(".{name}")) || name.ends_with(&format!(".{k}")))
+        .cloned()
+        .ok_or_else(|| candle::Error::Msg(format!("LoRA target {name} not found in model")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tensors(names: &[&str]) -> HashMap<String, Tensor> {
+        names
+            .iter()
+            .map(|name| {
+                let tensor = Tensor::zeros(1, DType::F32, &Device::Cpu).unwrap();
+                (name.to_string(), tensor)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_base_weight_name() {
+        let prefix = "base_model.model.encoder.layer.0.attention.self.query";
+
+        // Same names as the adapter
+        let exact = tensors(&["encoder.layer.0.attention.self.query.weight"]);
+        assert_eq!(
+            base_weight_name(&exact, prefix).unwrap(),
+            "encoder.layer.0.attention.self.query.weight"
+        );
+
+        // Base checkpoint prefixed with the model name
+        let prefixed = tensors(&[
+            "bert.encoder.layer.0.attention.self.query.weight",
+            "bert.encoder.layer.0.attention.self.key.weight",
+        ]);
+        assert_eq!(
+            base_weight_name(&prefixed, prefix).unwrap(),
+            "bert.encoder.layer.0.attention.self.query.weight"
+        );
+
+        // Adapter prefixed with the model name
+        let unprefixed = tensors(&["encoder.layer.0.attention.self.query.weight"]);
+        let bert_prefix = "base_model.model.bert.encoder.layer.0.attention.self.query";
+        assert_eq!(
+            base_weight_name(&unprefixed, bert_prefix).unwrap(),
+            "encoder.layer.0.attention.self.query.weight"
+        );
+
+        // A layer with a common suffix is not a match
+        let other = tensors(&["encoder.layer.10.attention.self.query.weight"]);
+        assert!(base_weight_name(&other, prefix).is_err());
+    }
+}
@@ -6,19 +6,22 @@ extern crate accelerate_src;
 
 mod bert;
 
-#[cfg(feature = "cuda")]
+#[cfg(any(feature = "cuda", feature = "metal"))]
 mod flash_bert;
 
 #[cfg(feature = "cuda")]
 mod flash_jina;
 mod jina;
+mod quantized_bert;
 
+use crate::layers::Quantization;
 pub use bert::{BertModel, Config, PositionEmbeddingType};
-use candle::{Result, Tensor};
+use candle::{DType, Result, Tensor};
 pub use jina::JinaBertModel;
+pub use quantized_bert::QuantizedBertModel;
 use text_embeddings_backend_core::Batch;
 
-#[cfg(feature = "cuda")]
+#[cfg(any(feature = "cuda", feature = "metal"))]
 pub use flash_bert::FlashBertModel;
 
 #[cfg(feature = "cuda")]
@@ -27,6 +30,10 @@ pub use flash_jina::FlashJinaBertModel;
 pub(crate) trait Model {
     fn is_padded(&self) -> bool;
 
+    fn quantize(&mut self, _quantization: Quantization) -> Result<()> {
+        candle::bail!("quantization is not implemented for this model");
+    }
+
     fn embed(&self, _batch: Batch) -> Result<(Option<Tensor>, Option<Tensor>)> {
         candle::bail!("`embed` is not implemented for this model");
     }
@@ -35,3 +42,31 @@ pub(crate) trait Model {
         candle::bail!("`predict is not implemented for this model");
     }
 }
+
+/// Mean pooling of padded `outputs` of shape `(batch_size, max_length, hidden_size)`,
+/// accumulated in f32. Tokens are weighted by their position if `weighted` is set.
+/// Every sequence is reduced over its own tokens only, so that its pooled embedding does not
+/// depend on the padding, and thus on the other sequences, of the batch.
+pub(crate) fn padded_mean_pool(
+    outputs: &Tensor,
+    cumulative_seq_lengths: &[u32],
+    weighted: bool,
+) -> Result<Tensor> {
+    let pooled = cumulative_seq_lengths
+        .windows(2)
+        .enumerate()
+        .map(|(i, bounds)| {
+            let length = (bounds[1] - bounds[0]) as usize;
+            let embeddings = outputs.get(i)?.narrow(0, 0, length)?.to_dtype(DType::F32)?;
+            if weighted {
+                let weights: Vec<f32> = (1..=length).map(|j| j as f32).collect();
+                let weights = Tensor::from_vec(weights, (length, 1), outputs.device())?;
+                let weights_sum = (length * (length + 1) / 2) as f64;
+                embeddings.broadcast_mul(&weights)?.sum_keepdim(0)? / weights_sum
+            } else {
+                embeddings.sum_keepdim(0)? / length as f64
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Tensor::cat(&pooled, 0)?.to_dtype(outputs.dtype())
+}
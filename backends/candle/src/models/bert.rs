@@ -1,5 +1,7 @@
-use crate::layers::{get_cublas_lt_wrapper, HiddenAct, LayerNorm, Linear};
-use crate::models::Model;
+use crate::debug;
+use crate::int4::QuantizationConfig;
+use crate::layers::{get_cublas_lt_wrapper, HiddenAct, LayerNorm, Linear, Quantization};
+use crate::models::{padded_mean_pool, Model};
 use candle::{DType, Device, IndexOp, Module, Result, Tensor, D};
 use candle_nn::{Embedding, VarBuilder};
 use serde::Deserialize;
@@ -28,6 +30,7 @@ pub struct Config {
     pub classifier_dropout: Option<f64>,
     pub model_type: Option<String>,
     pub id2label: Option<HashMap<String, String>>,
+    pub quantization_config: Option<QuantizationConfig>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Default)]
@@ -118,23 +121,35 @@ impl BertAttention {
         let all_head_size = config.num_attention_heads * attention_head_size;
         let hidden_size = config.hidden_size;
 
-        let query_weight = vb
-            .pp("self.query")
-            .get((all_head_size, hidden_size), "weight")?;
-        let query_bias = vb.pp("self.query").get(all_head_size, "bias")?;
-
-        let key_weight = vb
-            .pp("self.key")
-            .get((all_head_size, hidden_size), "weight")?;
-        let key_bias = vb.pp("self.key").get(all_head_size, "bias")?;
-
-        let value_weight = vb
-            .pp("self.value")
-            .get((all_head_size, hidden_size), "weight")?;
-        let value_bias = vb.pp("self.value").get(all_head_size, "bias")?;
+        let (qkv_weight, qkv_bias) = match (
+            vb.pp("self.query_key_value")
+                .get((3 * all_head_size, hidden_size), "weight"),
+            vb.pp("self.query_key_value").get(3 * all_head_size, "bias"),
+        ) {
+            // StarEncoder checkpoints already store a fused QKV projection
+            (Ok(qkv_weight), Ok(qkv_bias)) => (qkv_weight, qkv_bias),
+            _ => {
+                let query_weight = vb
+                    .pp("self.query")
+                    .get((all_head_size, hidden_size), "weight")?;
+                let query_bias = vb.pp("self.query").get(all_head_size, "bias")?;
+
+                let key_weight = vb
+                    .pp("self.key")
+                    .get((all_head_size, hidden_size), "weight")?;
+                let key_bias = vb.pp("self.key").get(all_head_size, "bias")?;
+
+                let value_weight = vb
+                    .pp("self.value")
+                    .get((all_head_size, hidden_size), "weight")?;
+                let value_bias = vb.pp("self.value").get(all_head_size, "bias")?;
 
-        let qkv_weight = Tensor::cat(&[&query_weight, &key_weight, &value_weight], 0)?;
-        let qkv_bias = Tensor::cat(&[&query_bias, &key_bias, &value_bias], 0)?;
+                (
+                    Tensor::cat(&[&query_weight, &key_weight, &value_weight], 0)?,
+                    Tensor::cat(&[&query_bias, &key_bias, &value_bias], 0)?,
+                )
+            }
+        };
 
         let qkv_linear = Linear::new(qkv_weight, Some(qkv_bias), None);
 
@@ -186,7 +201,7 @@ impl BertAttention {
 
         #[allow(unused_variables)]
         let context_layer = if let (Device::Cuda(_), Some(cublaslt)) =
-            (device, get_cublas_lt_wrapper())
+            (device, get_cublas_lt_wrapper(device))
         {
             #[cfg(feature = "cuda")]
             {
@@ -345,14 +360,25 @@ impl BertEncoder {
         Ok(BertEncoder { layers, span })
     }
 
+    fn quantize(&mut self, quantization: Quantization) -> Result<()> {
+        for layer in self.layers.iter_mut() {
+            layer.attention.qkv_linear.quantize(quantization)?;
+            layer.attention.dense.quantize(quantization)?;
+            layer.intermediate.quantize(quantization)?;
+            layer.output.quantize(quantization)?;
+        }
+        Ok(())
+    }
+
     fn forward(&self, hidden_states: &Tensor, attention_bias: Option<&Tensor>) -> Result<Tensor> {
         let _enter = self.span.enter();
 
         let mut hidden_states = hidden_states.clone();
 
         // Use a loop rather than a fold as it's easier to modify when adding debug/...
-        for layer in self.layers.iter() {
+        for (index, layer) in self.layers.iter().enumerate() {
             hidden_states = layer.forward(&hidden_states, attention_bias)?;
+            debug::dump(&format!("layer.{index}"), &hidden_states)?;
         }
 
         Ok(hidden_states)
@@ -439,11 +465,76 @@ impl ClassificationHead for RobertaClassificationHead {
     }
 }
 
+/// Masked language modeling head projecting token embeddings on the vocabulary for SPLADE
+pub struct BertSpladeHead {
+    transform: Linear,
+    transform_layer_norm: LayerNorm,
+    decoder: Linear,
+    span: tracing::Span,
+}
+
+impl BertSpladeHead {
+    pub(crate) fn load(vb: VarBuilder, config: &Config, word_embeddings: &Tensor) -> Result<Self> {
+        // Bert and Roberta use different names for the same weights
+        let bert_vb = vb.pp("cls.predictions");
+        let (vb, transform, transform_layer_norm) =
+            if bert_vb.get(config.vocab_size, "bias").is_ok() {
+                (bert_vb, "transform.dense", "transform.LayerNorm")
+            } else {
+                (vb.pp("lm_head"), "dense", "layer_norm")
+            };
+
+        let transform_weight = vb
+            .pp(transform)
+            .get((config.hidden_size, config.hidden_size), "weight")?;
+        let transform_bias = vb.pp(transform).get(config.hidden_size, "bias")?;
+        let transform = Linear::new(
+            transform_weight,
+            Some(transform_bias),
+            Some(config.hidden_act.clone()),
+        );
+
+        let transform_layer_norm = LayerNorm::load(
+            vb.pp(transform_layer_norm),
+            config.hidden_size,
+            config.layer_norm_eps as f32,
+        )?;
+
+        // The decoder weight is usually tied to the word embeddings
+        let decoder_weight = vb
+            .pp("decoder")
+            .get((config.vocab_size, config.hidden_size), "weight")
+            .unwrap_or(word_embeddings.clone());
+        let decoder_bias = vb.get(config.vocab_size, "bias")?;
+        let decoder = Linear::new(decoder_weight, Some(decoder_bias), None);
+
+        Ok(Self {
+            transform,
+            transform_layer_norm,
+            decoder,
+            span: tracing::span!(tracing::Level::TRACE, "splade"),
+        })
+    }
+
+    /// Project `hidden_states` on the vocabulary and apply the SPLADE `log(1 + relu(x))` activation
+    fn forward(&self, hidden_states: &Tensor) -> Result<Tensor> {
+        let _enter = self.span.enter();
+
+        let hidden_states = self.transform.forward(hidden_states)?;
+        let hidden_states = self
+            .transform_layer_norm
+            .forward(&hidden_states, &hidden_states.zeros_like()?)?;
+        let logits = self.decoder.forward(&hidden_states)?;
+        (logits.relu()? + 1.0)?.log()
+    }
+}
+
 pub struct BertModel {
     embeddings: BertEmbeddings,
     encoder: BertEncoder,
     pool: Pool,
     classifier: Option<Box<dyn ClassificationHead + Send>>,
+    splade: Option<BertSpladeHead>,
 
     num_attention_heads: usize,
 
@@ -466,7 +557,9 @@ impl BertModel {
                 let pool = Pool::Cls;
 
                 let classifier: Box<dyn ClassificationHead + Send> =
-                    if config.model_type == Some("bert".to_string()) {
+                    if config.model_type == Some("bert".to_string())
+                        || config.model_type == Some("starencoder".to_string())
+                    {
                         Box::new(BertClassificationHead::load(vb.pp("classifier"), config)?)
                     } else {
                         Box::new(RobertaClassificationHead::load(
@@ -503,11 +596,22 @@ impl BertModel {
             }
         };
 
+        let splade = if pool == Pool::Splade {
+            Some(BertSpladeHead::load(
+                vb.clone(),
+                config,
+                embeddings.word_embeddings.embeddings(),
+            )?)
+        } else {
+            None
+        };
+
         Ok(Self {
             embeddings,
             encoder,
             pool,
             classifier,
+            splade,
             num_attention_heads: config.num_attention_heads,
             device: vb.device().clone(),
             dtype: vb.dtype(),
@@ -522,9 +626,11 @@ impl BertModel {
         let max_length = batch.max_length as usize;
 
         let shape = (batch_size, max_length);
+        // `max_length` is rounded up to a length bucket by the queue if enabled
+        let padding = batch.input_ids.len() != batch_size * max_length;
 
-        let (input_ids, type_ids, position_ids, input_lengths, attention_bias, attention_mask) =
-            if batch_size > 1 {
+        let (input_ids, type_ids, position_ids, attention_bias, attention_mask) =
+            if batch_size > 1 || padding {
                 // Prepare padded batch
                 let elems = batch_size * max_length;
 
@@ -533,7 +639,6 @@ impl BertModel {
                 let mut position_ids = Vec::with_capacity(elems);
                 let mut attention_mask = Vec::with_capacity(elems);
                 let mut attention_bias = Vec::with_capacity(elems);
-                let mut input_lengths = Vec::with_capacity(batch_size);
                 // Bool to know if we need to use the attention mask
                 let mut masking = false;
 
@@ -541,7 +646,6 @@ impl BertModel {
                     let start = batch.cumulative_seq_lengths[i] as usize;
                     let end = batch.cumulative_seq_lengths[i + 1] as usize;
                     let seq_length = (end - start) as u32;
-                    input_lengths.push(seq_length as f32);
 
                     // Copy values
                     for j in start..end {
@@ -569,9 +673,9 @@ impl BertModel {
 
                 let (attention_bias, attention_mask) = match masking {
                     true => {
-                        // We only need the mask if we use mean pooling
-                        // For CLS pooling, the bias is enough
-                        let attention_mask = if self.pool == Pool::Mean {
+                        // We only need the mask if we use SPLADE pooling
+                        // For the other poolings, the bias is enough
+                        let attention_mask = if self.pool == Pool::Splade {
                             let attention_mask = Tensor::from_vec(
                                 attention_mask,
                                 (batch_size, max_length, 1),
@@ -608,7 +712,6 @@ impl BertModel {
                     input_ids,
                     type_ids,
                     position_ids,
-                    input_lengths,
                     attention_bias,
                     attention_mask,
                 )
@@ -617,7 +720,6 @@ impl BertModel {
                     batch.input_ids,
                     batch.token_type_ids,
                     batch.position_ids,
-                    vec![batch.max_length as f32],
                     None,
                     None,
                 )
@@ -627,12 +729,11 @@ impl BertModel {
         let input_ids = Tensor::from_vec(input_ids, shape, &self.device)?;
         let type_ids = Tensor::from_vec(type_ids, shape, &self.device)?;
         let position_ids = Tensor::from_vec(position_ids, shape, &self.device)?;
-        let input_lengths =
-            Tensor::from_vec(input_lengths, (batch_size, 1), &self.device)?.to_dtype(self.dtype)?;
 
         let embedding_output = self
             .embeddings
             .forward(&input_ids, &type_ids, &position_ids)?;
+        debug::dump("embeddings", &embedding_output)?;
 
         let outputs = self
             .encoder
@@ -642,42 +743,77 @@ impl BertModel {
         let has_raw_requests = !batch.raw_indices.is_empty();
 
         let pooled_embeddings = if has_pooling_requests {
-            let pooled_indices_length = batch.pooled_indices.len();
-            let mut outputs = outputs.clone();
-
             // Only use pooled_indices if at least one member of the batch ask for raw embeddings
             let pooled_indices = if has_raw_requests {
-                let pooled_indices =
-                    Tensor::from_vec(batch.pooled_indices, pooled_indices_length, &self.device)?;
-
-                // Select values in the batch
-                outputs = outputs.index_select(&pooled_indices, 0)?;
-                Some(pooled_indices)
+                let pooled_indices_length = batch.pooled_indices.len();
+                Some(Tensor::from_vec(
+                    batch.pooled_indices.clone(),
+                    pooled_indices_length,
+                    &self.device,
+                )?)
             } else {
                 None
             };
 
+            // The whole batch is pooled and the pooled members are selected afterwards so that
+            // only (batch, hidden) tensors are copied
             let pooled_embeddings = match self.pool {
                 // CLS pooling
                 Pool::Cls => outputs.i((.., 0))?,
                 // Mean pooling
-                Pool::Mean => {
-                    if let Some(ref attention_mask) = attention_mask {
-                        let mut attention_mask = attention_mask.clone();
-
-                        if let Some(pooled_indices) = pooled_indices {
-                            // Select values in the batch
-                            attention_mask = attention_mask.index_select(&pooled_indices, 0)?;
-                        };
+                Pool::Mean => padded_mean_pool(&outputs, &batch.cumulative_seq_lengths, false)?,
+                // Position weighted mean pooling
+                Pool::WeightedMean => {
+                    padded_mean_pool(&outputs, &batch.cumulative_seq_lengths, true)?
+                }
+                // Last token pooling
+                Pool::LastToken => {
+                    // Indices of the last tokens in the flattened outputs
+                    let last_indices: Vec<u32> = (0..batch_size)
+                        .map(|i| {
+                            let length = batch.cumulative_seq_lengths[i + 1]
+                                - batch.cumulative_seq_lengths[i];
+                            i as u32 * batch.max_length + length - 1
+                        })
+                        .collect();
+                    let last_indices = Tensor::from_vec(last_indices, batch_size, &self.device)?;
+
+                    let (b, l, h) = outputs.shape().dims3()?;
+                    outputs.reshape((b * l, h))?.index_select(&last_indices, 0)?
+                }
+                // SPLADE pooling
+                Pool::Splade => {
+                    let splade = self.splade.as_ref().expect("SPLADE head is not loaded");
+
+                    // The head projects on the vocabulary: only run it on the pooled members
+                    let (outputs, attention_mask) = match &pooled_indices {
+                        Some(pooled_indices) => (
+                            outputs.index_select(pooled_indices, 0)?,
+                            attention_mask
+                                .as_ref()
+                                .map(|mask| mask.index_select(pooled_indices, 0))
+                                .transpose()?,
+                        ),
+                        None => (outputs.clone(), attention_mask.clone()),
+                    };
+                    let mut activations = splade.forward(&outputs)?;
 
-                        // Mask padded values
-                        outputs = outputs.broadcast_mul(&attention_mask)?;
+                    if let Some(attention_mask) = attention_mask {
+                        // Mask padded values. Activations are positive so 0 is neutral for max
+                        activations = activations.broadcast_mul(&attention_mask)?;
                     }
 
-                    (outputs.sum(1)?.broadcast_div(&input_lengths))?
+                    activations.max(1)?
                 }
             };
-            Some(pooled_embeddings)
+
+            match pooled_indices {
+                // SPLADE activations are only computed for the pooled members
+                Some(pooled_indices) if self.pool != Pool::Splade => {
+                    Some(pooled_embeddings.index_select(&pooled_indices, 0)?)
+                }
+                _ => Some(pooled_embeddings),
+            }
         } else {
             None
         };
@@ -687,10 +823,9 @@ impl BertModel {
             let (b, l, h) = outputs.shape().dims3()?;
             let outputs = outputs.reshape((b * l, h))?;
 
-            // We need to remove the padding tokens only if batch_size > 1 and there are some
+            // We need to remove the padding tokens if the batch is padded or if there are some
             // member of the batch that require pooling
-            // or if batch_size > 1 and the members of the batch have different lengths
-            if (attention_mask.is_some() || has_pooling_requests) && batch_size > 1 {
+            if padding || has_pooling_requests {
                 let mut final_indices: Vec<u32> = Vec::with_capacity(batch_size * max_length);
 
                 for i in batch.raw_indices.into_iter() {
@@ -727,6 +862,10 @@ impl Model for BertModel {
         true
     }
 
+    fn quantize(&mut self, quantization: Quantization) -> Result<()> {
+        self.encoder.quantize(quantization)
+    }
+
     fn embed(&self, batch: Batch) -> Result<(Option<Tensor>, Option<Tensor>)> {
         self.forward(batch)
     }
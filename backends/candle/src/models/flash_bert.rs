@@ -1,5 +1,8 @@
+use crate::debug;
 use crate::flash_attn::flash_attn_varlen;
-use crate::layers::{LayerNorm, Linear};
+#[cfg(feature = "metal")]
+use crate::flash_attn::varlen_attention;
+use crate::layers::{LayerNorm, Linear, Quantization};
 use crate::models::bert::{
     BertClassificationHead, ClassificationHead, Config, PositionEmbeddingType,
     RobertaClassificationHead,
@@ -89,21 +92,33 @@ impl BertAttention {
         let all_head_size = config.num_attention_heads * attention_head_size;
         let hidden_size = config.hidden_size;
 
-        let query_weight = vb
-            .pp("self.query")
-            .get((all_head_size, hidden_size), "weight")?;
-        let query_bias = vb.pp("self.query").get(all_head_size, "bias")?;
-        let key_weight = vb
-            .pp("self.key")
-            .get((all_head_size, hidden_size), "weight")?;
-        let key_bias = vb.pp("self.key").get(all_head_size, "bias")?;
-        let value_weight = vb
-            .pp("self.value")
-            .get((all_head_size, hidden_size), "weight")?;
-        let value_bias = vb.pp("self.value").get(all_head_size, "bias")?;
-
-        let qkv_weight = Tensor::cat(&[&query_weight, &key_weight, &value_weight], 0)?;
-        let qkv_bias = Tensor::cat(&[&query_bias, &key_bias, &value_bias], 0)?;
+        let (qkv_weight, qkv_bias) = match (
+            vb.pp("self.query_key_value")
+                .get((3 * all_head_size, hidden_size), "weight"),
+            vb.pp("self.query_key_value").get(3 * all_head_size, "bias"),
+        ) {
+            // StarEncoder checkpoints already store a fused QKV projection
+            (Ok(qkv_weight), Ok(qkv_bias)) => (qkv_weight, qkv_bias),
+            _ => {
+                let query_weight = vb
+                    .pp("self.query")
+                    .get((all_head_size, hidden_size), "weight")?;
+                let query_bias = vb.pp("self.query").get(all_head_size, "bias")?;
+                let key_weight = vb
+                    .pp("self.key")
+                    .get((all_head_size, hidden_size), "weight")?;
+                let key_bias = vb.pp("self.key").get(all_head_size, "bias")?;
+                let value_weight = vb
+                    .pp("self.value")
+                    .get((all_head_size, hidden_size), "weight")?;
+                let value_bias = vb.pp("self.value").get(all_head_size, "bias")?;
+
+                (
+                    Tensor::cat(&[&query_weight, &key_weight, &value_weight], 0)?,
+                    Tensor::cat(&[&query_bias, &key_bias, &value_bias], 0)?,
+                )
+            }
+        };
 
         let qkv_linear = Linear::new(qkv_weight, Some(qkv_bias), None);
 
@@ -134,10 +149,12 @@ impl BertAttention {
         })
     }
 
+    #[cfg_attr(not(feature = "metal"), allow(unused_variables))]
     pub fn forward(
         &self,
         hidden_states: &Tensor,
         cu_seqlens: &Tensor,
+        cu_seqlens_host: &[u32],
         max_s: usize,
     ) -> Result<Tensor> {
         let _enter = self.span.enter();
@@ -154,18 +171,28 @@ impl BertAttention {
         let qkv = qkv.reshape(new_qkv_shape.as_slice())?;
         let qkv = qkv.chunk(3, 1)?;
 
-        let attention = flash_attn_varlen(
-            &qkv[0],
-            &qkv[1],
-            &qkv[2],
-            None,
-            cu_seqlens,
-            cu_seqlens,
-            max_s,
-            max_s,
-            self.softmax_scale,
-            false,
-        )?;
+        let attention = match hidden_states.device() {
+            #[cfg(feature = "metal")]
+            Device::Metal(_) => varlen_attention(
+                &qkv[0],
+                &qkv[1],
+                &qkv[2],
+                cu_seqlens_host,
+                self.softmax_scale,
+            )?,
+            _ => flash_attn_varlen(
+                &qkv[0],
+                &qkv[1],
+                &qkv[2],
+                None,
+                cu_seqlens,
+                cu_seqlens,
+                max_s,
+                max_s,
+                self.softmax_scale,
+                false,
+            )?,
+        };
         let attention = attention.flatten_from(candle::D::Minus2)?;
 
         let hidden_states = self.dense.forward(&attention)?;
@@ -230,11 +257,13 @@ impl BertLayer {
         &self,
         hidden_states: &Tensor,
         cu_seqlens: &Tensor,
+        cu_seqlens_host: &[u32],
         max_s: usize,
     ) -> Result<Tensor> {
         let _enter = self.span.enter();
 
-        let hidden_states = self.attention.forward(hidden_states, cu_seqlens, max_s)?;
+        let hidden_states =
+            self.attention.forward(hidden_states, cu_seqlens, cu_seqlens_host, max_s)?;
         let residual = hidden_states.clone();
 
         let hidden_states = self.intermediate.forward(&hidden_states)?;
@@ -260,14 +289,31 @@ impl BertEncoder {
         Ok(BertEncoder { layers, span })
     }
 
-    fn forward(&self, hidden_states: &Tensor, cu_seqlens: &Tensor, max_s: usize) -> Result<Tensor> {
+    fn quantize(&mut self, quantization: Quantization) -> Result<()> {
+        for layer in self.layers.iter_mut() {
+            layer.attention.qkv_linear.quantize(quantization)?;
+            layer.attention.dense.quantize(quantization)?;
+            layer.intermediate.quantize(quantization)?;
+            layer.output.quantize(quantization)?;
+        }
+        Ok(())
+    }
+
+    fn forward(
+        &self,
+        hidden_states: &Tensor,
+        cu_seqlens: &Tensor,
+        cu_seqlens_host: &[u32],
+        max_s: usize,
+    ) -> Result<Tensor> {
         let _enter = self.span.enter();
 
         let mut hidden_states = hidden_states.clone();
 
         // Use a loop rather than a fold as it's easier to modify when adding debug/...
-        for layer in self.layers.iter() {
-            hidden_states = layer.forward(&hidden_states, cu_seqlens, max_s)?
+        for (index, layer) in self.layers.iter().enumerate() {
+            hidden_states = layer.forward(&hidden_states, cu_seqlens, cu_seqlens_host, max_s)?;
+            debug::dump(&format!("layer.{index}"), &hidden_states)?;
         }
 
         Ok(hidden_states)
@@ -287,12 +333,13 @@ pub struct FlashBertModel {
 impl FlashBertModel {
     pub fn load(vb: VarBuilder, config: &Config, model_type: ModelType) -> Result<Self> {
         match vb.device() {
-            Device::Cuda(_) => {}
-            _ => candle::bail!("FlashBert requires Cuda"),
-        }
-
-        if vb.dtype() != DType::F16 {
-            candle::bail!("FlashBert requires DType::F16")
+            Device::Cuda(_) => {
+                if !matches!(vb.dtype(), DType::F16 | DType::BF16) {
+                    candle::bail!("FlashBert requires DType::F16 or DType::BF16 on Cuda")
+                }
+            }
+            Device::Metal(_) => {}
+            _ => candle::bail!("FlashBert requires Cuda or Metal"),
         }
 
         // Check position embedding type
@@ -306,7 +353,9 @@ impl FlashBertModel {
                 let pool = Pool::Cls;
 
                 let classifier: Box<dyn ClassificationHead + Send> =
-                    if config.model_type == Some("bert".to_string()) {
+                    if config.model_type == Some("bert".to_string())
+                        || config.model_type == Some("starencoder".to_string())
+                    {
                         Box::new(BertClassificationHead::load(vb.pp("classifier"), config)?)
                     } else {
                         Box::new(RobertaClassificationHead::load(
@@ -316,6 +365,9 @@ impl FlashBertModel {
                     };
                 (pool, Some(classifier))
             }
+            ModelType::Embedding(Pool::Splade) => {
+                candle::bail!("`splade` pooling is not supported for FlashBert")
+            }
             ModelType::Embedding(pool) => (pool, None),
         };
 
@@ -359,7 +411,7 @@ impl FlashBertModel {
         let batch_size = batch.len();
         let shape = batch.input_ids.len();
 
-        // Create Cuda tensors
+        // Create device tensors
         let input_ids = Tensor::from_vec(batch.input_ids, shape, &self.device)?;
         let type_ids = Tensor::from_vec(batch.token_type_ids, shape, &self.device)?;
         let position_ids = Tensor::from_vec(batch.position_ids, shape, &self.device)?;
@@ -372,10 +424,14 @@ impl FlashBertModel {
         let embedding_output = self
             .embeddings
             .forward(&input_ids, &type_ids, &position_ids)?;
+        debug::dump("embeddings", &embedding_output)?;
 
-        let outputs =
-            self.encoder
-                .forward(&embedding_output, &cu_seqlens, batch.max_length as usize)?;
+        let outputs = self.encoder.forward(
+            &embedding_output,
+            &cu_seqlens,
+            &batch.cumulative_seq_lengths,
+            batch.max_length as usize,
+        )?;
 
         let has_pooling_requests = !batch.pooled_indices.is_empty();
         let has_raw_requests = !batch.raw_indices.is_empty();
@@ -416,18 +472,66 @@ impl FlashBertModel {
                                 let start = batch.cumulative_seq_lengths[i];
                                 let len = batch.cumulative_seq_lengths[i + 1] - start;
 
-                                // Mean
-                                let embeddings = outputs.narrow(0, start as usize, len as usize)?;
-                                embeddings.sum_keepdim(0)? / (len as f64)
+                                // Mean, accumulated in f32 to not lose precision
+                                let embeddings = outputs
+                                    .narrow(0, start as usize, len as usize)?
+                                    .to_dtype(DType::F32)?;
+                                (embeddings.sum_keepdim(0)? / (len as f64))?
+                                    .to_dtype(outputs.dtype())
                             })
                             .collect();
 
                         // Concatenate all results
                         Some(Tensor::cat(&results?, 0)?)
                     } else {
-                        Some((outputs.sum_keepdim(0)? / (batch.max_length as f64))?)
+                        let embeddings = outputs.to_dtype(DType::F32)?;
+                        let embeddings = (embeddings.sum_keepdim(0)? / (batch.max_length as f64))?;
+                        Some(embeddings.to_dtype(outputs.dtype())?)
                     }
                 }
+                // Position weighted mean pooling
+                Pool::WeightedMean => {
+                    // for each request that requires pooling
+                    let results: Result<Vec<Tensor>> = batch
+                        .pooled_indices
+                        .iter()
+                        .map(|&i| {
+                            let i = i as usize;
+                            let start = batch.cumulative_seq_lengths[i];
+                            let len = batch.cumulative_seq_lengths[i + 1] - start;
+
+                            // Tokens are weighted by their position. Accumulated in f32
+                            let weights = Tensor::arange(1u32, len + 1, &self.device)?
+                                .to_dtype(DType::F32)?
+                                .reshape((len as usize, 1))?;
+                            let embeddings = outputs
+                                .narrow(0, start as usize, len as usize)?
+                                .to_dtype(DType::F32)?;
+                            let embeddings = (embeddings.broadcast_mul(&weights)?.sum_keepdim(0)?
+                                / ((len * (len + 1) / 2) as f64))?;
+                            embeddings.to_dtype(outputs.dtype())
+                        })
+                        .collect();
+
+                    // Concatenate all results
+                    Some(Tensor::cat(&results?, 0)?)
+                }
+                // Last token pooling
+                Pool::LastToken => {
+                    // Get the indices of the last tokens from cumulative_seq_lengths
+                    let last_indices: Vec<u32> = batch
+                        .pooled_indices
+                        .iter()
+                        .map(|&i| batch.cumulative_seq_lengths[i as usize + 1] - 1)
+                        .collect();
+                    let last_indices_length = last_indices.len();
+                    let last_indices =
+                        Tensor::from_vec(last_indices, last_indices_length, &self.device)?;
+
+                    // Select last tokens
+                    Some(outputs.index_select(&last_indices, 0)?)
+                }
+                Pool::Splade => unreachable!(),
             }
         } else {
             None
@@ -470,6 +574,9 @@ impl Model for FlashBertModel {
     fn is_padded(&self) -> bool {
         false
     }
+    fn quantize(&mut self, quantization: Quantization) -> Result<()> {
+        self.encoder.quantize(quantization)
+    }
     fn embed(&self, batch: Batch) -> Result<(Option<Tensor>, Option<Tensor>)> {
         self.forward(batch)
     }
@@ -1,6 +1,7 @@
 use crate::alibi::alibi_head_slopes;
+use crate::debug;
 use crate::flash_attn::flash_attn_varlen;
-use crate::layers::{HiddenAct, LayerNorm, Linear};
+use crate::layers::{HiddenAct, LayerNorm, Linear, Quantization};
 use crate::models::bert::{Config, PositionEmbeddingType};
 use crate::models::Model;
 use candle::{DType, Device, IndexOp, Result, Tensor};
@@ -272,14 +273,25 @@ impl BertEncoder {
         Ok(BertEncoder { layers, span })
     }
 
+    fn quantize(&mut self, quantization: Quantization) -> Result<()> {
+        for layer in self.layers.iter_mut() {
+            layer.attention.qkv_linear.quantize(quantization)?;
+            layer.attention.dense.quantize(quantization)?;
+            layer.gated.quantize(quantization)?;
+            layer.output.quantize(quantization)?;
+        }
+        Ok(())
+    }
+
     fn forward(&self, hidden_states: &Tensor, cu_seqlens: &Tensor, max_s: usize) -> Result<Tensor> {
         let _enter = self.span.enter();
 
         let mut hidden_states = hidden_states.clone();
 
         // Use a loop rather than a fold as it's easier to modify when adding debug/...
-        for layer in self.layers.iter() {
-            hidden_states = layer.forward(&hidden_states, cu_seqlens, max_s)?
+        for (index, layer) in self.layers.iter().enumerate() {
+            hidden_states = layer.forward(&hidden_states, cu_seqlens, max_s)?;
+            debug::dump(&format!("layer.{index}"), &hidden_states)?;
         }
 
         Ok(hidden_states)
@@ -313,8 +325,8 @@ impl FlashJinaBertModel {
             _ => candle::bail!("FlashJinaBertModel requires Cuda"),
         }
 
-        if vb.dtype() != DType::F16 {
-            candle::bail!("FlashJinaBertModel requires DType::F16")
+        if !matches!(vb.dtype(), DType::F16 | DType::BF16) {
+            candle::bail!("FlashJinaBertModel requires DType::F16 or DType::BF16")
         }
 
         let pool = match model_type {
@@ -322,6 +334,9 @@ impl FlashJinaBertModel {
             ModelType::Classifier => {
                 candle::bail!("`classifier` model type is not supported for Jina")
             }
+            ModelType::Embedding(Pool::Splade) => {
+                candle::bail!("`splade` pooling is not supported for Jina")
+            }
             ModelType::Embedding(pool) => pool,
         };
 
@@ -381,6 +396,7 @@ impl FlashJinaBertModel {
         let embedding_output = self
             .embeddings
             .forward(&input_ids, &type_ids, &position_ids)?;
+        debug::dump("embeddings", &embedding_output)?;
 
         let outputs =
             self.encoder
@@ -429,18 +445,66 @@ impl FlashJinaBertModel {
                                 let start = batch.cumulative_seq_lengths[i];
                                 let len = batch.cumulative_seq_lengths[i + 1] - start;
 
-                                // Mean
-                                let embeddings = outputs.narrow(0, start as usize, len as usize)?;
-                                embeddings.sum_keepdim(0)? / (len as f64)
+                                // Mean, accumulated in f32 to not lose precision
+                                let embeddings = outputs
+                                    .narrow(0, start as usize, len as usize)?
+                                    .to_dtype(DType::F32)?;
+                                (embeddings.sum_keepdim(0)? / (len as f64))?
+                                    .to_dtype(outputs.dtype())
                             })
                             .collect();
 
                         // Concatenate all results
                         Some(Tensor::cat(&results?, 0)?)
                     } else {
-                        Some((outputs.sum_keepdim(0)? / (batch.max_length as f64))?)
+                        let embeddings = outputs.to_dtype(DType::F32)?;
+                        let embeddings = (embeddings.sum_keepdim(0)? / (batch.max_length as f64))?;
+                        Some(embeddings.to_dtype(outputs.dtype())?)
                     }
                 }
+                // Position weighted mean pooling
+                Pool::WeightedMean => {
+                    // for each request that requires pooling
+                    let results: Result<Vec<Tensor>> = batch
+                        .pooled_indices
+                        .iter()
+                        .map(|&i| {
+                            let i = i as usize;
+                            let start = batch.cumulative_seq_lengths[i];
+                            let len = batch.cumulative_seq_lengths[i + 1] - start;
+
+                            // Tokens are weighted by their position. Accumulated in f32
+                            let weights = Tensor::arange(1u32, len + 1, &self.device)?
+                                .to_dtype(DType::F32)?
+                                .reshape((len as usize, 1))?;
+                            let embeddings = outputs
+                                .narrow(0, start as usize, len as usize)?
+                                .to_dtype(DType::F32)?;
+                            let embeddings = (embeddings.broadcast_mul(&weights)?.sum_keepdim(0)?
+                                / ((len * (len + 1) / 2) as f64))?;
+                            embeddings.to_dtype(outputs.dtype())
+                        })
+                        .collect();
+
+                    // Concatenate all results
+                    Some(Tensor::cat(&results?, 0)?)
+                }
+                // Last token pooling
+                Pool::LastToken => {
+                    // Get the indices of the last tokens from cumulative_seq_lengths
+                    let last_indices: Vec<u32> = batch
+                        .pooled_indices
+                        .iter()
+                        .map(|&i| batch.cumulative_seq_lengths[i as usize + 1] - 1)
+                        .collect();
+                    let last_indices_length = last_indices.len();
+                    let last_indices =
+                        Tensor::from_vec(last_indices, last_indices_length, &self.device)?;
+
+                    // Select last tokens
+                    Some(outputs.index_select(&last_indices, 0)?)
+                }
+                Pool::Splade => unreachable!(),
             }
         } else {
             None
@@ -483,6 +547,9 @@ impl Model for FlashJinaBertModel {
     fn is_padded(&self) -> bool {
         false
     }
+    fn quantize(&mut self, quantization: Quantization) -> Result<()> {
+        self.encoder.quantize(quantization)
+    }
     fn embed(&self, batch: Batch) -> Result<(Option<Tensor>, Option<Tensor>)> {
         self.forward(batch)
     }
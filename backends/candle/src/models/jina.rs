@@ -1,6 +1,7 @@
 use crate::alibi::build_alibi_tensor;
-use crate::layers::{get_cublas_lt_wrapper, HiddenAct, LayerNorm, Linear};
-use crate::models::Model;
+use crate::debug;
+use crate::layers::{get_cublas_lt_wrapper, HiddenAct, LayerNorm, Linear, Quantization};
+use crate::models::{padded_mean_pool, Model};
 use crate::models::{Config, PositionEmbeddingType};
 use candle::{DType, Device, IndexOp, Module, Result, Tensor, D};
 use candle_nn::{Embedding, VarBuilder};
@@ -160,7 +161,7 @@ impl BertAttention {
 
         #[allow(unused_variables)]
         let context_layer = if let (Device::Cuda(_), Some(cublaslt)) =
-            (device, get_cublas_lt_wrapper())
+            (device, get_cublas_lt_wrapper(device))
         {
             #[cfg(feature = "cuda")]
             {
@@ -323,14 +324,25 @@ impl BertEncoder {
         Ok(BertEncoder { layers, span })
     }
 
+    fn quantize(&mut self, quantization: Quantization) -> Result<()> {
+        for layer in self.layers.iter_mut() {
+            layer.attention.qkv_linear.quantize(quantization)?;
+            layer.attention.dense.quantize(quantization)?;
+            layer.gated.quantize(quantization)?;
+            layer.output.quantize(quantization)?;
+        }
+        Ok(())
+    }
+
     fn forward(&self, hidden_states: &Tensor, attention_bias: Option<&Tensor>) -> Result<Tensor> {
         let _enter = self.span.enter();
 
         let mut hidden_states = hidden_states.clone();
 
         // Use a loop rather than a fold as it's easier to modify when adding debug/...
-        for layer in self.layers.iter() {
+        for (index, layer) in self.layers.iter().enumerate() {
             hidden_states = layer.forward(&hidden_states, attention_bias)?;
+            debug::dump(&format!("layer.{index}"), &hidden_states)?;
         }
 
         Ok(hidden_states)
@@ -368,6 +380,9 @@ impl JinaBertModel {
             ModelType::Classifier => {
                 candle::bail!("`classifier` model type is not supported for Jina")
             }
+            ModelType::Embedding(Pool::Splade) => {
+                candle::bail!("`splade` pooling is not supported for Jina")
+            }
             ModelType::Embedding(pool) => pool,
         };
 
@@ -414,160 +429,128 @@ impl JinaBertModel {
         let max_length = batch.max_length as usize;
 
         let shape = (batch_size, max_length);
+        // `max_length` is rounded up to a length bucket by the queue if enabled
+        let padding = batch.input_ids.len() != batch_size * max_length;
+
+        let (input_ids, type_ids, position_ids, attention_bias) = if batch_size > 1 || padding {
+            // Prepare padded batch
+            let elems = batch_size * max_length;
+
+            let mut input_ids = Vec::with_capacity(elems);
+            let mut type_ids = Vec::with_capacity(elems);
+            let mut position_ids = Vec::with_capacity(elems);
+            let mut attention_bias = Vec::with_capacity(elems);
+            // Bool to know if we need to use the attention bias
+            let mut masking = false;
+
+            for i in 0..batch_size {
+                let start = batch.cumulative_seq_lengths[i] as usize;
+                let end = batch.cumulative_seq_lengths[i + 1] as usize;
+                let seq_length = (end - start) as u32;
+
+                // Copy values
+                for j in start..end {
+                    input_ids.push(batch.input_ids[j]);
+                    type_ids.push(batch.token_type_ids[j]);
+                    position_ids.push(batch.position_ids[j]);
+                    attention_bias.push(0.0);
+                }
 
-        let (input_ids, type_ids, position_ids, input_lengths, attention_bias, attention_mask) =
-            if batch_size > 1 {
-                // Prepare padded batch
-                let elems = batch_size * max_length;
-
-                let mut input_ids = Vec::with_capacity(elems);
-                let mut type_ids = Vec::with_capacity(elems);
-                let mut position_ids = Vec::with_capacity(elems);
-                let mut attention_mask = Vec::with_capacity(elems);
-                let mut attention_bias = Vec::with_capacity(elems);
-                let mut input_lengths = Vec::with_capacity(batch_size);
-                // Bool to know if we need to use the attention mask
-                let mut masking = false;
-
-                for i in 0..batch_size {
-                    let start = batch.cumulative_seq_lengths[i] as usize;
-                    let end = batch.cumulative_seq_lengths[i + 1] as usize;
-                    let seq_length = (end - start) as u32;
-                    input_lengths.push(seq_length as f32);
-
-                    // Copy values
-                    for j in start..end {
-                        input_ids.push(batch.input_ids[j]);
-                        type_ids.push(batch.token_type_ids[j]);
-                        position_ids.push(batch.position_ids[j]);
-                        attention_mask.push(1.0_f32);
-                        attention_bias.push(0.0);
+                // Add padding if needed
+                let padding = batch.max_length - seq_length;
+                if padding > 0 {
+                    // Set bool to use attention mask
+                    masking = true;
+                    for _ in 0..padding {
+                        input_ids.push(0);
+                        type_ids.push(0);
+                        position_ids.push(0);
+                        attention_bias.push(f32::NEG_INFINITY);
                     }
+                }
+            }
 
-                    // Add padding if needed
-                    let padding = batch.max_length - seq_length;
-                    if padding > 0 {
-                        // Set bool to use attention mask
-                        masking = true;
-                        for _ in 0..padding {
-                            input_ids.push(0);
-                            type_ids.push(0);
-                            position_ids.push(0);
-                            attention_mask.push(0.0_f32);
-                            attention_bias.push(f32::NEG_INFINITY);
-                        }
+            let attention_bias = match masking {
+                true => {
+                    let attention_bias = Tensor::from_vec(
+                        attention_bias,
+                        (batch_size, 1, 1, max_length),
+                        &self.device,
+                    )?
+                    .to_dtype(self.dtype)?;
+
+                    // Broadcast once instead of at every layer
+                    let mut attention_bias = attention_bias.broadcast_as((
+                        batch_size,
+                        self.num_attention_heads,
+                        max_length,
+                        max_length,
+                    ))?;
+
+                    // Add alibi tensor
+                    if let Some(alibi) = &self.alibi {
+                        let alibi = alibi
+                            .i((.., .., 0..max_length, 0..max_length))?
+                            .broadcast_as((
+                                batch_size,
+                                self.num_attention_heads,
+                                max_length,
+                                max_length,
+                            ))?;
+
+                        attention_bias = attention_bias.add(&alibi)?;
                     }
-                }
 
-                let (attention_bias, attention_mask) = match masking {
-                    true => {
-                        // We only need the mask if we use mean pooling
-                        // For CLS pooling, the bias is enough
-                        let attention_mask = if self.pool == Pool::Mean {
-                            let attention_mask = Tensor::from_vec(
-                                attention_mask,
-                                (batch_size, max_length, 1),
-                                &self.device,
-                            )?
-                            .to_dtype(self.dtype)?;
-
-                            Some(attention_mask)
-                        } else {
-                            None
-                        };
-
-                        let attention_bias = Tensor::from_vec(
-                            attention_bias,
-                            (batch_size, 1, 1, max_length),
-                            &self.device,
-                        )?
-                        .to_dtype(self.dtype)?;
-
-                        // Broadcast once instead of at every layer
-                        let mut attention_bias = attention_bias.broadcast_as((
-                            batch_size,
-                            self.num_attention_heads,
-                            max_length,
-                            max_length,
-                        ))?;
-
-                        // Add alibi tensor
-                        if let Some(alibi) = &self.alibi {
-                            let alibi = alibi
+                    Some(attention_bias.contiguous()?)
+                }
+                false => {
+                    if let Some(alibi) = &self.alibi {
+                        Some(
+                            alibi
                                 .i((.., .., 0..max_length, 0..max_length))?
                                 .broadcast_as((
                                     batch_size,
                                     self.num_attention_heads,
                                     max_length,
                                     max_length,
-                                ))?;
-
-                            attention_bias = attention_bias.add(&alibi)?;
-                        }
-
-                        (Some(attention_bias.contiguous()?), attention_mask)
-                    }
-                    false => {
-                        if let Some(alibi) = &self.alibi {
-                            (
-                                Some(
-                                    alibi
-                                        .i((.., .., 0..max_length, 0..max_length))?
-                                        .broadcast_as((
-                                            batch_size,
-                                            self.num_attention_heads,
-                                            max_length,
-                                            max_length,
-                                        ))?
-                                        .contiguous()?,
-                                ),
-                                None,
-                            )
-                        } else {
-                            (None, None)
-                        }
+                                ))?
+                                .contiguous()?,
+                        )
+                    } else {
+                        None
                     }
-                };
+                }
+            };
 
-                (
-                    input_ids,
-                    type_ids,
-                    position_ids,
-                    input_lengths,
-                    attention_bias,
-                    attention_mask,
+            (input_ids, type_ids, position_ids, attention_bias)
+        } else {
+            let attention_bias = if let Some(alibi) = &self.alibi {
+                Some(
+                    alibi
+                        .i((.., .., 0..max_length, 0..max_length))?
+                        .contiguous()?,
                 )
             } else {
-                let attention_bias = if let Some(alibi) = &self.alibi {
-                    Some(
-                        alibi
-                            .i((.., .., 0..max_length, 0..max_length))?
-                            .contiguous()?,
-                    )
-                } else {
-                    None
-                };
-
-                (
-                    batch.input_ids,
-                    batch.token_type_ids,
-                    batch.position_ids,
-                    vec![batch.max_length as f32],
-                    attention_bias,
-                    None,
-                )
+                None
             };
 
+            (
+                batch.input_ids,
+                batch.token_type_ids,
+                batch.position_ids,
+                attention_bias,
+            )
+        };
+
         // Create CPU tensors
         let input_ids = Tensor::from_vec(input_ids, shape, &self.device)?;
         let type_ids = Tensor::from_vec(type_ids, shape, &self.device)?;
         let position_ids = Tensor::from_vec(position_ids, shape, &self.device)?;
-        let input_lengths =
-            Tensor::from_vec(input_lengths, (batch_size, 1), &self.device)?.to_dtype(self.dtype)?;
 
         let embedding_output = self
             .embeddings
             .forward(&input_ids, &type_ids, &position_ids)?;
+        debug::dump("embeddings", &embedding_output)?;
 
         let outputs = self
             .encoder
@@ -577,55 +560,60 @@ impl JinaBertModel {
         let has_raw_requests = !batch.raw_indices.is_empty();
 
         let pooled_embeddings = if has_pooling_requests {
-            let pooled_indices_length = batch.pooled_indices.len();
-            let mut outputs = outputs.clone();
-
             // Only use pooled_indices if at least one member of the batch ask for raw embeddings
             let pooled_indices = if has_raw_requests {
-                let pooled_indices =
-                    Tensor::from_vec(batch.pooled_indices, pooled_indices_length, &self.device)?;
-
-                // Select values in the batch
-                outputs = outputs.index_select(&pooled_indices, 0)?;
-                Some(pooled_indices)
+                let pooled_indices_length = batch.pooled_indices.len();
+                Some(Tensor::from_vec(
+                    batch.pooled_indices.clone(),
+                    pooled_indices_length,
+                    &self.device,
+                )?)
             } else {
                 None
             };
 
+            // The whole batch is pooled and the pooled members are selected afterwards so that
+            // only (batch, hidden) tensors are copied
             let pooled_embeddings = match self.pool {
                 // CLS pooling
                 Pool::Cls => outputs.i((.., 0))?,
                 // Mean pooling
-                Pool::Mean => {
-                    if let Some(ref attention_mask) = attention_mask {
-                        let mut attention_mask = attention_mask.clone();
-
-                        if let Some(pooled_indices) = pooled_indices {
-                            // Select values in the batch
-                            attention_mask = attention_mask.index_select(&pooled_indices, 0)?;
-                        };
-
-                        // Mask padded values
-                        outputs = outputs.broadcast_mul(&attention_mask)?;
-                    }
-
-                    (outputs.sum(1)?.broadcast_div(&input_lengths))?
+                Pool::Mean => padded_mean_pool(&outputs, &batch.cumulative_seq_lengths, false)?,
+                // Position weighted mean pooling
+                Pool::WeightedMean => {
+                    padded_mean_pool(&outputs, &batch.cumulative_seq_lengths, true)?
                 }
+                // Last token pooling
+                Pool::LastToken => {
+                    // Indices of the last tokens in the flattened outputs
+                    let last_indices: Vec<u32> = (0..batch_size)
+                        .map(|i| {
+                            let length = batch.cumulative_seq_lengths[i + 1]
+                                - batch.cumulative_seq_lengths[i];
+                            i as u32 * batch.max_length + length - 1
+                        })
+                        .collect();
+                    let last_indices = Tensor::from_vec(last_indices, batch_size, &self.device)?;
+
+                    let (b, l, h) = outputs.shape().dims3()?;
+                    outputs.reshape((b * l, h))?.index_select(&last_indices, 0)?
+                }
+                Pool::Splade => unreachable!(),
             };
-            Some(pooled_embeddings)
+
+            match pooled_indices {
+                Some(pooled_indices) => Some(pooled_embeddings.index_select(&pooled_indices, 0)?),
+                None => Some(pooled_embeddings),
+            }
         } else {
             None
-        };
-
-        let raw_embeddings = if has_raw_requests {
             // Reshape outputs
             let (b, l, h) = outputs.shape().dims3()?;
             let outputs = outputs.reshape((b * l, h))?;
 
-            // We need to remove the padding tokens only if batch_size > 1 and there are some
+            // We need to remove the padding tokens if the batch is padded or if there are some
             // member of the batch that require pooling
-            // or if batch_size > 1 and the members of the batch have different lengths
-            if (attention_mask.is_some() || has_pooling_requests) && batch_size > 1 {
+            if padding || has_pooling_requests {
                 let mut final_indices: Vec<u32> = Vec::with_capacity(batch_size * max_length);
 
                 for i in batch.raw_indices.into_iter() {
@@ -661,6 +649,9 @@ impl Model for JinaBertModel {
     fn is_padded(&self) -> bool {
         true
     }
+    fn quantize(&mut self, quantization: Quantization) -> Result<()> {
+        self.encoder.quantize(quantization)
+    }
     fn embed(&self, batch: Batch) -> Result<(Option<Tensor>, Option<Tensor>)> {
         self.forward(batch)
     }
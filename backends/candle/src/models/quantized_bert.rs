@@ -0,0 +1,368 @@
+use crate::debug;
+use crate::layers::{HiddenAct, LayerNorm, Linear};
+use crate::models::bert::{Config, PositionEmbeddingType};
+use crate::models::{padded_mean_pool, Model};
+use candle::quantized::{gguf_file, QMatMul};
+use candle::{Device, IndexOp, Module, Result, Tensor, D};
+use candle_nn::Embedding;
+use std::fs::File;
+use std::path::Path;
+use text_embeddings_backend_core::{Batch, ModelType, Pool};
+
+/// GGUF file reader using the llama.cpp tensor names
+struct GgufWeights {
+    content: gguf_file::Content,
+    file: File,
+    device: Device,
+}
+
+impl GgufWeights {
+    fn open(path: &Path, device: &Device) -> Result<Self> {
+        let mut file = File::open(path)?;
+        let content = gguf_file::Content::read(&mut file)?;
+        Ok(Self {
+            content,
+            file,
+            device: device.clone(),
+        })
+    }
+
+    /// Load a quantized matmul weight
+    fn qmatmul(&mut self, name: &str) -> Result<QMatMul> {
+        let qtensor = self.content.tensor(&mut self.file, name)?;
+        QMatMul::from_qtensor(qtensor)
+    }
+
+    /// Load and dequantize a weight
+    fn tensor(&mut self, name: &str) -> Result<Tensor> {
+        self.content
+            .tensor(&mut self.file, name)?
+            .dequantize(&self.device)
+    }
+
+    fn linear(&mut self, prefix: &str, act: Option<HiddenAct>) -> Result<Linear> {
+        let weight = self.qmatmul(&format!("{prefix}.weight"))?;
+        let bias = self.tensor(&format!("{prefix}.bias"))?;
+        Ok(Linear::new_quantized(weight, Some(bias), act))
+    }
+
+    fn layer_norm(&mut self, prefix: &str, epsilon: f64) -> Result<LayerNorm> {
+        Ok(LayerNorm::new(
+            self.tensor(&format!("{prefix}.weight"))?,
+            self.tensor(&format!("{prefix}.bias"))?,
+            epsilon as f32,
+        ))
+    }
+}
+
+struct QuantizedBertEmbeddings {
+    word_embeddings: Embedding,
+    token_type_embeddings: Embedding,
+    position_embeddings: Embedding,
+    layer_norm: LayerNorm,
+    span: tracing::Span,
+}
+
+impl QuantizedBertEmbeddings {
+    fn load(weights: &mut GgufWeights, config: &Config) -> Result<Self> {
+        Ok(Self {
+            word_embeddings: Embedding::new(
+                weights.tensor("token_embd.weight")?,
+                config.hidden_size,
+            ),
+            token_type_embeddings: Embedding::new(
+                weights.tensor("token_types.weight")?,
+                config.hidden_size,
+            ),
+            position_embeddings: Embedding::new(
+                weights.tensor("position_embd.weight")?,
+                config.hidden_size,
+            ),
+            layer_norm: weights.layer_norm("token_embd_norm", config.layer_norm_eps)?,
+            span: tracing::span!(tracing::Level::TRACE, "embeddings"),
+        })
+    }
+
+    fn forward(
+        &self,
+        input_ids: &Tensor,
+        token_type_ids: &Tensor,
+        position_ids: &Tensor,
+    ) -> Result<Tensor> {
+        let _enter = self.span.enter();
+
+        let input_embeddings = self.word_embeddings.forward(input_ids)?;
+        let token_type_embeddings = self.token_type_embeddings.forward(token_type_ids)?;
+        let position_embeddings = self.position_embeddings.forward(position_ids)?;
+
+        let embeddings = input_embeddings.add(&token_type_embeddings)?;
+        self.layer_norm.forward(&embeddings, &position_embeddings)
+    }
+}
+
+struct QuantizedBertLayer {
+    query: Linear,
+    key: Linear,
+    value: Linear,
+    attention_output: Linear,
+    attention_layer_norm: LayerNorm,
+    intermediate: Linear,
+    output: Linear,
+    layer_norm: LayerNorm,
+
+    num_attention_heads: usize,
+    attention_head_size: usize,
+    softmax_scale: f64,
+
+    span: tracing::Span,
+}
+
+impl QuantizedBertLayer {
+    fn load(weights: &mut GgufWeights, index: usize, config: &Config) -> Result<Self> {
+        let prefix = format!("blk.{index}");
+        let attention_head_size = config.hidden_size / config.num_attention_heads;
+
+        Ok(Self {
+            query: weights.linear(&format!("{prefix}.attn_q"), None)?,
+            key: weights.linear(&format!("{prefix}.attn_k"), None)?,
+            value: weights.linear(&format!("{prefix}.attn_v"), None)?,
+            attention_output: weights.linear(&format!("{prefix}.attn_output"), None)?,
+            attention_layer_norm: weights
+                .layer_norm(&format!("{prefix}.attn_output_norm"), config.layer_norm_eps)?,
+            intermediate: weights.linear(
+                &format!("{prefix}.ffn_up"),
+                Some(config.hidden_act.clone()),
+            )?,
+            output: weights.linear(&format!("{prefix}.ffn_down"), None)?,
+            layer_norm: weights
+                .layer_norm(&format!("{prefix}.layer_output_norm"), config.layer_norm_eps)?,
+            num_attention_heads: config.num_attention_heads,
+            attention_head_size,
+            softmax_scale: 1. / (attention_head_size as f64).sqrt(),
+            span: tracing::span!(tracing::Level::TRACE, "layer"),
+        })
+    }
+
+    fn split_heads(&self, x: Tensor) -> Result<Tensor> {
+        let (batch_size, seq_len, _) = x.dims3()?;
+        x.reshape((
+            batch_size,
+            seq_len,
+            self.num_attention_heads,
+            self.attention_head_size,
+        ))?
+        .transpose(1, 2)?
+        .contiguous()
+    }
+
+    fn forward(&self, hidden_states: &Tensor, attention_bias: Option<&Tensor>) -> Result<Tensor> {
+        let _enter = self.span.enter();
+
+        let residual = hidden_states.clone();
+
+        let query_layer = self.split_heads(self.query.forward(hidden_states)?)?;
+        let key_layer = self.split_heads(self.key.forward(hidden_states)?)?;
+        let value_layer = self.split_heads(self.value.forward(hidden_states)?)?;
+
+        let attention_scores = query_layer.matmul(&key_layer.t()?)?;
+        let mut attention_scores = (attention_scores * self.softmax_scale)?;
+
+        if let Some(attention_bias) = attention_bias {
+            attention_scores = attention_scores.broadcast_add(attention_bias)?;
+        }
+
+        let attention_probs = candle_nn::ops::softmax_last_dim(&attention_scores)?;
+        let context_layer = attention_probs.matmul(&value_layer)?;
+        let context_layer = context_layer.transpose(1, 2)?.flatten_from(D::Minus2)?;
+
+        let hidden_states = self.attention_output.forward(&context_layer)?;
+        let hidden_states = self.attention_layer_norm.forward(&hidden_states, &residual)?;
+        let residual = hidden_states.clone();
+
+        let hidden_states = self.intermediate.forward(&hidden_states)?;
+        let hidden_states = self.output.forward(&hidden_states)?;
+        self.layer_norm.forward(&hidden_states, &residual)
+    }
+}
+
+pub struct QuantizedBertModel {
+    embeddings: QuantizedBertEmbeddings,
+    layers: Vec<QuantizedBertLayer>,
+    pool: Pool,
+    device: Device,
+
+    span: tracing::Span,
+}
+
+impl QuantizedBertModel {
+    pub fn load(
+        gguf_path: &Path,
+        device: &Device,
+        config: &Config,
+        model_type: ModelType,
+    ) -> Result<Self> {
+        if !matches!(device, Device::Cpu) {
+            candle::bail!("QuantizedBert requires Cpu");
+        }
+
+        // Check position embedding type
+        if config.position_embedding_type != PositionEmbeddingType::Absolute {
+            candle::bail!("QuantizedBert only supports absolute position embeddings")
+        }
+
+        let pool = match model_type {
+            ModelType::Classifier => {
+                candle::bail!("`classifier` model type is not supported for GGUF models")
+            }
+            ModelType::Embedding(Pool::Splade) => {
+                candle::bail!("`splade` pooling is not supported for GGUF models")
+            }
+            ModelType::Embedding(pool) => pool,
+        };
+
+        let mut weights = GgufWeights::open(gguf_path, device)?;
+
+        let embeddings = QuantizedBertEmbeddings::load(&mut weights, config)?;
+        let layers = (0..config.num_hidden_layers)
+            .map(|index| QuantizedBertLayer::load(&mut weights, index, config))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            embeddings,
+            layers,
+            pool,
+            device: device.clone(),
+            span: tracing::span!(tracing::Level::TRACE, "model"),
+        })
+    }
+
+    pub fn forward(&self, batch: Batch) -> Result<(Option<Tensor>, Option<Tensor>)> {
+        let _enter = self.span.enter();
+
+        let batch_size = batch.len();
+        let max_length = batch.max_length as usize;
+        let shape = (batch_size, max_length);
+
+        // Prepare padded batch
+        let elems = batch_size * max_length;
+        let mut input_ids = Vec::with_capacity(elems);
+        let mut type_ids = Vec::with_capacity(elems);
+        let mut position_ids = Vec::with_capacity(elems);
+        let mut attention_bias = Vec::with_capacity(elems);
+        // Bool to know if we need to use the attention bias
+        let mut masking = false;
+
+        for i in 0..batch_size {
+            let start = batch.cumulative_seq_lengths[i] as usize;
+            let end = batch.cumulative_seq_lengths[i + 1] as usize;
+
+            // Copy values
+            for j in start..end {
+                input_ids.push(batch.input_ids[j]);
+                type_ids.push(batch.token_type_ids[j]);
+                position_ids.push(batch.position_ids[j]);
+                attention_bias.push(0.0_f32);
+            }
+
+            // Add padding if needed
+            for _ in (end - start)..max_length {
+                masking = true;
+                input_ids.push(0);
+                type_ids.push(0);
+                position_ids.push(0);
+                attention_bias.push(f32::NEG_INFINITY);
+            }
+        }
+
+        let attention_bias = match masking {
+            true => Some(Tensor::from_vec(
+                attention_bias,
+                (batch_size, 1, 1, max_length),
+                &self.device,
+            )?),
+            false => None,
+        };
+
+        let input_ids = Tensor::from_vec(input_ids, shape, &self.device)?;
+        let type_ids = Tensor::from_vec(type_ids, shape, &self.device)?;
+        let position_ids = Tensor::from_vec(position_ids, shape, &self.device)?;
+
+        let mut outputs = self
+            .embeddings
+            .forward(&input_ids, &type_ids, &position_ids)?;
+        debug::dump("embeddings", &outputs)?;
+        for (index, layer) in self.layers.iter().enumerate() {
+            outputs = layer.forward(&outputs, attention_bias.as_ref())?;
+            debug::dump(&format!("layer.{index}"), &outputs)?;
+        }
+
+        let pooled_embeddings = if !batch.pooled_indices.is_empty() {
+            let pooled_embeddings = match self.pool {
+                // CLS pooling
+                Pool::Cls => outputs.i((.., 0))?,
+                // Mean pooling
+                Pool::Mean => padded_mean_pool(&outputs, &batch.cumulative_seq_lengths, false)?,
+                // Position weighted mean pooling
+                Pool::WeightedMean => {
+                    padded_mean_pool(&outputs, &batch.cumulative_seq_lengths, true)?
+                }
+                // Last token pooling
+                Pool::LastToken => {
+                    // Indices of the last tokens in the flattened outputs
+                    let last_indices: Vec<u32> = (0..batch_size)
+                        .map(|i| {
+                            let length = batch.cumulative_seq_lengths[i + 1]
+                                - batch.cumulative_seq_lengths[i];
+                            (i * max_length) as u32 + length - 1
+                        })
+                        .collect();
+                    let last_indices = Tensor::from_vec(last_indices, batch_size, &self.device)?;
+
+                    let (b, l, h) = outputs.shape().dims3()?;
+                    outputs.reshape((b * l, h))?.index_select(&last_indices, 0)?
+                }
+                Pool::Splade => unreachable!(),
+            };
+
+            let pooled_indices_length = batch.pooled_indices.len();
+            let pooled_indices =
+                Tensor::from_vec(batch.pooled_indices, pooled_indices_length, &self.device)?;
+            Some(pooled_embeddings.index_select(&pooled_indices, 0)?)
+        } else {
+            None
+        };
+
+        let raw_embeddings = if !batch.raw_indices.is_empty() {
+            let (b, l, h) = outputs.shape().dims3()?;
+            let outputs = outputs.reshape((b * l, h))?;
+
+            // Remove the padding tokens
+            let mut final_indices: Vec<u32> = Vec::with_capacity(elems);
+            for i in batch.raw_indices.into_iter() {
+                let start = i * batch.max_length;
+                let i = i as usize;
+                let length = batch.cumulative_seq_lengths[i + 1] - batch.cumulative_seq_lengths[i];
+                final_indices.extend(start..start + length);
+            }
+
+            let final_indices_length = final_indices.len();
+            let final_indices =
+                Tensor::from_vec(final_indices, final_indices_length, &self.device)?;
+            Some(outputs.index_select(&final_indices, 0)?)
+        } else {
+            None
+        };
+
+        Ok((pooled_embeddings, raw_embeddings))
+    }
+}
+
+impl Model for QuantizedBertModel {
+    fn is_padded(&self) -> bool {
+        true
+    }
+
+    fn embed(&self, batch: Batch) -> Result<(Option<Tensor>, Option<Tensor>)> {
+        self.forward(batch)
+    }
+}
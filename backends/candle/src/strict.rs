@@ -0,0 +1,106 @@
+/// Validation of the tensor names of a checkpoint against the tensors read by the model
+use candle::{DType, Device, Shape, Tensor};
+use candle_nn::var_builder::SimpleBackend;
+use candle_nn::Init;
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+use text_embeddings_backend_core::BackendError;
+
+/// Names listed for each side of the diff
+const MAX_LISTED_NAMES: usize = 20;
+
+/// Records the tensors the model asks for while it is loaded
+#[derive(Clone)]
+pub struct TensorTracker {
+    /// Tensors of the checkpoint
+    names: Arc<BTreeSet<String>>,
+    found: Arc<Mutex<BTreeSet<String>>>,
+    missing: Arc<Mutex<BTreeSet<String>>>,
+}
+
+impl TensorTracker {
+    /// Buffers such as `position_ids` are saved by some checkpoints but never read
+    pub fn new(names: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            names: Arc::new(
+                names
+                    .into_iter()
+                    .filter(|name| !name.ends_with("position_ids"))
+                    .collect(),
+            ),
+            found: Arc::new(Mutex::new(BTreeSet::new())),
+            missing: Arc::new(Mutex::new(BTreeSet::new())),
+        }
+    }
+
+    pub fn wrap(&self, tensors: Box<dyn SimpleBackend>) -> Box<dyn SimpleBackend> {
+        Box::new(TrackedTensors {
+            tensors,
+            tracker: self.clone(),
+        })
+    }
+
+    /// Fail if the model could not be loaded or did not read every tensor of the checkpoint.
+    /// The error lists the tensors the model looked for without finding them and the tensors of
+    /// the checkpoint it never read.
+    pub fn check<T>(&self, loaded: Result<T, BackendError>) -> Result<T, BackendError> {
+        let found = self.found.lock().unwrap();
+        let unexpected: Vec<&String> = self.names.difference(&found).collect();
+        match loaded {
+            Err(err) => {
+                let missing = self.missing.lock().unwrap();
+                Err(BackendError::Start(format!(
+                    "{err}\nTensor names of the model (-) and of the checkpoint (+):\n{}",
+                    diff(missing.iter().collect(), unexpected)
+                )))
+            }
+            Ok(_) if !unexpected.is_empty() => Err(BackendError::Start(format!(
+                "The model does not use every tensor of the checkpoint:\n{}",
+                diff(vec![], unexpected)
+            ))),
+            Ok(model) => Ok(model),
+        }
+    }
+}
+
+/// `- name` for the tensors missing from the checkpoint and `+ name` for the unexpected ones
+fn diff(missing: Vec<&String>, unexpected: Vec<&String>) -> String {
+    let mut lines = Vec::new();
+    for (sign, names) in [("-", missing), ("+", unexpected)] {
+        for name in names.iter().take(MAX_LISTED_NAMES) {
+            lines.push(format!("{sign} {name}"));
+        }
+        if names.len() > MAX_LISTED_NAMES {
+            lines.push(format!("{sign} ... and {} more", names.len() - MAX_LISTED_NAMES));
+        }
+    }
+    lines.join("\n")
+}
+
+struct TrackedTensors {
+    tensors: Box<dyn SimpleBackend>,
+    tracker: TensorTracker,
+}
+
+impl SimpleBackend for TrackedTensors {
+    fn get(
+        &self,
+        s: Shape,
+        name: &str,
+        h: Init,
+        dtype: DType,
+        dev: &Device,
+    ) -> candle::Result<Tensor> {
+        let read = if self.tensors.contains_tensor(name) {
+            &self.tracker.found
+        } else {
+            &self.tracker.missing
+        };
+        read.lock().unwrap().insert(name.to_string());
+        self.tensors.get(s, name, h, dtype, dev)
+    }
+
+    fn contains_tensor(&self, name: &str) -> bool {
+        self.tensors.contains_tensor(name)
+    }
+}
@@ -165,5 +165,7 @@ pub fn batch(encodings: Vec<Encoding>, pooled_indices: Vec<u32>, raw_indices: Ve
         max_length,
         pooled_indices,
         raw_indices,
+        normalized_indices: vec![],
+        quantized_indices: vec![],
     }
 }
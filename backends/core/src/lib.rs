@@ -2,9 +2,10 @@
 use clap::ValueEnum;
 use nohash_hasher::IntMap;
 use std::fmt;
+use std::path::PathBuf;
 use thiserror::Error;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Batch {
     pub input_ids: Vec<u32>,
     pub token_type_ids: Vec<u32>,
@@ -13,6 +14,11 @@ pub struct Batch {
     pub max_length: u32,
     pub pooled_indices: Vec<u32>,
     pub raw_indices: Vec<u32>,
+    /// Members of `pooled_indices` whose embedding must be L2 normalized by the backend
+    pub normalized_indices: Vec<u32>,
+    /// Members of `pooled_indices` whose embedding must be quantized by the backend, after
+    /// normalization
+    pub quantized_indices: Vec<(u32, EmbeddingQuantization)>,
 }
 
 impl Batch {
@@ -23,11 +29,140 @@ impl Batch {
     pub fn is_empty(&self) -> bool {
         self.len() == 0
     }
+
+    /// Batch of the `members` of this batch, in this order
+    pub fn select(&self, members: &[usize]) -> Batch {
+        let mut batch = Batch {
+            input_ids: Vec::new(),
+            token_type_ids: Vec::new(),
+            position_ids: Vec::new(),
+            cumulative_seq_lengths: vec![0],
+            max_length: 0,
+            pooled_indices: Vec::new(),
+            raw_indices: Vec::new(),
+            normalized_indices: Vec::new(),
+            quantized_indices: Vec::new(),
+        };
+
+        for (index, &member) in members.iter().enumerate() {
+            let start = self.cumulative_seq_lengths[member] as usize;
+            let end = self.cumulative_seq_lengths[member + 1] as usize;
+
+            batch.input_ids.extend_from_slice(&self.input_ids[start..end]);
+            batch.token_type_ids.extend_from_slice(&self.token_type_ids[start..end]);
+            batch.position_ids.extend_from_slice(&self.position_ids[start..end]);
+            batch.cumulative_seq_lengths.push(batch.input_ids.len() as u32);
+            batch.max_length = batch.max_length.max((end - start) as u32);
+
+            let (member, index) = (member as u32, index as u32);
+            match self.pooled_indices.contains(&member) {
+                true => batch.pooled_indices.push(index),
+                false => batch.raw_indices.push(index),
+            }
+            if self.normalized_indices.contains(&member) {
+                batch.normalized_indices.push(index);
+            }
+            if let Some(quantization) = self.quantization(member) {
+                batch.quantized_indices.push((index, quantization));
+            }
+        }
+        batch
+    }
+
+    /// Quantization of the pooled embedding of `member`
+    pub fn quantization(&self, member: u32) -> Option<EmbeddingQuantization> {
+        self.quantized_indices
+            .iter()
+            .find(|(i, _)| *i == member)
+            .map(|(_, quantization)| *quantization)
+    }
 }
 
 pub enum Embedding {
     Pooled(Vec<f32>),
     All(Vec<Vec<f32>>),
+    Quantized(QuantizedEmbedding),
+}
+
+/// Quantization of a pooled embedding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmbeddingQuantization {
+    /// Symmetric int8 quantization with a scale per embedding
+    Int8,
+    /// Sign of every value
+    Binary,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum QuantizedEmbedding {
+    /// `v ~= value * scale`
+    Int8 { values: Vec<i8>, scale: f32 },
+    /// One bit per value, set if the value is positive. See `pack_bits`
+    Binary(Vec<u8>),
+}
+
+impl QuantizedEmbedding {
+    /// Quantize `embedding` on the host
+    pub fn new(quantization: EmbeddingQuantization, embedding: &[f32]) -> Self {
+        match quantization {
+            EmbeddingQuantization::Int8 => {
+                let max = embedding.iter().fold(0.0_f32, |max, v| max.max(v.abs()));
+                let scale = if max > 0.0 { max / 127.0 } else { 1.0 };
+                let values = embedding
+                    .iter()
+                    .map(|v| (v / scale).round().clamp(-127.0, 127.0) as i8)
+                    .collect();
+                Self::Int8 { values, scale }
+            }
+            EmbeddingQuantization::Binary => {
+                let bits: Vec<u8> = embedding.iter().map(|v| (*v > 0.0) as u8).collect();
+                Self::Binary(pack_bits(&bits))
+            }
+        }
+    }
+}
+
+/// Pack `bits`, that are 0 or 1, in bytes, most significant bit first.
+/// The last byte is zero padded.
+pub fn pack_bits(bits: &[u8]) -> Vec<u8> {
+    bits.chunks(8)
+        .map(|chunk| {
+            chunk
+                .iter()
+                .enumerate()
+                .fold(0_u8, |byte, (i, bit)| byte | ((bit & 1) << (7 - i)))
+        })
+        .collect()
+}
+
+/// Pooled `embedding` of a batch member, quantized with `quantization` if set.
+/// Used by the backends pooling on the host
+pub fn pooled_embedding(
+    embedding: Vec<f32>,
+    quantization: Option<EmbeddingQuantization>,
+) -> Embedding {
+    match quantization {
+        Some(quantization) => {
+            Embedding::Quantized(QuantizedEmbedding::new(quantization, &embedding))
+        }
+        None => Embedding::Pooled(embedding),
+    }
+}
+
+/// L2 normalize `embedding` in place. Used by the backends pooling on the host
+pub fn normalize(embedding: &mut [f32]) {
+    let norm = embedding
+        .iter()
+        .map(|v| {
+            let v = *v as f64;
+            v * v
+        })
+        .sum::<f64>()
+        .sqrt();
+    let scale = (1.0 / norm) as f32;
+    for v in embedding.iter_mut() {
+        *v *= scale;
+    }
 }
 
 pub type Embeddings = IntMap<usize, Embedding>;
@@ -41,6 +176,11 @@ pub trait Backend {
 
     fn is_padded(&self) -> bool;
 
+    /// Whether pooled embeddings are projected by a sentence-transformers `2_Dense` layer
+    fn has_dense(&self) -> bool {
+        false
+    }
+
     fn embed(&self, batch: Batch) -> Result<Embeddings, BackendError>;
 
     fn predict(&self, batch: Batch) -> Result<Predictions, BackendError>;
@@ -57,6 +197,12 @@ pub enum ModelType {
 pub enum Pool {
     Cls,
     Mean,
+    /// Mean weighted by the position of the tokens, as used by SGPT models
+    #[cfg_attr(feature = "clap", value(name = "weighted_mean"))]
+    WeightedMean,
+    #[cfg_attr(feature = "clap", value(name = "last_token"))]
+    LastToken,
+    Splade,
 }
 
 impl fmt::Display for Pool {
@@ -64,10 +210,24 @@ impl fmt::Display for Pool {
         match self {
             Pool::Cls => write!(f, "cls"),
             Pool::Mean => write!(f, "mean"),
+            Pool::WeightedMean => write!(f, "weighted_mean"),
+            Pool::LastToken => write!(f, "last_token"),
+            Pool::Splade => write!(f, "splade"),
         }
     }
 }
 
+/// TensorRT engine configuration
+#[derive(Debug, Clone)]
+pub struct TensorRtConfig {
+    /// Directory where the built engines are cached
+    pub engine_cache_path: PathBuf,
+    /// Maximum batch size of the engine optimization profile
+    pub max_batch_size: usize,
+    /// Maximum sequence length of the engine optimization profile
+    pub max_sequence_length: usize,
+}
+
 #[derive(Debug, Error, Clone)]
 pub enum BackendError {
     #[error("No backend found")]
@@ -79,3 +239,50 @@ pub enum BackendError {
     #[error("Backend is unhealthy")]
     Unhealthy,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quantize_int8() {
+        let embedding = [0.6, -1.0, 0.25, 0.0];
+        let QuantizedEmbedding::Int8 { values, scale } =
+            QuantizedEmbedding::new(EmbeddingQuantization::Int8, &embedding)
+        else {
+            panic!("unexpected enum variant")
+        };
+        assert_eq!(scale, 1.0 / 127.0);
+        assert_eq!(values, vec![76, -127, 32, 0]);
+
+        // All zero embeddings keep a scale of 1
+        let quantized = QuantizedEmbedding::new(EmbeddingQuantization::Int8, &[0.0; 3]);
+        assert_eq!(
+            quantized,
+            QuantizedEmbedding::Int8 {
+                values: vec![0; 3],
+                scale: 1.0
+            }
+        );
+    }
+
+    #[test]
+    fn test_pack_bits() {
+        assert_eq!(pack_bits(&[1, 0, 0, 0, 0, 0, 0, 1]), vec![0b1000_0001]);
+        // The last byte is zero padded
+        assert_eq!(
+            pack_bits(&[1, 1, 1, 1, 0, 0, 0, 0, 1, 0, 1]),
+            vec![0b1111_0000, 0b1010_0000]
+        );
+        assert!(pack_bits(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_quantize_binary() {
+        let embedding = [0.1, -0.2, 0.0, 3.0, -1.0, 2.0, 0.5, -0.5, 1.0];
+        assert_eq!(
+            QuantizedEmbedding::new(EmbeddingQuantization::Binary, &embedding),
+            QuantizedEmbedding::Binary(vec![0b1001_0110, 0b1000_0000])
+        );
+    }
+}
@@ -0,0 +1,452 @@
+use ndarray::{s, Array2, ArrayD, Axis, Ix2, Ix3};
+use nohash_hasher::BuildNoHashHasher;
+use ort::{DynValue, GraphOptimizationLevel, Session};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use text_embeddings_backend_core::{
+    normalize, pooled_embedding, Backend, BackendError, Batch, Embedding, Embeddings, ModelType,
+    Pool, Predictions, TensorRtConfig,
+};
+
+pub struct OrtBackend {
+    session: Session,
+    model_type: ModelType,
+    type_id_name: Option<String>,
+    position_ids: bool,
+    max_batch_size: Option<usize>,
+}
+
+impl OrtBackend {
+    pub fn new(
+        model_path: PathBuf,
+        dtype: String,
+        model_type: ModelType,
+        tensorrt: Option<TensorRtConfig>,
+        openvino_device: Option<String>,
+    ) -> Result<Self, BackendError> {
+        // Check dtype
+        if &dtype != "float32" {
+            return Err(BackendError::Start(format!(
+                "DType {dtype} is not supported"
+            )));
+        }
+
+        if model_type == ModelType::Embedding(Pool::Splade) {
+            return Err(BackendError::Start(
+                "`splade` pooling is not supported by the ONNX Runtime backend".to_string(),
+            ));
+        }
+
+        if model_path.join("2_Dense/config.json").exists() {
+            return Err(BackendError::Start(
+                "`2_Dense` is not supported by the ONNX Runtime backend".to_string(),
+            ));
+        }
+
+        // Get model path
+        let onnx_path = {
+            let default_path = model_path.join("model.onnx");
+            match default_path.exists() {
+                true => default_path,
+                false => model_path.join("onnx/model.onnx"),
+            }
+        };
+
+        // Start onnx session
+        let rocm = openvino_device.is_none() && tensorrt.is_none() && rocm_is_available();
+        let mut session = match &openvino_device {
+            None => {
+                let builder = Session::builder()
+                    .s()?
+                    .with_intra_threads(num_cpus::get())
+                    .s()?
+                    .with_optimization_level(GraphOptimizationLevel::Level3)
+                    .s()?;
+                #[cfg(feature = "rocm")]
+                let builder = match rocm {
+                    true => builder
+                        .with_execution_providers([ort::ROCmExecutionProvider::default()
+                            .build()
+                            .error_on_failure()])
+                        .s()?,
+                    false => builder,
+                };
+                builder.commit_from_file(&onnx_path).s()?
+            }
+            Some(device_type) => openvino_session(&onnx_path, &model_path, device_type)?,
+        };
+
+        // Check if the model requires type tokens and position ids
+        let mut type_id_name = None;
+        let mut position_ids = false;
+        for input in &session.inputs {
+            if &input.name == "token_type_ids" || &input.name == "input_type" {
+                type_id_name = Some(input.name.clone());
+            } else if &input.name == "position_ids" {
+                position_ids = true;
+            }
+        }
+
+        let max_batch_size = tensorrt.as_ref().map(|config| config.max_batch_size);
+
+        match (tensorrt, openvino_device) {
+            (None, None) if rocm => tracing::info!("Starting ONNX Runtime model on ROCm"),
+            (None, None) => tracing::info!("Starting ONNX Runtime model on Cpu"),
+            (None, Some(device_type)) => {
+                tracing::info!("Starting ONNX Runtime model on OpenVINO {device_type}")
+            }
+            (Some(_), Some(_)) => {
+                return Err(BackendError::Start(
+                    "TensorRT and OpenVINO cannot be used at the same time".to_string(),
+                ))
+            }
+            (Some(config), None) => {
+                // The optimization profile needs the input names so we can only rebuild the
+                // session once the CPU session has been inspected
+                let mut input_names = vec!["input_ids", "attention_mask"];
+                if let Some(type_id_name) = &type_id_name {
+                    input_names.push(type_id_name);
+                }
+                if position_ids {
+                    input_names.push("position_ids");
+                }
+                session = tensorrt_session(&onnx_path, &input_names, config)?;
+                tracing::info!("Starting ONNX Runtime model on TensorRT");
+            }
+        }
+
+        Ok(Self {
+            session,
+            model_type,
+            type_id_name,
+            position_ids,
+            max_batch_size,
+        })
+    }
+
+    /// Pad the batch and run the session. Returns the requested output and the attention mask.
+    fn forward(
+        &self,
+        batch: &Batch,
+        output_name: &str,
+    ) -> Result<(ArrayD<f32>, Array2<f32>), BackendError> {
+        let batch_size = batch.len();
+        let max_length = batch.max_length as usize;
+        let elems = batch_size * max_length;
+
+        // Prepare padded batch
+        let mut input_ids = Vec::with_capacity(elems);
+        let mut type_ids = Vec::with_capacity(elems);
+        let mut position_ids = Vec::with_capacity(elems);
+        let mut attention_mask = Vec::with_capacity(elems);
+
+        for i in 0..batch_size {
+            let start = batch.cumulative_seq_lengths[i] as usize;
+            let end = batch.cumulative_seq_lengths[i + 1] as usize;
+
+            // Copy values
+            for j in start..end {
+                input_ids.push(batch.input_ids[j] as i64);
+                type_ids.push(batch.token_type_ids[j] as i64);
+                position_ids.push(batch.position_ids[j] as i64);
+                attention_mask.push(1_i64);
+            }
+
+            // Add padding if needed
+            for _ in (end - start)..max_length {
+                input_ids.push(0);
+                type_ids.push(0);
+                position_ids.push(0);
+                attention_mask.push(0);
+            }
+        }
+
+        let shape = (batch_size, max_length);
+        let input_ids = Array2::from_shape_vec(shape, input_ids).e()?;
+        let attention_mask = Array2::from_shape_vec(shape, attention_mask).e()?;
+        let float_attention_mask = attention_mask.mapv(|v| v as f32);
+
+        // Create onnx inputs
+        let mut inputs = ort::inputs![
+            "input_ids" => input_ids,
+            "attention_mask" => attention_mask,
+        ]
+        .e()?;
+        if let Some(type_id_name) = &self.type_id_name {
+            let type_ids = DynValue::try_from(Array2::from_shape_vec(shape, type_ids).e()?).e()?;
+            inputs.push((type_id_name.clone().into(), type_ids.into()));
+        }
+        if self.position_ids {
+            let position_ids =
+                DynValue::try_from(Array2::from_shape_vec(shape, position_ids).e()?).e()?;
+            inputs.push(("position_ids".into(), position_ids.into()));
+        }
+
+        // Run model
+        let outputs = self.session.run(inputs).e()?;
+        let output = outputs
+            .get(output_name)
+            .ok_or_else(|| {
+                BackendError::Inference(format!("`{output_name}` not found in model outputs"))
+            })?
+            .try_extract_tensor::<f32>()
+            .e()?
+            .into_owned();
+
+        Ok((output, float_attention_mask))
+    }
+}
+
+#[cfg(feature = "tensorrt")]
+fn tensorrt_session(
+    onnx_path: &Path,
+    input_names: &[&str],
+    config: TensorRtConfig,
+) -> Result<Session, BackendError> {
+    use ort::TensorRTExecutionProvider;
+
+    // Engines are only valid for the shapes they were built for
+    let engine_cache_path = config.engine_cache_path.join(format!(
+        "b{}_s{}",
+        config.max_batch_size, config.max_sequence_length
+    ));
+    std::fs::create_dir_all(&engine_cache_path).map_err(|err| {
+        BackendError::Start(format!(
+            "Could not create TensorRT engine cache {engine_cache_path:?}: {err}"
+        ))
+    })?;
+
+    let profile = |batch_size: usize, sequence_length: usize| {
+        input_names
+            .iter()
+            .map(|name| format!("{name}:{batch_size}x{sequence_length}"))
+            .collect::<Vec<_>>()
+            .join(",")
+    };
+
+    let provider = TensorRTExecutionProvider::default()
+        .with_engine_cache(true)
+        .with_engine_cache_path(engine_cache_path.display())
+        .with_timing_cache(true)
+        .with_profile_min_shapes(profile(1, 1))
+        .with_profile_opt_shapes(profile(
+            config.max_batch_size,
+            config.max_sequence_length,
+        ))
+        .with_profile_max_shapes(profile(
+            config.max_batch_size,
+            config.max_sequence_length,
+        ))
+        .build()
+        .error_on_failure();
+
+    tracing::info!("Building TensorRT engine. This can take a few minutes on the first start");
+    Session::builder()
+        .s()?
+        .with_execution_providers([provider])
+        .s()?
+        .commit_from_file(onnx_path)
+        .s()
+}
+
+#[cfg(not(feature = "tensorrt"))]
+fn tensorrt_session(
+    _onnx_path: &Path,
+    _input_names: &[&str],
+    _config: TensorRtConfig,
+) -> Result<Session, BackendError> {
+    Err(BackendError::Start(
+        "TensorRT support was not compiled in. Use the `tensorrt` feature.".to_string(),
+    ))
+}
+
+/// Check if an AMD GPU can be used through the ROCm execution provider
+fn rocm_is_available() -> bool {
+    #[cfg(feature = "rocm")]
+    {
+        use ort::ExecutionProvider;
+        ort::ROCmExecutionProvider::default()
+            .is_available()
+            .unwrap_or(false)
+    }
+    #[cfg(not(feature = "rocm"))]
+    false
+}
+
+#[cfg(feature = "openvino")]
+fn openvino_session(
+    onnx_path: &Path,
+    model_path: &Path,
+    device_type: &str,
+) -> Result<Session, BackendError> {
+    use ort::OpenVINOExecutionProvider;
+
+    // Compiled blobs are cached next to the model weights to skip compilation on restarts
+    let cache_dir = model_path.join("openvino_cache");
+
+    // OpenVINO selects bf16 kernels by itself on CPUs that support them
+    let provider = OpenVINOExecutionProvider::default()
+        .with_device_type(device_type)
+        .with_num_threads(num_cpus::get())
+        .with_cache_dir(cache_dir.display())
+        .build()
+        .error_on_failure();
+
+    Session::builder()
+        .s()?
+        .with_execution_providers([provider])
+        .s()?
+        .commit_from_file(onnx_path)
+        .s()
+}
+
+#[cfg(not(feature = "openvino"))]
+fn openvino_session(
+    _onnx_path: &Path,
+    _model_path: &Path,
+    _device_type: &str,
+) -> Result<Session, BackendError> {
+    Err(BackendError::Start(
+        "OpenVINO support was not compiled in. Use the `openvino` feature.".to_string(),
+    ))
+}
+
+impl Backend for OrtBackend {
+    fn health(&self) -> Result<(), BackendError> {
+        Ok(())
+    }
+
+    fn max_batch_size(&self) -> Option<usize> {
+        self.max_batch_size
+    }
+
+    fn is_padded(&self) -> bool {
+        true
+    }
+
+    fn embed(&self, batch: Batch) -> Result<Embeddings, BackendError> {
+        let pool = match &self.model_type {
+            ModelType::Classifier => {
+                return Err(BackendError::Inference(
+                    "`embed` is not implemented for this model".to_string(),
+                ))
+            }
+            ModelType::Embedding(pool) => pool,
+        };
+
+        let batch_size = batch.len();
+        let (outputs, attention_mask) = self.forward(&batch, "last_hidden_state")?;
+        let outputs = outputs.into_dimensionality::<Ix3>().e()?;
+
+        let mut embeddings =
+            HashMap::with_capacity_and_hasher(batch_size, BuildNoHashHasher::default());
+
+        if !batch.pooled_indices.is_empty() {
+            let pooled_embeddings = match pool {
+                // CLS pooling
+                Pool::Cls => outputs.slice(s![.., 0, ..]).into_owned(),
+                // Mean pooling
+                Pool::Mean => {
+                    // Mask padded values
+                    let masked = &outputs * &attention_mask.clone().insert_axis(Axis(2));
+                    let input_lengths = attention_mask.sum_axis(Axis(1)).insert_axis(Axis(1));
+                    masked.sum_axis(Axis(1)) / input_lengths
+                }
+                // Position weighted mean pooling
+                Pool::WeightedMean => {
+                    let hidden_size = outputs.shape()[2];
+                    let mut pooled = Array2::zeros((batch_size, hidden_size));
+                    for i in 0..batch_size {
+                        let length =
+                            (batch.cumulative_seq_lengths[i + 1] - batch.cumulative_seq_lengths[i])
+                                as usize;
+                        // Tokens are weighted by their position
+                        let weights_sum = (length * (length + 1) / 2) as f32;
+                        for j in 0..length {
+                            let weight = (j + 1) as f32 / weights_sum;
+                            pooled
+                                .row_mut(i)
+                                .scaled_add(weight, &outputs.slice(s![i, j, ..]));
+                        }
+                    }
+                    pooled
+                }
+                // Last token pooling
+                Pool::LastToken => {
+                    let hidden_size = outputs.shape()[2];
+                    Array2::from_shape_fn((batch_size, hidden_size), |(i, j)| {
+                        let length =
+                            batch.cumulative_seq_lengths[i + 1] - batch.cumulative_seq_lengths[i];
+                        outputs[[i, length as usize - 1, j]]
+                    })
+                }
+                Pool::Splade => unreachable!(),
+            };
+
+            for &i in batch.pooled_indices.iter() {
+                let mut e = pooled_embeddings.row(i as usize).to_vec();
+                if batch.normalized_indices.contains(&i) {
+                    normalize(&mut e);
+                }
+                embeddings.insert(i as usize, pooled_embedding(e, batch.quantization(i)));
+            }
+        }
+
+        for i in batch.raw_indices.into_iter() {
+            let i = i as usize;
+            let length =
+                (batch.cumulative_seq_lengths[i + 1] - batch.cumulative_seq_lengths[i]) as usize;
+            let e = outputs
+                .slice(s![i, ..length, ..])
+                .outer_iter()
+                .map(|v| v.to_vec())
+                .collect();
+            embeddings.insert(i, Embedding::All(e));
+        }
+
+        Ok(embeddings)
+    }
+
+    fn predict(&self, batch: Batch) -> Result<Predictions, BackendError> {
+        if !matches!(self.model_type, ModelType::Classifier) {
+            return Err(BackendError::Inference(
+                "`predict` is not implemented for this model".to_string(),
+            ));
+        }
+
+        let batch_size = batch.len();
+        let (outputs, _) = self.forward(&batch, "logits")?;
+        let outputs = outputs.into_dimensionality::<Ix2>().e()?;
+
+        let mut predictions =
+            HashMap::with_capacity_and_hasher(batch_size, BuildNoHashHasher::default());
+        for (i, r) in outputs.outer_iter().enumerate() {
+            predictions.insert(i, r.to_vec());
+        }
+
+        Ok(predictions)
+    }
+}
+
+pub trait WrapErr<O> {
+    fn s(self) -> Result<O, BackendError>;
+    fn e(self) -> Result<O, BackendError>;
+}
+
+impl<O> WrapErr<O> for Result<O, ort::Error> {
+    fn s(self) -> Result<O, BackendError> {
+        self.map_err(|e| BackendError::Start(e.to_string()))
+    }
+    fn e(self) -> Result<O, BackendError> {
+        self.map_err(|e| BackendError::Inference(e.to_string()))
+    }
+}
+
+impl<O> WrapErr<O> for Result<O, ndarray::ShapeError> {
+    fn s(self) -> Result<O, BackendError> {
+        self.map_err(|e| BackendError::Start(e.to_string()))
+    }
+    fn e(self) -> Result<O, BackendError> {
+        self.map_err(|e| BackendError::Inference(e.to_string()))
+    }
+}
@@ -5,7 +5,8 @@ use backend_grpc_client::Client;
 use nohash_hasher::BuildNoHashHasher;
 use std::collections::HashMap;
 use text_embeddings_backend_core::{
-    Backend, BackendError, Batch, Embedding, Embeddings, ModelType, Pool, Predictions,
+    normalize, pooled_embedding, Backend, BackendError, Batch, Embeddings, ModelType, Pool,
+    Predictions,
 };
 use tokio::runtime::Runtime;
 
@@ -80,6 +81,10 @@ impl Backend for PythonBackend {
             ));
         }
         let batch_size = batch.len();
+        let quantizations: Vec<_> = (0..batch_size as u32)
+            .map(|i| batch.quantization(i))
+            .collect();
+        let normalized_indices = batch.normalized_indices;
 
         let results = self
             .tokio_runtime
@@ -95,8 +100,11 @@ impl Backend for PythonBackend {
 
         let mut embeddings =
             HashMap::with_capacity_and_hasher(batch_size, BuildNoHashHasher::default());
-        for (i, e) in pooled_embeddings.into_iter().enumerate() {
-            embeddings.insert(i, Embedding::Pooled(e));
+        for (i, mut e) in pooled_embeddings.into_iter().enumerate() {
+            if normalized_indices.contains(&(i as u32)) {
+                normalize(&mut e);
+            }
+            embeddings.insert(i, pooled_embedding(e, quantizations[i]));
         }
 
         Ok(embeddings)
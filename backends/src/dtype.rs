@@ -3,8 +3,8 @@ use std::fmt;
 #[cfg(feature = "clap")]
 use clap::ValueEnum;
 
-#[derive(Debug, PartialEq)]
-#[cfg_attr(feature = "clap", derive(Clone, ValueEnum))]
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "clap", derive(ValueEnum))]
 pub enum DType {
     // Float16 is not available on accelerate
     #[cfg(any(
@@ -13,8 +13,22 @@ pub enum DType {
     ))]
     Float16,
     // Float32 is not available on candle cuda
-    #[cfg(any(feature = "python", feature = "candle"))]
+    #[cfg(any(
+        feature = "python",
+        feature = "candle",
+        feature = "ort",
+        feature = "wgpu"
+    ))]
     Float32,
+    // Int8 dynamic quantization is only available on candle cpu
+    #[cfg(feature = "candle")]
+    Int8,
+    // Float8 weights are only available on candle cuda
+    #[cfg(all(feature = "candle", feature = "cuda"))]
+    Float8,
+    // Pick the dtype from the device capabilities
+    #[cfg(feature = "candle")]
+    Auto,
     // #[cfg(feature = "candle")]
     // Q6K,
 }
@@ -29,8 +43,19 @@ impl fmt::Display for DType {
             ))]
             DType::Float16 => write!(f, "float16"),
             // Float32 is not available on candle cuda
-            #[cfg(any(feature = "python", feature = "candle"))]
+            #[cfg(any(
+        feature = "python",
+        feature = "candle",
+        feature = "ort",
+        feature = "wgpu"
+    ))]
             DType::Float32 => write!(f, "float32"),
+            #[cfg(feature = "candle")]
+            DType::Int8 => write!(f, "int8"),
+            #[cfg(all(feature = "candle", feature = "cuda"))]
+            DType::Float8 => write!(f, "float8"),
+            #[cfg(feature = "candle")]
+            DType::Auto => write!(f, "auto"),
             // #[cfg(feature = "candle")]
             // DType::Q6K => write!(f, "q6k"),
         }
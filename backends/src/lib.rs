@@ -1,6 +1,8 @@
 mod dtype;
+mod numa;
 
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::thread::JoinHandle;
 use std::time::{Duration, Instant};
@@ -9,93 +11,321 @@ use tokio::sync::{mpsc, oneshot, watch};
 use tracing::{instrument, Span};
 
 pub use crate::dtype::DType;
+pub use crate::numa::NumaPolicy;
 pub use text_embeddings_backend_core::{
-    BackendError, Batch, Embedding, Embeddings, ModelType, Pool,
+    normalize, BackendError, Batch, Embedding, EmbeddingQuantization, Embeddings, ModelType, Pool,
+    QuantizedEmbedding, TensorRtConfig,
 };
 
+/// Environment making the results of the CUDA and MKL libraries reproducible across runs on the
+/// same hardware. Read by the libraries when they are initialized
+const DETERMINISTIC_ENV: [(&str, &str); 3] = [
+    // cuBLAS does not share its workspaces between streams
+    ("CUBLAS_WORKSPACE_CONFIG", ":4096:8"),
+    // Float32 matmuls do not run on TF32 tensor cores
+    ("NVIDIA_TF32_OVERRIDE", "0"),
+    // MKL runs the same code path, and the same reductions, whatever the memory alignment
+    ("MKL_CBWR", "AUTO,STRICT"),
+];
+
+/// Set the variables of `DETERMINISTIC_ENV` that are not set yet. Must be called at startup,
+/// before other threads may read the environment and before the libraries are initialized
+pub fn set_deterministic_env() {
+    for (key, value) in DETERMINISTIC_ENV {
+        if std::env::var_os(key).is_none() {
+            std::env::set_var(key, value);
+        }
+    }
+}
+
 #[cfg(feature = "candle")]
 use text_embeddings_backend_candle::CandleBackend;
 
+#[cfg(feature = "ort")]
+use text_embeddings_backend_ort::OrtBackend;
+
 #[cfg(feature = "python")]
 use text_embeddings_backend_python::PythonBackend;
 
+#[cfg(feature = "wgpu")]
+use text_embeddings_backend_wgpu::WgpuBackend;
+
 #[derive(Debug, Clone)]
 pub struct Backend {
-    /// Channel to communicate with the background thread
-    backend_sender: mpsc::UnboundedSender<BackendCommand>,
-    /// Health status
+    /// Model replicas, one per device
+    replicas: Arc<Vec<Replica>>,
+    /// Health status of every replica, in the order of `replicas`
+    replica_health: Arc<Vec<AtomicBool>>,
+    /// Health status, healthy when all the replicas are healthy
     health_receiver: watch::Receiver<bool>,
-    _backend_thread: Arc<BackendThread>,
     pub padded_model: bool,
+    /// Pooled embeddings are projected by a `2_Dense` layer
+    pub dense_model: bool,
     pub max_batch_size: Option<usize>,
     pub model_type: ModelType,
 }
 
+#[derive(Debug)]
+struct Replica {
+    device_id: usize,
+    /// Channel to communicate with the background thread
+    backend_sender: mpsc::UnboundedSender<BackendCommand>,
+    /// Number of commands sent to the background thread and not yet answered
+    in_flight: AtomicUsize,
+    _backend_thread: BackendThread,
+}
+
 impl Backend {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         model_path: PathBuf,
         dtype: DType,
         model_type: ModelType,
         uds_path: String,
         otlp_endpoint: Option<String>,
+        tensorrt: Option<TensorRtConfig>,
+        openvino_device: Option<String>,
+        data_parallel: bool,
+        numa: NumaPolicy,
+        lora_adapter: Option<PathBuf>,
+        strict_load: bool,
+        deterministic: bool,
+        max_position_embeddings: Option<usize>,
     ) -> Result<Self, BackendError> {
-        let (backend_sender, backend_receiver) = mpsc::unbounded_channel();
-
-        let backend = init_backend(
-            model_path,
-            dtype,
-            model_type.clone(),
-            uds_path,
-            otlp_endpoint,
-        )?;
-        let padded_model = backend.is_padded();
-        let max_batch_size = backend.max_batch_size();
+        if deterministic {
+            if !cfg!(feature = "candle") {
+                return Err(BackendError::Start(
+                    "Deterministic mode is only available with the Candle backend".to_string(),
+                ));
+            }
+            for (key, _) in DETERMINISTIC_ENV {
+                if std::env::var_os(key).is_none() {
+                    tracing::warn!("`{key}` is not set: call `set_deterministic_env` at startup");
+                }
+            }
+        }
+        let placements = replica_placements(data_parallel, numa)?;
 
         let (health_sender, health_receiver) = watch::channel(false);
-        let _backend_thread =
-            Arc::new(BackendThread::new(backend, backend_receiver, health_sender));
+        let health_sender = Arc::new(health_sender);
+        let replica_health: Arc<Vec<AtomicBool>> =
+            Arc::new(placements.iter().map(|_| AtomicBool::new(false)).collect());
+
+        let mut padded_model = false;
+        let mut dense_model = false;
+        let mut max_batch_size = None;
+        let mut replicas = Vec::with_capacity(placements.len());
+        for (index, (device_id, cpus)) in placements.into_iter().enumerate() {
+            // CPU replicas are identified by their NUMA node
+            let pool = match cpus {
+                Some(cpus) => {
+                    tracing::info!("Starting model replica on NUMA node {device_id}: {cpus:?}");
+                    Some(numa::thread_pool(device_id, cpus)?)
+                }
+                None => {
+                    tracing::info!("Starting model replica on device {device_id}");
+                    None
+                }
+            };
+
+            let (backend_sender, backend_receiver) = mpsc::unbounded_channel();
+
+            let init = || {
+                init_backend(
+                    model_path.clone(),
+                    dtype.clone(),
+                    model_type.clone(),
+                    uds_path.clone(),
+                    otlp_endpoint.clone(),
+                    tensorrt.clone(),
+                    openvino_device.clone(),
+                    device_id,
+                    lora_adapter.clone(),
+                    strict_load,
+                    max_position_embeddings,
+                )
+            };
+            let backend = match &pool {
+                Some(pool) => pool.install(init)?,
+                None => init()?,
+            };
+            padded_model = backend.is_padded();
+            dense_model = backend.has_dense();
+            max_batch_size = backend.max_batch_size();
+
+            let _backend_thread = BackendThread::new(
+                backend,
+                backend_receiver,
+                ReplicaHealth {
+                    index,
+                    replicas: replica_health.clone(),
+                    sender: health_sender.clone(),
+                },
+                device_id,
+                pool,
+            );
+
+            replicas.push(Replica {
+                device_id,
+                backend_sender,
+                in_flight: AtomicUsize::new(0),
+                _backend_thread,
+            });
+        }
 
         Ok(Self {
-            backend_sender,
+            replicas: Arc::new(replicas),
+            replica_health,
             health_receiver,
-            _backend_thread,
             padded_model,
+            dense_model,
             max_batch_size,
             model_type,
         })
     }
 
+    /// Number of model replicas that can process batches concurrently
+    pub fn num_replicas(&self) -> usize {
+        self.replicas.len()
+    }
+
     #[instrument(skip(self))]
     pub async fn health(&self) -> Result<(), BackendError> {
-        if *self.health_receiver.borrow() {
-            // The backend is healthy. Only do a basic health check by calling the
-            // the underlying health method.
-
-            let (sender, receiver) = oneshot::channel();
-            self.backend_sender
-                .send(BackendCommand::Health(Span::current(), sender))
-                .expect("No backend receiver. This is a bug.");
-            receiver.await.expect(
-                "Backend blocking task dropped the sender without sending a response. This is a bug.",
-            )
-        } else {
-            // The backend is un-healthy or only just started. Do a more advanced health check
-            // by calling the model forward on a test batch
-
-            let batch = Batch {
-                input_ids: vec![0],
-                token_type_ids: vec![0],
-                position_ids: vec![0],
-                cumulative_seq_lengths: vec![0, 1],
-                max_length: 1,
-                pooled_indices: vec![0],
-                raw_indices: vec![],
-            };
-            match &self.model_type {
-                ModelType::Classifier => self.predict(batch).await.map(|_| ()),
-                ModelType::Embedding(_) => self.embed(batch).await.map(|_| ()),
+        for (replica, healthy) in self.replicas.iter().zip(self.replica_health.iter()) {
+            if healthy.load(Ordering::Relaxed) {
+                // The replica is healthy. Only do a basic health check by calling the
+                // the underlying health method of the replica.
+                let (sender, receiver) = oneshot::channel();
+                let result = replica
+                    .send(BackendCommand::Health(Span::current(), sender), receiver)
+                    .await;
+                if let Err(err) = result {
+                    tracing::error!("Replica on device {} is unhealthy: {err}", replica.device_id);
+                    return Err(err);
+                }
+            } else {
+                // The replica is un-healthy or only just started. Do a more advanced health
+                // check by calling the model forward on a test batch
+                let batch = Batch {
+                    input_ids: vec![0],
+                    token_type_ids: vec![0],
+                    position_ids: vec![0],
+                    cumulative_seq_lengths: vec![0, 1],
+                    max_length: 1,
+                    pooled_indices: vec![0],
+                    raw_indices: vec![],
+                    normalized_indices: vec![],
+                    quantized_indices: vec![],
+                };
+                replica.forward(batch, &self.model_type).await?;
             }
         }
+        Ok(())
+    }
+
+    /// Find the largest token budget that every replica can run, by doubling the number of
+    /// `max_length` sequences in a probe batch until the forward fails or `max_batch_tokens`
+    /// is reached.
+    /// Sequences of `max_length` tokens are the most memory hungry per token so the budget is
+    /// safe for any batch assembled by the queue.
+    #[instrument(skip(self))]
+    pub async fn calibrate_max_batch_tokens(
+        &self,
+        max_length: usize,
+        max_batch_tokens: usize,
+        max_batch_requests: Option<usize>,
+    ) -> Result<usize, BackendError> {
+        let mut budget = None;
+        let mut sequences = 1;
+        while sequences * max_length <= max_batch_tokens
+            && max_batch_requests.map_or(true, |max| sequences <= max)
+        {
+            let tokens = sequences * max_length;
+            let batch = probe_batch(sequences, max_length);
+
+            let mut result = Ok(());
+            for replica in self.replicas.iter() {
+                result = replica.forward(batch.clone(), &self.model_type).await;
+                if result.is_err() {
+                    break;
+                }
+            }
+            match result {
+                Ok(()) => {
+                    tracing::info!("Batch of {tokens} tokens fits on all replicas");
+                    budget = Some(tokens);
+                    sequences *= 2;
+                }
+                Err(err) => {
+                    tracing::info!("Batch of {tokens} tokens failed: {err}");
+                    break;
+                }
+            }
+        }
+
+        budget.ok_or_else(|| {
+            BackendError::Start(format!(
+                "A single sequence of {max_length} tokens does not fit in `max_batch_tokens` or \
+                 failed to run"
+            ))
+        })
+    }
+
+    /// Run the largest batch the queue can assemble on every replica, so that kernels are
+    /// compiled, workspaces are allocated and out of memory errors are raised before the server
+    /// accepts requests instead of during the first ones.
+    #[instrument(skip(self))]
+    pub async fn warmup(
+        &self,
+        max_length: usize,
+        max_batch_tokens: usize,
+        max_batch_requests: Option<usize>,
+    ) -> Result<(), BackendError> {
+        // Longer inputs are rejected by the validation
+        let max_length = max_length.min(max_batch_tokens);
+        let mut sequences = max_batch_tokens / max_length;
+        if let Some(max_batch_requests) = max_batch_requests {
+            sequences = sequences.min(max_batch_requests);
+        }
+        let batch = probe_batch(sequences.max(1), max_length);
+
+        for replica in self.replicas.iter() {
+            let device_id = replica.device_id;
+            let start = Instant::now();
+            replica
+                .forward(batch.clone(), &self.model_type)
+                .await
+                .map_err(|err| {
+                    BackendError::Start(format!("Warmup failed on device {device_id}: {err}"))
+                })?;
+            tracing::info!("Warmup of device {device_id} took {:?}", start.elapsed());
+        }
+        Ok(())
+    }
+
+    /// Run the same sequence twice on every replica and fail unless all the outputs are
+    /// bit-exact, so that a deterministic server does not start on a device or with kernels that
+    /// do not reproduce their results.
+    #[instrument(skip(self))]
+    pub async fn check_determinism(&self, max_length: usize) -> Result<(), BackendError> {
+        let batch = probe_batch(1, max_length);
+        let mut reference = None;
+        for replica in self.replicas.iter() {
+            for _ in 0..2 {
+                let bits = replica.output_bits(batch.clone(), &self.model_type).await?;
+                match &reference {
+                    None => reference = Some(bits),
+                    Some(reference) if *reference != bits => {
+                        return Err(BackendError::Start(format!(
+                            "The outputs of device {} are not reproducible",
+                            replica.device_id
+                        )))
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+        Ok(())
     }
 
     #[instrument(skip(self))]
@@ -107,38 +337,310 @@ impl Backend {
     pub async fn embed(&self, batch: Batch) -> Result<(Embeddings, Duration), BackendError> {
         let (sender, receiver) = oneshot::channel();
 
-        self.backend_sender
-            .send(BackendCommand::Embed(batch, Span::current(), sender))
+        self.least_loaded()
+            .send(BackendCommand::Embed(batch, Span::current(), sender), receiver)
+            .await
+    }
+
+    /// Run `batch` in sub-batches of at most `max_tokens` tokens, shortest members first.
+    /// `on_results` is called with the embeddings of every sub-batch, keyed by their index in
+    /// `batch`, as soon as the sub-batch is computed. The remaining sub-batches are not run if
+    /// one fails.
+    #[instrument(skip_all)]
+    pub async fn embed_stream(
+        &self,
+        batch: Batch,
+        max_tokens: usize,
+        mut on_results: impl FnMut(Result<(Embeddings, Duration), BackendError>),
+    ) {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+
+        let replica = self.least_loaded();
+        replica.in_flight.fetch_add(1, Ordering::Relaxed);
+        replica
+            .backend_sender
+            .send(BackendCommand::EmbedStream(batch, max_tokens, Span::current(), sender))
             .expect("No backend receiver. This is a bug.");
-        receiver.await.expect(
-            "Backend blocking task dropped the sender without send a response. This is a bug.",
-        )
+        while let Some(results) = receiver.recv().await {
+            on_results(results);
+        }
+        replica.in_flight.fetch_sub(1, Ordering::Relaxed);
     }
 
     #[instrument(skip_all)]
     pub async fn predict(&self, batch: Batch) -> Result<(Predictions, Duration), BackendError> {
         let (sender, receiver) = oneshot::channel();
 
+        self.least_loaded()
+            .send(
+                BackendCommand::Predict(batch, Span::current(), sender),
+                receiver,
+            )
+            .await
+    }
+
+    /// Pick the replica with the fewest in-flight commands, healthy replicas first
+    fn least_loaded(&self) -> &Replica {
+        self.replicas
+            .iter()
+            .zip(self.replica_health.iter())
+            .min_by_key(|(replica, healthy)| {
+                (
+                    !healthy.load(Ordering::Relaxed),
+                    replica.in_flight.load(Ordering::Relaxed),
+                )
+            })
+            .map(|(replica, _)| replica)
+            .expect("Backend has no replica. This is a bug.")
+    }
+}
+
+impl Replica {
+    /// Run the model forward on `batch` and discard the results
+    async fn forward(&self, batch: Batch, model_type: &ModelType) -> Result<(), BackendError> {
+        match model_type {
+            ModelType::Classifier => {
+                let (sender, receiver) = oneshot::channel();
+                self.send(
+                    BackendCommand::Predict(batch, Span::current(), sender),
+                    receiver,
+                )
+                .await
+                .map(|_| ())
+            }
+            ModelType::Embedding(_) => {
+                let (sender, receiver) = oneshot::channel();
+                self.send(
+                    BackendCommand::Embed(batch, Span::current(), sender),
+                    receiver,
+                )
+                .await
+                .map(|_| ())
+            }
+        }
+    }
+
+    /// Run the model forward on `batch` and return the bits of its outputs
+    async fn output_bits(
+        &self,
+        batch: Batch,
+        model_type: &ModelType,
+    ) -> Result<Vec<u32>, BackendError> {
+        let values: Vec<f32> = match model_type {
+            ModelType::Classifier => {
+                let (sender, receiver) = oneshot::channel();
+                let (predictions, _) = self
+                    .send(
+                        BackendCommand::Predict(batch, Span::current(), sender),
+                        receiver,
+                    )
+                    .await?;
+                predictions.into_values().flatten().collect()
+            }
+            ModelType::Embedding(_) => {
+                let (sender, receiver) = oneshot::channel();
+                let (embeddings, _) = self
+                    .send(
+                        BackendCommand::Embed(batch, Span::current(), sender),
+                        receiver,
+                    )
+                    .await?;
+                embeddings
+                    .into_values()
+                    .flat_map(|embedding| match embedding {
+                        Embedding::Pooled(values) => values,
+                        Embedding::All(values) => values.into_iter().flatten().collect(),
+                        Embedding::Quantized(QuantizedEmbedding::Int8 { values, scale }) => {
+                            values.into_iter().map(f32::from).chain([scale]).collect()
+                        }
+                        Embedding::Quantized(QuantizedEmbedding::Binary(values)) => {
+                            values.into_iter().map(f32::from).collect()
+                        }
+                    })
+                    .collect()
+            }
+        };
+        Ok(values.into_iter().map(f32::to_bits).collect())
+    }
+
+    async fn send<T>(
+        &self,
+        cmd: BackendCommand,
+        receiver: oneshot::Receiver<Result<T, BackendError>>,
+    ) -> Result<T, BackendError> {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
         self.backend_sender
-            .send(BackendCommand::Predict(batch, Span::current(), sender))
+            .send(cmd)
             .expect("No backend receiver. This is a bug.");
-        receiver.await.expect(
+        let result = receiver.await.expect(
             "Backend blocking task dropped the sender without send a response. This is a bug.",
-        )
+        );
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+        result
+    }
+}
+
+/// Split `batch` in sub-batches of at most `max_tokens` tokens, shortest members first.
+/// Returns the members of every sub-batch with their index in `batch`.
+fn split_batch(batch: &Batch, max_tokens: usize) -> Vec<(Vec<usize>, Batch)> {
+    let length = |i: usize| {
+        (batch.cumulative_seq_lengths[i + 1] - batch.cumulative_seq_lengths[i]) as usize
+    };
+    let mut order: Vec<usize> = (0..batch.len()).collect();
+    order.sort_by_key(|&i| length(i));
+
+    let mut groups = Vec::new();
+    let mut members = Vec::new();
+    let mut tokens = 0;
+    for i in order {
+        // A member longer than `max_tokens` is run on its own
+        if !members.is_empty() && tokens + length(i) > max_tokens {
+            groups.push(std::mem::take(&mut members));
+            tokens = 0;
+        }
+        members.push(i);
+        tokens += length(i);
     }
+    if !members.is_empty() {
+        groups.push(members);
+    }
+
+    groups
+        .into_iter()
+        .map(|members| {
+            let sub_batch = batch.select(&members);
+            (members, sub_batch)
+        })
+        .collect()
 }
 
-#[allow(unused)]
+/// Batch of `sequences` dummy sequences of `max_length` tokens
+fn probe_batch(sequences: usize, max_length: usize) -> Batch {
+    let tokens = sequences * max_length;
+    Batch {
+        input_ids: vec![0; tokens],
+        token_type_ids: vec![0; tokens],
+        position_ids: (0..sequences).flat_map(|_| 0..max_length as u32).collect(),
+        cumulative_seq_lengths: (0..=sequences).map(|i| (i * max_length) as u32).collect(),
+        max_length: max_length as u32,
+        pooled_indices: (0..sequences as u32).collect(),
+        raw_indices: vec![],
+        normalized_indices: vec![],
+        quantized_indices: vec![],
+    }
+}
+
+/// Devices on which a model replica is started.
+/// Replicas placed on a NUMA node get the CPUs of the node and the node id as device id.
+fn replica_placements(
+    data_parallel: bool,
+    numa: NumaPolicy,
+) -> Result<Vec<(usize, Option<Vec<usize>>)>, BackendError> {
+    if numa != NumaPolicy::None {
+        if data_parallel {
+            return Err(BackendError::Start(
+                "NUMA policies cannot be combined with data parallelism".to_string(),
+            ));
+        }
+        #[cfg(feature = "candle")]
+        if text_embeddings_backend_candle::cuda_device_count() > 0 {
+            return Err(BackendError::Start(
+                "NUMA policies are only available when the model runs on CPU".to_string(),
+            ));
+        }
+
+        let nodes = numa::nodes()?;
+        let nodes = match numa {
+            NumaPolicy::Bind => nodes.into_iter().take(1).collect(),
+            _ => nodes,
+        };
+        return Ok(nodes
+            .into_iter()
+            .map(|(node, cpus)| (node, Some(cpus)))
+            .collect());
+    }
+
+    if !data_parallel {
+        return Ok(vec![(0, None)]);
+    }
+
+    #[cfg(feature = "candle")]
+    {
+        let count = text_embeddings_backend_candle::cuda_device_count();
+        if count > 0 {
+            return Ok((0..count).map(|device_id| (device_id, None)).collect());
+        }
+    }
+
+    Err(BackendError::Start(
+        "Data parallelism requires the Candle backend and at least one CUDA device".to_string(),
+    ))
+}
+
+#[allow(unused, clippy::too_many_arguments)]
 fn init_backend(
     model_path: PathBuf,
     dtype: DType,
     model_type: ModelType,
     uds_path: String,
     otlp_endpoint: Option<String>,
+    tensorrt: Option<TensorRtConfig>,
+    openvino_device: Option<String>,
+    device_id: usize,
+    lora_adapter: Option<PathBuf>,
+    strict_load: bool,
+    max_position_embeddings: Option<usize>,
 ) -> Result<Box<dyn CoreBackend + Send>, BackendError> {
+    if tensorrt.is_some() && !cfg!(feature = "ort") {
+        return Err(BackendError::Start(
+            "TensorRT is only available with the ONNX Runtime backend".to_string(),
+        ));
+    }
+    if openvino_device.is_some() && !cfg!(feature = "ort") {
+        return Err(BackendError::Start(
+            "OpenVINO is only available with the ONNX Runtime backend".to_string(),
+        ));
+    }
+    if lora_adapter.is_some() && !cfg!(feature = "candle") {
+        return Err(BackendError::Start(
+            "LoRA adapters are only available with the Candle backend".to_string(),
+        ));
+    }
+    if strict_load && !cfg!(feature = "candle") {
+        return Err(BackendError::Start(
+            "Strict loading is only available with the Candle backend".to_string(),
+        ));
+    }
+    if max_position_embeddings.is_some() && !cfg!(feature = "candle") {
+        return Err(BackendError::Start(
+            "Extending the maximum input length is only available with the Candle backend"
+                .to_string(),
+        ));
+    }
+
     if cfg!(feature = "candle") {
         #[cfg(feature = "candle")]
-        return Ok(Box::new(CandleBackend::new(
+        return Ok(Box::new(CandleBackend::new_on_device(
+            model_path,
+            dtype.to_string(),
+            model_type,
+            device_id,
+            lora_adapter.as_deref(),
+            strict_load,
+            max_position_embeddings,
+        )?));
+    } else if cfg!(feature = "ort") {
+        #[cfg(feature = "ort")]
+        return Ok(Box::new(OrtBackend::new(
+            model_path,
+            dtype.to_string(),
+            model_type,
+            tensorrt,
+            openvino_device,
+        )?));
+    } else if cfg!(feature = "wgpu") {
+        #[cfg(feature = "wgpu")]
+        return Ok(Box::new(WgpuBackend::new(
             model_path,
             dtype.to_string(),
             model_type,
@@ -164,6 +666,29 @@ fn init_backend(
     Err(BackendError::NoBackend)
 }
 
+/// Health status of a replica, shared with the other replicas of the backend
+struct ReplicaHealth {
+    /// Index of the replica
+    index: usize,
+    replicas: Arc<Vec<AtomicBool>>,
+    /// Health status of the backend
+    sender: Arc<watch::Sender<bool>>,
+}
+
+impl ReplicaHealth {
+    fn set(&self, healthy: bool) {
+        self.replicas[self.index].store(healthy, Ordering::Relaxed);
+        // Computed under the lock of the channel so that concurrent updates of the replicas
+        // cannot send a stale status
+        self.sender.send_modify(|backend_healthy| {
+            *backend_healthy = self
+                .replicas
+                .iter()
+                .all(|healthy| healthy.load(Ordering::Relaxed))
+        });
+    }
+}
+
 #[derive(Debug)]
 struct BackendThread(Option<JoinHandle<()>>);
 
@@ -171,33 +696,79 @@ impl BackendThread {
     fn new(
         backend: Box<dyn CoreBackend + Send>,
         mut backend_receiver: mpsc::UnboundedReceiver<BackendCommand>,
-        health_sender: watch::Sender<bool>,
+        health: ReplicaHealth,
+        device_id: usize,
+        pool: Option<rayon::ThreadPool>,
     ) -> Self {
+        let device = device_id.to_string();
         let handle = std::thread::spawn(move || {
-            while let Some(cmd) = backend_receiver.blocking_recv() {
-                let start = Instant::now();
-                let mut healthy = false;
-                match cmd {
-                    BackendCommand::Health(span, sender) => {
-                        let _span = span.entered();
-                        let _ = sender.send(backend.health().map(|_| healthy = true));
-                    }
-                    BackendCommand::Embed(batch, span, sender) => {
-                        let _span = span.entered();
-                        let _ = sender.send(backend.embed(batch).map(|e| {
-                            healthy = true;
-                            (e, start.elapsed())
-                        }));
-                    }
-                    BackendCommand::Predict(batch, span, sender) => {
-                        let _span = span.entered();
-                        let _ = sender.send(backend.predict(batch).map(|e| {
-                            healthy = true;
-                            (e, start.elapsed())
-                        }));
-                    }
-                };
-                let _ = health_sender.send(healthy);
+            let run = move || {
+                while let Some(cmd) = backend_receiver.blocking_recv() {
+                    let start = Instant::now();
+                    let mut healthy = false;
+                    match cmd {
+                        BackendCommand::Health(span, sender) => {
+                            let _span = span.entered();
+                            let _ = sender.send(backend.health().map(|_| healthy = true));
+                        }
+                        BackendCommand::Embed(batch, span, sender) => {
+                            let _span = span.entered();
+                            metrics::increment_counter!(
+                                "te_backend_replica_batch_count",
+                                "device" => device.clone()
+                            );
+                            let _ = sender.send(backend.embed(batch).map(|e| {
+                                healthy = true;
+                                (e, start.elapsed())
+                            }));
+                        }
+                        BackendCommand::EmbedStream(batch, max_tokens, span, sender) => {
+                            let _span = span.entered();
+                            metrics::increment_counter!(
+                                "te_backend_replica_batch_count",
+                                "device" => device.clone()
+                            );
+                            for (members, sub_batch) in split_batch(&batch, max_tokens) {
+                                let results = backend.embed(sub_batch).map(|embeddings| {
+                                    // Key the embeddings by their index in `batch`
+                                    let embeddings = embeddings
+                                        .into_iter()
+                                        .map(|(i, embedding)| (members[i], embedding))
+                                        .collect();
+                                    (embeddings, start.elapsed())
+                                });
+                                healthy = results.is_ok();
+                                let _ = sender.send(results);
+                                if !healthy {
+                                    break;
+                                }
+                            }
+                        }
+                        BackendCommand::Predict(batch, span, sender) => {
+                            let _span = span.entered();
+                            metrics::increment_counter!(
+                                "te_backend_replica_batch_count",
+                                "device" => device.clone()
+                            );
+                            let _ = sender.send(backend.predict(batch).map(|e| {
+                                healthy = true;
+                                (e, start.elapsed())
+                            }));
+                        }
+                    };
+                    metrics::gauge!(
+                        "te_backend_replica_healthy",
+                        if healthy { 1.0 } else { 0.0 },
+                        "device" => device.clone()
+                    );
+                    health.set(healthy);
+                }
+            };
+
+            match pool {
+                // The kernels of the backend use the pool of the replica
+                Some(pool) => pool.install(run),
+                None => run(),
             }
         });
         Self(Some(handle))
@@ -223,4 +794,10 @@ enum BackendCommand {
         #[allow(clippy::type_complexity)]
         oneshot::Sender<Result<(Predictions, Duration), BackendError>>,
     ),
+    EmbedStream(
+        Batch,
+        usize,
+        Span,
+        mpsc::UnboundedSender<Result<(Embeddings, Duration), BackendError>>,
+    ),
 }
@@ -0,0 +1,112 @@
+/// NUMA placement of the model replicas running on CPU
+use crate::BackendError;
+
+#[cfg(feature = "clap")]
+use clap::ValueEnum;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "clap", derive(ValueEnum))]
+pub enum NumaPolicy {
+    /// Let the OS schedule the threads of the replica
+    None,
+    /// Run a single replica pinned to the CPUs of the first NUMA node
+    Bind,
+    /// Run a replica on every NUMA node, each pinned to the CPUs of its node
+    ReplicaPerNode,
+}
+
+/// CPUs of every NUMA node of the machine, ordered by node id
+#[cfg(target_os = "linux")]
+pub(crate) fn nodes() -> Result<Vec<(usize, Vec<usize>)>, BackendError> {
+    let mut nodes = Vec::new();
+    for entry in std::fs::read_dir("/sys/devices/system/node").map_err(read_error)? {
+        let path = entry.map_err(read_error)?.path();
+        let Some(node) = path
+            .file_name()
+            .and_then(|name| name.to_str()?.strip_prefix("node")?.parse::<usize>().ok())
+        else {
+            continue;
+        };
+
+        let cpulist = std::fs::read_to_string(path.join("cpulist")).map_err(read_error)?;
+        let cpus = parse_cpulist(cpulist.trim())?;
+        // Memory only nodes have no CPU
+        if !cpus.is_empty() {
+            nodes.push((node, cpus));
+        }
+    }
+    nodes.sort();
+
+    if nodes.is_empty() {
+        return Err(BackendError::Start("No NUMA node with CPUs found".to_string()));
+    }
+    Ok(nodes)
+}
+
+#[cfg(target_os = "linux")]
+fn read_error(err: std::io::Error) -> BackendError {
+    BackendError::Start(format!("Could not read the NUMA topology: {err}"))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn nodes() -> Result<Vec<(usize, Vec<usize>)>, BackendError> {
+    Err(BackendError::Start("NUMA policies are only supported on Linux".to_string()))
+}
+
+/// Parse a list of CPU ranges such as `0-3,8-11`
+#[cfg(target_os = "linux")]
+fn parse_cpulist(cpulist: &str) -> Result<Vec<usize>, BackendError> {
+    let invalid = || BackendError::Start(format!("Invalid NUMA node cpulist `{cpulist}`"));
+
+    let mut cpus = Vec::new();
+    for range in cpulist.split(',').filter(|range| !range.is_empty()) {
+        match range.split_once('-') {
+            Some((start, end)) => {
+                let start: usize = start.parse().map_err(|_| invalid())?;
+                let end: usize = end.parse().map_err(|_| invalid())?;
+                cpus.extend(start..=end);
+            }
+            None => cpus.push(range.parse().map_err(|_| invalid())?),
+        }
+    }
+    Ok(cpus)
+}
+
+/// Thread pool of a replica, with every thread pinned to `cpus`.
+/// The backend is loaded and run on this pool so that its kernels, the threads it spawns and the
+/// memory it first touches stay on the node.
+pub(crate) fn thread_pool(
+    node: usize,
+    cpus: Vec<usize>,
+) -> Result<rayon::ThreadPool, BackendError> {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(cpus.len())
+        .thread_name(move |i| format!("numa-{node}-{i}"))
+        .start_handler(move |_| {
+            if let Err(err) = pin_current_thread(&cpus) {
+                tracing::warn!("Could not pin thread to NUMA node {node}: {err}");
+            }
+        })
+        .build()
+        .map_err(|err| BackendError::Start(format!("Could not start thread pool: {err}")))
+}
+
+#[cfg(target_os = "linux")]
+fn pin_current_thread(cpus: &[usize]) -> std::io::Result<()> {
+    // Safety: `set` is a plain bit mask that outlives the call
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for &cpu in cpus {
+            libc::CPU_SET(cpu, &mut set);
+        }
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_current_thread(_cpus: &[usize]) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
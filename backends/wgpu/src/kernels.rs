@@ -0,0 +1,245 @@
+use bytemuck::{Pod, Zeroable};
+use std::borrow::Cow;
+use wgpu::util::DeviceExt;
+
+const WG_2D: u32 = 16;
+
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable)]
+struct MatmulParams {
+    m: u32,
+    n: u32,
+    k: u32,
+    act: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable)]
+struct LayerNormParams {
+    cols: u32,
+    eps: f32,
+    _pad: [u32; 2],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable)]
+struct EmbeddingsParams {
+    hidden: u32,
+    _pad: [u32; 3],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable)]
+struct AttentionParams {
+    max_len: u32,
+    num_heads: u32,
+    head_size: u32,
+    scale: f32,
+}
+
+/// Activation fused in the matmul epilogue
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Activation {
+    None = 0,
+    Gelu = 1,
+    Relu = 2,
+}
+
+/// Compiled WGSL compute pipelines
+pub(crate) struct Kernels {
+    matmul: wgpu::ComputePipeline,
+    layer_norm: wgpu::ComputePipeline,
+    embeddings: wgpu::ComputePipeline,
+    attention: wgpu::ComputePipeline,
+}
+
+impl Kernels {
+    pub fn new(device: &wgpu::Device) -> Self {
+        let pipeline = |label: &str, source: &'static str| {
+            let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some(label),
+                source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(source)),
+            });
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(label),
+                layout: None,
+                module: &module,
+                entry_point: "main",
+            })
+        };
+
+        Self {
+            matmul: pipeline("matmul", include_str!("shaders/matmul.wgsl")),
+            layer_norm: pipeline("layer_norm", include_str!("shaders/layer_norm.wgsl")),
+            embeddings: pipeline("embeddings", include_str!("shaders/embeddings.wgsl")),
+            attention: pipeline("attention", include_str!("shaders/attention.wgsl")),
+        }
+    }
+
+    /// out[m, n] = act(x[m, k] . w[n, k]^T + b[n])
+    #[allow(clippy::too_many_arguments)]
+    pub fn matmul(
+        &self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        x: &wgpu::Buffer,
+        w: &wgpu::Buffer,
+        b: &wgpu::Buffer,
+        out: &wgpu::Buffer,
+        (m, n, k): (usize, usize, usize),
+        act: Activation,
+    ) {
+        let params = MatmulParams {
+            m: m as u32,
+            n: n as u32,
+            k: k as u32,
+            act: act as u32,
+        };
+        dispatch(
+            device,
+            encoder,
+            &self.matmul,
+            &[x, w, b, out],
+            bytemuck::bytes_of(&params),
+            (div_ceil(n, WG_2D), div_ceil(m, WG_2D), 1),
+        );
+    }
+
+    /// out[row] = layer_norm(x[row] + residual[row]) * gamma + beta
+    #[allow(clippy::too_many_arguments)]
+    pub fn layer_norm(
+        &self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        x: &wgpu::Buffer,
+        residual: &wgpu::Buffer,
+        gamma: &wgpu::Buffer,
+        beta: &wgpu::Buffer,
+        out: &wgpu::Buffer,
+        (rows, cols): (usize, usize),
+        eps: f32,
+    ) {
+        let params = LayerNormParams {
+            cols: cols as u32,
+            eps,
+            _pad: [0; 2],
+        };
+        dispatch(
+            device,
+            encoder,
+            &self.layer_norm,
+            &[x, residual, gamma, beta, out],
+            bytemuck::bytes_of(&params),
+            (rows as u32, 1, 1),
+        );
+    }
+
+    /// Sum word and token type embeddings into `out` and gather position embeddings into
+    /// `position_out`
+    #[allow(clippy::too_many_arguments)]
+    pub fn embeddings(
+        &self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        ids: [&wgpu::Buffer; 3],
+        tables: [&wgpu::Buffer; 3],
+        out: &wgpu::Buffer,
+        position_out: &wgpu::Buffer,
+        (tokens, hidden): (usize, usize),
+    ) {
+        let params = EmbeddingsParams {
+            hidden: hidden as u32,
+            _pad: [0; 3],
+        };
+        dispatch(
+            device,
+            encoder,
+            &self.embeddings,
+            &[
+                ids[0],
+                ids[1],
+                ids[2],
+                tables[0],
+                tables[1],
+                tables[2],
+                out,
+                position_out,
+            ],
+            bytemuck::bytes_of(&params),
+            (tokens as u32, 1, 1),
+        );
+    }
+
+    /// Multi-head attention over the fused `qkv` projection of a padded batch
+    #[allow(clippy::too_many_arguments)]
+    pub fn attention(
+        &self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        qkv: &wgpu::Buffer,
+        lengths: &wgpu::Buffer,
+        out: &wgpu::Buffer,
+        (batch_size, max_len): (usize, usize),
+        (num_heads, head_size): (usize, usize),
+    ) {
+        let params = AttentionParams {
+            max_len: max_len as u32,
+            num_heads: num_heads as u32,
+            head_size: head_size as u32,
+            scale: 1.0 / (head_size as f32).sqrt(),
+        };
+        dispatch(
+            device,
+            encoder,
+            &self.attention,
+            &[qkv, lengths, out],
+            bytemuck::bytes_of(&params),
+            ((batch_size * max_len) as u32, num_heads as u32, 1),
+        );
+    }
+}
+
+/// Bind `buffers` in order followed by a uniform buffer holding `params` and record a dispatch
+fn dispatch(
+    device: &wgpu::Device,
+    encoder: &mut wgpu::CommandEncoder,
+    pipeline: &wgpu::ComputePipeline,
+    buffers: &[&wgpu::Buffer],
+    params: &[u8],
+    workgroups: (u32, u32, u32),
+) {
+    let params = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("params"),
+        contents: params,
+        usage: wgpu::BufferUsages::UNIFORM,
+    });
+
+    let entries: Vec<wgpu::BindGroupEntry> = buffers
+        .iter()
+        .copied()
+        .chain(std::iter::once(&params))
+        .enumerate()
+        .map(|(i, buffer)| wgpu::BindGroupEntry {
+            binding: i as u32,
+            resource: buffer.as_entire_binding(),
+        })
+        .collect();
+
+    let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: None,
+        layout: &pipeline.get_bind_group_layout(0),
+        entries: &entries,
+    });
+
+    let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+        label: None,
+        timestamp_writes: None,
+    });
+    pass.set_pipeline(pipeline);
+    pass.set_bind_group(0, &bind_group, &[]);
+    pass.dispatch_workgroups(workgroups.0, workgroups.1, workgroups.2);
+}
+
+fn div_ceil(a: usize, b: u32) -> u32 {
+    ((a as u32) + b - 1) / b
+}
@@ -0,0 +1,589 @@
+mod kernels;
+
+use crate::kernels::{Activation, Kernels};
+use nohash_hasher::BuildNoHashHasher;
+use safetensors::{Dtype, SafeTensors};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use text_embeddings_backend_core::{
+    normalize, pooled_embedding, Backend, BackendError, Batch, Embedding, Embeddings, ModelType,
+    Pool, Predictions,
+};
+use wgpu::util::DeviceExt;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum HiddenAct {
+    #[serde(alias = "gelu_pytorch_tanh", alias = "gelu_new")]
+    Gelu,
+    Relu,
+}
+
+#[derive(Debug, Deserialize)]
+struct Config {
+    hidden_size: usize,
+    num_hidden_layers: usize,
+    num_attention_heads: usize,
+    intermediate_size: usize,
+    hidden_act: HiddenAct,
+    layer_norm_eps: f64,
+    model_type: Option<String>,
+}
+
+struct Linear {
+    weight: wgpu::Buffer,
+    bias: wgpu::Buffer,
+    out_features: usize,
+    in_features: usize,
+}
+
+struct LayerNorm {
+    weight: wgpu::Buffer,
+    bias: wgpu::Buffer,
+}
+
+struct Layer {
+    qkv: Linear,
+    attention_output: Linear,
+    attention_layer_norm: LayerNorm,
+    intermediate: Linear,
+    output: Linear,
+    layer_norm: LayerNorm,
+}
+
+pub struct WgpuBackend {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    kernels: Kernels,
+
+    word_embeddings: wgpu::Buffer,
+    token_type_embeddings: wgpu::Buffer,
+    position_embeddings: wgpu::Buffer,
+    embeddings_layer_norm: LayerNorm,
+    layers: Vec<Layer>,
+
+    hidden_size: usize,
+    num_attention_heads: usize,
+    intermediate_size: usize,
+    activation: Activation,
+    layer_norm_eps: f32,
+    pool: Pool,
+}
+
+impl WgpuBackend {
+    pub fn new(
+        model_path: PathBuf,
+        dtype: String,
+        model_type: ModelType,
+    ) -> Result<Self, BackendError> {
+        // Check dtype
+        if &dtype != "float32" {
+            return Err(BackendError::Start(format!(
+                "DType {dtype} is not supported"
+            )));
+        }
+
+        let pool = match model_type {
+            ModelType::Classifier => {
+                return Err(BackendError::Start(
+                    "`classifier` model type is not supported by the wgpu backend".to_string(),
+                ))
+            }
+            ModelType::Embedding(Pool::Splade) => {
+                return Err(BackendError::Start(
+                    "`splade` pooling is not supported by the wgpu backend".to_string(),
+                ))
+            }
+            ModelType::Embedding(pool) => pool,
+        };
+
+        if model_path.join("2_Dense/config.json").exists() {
+            tracing::warn!(
+                "`2_Dense` is not supported by the wgpu backend. Embeddings will not be projected"
+            );
+        }
+
+        // Load config
+        let config = std::fs::read_to_string(model_path.join("config.json")).s()?;
+        let config: Config = serde_json::from_str(&config).s()?;
+
+        let head_size = config.hidden_size / config.num_attention_heads;
+        // See `DIMS_PER_THREAD` in `attention.wgsl`
+        if head_size > 256 {
+            return Err(BackendError::Start(format!(
+                "Head size {head_size} is not supported"
+            )));
+        }
+
+        // Get wgpu device
+        let instance = wgpu::Instance::default();
+        let adapter =
+            pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::HighPerformance,
+                compatible_surface: None,
+                force_fallback_adapter: false,
+            }))
+            .ok_or(BackendError::Start("No wgpu adapter found".to_string()))?;
+        let adapter_limits = adapter.limits();
+        let (device, queue) = pollster::block_on(adapter.request_device(
+            &wgpu::DeviceDescriptor {
+                label: Some("text-embeddings-inference"),
+                required_features: wgpu::Features::empty(),
+                required_limits: wgpu::Limits {
+                    max_storage_buffer_binding_size: adapter_limits
+                        .max_storage_buffer_binding_size,
+                    max_buffer_size: adapter_limits.max_buffer_size,
+                    ..wgpu::Limits::default()
+                },
+            },
+            None,
+        ))
+        .s()?;
+
+        // Load weights
+        let file = std::fs::File::open(model_path.join("model.safetensors")).s()?;
+        let mmap = unsafe { memmap2::MmapOptions::new().map(&file).s()? };
+        let tensors = SafeTensors::deserialize(&mmap).s()?;
+        let weights = Weights::new(&device, &tensors, &config)?;
+
+        let embeddings_layer_norm = weights.layer_norm("embeddings.LayerNorm")?;
+        let layers = (0..config.num_hidden_layers)
+            .map(|i| weights.layer(&format!("encoder.layer.{i}")))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        tracing::info!("Starting Bert model on {:?}", adapter.get_info().backend);
+
+        Ok(Self {
+            word_embeddings: weights.buffer("embeddings.word_embeddings.weight")?,
+            token_type_embeddings: weights.buffer("embeddings.token_type_embeddings.weight")?,
+            position_embeddings: weights.buffer("embeddings.position_embeddings.weight")?,
+            embeddings_layer_norm,
+            layers,
+            kernels: Kernels::new(&device),
+            device,
+            queue,
+            hidden_size: config.hidden_size,
+            num_attention_heads: config.num_attention_heads,
+            intermediate_size: config.intermediate_size,
+            activation: match config.hidden_act {
+                HiddenAct::Gelu => Activation::Gelu,
+                HiddenAct::Relu => Activation::Relu,
+            },
+            layer_norm_eps: config.layer_norm_eps as f32,
+            pool,
+        })
+    }
+
+    /// Run the encoder on a padded batch and return the last hidden state
+    fn forward(&self, batch: &Batch) -> Vec<f32> {
+        let batch_size = batch.len();
+        let max_length = batch.max_length as usize;
+        let tokens = batch_size * max_length;
+        let hidden = self.hidden_size;
+
+        // Prepare padded batch
+        let mut input_ids = Vec::with_capacity(tokens);
+        let mut type_ids = Vec::with_capacity(tokens);
+        let mut position_ids = Vec::with_capacity(tokens);
+        let mut lengths = Vec::with_capacity(batch_size);
+
+        for i in 0..batch_size {
+            let start = batch.cumulative_seq_lengths[i] as usize;
+            let end = batch.cumulative_seq_lengths[i + 1] as usize;
+            lengths.push((end - start) as u32);
+
+            input_ids.extend_from_slice(&batch.input_ids[start..end]);
+            type_ids.extend_from_slice(&batch.token_type_ids[start..end]);
+            position_ids.extend_from_slice(&batch.position_ids[start..end]);
+
+            // Add padding if needed
+            let padding = max_length - (end - start);
+            input_ids.extend(std::iter::repeat(0).take(padding));
+            type_ids.extend(std::iter::repeat(0).take(padding));
+            position_ids.extend(std::iter::repeat(0).take(padding));
+        }
+
+        let input_ids = self.storage_buffer(bytemuck::cast_slice(&input_ids));
+        let type_ids = self.storage_buffer(bytemuck::cast_slice(&type_ids));
+        let position_ids = self.storage_buffer(bytemuck::cast_slice(&position_ids));
+        let lengths = self.storage_buffer(bytemuck::cast_slice(&lengths));
+
+        // Activations
+        let hidden_states = self.scratch_buffer(tokens * hidden);
+        let attention_states = self.scratch_buffer(tokens * hidden);
+        let projection = self.scratch_buffer(tokens * hidden);
+        let qkv = self.scratch_buffer(tokens * 3 * hidden);
+        let context = self.scratch_buffer(tokens * hidden);
+        let intermediate = self.scratch_buffer(tokens * self.intermediate_size);
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        let device = &self.device;
+        let kernels = &self.kernels;
+
+        kernels.embeddings(
+            device,
+            &mut encoder,
+            [&input_ids, &type_ids, &position_ids],
+            [
+                &self.word_embeddings,
+                &self.token_type_embeddings,
+                &self.position_embeddings,
+            ],
+            &projection,
+            &attention_states,
+            (tokens, hidden),
+        );
+        kernels.layer_norm(
+            device,
+            &mut encoder,
+            &projection,
+            &attention_states,
+            &self.embeddings_layer_norm.weight,
+            &self.embeddings_layer_norm.bias,
+            &hidden_states,
+            (tokens, hidden),
+            self.layer_norm_eps,
+        );
+
+        let linear = |encoder: &mut wgpu::CommandEncoder,
+                      x: &wgpu::Buffer,
+                      linear: &Linear,
+                      out: &wgpu::Buffer,
+                      act: Activation| {
+            kernels.matmul(
+                device,
+                encoder,
+                x,
+                &linear.weight,
+                &linear.bias,
+                out,
+                (tokens, linear.out_features, linear.in_features),
+                act,
+            );
+        };
+
+        for layer in &self.layers {
+            // Self attention
+            linear(&mut encoder, &hidden_states, &layer.qkv, &qkv, Activation::None);
+            kernels.attention(
+                device,
+                &mut encoder,
+                &qkv,
+                &lengths,
+                &context,
+                (batch_size, max_length),
+                (self.num_attention_heads, hidden / self.num_attention_heads),
+            );
+            linear(
+                &mut encoder,
+                &context,
+                &layer.attention_output,
+                &projection,
+                Activation::None,
+            );
+            kernels.layer_norm(
+                device,
+                &mut encoder,
+                &projection,
+                &hidden_states,
+                &layer.attention_layer_norm.weight,
+                &layer.attention_layer_norm.bias,
+                &attention_states,
+                (tokens, hidden),
+                self.layer_norm_eps,
+            );
+
+            // Feed forward
+            linear(
+                &mut encoder,
+                &attention_states,
+                &layer.intermediate,
+                &intermediate,
+                self.activation,
+            );
+            linear(
+                &mut encoder,
+                &intermediate,
+                &layer.output,
+                &projection,
+                Activation::None,
+            );
+            kernels.layer_norm(
+                device,
+                &mut encoder,
+                &projection,
+                &attention_states,
+                &layer.layer_norm.weight,
+                &layer.layer_norm.bias,
+                &hidden_states,
+                (tokens, hidden),
+                self.layer_norm_eps,
+            );
+        }
+
+        // Device => Host data transfer
+        let size = (tokens * hidden * std::mem::size_of::<f32>()) as u64;
+        let staging = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("staging"),
+            size,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        encoder.copy_buffer_to_buffer(&hidden_states, 0, &staging, 0, size);
+        self.queue.submit(Some(encoder.finish()));
+
+        let slice = staging.slice(..);
+        slice.map_async(wgpu::MapMode::Read, |_| {});
+        self.device.poll(wgpu::Maintain::Wait);
+
+        let view = slice.get_mapped_range();
+        let outputs = bytemuck::cast_slice::<u8, f32>(&view).to_vec();
+        drop(view);
+        staging.unmap();
+        outputs
+    }
+
+    fn storage_buffer(&self, contents: &[u8]) -> wgpu::Buffer {
+        self.device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: None,
+                contents,
+                usage: wgpu::BufferUsages::STORAGE,
+            })
+    }
+
+    fn scratch_buffer(&self, elems: usize) -> wgpu::Buffer {
+        self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: (elems * std::mem::size_of::<f32>()) as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        })
+    }
+}
+
+impl Backend for WgpuBackend {
+    fn health(&self) -> Result<(), BackendError> {
+        Ok(())
+    }
+
+    fn is_padded(&self) -> bool {
+        true
+    }
+
+    fn embed(&self, batch: Batch) -> Result<Embeddings, BackendError> {
+        let batch_size = batch.len();
+        let max_length = batch.max_length as usize;
+        let hidden = self.hidden_size;
+
+        let outputs = self.forward(&batch);
+        let token = |i: usize, j: usize| {
+            let start = (i * max_length + j) * hidden;
+            &outputs[start..start + hidden]
+        };
+
+        let mut embeddings =
+            HashMap::with_capacity_and_hasher(batch_size, BuildNoHashHasher::default());
+
+        for &i in batch.pooled_indices.iter() {
+            let quantization = batch.quantization(i);
+            let i = i as usize;
+            let length =
+                (batch.cumulative_seq_lengths[i + 1] - batch.cumulative_seq_lengths[i]) as usize;
+
+            let mut e = match self.pool {
+                // CLS pooling
+                Pool::Cls => token(i, 0).to_vec(),
+                // Mean pooling
+                Pool::Mean => {
+                    let mut e = vec![0.0; hidden];
+                    for j in 0..length {
+                        for (acc, v) in e.iter_mut().zip(token(i, j)) {
+                            *acc += v;
+                        }
+                    }
+                    e.iter_mut().for_each(|v| *v /= length as f32);
+                    e
+                }
+                // Position weighted mean pooling
+                Pool::WeightedMean => {
+                    // Tokens are weighted by their position
+                    let weights_sum = (length * (length + 1) / 2) as f32;
+                    let mut e = vec![0.0; hidden];
+                    for j in 0..length {
+                        let weight = (j + 1) as f32 / weights_sum;
+                        for (acc, v) in e.iter_mut().zip(token(i, j)) {
+                            *acc += weight * v;
+                        }
+                    }
+                    e
+                }
+                // Last token pooling
+                Pool::LastToken => token(i, length - 1).to_vec(),
+                Pool::Splade => unreachable!(),
+            };
+            if batch.normalized_indices.contains(&(i as u32)) {
+                normalize(&mut e);
+            }
+            embeddings.insert(i, pooled_embedding(e, quantization));
+        }
+
+        for i in batch.raw_indices {
+            let i = i as usize;
+            let length =
+                (batch.cumulative_seq_lengths[i + 1] - batch.cumulative_seq_lengths[i]) as usize;
+            let e = (0..length).map(|j| token(i, j).to_vec()).collect();
+            embeddings.insert(i, Embedding::All(e));
+        }
+
+        Ok(embeddings)
+    }
+
+    fn predict(&self, _batch: Batch) -> Result<Predictions, BackendError> {
+        Err(BackendError::Inference(
+            "`predict` is not implemented for this model".to_string(),
+        ))
+    }
+}
+
+/// Safetensors weights uploaded to the device as f32
+struct Weights<'a> {
+    device: &'a wgpu::Device,
+    tensors: &'a SafeTensors<'a>,
+    prefix: String,
+}
+
+impl<'a> Weights<'a> {
+    fn new(
+        device: &'a wgpu::Device,
+        tensors: &'a SafeTensors<'a>,
+        config: &Config,
+    ) -> Result<Self, BackendError> {
+        // Weights can be saved with or without the model type prefix
+        let model_type = config.model_type.clone().unwrap_or("bert".to_string());
+        let prefix = [String::new(), format!("{model_type}."), "roberta.".to_string()]
+            .into_iter()
+            .find(|prefix| {
+                tensors
+                    .tensor(&format!("{prefix}embeddings.word_embeddings.weight"))
+                    .is_ok()
+            })
+            .ok_or(BackendError::Start(
+                "`embeddings.word_embeddings.weight` not found".to_string(),
+            ))?;
+
+        Ok(Self {
+            device,
+            tensors,
+            prefix,
+        })
+    }
+
+    fn f32(&self, name: &str) -> Result<Vec<f32>, BackendError> {
+        let name = format!("{}{name}", self.prefix);
+        let view = self.tensors.tensor(&name).s()?;
+        let data = view.data();
+        match view.dtype() {
+            Dtype::F32 => Ok(data
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect()),
+            Dtype::F16 => Ok(data
+                .chunks_exact(2)
+                .map(|b| half::f16::from_le_bytes([b[0], b[1]]).to_f32())
+                .collect()),
+            Dtype::BF16 => Ok(data
+                .chunks_exact(2)
+                .map(|b| half::bf16::from_le_bytes([b[0], b[1]]).to_f32())
+                .collect()),
+            dtype => Err(BackendError::Start(format!(
+                "Weight {name} has unsupported dtype {dtype:?}"
+            ))),
+        }
+    }
+
+    fn upload(&self, data: &[f32]) -> wgpu::Buffer {
+        self.device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: None,
+                contents: bytemuck::cast_slice(data),
+                usage: wgpu::BufferUsages::STORAGE,
+            })
+    }
+
+    fn buffer(&self, name: &str) -> Result<wgpu::Buffer, BackendError> {
+        Ok(self.upload(&self.f32(name)?))
+    }
+
+    fn shape(&self, name: &str) -> Result<Vec<usize>, BackendError> {
+        let name = format!("{}{name}", self.prefix);
+        Ok(self.tensors.tensor(&name).s()?.shape().to_vec())
+    }
+
+    fn linear(&self, prefixes: &[&str]) -> Result<Linear, BackendError> {
+        let mut weight = Vec::new();
+        let mut bias = Vec::new();
+        let mut out_features = 0;
+        let mut in_features = 0;
+
+        // Multiple prefixes are fused along the output dimension
+        for prefix in prefixes {
+            let shape = self.shape(&format!("{prefix}.weight"))?;
+            out_features += shape[0];
+            in_features = shape[1];
+            weight.extend(self.f32(&format!("{prefix}.weight"))?);
+            bias.extend(self.f32(&format!("{prefix}.bias"))?);
+        }
+
+        Ok(Linear {
+            weight: self.upload(&weight),
+            bias: self.upload(&bias),
+            out_features,
+            in_features,
+        })
+    }
+
+    fn layer_norm(&self, prefix: &str) -> Result<LayerNorm, BackendError> {
+        let get = |names: [&str; 2]| {
+            self.buffer(&format!("{prefix}.{}", names[0]))
+                .or_else(|_| self.buffer(&format!("{prefix}.{}", names[1])))
+        };
+        Ok(LayerNorm {
+            weight: get(["weight", "gamma"])?,
+            bias: get(["bias", "beta"])?,
+        })
+    }
+
+    fn layer(&self, prefix: &str) -> Result<Layer, BackendError> {
+        let attention = format!("{prefix}.attention");
+        Ok(Layer {
+            qkv: self
+                .linear(&[&format!("{attention}.self.query_key_value")])
+                .or_else(|_| {
+                    self.linear(&[
+                        &format!("{attention}.self.query"),
+                        &format!("{attention}.self.key"),
+                        &format!("{attention}.self.value"),
+                    ])
+                })?,
+            attention_output: self.linear(&[&format!("{attention}.output.dense")])?,
+            attention_layer_norm: self.layer_norm(&format!("{attention}.output.LayerNorm"))?,
+            intermediate: self.linear(&[&format!("{prefix}.intermediate.dense")])?,
+            output: self.linear(&[&format!("{prefix}.output.dense")])?,
+            layer_norm: self.layer_norm(&format!("{prefix}.output.LayerNorm"))?,
+        })
+    }
+}
+
+pub trait WrapErr<O> {
+    fn s(self) -> Result<O, BackendError>;
+}
+
+impl<O, E: std::fmt::Display> WrapErr<O> for Result<O, E> {
+    fn s(self) -> Result<O, BackendError> {
+        self.map_err(|e| BackendError::Start(e.to_string()))
+    }
+}
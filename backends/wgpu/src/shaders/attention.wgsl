@@ -0,0 +1,130 @@
+// Multi-head attention over a padded batch with an online softmax.
+// qkv: [batch_size * max_len, 3 * hidden], out: [batch_size * max_len, hidden]
+// One workgroup computes the output of one head for one query token.
+struct Params {
+    max_len: u32,
+    num_heads: u32,
+    head_size: u32,
+    scale: f32,
+}
+
+@group(0) @binding(0) var<storage, read> qkv: array<f32>;
+@group(0) @binding(1) var<storage, read> lengths: array<u32>;
+@group(0) @binding(2) var<storage, read_write> out: array<f32>;
+@group(0) @binding(3) var<uniform> params: Params;
+
+const WG: u32 = 64u;
+// Keys processed per chunk
+const CHUNK: u32 = 256u;
+// Maximum head size is WG * DIMS_PER_THREAD
+const DIMS_PER_THREAD: u32 = 4u;
+const MASKED: f32 = -1e30;
+
+var<workgroup> scores: array<f32, 256>;
+var<workgroup> partial: array<f32, 64>;
+
+fn reduce_max(lid: u32) -> f32 {
+    for (var s = WG / 2u; s > 0u; s = s >> 1u) {
+        if (lid < s) {
+            partial[lid] = max(partial[lid], partial[lid + s]);
+        }
+        workgroupBarrier();
+    }
+    let total = partial[0];
+    workgroupBarrier();
+    return total;
+}
+
+fn reduce_sum(lid: u32) -> f32 {
+    for (var s = WG / 2u; s > 0u; s = s >> 1u) {
+        if (lid < s) {
+            partial[lid] += partial[lid + s];
+        }
+        workgroupBarrier();
+    }
+    let total = partial[0];
+    workgroupBarrier();
+    return total;
+}
+
+@compute @workgroup_size(64)
+fn main(
+    @builtin(workgroup_id) wid: vec3<u32>,
+    @builtin(local_invocation_id) lid: vec3<u32>,
+) {
+    let token = wid.x;
+    let head = wid.y;
+    let hidden = params.num_heads * params.head_size;
+    let stride = 3u * hidden;
+
+    let batch = token / params.max_len;
+    let length = lengths[batch];
+    let first_key = batch * params.max_len;
+
+    let q_base = token * stride + head * params.head_size;
+    let k_offset = hidden + head * params.head_size;
+    let v_offset = 2u * hidden + head * params.head_size;
+
+    var acc = array<f32, 4>(0.0, 0.0, 0.0, 0.0);
+    var m = MASKED;
+    var l = 0.0;
+
+    let chunks = (params.max_len + CHUNK - 1u) / CHUNK;
+    for (var c = 0u; c < chunks; c++) {
+        // Scores of this chunk
+        var local_max = MASKED;
+        for (var jj = lid.x; jj < CHUNK; jj += WG) {
+            let j = c * CHUNK + jj;
+            var s = MASKED;
+            if (j < length) {
+                let k_base = (first_key + j) * stride + k_offset;
+                var dot = 0.0;
+                for (var d = 0u; d < params.head_size; d++) {
+                    dot += qkv[q_base + d] * qkv[k_base + d];
+                }
+                s = dot * params.scale;
+            }
+            scores[jj] = s;
+            local_max = max(local_max, s);
+        }
+        partial[lid.x] = local_max;
+        workgroupBarrier();
+        let new_m = max(m, reduce_max(lid.x));
+        let correction = exp(m - new_m);
+
+        // Unnormalized probabilities
+        var local_sum = 0.0;
+        for (var jj = lid.x; jj < CHUNK; jj += WG) {
+            let p = exp(scores[jj] - new_m);
+            scores[jj] = p;
+            local_sum += p;
+        }
+        partial[lid.x] = local_sum;
+        workgroupBarrier();
+        l = l * correction + reduce_sum(lid.x);
+        m = new_m;
+
+        // Accumulate values
+        let keys = min(CHUNK, params.max_len - c * CHUNK);
+        for (var r = 0u; r < DIMS_PER_THREAD; r++) {
+            let d = lid.x + r * WG;
+            if (d < params.head_size) {
+                var value = acc[r] * correction;
+                for (var jj = 0u; jj < keys; jj++) {
+                    let v_base = (first_key + c * CHUNK + jj) * stride + v_offset;
+                    value += scores[jj] * qkv[v_base + d];
+                }
+                acc[r] = value;
+            }
+        }
+        workgroupBarrier();
+    }
+
+    let out_base = token * hidden + head * params.head_size;
+    for (var r = 0u; r < DIMS_PER_THREAD; r++) {
+        let d = lid.x + r * WG;
+        if (d < params.head_size) {
+            out[out_base + d] = acc[r] / l;
+        }
+    }
+}
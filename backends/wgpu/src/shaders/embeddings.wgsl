@@ -0,0 +1,35 @@
+// out[t] = word[input_ids[t]] + token_type[type_ids[t]]
+// position_out[t] = position[position_ids[t]]
+struct Params {
+    hidden: u32,
+    _pad0: u32,
+    _pad1: u32,
+    _pad2: u32,
+}
+
+@group(0) @binding(0) var<storage, read> input_ids: array<u32>;
+@group(0) @binding(1) var<storage, read> type_ids: array<u32>;
+@group(0) @binding(2) var<storage, read> position_ids: array<u32>;
+@group(0) @binding(3) var<storage, read> word: array<f32>;
+@group(0) @binding(4) var<storage, read> token_type: array<f32>;
+@group(0) @binding(5) var<storage, read> position: array<f32>;
+@group(0) @binding(6) var<storage, read_write> out: array<f32>;
+@group(0) @binding(7) var<storage, read_write> position_out: array<f32>;
+@group(0) @binding(8) var<uniform> params: Params;
+
+@compute @workgroup_size(256)
+fn main(
+    @builtin(workgroup_id) wid: vec3<u32>,
+    @builtin(local_invocation_id) lid: vec3<u32>,
+) {
+    let t = wid.x;
+    let h = params.hidden;
+    let word_base = input_ids[t] * h;
+    let type_base = type_ids[t] * h;
+    let position_base = position_ids[t] * h;
+
+    for (var c = lid.x; c < h; c += 256u) {
+        out[t * h + c] = word[word_base + c] + token_type[type_base + c];
+        position_out[t * h + c] = position[position_base + c];
+    }
+}
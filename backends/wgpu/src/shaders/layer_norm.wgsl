@@ -0,0 +1,61 @@
+// out[row] = layer_norm(x[row] + residual[row]) * gamma + beta
+struct Params {
+    cols: u32,
+    eps: f32,
+    _pad0: u32,
+    _pad1: u32,
+}
+
+@group(0) @binding(0) var<storage, read> x: array<f32>;
+@group(0) @binding(1) var<storage, read> residual: array<f32>;
+@group(0) @binding(2) var<storage, read> gamma: array<f32>;
+@group(0) @binding(3) var<storage, read> beta: array<f32>;
+@group(0) @binding(4) var<storage, read_write> out: array<f32>;
+@group(0) @binding(5) var<uniform> params: Params;
+
+const WG: u32 = 256u;
+
+var<workgroup> partial: array<f32, 256>;
+
+fn reduce(lid: u32) -> f32 {
+    for (var s = WG / 2u; s > 0u; s = s >> 1u) {
+        if (lid < s) {
+            partial[lid] += partial[lid + s];
+        }
+        workgroupBarrier();
+    }
+    let total = partial[0];
+    workgroupBarrier();
+    return total;
+}
+
+@compute @workgroup_size(256)
+fn main(
+    @builtin(workgroup_id) wid: vec3<u32>,
+    @builtin(local_invocation_id) lid: vec3<u32>,
+) {
+    let base = wid.x * params.cols;
+    let n = f32(params.cols);
+
+    var sum = 0.0;
+    for (var c = lid.x; c < params.cols; c += WG) {
+        sum += x[base + c] + residual[base + c];
+    }
+    partial[lid.x] = sum;
+    workgroupBarrier();
+    let mean = reduce(lid.x) / n;
+
+    var sq = 0.0;
+    for (var c = lid.x; c < params.cols; c += WG) {
+        let d = x[base + c] + residual[base + c] - mean;
+        sq += d * d;
+    }
+    partial[lid.x] = sq;
+    workgroupBarrier();
+    let inv_std = inverseSqrt(reduce(lid.x) / n + params.eps);
+
+    for (var c = lid.x; c < params.cols; c += WG) {
+        let d = x[base + c] + residual[base + c] - mean;
+        out[base + c] = d * inv_std * gamma[c] + beta[c];
+    }
+}
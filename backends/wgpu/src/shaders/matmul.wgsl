@@ -0,0 +1,70 @@
+// out[m, n] = act(x[m, k] . w[n, k]^T + b[n])
+struct Params {
+    m: u32,
+    n: u32,
+    k: u32,
+    // 0: none, 1: gelu, 2: relu
+    act: u32,
+}
+
+@group(0) @binding(0) var<storage, read> x: array<f32>;
+@group(0) @binding(1) var<storage, read> w: array<f32>;
+@group(0) @binding(2) var<storage, read> b: array<f32>;
+@group(0) @binding(3) var<storage, read_write> out: array<f32>;
+@group(0) @binding(4) var<uniform> params: Params;
+
+const TILE: u32 = 16u;
+
+var<workgroup> tile_x: array<array<f32, 16>, 16>;
+var<workgroup> tile_w: array<array<f32, 16>, 16>;
+
+fn gelu(v: f32) -> f32 {
+    // tanh approximation, clamped to avoid overflows in `tanh`
+    let inner = clamp(0.7978845608 * (v + 0.044715 * v * v * v), -15.0, 15.0);
+    return 0.5 * v * (1.0 + tanh(inner));
+}
+
+@compute @workgroup_size(16, 16)
+fn main(
+    @builtin(global_invocation_id) gid: vec3<u32>,
+    @builtin(local_invocation_id) lid: vec3<u32>,
+) {
+    let row = gid.y;
+    let col = gid.x;
+
+    var acc = 0.0;
+    let tiles = (params.k + TILE - 1u) / TILE;
+    for (var t = 0u; t < tiles; t++) {
+        let kx = t * TILE + lid.x;
+        if (row < params.m && kx < params.k) {
+            tile_x[lid.y][lid.x] = x[row * params.k + kx];
+        } else {
+            tile_x[lid.y][lid.x] = 0.0;
+        }
+
+        // Load the tile of `w` already transposed
+        let wn = (gid.x - lid.x) + lid.y;
+        let kw = t * TILE + lid.x;
+        if (wn < params.n && kw < params.k) {
+            tile_w[lid.x][lid.y] = w[wn * params.k + kw];
+        } else {
+            tile_w[lid.x][lid.y] = 0.0;
+        }
+        workgroupBarrier();
+
+        for (var i = 0u; i < TILE; i++) {
+            acc += tile_x[lid.y][i] * tile_w[i][lid.x];
+        }
+        workgroupBarrier();
+    }
+
+    if (row < params.m && col < params.n) {
+        var v = acc + b[col];
+        if (params.act == 1u) {
+            v = gelu(v);
+        } else if (params.act == 2u) {
+            v = max(v, 0.0);
+        }
+        out[row * params.n + col] = v;
+    }
+}
@@ -0,0 +1,63 @@
+/*
+ * C interface of Text Embeddings Inference.
+ * Link against `libtei` built with
+ * `cargo build --profile release-ffi -p text-embeddings-inference-ffi`.
+ * The `release-ffi` profile unwinds panics so that they are reported as `TEI_PANIC`.
+ */
+#ifndef TEI_H
+#define TEI_H
+
+#include <stdbool.h>
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef enum TeiStatus {
+    TEI_OK = 0,
+    /* A pointer is null or a string is not valid UTF-8 */
+    TEI_INVALID_ARGUMENT = 1,
+    /* The model could not be downloaded or loaded */
+    TEI_LOAD_ERROR = 2,
+    /* An input is invalid, e.g. longer than the maximum input length of the model */
+    TEI_VALIDATION_ERROR = 3,
+    /* The backend failed */
+    TEI_INFERENCE_ERROR = 4,
+    /* The call panicked. The handles it used must not be used anymore */
+    TEI_PANIC = 5,
+} TeiStatus;
+
+/* Loaded model. Handles can be used from several threads concurrently */
+typedef struct TeiModel TeiModel;
+
+/* Message of the last error of the calling thread, or NULL. Valid until the next failing call */
+const char *tei_last_error(void);
+
+/*
+ * Load `model_id`, a Hub model id or a local directory. `revision`, `dtype` (`float16` or
+ * `float32`) and `pooling` (`cls`, `mean`, ...) may be NULL to use the defaults of the model.
+ * Gated models read their token from the `HF_API_TOKEN` environment variable.
+ */
+TeiStatus tei_load_model(const char *model_id, const char *revision, const char *dtype,
+                         const char *pooling, TeiModel **out_model);
+
+/*
+ * Embed `num_inputs` strings. On success `*out_embeddings` holds `num_inputs * *out_dimension`
+ * floats, the embeddings of the inputs one after the other, to release with
+ * `tei_free_embeddings`.
+ */
+TeiStatus tei_embed(const TeiModel *model, const char *const *inputs, size_t num_inputs,
+                    bool truncate, bool normalize, float **out_embeddings, size_t *out_dimension);
+
+/* Release the `len` floats returned by `tei_embed` */
+void tei_free_embeddings(float *embeddings, size_t len);
+
+/* Stop the model and release its handle */
+void tei_free(TeiModel *model);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* TEI_H */
@@ -0,0 +1,355 @@
+//! C interface of Text Embeddings Inference, declared in `include/tei.h`.
+//! Models are opaque `TeiModel` handles. Functions return a `TeiStatus` and the message of the
+//! last error of the calling thread is available with `tei_last_error`.
+//! Panics never unwind into the caller: they are reported as `TeiStatus::Panic`.
+use clap::ValueEnum;
+use futures::future::try_join_all;
+use std::any::Any;
+use std::cell::RefCell;
+use std::ffi::{c_char, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use text_embeddings_backend::{DType, Pool};
+use text_embeddings_core::infer::Infer;
+use text_embeddings_core::model::{self, LoadError, ModelOptions};
+use text_embeddings_core::queue::Priority;
+use text_embeddings_core::TextEmbeddingsError;
+use tokio::runtime::Runtime;
+
+/// Status codes. Their values are part of the ABI and must not change
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TeiStatus {
+    Ok = 0,
+    /// A pointer is null or a string is not valid UTF-8
+    InvalidArgument = 1,
+    /// The model could not be downloaded or loaded
+    LoadError = 2,
+    /// An input is invalid, e.g. longer than the maximum input length of the model
+    ValidationError = 3,
+    /// The backend failed
+    InferenceError = 4,
+    /// The call panicked. The handles it used must not be used anymore
+    Panic = 5,
+}
+
+/// Loaded model
+pub struct TeiModel {
+    infer: Infer,
+    runtime: Runtime,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+fn set_error(status: TeiStatus, message: impl ToString) -> TeiStatus {
+    let message = CString::new(message.to_string().replace('\0', " ")).unwrap();
+    LAST_ERROR.with(|error| *error.borrow_mut() = Some(message));
+    status
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    let message = payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic");
+    format!("Panic: {message}")
+}
+
+/// Run `f`, reporting a panic as `TeiStatus::Panic` as unwinding into C is undefined behavior
+fn catch_panic(f: impl FnOnce() -> TeiStatus) -> TeiStatus {
+    panic::catch_unwind(AssertUnwindSafe(f))
+        .unwrap_or_else(|payload| set_error(TeiStatus::Panic, panic_message(payload.as_ref())))
+}
+
+/// Message of the last error of the calling thread, or null if no call failed.
+/// The string is valid until the next failing call on this thread.
+#[no_mangle]
+pub extern "C" fn tei_last_error() -> *const c_char {
+    panic::catch_unwind(|| {
+        LAST_ERROR.with(|error| {
+            error
+                .borrow()
+                .as_ref()
+                .map_or(ptr::null(), |message| message.as_ptr())
+        })
+    })
+    .unwrap_or(ptr::null())
+}
+
+/// Read an optional UTF-8 string
+///
+/// # Safety
+/// `value` must be null or a valid NUL-terminated string
+unsafe fn optional_str<'a>(value: *const c_char) -> Result<Option<&'a str>, TeiStatus> {
+    if value.is_null() {
+        return Ok(None);
+    }
+    CStr::from_ptr(value)
+        .to_str()
+        .map(Some)
+        .map_err(|_| set_error(TeiStatus::InvalidArgument, "String is not valid UTF-8"))
+}
+
+/// Load `model_id`, a Hub model id or a local directory, and store its handle in `out_model`.
+/// `revision`, `dtype` (`float16` or `float32`) and `pooling` (`cls`, `mean`, ...) may be null
+/// to use the defaults of the model.
+///
+/// # Safety
+/// The strings must be null or valid NUL-terminated strings and `out_model` must be a valid
+/// pointer
+#[no_mangle]
+pub unsafe extern "C" fn tei_load_model(
+    model_id: *const c_char,
+    revision: *const c_char,
+    dtype: *const c_char,
+    pooling: *const c_char,
+    out_model: *mut *mut TeiModel,
+) -> TeiStatus {
+    catch_panic(|| match load_model(model_id, revision, dtype, pooling) {
+        Ok(model) if !out_model.is_null() => {
+            *out_model = Box::into_raw(Box::new(model));
+            TeiStatus::Ok
+        }
+        Ok(_) => set_error(TeiStatus::InvalidArgument, "`out_model` is null"),
+        Err(status) => status,
+    })
+}
+
+unsafe fn load_model(
+    model_id: *const c_char,
+    revision: *const c_char,
+    dtype: *const c_char,
+    pooling: *const c_char,
+) -> Result<TeiModel, TeiStatus> {
+    let model_id = optional_str(model_id)?
+        .ok_or_else(|| set_error(TeiStatus::InvalidArgument, "`model_id` is null"))?;
+    let revision = optional_str(revision)?;
+    // Float16 is only fast on GPUs
+    let default_dtype = if cfg!(any(feature = "cuda", feature = "metal")) {
+        "float16"
+    } else {
+        "float32"
+    };
+    let dtype = optional_str(dtype)?.unwrap_or(default_dtype);
+    let dtype = DType::from_str(dtype, true).map_err(|_| {
+        set_error(TeiStatus::InvalidArgument, format!("Unsupported dtype `{dtype}`"))
+    })?;
+    let pooling = optional_str(pooling)?
+        .map(|pooling| {
+            Pool::from_str(pooling, true).map_err(|_| {
+                set_error(TeiStatus::InvalidArgument, format!("Unknown pooling `{pooling}`"))
+            })
+        })
+        .transpose()?;
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .map_err(|err| set_error(TeiStatus::LoadError, err))?;
+    let options = ModelOptions {
+        pooling,
+        tokenization_workers: num_cpus::get_physical(),
+        ..ModelOptions::new(dtype)
+    };
+    let model = runtime
+        .block_on(model::load(
+            model_id,
+            revision,
+            std::env::var("HF_API_TOKEN").ok(),
+            &options,
+        ))
+        .map_err(|err| match err {
+            LoadError::Config(_) => set_error(TeiStatus::InvalidArgument, err),
+            _ => set_error(TeiStatus::LoadError, err),
+        })?;
+
+    Ok(TeiModel {
+        infer: model.infer,
+        runtime,
+    })
+}
+
+/// Embed the `num_inputs` strings of `inputs` in batches.
+/// On success, `out_embeddings` points to `num_inputs * out_dimension` floats, the embeddings
+/// of the inputs one after the other, to release with `tei_free_embeddings`.
+///
+/// # Safety
+/// `model` must be a handle returned by `tei_load_model`, `inputs` must point to `num_inputs`
+/// valid NUL-terminated strings and the output pointers must be valid
+#[no_mangle]
+pub unsafe extern "C" fn tei_embed(
+    model: *const TeiModel,
+    inputs: *const *const c_char,
+    num_inputs: usize,
+    truncate: bool,
+    normalize: bool,
+    out_embeddings: *mut *mut f32,
+    out_dimension: *mut usize,
+) -> TeiStatus {
+    catch_panic(|| {
+        embed(
+            model,
+            inputs,
+            num_inputs,
+            truncate,
+            normalize,
+            out_embeddings,
+            out_dimension,
+        )
+    })
+}
+
+unsafe fn embed(
+    model: *const TeiModel,
+    inputs: *const *const c_char,
+    num_inputs: usize,
+    truncate: bool,
+    normalize: bool,
+    out_embeddings: *mut *mut f32,
+    out_dimension: *mut usize,
+) -> TeiStatus {
+    if model.is_null() || out_embeddings.is_null() || out_dimension.is_null() {
+        return set_error(TeiStatus::InvalidArgument, "Null pointer");
+    }
+    if inputs.is_null() && num_inputs > 0 {
+        return set_error(TeiStatus::InvalidArgument, "`inputs` is null");
+    }
+    let model = &*model;
+
+    let mut texts = Vec::with_capacity(num_inputs);
+    for i in 0..num_inputs {
+        match optional_str(*inputs.add(i)) {
+            Ok(Some(text)) => texts.push(text.to_string()),
+            Ok(None) => return set_error(TeiStatus::InvalidArgument, format!("Input {i} is null")),
+            Err(status) => return status,
+        }
+    }
+
+    let embeddings = model.runtime.block_on(try_join_all(texts.into_iter().map(|text| async move {
+        let permit = model.infer.acquire_permit().await;
+        let response = model
+            .infer
+            .embed_pooled(
+                text,
+                truncate,
+                None,
+                normalize,
+                None,
+                None,
+                Priority::Normal,
+                permit,
+            )
+            .await?;
+        Ok::<_, TextEmbeddingsError>(response.results)
+    })));
+    let embeddings = match embeddings {
+        Ok(embeddings) => embeddings,
+        Err(err @ (TextEmbeddingsError::Validation(_) | TextEmbeddingsError::Tokenizer(_))) => {
+            return set_error(TeiStatus::ValidationError, err)
+        }
+        Err(err) => return set_error(TeiStatus::InferenceError, err),
+    };
+
+    let dimension = embeddings.first().map_or(0, Vec::len);
+    let flat: Box<[f32]> = embeddings.into_iter().flatten().collect();
+    *out_dimension = dimension;
+    *out_embeddings = Box::into_raw(flat) as *mut f32;
+    TeiStatus::Ok
+}
+
+/// Release the `len` floats returned by `tei_embed`, i.e. `num_inputs * out_dimension`
+///
+/// # Safety
+/// `embeddings` must be null or returned by `tei_embed` with the same length, and not released
+/// yet
+#[no_mangle]
+pub unsafe extern "C" fn tei_free_embeddings(embeddings: *mut f32, len: usize) {
+    catch_panic(|| {
+        if !embeddings.is_null() {
+            drop(Box::from_raw(ptr::slice_from_raw_parts_mut(embeddings, len)));
+        }
+        TeiStatus::Ok
+    });
+}
+
+/// Stop the model and release its handle
+///
+/// # Safety
+/// `model` must be null or a handle returned by `tei_load_model`, and not released yet. No
+/// other call may use the handle concurrently
+#[no_mangle]
+pub unsafe extern "C" fn tei_free(model: *mut TeiModel) {
+    catch_panic(|| {
+        if !model.is_null() {
+            let model = Box::from_raw(model);
+            model.runtime.block_on(model.infer.shutdown());
+        }
+        TeiStatus::Ok
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn last_error() -> String {
+        let error = tei_last_error();
+        assert!(!error.is_null());
+        let error = unsafe { CStr::from_ptr(error) };
+        error.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_catch_panic() {
+        assert_eq!(catch_panic(|| TeiStatus::Ok), TeiStatus::Ok);
+
+        let status = catch_panic(|| panic!("boom"));
+        assert_eq!(status, TeiStatus::Panic);
+        assert_eq!(last_error(), "Panic: boom");
+
+        let status = catch_panic(|| panic!("formatted {}", 42));
+        assert_eq!(status, TeiStatus::Panic);
+        assert_eq!(last_error(), "Panic: formatted 42");
+    }
+
+    #[test]
+    fn test_invalid_arguments() {
+        let mut model = ptr::null_mut();
+        let status = unsafe {
+            tei_load_model(
+                ptr::null(),
+                ptr::null(),
+                ptr::null(),
+                ptr::null(),
+                &mut model,
+            )
+        };
+        assert_eq!(status, TeiStatus::InvalidArgument);
+        assert_eq!(last_error(), "`model_id` is null");
+        assert!(model.is_null());
+
+        let mut embeddings = ptr::null_mut();
+        let mut dimension = 0;
+        let status = unsafe {
+            tei_embed(
+                ptr::null(),
+                ptr::null(),
+                0,
+                false,
+                false,
+                &mut embeddings,
+                &mut dimension,
+            )
+        };
+        assert_eq!(status, TeiStatus::InvalidArgument);
+        assert_eq!(last_error(), "Null pointer");
+
+        unsafe {
+            tei_free_embeddings(ptr::null_mut(), 0);
+            tei_free(ptr::null_mut());
+        }
+    }
+}
@@ -0,0 +1,357 @@
+/// Typed client of the HTTP and gRPC APIs of the router
+use crate::pb::tei::v1 as grpc;
+use crate::{to_napi_err, EmbedOptions, PredictOptions, Prediction, Rank};
+use futures::future::try_join_all;
+use grpc::{
+    embed_client::EmbedClient, info_client::InfoClient, predict_client::PredictClient,
+    rerank_client::RerankClient,
+};
+use napi::bindgen_prelude::Float32Array;
+use napi::Result;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tonic::transport::Channel;
+
+#[napi(object)]
+#[derive(Default)]
+pub struct ClientOptions {
+    /// Sent as a bearer token, for servers started with `--api-key`
+    pub api_key: Option<String>,
+    pub timeout_ms: Option<u32>,
+}
+
+#[napi(object)]
+#[derive(Deserialize)]
+pub struct ServerInfo {
+    pub model_id: String,
+    pub model_sha: Option<String>,
+    pub model_dtype: String,
+    pub max_input_length: u32,
+    pub max_batch_tokens: u32,
+    pub max_client_batch_size: u32,
+    pub version: String,
+}
+
+enum Transport {
+    Http(HttpTransport),
+    Grpc(Channel),
+}
+
+struct HttpTransport {
+    client: reqwest::Client,
+    url: String,
+}
+
+/// Client of a running server
+#[napi]
+pub struct Client {
+    transport: Transport,
+    api_key: Option<String>,
+}
+
+#[napi]
+impl Client {
+    /// `http://` and `https://` URLs use the HTTP API and `grpc://` URLs the gRPC API
+    #[napi]
+    pub async fn connect(url: String, options: Option<ClientOptions>) -> Result<Client> {
+        let options = options.unwrap_or_default();
+        let timeout = options
+            .timeout_ms
+            .map(|timeout| Duration::from_millis(timeout as u64));
+
+        let transport = if let Some(address) = url.strip_prefix("grpc://") {
+            let mut endpoint =
+                Channel::from_shared(format!("http://{address}")).map_err(to_napi_err)?;
+            if let Some(timeout) = timeout {
+                endpoint = endpoint.timeout(timeout);
+            }
+            Transport::Grpc(endpoint.connect().await.map_err(to_napi_err)?)
+        } else {
+            let mut builder = reqwest::Client::builder();
+            if let Some(timeout) = timeout {
+                builder = builder.timeout(timeout);
+            }
+            Transport::Http(HttpTransport {
+                client: builder.build().map_err(to_napi_err)?,
+                url: url.trim_end_matches('/').to_string(),
+            })
+        };
+
+        Ok(Self {
+            transport,
+            api_key: options.api_key,
+        })
+    }
+
+    /// Model and limits of the server
+    #[napi]
+    pub async fn info(&self) -> Result<ServerInfo> {
+        match &self.transport {
+            Transport::Http(http) => http.get("/info", &self.api_key).await,
+            Transport::Grpc(channel) => {
+                let info = InfoClient::new(channel.clone())
+                    .info(self.grpc_request(grpc::InfoRequest {})?)
+                    .await
+                    .map_err(to_napi_err)?
+                    .into_inner();
+                Ok(ServerInfo {
+                    model_id: info.model_id,
+                    model_sha: info.model_sha,
+                    model_dtype: info.model_dtype,
+                    max_input_length: info.max_input_length,
+                    max_batch_tokens: info.max_batch_tokens,
+                    max_client_batch_size: info.max_client_batch_size,
+                    version: info.version,
+                })
+            }
+        }
+    }
+
+    /// Pooled embeddings of `inputs`
+    #[napi]
+    pub async fn embed(
+        &self,
+        inputs: Vec<String>,
+        options: Option<EmbedOptions>,
+    ) -> Result<Vec<Float32Array>> {
+        let options = options.unwrap_or_default();
+        let truncate = options.truncate.unwrap_or(false);
+        let normalize = options.normalize.unwrap_or(true);
+        let dimensions = options.dimensions;
+
+        let embeddings: Vec<Vec<f32>> = match &self.transport {
+            Transport::Http(http) => {
+                let request = HttpEmbedRequest {
+                    inputs,
+                    truncate,
+                    normalize,
+                    dimensions,
+                };
+                http.post("/embed", &request, &self.api_key).await?
+            }
+            Transport::Grpc(channel) => {
+                try_join_all(inputs.into_iter().map(|inputs| async move {
+                    let request = self.grpc_request(grpc::EmbedRequest {
+                        inputs,
+                        truncate,
+                        normalize,
+                        dimensions,
+                        truncation_direction: None,
+                    })?;
+                    let response = EmbedClient::new(channel.clone())
+                        .embed(request)
+                        .await
+                        .map_err(to_napi_err)?;
+                    Ok::<_, napi::Error>(response.into_inner().embeddings)
+                }))
+                .await?
+            }
+        };
+        Ok(embeddings.into_iter().map(Float32Array::new).collect())
+    }
+
+    /// Texts sorted by decreasing relevance for `query`. Requires a reranker model
+    #[napi]
+    pub async fn rerank(
+        &self,
+        query: String,
+        texts: Vec<String>,
+        options: Option<PredictOptions>,
+    ) -> Result<Vec<Rank>> {
+        let options = options.unwrap_or_default();
+        let truncate = options.truncate.unwrap_or(false);
+        let raw_scores = options.raw_scores.unwrap_or(false);
+
+        match &self.transport {
+            Transport::Http(http) => {
+                let request = HttpRerankRequest {
+                    query,
+                    texts,
+                    truncate,
+                    raw_scores,
+                };
+                let response: Vec<HttpRank> = http.post("/rerank", &request, &self.api_key).await?;
+                Ok(response
+                    .into_iter()
+                    .map(|rank| Rank {
+                        index: rank.index,
+                        score: rank.score as f64,
+                    })
+                    .collect())
+            }
+            Transport::Grpc(channel) => {
+                let request = self.grpc_request(grpc::RerankRequest {
+                    query,
+                    texts,
+                    truncate,
+                    raw_scores,
+                    return_text: false,
+                    truncation_direction: None,
+                })?;
+                let response = RerankClient::new(channel.clone())
+                    .rerank(request)
+                    .await
+                    .map_err(to_napi_err)?;
+                Ok(response
+                    .into_inner()
+                    .ranks
+                    .into_iter()
+                    .map(|rank| Rank {
+                        index: rank.index,
+                        score: rank.score as f64,
+                    })
+                    .collect())
+            }
+        }
+    }
+
+    /// Scores of every label of the classifier for each input
+    #[napi]
+    pub async fn predict(
+        &self,
+        inputs: Vec<String>,
+        options: Option<PredictOptions>,
+    ) -> Result<Vec<Vec<Prediction>>> {
+        let options = options.unwrap_or_default();
+        let truncate = options.truncate.unwrap_or(false);
+        let raw_scores = options.raw_scores.unwrap_or(false);
+
+        let predictions: Vec<Vec<HttpPrediction>> = match &self.transport {
+            Transport::Http(http) => {
+                let request = HttpPredictRequest {
+                    // Each input is a batch item of a single text
+                    inputs: inputs.into_iter().map(|input| vec![input]).collect(),
+                    truncate,
+                    raw_scores,
+                };
+                http.post("/predict", &request, &self.api_key).await?
+            }
+            Transport::Grpc(channel) => {
+                try_join_all(inputs.into_iter().map(|inputs| async move {
+                    let request = self.grpc_request(grpc::PredictRequest {
+                        inputs,
+                        truncate,
+                        raw_scores,
+                        truncation_direction: None,
+                    })?;
+                    let response = PredictClient::new(channel.clone())
+                        .predict(request)
+                        .await
+                        .map_err(to_napi_err)?;
+                    Ok::<_, napi::Error>(
+                        response
+                            .into_inner()
+                            .predictions
+                            .into_iter()
+                            .map(|prediction| HttpPrediction {
+                                label: prediction.label,
+                                score: prediction.score,
+                            })
+                            .collect(),
+                    )
+                }))
+                .await?
+            }
+        };
+        Ok(predictions
+            .into_iter()
+            .map(|predictions| {
+                predictions
+                    .into_iter()
+                    .map(|prediction| Prediction {
+                        label: prediction.label,
+                        score: prediction.score as f64,
+                    })
+                    .collect()
+            })
+            .collect())
+    }
+}
+
+impl Client {
+    fn grpc_request<T>(&self, message: T) -> Result<tonic::Request<T>> {
+        let mut request = tonic::Request::new(message);
+        if let Some(api_key) = &self.api_key {
+            let value = format!("Bearer {api_key}").parse().map_err(to_napi_err)?;
+            request.metadata_mut().insert("authorization", value);
+        }
+        Ok(request)
+    }
+}
+
+impl HttpTransport {
+    async fn get<T: DeserializeOwned>(&self, route: &str, api_key: &Option<String>) -> Result<T> {
+        send(self.client.get(format!("{}{route}", self.url)), api_key).await
+    }
+
+    async fn post<T: DeserializeOwned>(
+        &self,
+        route: &str,
+        body: &impl Serialize,
+        api_key: &Option<String>,
+    ) -> Result<T> {
+        send(self.client.post(format!("{}{route}", self.url)).json(body), api_key).await
+    }
+}
+
+async fn send<T: DeserializeOwned>(
+    mut request: reqwest::RequestBuilder,
+    api_key: &Option<String>,
+) -> Result<T> {
+    if let Some(api_key) = api_key {
+        request = request.bearer_auth(api_key);
+    }
+    let response = request.send().await.map_err(to_napi_err)?;
+    let status = response.status();
+    if !status.is_success() {
+        let error = response
+            .json::<HttpError>()
+            .await
+            .map(|error| error.error)
+            .unwrap_or_default();
+        return Err(to_napi_err(format!("{status}: {error}")));
+    }
+    response.json().await.map_err(to_napi_err)
+}
+
+#[derive(Serialize)]
+struct HttpEmbedRequest {
+    inputs: Vec<String>,
+    truncate: bool,
+    normalize: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dimensions: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct HttpRerankRequest {
+    query: String,
+    texts: Vec<String>,
+    truncate: bool,
+    raw_scores: bool,
+}
+
+#[derive(Deserialize)]
+struct HttpRank {
+    index: u32,
+    score: f32,
+}
+
+#[derive(Serialize)]
+struct HttpPredictRequest {
+    inputs: Vec<Vec<String>>,
+    truncate: bool,
+    raw_scores: bool,
+}
+
+#[derive(Deserialize)]
+struct HttpPrediction {
+    label: String,
+    score: f32,
+}
+
+#[derive(Deserialize)]
+struct HttpError {
+    error: String,
+}
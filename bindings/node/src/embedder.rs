@@ -0,0 +1,184 @@
+/// Model running in-process
+use crate::{ranks, to_napi_err, EmbedOptions, PredictOptions, Prediction, Rank};
+use clap::ValueEnum;
+use futures::future::try_join_all;
+use napi::bindgen_prelude::Float32Array;
+use napi::Result;
+use text_embeddings_backend::{DType, Pool};
+use text_embeddings_core::infer::Infer;
+use text_embeddings_core::model::{self, ModelOptions};
+use text_embeddings_core::queue::Priority;
+
+#[napi(object)]
+#[derive(Default)]
+pub struct EmbedderOptions {
+    /// Revision of the model on the Hub. Defaults to `main`
+    pub revision: Option<String>,
+    /// `float32` by default
+    pub dtype: Option<String>,
+    /// Pooling of embedding models. Defaults to the pooling configuration of the model
+    pub pooling: Option<String>,
+    /// Defaults to 16384
+    pub max_batch_tokens: Option<u32>,
+    /// Defaults to 512
+    pub max_concurrent_requests: Option<u32>,
+    pub hf_api_token: Option<String>,
+}
+
+/// Embedding model, classifier or reranker loaded from the Hugging Face Hub or a local directory
+#[napi]
+pub struct Embedder {
+    infer: Infer,
+    max_input_length: u32,
+    labels: Vec<String>,
+}
+
+#[napi]
+impl Embedder {
+    /// Download the model if needed and start it
+    #[napi]
+    pub async fn load(model_id: String, options: Option<EmbedderOptions>) -> Result<Embedder> {
+        let options = options.unwrap_or_default();
+        let dtype = options.dtype.as_deref().unwrap_or("float32");
+        let dtype = DType::from_str(dtype, true)
+            .map_err(|_| to_napi_err(format!("Unsupported dtype `{dtype}`")))?;
+        let pooling = options
+            .pooling
+            .map(|pooling| {
+                Pool::from_str(&pooling, true)
+                    .map_err(|_| to_napi_err(format!("Unknown pooling `{pooling}`")))
+            })
+            .transpose()?;
+
+        let model_options = ModelOptions {
+            pooling,
+            max_batch_tokens: options.max_batch_tokens.unwrap_or(16384) as usize,
+            max_concurrent_requests: options.max_concurrent_requests.unwrap_or(512) as usize,
+            tokenization_workers: num_cpus::get_physical(),
+            ..ModelOptions::new(dtype)
+        };
+        let model = model::load(
+            &model_id,
+            options.revision.as_deref(),
+            options.hf_api_token,
+            &model_options,
+        )
+        .await
+        .map_err(to_napi_err)?;
+
+        Ok(Self {
+            infer: model.infer,
+            max_input_length: model.max_input_length as u32,
+            labels: model.config.labels(),
+        })
+    }
+
+    /// Maximum number of tokens of an input. Longer inputs are rejected unless `truncate` is set
+    #[napi(getter)]
+    pub fn max_input_length(&self) -> u32 {
+        self.max_input_length
+    }
+
+    /// Pooled embeddings of `inputs`, computed in batches
+    #[napi]
+    pub async fn embed(
+        &self,
+        inputs: Vec<String>,
+        options: Option<EmbedOptions>,
+    ) -> Result<Vec<Float32Array>> {
+        let options = options.unwrap_or_default();
+        let truncate = options.truncate.unwrap_or(false);
+        let normalize = options.normalize.unwrap_or(true);
+        let dimensions = options.dimensions.map(|dimensions| dimensions as usize);
+        try_join_all(inputs.into_iter().map(|input| async move {
+            let permit = self.infer.acquire_permit().await;
+            let response = self
+                .infer
+                .embed_pooled(
+                    input,
+                    truncate,
+                    None,
+                    normalize,
+                    dimensions,
+                    None,
+                    Priority::Normal,
+                    permit,
+                )
+                .await
+                .map_err(to_napi_err)?;
+            Ok(Float32Array::new(response.results))
+        }))
+        .await
+    }
+
+    /// Texts sorted by decreasing relevance for `query`. Requires a reranker model
+    #[napi]
+    pub async fn rerank(
+        &self,
+        query: String,
+        texts: Vec<String>,
+        options: Option<PredictOptions>,
+    ) -> Result<Vec<Rank>> {
+        let options = options.unwrap_or_default();
+        let truncate = options.truncate.unwrap_or(false);
+        let raw_scores = options.raw_scores.unwrap_or(false);
+        let scores = try_join_all(texts.into_iter().map(|text| {
+            let query = query.clone();
+            async move {
+                let permit = self.infer.acquire_permit().await;
+                let response = self
+                    .infer
+                    .predict(
+                        (query, text),
+                        truncate,
+                        None,
+                        raw_scores,
+                        None,
+                        Priority::Normal,
+                        permit,
+                    )
+                    .await
+                    .map_err(to_napi_err)?;
+                Ok::<_, napi::Error>(response.results[0])
+            }
+        }))
+        .await?;
+        Ok(ranks(scores))
+    }
+
+    /// Scores of every label of the classifier for each input
+    #[napi]
+    pub async fn predict(
+        &self,
+        inputs: Vec<String>,
+        options: Option<PredictOptions>,
+    ) -> Result<Vec<Vec<Prediction>>> {
+        let options = options.unwrap_or_default();
+        let truncate = options.truncate.unwrap_or(false);
+        let raw_scores = options.raw_scores.unwrap_or(false);
+        try_join_all(inputs.into_iter().map(|input| async move {
+            let permit = self.infer.acquire_permit().await;
+            let response = self
+                .infer
+                .predict(input, truncate, None, raw_scores, None, Priority::Normal, permit)
+                .await
+                .map_err(to_napi_err)?;
+            Ok(response
+                .results
+                .into_iter()
+                .enumerate()
+                .map(|(id, score)| Prediction {
+                    label: self.labels.get(id).cloned().unwrap_or_else(|| id.to_string()),
+                    score: score as f64,
+                })
+                .collect())
+        }))
+        .await
+    }
+
+    /// Stop the inference tasks. The embedder cannot be used afterwards
+    #[napi]
+    pub async fn shutdown(&self) {
+        self.infer.shutdown().await;
+    }
+}
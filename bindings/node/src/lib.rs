@@ -0,0 +1,61 @@
+//! Node.js bindings of Text Embeddings Inference.
+//! `Embedder` runs a model in-process on the CPU and `Client` calls a running server over HTTP
+//! or gRPC. Both return the same types.
+
+mod client;
+mod embedder;
+#[allow(clippy::derive_partial_eq_without_eq)]
+mod pb;
+
+#[macro_use]
+extern crate napi_derive;
+
+#[napi(object)]
+#[derive(Default)]
+pub struct EmbedOptions {
+    /// Truncate the inputs longer than the maximum input length of the model
+    pub truncate: Option<bool>,
+    /// Defaults to `true`
+    pub normalize: Option<bool>,
+    /// Truncate the embeddings to their first `dimensions` values
+    pub dimensions: Option<u32>,
+}
+
+#[napi(object)]
+#[derive(Default)]
+pub struct PredictOptions {
+    pub truncate: Option<bool>,
+    /// Return the scores before the activation of the classifier
+    pub raw_scores: Option<bool>,
+}
+
+#[napi(object)]
+pub struct Rank {
+    /// Index of the text in the request
+    pub index: u32,
+    pub score: f64,
+}
+
+#[napi(object)]
+pub struct Prediction {
+    pub label: String,
+    pub score: f64,
+}
+
+/// Sort the scores of the texts by decreasing relevance, as the server does
+fn ranks(scores: Vec<f32>) -> Vec<Rank> {
+    let mut ranks: Vec<Rank> = scores
+        .into_iter()
+        .enumerate()
+        .map(|(index, score)| Rank {
+            index: index as u32,
+            score: score as f64,
+        })
+        .collect();
+    ranks.sort_by(|a, b| b.score.total_cmp(&a.score));
+    ranks
+}
+
+fn to_napi_err(err: impl std::fmt::Display) -> napi::Error {
+    napi::Error::from_reason(err.to_string())
+}
@@ -0,0 +1,211 @@
+//! Python bindings of Text Embeddings Inference.
+//! `Embedder` runs the model in-process with the Candle backend, with the same batching and
+//! tokenization as the router, without an HTTP server.
+use clap::ValueEnum;
+use futures::future::try_join_all;
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use text_embeddings_backend::{DType, Pool};
+use text_embeddings_core::infer::Infer;
+use text_embeddings_core::model::{self, LoadError, ModelOptions};
+use text_embeddings_core::queue::Priority;
+use text_embeddings_core::TextEmbeddingsError;
+use tokio::runtime::Runtime;
+
+/// Embedding model, classifier or reranker loaded from the Hugging Face Hub or a local directory
+#[pyclass(module = "text_embeddings_inference")]
+struct Embedder {
+    infer: Infer,
+    runtime: Runtime,
+    max_input_length: usize,
+}
+
+#[pymethods]
+impl Embedder {
+    #[new]
+    #[pyo3(signature = (
+        model_id,
+        revision = None,
+        dtype = None,
+        pooling = None,
+        max_batch_tokens = 16384,
+        max_concurrent_requests = 512,
+        hf_api_token = None,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        py: Python<'_>,
+        model_id: String,
+        revision: Option<String>,
+        dtype: Option<String>,
+        pooling: Option<String>,
+        max_batch_tokens: usize,
+        max_concurrent_requests: usize,
+        hf_api_token: Option<String>,
+    ) -> PyResult<Self> {
+        // Float16 is only fast on GPUs
+        let dtype = dtype.unwrap_or_else(|| {
+            if cfg!(any(feature = "cuda", feature = "metal")) {
+                "float16".to_string()
+            } else {
+                "float32".to_string()
+            }
+        });
+        let dtype = DType::from_str(&dtype, true)
+            .map_err(|_| PyValueError::new_err(format!("Unsupported dtype `{dtype}`")))?;
+        let pooling = pooling
+            .map(|pooling| {
+                Pool::from_str(&pooling, true)
+                    .map_err(|_| PyValueError::new_err(format!("Unknown pooling `{pooling}`")))
+            })
+            .transpose()?;
+
+        let options = ModelOptions {
+            pooling,
+            max_batch_tokens,
+            max_concurrent_requests,
+            tokenization_workers: num_cpus::get_physical(),
+            ..ModelOptions::new(dtype)
+        };
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+        let model = py
+            .allow_threads(|| {
+                runtime.block_on(model::load(
+                    &model_id,
+                    revision.as_deref(),
+                    hf_api_token,
+                    &options,
+                ))
+            })
+            .map_err(|err| match err {
+                LoadError::Config(_) => PyValueError::new_err(err.to_string()),
+                _ => PyRuntimeError::new_err(err.to_string()),
+            })?;
+
+        Ok(Self {
+            infer: model.infer,
+            runtime,
+            max_input_length: model.max_input_length,
+        })
+    }
+
+    /// Maximum number of tokens of an input. Longer inputs are rejected unless `truncate` is set
+    #[getter]
+    fn max_input_length(&self) -> usize {
+        self.max_input_length
+    }
+
+    /// Pooled embeddings of `inputs`, computed in batches
+    #[pyo3(signature = (inputs, truncate = false, normalize = true, dimensions = None))]
+    fn embed(
+        &self,
+        py: Python<'_>,
+        inputs: Vec<String>,
+        truncate: bool,
+        normalize: bool,
+        dimensions: Option<usize>,
+    ) -> PyResult<Vec<Vec<f32>>> {
+        py.allow_threads(|| {
+            self.runtime.block_on(try_join_all(inputs.into_iter().map(|input| async move {
+                let permit = self.infer.acquire_permit().await;
+                let response = self
+                    .infer
+                    .embed_pooled(
+                        input,
+                        truncate,
+                        None,
+                        normalize,
+                        dimensions,
+                        None,
+                        Priority::Normal,
+                        permit,
+                    )
+                    .await?;
+                Ok(response.results)
+            })))
+        })
+        .map_err(to_py_err)
+    }
+
+    /// Relevance score of every text of `texts` for `query`. Requires a reranker model
+    #[pyo3(signature = (query, texts, truncate = false, raw_scores = false))]
+    fn rerank(
+        &self,
+        py: Python<'_>,
+        query: String,
+        texts: Vec<String>,
+        truncate: bool,
+        raw_scores: bool,
+    ) -> PyResult<Vec<f32>> {
+        py.allow_threads(|| {
+            self.runtime.block_on(try_join_all(texts.into_iter().map(|text| {
+                let query = query.clone();
+                async move {
+                    let permit = self.infer.acquire_permit().await;
+                    let response = self
+                        .infer
+                        .predict(
+                            (query, text),
+                            truncate,
+                            None,
+                            raw_scores,
+                            None,
+                            Priority::Normal,
+                            permit,
+                        )
+                        .await?;
+                    Ok(response.results[0])
+                }
+            })))
+        })
+        .map_err(to_py_err)
+    }
+
+    /// Scores of every label of the classifier for each input
+    #[pyo3(signature = (inputs, truncate = false, raw_scores = false))]
+    fn predict(
+        &self,
+        py: Python<'_>,
+        inputs: Vec<String>,
+        truncate: bool,
+        raw_scores: bool,
+    ) -> PyResult<Vec<Vec<f32>>> {
+        py.allow_threads(|| {
+            self.runtime.block_on(try_join_all(inputs.into_iter().map(|input| async move {
+                let permit = self.infer.acquire_permit().await;
+                let response = self
+                    .infer
+                    .predict(input, truncate, None, raw_scores, None, Priority::Normal, permit)
+                    .await?;
+                Ok(response.results)
+            })))
+        })
+        .map_err(to_py_err)
+    }
+}
+
+impl Drop for Embedder {
+    fn drop(&mut self) {
+        self.runtime.block_on(self.infer.shutdown());
+    }
+}
+
+fn to_py_err(err: TextEmbeddingsError) -> PyErr {
+    match err {
+        TextEmbeddingsError::Tokenizer(_) | TextEmbeddingsError::Validation(_) => {
+            PyValueError::new_err(err.to_string())
+        }
+        TextEmbeddingsError::Overloaded(_) | TextEmbeddingsError::Backend(_) => {
+            PyRuntimeError::new_err(err.to_string())
+        }
+    }
+}
+
+#[pymodule]
+fn text_embeddings_inference(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
+    m.add_class::<Embedder>()?;
+    m.add("__version__", env!("CARGO_PKG_VERSION"))?;
+    Ok(())
+}
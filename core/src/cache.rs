@@ -0,0 +1,200 @@
+use crate::tokenization::{EncodingInput, TruncationDirection};
+use lru::LruCache;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use text_embeddings_backend::Pool;
+
+/// Pooled embedding stored in the cache
+#[derive(Debug, Clone)]
+pub(crate) struct CachedEmbedding {
+    pub results: Vec<f32>,
+    pub prompt_tokens: usize,
+    pub dropped_tokens: usize,
+}
+
+impl CachedEmbedding {
+    /// Little-endian `prompt_tokens` and `dropped_tokens` followed by the little-endian values
+    #[cfg(feature = "redis")]
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(8 + 4 * self.results.len());
+        bytes.extend_from_slice(&(self.prompt_tokens as u32).to_le_bytes());
+        bytes.extend_from_slice(&(self.dropped_tokens as u32).to_le_bytes());
+        for v in &self.results {
+            bytes.extend_from_slice(&v.to_le_bytes());
+        }
+        bytes
+    }
+
+    #[cfg(feature = "redis")]
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 8 || bytes.len() % 4 != 0 {
+            return None;
+        }
+        let mut chunks = bytes.chunks_exact(4).map(|c| [c[0], c[1], c[2], c[3]]);
+        let prompt_tokens = u32::from_le_bytes(chunks.next()?) as usize;
+        let dropped_tokens = u32::from_le_bytes(chunks.next()?) as usize;
+        let results = chunks.map(f32::from_le_bytes).collect();
+        Some(Self {
+            results,
+            prompt_tokens,
+            dropped_tokens,
+        })
+    }
+}
+
+/// Cache of pooled embeddings keyed by model, input and embedding parameters.
+/// Inputs are used as is: two texts only share an entry if they are identical.
+#[derive(Clone)]
+pub struct EmbeddingCache {
+    /// Identifies the model in the keys so that several models can share the same store
+    prefix: String,
+    /// Entries older than `ttl` are not returned
+    ttl: Option<Duration>,
+    store: CacheStore,
+}
+
+#[derive(Clone)]
+enum CacheStore {
+    Memory(Arc<Mutex<LruCache<String, (Instant, CachedEmbedding)>>>),
+    #[cfg(feature = "redis")]
+    Redis(redis::aio::ConnectionManager),
+}
+
+impl EmbeddingCache {
+    /// In-memory LRU cache holding at most `capacity` embeddings
+    pub fn memory(capacity: NonZeroUsize, ttl: Option<Duration>) -> Self {
+        Self {
+            prefix: String::new(),
+            ttl,
+            store: CacheStore::Memory(Arc::new(Mutex::new(LruCache::new(capacity)))),
+        }
+    }
+
+    /// Cache stored in Redis. Can be shared by several replicas of the router
+    #[cfg(feature = "redis")]
+    pub async fn redis(url: &str, ttl: Option<Duration>) -> redis::RedisResult<Self> {
+        let client = redis::Client::open(url)?;
+        let connection = redis::aio::ConnectionManager::new(client).await?;
+        Ok(Self {
+            prefix: String::new(),
+            ttl,
+            store: CacheStore::Redis(connection),
+        })
+    }
+
+    /// Cache sharing the same store but with keys prefixed with `prefix`
+    pub fn with_prefix(&self, prefix: String) -> Self {
+        Self {
+            prefix,
+            ttl: self.ttl,
+            store: self.store.clone(),
+        }
+    }
+
+    pub(crate) fn key(
+        &self,
+        input: &EncodingInput,
+        truncate: bool,
+        truncation_direction: TruncationDirection,
+        normalize: bool,
+        dimensions: Option<usize>,
+        pooling: Option<&Pool>,
+    ) -> String {
+        let input = match input {
+            EncodingInput::Single(s) => format!("s:{s}"),
+            EncodingInput::Dual(s1, s2) => format!("d:{}:{s1}{s2}", s1.len()),
+            EncodingInput::Ids(ids) => format!("i:{ids:?}"),
+        };
+        // Right truncation keeps the keys written before the direction was configurable
+        let truncate = match (truncate, truncation_direction) {
+            (true, TruncationDirection::Left) => "left".to_string(),
+            _ => truncate.to_string(),
+        };
+        let pooling = pooling.map(Pool::to_string).unwrap_or_default();
+        // `te2` entries also store the number of tokens dropped by truncation
+        format!(
+            "te2:{}:{truncate}:{normalize}:{dimensions:?}:{pooling}:{input}",
+            self.prefix
+        )
+    }
+
+    pub(crate) async fn get(&self, key: &str) -> Option<CachedEmbedding> {
+        let cached = match &self.store {
+            CacheStore::Memory(cache) => {
+                let mut cache = cache.lock().unwrap();
+                let cached = cache
+                    .get(key)
+                    .filter(|(inserted, _)| !self.is_expired(*inserted))
+                    .map(|(_, embedding)| embedding.clone());
+                if cached.is_none() {
+                    // Remove expired entry
+                    cache.pop(key);
+                }
+                cached
+            }
+            #[cfg(feature = "redis")]
+            CacheStore::Redis(connection) => {
+                use redis::AsyncCommands;
+
+                let mut connection = connection.clone();
+                match connection.get::<_, Option<Vec<u8>>>(key).await {
+                    Ok(bytes) => bytes.and_then(|bytes| CachedEmbedding::from_bytes(&bytes)),
+                    Err(err) => {
+                        tracing::warn!("Could not read from the embedding cache: {err}");
+                        None
+                    }
+                }
+            }
+        };
+
+        match cached {
+            Some(_) => metrics::increment_counter!("te_embed_cache_hit"),
+            None => metrics::increment_counter!("te_embed_cache_miss"),
+        }
+        cached
+    }
+
+    pub(crate) async fn insert(&self, key: String, embedding: CachedEmbedding) {
+        match &self.store {
+            CacheStore::Memory(cache) => {
+                let mut cache = cache.lock().unwrap();
+                cache.put(key, (Instant::now(), embedding));
+                metrics::gauge!("te_embed_cache_size", cache.len() as f64);
+            }
+            #[cfg(feature = "redis")]
+            CacheStore::Redis(connection) => {
+                use redis::AsyncCommands;
+
+                let mut connection = connection.clone();
+                let bytes = embedding.to_bytes();
+                let result: redis::RedisResult<()> = match self.ttl {
+                    Some(ttl) => connection.set_ex(key, bytes, ttl.as_secs().max(1)).await,
+                    None => connection.set(key, bytes).await,
+                };
+                if let Err(err) = result {
+                    tracing::warn!("Could not write to the embedding cache: {err}");
+                }
+            }
+        }
+    }
+
+    fn is_expired(&self, inserted: Instant) -> bool {
+        self.ttl.map_or(false, |ttl| inserted.elapsed() > ttl)
+    }
+}
+
+impl std::fmt::Debug for EmbeddingCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let store = match &self.store {
+            CacheStore::Memory(_) => "memory",
+            #[cfg(feature = "redis")]
+            CacheStore::Redis(_) => "redis",
+        };
+        f.debug_struct("EmbeddingCache")
+            .field("prefix", &self.prefix)
+            .field("ttl", &self.ttl)
+            .field("store", &store)
+            .finish()
+    }
+}
@@ -1,7 +1,51 @@
-use hf_hub::api::tokio::{ApiError, ApiRepo};
+use crate::slow_tokenizer::{SLOW_TOKENIZER_CONFIG_FILES, SLOW_TOKENIZER_FILES};
+use hf_hub::api::tokio::{ApiBuilder, ApiError, ApiRepo};
+use hf_hub::{Repo, RepoType};
+use std::collections::BTreeSet;
 use std::path::PathBuf;
 use tracing::instrument;
 
+/// Download a model from the Hub with its optional prompts and `2_Dense` projection, and its
+/// pooling configuration if `pooling_config` is set. Returns the model root
+#[instrument(skip(hf_api_token))]
+pub async fn download_model(
+    model_id: &str,
+    revision: Option<&str>,
+    hf_api_token: Option<String>,
+    huggingface_hub_cache: Option<String>,
+    pooling_config: bool,
+) -> Result<PathBuf, ApiError> {
+    let mut builder = ApiBuilder::new()
+        .with_progress(false)
+        .with_token(hf_api_token);
+
+    if let Some(cache_dir) = huggingface_hub_cache {
+        builder = builder.with_cache_dir(cache_dir.into());
+    }
+
+    let api = builder.build()?;
+    let api_repo = api.repo(Repo::with_revision(
+        model_id.to_string(),
+        RepoType::Model,
+        revision.unwrap_or("main").to_string(),
+    ));
+
+    // Optionally download the pooling config.
+    if pooling_config {
+        // If a pooling config exist, download it
+        let _ = download_pool_config(&api_repo).await;
+    }
+
+    // Optionally download the sentence-transformers config holding the prompts
+    let _ = download_st_config(&api_repo).await;
+
+    // Optionally download the projection applied after pooling
+    let _ = download_dense(&api_repo).await;
+
+    // Download model from the Hub
+    download_artifacts(&api_repo).await
+}
+
 #[instrument(skip_all)]
 pub async fn download_artifacts(api: &ApiRepo) -> Result<PathBuf, ApiError> {
     let start = std::time::Instant::now();
@@ -9,26 +53,117 @@ pub async fn download_artifacts(api: &ApiRepo) -> Result<PathBuf, ApiError> {
     tracing::info!("Starting download");
 
     api.get("config.json").await?;
-    api.get("tokenizer.json").await?;
-
-    let model_root = match api.get("model.safetensors").await {
-        Ok(p) => p,
-        Err(_) => {
-            let p = api.get("pytorch_model.bin").await?;
-            tracing::warn!("`model.safetensors` not found. Using `pytorch_model.bin` instead. Model loading will be significantly slower.");
-            p
+    if let Err(err) = api.get("tokenizer.json").await {
+        if !download_slow_tokenizer(api).await {
+            return Err(err);
         }
     }
+
+    let model_root = if cfg!(feature = "ort") {
+        download_onnx(api).await?
+    } else {
+        match api.get("model.safetensors").await {
+            Ok(p) => p,
+            Err(_) => match download_safetensors_shards(api).await {
+                Ok(p) => p,
+                Err(_) => match api.get("pytorch_model.bin").await {
+                    Ok(p) => {
+                        tracing::warn!("`model.safetensors` not found. Using `pytorch_model.bin` instead. Model loading will be significantly slower.");
+                        p
+                    }
+                    Err(err) => match api.get("model.gguf").await {
+                        Ok(p) => {
+                            tracing::info!("Using GGUF quantized weights `model.gguf`");
+                            p
+                        }
+                        Err(_) => return Err(err),
+                    },
+                },
+            },
+        }
         .parent()
         .unwrap()
-        .to_path_buf();
+        .to_path_buf()
+    };
 
     tracing::info!("Model artifacts downloaded in {:?}", start.elapsed());
     Ok(model_root)
 }
 
+/// Download the files of a slow tokenizer, converted at startup. Returns `false` if the model has
+/// none
+async fn download_slow_tokenizer(api: &ApiRepo) -> bool {
+    'vocabulary: for files in SLOW_TOKENIZER_FILES {
+        for file in files {
+            if api.get(file).await.is_err() {
+                continue 'vocabulary;
+            }
+        }
+        for file in SLOW_TOKENIZER_CONFIG_FILES {
+            let _ = api.get(file).await;
+        }
+        return true;
+    }
+    false
+}
+
+/// Download the shards listed in `model.safetensors.index.json` and return the path of the index
+async fn download_safetensors_shards(api: &ApiRepo) -> Result<PathBuf, ApiError> {
+    let index_path = api.get("model.safetensors.index.json").await?;
+    let index: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&index_path)?)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+
+    let shards: BTreeSet<&str> = index["weight_map"]
+        .as_object()
+        .into_iter()
+        .flat_map(|weight_map| weight_map.values())
+        .filter_map(|shard| shard.as_str())
+        .collect();
+    for shard in &shards {
+        api.get(shard).await?;
+    }
+    tracing::info!("Downloaded {} safetensors shards", shards.len());
+    Ok(index_path)
+}
+
+/// Download the ONNX weights and return the model root
+async fn download_onnx(api: &ApiRepo) -> Result<PathBuf, ApiError> {
+    match api.get("model.onnx").await {
+        Ok(p) => Ok(p.parent().unwrap().to_path_buf()),
+        Err(_) => {
+            let p = api.get("onnx/model.onnx").await?;
+            Ok(p.parent().unwrap().parent().unwrap().to_path_buf())
+        }
+    }
+}
+
+#[instrument(skip_all)]
+pub async fn download_st_config(api: &ApiRepo) -> Result<PathBuf, ApiError> {
+    let st_config_path = api.get("config_sentence_transformers.json").await?;
+    Ok(st_config_path)
+}
+
 #[instrument(skip_all)]
 pub async fn download_pool_config(api: &ApiRepo) -> Result<PathBuf, ApiError> {
     let pool_config_path = api.get("1_Pooling/config.json").await?;
     Ok(pool_config_path)
 }
+
+/// Download the sentence-transformers `Dense` projection applied after pooling
+#[instrument(skip_all)]
+pub async fn download_dense(api: &ApiRepo) -> Result<PathBuf, ApiError> {
+    api.get("2_Dense/config.json").await?;
+    let dense_path = match api.get("2_Dense/model.safetensors").await {
+        Ok(p) => p,
+        Err(_) => api.get("2_Dense/pytorch_model.bin").await?,
+    };
+    Ok(dense_path.parent().unwrap().to_path_buf())
+}
+
+/// Download a PEFT LoRA adapter and return the adapter root
+#[instrument(skip_all)]
+pub async fn download_lora_adapter(api: &ApiRepo) -> Result<PathBuf, ApiError> {
+    api.get("adapter_config.json").await?;
+    let adapter_path = api.get("adapter_model.safetensors").await?;
+    Ok(adapter_path.parent().unwrap().to_path_buf())
+}
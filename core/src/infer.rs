@@ -1,11 +1,16 @@
-use crate::queue::{Entry, Metadata, NextBatch, Queue};
-use crate::tokenization::{EncodingInput, RawEncoding, Tokenization};
+use crate::cache::{CachedEmbedding, EmbeddingCache};
+use crate::queue::{Entry, Metadata, NextBatch, Priority, Queue};
+use crate::tokenization::{
+    EncodingInput, RawEncoding, TokenCount, Tokenization, TruncationDirection,
+};
 use crate::TextEmbeddingsError;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
-use text_embeddings_backend::{Backend, BackendError, Embedding, ModelType};
+use text_embeddings_backend::{
+    Backend, BackendError, Embedding, EmbeddingQuantization, ModelType, Pool, QuantizedEmbedding,
+};
 use tokio::sync::{mpsc, oneshot, watch, Notify, OwnedSemaphorePermit, Semaphore};
-use tracing::instrument;
+use tracing::{instrument, Instrument, Span};
 
 /// Inference struct
 #[derive(Debug, Clone)]
@@ -16,7 +21,19 @@ pub struct Infer {
     notify_batching_task: Arc<Notify>,
     /// Inference limit
     limit_concurrent_requests: Arc<Semaphore>,
+    max_concurrent_requests: usize,
+    /// Stops the background tasks
+    shutdown_sender: Arc<watch::Sender<bool>>,
+    /// Closed once every clone of this `Infer` is dropped
+    clones_sender: Arc<watch::Sender<()>>,
+    clone_guard: CloneGuard,
     backend: Backend,
+    /// Activation applied on classifier scores
+    classifier_activation: ClassifierActivation,
+    /// Cache of pooled embeddings
+    cache: Option<EmbeddingCache>,
+    /// Label of the metrics
+    model: String,
 }
 
 impl Infer {
@@ -26,25 +43,37 @@ impl Infer {
         queue: Queue,
         max_concurrent_requests: usize,
         backend: Backend,
+        classifier_activation: ClassifierActivation,
+        sub_batch_tokens: Option<usize>,
+        model: String,
     ) -> Self {
         let notify_batching_task = Arc::new(Notify::new());
 
         let (embed_sender, embed_receiver) = mpsc::unbounded_channel();
+        let (shutdown_sender, shutdown_receiver) = watch::channel(false);
+        let (clones_sender, clones_receiver) = watch::channel(());
 
         // Create two batching tasks to prefetch batches
         tokio::spawn(batching_task(
             queue.clone(),
             notify_batching_task.clone(),
             embed_sender.clone(),
+            shutdown_receiver.clone(),
         ));
         tokio::spawn(batching_task(
             queue.clone(),
             notify_batching_task.clone(),
             embed_sender,
+            shutdown_receiver,
         ));
 
         // Create embed task to communicate with backend
-        tokio::spawn(backend_task(backend.clone(), embed_receiver));
+        tokio::spawn(backend_task(
+            backend.clone(),
+            queue.clone(),
+            embed_receiver,
+            sub_batch_tokens,
+        ));
 
         // Inference limit with a semaphore
         let semaphore = Arc::new(Semaphore::new(max_concurrent_requests));
@@ -54,10 +83,23 @@ impl Infer {
             queue,
             notify_batching_task,
             limit_concurrent_requests: semaphore,
+            max_concurrent_requests,
+            shutdown_sender: Arc::new(shutdown_sender),
+            clones_sender: Arc::new(clones_sender),
+            clone_guard: CloneGuard(std::sync::Mutex::new(Some(clones_receiver))),
             backend,
+            classifier_activation,
+            cache: None,
+            model,
         }
     }
 
+    /// Serve identical pooled embedding requests from `cache`
+    pub fn with_cache(mut self, cache: EmbeddingCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
     #[instrument(skip(self))]
     pub async fn tokenize<I: Into<EncodingInput> + std::fmt::Debug>(
         &self,
@@ -68,7 +110,50 @@ impl Infer {
             .tokenize(inputs.into(), add_special_tokens)
             .await
             .map_err(|err| {
-                metrics::increment_counter!("te_request_failure", "err" => "tokenization");
+                metrics::increment_counter!(
+                    "te_request_failure",
+                    "err" => "tokenization",
+                    "model" => self.model.clone()
+                );
+                tracing::error!("{err}");
+                err
+            })
+    }
+
+    #[instrument(skip(self))]
+    pub async fn count_tokens<I: Into<EncodingInput> + std::fmt::Debug>(
+        &self,
+        inputs: I,
+    ) -> Result<TokenCount, TextEmbeddingsError> {
+        self.tokenization
+            .count(inputs.into())
+            .await
+            .map_err(|err| {
+                metrics::increment_counter!(
+                    "te_request_failure",
+                    "err" => "tokenization",
+                    "model" => self.model.clone()
+                );
+                tracing::error!("{err}");
+                err
+            })
+    }
+
+    #[instrument(skip(self))]
+    pub async fn decode(
+        &self,
+        ids: Vec<u32>,
+        skip_special_tokens: bool,
+    ) -> Result<String, TextEmbeddingsError> {
+        self.tokenization
+            .decode(ids, skip_special_tokens)
+            .await
+            .map_err(|err| {
+                metrics::increment_counter!(
+                    "te_request_failure",
+                    "err" => "tokenization",
+                    "model" => self.model.clone()
+                );
                 tracing::error!("{err}");
                 err
             })
@@ -81,7 +166,11 @@ impl Infer {
             .limit_concurrent_requests
             .try_acquire_owned()
             .map_err(|err| {
-                metrics::increment_counter!("te_request_failure", "err" => "overloaded");
+                metrics::increment_counter!(
+                    "te_request_failure",
+                    "err" => "overloaded",
+                    "model" => self.model.clone()
+                );
                 tracing::error!("{err}");
                 TextEmbeddingsError::from(err)
             })
@@ -101,12 +190,24 @@ impl Infer {
         &self,
         inputs: I,
         truncate: bool,
+        truncation_direction: Option<TruncationDirection>,
+        priority: Priority,
         permit: OwnedSemaphorePermit,
     ) -> Result<AllEmbeddingsInferResponse, TextEmbeddingsError> {
         let start_time = Instant::now();
 
         let results = self
-            .embed(inputs, truncate, false, &start_time, permit)
+            .embed(
+                inputs,
+                truncate,
+                truncation_direction,
+                false,
+                false,
+                None,
+                priority,
+                &start_time,
+                permit,
+            )
             .await?;
 
         let InferResult::AllEmbedding(response) = results else {
@@ -117,91 +218,318 @@ impl Infer {
         let total_time = start_time.elapsed();
 
         // Metrics
-        metrics::increment_counter!("te_embed_success");
-        metrics::histogram!("te_embed_duration", total_time.as_secs_f64());
+        metrics::increment_counter!("te_embed_success", "model" => self.model.clone());
+        metrics::histogram!(
+            "te_embed_duration",
+            total_time.as_secs_f64(),
+            "model" => self.model.clone()
+        );
         metrics::histogram!(
             "te_embed_tokenization_duration",
-            response.metadata.tokenization.as_secs_f64()
+            response.metadata.tokenization.as_secs_f64(),
+            "model" => self.model.clone()
         );
         metrics::histogram!(
             "te_embed_queue_duration",
-            response.metadata.queue.as_secs_f64()
+            response.metadata.queue.as_secs_f64(),
+            "model" => self.model.clone()
         );
         metrics::histogram!(
             "te_embed_inference_duration",
-            response.metadata.inference.as_secs_f64()
+            response.metadata.inference.as_secs_f64(),
+            "model" => self.model.clone()
         );
 
         Ok(response)
     }
 
+    #[allow(clippy::too_many_arguments)]
     #[instrument(skip(self, permit))]
     pub async fn embed_pooled<I: Into<EncodingInput> + std::fmt::Debug>(
         &self,
         inputs: I,
         truncate: bool,
+        truncation_direction: Option<TruncationDirection>,
         normalize: bool,
+        dimensions: Option<usize>,
+        pooling: Option<Pool>,
+        priority: Priority,
         permit: OwnedSemaphorePermit,
     ) -> Result<PooledEmbeddingsInferResponse, TextEmbeddingsError> {
         let start_time = Instant::now();
 
-        let results = self
-            .embed(inputs, truncate, true, &start_time, permit)
-            .await?;
+        // Only pooling methods other than the one of the model are applied by the router
+        let pooling =
+            pooling.filter(|pool| self.backend.model_type != ModelType::Embedding(pool.clone()));
+        if pooling == Some(Pool::Splade) {
+            metrics::increment_counter!(
+                "te_request_failure",
+                "err" => "validation",
+                "model" => self.model.clone()
+            );
+            let message = "`splade` pooling is only supported by SPLADE models".to_string();
+            tracing::error!("{message}");
+            return Err(TextEmbeddingsError::Validation(message));
+        }
+        // The `2_Dense` projection is only applied by the backend after the pooling of the model
+        if pooling.is_some() && self.backend.dense_model {
+            metrics::increment_counter!(
+                "te_request_failure",
+                "err" => "validation",
+                "model" => self.model.clone()
+            );
+            let message =
+                "`pooling` cannot be overridden for models with a `2_Dense` projection".to_string();
+            tracing::error!("{message}");
+            return Err(TextEmbeddingsError::Validation(message));
+        }
 
-        let InferResult::PooledEmbedding(mut response) = results else {
-            panic!("unexpected enum variant")
+        let inputs = inputs.into();
+        let cache_key = self.cache.as_ref().map(|cache| {
+            let direction =
+                truncation_direction.unwrap_or(self.tokenization.truncation_direction());
+            cache.key(
+                &inputs,
+                truncate,
+                direction,
+                normalize,
+                dimensions,
+                pooling.as_ref(),
+            )
+        });
+        if let (Some(cache), Some(key)) = (&self.cache, &cache_key) {
+            // Cache hits skip tokenization and inference
+            if let Some(cached) = cache.get(key).await {
+                metrics::increment_counter!("te_embed_success", "model" => self.model.clone());
+                return Ok(PooledEmbeddingsInferResponse {
+                    results: cached.results,
+                    metadata: InferMetadata {
+                        prompt_tokens: cached.prompt_tokens,
+                        dropped_tokens: cached.dropped_tokens,
+                        tokenization: Duration::ZERO,
+                        queue: Duration::ZERO,
+                        inference: Duration::ZERO,
+                        batch_id: None,
+                    },
+                });
+            }
+        }
+
+        // Embeddings are normalized on device unless they are truncated or pooled here first
+        let backend_normalize = normalize && dimensions.is_none() && pooling.is_none();
+
+        let mut response = match pooling {
+            Some(pool) => {
+                // Pool the token embeddings
+                let results = self
+                    .embed(
+                        inputs,
+                        truncate,
+                        truncation_direction,
+                        false,
+                        false,
+                        None,
+                        priority,
+                        &start_time,
+                        permit,
+                    )
+                    .await?;
+
+                let InferResult::AllEmbedding(response) = results else {
+                    panic!("unexpected enum variant")
+                };
+                PooledEmbeddingsInferResponse {
+                    results: pool_embeddings(&pool, response.results),
+                    metadata: response.metadata,
+                }
+            }
+            None => {
+                let results = self
+                    .embed(
+                        inputs,
+                        truncate,
+                        truncation_direction,
+                        true,
+                        backend_normalize,
+                        None,
+                        priority,
+                        &start_time,
+                        permit,
+                    )
+                    .await?;
+
+                let InferResult::PooledEmbedding(response) = results else {
+                    panic!("unexpected enum variant")
+                };
+                response
+            }
         };
 
-        if normalize {
-            // Normalize embedding
-            let scale = (1.0
-                / response
-                    .results
-                    .iter()
-                    .map(|v| {
-                        let v = *v as f64;
-                        v * v
-                    })
-                    .sum::<f64>()
-                    .sqrt()) as f32;
-            for v in response.results.iter_mut() {
-                *v *= scale;
+        if let Some(dimensions) = dimensions {
+            // Matryoshka truncation. Normalization is applied on the truncated embedding
+            let hidden_size = response.results.len();
+            if dimensions == 0 || dimensions > hidden_size {
+                metrics::increment_counter!(
+                    "te_request_failure",
+                    "err" => "validation",
+                    "model" => self.model.clone()
+                );
+                let message = format!(
+                    "`dimensions` must be between 1 and {hidden_size}. Given: {dimensions}"
+                );
+                tracing::error!("{message}");
+                return Err(TextEmbeddingsError::Validation(message));
             }
+            response.results.truncate(dimensions);
+        }
+
+        if normalize && !backend_normalize {
+            text_embeddings_backend::normalize(&mut response.results);
+        }
+
+        if let (Some(cache), Some(key)) = (&self.cache, cache_key) {
+            let embedding = CachedEmbedding {
+                results: response.results.clone(),
+                prompt_tokens: response.metadata.prompt_tokens,
+                dropped_tokens: response.metadata.dropped_tokens,
+            };
+            cache.insert(key, embedding).await;
         }
 
         // Timings
         let total_time = start_time.elapsed();
 
         // Metrics
-        metrics::increment_counter!("te_embed_success");
-        metrics::histogram!("te_embed_duration", total_time.as_secs_f64());
+        metrics::increment_counter!("te_embed_success", "model" => self.model.clone());
+        metrics::histogram!(
+            "te_embed_duration",
+            total_time.as_secs_f64(),
+            "model" => self.model.clone()
+        );
         metrics::histogram!(
             "te_embed_tokenization_duration",
-            response.metadata.tokenization.as_secs_f64()
+            response.metadata.tokenization.as_secs_f64(),
+            "model" => self.model.clone()
         );
         metrics::histogram!(
             "te_embed_queue_duration",
-            response.metadata.queue.as_secs_f64()
+            response.metadata.queue.as_secs_f64(),
+            "model" => self.model.clone()
         );
         metrics::histogram!(
             "te_embed_inference_duration",
-            response.metadata.inference.as_secs_f64()
+            response.metadata.inference.as_secs_f64(),
+            "model" => self.model.clone()
         );
 
         Ok(response)
     }
 
+    /// Normalized pooled embedding quantized with `quantization`.
+    /// The embedding is quantized on device unless it is truncated, pooled by the router or
+    /// cached, in which case it is quantized on the host
+    #[allow(clippy::too_many_arguments)]
+    #[instrument(skip(self, permit))]
+    pub async fn embed_quantized<I: Into<EncodingInput> + std::fmt::Debug>(
+        &self,
+        inputs: I,
+        truncate: bool,
+        truncation_direction: Option<TruncationDirection>,
+        dimensions: Option<usize>,
+        pooling: Option<Pool>,
+        quantization: EmbeddingQuantization,
+        priority: Priority,
+        permit: OwnedSemaphorePermit,
+    ) -> Result<QuantizedEmbeddingsInferResponse, TextEmbeddingsError> {
+        let pooling =
+            pooling.filter(|pool| self.backend.model_type != ModelType::Embedding(pool.clone()));
+        if dimensions.is_some() || pooling.is_some() || self.cache.is_some() {
+            let response = self
+                .embed_pooled(
+                    inputs,
+                    truncate,
+                    truncation_direction,
+                    true,
+                    dimensions,
+                    pooling,
+                    priority,
+                    permit,
+                )
+                .await?;
+            return Ok(QuantizedEmbeddingsInferResponse {
+                results: QuantizedEmbedding::new(quantization, &response.results),
+                metadata: response.metadata,
+            });
+        }
+
+        let start_time = Instant::now();
+
+        let results = self
+            .embed(
+                inputs,
+                truncate,
+                truncation_direction,
+                true,
+                true,
+                Some(quantization),
+                priority,
+                &start_time,
+                permit,
+            )
+            .await?;
+
+        let InferResult::QuantizedEmbedding(response) = results else {
+            panic!("unexpected enum variant")
+        };
+
+        // Timings
+        let total_time = start_time.elapsed();
+
+        // Metrics
+        metrics::increment_counter!("te_embed_success", "model" => self.model.clone());
+        metrics::histogram!(
+            "te_embed_duration",
+            total_time.as_secs_f64(),
+            "model" => self.model.clone()
+        );
+        metrics::histogram!(
+            "te_embed_tokenization_duration",
+            response.metadata.tokenization.as_secs_f64(),
+            "model" => self.model.clone()
+        );
+        metrics::histogram!(
+            "te_embed_queue_duration",
+            response.metadata.queue.as_secs_f64(),
+            "model" => self.model.clone()
+        );
+        metrics::histogram!(
+            "te_embed_inference_duration",
+            response.metadata.inference.as_secs_f64(),
+            "model" => self.model.clone()
+        );
+
+        Ok(response)
+    }
+
+    #[allow(clippy::too_many_arguments)]
     async fn embed<I: Into<EncodingInput> + std::fmt::Debug>(
         &self,
         inputs: I,
         truncate: bool,
+        truncation_direction: Option<TruncationDirection>,
         pooling: bool,
+        normalize: bool,
+        quantization: Option<EmbeddingQuantization>,
+        priority: Priority,
         start_time: &Instant,
         _permit: OwnedSemaphorePermit,
     ) -> Result<InferResult, TextEmbeddingsError> {
         if self.is_classifier() {
-            metrics::increment_counter!("te_request_failure", "err" => "model_type");
+            metrics::increment_counter!(
+                "te_request_failure",
+                "err" => "model_type",
+                "model" => self.model.clone()
+            );
             let message = "Model is not an embedding model".to_string();
             tracing::error!("{message}");
             return Err(TextEmbeddingsError::Backend(BackendError::Inference(
@@ -209,19 +537,27 @@ impl Infer {
             )));
         }
 
-        metrics::increment_counter!("te_embed_count");
+        metrics::increment_counter!("te_embed_count", "model" => self.model.clone());
 
         // Tokenization
-        let encoding = self
+        let mut encoding = self
             .tokenization
-            .encode(inputs.into(), truncate)
+            .encode(inputs.into(), truncate, truncation_direction)
             .await
             .map_err(|err| {
-                metrics::increment_counter!("te_request_failure", "err" => "tokenization");
+                metrics::increment_counter!(
+                    "te_request_failure",
+                    "err" => "tokenization",
+                    "model" => self.model.clone()
+                );
                 tracing::error!("{err}");
                 err
             })?;
 
+        // Offsets are only returned with the token embeddings and are not sent to the backend
+        let offsets = std::mem::take(&mut encoding.offsets);
+        let special_tokens_mask = std::mem::take(&mut encoding.special_tokens_mask);
+
         // MPSC channel to communicate with the background batching task
         let (response_tx, response_rx) = oneshot::channel();
 
@@ -232,37 +568,57 @@ impl Infer {
                 tokenization: start_time.elapsed(),
                 queue_time: Instant::now(),
                 prompt_tokens: encoding.input_ids.len(),
+                dropped_tokens: encoding.dropped_tokens,
                 pooling,
+                normalize,
+                quantization,
+                priority,
+                span: Span::current(),
             },
             encoding,
         });
 
         self.notify_batching_task.notify_one();
 
-        let response = response_rx
+        let mut response = PendingResponse::new(response_rx, self.queue.clone())
+            .recv()
             .await
-            .expect(
-                "Infer batching task dropped the sender without sending a response. This is a bug.",
-            )
             .map_err(|err| {
-                metrics::increment_counter!("te_request_failure", "err" => "inference");
+                metrics::increment_counter!(
+                    "te_request_failure",
+                    "err" => "inference",
+                    "model" => self.model.clone()
+                );
                 tracing::error!("{err}");
                 err
             })?;
 
+        if let InferResult::AllEmbedding(response) = &mut response {
+            response.offsets = offsets;
+            response.special_tokens_mask = special_tokens_mask;
+        }
+
         Ok(response)
     }
 
+    #[allow(clippy::too_many_arguments)]
     #[instrument(skip(self, _permit))]
     pub async fn predict<I: Into<EncodingInput> + std::fmt::Debug>(
         &self,
         inputs: I,
         truncate: bool,
+        truncation_direction: Option<TruncationDirection>,
         raw_scores: bool,
+        activation: Option<ClassifierActivation>,
+        priority: Priority,
         _permit: OwnedSemaphorePermit,
     ) -> Result<ClassificationInferResponse, TextEmbeddingsError> {
         if !self.is_classifier() {
-            metrics::increment_counter!("te_request_failure", "err" => "model_type");
+            metrics::increment_counter!(
+                "te_request_failure",
+                "err" => "model_type",
+                "model" => self.model.clone()
+            );
             let message = "Model is not a classifier model".to_string();
             return Err(TextEmbeddingsError::Backend(BackendError::Inference(
                 message,
@@ -270,15 +626,19 @@ impl Infer {
         }
 
         let start_time = Instant::now();
-        metrics::increment_counter!("te_predict_count");
+        metrics::increment_counter!("te_predict_count", "model" => self.model.clone());
 
         // Tokenization
         let encoding = self
             .tokenization
-            .encode(inputs.into(), truncate)
+            .encode(inputs.into(), truncate, truncation_direction)
             .await
             .map_err(|err| {
-                metrics::increment_counter!("te_request_failure", "err" => "tokenization");
+                metrics::increment_counter!(
+                    "te_request_failure",
+                    "err" => "tokenization",
+                    "model" => self.model.clone()
+                );
                 tracing::error!("{err}");
                 err
             })?;
@@ -293,20 +653,27 @@ impl Infer {
                 tokenization: start_time.elapsed(),
                 queue_time: Instant::now(),
                 prompt_tokens: encoding.input_ids.len(),
+                dropped_tokens: encoding.dropped_tokens,
                 pooling: true,
+                normalize: false,
+                quantization: None,
+                priority,
+                span: Span::current(),
             },
             encoding,
         });
 
         self.notify_batching_task.notify_one();
 
-        let response = response_rx
+        let response = PendingResponse::new(response_rx, self.queue.clone())
+            .recv()
             .await
-            .expect(
-                "Infer batching task dropped the sender without sending a response. This is a bug.",
-            )
             .map_err(|err| {
-                metrics::increment_counter!("te_request_failure", "err" => "inference");
+                metrics::increment_counter!(
+                    "te_request_failure",
+                    "err" => "inference",
+                    "model" => self.model.clone()
+                );
                 tracing::error!("{err}");
                 err
             })?;
@@ -316,26 +683,32 @@ impl Infer {
         };
 
         if !raw_scores {
-            // Softmax
-            if response.results.len() > 1 {
-                let max = *response
-                    .results
-                    .iter()
-                    .max_by(|x, y| x.abs().partial_cmp(&y.abs()).unwrap())
-                    .unwrap();
-
-                let mut den = 0.0;
-                for v in response.results.iter_mut() {
-                    *v = (*v - max).exp();
-                    den += *v;
+            match activation.unwrap_or(self.classifier_activation) {
+                // Softmax
+                ClassifierActivation::Softmax if response.results.len() > 1 => {
+                    let max = *response
+                        .results
+                        .iter()
+                        .max_by(|x, y| x.abs().partial_cmp(&y.abs()).unwrap())
+                        .unwrap();
+
+                    let mut den = 0.0;
+                    for v in response.results.iter_mut() {
+                        *v = (*v - max).exp();
+                        den += *v;
+                    }
+                    for v in response.results.iter_mut() {
+                        *v /= den;
+                    }
                 }
-                for v in response.results.iter_mut() {
-                    *v /= den;
+                // Sigmoid
+                ClassifierActivation::Softmax | ClassifierActivation::Sigmoid => {
+                    for v in response.results.iter_mut() {
+                        *v = 1.0 / (1.0 + (-*v).exp());
+                    }
                 }
-            }
-            // Sigmoid
-            else {
-                response.results[0] = 1.0 / (1.0 + (-response.results[0]).exp());
+                // Regression heads return their raw score
+                ClassifierActivation::Identity => {}
             }
         }
 
@@ -343,19 +716,26 @@ impl Infer {
         let total_time = start_time.elapsed();
 
         // Metrics
-        metrics::increment_counter!("te_predict_success");
-        metrics::histogram!("te_predict_duration", total_time.as_secs_f64());
+        metrics::increment_counter!("te_predict_success", "model" => self.model.clone());
+        metrics::histogram!(
+            "te_predict_duration",
+            total_time.as_secs_f64(),
+            "model" => self.model.clone()
+        );
         metrics::histogram!(
             "te_predict_tokenization_duration",
-            response.metadata.tokenization.as_secs_f64()
+            response.metadata.tokenization.as_secs_f64(),
+            "model" => self.model.clone()
         );
         metrics::histogram!(
             "te_predict_queue_duration",
-            response.metadata.queue.as_secs_f64()
+            response.metadata.queue.as_secs_f64(),
+            "model" => self.model.clone()
         );
         metrics::histogram!(
             "te_predict_inference_duration",
-            response.metadata.inference.as_secs_f64()
+            response.metadata.inference.as_secs_f64(),
+            "model" => self.model.clone()
         );
 
         Ok(response)
@@ -375,6 +755,101 @@ impl Infer {
     pub fn health_watcher(&self) -> watch::Receiver<bool> {
         self.backend.health_watcher()
     }
+
+    /// Wait for the in-flight requests to finish and stop the background tasks.
+    /// The requests either hold another clone of this `Infer`, which is waited for, or a permit.
+    /// The semaphore is not closed so the requests still waiting for a permit are served.
+    #[instrument(skip(self))]
+    pub async fn shutdown(&self) {
+        // This clone is not waited for
+        self.clone_guard.0.lock().unwrap().take();
+        self.clones_sender.closed().await;
+        let _permits = self
+            .limit_concurrent_requests
+            .acquire_many(self.max_concurrent_requests as u32)
+            .await
+            .expect("Semaphore has been closed. This is a bug.");
+        let _ = self.shutdown_sender.send(true);
+    }
+}
+
+/// Held by every clone of an `Infer` so that `Infer::shutdown` can wait for them
+#[derive(Debug)]
+struct CloneGuard(std::sync::Mutex<Option<watch::Receiver<()>>>);
+
+impl Clone for CloneGuard {
+    fn clone(&self) -> Self {
+        Self(std::sync::Mutex::new(self.0.lock().unwrap().clone()))
+    }
+}
+
+/// Response of a queued entry.
+/// If it is dropped before the response is received (e.g. the client disconnected), the entry
+/// is removed from the queue instead of being embedded for nothing.
+struct PendingResponse {
+    receiver: oneshot::Receiver<Result<InferResult, BackendError>>,
+    queue: Queue,
+    received: bool,
+}
+
+impl PendingResponse {
+    fn new(receiver: oneshot::Receiver<Result<InferResult, BackendError>>, queue: Queue) -> Self {
+        Self {
+            receiver,
+            queue,
+            received: false,
+        }
+    }
+
+    async fn recv(mut self) -> Result<InferResult, BackendError> {
+        let response = (&mut self.receiver).await.expect(
+            "Infer batching task dropped the sender without sending a response. This is a bug.",
+        );
+        self.received = true;
+        response
+    }
+}
+
+impl Drop for PendingResponse {
+    fn drop(&mut self) {
+        if !self.received {
+            // Close the receiver first so that the queue sees the entry as cancelled
+            self.receiver.close();
+            self.queue.prune();
+        }
+    }
+}
+
+/// Pool the token embeddings of one input
+fn pool_embeddings(pool: &Pool, embeddings: Vec<Vec<f32>>) -> Vec<f32> {
+    match pool {
+        Pool::Cls => embeddings.into_iter().next().unwrap_or_default(),
+        Pool::LastToken => embeddings.into_iter().last().unwrap_or_default(),
+        Pool::Mean => {
+            let mut mean = vec![0.0; embeddings.first().map_or(0, Vec::len)];
+            for embedding in &embeddings {
+                for (acc, v) in mean.iter_mut().zip(embedding) {
+                    *acc += v;
+                }
+            }
+            mean.iter_mut().for_each(|v| *v /= embeddings.len() as f32);
+            mean
+        }
+        Pool::WeightedMean => {
+            // Tokens are weighted by their position
+            let length = embeddings.len();
+            let weights_sum = (length * (length + 1) / 2) as f32;
+            let mut mean = vec![0.0; embeddings.first().map_or(0, Vec::len)];
+            for (j, embedding) in embeddings.iter().enumerate() {
+                let weight = (j + 1) as f32 / weights_sum;
+                for (acc, v) in mean.iter_mut().zip(embedding) {
+                    *acc += weight * v;
+                }
+            }
+            mean
+        }
+        Pool::Splade => unreachable!(),
+    }
 }
 
 #[instrument(skip_all)]
@@ -382,9 +857,13 @@ async fn batching_task(
     queue: Queue,
     notify: Arc<Notify>,
     embed_sender: mpsc::UnboundedSender<(NextBatch, oneshot::Sender<()>)>,
+    mut shutdown: watch::Receiver<bool>,
 ) {
     loop {
-        notify.notified().await;
+        tokio::select! {
+            _ = notify.notified() => {}
+            _ = shutdown.changed() => break,
+        }
 
         while let Some(next_batch) = queue.next_batch().await {
             let (callback_sender, callback_receiver) = oneshot::channel();
@@ -399,74 +878,115 @@ async fn batching_task(
 #[instrument(skip_all)]
 async fn backend_task(
     backend: Backend,
+    queue: Queue,
     mut embed_receiver: mpsc::UnboundedReceiver<(NextBatch, oneshot::Sender<()>)>,
+    sub_batch_tokens: Option<usize>,
 ) {
+    // One permit per model replica
+    let replicas = Arc::new(Semaphore::new(backend.num_replicas()));
+    let mut batch_id = 0;
+
     while let Some((batch, _callback)) = embed_receiver.recv().await {
-        match &backend.model_type {
-            ModelType::Classifier => {
-                let results = backend.predict(batch.1).await;
+        let permit = replicas
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("Semaphore has been closed. This is a bug.");
+        // The batch was prefetched while the previous forward pass was running: admit the
+        // requests that were queued since then if they fit in the token budget
+        let batch = queue.fill_batch(batch).await;
+        batch_id += 1;
 
-                // Handle sending responses in another thread to avoid starving the backend
-                std::thread::spawn(move || match results {
-                    Ok((mut predictions, inference_duration)) => {
-                        batch.0.into_iter().enumerate().for_each(|(i, m)| {
-                            let infer_metadata = InferMetadata {
-                                prompt_tokens: m.prompt_tokens,
-                                tokenization: m.tokenization,
-                                queue: m.queue_time.elapsed() - inference_duration,
-                                inference: inference_duration,
-                            };
-
-                            let _ = m.response_tx.send(Ok(InferResult::Classification(
-                                ClassificationInferResponse {
-                                    results: predictions.remove(&i).expect(
-                                        "prediction not found in results. This is a backend bug.",
-                                    ),
-                                    metadata: infer_metadata,
-                                },
-                            )));
-                        });
-                    }
-                    Err(err) => {
-                        batch.0.into_iter().for_each(|m| {
-                            let _ = m.response_tx.send(Err(err.clone()));
-                        });
-                    }
-                });
+        // The batch is shared by several requests: link it to their traces
+        let span = tracing::info_span!("batch", id = batch_id, size = batch.0.len());
+        for metadata in &batch.0 {
+            span.follows_from(&metadata.span);
+        }
+        let backend = backend.clone();
+        tokio::spawn(
+            process_batch(backend, batch, batch_id, sub_batch_tokens, permit).instrument(span),
+        );
+
+        // Only ask the batching task for a new batch once a replica is free
+        let _ = replicas.acquire().await;
+    }
+}
+
+async fn process_batch(
+    backend: Backend,
+    batch: NextBatch,
+    batch_id: u64,
+    sub_batch_tokens: Option<usize>,
+    _permit: OwnedSemaphorePermit,
+) {
+    match &backend.model_type {
+        ModelType::Classifier => {
+            let results = backend.predict(batch.1).await;
+
+            // Handle sending responses in another thread to avoid starving the backend
+            std::thread::spawn(move || match results {
+                Ok((mut predictions, inference_duration)) => {
+                    batch.0.into_iter().enumerate().for_each(|(i, m)| {
+                        let infer_metadata = InferMetadata {
+                            prompt_tokens: m.prompt_tokens,
+                            dropped_tokens: m.dropped_tokens,
+                            tokenization: m.tokenization,
+                            queue: m.queue_time.elapsed() - inference_duration,
+                            inference: inference_duration,
+                            batch_id: Some(batch_id),
+                        };
+
+                        let _ = m.response_tx.send(Ok(InferResult::Classification(
+                            ClassificationInferResponse {
+                                results: predictions.remove(&i).expect(
+                                    "prediction not found in results. This is a backend bug.",
+                                ),
+                                metadata: infer_metadata,
+                            },
+                        )));
+                    });
+                }
+                Err(err) => {
+                    batch.0.into_iter().for_each(|m| {
+                        let _ = m.response_tx.send(Err(err.clone()));
+                    });
+                }
+            });
+        }
+        ModelType::Embedding(_) => match sub_batch_tokens {
+            Some(max_tokens) => {
+                // Answer the requests of every sub-batch as soon as it is computed
+                let mut metadata: Vec<Option<Metadata>> = batch.0.into_iter().map(Some).collect();
+                backend
+                    .embed_stream(batch.1, max_tokens, |results| match results {
+                        Ok((embeddings, inference_duration)) => {
+                            for (i, embedding) in embeddings {
+                                let m = metadata[i]
+                                    .take()
+                                    .expect("embedding sent twice. This is a backend bug.");
+                                send_embedding(m, embedding, inference_duration, batch_id);
+                            }
+                        }
+                        Err(err) => {
+                            // The remaining sub-batches are not run
+                            for m in metadata.iter_mut().filter_map(Option::take) {
+                                let _ = m.response_tx.send(Err(err.clone()));
+                            }
+                        }
+                    })
+                    .await;
             }
-            ModelType::Embedding(_) => {
+            None => {
                 let results = backend.embed(batch.1).await;
 
                 // Handle sending responses in another thread to avoid starving the backend
                 std::thread::spawn(move || match results {
                     Ok((mut embeddings, inference_duration)) => {
                         batch.0.into_iter().enumerate().for_each(|(i, m)| {
-                            let metadata = InferMetadata {
-                                prompt_tokens: m.prompt_tokens,
-                                tokenization: m.tokenization,
-                                queue: m.queue_time.elapsed() - inference_duration,
-                                inference: inference_duration,
-                            };
-
-                            let results = match embeddings
+                            let embedding = embeddings
                                 .remove(&i)
-                                .expect("embedding not found in results. This is a backend bug.")
-                            {
-                                Embedding::Pooled(e) => {
-                                    InferResult::PooledEmbedding(PooledEmbeddingsInferResponse {
-                                        results: e,
-                                        metadata,
-                                    })
-                                }
-                                Embedding::All(e) => {
-                                    InferResult::AllEmbedding(AllEmbeddingsInferResponse {
-                                        results: e,
-                                        metadata,
-                                    })
-                                }
-                            };
-
-                            let _ = m.response_tx.send(Ok(results));
+                                .expect("embedding not found in results. This is a backend bug.");
+                            send_embedding(m, embedding, inference_duration, batch_id);
                         })
                     }
                     Err(err) => {
@@ -476,22 +996,76 @@ async fn backend_task(
                     }
                 });
             }
-        };
-    }
+        },
+    };
+}
+
+fn send_embedding(
+    m: Metadata,
+    embedding: Embedding,
+    inference_duration: Duration,
+    batch_id: u64,
+) {
+    let metadata = InferMetadata {
+        prompt_tokens: m.prompt_tokens,
+        dropped_tokens: m.dropped_tokens,
+        tokenization: m.tokenization,
+        queue: m.queue_time.elapsed() - inference_duration,
+        inference: inference_duration,
+        batch_id: Some(batch_id),
+    };
+
+    let results = match embedding {
+        Embedding::Pooled(e) => InferResult::PooledEmbedding(PooledEmbeddingsInferResponse {
+            results: e,
+            metadata,
+        }),
+        Embedding::Quantized(e) => {
+            InferResult::QuantizedEmbedding(QuantizedEmbeddingsInferResponse {
+                results: e,
+                metadata,
+            })
+        }
+        Embedding::All(e) => InferResult::AllEmbedding(AllEmbeddingsInferResponse {
+            results: e,
+            // Set by `Infer::embed`
+            offsets: Vec::new(),
+            special_tokens_mask: Vec::new(),
+            metadata,
+        }),
+    };
+
+    let _ = m.response_tx.send(Ok(results));
+}
+
+/// Activation applied on top of the classifier logits
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClassifierActivation {
+    /// Softmax over all labels. Falls back to a sigmoid if the model only has one label
+    Softmax,
+    /// Independent sigmoid for each label
+    Sigmoid,
+    /// No activation. Used for regression heads
+    Identity,
 }
 
 #[derive(Debug)]
 pub struct InferMetadata {
     pub prompt_tokens: usize,
+    /// Number of tokens cut from the input by truncation
+    pub dropped_tokens: usize,
     pub tokenization: Duration,
     pub queue: Duration,
     pub inference: Duration,
+    /// Number of the batch the request was computed in. `None` for cache hits
+    pub batch_id: Option<u64>,
 }
 
 #[derive(Debug)]
 pub(crate) enum InferResult {
     Classification(ClassificationInferResponse),
     PooledEmbedding(PooledEmbeddingsInferResponse),
+    QuantizedEmbedding(QuantizedEmbeddingsInferResponse),
     AllEmbedding(AllEmbeddingsInferResponse),
 }
 
@@ -507,8 +1081,18 @@ pub struct PooledEmbeddingsInferResponse {
     pub metadata: InferMetadata,
 }
 
+#[derive(Debug)]
+pub struct QuantizedEmbeddingsInferResponse {
+    pub results: QuantizedEmbedding,
+    pub metadata: InferMetadata,
+}
+
 #[derive(Debug)]
 pub struct AllEmbeddingsInferResponse {
     pub results: Vec<Vec<f32>>,
+    /// Character offsets of the tokens in the input
+    pub offsets: Vec<(usize, usize)>,
+    /// 1 for the special tokens added by the tokenizer
+    pub special_tokens_mask: Vec<u32>,
     pub metadata: InferMetadata,
 }
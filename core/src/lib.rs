@@ -1,6 +1,9 @@
+pub mod cache;
 pub mod download;
 pub mod infer;
+pub mod model;
 pub mod queue;
+pub mod slow_tokenizer;
 pub mod tokenization;
 
 use text_embeddings_backend::BackendError;
@@ -0,0 +1,596 @@
+/// Load a model from its directory: configuration, tokenizer, backend, queue and inference tasks.
+/// Shared by the router and the language bindings
+use crate::download::download_model;
+use crate::infer::{ClassifierActivation, Infer};
+use crate::queue::{PaddingBuckets, Queue};
+use crate::slow_tokenizer::load_tokenizer;
+use crate::tokenization::{Preprocessing, Tokenization, TruncationDirection};
+use hf_hub::api::tokio::ApiError;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use text_embeddings_backend::{
+    Backend, BackendError, DType, ModelType, NumaPolicy, Pool, TensorRtConfig,
+};
+use thiserror::Error;
+use tokenizers::decoders::metaspace::PrependScheme;
+use tokenizers::models::ModelWrapper;
+use tokenizers::pre_tokenizers::sequence::Sequence;
+use tokenizers::processors::template::TemplateProcessing;
+use tokenizers::{PostProcessorWrapper, PreTokenizerWrapper, Tokenizer};
+
+#[derive(Debug, Deserialize)]
+pub struct ModelConfig {
+    pub architectures: Vec<String>,
+    pub model_type: String,
+    #[serde(alias = "n_positions")]
+    pub max_position_embeddings: usize,
+    pub pad_token_id: usize,
+    /// Number of token types. Not set by the models without token type embeddings
+    pub type_vocab_size: Option<usize>,
+    #[serde(alias = "n_embd", alias = "d_model")]
+    pub hidden_size: Option<usize>,
+    pub vocab_size: Option<usize>,
+    pub id2label: Option<HashMap<String, String>>,
+    pub label2id: Option<HashMap<String, usize>>,
+    pub problem_type: Option<ProblemType>,
+}
+
+impl ModelConfig {
+    /// Labels of the classifiers, ordered by id
+    pub fn labels(&self) -> Vec<String> {
+        let mut labels: Vec<(usize, &String)> = self
+            .id2label
+            .iter()
+            .flatten()
+            .filter_map(|(id, label)| Some((id.parse().ok()?, label)))
+            .collect();
+        labels.sort();
+        labels.into_iter().map(|(_, label)| label.clone()).collect()
+    }
+
+    /// Classifiers with a single output score the relevance of a pair of texts
+    pub fn is_reranker(&self) -> bool {
+        self.id2label.as_ref().map_or(false, |labels| labels.len() <= 1)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProblemType {
+    SingleLabelClassification,
+    MultiLabelClassification,
+    Regression,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PoolConfig {
+    pooling_mode_cls_token: bool,
+    pooling_mode_mean_tokens: bool,
+    pooling_mode_max_tokens: bool,
+    pooling_mode_mean_sqrt_len_tokens: bool,
+    #[serde(default)]
+    pooling_mode_weightedmean_tokens: bool,
+    #[serde(default)]
+    pooling_mode_lasttoken: bool,
+}
+
+impl PoolConfig {
+    /// Pooling method enabled by the configuration, if the backends implement it
+    pub fn pool(&self) -> Option<Pool> {
+        if self.pooling_mode_cls_token {
+            Some(Pool::Cls)
+        } else if self.pooling_mode_mean_tokens {
+            Some(Pool::Mean)
+        } else if self.pooling_mode_weightedmean_tokens {
+            Some(Pool::WeightedMean)
+        } else if self.pooling_mode_lasttoken {
+            Some(Pool::LastToken)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct STConfig {
+    #[serde(default)]
+    pub prompts: HashMap<String, String>,
+    pub default_prompt_name: Option<String>,
+}
+
+/// Options of `load_model`
+#[derive(Debug, Clone)]
+pub struct ModelOptions {
+    pub dtype: DType,
+    /// Defaults to the pooling of `1_Pooling/config.json`. Ignored by classifiers
+    pub pooling: Option<Pool>,
+    /// Default truncation direction of the requests
+    pub truncation_direction: TruncationDirection,
+    /// Overrides the maximum input length of the model configuration
+    pub max_input_length: Option<usize>,
+    pub preprocessing: Preprocessing,
+    pub tokenization_workers: usize,
+    /// Inputs waiting for a tokenizer worker. Defaults to 4 inputs per worker
+    pub tokenization_queue_size: Option<usize>,
+    pub max_concurrent_requests: usize,
+    pub max_batch_tokens: usize,
+    /// Probe the largest batch the device can run instead of warming up with `max_batch_tokens`
+    pub calibrate_max_batch_tokens: bool,
+    pub max_batch_requests: Option<usize>,
+    /// Maximum share of padding tokens in the batches of padded models
+    pub max_padding_ratio: Option<f32>,
+    /// Padded lengths of the batches of padded models are rounded up to this multiple
+    pub padding_bucket_size: Option<usize>,
+    /// Batches of embedding models are run in sub-batches of at most this number of tokens
+    pub sub_batch_tokens: Option<usize>,
+    /// Batch size of the TensorRT engines when `max_batch_requests` is not set
+    pub max_client_batch_size: usize,
+    /// Socket of the Python backend
+    pub uds_path: String,
+    pub otlp_endpoint: Option<String>,
+    pub tensorrt_engine_cache: Option<String>,
+    pub openvino_device: Option<String>,
+    pub data_parallel: bool,
+    pub numa: NumaPolicy,
+    /// Directory of a LoRA adapter merged into the model weights
+    pub lora_adapter: Option<PathBuf>,
+    /// Check the tensor names of the checkpoint
+    pub strict_load: bool,
+    /// Bit-exact outputs across runs on the same hardware
+    pub deterministic: bool,
+}
+
+impl ModelOptions {
+    /// Options with the defaults of the router
+    pub fn new(dtype: DType) -> Self {
+        Self {
+            dtype,
+            pooling: None,
+            truncation_direction: TruncationDirection::Right,
+            max_input_length: None,
+            preprocessing: Preprocessing::default(),
+            tokenization_workers: 1,
+            tokenization_queue_size: None,
+            max_concurrent_requests: 512,
+            max_batch_tokens: 16384,
+            calibrate_max_batch_tokens: false,
+            max_batch_requests: None,
+            max_padding_ratio: None,
+            padding_bucket_size: None,
+            sub_batch_tokens: None,
+            max_client_batch_size: 32,
+            uds_path: String::new(),
+            otlp_endpoint: None,
+            tensorrt_engine_cache: None,
+            openvino_device: None,
+            data_parallel: false,
+            numa: NumaPolicy::None,
+            lora_adapter: None,
+            strict_load: false,
+            deterministic: false,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum LoadError {
+    #[error("Invalid model: {0}")]
+    Config(String),
+    #[error("Could not download the model: {0}")]
+    Download(#[from] ApiError),
+    #[error("Could not load the tokenizer: {0}")]
+    Tokenizer(#[from] tokenizers::Error),
+    #[error("{0}: {1}")]
+    Backend(&'static str, BackendError),
+}
+
+/// Tokenizer details returned to the clients
+#[derive(Debug, Clone)]
+pub struct TokenizerInfo {
+    /// `BPE`, `WordPiece`, `WordLevel` or `Unigram`
+    pub model: String,
+    /// Vocabulary size, added tokens included
+    pub vocab_size: usize,
+    /// Content of the special tokens, sorted
+    pub special_tokens: Vec<String>,
+}
+
+/// Model ready to serve requests
+pub struct LoadedModel {
+    pub infer: Infer,
+    pub config: ModelConfig,
+    /// Prompts of `config_sentence_transformers.json`
+    pub st_config: STConfig,
+    pub model_type: ModelType,
+    /// Size of the embeddings, after the `2_Dense` projection. `None` for classifiers
+    pub embedding_dimension: Option<usize>,
+    pub tokenizer: TokenizerInfo,
+    pub max_input_length: usize,
+    /// Calibrated if `calibrate_max_batch_tokens` is set
+    pub max_batch_tokens: usize,
+    /// Limited by the backend and by the deterministic mode
+    pub max_batch_requests: Option<usize>,
+}
+
+/// Load `model_id`, a local directory or a model downloaded from the Hub to the default cache.
+/// Must be called from a Tokio runtime.
+pub async fn load(
+    model_id: &str,
+    revision: Option<&str>,
+    hf_api_token: Option<String>,
+    options: &ModelOptions,
+) -> Result<LoadedModel, LoadError> {
+    let path = Path::new(model_id);
+    let model_root = if path.is_dir() {
+        path.to_path_buf()
+    } else {
+        download_model(
+            model_id,
+            revision,
+            hf_api_token,
+            None,
+            options.pooling.is_none(),
+        )
+        .await?
+    };
+    load_model(model_root, model_id, options).await
+}
+
+/// Load the model of `model_root` with its own tokenizer, queue and backend. `model_id` labels
+/// the metrics of the model.
+/// Must be called from a Tokio runtime.
+pub async fn load_model(
+    model_root: PathBuf,
+    model_id: &str,
+    options: &ModelOptions,
+) -> Result<LoadedModel, LoadError> {
+    let config: ModelConfig = read_json(&model_root.join("config.json"))?;
+
+    // Load prompts from the sentence-transformers config
+    let st_config_path = model_root.join("config_sentence_transformers.json");
+    let st_config: STConfig = if st_config_path.exists() {
+        read_json(&st_config_path)?
+    } else {
+        STConfig::default()
+    };
+    if let Some(name) = &st_config.default_prompt_name {
+        if !st_config.prompts.contains_key(name) {
+            return Err(LoadError::Config(format!(
+                "Default prompt `{name}` is not defined in `config_sentence_transformers.json`"
+            )));
+        }
+    }
+
+    // Set model type from config
+    let classifier = config
+        .architectures
+        .iter()
+        .any(|arch| arch.ends_with("Classification"));
+    let model_type = if classifier {
+        if options.pooling.is_some() {
+            tracing::warn!("`pooling` is set but the model is a classifier. Ignoring `pooling`.");
+        }
+        ModelType::Classifier
+    } else {
+        let pool = match options.pooling {
+            Some(pool) => pool,
+            None => pool_from_config(&model_root)?,
+        };
+        ModelType::Embedding(pool)
+    };
+
+    let embedding_dimension = match &model_type {
+        ModelType::Classifier => None,
+        // SPLADE models return a weight for every token of the vocabulary
+        ModelType::Embedding(Pool::Splade) => config.vocab_size,
+        ModelType::Embedding(_) => dense_out_features(&model_root).or(config.hidden_size),
+    };
+
+    let tokenizer = model_tokenizer(&model_root, &config)?;
+    let mut special_tokens: Vec<String> = tokenizer
+        .get_added_tokens_decoder()
+        .into_values()
+        .filter(|token| token.special)
+        .map(|token| token.content)
+        .collect();
+    special_tokens.sort();
+    let tokenizer_info = TokenizerInfo {
+        model: match tokenizer.get_model() {
+            ModelWrapper::BPE(_) => "BPE",
+            ModelWrapper::WordPiece(_) => "WordPiece",
+            ModelWrapper::WordLevel(_) => "WordLevel",
+            ModelWrapper::Unigram(_) => "Unigram",
+        }
+        .to_string(),
+        vocab_size: tokenizer.get_vocab_size(true),
+        special_tokens,
+    };
+
+    // Position IDs offset. Used for Roberta and camembert.
+    let position_offset = if &config.model_type == "xlm-roberta"
+        || &config.model_type == "camembert"
+        || &config.model_type == "roberta"
+    {
+        config.pad_token_id + 1
+    } else {
+        0
+    };
+    let model_max_input_length = config.max_position_embeddings - position_offset;
+    let max_input_length = match options.max_input_length {
+        Some(0) => {
+            return Err(LoadError::Config(
+                "`max_input_length` must be > 0".to_string(),
+            ))
+        }
+        Some(max_input_length) if max_input_length > model_max_input_length => {
+            tracing::warn!(
+                "Extending the maximum input length from {model_max_input_length} to \
+                 {max_input_length}: the model was not trained on sequences this long"
+            );
+            max_input_length
+        }
+        Some(max_input_length) => {
+            if max_input_length < model_max_input_length {
+                tracing::info!(
+                    "Limiting the maximum input length from {model_max_input_length} to \
+                     {max_input_length}"
+                );
+            }
+            max_input_length
+        }
+        None => model_max_input_length,
+    };
+    // Number of positions the backend must support beyond the model configuration
+    let max_position_embeddings = (max_input_length > model_max_input_length)
+        .then_some(max_input_length + position_offset);
+
+    // Tokenization logic
+    let tokenization_workers = options.tokenization_workers;
+    let tokenization = Tokenization::new(
+        tokenization_workers,
+        options
+            .tokenization_queue_size
+            .unwrap_or(4 * tokenization_workers),
+        tokenizer,
+        max_input_length,
+        position_offset,
+        options.truncation_direction,
+        config.type_vocab_size.unwrap_or(1),
+        options.preprocessing.clone(),
+        model_id.to_string(),
+    );
+
+    // TensorRT engines are built for the maximum shapes the queue can produce
+    let tensorrt = options
+        .tensorrt_engine_cache
+        .as_ref()
+        .map(|engine_cache_path| TensorRtConfig {
+            engine_cache_path: engine_cache_path.into(),
+            max_batch_size: options
+                .max_batch_requests
+                .unwrap_or(options.max_client_batch_size),
+            max_sequence_length: max_input_length,
+        });
+
+    // Create backend
+    tracing::info!("Starting model backend");
+    let backend = Backend::new(
+        model_root,
+        options.dtype.clone(),
+        model_type.clone(),
+        options.uds_path.clone(),
+        options.otlp_endpoint.clone(),
+        tensorrt,
+        options.openvino_device.clone(),
+        options.data_parallel,
+        options.numa,
+        options.lora_adapter.clone(),
+        options.strict_load,
+        options.deterministic,
+        max_position_embeddings,
+    )
+    .map_err(|err| LoadError::Backend("Could not create backend", err))?;
+    backend
+        .health()
+        .await
+        .map_err(|err| LoadError::Backend("Model backend is not healthy", err))?;
+
+    let max_batch_requests = backend
+        .max_batch_size
+        .map(|s| {
+            tracing::warn!("Backend does not support a batch size > {s}");
+            tracing::warn!("forcing `max_batch_requests={s}`");
+            s
+        })
+        .or(options.max_batch_requests);
+
+    // A batch holding a single sequence is not padded and its matmuls and pooling reduce over
+    // the tokens of the sequence only: its outputs do not depend on the other requests in flight
+    let (max_batch_requests, padding_bucket_size) = if options.deterministic {
+        tracing::info!("Deterministic mode: forcing `max_batch_requests=1`");
+        (Some(1), None)
+    } else {
+        (max_batch_requests, options.padding_bucket_size)
+    };
+
+    let max_batch_tokens = if options.calibrate_max_batch_tokens {
+        tracing::info!("Calibrating `max_batch_tokens`");
+        let max_batch_tokens = backend
+            .calibrate_max_batch_tokens(
+                max_input_length,
+                options.max_batch_tokens,
+                max_batch_requests,
+            )
+            .await
+            .map_err(|err| LoadError::Backend("Could not calibrate `max_batch_tokens`", err))?;
+        tracing::info!("Using `max_batch_tokens={max_batch_tokens}`");
+        max_batch_tokens
+    } else {
+        // The calibration already ran the largest batch
+        tracing::info!("Warming up model backend");
+        backend
+            .warmup(max_input_length, options.max_batch_tokens, max_batch_requests)
+            .await
+            .map_err(|err| {
+                LoadError::Backend(
+                    "Model backend warmup failed. Consider lowering `max_batch_tokens`",
+                    err,
+                )
+            })?;
+        options.max_batch_tokens
+    };
+
+    if options.deterministic {
+        backend
+            .check_determinism(max_input_length)
+            .await
+            .map_err(|err| {
+                LoadError::Backend("Deterministic mode is not supported on this device", err)
+            })?;
+    }
+
+    // Queue logic
+    let queue = Queue::new(
+        backend.padded_model,
+        max_batch_tokens,
+        max_batch_requests,
+        options.max_padding_ratio,
+        padding_bucket_size.map(|size| PaddingBuckets {
+            size,
+            max_input_length,
+        }),
+        options.max_concurrent_requests,
+        model_id.to_string(),
+    );
+
+    // Classifier activation from `problem_type`
+    let classifier_activation = match config.problem_type {
+        None | Some(ProblemType::SingleLabelClassification) => ClassifierActivation::Softmax,
+        Some(ProblemType::MultiLabelClassification) => ClassifierActivation::Sigmoid,
+        Some(ProblemType::Regression) => ClassifierActivation::Identity,
+    };
+
+    // Create infer task
+    let infer = Infer::new(
+        tokenization,
+        queue,
+        options.max_concurrent_requests,
+        backend,
+        classifier_activation,
+        options.sub_batch_tokens,
+        model_id.to_string(),
+    );
+
+    Ok(LoadedModel {
+        infer,
+        config,
+        st_config,
+        model_type,
+        embedding_dimension,
+        tokenizer: tokenizer_info,
+        max_input_length,
+        max_batch_tokens,
+        max_batch_requests,
+    })
+}
+
+/// Load the tokenizer of the model, fixed up to encode the inputs as the model was trained on
+fn model_tokenizer(model_root: &Path, config: &ModelConfig) -> Result<Tokenizer, LoadError> {
+    let mut tokenizer = load_tokenizer(model_root)?;
+    // See https://github.com/huggingface/tokenizers/pull/1357
+    if let Some(pre_tokenizer) = tokenizer.get_pre_tokenizer() {
+        if let PreTokenizerWrapper::Metaspace(m) = pre_tokenizer {
+            // We are forced to clone since `Tokenizer` does not have a `get_mut` for `pre_tokenizer`
+            let mut m = m.clone();
+            m.set_prepend_scheme(PrependScheme::First);
+            tokenizer.with_pre_tokenizer(PreTokenizerWrapper::Metaspace(m));
+        } else if let PreTokenizerWrapper::Sequence(s) = pre_tokenizer {
+            let pre_tokenizers = s.get_pre_tokenizers();
+            // Check if we have a Metaspace pre tokenizer in the sequence
+            let has_metaspace = pre_tokenizers
+                .iter()
+                .any(|t| matches!(t, PreTokenizerWrapper::Metaspace(_)));
+
+            if has_metaspace {
+                let mut new_pre_tokenizers = Vec::with_capacity(s.get_pre_tokenizers().len());
+
+                for pre_tokenizer in pre_tokenizers {
+                    if let PreTokenizerWrapper::WhitespaceSplit(_) = pre_tokenizer {
+                        // Remove WhitespaceSplit
+                        // This will be done by the Metaspace pre tokenizer
+                        continue;
+                    }
+
+                    let mut pre_tokenizer = pre_tokenizer.clone();
+
+                    if let PreTokenizerWrapper::Metaspace(ref mut m) = pre_tokenizer {
+                        m.set_prepend_scheme(PrependScheme::First);
+                    }
+                    new_pre_tokenizers.push(pre_tokenizer);
+                }
+                tokenizer.with_pre_tokenizer(PreTokenizerWrapper::Sequence(Sequence::new(
+                    new_pre_tokenizers,
+                )));
+            }
+        }
+    }
+
+    // StarEncoder tokenizers do not add the `<cls>` and `<sep>` special tokens by themselves
+    if &config.model_type == "starencoder" && tokenizer.get_post_processor().is_none() {
+        let special_tokens = ["<cls>", "<sep>"]
+            .into_iter()
+            .map(|token| {
+                tokenizer.token_to_id(token).map(|id| (token, id)).ok_or_else(|| {
+                    LoadError::Config(format!("`{token}` not found in tokenizer vocabulary"))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let post_processor = TemplateProcessing::builder()
+            .try_single("<cls> $A <sep>")
+            .map_err(LoadError::Config)?
+            .try_pair("<cls> $A <sep> $B:1 <sep>:1")
+            .map_err(LoadError::Config)?
+            .special_tokens(special_tokens)
+            .build()
+            .map_err(|err| {
+                LoadError::Config(format!("Failed to build StarEncoder post-processor: {err}"))
+            })?;
+        tokenizer.with_post_processor(PostProcessorWrapper::Template(post_processor));
+    }
+
+    tokenizer.with_padding(None);
+    Ok(tokenizer)
+}
+
+fn pool_from_config(model_root: &Path) -> Result<Pool, LoadError> {
+    let path = model_root.join("1_Pooling/config.json");
+    if !path.exists() {
+        return Err(LoadError::Config(
+            "`pooling` is not set and the model has no pooling configuration \
+             (`1_Pooling/config.json`)"
+                .to_string(),
+        ));
+    }
+    let config: PoolConfig = read_json(&path)?;
+    config
+        .pool()
+        .ok_or_else(|| LoadError::Config(format!("Pooling config {config:?} is not supported")))
+}
+
+/// Size of the embeddings after the `2_Dense` projection, if the model has one
+fn dense_out_features(model_root: &Path) -> Option<usize> {
+    let config = fs::read_to_string(model_root.join("2_Dense/config.json")).ok()?;
+    let config: serde_json::Value = serde_json::from_str(&config).ok()?;
+    config["out_features"].as_u64().map(|size| size as usize)
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T, LoadError> {
+    let content = fs::read_to_string(path)
+        .map_err(|err| LoadError::Config(format!("Could not read {path:?}: {err}")))?;
+    serde_json::from_str(&content)
+        .map_err(|err| LoadError::Config(format!("Could not parse {path:?}: {err}")))
+}
@@ -3,7 +3,7 @@ use crate::tokenization::ValidEncoding;
 use std::cmp::max;
 use std::collections::VecDeque;
 use std::time::{Duration, Instant};
-use text_embeddings_backend::{BackendError, Batch};
+use text_embeddings_backend::{BackendError, Batch, EmbeddingQuantization};
 use tokio::sync::{mpsc, oneshot};
 use tracing::{instrument, Span};
 
@@ -27,8 +27,216 @@ pub struct Metadata {
     pub(crate) queue_time: Instant,
     /// Number of tokens in the prompt
     pub(crate) prompt_tokens: usize,
+    /// Number of tokens cut from the prompt by truncation
+    pub(crate) dropped_tokens: usize,
     /// Pooled embedding
     pub(crate) pooling: bool,
+    /// Pooled embedding normalized by the backend
+    pub(crate) normalize: bool,
+    /// Pooled embedding quantized by the backend
+    pub(crate) quantization: Option<EmbeddingQuantization>,
+    /// Queue lane of this entry
+    pub(crate) priority: Priority,
+    /// Span of the request, linked from the span of the batch it is computed in
+    pub(crate) span: Span,
+}
+
+/// Requests are batched by decreasing priority. Entries waiting for longer than
+/// `PROMOTION_DELAY` move to the next lane so that lower priorities are not starved.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Interactive requests
+    High,
+    #[default]
+    Normal,
+    /// Bulk ingestion
+    Batch,
+}
+
+/// Length buckets of the batches of padded models.
+/// A backend that runs a small set of shapes hits its kernel caches more often.
+#[derive(Debug, Clone, Copy)]
+pub struct PaddingBuckets {
+    /// Padded lengths are rounded up to a multiple of `size`
+    pub size: usize,
+    /// Padded lengths are capped to the longest possible input
+    pub max_input_length: usize,
+}
+
+/// Padded length of a batch where the longest entry has `length` tokens
+fn padded_length(length: usize, padding_buckets: Option<PaddingBuckets>) -> usize {
+    let Some(buckets) = padding_buckets else {
+        return length;
+    };
+    let padded = (length + buckets.size - 1) / buckets.size * buckets.size;
+    padded.min(buckets.max_input_length).max(length)
+}
+
+/// Time after which an entry is promoted to the next priority lane
+const PROMOTION_DELAY: Duration = Duration::from_secs(2);
+
+/// One FIFO lane per priority
+#[derive(Debug, Default)]
+struct Lanes {
+    /// Label of the metrics
+    model: String,
+    high: VecDeque<Entry>,
+    normal: VecDeque<Entry>,
+    batch: VecDeque<Entry>,
+}
+
+impl Lanes {
+    fn lane(&mut self, priority: Priority) -> &mut VecDeque<Entry> {
+        match priority {
+            Priority::High => &mut self.high,
+            Priority::Normal => &mut self.normal,
+            Priority::Batch => &mut self.batch,
+        }
+    }
+
+    fn push_back(&mut self, entry: Entry) {
+        self.lane(entry.metadata.priority).push_back(entry);
+    }
+
+    /// Put back an entry that was just popped
+    fn push_front(&mut self, entry: Entry) {
+        self.lane(entry.metadata.priority).push_front(entry);
+    }
+
+    /// Pop the oldest entry of the highest priority lane
+    fn pop_front(&mut self) -> Option<Entry> {
+        self.high
+            .pop_front()
+            .or_else(|| self.normal.pop_front())
+            .or_else(|| self.batch.pop_front())
+    }
+
+    /// Move the entries that waited for longer than `PROMOTION_DELAY` to the next lane
+    fn promote(&mut self) {
+        for (from, to) in [
+            (Priority::Normal, Priority::High),
+            (Priority::Batch, Priority::Normal),
+        ] {
+            while let Some(entry) = self.lane(from).front() {
+                if entry.metadata.queue_time.elapsed() <= PROMOTION_DELAY {
+                    break;
+                }
+                let mut entry = self.lane(from).pop_front().unwrap();
+                entry.metadata.priority = to;
+                // Keep the lanes ordered by queue time
+                let lane = self.lane(to);
+                let position = lane.partition_point(|queued| {
+                    queued.metadata.queue_time <= entry.metadata.queue_time
+                });
+                lane.insert(position, entry);
+                metrics::increment_counter!(
+                    "te_queue_promotion_count",
+                    "model" => self.model.clone()
+                );
+            }
+        }
+    }
+
+    /// Remove the entries where the response receiver was dropped
+    fn prune(&mut self) -> usize {
+        let len = self.len();
+        for lane in [&mut self.high, &mut self.normal, &mut self.batch] {
+            lane.retain(|entry| !entry.metadata.response_tx.is_closed());
+        }
+        len - self.len()
+    }
+
+    fn len(&self) -> usize {
+        self.high.len() + self.normal.len() + self.batch.len()
+    }
+
+    /// Pop the entries to add to a padded batch of `size` entries, `tokens` tokens and
+    /// `max_length` padded length.
+    /// The oldest entry of the highest priority lane is picked first. The other entries are picked
+    /// among the next `window` entries by priority and closest length to limit padding.
+    /// Entries that are not picked are put back in the queue in order.
+    #[allow(clippy::too_many_arguments)]
+    fn pop_padded(
+        &mut self,
+        window: usize,
+        max_entries: usize,
+        max_batch_tokens: usize,
+        max_padding_ratio: Option<f32>,
+        padding_buckets: Option<PaddingBuckets>,
+        mut size: usize,
+        mut tokens: usize,
+        mut max_length: usize,
+    ) -> Vec<Entry> {
+        let mut candidates = Vec::with_capacity(window);
+        while candidates.len() < window {
+            match self.pop_front() {
+                // Filter entries where the response receiver was dropped
+                Some(entry) if entry.metadata.response_tx.is_closed() => {
+                    metrics::increment_counter!(
+                        "te_request_failure",
+                        "err" => "dropped",
+                        "model" => self.model.clone()
+                    );
+                }
+                Some(entry) => candidates.push(entry),
+                None => break,
+            }
+        }
+
+        let Some(first) = candidates.first() else {
+            return Vec::new();
+        };
+        let first_length = first.encoding.input_ids.len();
+
+        // The sort is stable: entries with the same key keep their queue order
+        let mut order: Vec<usize> = (0..candidates.len()).collect();
+        order[1..].sort_by_key(|&i| {
+            let entry = &candidates[i];
+            (
+                entry.metadata.priority,
+                entry.encoding.input_ids.len().abs_diff(first_length),
+            )
+        });
+
+        let mut picked = vec![false; candidates.len()];
+        let mut picked_count = 0;
+        for i in order {
+            if picked_count == max_entries {
+                break;
+            }
+
+            let entry_tokens = candidates[i].encoding.input_ids.len();
+            let entry_max_length = padded_length(max(max_length, entry_tokens), padding_buckets);
+            let padded_tokens = entry_max_length * (size + 1);
+            if padded_tokens > max_batch_tokens {
+                continue;
+            }
+            let padding_ratio = 1.0 - (tokens + entry_tokens) as f32 / padded_tokens as f32;
+            // A batch always has at least one entry
+            if size > 0 && max_padding_ratio.map_or(false, |max| padding_ratio > max) {
+                continue;
+            }
+
+            picked[i] = true;
+            picked_count += 1;
+            size += 1;
+            tokens += entry_tokens;
+            max_length = entry_max_length;
+        }
+
+        let mut entries = Vec::new();
+        let mut rest = Vec::new();
+        for (entry, picked) in candidates.into_iter().zip(picked) {
+            match picked {
+                true => entries.push(entry),
+                false => rest.push(entry),
+            }
+        }
+        for entry in rest.into_iter().rev() {
+            self.push_front(entry);
+        }
+        entries
+    }
 }
 
 /// Request Queue
@@ -43,7 +251,10 @@ impl Queue {
         padded_model: bool,
         max_batch_tokens: usize,
         max_batch_requests: Option<usize>,
+        max_padding_ratio: Option<f32>,
+        padding_buckets: Option<PaddingBuckets>,
         max_concurrent_requests: usize,
+        model: String,
     ) -> Self {
         // Create channels
         let (queue_sender, queue_receiver) = mpsc::unbounded_channel();
@@ -54,7 +265,10 @@ impl Queue {
                 padded_model,
                 max_batch_tokens,
                 max_batch_requests,
+                max_padding_ratio,
+                padding_buckets,
                 max_concurrent_requests,
+                model,
                 queue_receiver,
             )
         });
@@ -72,15 +286,35 @@ impl Queue {
             .expect("Queue background task dropped the receiver. This is a bug.");
     }
 
+    /// Remove the entries of the requests that were cancelled while waiting in the queue
+    #[instrument(skip_all)]
+    pub fn prune(&self) {
+        // The background task is only gone if the process is shutting down
+        let _ = self.queue_sender.send(QueueCommand::Prune(Span::current()));
+    }
+
     /// Get the next batch from the queue
     #[instrument(skip(self))]
     pub async fn next_batch(&self) -> Option<NextBatch> {
+        self.send_next_batch(None).await
+    }
+
+    /// Admit the entries queued since `batch` was assembled, within the same token budget
+    #[instrument(skip_all)]
+    pub async fn fill_batch(&self, batch: NextBatch) -> NextBatch {
+        self.send_next_batch(Some(batch))
+            .await
+            .expect("Queue background task dropped the batch. This is a bug.")
+    }
+
+    async fn send_next_batch(&self, batch: Option<NextBatch>) -> Option<NextBatch> {
         let (response_sender, response_receiver) = oneshot::channel();
 
         // Send next batch command to the background task managing the state
         // Unwrap is safe here
         self.queue_sender
             .send(QueueCommand::NextBatch {
+                batch,
                 response_sender,
                 span: Span::current(),
             })
@@ -94,58 +328,138 @@ impl Queue {
 }
 
 // Background task responsible of the queue state
+#[allow(clippy::too_many_arguments)]
 fn queue_blocking_task(
     padded_model: bool,
     max_batch_tokens: usize,
     max_batch_requests: Option<usize>,
+    max_padding_ratio: Option<f32>,
+    padding_buckets: Option<PaddingBuckets>,
     max_concurrent_requests: usize,
+    model: String,
     mut queue_receiver: mpsc::UnboundedReceiver<QueueCommand>,
 ) {
     let capacity = max_batch_requests.unwrap_or(max_concurrent_requests);
+    // Only padded models run the padded length
+    let padding_buckets = padding_buckets.filter(|_| padded_model);
 
-    let mut entries: VecDeque<Entry> = VecDeque::with_capacity(max_concurrent_requests);
+    let mut entries = Lanes {
+        model: model.clone(),
+        ..Default::default()
+    };
 
     while let Some(cmd) = queue_receiver.blocking_recv() {
         match cmd {
             QueueCommand::Append(entry, span) => {
                 let _span = span.entered();
                 entries.push_back(*entry);
-                metrics::increment_gauge!("te_queue_size", 1.0);
+                metrics::increment_gauge!("te_queue_size", 1.0, "model" => model.clone());
+            }
+            QueueCommand::Prune(span) => {
+                let _span = span.entered();
+                let pruned = entries.prune();
+                if pruned > 0 {
+                    metrics::counter!(
+                        "te_request_failure",
+                        pruned as u64,
+                        "err" => "dropped",
+                        "model" => model.clone()
+                    );
+                    metrics::gauge!(
+                        "te_queue_size",
+                        entries.len() as f64,
+                        "model" => model.clone()
+                    );
+                }
             }
             QueueCommand::NextBatch {
+                batch,
                 response_sender,
                 span,
             } => {
                 let _span = span.entered();
 
-                let mut input_ids = Vec::with_capacity(max_batch_tokens);
-                let mut token_type_ids = Vec::with_capacity(max_batch_tokens);
-                let mut position_ids = Vec::with_capacity(max_batch_tokens);
+                entries.promote();
+
+                // Entries are appended to the prefetched batch, if any
+                let filling = batch.is_some();
+                let (
+                    mut metadata,
+                    Batch {
+                        mut input_ids,
+                        mut token_type_ids,
+                        mut position_ids,
+                        cumulative_seq_lengths: mut cu_seq_lengths,
+                        mut max_length,
+                        mut pooled_indices,
+                        mut raw_indices,
+                        mut normalized_indices,
+                        mut quantized_indices,
+                    },
+                ) = batch.unwrap_or_else(|| {
+                    (
+                        Vec::with_capacity(capacity),
+                        Batch {
+                            input_ids: Vec::with_capacity(max_batch_tokens),
+                            token_type_ids: Vec::with_capacity(max_batch_tokens),
+                            position_ids: Vec::with_capacity(max_batch_tokens),
+                            cumulative_seq_lengths: vec![0],
+                            max_length: 0,
+                            pooled_indices: Vec::with_capacity(capacity),
+                            raw_indices: Vec::with_capacity(capacity),
+                            normalized_indices: Vec::with_capacity(capacity),
+                            quantized_indices: Vec::with_capacity(capacity),
+                        },
+                    )
+                });
 
-                let mut pooled_indices = Vec::with_capacity(capacity);
-                let mut raw_indices = Vec::with_capacity(capacity);
-                let mut metadata = Vec::with_capacity(capacity);
-                let mut cu_seq_lengths = Vec::with_capacity(capacity);
-                cu_seq_lengths.push(0);
+                let mut current_tokens = input_ids.len();
+                let mut entry_index = metadata.len() as u32;
+                let admitted_from = metadata.len();
 
-                let mut current_tokens = 0;
-                let mut max_length = 0;
+                // Padded models batch the queued entries closest in length to limit padding
+                let mut padded_entries = padded_model.then(|| {
+                    entries
+                        .pop_padded(
+                            max_concurrent_requests,
+                            max_batch_requests
+                                .map_or(usize::MAX, |max| max.saturating_sub(metadata.len())),
+                            max_batch_tokens,
+                            max_padding_ratio,
+                            padding_buckets,
+                            metadata.len(),
+                            current_tokens,
+                            max_length as usize,
+                        )
+                        .into_iter()
+                });
 
-                let mut entry_index = 0;
+                while Some(metadata.len()) != max_batch_requests {
+                    let entry = match &mut padded_entries {
+                        Some(padded_entries) => padded_entries.next(),
+                        None => entries.pop_front(),
+                    };
+                    let Some(entry) = entry else {
+                        break;
+                    };
 
-                while let Some(entry) = entries.pop_front() {
                     // Filter entries where the response receiver was dropped (== entries where the request
                     // was dropped by the client)
                     if entry.metadata.response_tx.is_closed() {
-                        metrics::increment_counter!("te_request_failure", "err" => "dropped");
+                        metrics::increment_counter!(
+                            "te_request_failure",
+                            "err" => "dropped",
+                            "model" => model.clone()
+                        );
                         continue;
                     }
 
                     let entry_tokens = entry.encoding.input_ids.len();
+                    let entry_max_length =
+                        padded_length(max(max_length as usize, entry_tokens), padding_buckets);
 
                     let total_tokens = if padded_model {
-                        (max(max_length, entry_tokens as u32) * (metadata.len() + 1) as u32)
-                            as usize
+                        entry_max_length * (metadata.len() + 1)
                     } else {
                         current_tokens + entry_tokens
                     };
@@ -159,8 +473,14 @@ fn queue_blocking_task(
                         true => pooled_indices.push(entry_index),
                         false => raw_indices.push(entry_index),
                     }
+                    if entry.metadata.normalize {
+                        normalized_indices.push(entry_index);
+                    }
+                    if let Some(quantization) = entry.metadata.quantization {
+                        quantized_indices.push((entry_index, quantization));
+                    }
 
-                    max_length = max(max_length, entry_tokens as u32);
+                    max_length = entry_max_length as u32;
 
                     input_ids.extend(entry.encoding.input_ids);
                     token_type_ids.extend(entry.encoding.token_type_ids);
@@ -171,13 +491,17 @@ fn queue_blocking_task(
                     cu_seq_lengths.push(current_tokens as u32);
 
                     entry_index += 1;
+                }
 
-                    if Some(metadata.len()) == max_batch_requests {
-                        break;
+                // Put back the picked entries that were not batched
+                if let Some(padded_entries) = padded_entries {
+                    for entry in padded_entries.rev() {
+                        entries.push_front(entry);
                     }
                 }
 
                 let batch_size = metadata.len();
+                let admitted = batch_size - admitted_from;
                 let next_batch = if metadata.is_empty() {
                     None
                 } else {
@@ -191,15 +515,51 @@ fn queue_blocking_task(
                             max_length,
                             pooled_indices,
                             raw_indices,
+                            normalized_indices,
+                            quantized_indices,
                         },
                     ))
                 };
 
                 let _ = response_sender.send(next_batch);
 
-                metrics::histogram!("te_batch_next_size", batch_size as f64);
-                metrics::histogram!("te_batch_next_tokens", current_tokens as f64);
-                metrics::gauge!("te_queue_size", entries.len() as f64);
+                if filling {
+                    metrics::counter!(
+                        "te_batch_fill_count",
+                        admitted as u64,
+                        "model" => model.clone()
+                    );
+
+                    // Every batch is filled right before it is sent to the backend
+                    let batch_tokens = match padded_model {
+                        true => max_length as usize * batch_size,
+                        false => current_tokens,
+                    };
+                    metrics::histogram!(
+                        "te_batch_token_budget_ratio",
+                        batch_tokens as f64 / max_batch_tokens as f64,
+                        "model" => model.clone()
+                    );
+                    if padded_model && batch_tokens > 0 {
+                        metrics::histogram!(
+                            "te_batch_padding_ratio",
+                            1.0 - current_tokens as f64 / batch_tokens as f64,
+                            "model" => model.clone()
+                        );
+                    }
+                } else {
+                    metrics::histogram!(
+                        "te_batch_next_size",
+                        batch_size as f64,
+                        "model" => model.clone()
+                    );
+                    metrics::histogram!(
+                        "te_batch_next_tokens",
+                        current_tokens as f64,
+                        "model" => model.clone()
+                    );
+                }
+                metrics::gauge!("te_queue_size", entries.len() as f64, "model" => model.clone());
             }
         }
     }
@@ -210,7 +570,10 @@ pub type NextBatch = (Vec<Metadata>, Batch);
 #[derive(Debug)]
 enum QueueCommand {
     Append(Box<Entry>, Span),
+    Prune(Span),
     NextBatch {
+        /// Prefetched batch to fill
+        batch: Option<NextBatch>,
         response_sender: oneshot::Sender<Option<NextBatch>>,
         span: Span,
     },
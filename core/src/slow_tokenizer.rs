@@ -0,0 +1,429 @@
+/// Conversion of the slow tokenizers of transformers, for the models that do not ship a
+/// `tokenizer.json`
+use std::fs;
+use std::path::Path;
+use tokenizers::decoders::wordpiece::WordPiece as WordPieceDecoder;
+use tokenizers::models::bpe::BPE;
+use tokenizers::models::unigram::Unigram;
+use tokenizers::models::wordpiece::WordPiece;
+use tokenizers::normalizers::replace::ReplacePattern;
+use tokenizers::normalizers::{
+    BertNormalizer, Lowercase, NormalizerWrapper, Precompiled, Replace, Sequence, Strip,
+};
+use tokenizers::pre_tokenizers::bert::BertPreTokenizer;
+use tokenizers::pre_tokenizers::byte_level::ByteLevel;
+use tokenizers::pre_tokenizers::metaspace::Metaspace;
+use tokenizers::processors::roberta::RobertaProcessing;
+use tokenizers::processors::template::TemplateProcessing;
+use tokenizers::{
+    AddedToken, DecoderWrapper, ModelWrapper, PostProcessorWrapper, PreTokenizerWrapper, Tokenizer,
+};
+
+/// Vocabulary files of the slow tokenizers that can be converted, in order of preference. The
+/// files of an entry are all required
+pub const SLOW_TOKENIZER_FILES: [&[&str]; 5] = [
+    &["vocab.txt"],
+    &["vocab.json", "merges.txt"],
+    &["sentencepiece.bpe.model"],
+    &["spiece.model"],
+    &["spm.model"],
+];
+
+/// Optional configuration files of the slow tokenizers
+pub const SLOW_TOKENIZER_CONFIG_FILES: [&str; 2] =
+    ["tokenizer_config.json", "special_tokens_map.json"];
+
+/// Load the `tokenizer.json` of `model_root`, or convert its slow tokenizer if it has none
+pub fn load_tokenizer(model_root: &Path) -> tokenizers::Result<Tokenizer> {
+    let tokenizer_path = model_root.join("tokenizer.json");
+    if tokenizer_path.exists() {
+        return Tokenizer::from_file(tokenizer_path);
+    }
+
+    let Some(files) = SLOW_TOKENIZER_FILES
+        .iter()
+        .find(|files| files.iter().all(|file| model_root.join(file).exists()))
+    else {
+        return Err(
+            "`tokenizer.json` not found and no slow tokenizer vocabulary to convert".into(),
+        );
+    };
+    tracing::warn!("`tokenizer.json` not found. Converting the slow tokenizer `{}`", files[0]);
+
+    let config = SlowTokenizerConfig::read(model_root)?;
+    let mut tokenizer = match files[0] {
+        "vocab.txt" => wordpiece(&model_root.join("vocab.txt"), &config)?,
+        "vocab.json" => byte_level_bpe(
+            &model_root.join("vocab.json"),
+            &model_root.join("merges.txt"),
+            &config,
+        )?,
+        // fairseq models such as XLM-RoBERTa shift the ids of the SentencePiece vocabulary
+        "sentencepiece.bpe.model" => {
+            sentencepiece(&model_root.join(files[0]), &config, Layout::Fairseq)?
+        }
+        file => sentencepiece(&model_root.join(file), &config, Layout::Bert)?,
+    };
+
+    let special_tokens: Vec<AddedToken> = config
+        .special_tokens()
+        .into_iter()
+        .filter(|token| tokenizer.token_to_id(token).is_some())
+        .map(|token| AddedToken::from(token, true))
+        .collect();
+    tokenizer.add_special_tokens(&special_tokens);
+    Ok(tokenizer)
+}
+
+/// `tokenizer_config.json` and `special_tokens_map.json`
+struct SlowTokenizerConfig {
+    model_type: String,
+    tokenizer_config: serde_json::Value,
+    special_tokens_map: serde_json::Value,
+}
+
+impl SlowTokenizerConfig {
+    fn read(model_root: &Path) -> tokenizers::Result<Self> {
+        let read_json = |file: &str| -> tokenizers::Result<serde_json::Value> {
+            match fs::read_to_string(model_root.join(file)) {
+                Ok(content) => Ok(serde_json::from_str(&content)?),
+                Err(_) => Ok(serde_json::Value::Null),
+            }
+        };
+        Ok(Self {
+            model_type: read_json("config.json")?["model_type"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            tokenizer_config: read_json("tokenizer_config.json")?,
+            special_tokens_map: read_json("special_tokens_map.json")?,
+        })
+    }
+
+    fn flag(&self, name: &str, default: bool) -> bool {
+        self.tokenizer_config[name].as_bool().unwrap_or(default)
+    }
+
+    /// Special token `name` (e.g. `cls_token`). Tokens are either strings or `AddedToken`
+    /// objects
+    fn token(&self, name: &str, default: &str) -> String {
+        [&self.special_tokens_map[name], &self.tokenizer_config[name]]
+            .into_iter()
+            .find_map(|token| token.as_str().or_else(|| token["content"].as_str()))
+            .unwrap_or(default)
+            .to_string()
+    }
+
+    /// Special tokens of the configuration, or the defaults of the BERT and fairseq vocabularies
+    fn special_tokens(&self) -> Vec<String> {
+        let mut tokens: Vec<String> = [
+            ("cls_token", "[CLS]", "<s>"),
+            ("sep_token", "[SEP]", "</s>"),
+            ("unk_token", "[UNK]", "<unk>"),
+            ("pad_token", "[PAD]", "<pad>"),
+            ("mask_token", "[MASK]", "<mask>"),
+        ]
+        .into_iter()
+        .flat_map(|(name, bert, fairseq)| [self.token(name, bert), self.token(name, fairseq)])
+        .collect();
+        tokens.dedup();
+        tokens
+    }
+}
+
+fn token_id(tokenizer: &Tokenizer, token: &str) -> tokenizers::Result<u32> {
+    tokenizer
+        .token_to_id(token)
+        .ok_or_else(|| format!("`{token}` not found in the vocabulary").into())
+}
+
+/// `[CLS] A [SEP]` and `[CLS] A [SEP] B [SEP]` with the type 1 for the second sequence
+fn bert_template(
+    tokenizer: &Tokenizer,
+    cls: &str,
+    sep: &str,
+) -> tokenizers::Result<TemplateProcessing> {
+    Ok(TemplateProcessing::builder()
+        .try_single(format!("{cls}:0 $A:0 {sep}:0"))?
+        .try_pair(format!("{cls}:0 $A:0 {sep}:0 $B:1 {sep}:1"))?
+        .special_tokens(vec![
+            (cls.to_string(), token_id(tokenizer, cls)?),
+            (sep.to_string(), token_id(tokenizer, sep)?),
+        ])
+        .build()?)
+}
+
+/// BERT tokenizer from `vocab.txt`
+fn wordpiece(vocab: &Path, config: &SlowTokenizerConfig) -> tokenizers::Result<Tokenizer> {
+    let unk = config.token("unk_token", "[UNK]");
+    let model = WordPiece::from_file(&vocab.to_string_lossy())
+        .unk_token(unk)
+        .continuing_subword_prefix("##".to_string())
+        .build()?;
+
+    let lowercase = config.flag("do_lower_case", true);
+    let mut tokenizer = Tokenizer::new(ModelWrapper::WordPiece(model));
+    tokenizer.with_normalizer(NormalizerWrapper::BertNormalizer(BertNormalizer::new(
+        true,
+        config.flag("tokenize_chinese_chars", true),
+        config.tokenizer_config["strip_accents"].as_bool(),
+        lowercase,
+    )));
+    tokenizer.with_pre_tokenizer(PreTokenizerWrapper::BertPreTokenizer(BertPreTokenizer));
+    let template = bert_template(
+        &tokenizer,
+        &config.token("cls_token", "[CLS]"),
+        &config.token("sep_token", "[SEP]"),
+    )?;
+    tokenizer.with_post_processor(PostProcessorWrapper::Template(template));
+    tokenizer.with_decoder(DecoderWrapper::WordPiece(WordPieceDecoder::default()));
+    Ok(tokenizer)
+}
+
+/// RoBERTa tokenizer from `vocab.json` and `merges.txt`
+fn byte_level_bpe(
+    vocab: &Path,
+    merges: &Path,
+    config: &SlowTokenizerConfig,
+) -> tokenizers::Result<Tokenizer> {
+    let model = BPE::from_file(&vocab.to_string_lossy(), &merges.to_string_lossy()).build()?;
+    let add_prefix_space = config.flag("add_prefix_space", false);
+
+    let mut tokenizer = Tokenizer::new(ModelWrapper::BPE(model));
+    tokenizer.with_pre_tokenizer(PreTokenizerWrapper::ByteLevel(ByteLevel::new(
+        add_prefix_space,
+        true,
+        true,
+    )));
+    let cls = config.token("cls_token", "<s>");
+    let sep = config.token("sep_token", "</s>");
+    let processor = RobertaProcessing::new(
+        (sep.clone(), token_id(&tokenizer, &sep)?),
+        (cls.clone(), token_id(&tokenizer, &cls)?),
+    )
+    .trim_offsets(true)
+    .add_prefix_space(add_prefix_space);
+    tokenizer.with_post_processor(PostProcessorWrapper::Roberta(processor));
+    tokenizer.with_decoder(DecoderWrapper::ByteLevel(ByteLevel::default()));
+    Ok(tokenizer)
+}
+
+/// Mapping of the SentencePiece vocabulary to the ids of the model
+#[derive(Clone, Copy, PartialEq)]
+enum Layout {
+    /// Ids of the SentencePiece model, `[CLS]`/`[SEP]` special tokens (ALBERT, DeBERTa-v2)
+    Bert,
+    /// fairseq dictionary: `<s>`, `<pad>`, `</s>` and `<unk>` first and `<mask>` last
+    /// (XLM-RoBERTa, CamemBERT)
+    Fairseq,
+}
+
+/// Unigram tokenizer from a SentencePiece model, as the `SpmConverter` of transformers
+fn sentencepiece(
+    path: &Path,
+    config: &SlowTokenizerConfig,
+    layout: Layout,
+) -> tokenizers::Result<Tokenizer> {
+    let model = SentencePieceModel::parse(&fs::read(path)?)?;
+    if model.model_type != UNIGRAM {
+        return Err("only the SentencePiece Unigram models can be converted".into());
+    }
+
+    let (vocab, unk_id) = match layout {
+        Layout::Bert => (model.pieces, model.unk_id),
+        Layout::Fairseq if config.model_type == "camembert" => {
+            let mut vocab: Vec<(String, f64)> = ["<s>NOTUSED", "<pad>", "</s>NOTUSED", "<unk>"]
+                .into_iter()
+                .map(|token| (token.to_string(), 0.0))
+                .collect();
+            vocab.push(("<unk>NOTUSED".to_string(), -100.0));
+            vocab.extend(model.pieces.into_iter().skip(1));
+            vocab.push(("<mask>".to_string(), 0.0));
+            (vocab, 3)
+        }
+        Layout::Fairseq => {
+            let mut vocab: Vec<(String, f64)> = ["<s>", "<pad>", "</s>", "<unk>"]
+                .into_iter()
+                .map(|token| (token.to_string(), 0.0))
+                .collect();
+            vocab.extend(model.pieces.into_iter().skip(3));
+            vocab.push(("<mask>".to_string(), 0.0));
+            (vocab, 3)
+        }
+    };
+    let unigram = Unigram::from(vocab, Some(unk_id), model.byte_fallback)?;
+
+    let mut normalizers = Vec::new();
+    if !model.precompiled_charsmap.is_empty() {
+        let precompiled = Precompiled::from(&model.precompiled_charsmap)
+            .map_err(|err| format!("invalid precompiled charsmap: {err:?}"))?;
+        normalizers.push(NormalizerWrapper::Precompiled(precompiled));
+    }
+    if config.flag("do_lower_case", false) {
+        normalizers.push(NormalizerWrapper::Lowercase(Lowercase));
+    }
+    normalizers.push(NormalizerWrapper::StripNormalizer(Strip::new(false, true)));
+    normalizers.push(NormalizerWrapper::Replace(Replace::new(
+        ReplacePattern::Regex(" {2,}".to_string()),
+        "\u{2581}",
+    )?));
+
+    let mut tokenizer = Tokenizer::new(ModelWrapper::Unigram(unigram));
+    tokenizer.with_normalizer(NormalizerWrapper::Sequence(Sequence::new(normalizers)));
+    tokenizer.with_pre_tokenizer(PreTokenizerWrapper::Metaspace(Metaspace::new(
+        '\u{2581}', true,
+    )));
+    tokenizer.with_decoder(DecoderWrapper::Metaspace(Metaspace::new('\u{2581}', true)));
+
+    match layout {
+        Layout::Bert => {
+            let template = bert_template(
+                &tokenizer,
+                &config.token("cls_token", "[CLS]"),
+                &config.token("sep_token", "[SEP]"),
+            )?;
+            tokenizer.with_post_processor(PostProcessorWrapper::Template(template));
+        }
+        Layout::Fairseq => {
+            let cls = config.token("cls_token", "<s>");
+            let sep = config.token("sep_token", "</s>");
+            let template = TemplateProcessing::builder()
+                .try_single(format!("{cls} $A {sep}"))?
+                .try_pair(format!("{cls} $A {sep} {sep} $B {sep}"))?
+                .special_tokens(vec![
+                    (cls.clone(), token_id(&tokenizer, &cls)?),
+                    (sep.clone(), token_id(&tokenizer, &sep)?),
+                ])
+                .build()?;
+            tokenizer.with_post_processor(PostProcessorWrapper::Template(template));
+        }
+    }
+    Ok(tokenizer)
+}
+
+/// `TrainerSpec.ModelType.UNIGRAM` of `sentencepiece_model.proto`
+const UNIGRAM: u64 = 1;
+
+/// Fields of a `sentencepiece_model.proto` `ModelProto` used by the conversion
+struct SentencePieceModel {
+    pieces: Vec<(String, f64)>,
+    model_type: u64,
+    unk_id: usize,
+    byte_fallback: bool,
+    precompiled_charsmap: Vec<u8>,
+}
+
+impl SentencePieceModel {
+    fn parse(data: &[u8]) -> tokenizers::Result<Self> {
+        let mut model = Self {
+            pieces: Vec::new(),
+            model_type: UNIGRAM,
+            unk_id: 0,
+            byte_fallback: false,
+            precompiled_charsmap: Vec::new(),
+        };
+
+        let mut reader = ProtoReader(data);
+        while let Some((field, value)) = reader.next_field()? {
+            match (field, value) {
+                // SentencePiece pieces
+                (1, ProtoValue::Bytes(piece)) => {
+                    let (mut text, mut score) = (String::new(), 0.0);
+                    let mut reader = ProtoReader(piece);
+                    while let Some((field, value)) = reader.next_field()? {
+                        match (field, value) {
+                            (1, ProtoValue::Bytes(bytes)) => {
+                                text = String::from_utf8(bytes.to_vec())?;
+                            }
+                            (2, ProtoValue::Fixed32(bits)) => score = f32::from_bits(bits) as f64,
+                            _ => {}
+                        }
+                    }
+                    model.pieces.push((text, score));
+                }
+                // TrainerSpec trainer_spec
+                (2, ProtoValue::Bytes(spec)) => {
+                    let mut reader = ProtoReader(spec);
+                    while let Some((field, value)) = reader.next_field()? {
+                        match (field, value) {
+                            (3, ProtoValue::Varint(value)) => model.model_type = value,
+                            (35, ProtoValue::Varint(value)) => model.byte_fallback = value != 0,
+                            (40, ProtoValue::Varint(value)) => model.unk_id = value as usize,
+                            _ => {}
+                        }
+                    }
+                }
+                // NormalizerSpec normalizer_spec
+                (3, ProtoValue::Bytes(spec)) => {
+                    let mut reader = ProtoReader(spec);
+                    while let Some((field, value)) = reader.next_field()? {
+                        if let (2, ProtoValue::Bytes(charsmap)) = (field, value) {
+                            model.precompiled_charsmap = charsmap.to_vec();
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if model.pieces.is_empty() {
+            return Err("the SentencePiece model has no pieces".into());
+        }
+        Ok(model)
+    }
+}
+
+enum ProtoValue<'a> {
+    Varint(u64),
+    /// No field of the conversion is a 64-bit number: the value is skipped
+    Fixed64,
+    Fixed32(u32),
+    Bytes(&'a [u8]),
+}
+
+/// Reader of the fields of an encoded protobuf message
+struct ProtoReader<'a>(&'a [u8]);
+
+impl<'a> ProtoReader<'a> {
+    fn take(&mut self, length: usize) -> tokenizers::Result<&'a [u8]> {
+        if length > self.0.len() {
+            return Err("truncated protobuf message".into());
+        }
+        let (value, rest) = self.0.split_at(length);
+        self.0 = rest;
+        Ok(value)
+    }
+
+    fn varint(&mut self) -> tokenizers::Result<u64> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.take(1)?[0];
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("invalid protobuf varint".into())
+    }
+
+    fn next_field(&mut self) -> tokenizers::Result<Option<(u64, ProtoValue<'a>)>> {
+        if self.0.is_empty() {
+            return Ok(None);
+        }
+        let key = self.varint()?;
+        let value = match key & 0x7 {
+            0 => ProtoValue::Varint(self.varint()?),
+            1 => {
+                self.take(8)?;
+                ProtoValue::Fixed64
+            }
+            2 => {
+                let length = self.varint()? as usize;
+                ProtoValue::Bytes(self.take(length)?)
+            }
+            5 => ProtoValue::Fixed32(u32::from_le_bytes(self.take(4)?.try_into()?)),
+            wire_type => return Err(format!("unsupported protobuf wire type {wire_type}").into()),
+        };
+        Ok(Some((key >> 3, value)))
+    }
+}
@@ -1,36 +1,156 @@
 /// Payload tokenization logic
 use crate::TextEmbeddingsError;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use tokenizers::tokenizer::Tokenizer;
 pub use tokenizers::Encoding as RawEncoding;
-use tokenizers::{EncodeInput, TruncationDirection, TruncationParams, TruncationStrategy};
+use tokenizers::{EncodeInput, NormalizedString, TruncationParams, TruncationStrategy};
 use tokio::sync::{mpsc, oneshot};
 use tracing::{instrument, Span};
 
+/// Side of the inputs that is cut when they are longer than the maximum input length
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TruncationDirection {
+    /// Keep the last tokens, e.g. for the queries of decoder-based embedders
+    Left,
+    /// Keep the first tokens
+    #[default]
+    Right,
+}
+
+impl From<TruncationDirection> for tokenizers::TruncationDirection {
+    fn from(value: TruncationDirection) -> Self {
+        match value {
+            TruncationDirection::Left => Self::Left,
+            TruncationDirection::Right => Self::Right,
+        }
+    }
+}
+
+/// Unicode normalization form
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnicodeNormalization {
+    Nfc,
+    Nfkc,
+}
+
+/// Preprocessing of the texts of the inputs before tokenization, so that clients sending the
+/// same text with different encodings or spacing get the same embeddings
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Preprocessing {
+    pub unicode_normalization: Option<UnicodeNormalization>,
+    /// Replace the runs of whitespace with a single space and trim the text
+    pub collapse_whitespace: bool,
+    /// Remove the control characters that are not whitespace
+    pub strip_control: bool,
+    pub lowercase: bool,
+}
+
+impl Preprocessing {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn apply(&self, text: String) -> String {
+        if self.is_empty() {
+            return text;
+        }
+
+        let mut normalized = NormalizedString::from(text);
+        if self.strip_control {
+            normalized.filter(|c| !c.is_control() || c.is_whitespace());
+        }
+        match self.unicode_normalization {
+            Some(UnicodeNormalization::Nfc) => {
+                normalized.nfc();
+            }
+            Some(UnicodeNormalization::Nfkc) => {
+                normalized.nfkc();
+            }
+            None => {}
+        }
+        if self.lowercase {
+            normalized.lowercase();
+        }
+
+        let text = normalized.get();
+        if self.collapse_whitespace {
+            text.split_whitespace().collect::<Vec<_>>().join(" ")
+        } else {
+            text.to_string()
+        }
+    }
+
+    fn apply_input(&self, inputs: EncodingInput) -> EncodingInput {
+        match inputs {
+            EncodingInput::Single(s) => EncodingInput::Single(self.apply(s)),
+            EncodingInput::Dual(s1, s2) => EncodingInput::Dual(self.apply(s1), self.apply(s2)),
+            EncodingInput::Ids(ids) => EncodingInput::Ids(ids),
+        }
+    }
+}
+
+impl fmt::Display for Preprocessing {
+    /// Steps separated by commas, as in `--preprocessing`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let steps = [
+            (self.strip_control, "strip-control"),
+            (self.unicode_normalization == Some(UnicodeNormalization::Nfc), "nfc"),
+            (self.unicode_normalization == Some(UnicodeNormalization::Nfkc), "nfkc"),
+            (self.lowercase, "lowercase"),
+            (self.collapse_whitespace, "collapse-whitespace"),
+        ];
+        let steps: Vec<&str> = steps
+            .into_iter()
+            .filter_map(|(enabled, step)| enabled.then_some(step))
+            .collect();
+        write!(f, "{}", steps.join(","))
+    }
+}
+
 /// Validation
 #[derive(Debug, Clone)]
 pub struct Tokenization {
-    /// Channel to communicate with the background tokenization task
-    sender: mpsc::UnboundedSender<TokenizerRequest>,
+    /// Channel to communicate with the tokenization workers
+    sender: mpsc::Sender<(TokenizerRequest, Instant)>,
+    /// Used by the requests that do not set a direction
+    truncation_direction: TruncationDirection,
+    /// Label of the metrics
+    model: String,
 }
 
 impl Tokenization {
+    /// Start `workers` tokenization threads pulling from a queue of `queue_size` inputs.
+    /// Callers wait for a free slot when the queue is full.
+    /// `type_vocab_size` is the number of token types the model embeds.
+    /// `preprocessing` is applied to the texts that are encoded, not to the texts that are only
+    /// tokenized, whose offsets refer to the text sent by the client.
+    /// `model` labels the metrics.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         workers: usize,
+        queue_size: usize,
         tokenizer: Tokenizer,
         max_input_length: usize,
         position_offset: usize,
+        truncation_direction: TruncationDirection,
+        type_vocab_size: usize,
+        preprocessing: Preprocessing,
+        model: String,
     ) -> Self {
         tracing::info!("Starting {workers} tokenization workers");
 
         // Create channel
-        let (sender, mut round_robin_receiver) = mpsc::unbounded_channel();
-        let mut senders = Vec::with_capacity(workers);
+        let (sender, receiver) = mpsc::channel(queue_size.max(1));
+        // Workers share the queue so that a long input only delays the worker encoding it
+        let receiver = Arc::new(Mutex::new(receiver));
 
         // Create workers
         for _ in 0..workers {
             let tokenizer_clone = tokenizer.clone();
-            let (tokenizer_sender, tokenizer_receiver) = mpsc::unbounded_channel();
-            senders.push(tokenizer_sender);
+            let receiver = receiver.clone();
+            let model = model.clone();
 
             // Spawn worker
             std::thread::spawn(move || {
@@ -38,25 +158,37 @@ impl Tokenization {
                     tokenizer_clone,
                     max_input_length,
                     position_offset,
-                    tokenizer_receiver,
+                    type_vocab_size,
+                    preprocessing,
+                    model,
+                    receiver,
                 )
             });
         }
 
-        // Create tokenization round robin task
-        tokio::spawn(async move {
-            // Loop over requests
-            loop {
-                for sender in &senders {
-                    match round_robin_receiver.recv().await {
-                        None => return,
-                        Some(request) => sender.send(request).unwrap(),
-                    };
-                }
-            }
-        });
+        Self {
+            sender,
+            truncation_direction,
+            model,
+        }
+    }
+
+    /// Truncation direction of the requests that do not set one
+    pub fn truncation_direction(&self) -> TruncationDirection {
+        self.truncation_direction
+    }
 
-        Self { sender }
+    /// Wait for a free slot in the queue and send `request` to the workers
+    async fn send(&self, request: TokenizerRequest) {
+        self.sender
+            .send((request, Instant::now()))
+            .await
+            .expect("Tokenization workers dropped the receiver. This is a bug.");
+        metrics::increment_gauge!(
+            "te_tokenization_queue_size",
+            1.0,
+            "model" => self.model.clone()
+        );
     }
 
     #[instrument(skip_all)]
@@ -64,6 +196,7 @@ impl Tokenization {
         &self,
         inputs: EncodingInput,
         truncate: bool,
+        truncation_direction: Option<TruncationDirection>,
     ) -> Result<ValidEncoding, TextEmbeddingsError> {
         // Check if inputs is empty
         if inputs.is_empty() {
@@ -74,16 +207,16 @@ impl Tokenization {
 
         // Create response channel
         let (response_sender, response_receiver) = oneshot::channel();
-        // Send request to the background validation task
-        // Unwrap is safe here
-        self.sender
-            .send(TokenizerRequest::Encode(
-                inputs,
-                truncate,
-                response_sender,
-                Span::current(),
-            ))
-            .expect("Tokenization background task dropped the receiver. This is a bug.");
+        // Send request to the tokenization workers
+        let truncation_direction = truncation_direction.unwrap_or(self.truncation_direction);
+        let request = TokenizerRequest::Encode(
+            inputs,
+            truncate,
+            truncation_direction,
+            response_sender,
+            Span::current(),
+        );
+        self.send(request).await;
 
         // Await on response channel
         // Unwrap is safe here
@@ -105,16 +238,64 @@ impl Tokenization {
 
         // Create response channel
         let (response_sender, response_receiver) = oneshot::channel();
-        // Send request to the background validation task
+        // Send request to the tokenization workers
+        let request = TokenizerRequest::Tokenize(
+            inputs,
+            add_special_tokens,
+            response_sender,
+            Span::current(),
+        );
+        self.send(request).await;
+
+        // Await on response channel
         // Unwrap is safe here
-        self.sender
-            .send(TokenizerRequest::Tokenize(
-                inputs,
-                add_special_tokens,
-                response_sender,
-                Span::current(),
-            ))
-            .expect("Tokenization background task dropped the receiver. This is a bug.");
+        response_receiver.await.expect("Tokenization background task dropped the sender without sending a response. This is a bug.")
+    }
+
+    /// Count the tokens of `inputs` as they would be encoded, before any truncation
+    #[instrument(skip_all)]
+    pub async fn count(&self, inputs: EncodingInput) -> Result<TokenCount, TextEmbeddingsError> {
+        // Check if inputs is empty
+        if inputs.is_empty() {
+            return Err(TextEmbeddingsError::Validation(
+                "`inputs` cannot be empty".to_string(),
+            ));
+        }
+
+        // Create response channel
+        let (response_sender, response_receiver) = oneshot::channel();
+        // Send request to the tokenization workers
+        let request = TokenizerRequest::Count(inputs, response_sender, Span::current());
+        self.send(request).await;
+
+        // Await on response channel
+        // Unwrap is safe here
+        response_receiver.await.expect("Tokenization background task dropped the sender without sending a response. This is a bug.")
+    }
+
+    #[instrument(skip_all)]
+    pub async fn decode(
+        &self,
+        ids: Vec<u32>,
+        skip_special_tokens: bool,
+    ) -> Result<String, TextEmbeddingsError> {
+        // Check if ids is empty
+        if ids.is_empty() {
+            return Err(TextEmbeddingsError::Validation(
+                "`ids` cannot be empty".to_string(),
+            ));
+        }
+
+        // Create response channel
+        let (response_sender, response_receiver) = oneshot::channel();
+        // Send request to the tokenization workers
+        let request = TokenizerRequest::Decode(
+            ids,
+            skip_special_tokens,
+            response_sender,
+            Span::current(),
+        );
+        self.send(request).await;
 
         // Await on response channel
         // Unwrap is safe here
@@ -127,23 +308,54 @@ fn tokenizer_worker(
     mut tokenizer: Tokenizer,
     max_input_length: usize,
     position_offset: usize,
-    mut receiver: mpsc::UnboundedReceiver<TokenizerRequest>,
+    type_vocab_size: usize,
+    preprocessing: Preprocessing,
+    model: String,
+    receiver: Arc<Mutex<mpsc::Receiver<(TokenizerRequest, Instant)>>>,
 ) {
-    // Loop over requests
-    while let Some(request) = receiver.blocking_recv() {
+    loop {
+        // The lock is released before the request is processed
+        let next = receiver.lock().unwrap().blocking_recv();
+        let Some((request, queued_at)) = next else {
+            // The sender was dropped
+            return;
+        };
+        metrics::decrement_gauge!("te_tokenization_queue_size", 1.0, "model" => model.clone());
+        metrics::histogram!(
+            "te_tokenization_queue_duration",
+            queued_at.elapsed().as_secs_f64(),
+            "model" => model.clone()
+        );
+
         match request {
-            TokenizerRequest::Encode(inputs, truncate, response_tx, parent_span) => {
+            TokenizerRequest::Encode(
+                inputs,
+                truncate,
+                truncation_direction,
+                response_tx,
+                parent_span,
+            ) => {
                 parent_span.in_scope(|| {
                     if !response_tx.is_closed() {
-                        // It's possible that the user dropped its request resulting in a send error.
-                        // We just discard the error
-                        let _ = response_tx.send(encode_input(
-                            inputs,
+                        let encoding = encode_input(
+                            preprocessing.apply_input(inputs),
                             truncate,
+                            truncation_direction,
                             max_input_length,
                             position_offset,
+                            type_vocab_size,
                             &mut tokenizer,
-                        ));
+                        );
+                        if let Ok(encoding) = &encoding {
+                            metrics::histogram!(
+                                "te_request_input_length",
+                                encoding.input_ids.len() as f64,
+                                "model" => model.clone()
+                            );
+                        }
+                        // It's possible that the user dropped its request resulting in a send error.
+                        // We just discard the error
+                        let _ = response_tx.send(encoding);
                     }
                 })
             }
@@ -161,6 +373,27 @@ fn tokenizer_worker(
                     }
                 })
             }
+            TokenizerRequest::Count(inputs, response_tx, parent_span) => {
+                parent_span.in_scope(|| {
+                    if !response_tx.is_closed() {
+                        // It's possible that the user dropped its request resulting in a send error.
+                        // We just discard the error
+                        let _ = response_tx.send(count_input(
+                            preprocessing.apply_input(inputs),
+                            &mut tokenizer,
+                        ));
+                    }
+                })
+            }
+            TokenizerRequest::Decode(ids, skip_special_tokens, response_tx, parent_span) => {
+                parent_span.in_scope(|| {
+                    if !response_tx.is_closed() {
+                        // It's possible that the user dropped its request resulting in a send error.
+                        // We just discard the error
+                        let _ = response_tx.send(decode_ids(ids, skip_special_tokens, &tokenizer));
+                    }
+                })
+            }
         }
     }
 }
@@ -174,6 +407,11 @@ fn tokenize_input(
     let inputs: EncodeInput = match inputs {
         EncodingInput::Single(s) => s.into(),
         EncodingInput::Dual(s1, s2) => (s1, s2).into(),
+        EncodingInput::Ids(_) => {
+            return Err(TextEmbeddingsError::Validation(
+                "token ids cannot be tokenized".to_string(),
+            ))
+        }
     };
 
     Ok(tokenizer
@@ -181,23 +419,109 @@ fn tokenize_input(
         .encode(inputs, add_special_tokens)?)
 }
 
+fn count_input(
+    inputs: EncodingInput,
+    tokenizer: &mut Tokenizer,
+) -> Result<TokenCount, TextEmbeddingsError> {
+    // Token ids are used as is
+    if let EncodingInput::Ids(input_ids) = inputs {
+        check_vocabulary(&input_ids, tokenizer)?;
+        return Ok(TokenCount {
+            tokens: input_ids.len(),
+            special_tokens: 0,
+        });
+    }
+
+    let encoding = tokenize_input(inputs, true, None, tokenizer)?;
+    Ok(TokenCount {
+        tokens: encoding.len(),
+        special_tokens: encoding
+            .get_special_tokens_mask()
+            .iter()
+            .filter(|&&special| special == 1)
+            .count(),
+    })
+}
+
+fn decode_ids(
+    ids: Vec<u32>,
+    skip_special_tokens: bool,
+    tokenizer: &Tokenizer,
+) -> Result<String, TextEmbeddingsError> {
+    check_vocabulary(&ids, tokenizer)?;
+    Ok(tokenizer.decode(&ids, skip_special_tokens)?)
+}
+
+/// Check that token ids sent by the client exist in the vocabulary of the tokenizer
+fn check_vocabulary(ids: &[u32], tokenizer: &Tokenizer) -> Result<(), TextEmbeddingsError> {
+    let vocab_size = tokenizer.get_vocab_size(true);
+    match ids.iter().find(|&&id| id as usize >= vocab_size) {
+        Some(id) => Err(TextEmbeddingsError::Validation(format!(
+            "token id {id} is out of the vocabulary of size {vocab_size}"
+        ))),
+        None => Ok(()),
+    }
+}
+
 /// Get input length and optionally truncate it
 fn encode_input(
     inputs: EncodingInput,
     truncate: bool,
+    truncation_direction: TruncationDirection,
     max_input_length: usize,
     position_offset: usize,
+    type_vocab_size: usize,
     tokenizer: &mut Tokenizer,
 ) -> Result<ValidEncoding, TextEmbeddingsError> {
     // Default truncation params
     let truncate_params = truncate.then_some(TruncationParams {
-        direction: TruncationDirection::Right,
+        direction: truncation_direction.into(),
         max_length: max_input_length,
         strategy: TruncationStrategy::LongestFirst,
         stride: 0,
     });
 
-    let encoding = tokenize_input(inputs, true, truncate_params, tokenizer)?;
+    // Token ids are used as is
+    if let EncodingInput::Ids(mut input_ids) = inputs {
+        check_vocabulary(&input_ids, tokenizer)?;
+        let mut dropped_tokens = 0;
+        if truncate && input_ids.len() > max_input_length {
+            dropped_tokens = input_ids.len() - max_input_length;
+            match truncation_direction {
+                TruncationDirection::Left => {
+                    input_ids.drain(..input_ids.len() - max_input_length);
+                }
+                TruncationDirection::Right => input_ids.truncate(max_input_length),
+            }
+        }
+        let seq_len = input_ids.len();
+        if seq_len > max_input_length {
+            return Err(TextEmbeddingsError::Validation(format!(
+                "`inputs` must have less than {max_input_length} tokens. Given: {seq_len}"
+            )));
+        }
+
+        return Ok(ValidEncoding {
+            input_ids,
+            token_type_ids: vec![0; seq_len],
+            position_ids: (position_offset as u32..(seq_len + position_offset) as u32)
+                .collect::<Vec<_>>(),
+            // There is no text to point to
+            offsets: vec![(0, 0); seq_len],
+            special_tokens_mask: vec![0; seq_len],
+            dropped_tokens,
+        });
+    }
+
+    // The input is first encoded in full to count the tokens dropped by truncation: only the
+    // inputs that are too long are encoded twice
+    let mut encoding = tokenize_input(inputs.clone(), true, None, tokenizer)?;
+    let mut dropped_tokens = 0;
+    if truncate_params.is_some() && encoding.len() > max_input_length {
+        let full_length = encoding.len();
+        encoding = tokenize_input(inputs, true, truncate_params, tokenizer)?;
+        dropped_tokens = full_length - encoding.len();
+    }
     let seq_len = encoding.len();
 
     if seq_len > max_input_length {
@@ -206,34 +530,88 @@ fn encode_input(
         )));
     }
 
-    metrics::histogram!("te_request_input_length", seq_len as f64);
-
     Ok(ValidEncoding {
         input_ids: encoding.get_ids().to_vec(),
-        token_type_ids: encoding.get_type_ids().to_vec(),
+        token_type_ids: token_type_ids(&encoding, type_vocab_size),
         position_ids: (position_offset as u32..(seq_len + position_offset) as u32)
             .collect::<Vec<_>>(),
+        offsets: encoding.get_offsets().to_vec(),
+        special_tokens_mask: encoding.get_special_tokens_mask().to_vec(),
+        dropped_tokens,
     })
 }
 
+/// Token type ids of `encoding` for a model embedding `type_vocab_size` token types
+fn token_type_ids(encoding: &RawEncoding, type_vocab_size: usize) -> Vec<u32> {
+    // Models without segment embeddings, such as RoBERTa, only know the type 0
+    if type_vocab_size <= 1 {
+        return vec![0; encoding.len()];
+    }
+
+    let type_ids = encoding.get_type_ids();
+    // Post-processors that do not assign a segment to the second sequence of a pair, e.g.
+    // templates without `$B:1`: the second sequence and the special tokens after it get the type 1
+    // as in BERT
+    if encoding.n_sequences() > 1 && type_ids.iter().all(|&type_id| type_id == 0) {
+        let second = encoding
+            .get_sequence_ids()
+            .iter()
+            .position(|&sequence_id| sequence_id == Some(1))
+            .unwrap_or(encoding.len());
+        return (0..encoding.len()).map(|i| (i >= second) as u32).collect();
+    }
+    // The model has no embedding for the types above its vocabulary
+    type_ids
+        .iter()
+        .map(|&type_id| type_id.min(type_vocab_size as u32 - 1))
+        .collect()
+}
+
 #[derive(Debug)]
 pub struct ValidEncoding {
     pub input_ids: Vec<u32>,
     pub token_type_ids: Vec<u32>,
     pub position_ids: Vec<u32>,
+    /// Character offsets of the tokens in the input
+    pub offsets: Vec<(usize, usize)>,
+    /// 1 for the special tokens added by the tokenizer
+    pub special_tokens_mask: Vec<u32>,
+    /// Number of tokens cut from the input by truncation
+    pub dropped_tokens: usize,
 }
 
-#[derive(Debug)]
+/// Length of an input once encoded
+#[derive(Debug, Clone, Copy)]
+pub struct TokenCount {
+    /// Number of tokens, special tokens included
+    pub tokens: usize,
+    /// Number of special tokens added by the tokenizer
+    pub special_tokens: usize,
+}
+
+#[derive(Debug, Clone)]
 pub enum EncodingInput {
     Single(String),
     Dual(String, String),
+    /// Already tokenized input
+    Ids(Vec<u32>),
 }
 
 impl EncodingInput {
+    /// Number of characters of the input. Token ids have no characters.
+    pub fn count_chars(&self) -> usize {
+        match self {
+            EncodingInput::Single(s) => s.chars().count(),
+            EncodingInput::Dual(s1, s2) => s1.chars().count() + s2.chars().count(),
+            EncodingInput::Ids(_) => 0,
+        }
+    }
+
     fn is_empty(&self) -> bool {
         match self {
             EncodingInput::Single(s) => s.is_empty(),
             EncodingInput::Dual(s1, s2) => s1.is_empty() && s2.is_empty(),
+            EncodingInput::Ids(ids) => ids.is_empty(),
         }
     }
 }
@@ -244,6 +622,12 @@ impl From<String> for EncodingInput {
     }
 }
 
+impl From<Vec<u32>> for EncodingInput {
+    fn from(value: Vec<u32>) -> Self {
+        Self::Ids(value)
+    }
+}
+
 impl From<(String, String)> for EncodingInput {
     fn from(value: (String, String)) -> Self {
         Self::Dual(value.0, value.1)
@@ -254,6 +638,7 @@ enum TokenizerRequest {
     Encode(
         EncodingInput,
         bool,
+        TruncationDirection,
         oneshot::Sender<Result<ValidEncoding, TextEmbeddingsError>>,
         Span,
     ),
@@ -263,4 +648,15 @@ enum TokenizerRequest {
         oneshot::Sender<Result<RawEncoding, TextEmbeddingsError>>,
         Span,
     ),
+    Count(
+        EncodingInput,
+        oneshot::Sender<Result<TokenCount, TextEmbeddingsError>>,
+        Span,
+    ),
+    Decode(
+        Vec<u32>,
+        bool,
+        oneshot::Sender<Result<String, TextEmbeddingsError>>,
+        Span,
+    ),
 }
@@ -0,0 +1,198 @@
+/// API key authentication and per-key quotas
+use crate::{ErrorResponse, ErrorType};
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Quotas are counted over fixed windows of one minute
+const QUOTA_WINDOW: Duration = Duration::from_secs(60);
+
+/// An entry of the `--api-key-file` JSON array
+#[derive(Debug, Deserialize)]
+struct ApiKeyConfig {
+    key: String,
+    /// Maximum number of requests per minute. Unlimited if not set
+    #[serde(default)]
+    max_requests_per_minute: Option<u64>,
+    /// Maximum number of prompt tokens per minute. Unlimited if not set
+    #[serde(default)]
+    max_tokens_per_minute: Option<u64>,
+}
+
+struct ApiKeyState {
+    max_requests_per_minute: Option<u64>,
+    max_tokens_per_minute: Option<u64>,
+    usage: Mutex<Usage>,
+}
+
+struct Usage {
+    window_start: Instant,
+    requests: u64,
+    tokens: u64,
+}
+
+impl Usage {
+    /// Start a new window if the current one is over
+    fn renew(&mut self) {
+        if self.window_start.elapsed() >= QUOTA_WINDOW {
+            self.window_start = Instant::now();
+            self.requests = 0;
+            self.tokens = 0;
+        }
+    }
+}
+
+/// Keys allowed to call the inference routes
+#[derive(Clone)]
+pub(crate) struct ApiKeys {
+    keys: Arc<HashMap<String, ApiKeyState>>,
+}
+
+impl ApiKeys {
+    /// Keys given with `--api-key` have no quotas.
+    /// Returns `None` if no key is configured: authentication is then disabled.
+    pub(crate) fn load(
+        api_keys: Vec<String>,
+        api_key_file: Option<String>,
+    ) -> Result<Option<Self>> {
+        let mut configs: Vec<ApiKeyConfig> = api_keys
+            .into_iter()
+            .map(|key| ApiKeyConfig {
+                key,
+                max_requests_per_minute: None,
+                max_tokens_per_minute: None,
+            })
+            .collect();
+
+        if let Some(path) = api_key_file {
+            let file = fs::read_to_string(&path)
+                .with_context(|| format!("Could not read API key file `{path}`"))?;
+            let file_configs: Vec<ApiKeyConfig> = serde_json::from_str(&file)
+                .with_context(|| format!("Failed to parse API key file `{path}`"))?;
+            configs.extend(file_configs);
+        }
+
+        if configs.is_empty() {
+            return Ok(None);
+        }
+
+        let mut keys = HashMap::with_capacity(configs.len());
+        for config in configs {
+            if config.key.is_empty() {
+                return Err(anyhow!("API keys cannot be empty"));
+            }
+            let state = ApiKeyState {
+                max_requests_per_minute: config.max_requests_per_minute,
+                max_tokens_per_minute: config.max_tokens_per_minute,
+                usage: Mutex::new(Usage {
+                    window_start: Instant::now(),
+                    requests: 0,
+                    tokens: 0,
+                }),
+            };
+            if keys.insert(config.key, state).is_some() {
+                return Err(anyhow!("API keys must be unique"));
+            }
+        }
+        tracing::info!("API key authentication enabled for {} keys", keys.len());
+
+        Ok(Some(Self {
+            keys: Arc::new(keys),
+        }))
+    }
+
+    /// Check that `key` is valid and within its quotas and count the request
+    pub(crate) fn authorize(&self, key: Option<&str>) -> Result<(), ErrorResponse> {
+        let state = match key.and_then(|key| self.keys.get(key)) {
+            Some(state) => state,
+            None => {
+                metrics::increment_counter!("te_request_failure", "err" => "unauthorized");
+                let message = match key {
+                    Some(_) => "Invalid API key",
+                    None => "Missing API key",
+                };
+                tracing::error!("{message}");
+                return Err(ErrorResponse {
+                    error: message.to_string(),
+                    error_type: ErrorType::Unauthorized,
+                });
+            }
+        };
+
+        let mut usage = state.usage.lock().unwrap();
+        usage.renew();
+
+        let message = if state
+            .max_requests_per_minute
+            .map_or(false, |max| usage.requests >= max)
+        {
+            Some("Request quota exceeded")
+        } else if state
+            .max_tokens_per_minute
+            .map_or(false, |max| usage.tokens >= max)
+        {
+            Some("Token quota exceeded")
+        } else {
+            None
+        };
+        if let Some(message) = message {
+            metrics::increment_counter!("te_request_failure", "err" => "quota");
+            tracing::error!("{message}");
+            return Err(ErrorResponse {
+                error: message.to_string(),
+                error_type: ErrorType::QuotaExceeded,
+            });
+        }
+
+        usage.requests += 1;
+        Ok(())
+    }
+
+    /// Count the prompt tokens of a request authorized with `key`
+    #[cfg_attr(not(feature = "http"), allow(dead_code))]
+    pub(crate) fn record_tokens(&self, key: &str, tokens: u64) {
+        if let Some(state) = self.keys.get(key) {
+            state.usage.lock().unwrap().tokens += tokens;
+        }
+    }
+
+    /// Check that `tokens` more prompt tokens fit in the token quota of `key` for the current
+    /// window, before starting a batch job of that many tokens
+    #[cfg_attr(not(feature = "http"), allow(dead_code))]
+    pub(crate) fn check_tokens(&self, key: &str, tokens: u64) -> Result<(), ErrorResponse> {
+        let Some(state) = self.keys.get(key) else {
+            return Ok(());
+        };
+        let Some(max_tokens) = state.max_tokens_per_minute else {
+            return Ok(());
+        };
+
+        let mut usage = state.usage.lock().unwrap();
+        usage.renew();
+        if usage.tokens + tokens > max_tokens {
+            metrics::increment_counter!("te_request_failure", "err" => "quota");
+            let message = format!(
+                "Token quota exceeded: {tokens} tokens with {} left in the current minute",
+                max_tokens.saturating_sub(usage.tokens)
+            );
+            tracing::error!("{message}");
+            return Err(ErrorResponse {
+                error: message,
+                error_type: ErrorType::QuotaExceeded,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Identify an API key in the logs and usage counters without writing the key itself.
+/// The first 8 bytes of the SHA-256 of the key, so that fingerprints are stable across releases
+#[cfg_attr(not(feature = "http"), allow(dead_code))]
+pub(crate) fn key_fingerprint(key: &str) -> String {
+    let digest = Sha256::digest(key.as_bytes());
+    digest[..8].iter().map(|byte| format!("{byte:02x}")).collect()
+}
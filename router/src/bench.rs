@@ -0,0 +1,205 @@
+/// `bench` subcommand: latency and throughput of the model on synthetic inputs
+use crate::{Info, ModelType};
+use anyhow::{anyhow, Result};
+use futures::future::try_join_all;
+use futures::{stream, StreamExt};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::time::{Duration, Instant};
+use text_embeddings_core::infer::Infer;
+use text_embeddings_core::queue::Priority;
+
+/// Distribution of the lengths of the synthetic inputs
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum LengthDistribution {
+    /// Every input has the sequence length
+    Fixed,
+    /// Lengths are drawn uniformly between 1 and the sequence length
+    Uniform,
+    /// Lengths follow an exponential distribution of mean a quarter of the sequence length,
+    /// capped to it: most inputs are short and a few are long, as in most corpora
+    LongTail,
+}
+
+impl LengthDistribution {
+    fn sample(&self, rng: &mut StdRng, sequence_length: usize) -> usize {
+        match self {
+            LengthDistribution::Fixed => sequence_length,
+            LengthDistribution::Uniform => rng.gen_range(1..=sequence_length),
+            LengthDistribution::LongTail => {
+                let mean = sequence_length as f64 / 4.0;
+                let length = (-(1.0 - rng.gen::<f64>()).ln() * mean).ceil() as usize;
+                length.clamp(1, sequence_length)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct BenchArgs {
+    /// Maximum number of tokens of the inputs, separated by commas. Lengths larger than the
+    /// maximum input length of the model are capped to it
+    #[clap(default_value = "128,512", long, value_delimiter = ',')]
+    pub sequence_lengths: Vec<usize>,
+
+    /// Distributions of the lengths of the inputs, separated by commas
+    #[clap(default_value = "fixed", long, value_delimiter = ',', value_enum)]
+    pub distributions: Vec<LengthDistribution>,
+
+    /// Number of inputs of each request, separated by commas
+    #[clap(default_value = "1,32", long, value_delimiter = ',')]
+    pub batch_sizes: Vec<usize>,
+
+    /// Number of requests in flight at the same time, separated by commas
+    #[clap(default_value = "1,32", long, value_delimiter = ',')]
+    pub concurrency: Vec<usize>,
+
+    /// Number of requests sent for each combination of the parameters above
+    #[clap(default_value = "64", long)]
+    pub requests: usize,
+
+    /// Seed of the synthetic inputs, to compare runs with different router options
+    #[clap(default_value = "0", long)]
+    pub seed: u64,
+}
+
+/// Latencies and processed tokens of a run
+struct RunResult {
+    latencies: Vec<Duration>,
+    tokens: usize,
+    duration: Duration,
+}
+
+impl RunResult {
+    /// Nearest-rank percentile of the latencies in milliseconds
+    fn percentile(&self, percentile: f64) -> f64 {
+        let rank = (percentile / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        let index = rank.clamp(1, self.latencies.len()) - 1;
+        self.latencies[index].as_secs_f64() * 1000.0
+    }
+}
+
+/// Run every combination of the parameters of `args` and print a line of statistics for each
+pub(crate) async fn bench(infer: &Infer, info: &Info, args: BenchArgs) -> Result<()> {
+    let sweeps = [
+        ("--sequence-lengths", &args.sequence_lengths),
+        ("--batch-sizes", &args.batch_sizes),
+        ("--concurrency", &args.concurrency),
+    ];
+    for (name, values) in sweeps {
+        if values.is_empty() || values.contains(&0) {
+            return Err(anyhow!("`{name}` must only hold values > 0"));
+        }
+    }
+    if args.distributions.is_empty() {
+        return Err(anyhow!("`--distributions` cannot be empty"));
+    }
+    if args.requests == 0 {
+        return Err(anyhow!("`--requests` must be > 0"));
+    }
+
+    let classifier = !matches!(info.model_type, ModelType::Embedding(_));
+    // Token ids are drawn at random from the vocabulary: the cost of a batch only depends on its
+    // shape
+    let vocab_size = info.metadata.tokenizer.vocab_size as u32;
+    let mut rng = StdRng::seed_from_u64(args.seed);
+
+    println!(
+        "{:<12} {:>7} {:>6} {:>11} {:>9} {:>9} {:>9} {:>9} {:>11}",
+        "distribution",
+        "length",
+        "batch",
+        "concurrency",
+        "p50 (ms)",
+        "p95 (ms)",
+        "p99 (ms)",
+        "req/s",
+        "tokens/s"
+    );
+    for &distribution in &args.distributions {
+        for &sequence_length in &args.sequence_lengths {
+            let sequence_length = sequence_length.min(info.max_input_length);
+            for &batch_size in &args.batch_sizes {
+                for &concurrency in &args.concurrency {
+                    let requests: Vec<Vec<Vec<u32>>> = (0..args.requests)
+                        .map(|_| {
+                            (0..batch_size)
+                                .map(|_| {
+                                    let length = distribution.sample(&mut rng, sequence_length);
+                                    (0..length).map(|_| rng.gen_range(0..vocab_size)).collect()
+                                })
+                                .collect()
+                        })
+                        .collect();
+
+                    let mut result = run(infer, classifier, requests, concurrency).await?;
+                    result.latencies.sort();
+                    let seconds = result.duration.as_secs_f64();
+                    println!(
+                        "{:<12} {:>7} {:>6} {:>11} {:>9.2} {:>9.2} {:>9.2} {:>9.1} {:>11.0}",
+                        format!("{distribution:?}").to_lowercase(),
+                        sequence_length,
+                        batch_size,
+                        concurrency,
+                        result.percentile(50.0),
+                        result.percentile(95.0),
+                        result.percentile(99.0),
+                        args.requests as f64 / seconds,
+                        result.tokens as f64 / seconds,
+                    );
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Send `requests` with at most `concurrency` of them in flight. The inputs of a request are
+/// queued at once, as the HTTP and gRPC servers do
+async fn run(
+    infer: &Infer,
+    classifier: bool,
+    requests: Vec<Vec<Vec<u32>>>,
+    concurrency: usize,
+) -> Result<RunResult> {
+    let start = Instant::now();
+    let results: Vec<_> = stream::iter(requests)
+        .map(|inputs| async move {
+            let start = Instant::now();
+            let tokens = try_join_all(inputs.into_iter().map(|input| async move {
+                let permit = infer.acquire_permit().await;
+                let metadata = if classifier {
+                    infer
+                        .predict(input, false, None, false, None, Priority::Normal, permit)
+                        .await?
+                        .metadata
+                } else {
+                    let priority = Priority::Normal;
+                    infer
+                        .embed_pooled(input, false, None, true, None, None, priority, permit)
+                        .await?
+                        .metadata
+                };
+                Ok::<_, anyhow::Error>(metadata.prompt_tokens)
+            }))
+            .await?;
+            Ok::<_, anyhow::Error>((start.elapsed(), tokens.into_iter().sum::<usize>()))
+        })
+        .buffer_unordered(concurrency)
+        .collect()
+        .await;
+    let duration = start.elapsed();
+
+    let mut latencies = Vec::with_capacity(results.len());
+    let mut tokens = 0;
+    for result in results {
+        let (latency, request_tokens) = result?;
+        latencies.push(latency);
+        tokens += request_tokens;
+    }
+    Ok(RunResult {
+        latencies,
+        tokens,
+        duration,
+    })
+}
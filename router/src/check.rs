@@ -0,0 +1,435 @@
+/// `check` subcommand: support of a model by every backend, from its configuration files only.
+/// The rules mirror the checks the backends run when they load a model, for every build of the
+/// router, not only the one running the command.
+use anyhow::{anyhow, Context, Result};
+use hf_hub::api::tokio::ApiBuilder;
+use hf_hub::{Cache, Repo, RepoType};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use text_embeddings_backend::{DType, ModelType, Pool};
+use text_embeddings_core::model::PoolConfig;
+use text_embeddings_core::slow_tokenizer::{
+    load_tokenizer, SLOW_TOKENIZER_CONFIG_FILES, SLOW_TOKENIZER_FILES,
+};
+use tokenizers::models::ModelWrapper;
+
+/// `model_type` values of the architectures implemented by the Candle and wgpu backends
+const BERT_MODEL_TYPES: [&str; 5] = ["bert", "xlm-roberta", "camembert", "roberta", "starencoder"];
+
+/// Builds of the router, named after their cargo features
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Target {
+    Candle,
+    CandleCuda,
+    CandleCudaTuring,
+    CandleCudaVolta,
+    Metal,
+    Ort,
+    Wgpu,
+    Python,
+}
+
+impl Target {
+    const ALL: [Target; 8] = [
+        Target::Candle,
+        Target::CandleCuda,
+        Target::CandleCudaTuring,
+        Target::CandleCudaVolta,
+        Target::Metal,
+        Target::Ort,
+        Target::Wgpu,
+        Target::Python,
+    ];
+
+    fn features(&self) -> &'static str {
+        match self {
+            Target::Candle => "candle",
+            Target::CandleCuda => "candle-cuda",
+            Target::CandleCudaTuring => "candle-cuda-turing",
+            Target::CandleCudaVolta => "candle-cuda-volta",
+            Target::Metal => "metal",
+            Target::Ort => "ort",
+            Target::Wgpu => "wgpu",
+            Target::Python => "python",
+        }
+    }
+}
+
+/// What the backends look at to decide if they can run a model
+struct ModelSummary {
+    model_type: String,
+    kind: ModelType,
+    position_embedding_type: String,
+    head_size: Option<usize>,
+    /// Files of the repository, e.g. `onnx/model.onnx`
+    files: BTreeSet<String>,
+}
+
+impl ModelSummary {
+    fn has(&self, file: &str) -> bool {
+        self.files.contains(file)
+    }
+
+    fn dense_note(&self) -> &'static str {
+        if self.has("2_Dense/config.json") {
+            ", `2_Dense` projection is not applied"
+        } else {
+            ""
+        }
+    }
+}
+
+/// Print whether each build of the router can run `model_id` with each dtype.
+/// Fails if none can
+pub(crate) async fn check(
+    model_id: &str,
+    revision: Option<String>,
+    dtype: Option<DType>,
+    pooling: Option<Pool>,
+    hf_api_token: Option<String>,
+    huggingface_hub_cache: Option<String>,
+    offline: bool,
+) -> Result<()> {
+    let (model_root, files) = fetch_metadata(
+        model_id,
+        revision,
+        pooling.is_some(),
+        hf_api_token,
+        huggingface_hub_cache,
+        offline,
+    )
+    .await?;
+
+    let config = fs::read_to_string(model_root.join("config.json"))
+        .context("`config.json` not found")?;
+    let config: serde_json::Value =
+        serde_json::from_str(&config).context("Failed to parse `config.json`")?;
+    let architectures: Vec<&str> = config["architectures"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|arch| arch.as_str())
+        .collect();
+    let model_type = config["model_type"].as_str().unwrap_or_default().to_string();
+    let position_embedding_type = config["position_embedding_type"]
+        .as_str()
+        .unwrap_or("absolute")
+        .to_string();
+    let head_size = config["hidden_size"]
+        .as_u64()
+        .zip(config["num_attention_heads"].as_u64())
+        .filter(|(_, heads)| *heads > 0)
+        .map(|(hidden_size, heads)| (hidden_size / heads) as usize);
+
+    println!("Model: {model_id}");
+    println!("  architectures: {}", architectures.join(", "));
+    println!("  model type: {model_type}");
+    println!("  position embeddings: {position_embedding_type}");
+
+    let tokenizer = load_tokenizer(&model_root)
+        .map_err(|err| anyhow!("Could not load the tokenizer: {err}"))?;
+    let tokenizer_model = match tokenizer.get_model() {
+        ModelWrapper::BPE(_) => "BPE",
+        ModelWrapper::WordPiece(_) => "WordPiece",
+        ModelWrapper::WordLevel(_) => "WordLevel",
+        ModelWrapper::Unigram(_) => "Unigram",
+    };
+    println!("  tokenizer: {tokenizer_model}");
+
+    let classifier = architectures
+        .iter()
+        .any(|arch| arch.ends_with("Classification"));
+    let kind = if classifier {
+        println!("  kind: classifier");
+        ModelType::Classifier
+    } else {
+        let pool = match pooling {
+            Some(pool) => pool,
+            None => {
+                let config = fs::read_to_string(model_root.join("1_Pooling/config.json"))
+                    .context(
+                        "`--pooling` is not set and the model has no pooling configuration \
+                         (`1_Pooling/config.json`)",
+                    )?;
+                let config: PoolConfig = serde_json::from_str(&config)
+                    .context("Failed to parse `1_Pooling/config.json`")?;
+                config
+                    .pool()
+                    .ok_or_else(|| anyhow!("Pooling config {config:?} is not supported"))?
+            }
+        };
+        println!("  kind: embedding model with {pool} pooling");
+        ModelType::Embedding(pool)
+    };
+
+    let model = ModelSummary {
+        model_type,
+        kind,
+        position_embedding_type,
+        head_size,
+        files,
+    };
+    let weights: Vec<&str> = [
+        "model.safetensors",
+        "model.safetensors.index.json",
+        "pytorch_model.bin",
+        "model.gguf",
+        "model.onnx",
+        "onnx/model.onnx",
+    ]
+    .into_iter()
+    .filter(|file| model.has(file))
+    .collect();
+    println!("  weights: {}", weights.join(", "));
+    println!();
+
+    let dtypes = match dtype {
+        Some(dtype) => vec![dtype.to_string()],
+        None => vec!["float32".to_string(), "float16".to_string()],
+    };
+    let mut supported = false;
+    println!("{:<20} {:<9} support", "features", "dtype");
+    for target in Target::ALL {
+        for dtype in &dtypes {
+            let support = match check_target(target, dtype, &model) {
+                Ok(implementation) => {
+                    supported = true;
+                    format!("yes: {implementation}")
+                }
+                Err(reason) => format!("no: {reason}"),
+            };
+            println!("{:<20} {dtype:<9} {support}", target.features());
+        }
+    }
+
+    if !supported {
+        return Err(anyhow!("{model_id} is not supported by any backend"));
+    }
+    Ok(())
+}
+
+/// Implementation running the model on `target`, or the reason why it cannot
+fn check_target(target: Target, dtype: &str, model: &ModelSummary) -> Result<String, String> {
+    match target {
+        Target::Ort => {
+            if dtype != "float32" {
+                return Err("only float32 is supported".to_string());
+            }
+            if model.kind == ModelType::Embedding(Pool::Splade) {
+                return Err("splade pooling is not supported".to_string());
+            }
+            if !model.has("model.onnx") && !model.has("onnx/model.onnx") {
+                return Err("no `model.onnx` or `onnx/model.onnx`".to_string());
+            }
+            Ok(format!("ONNX Runtime{}", model.dense_note()))
+        }
+        Target::Wgpu => {
+            if dtype != "float32" {
+                return Err("only float32 is supported".to_string());
+            }
+            match &model.kind {
+                ModelType::Classifier => return Err("classifiers are not supported".to_string()),
+                ModelType::Embedding(Pool::Splade) => {
+                    return Err("splade pooling is not supported".to_string())
+                }
+                ModelType::Embedding(_) => {}
+            }
+            if !BERT_MODEL_TYPES.contains(&model.model_type.as_str()) {
+                return Err(format!("model type `{}` is not supported", model.model_type));
+            }
+            if model.position_embedding_type != "absolute" {
+                return Err(format!(
+                    "{} position embeddings are not supported",
+                    model.position_embedding_type
+                ));
+            }
+            if model.head_size.map_or(true, |head_size| head_size > 256) {
+                return Err("attention heads larger than 256 are not supported".to_string());
+            }
+            if !model.has("model.safetensors") {
+                return Err("no `model.safetensors`".to_string());
+            }
+            Ok(format!("wgpu Bert{}", model.dense_note()))
+        }
+        Target::Python => {
+            match &model.kind {
+                ModelType::Classifier => return Err("classifiers are not supported".to_string()),
+                ModelType::Embedding(Pool::Cls) => {}
+                ModelType::Embedding(pool) => {
+                    return Err(format!("{pool} pooling is not supported"))
+                }
+            }
+            if !matches!(dtype, "float32" | "float16") {
+                return Err(format!("{dtype} is not supported"));
+            }
+            Ok("Python backend".to_string())
+        }
+        _ => check_candle(target, dtype, model),
+    }
+}
+
+/// Model selected by the Candle backend for `target`. See `load_model` in the Candle backend
+fn check_candle(target: Target, dtype: &str, model: &ModelSummary) -> Result<String, String> {
+    if !BERT_MODEL_TYPES.contains(&model.model_type.as_str()) {
+        return Err(format!("model type `{}` is not supported", model.model_type));
+    }
+    let alibi = match model.position_embedding_type.as_str() {
+        "absolute" => false,
+        "alibi" => true,
+        position => return Err(format!("{position} position embeddings are not supported")),
+    };
+
+    let safetensors =
+        model.has("model.safetensors") || model.has("model.safetensors.index.json");
+    let pytorch = model.has("pytorch_model.bin");
+    if !safetensors && !pytorch {
+        return if model.has("model.gguf") {
+            Ok("QuantizedBert on CPU, from `model.gguf`".to_string())
+        } else {
+            Err("no `model.safetensors`, `pytorch_model.bin` or `model.gguf`".to_string())
+        };
+    }
+
+    let cuda = matches!(
+        target,
+        Target::CandleCuda | Target::CandleCudaTuring | Target::CandleCudaVolta
+    );
+    match dtype {
+        "int8" if target != Target::Candle => {
+            return Err("int8 is only available on CPU".to_string())
+        }
+        "float8" if !cuda => return Err("float8 is only available on CUDA".to_string()),
+        "float32" | "float16" | "int8" | "float8" | "auto" => {}
+        dtype => return Err(format!("{dtype} is not supported")),
+    }
+    // `auto` selects a half precision dtype on GPUs
+    let half =
+        matches!(dtype, "float16" | "float8") || (dtype == "auto" && target != Target::Candle);
+    let splade = model.kind == ModelType::Embedding(Pool::Splade);
+
+    let implementation = match target {
+        Target::CandleCuda | Target::CandleCudaTuring if half && !alibi && !splade => "FlashBert",
+        Target::CandleCuda if half && alibi => "FlashJinaBert",
+        Target::Metal if !alibi && !splade => "FlashBert",
+        _ if alibi => "JinaBert",
+        _ => "Bert",
+    };
+    let mut support = implementation.to_string();
+    if target == Target::Candle && dtype == "float16" {
+        support.push_str(", half precision matmuls are slow on CPU");
+    }
+    if !safetensors {
+        support.push_str(", loading `pytorch_model.bin` is slow");
+    }
+    Ok(support)
+}
+
+/// Download the configuration files of the model, and not its weights.
+/// Returns the directory holding them and the files of the repository
+async fn fetch_metadata(
+    model_id: &str,
+    revision: Option<String>,
+    pooling_set: bool,
+    hf_api_token: Option<String>,
+    huggingface_hub_cache: Option<String>,
+    offline: bool,
+) -> Result<(PathBuf, BTreeSet<String>)> {
+    let model_id_path = Path::new(model_id);
+    if model_id_path.is_dir() {
+        return Ok((model_id_path.to_path_buf(), list_files(model_id_path)?));
+    }
+    if model_id.contains("://") {
+        return Err(anyhow!(
+            "`check` only supports Hub models and local directories. Download the model and \
+             check its directory"
+        ));
+    }
+
+    let revision = revision.unwrap_or("main".to_string());
+    if offline {
+        let cache = huggingface_hub_cache
+            .map(|dir| Cache::new(dir.into()))
+            .unwrap_or_default();
+        let repo = cache.repo(Repo::with_revision(
+            model_id.to_string(),
+            RepoType::Model,
+            revision,
+        ));
+        let config_path = repo
+            .get("config.json")
+            .ok_or_else(|| anyhow!("{model_id} is not in the Hub cache"))?;
+        let model_root = config_path.parent().unwrap().to_path_buf();
+        let files = list_files(&model_root)?;
+        return Ok((model_root, files));
+    }
+
+    let mut builder = ApiBuilder::new()
+        .with_progress(false)
+        .with_token(hf_api_token);
+    if let Some(cache_dir) = huggingface_hub_cache {
+        builder = builder.with_cache_dir(cache_dir.into());
+    }
+    let api = builder.build()?;
+    let api_repo = api.repo(Repo::with_revision(
+        model_id.to_string(),
+        RepoType::Model,
+        revision,
+    ));
+
+    let files: BTreeSet<String> = api_repo
+        .info()
+        .await
+        .with_context(|| format!("Could not list the files of {model_id}"))?
+        .siblings
+        .into_iter()
+        .map(|sibling| sibling.rfilename)
+        .collect();
+    let config_path = api_repo
+        .get("config.json")
+        .await
+        .context("Could not download `config.json`")?;
+    // The slow tokenizer of the models without `tokenizer.json` is converted
+    let tokenizer_files = if files.contains("tokenizer.json") {
+        vec!["tokenizer.json"]
+    } else {
+        SLOW_TOKENIZER_FILES
+            .into_iter()
+            .find(|slow_files| slow_files.iter().all(|file| files.contains(*file)))
+            .map(|slow_files| [slow_files, &SLOW_TOKENIZER_CONFIG_FILES[..]].concat())
+            .unwrap_or_default()
+    };
+    for file in tokenizer_files.into_iter().filter(|file| files.contains(*file)) {
+        api_repo
+            .get(file)
+            .await
+            .with_context(|| format!("Could not download `{file}`"))?;
+    }
+    if !pooling_set && files.contains("1_Pooling/config.json") {
+        api_repo
+            .get("1_Pooling/config.json")
+            .await
+            .context("Could not download `1_Pooling/config.json`")?;
+    }
+
+    Ok((config_path.parent().unwrap().to_path_buf(), files))
+}
+
+/// Files of `model_root` and of its sub-directories, relative to `model_root`
+fn list_files(model_root: &Path) -> Result<BTreeSet<String>> {
+    let mut files = BTreeSet::new();
+    for entry in fs::read_dir(model_root)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        if path.is_dir() {
+            for entry in fs::read_dir(&path)? {
+                let file_name = entry?.file_name();
+                files.insert(format!("{name}/{}", file_name.to_string_lossy()));
+            }
+        } else if path.exists() {
+            // Broken symlinks of the Hub cache are not listed
+            files.insert(name);
+        }
+    }
+    Ok(files)
+}
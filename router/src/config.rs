@@ -0,0 +1,139 @@
+/// TOML and YAML configuration files holding the command line arguments
+use anyhow::{anyhow, Context, Result};
+use clap::{ArgMatches, Command};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// Arguments that are not written by `print_config`. They match the `#[redact]` fields of `Args`
+const SECRET_ARGS: [&str; 4] = [
+    "hf_api_token",
+    "model_registry_token",
+    "embedding_cache_redis_url",
+    "api_key",
+];
+
+/// Arguments that only make sense on the command line
+const COMMAND_LINE_ARGS: [&str; 4] = ["config", "print_config", "help", "version"];
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ConfigValue {
+    Bool(bool),
+    Integer(i64),
+    Float(f64),
+    String(String),
+    List(Vec<ConfigValue>),
+}
+
+impl ConfigValue {
+    fn to_arg(&self, key: &str) -> Result<String> {
+        match self {
+            ConfigValue::Bool(value) => Ok(value.to_string()),
+            ConfigValue::Integer(value) => Ok(value.to_string()),
+            ConfigValue::Float(value) => Ok(value.to_string()),
+            ConfigValue::String(value) => Ok(value.clone()),
+            ConfigValue::List(values) => {
+                let values = values
+                    .iter()
+                    .map(|value| match value {
+                        ConfigValue::List(_) => Err(anyhow!("`{key}` cannot hold nested lists")),
+                        value => value.to_arg(key),
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(values.join(","))
+            }
+        }
+    }
+}
+
+/// Set the environment variables of the arguments listed in the configuration file at `path`.
+/// Variables that are already set are kept, so that the command line takes precedence over the
+/// environment, which takes precedence over the file.
+/// Keys are the names of the arguments, e.g. `max_batch_tokens` or `max-batch-tokens`.
+pub fn apply_config_file(command: &Command, path: &str) -> Result<()> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Could not read configuration file `{path}`"))?;
+    let extension = Path::new(path).extension().and_then(|ext| ext.to_str());
+    let config: Result<BTreeMap<String, ConfigValue>> = match extension {
+        Some("toml") => toml::from_str(&content).map_err(anyhow::Error::from),
+        Some("yaml" | "yml") => serde_yaml::from_str(&content).map_err(anyhow::Error::from),
+        _ => Err(anyhow!("The file extension must be `.toml`, `.yaml` or `.yml`")),
+    };
+    let config = config.with_context(|| format!("Invalid configuration file `{path}`"))?;
+
+    for (key, value) in config {
+        let id = key.replace('-', "_");
+        let arg = command
+            .get_arguments()
+            .filter(|arg| !COMMAND_LINE_ARGS.contains(&arg.get_id().as_str()))
+            .find(|arg| arg.get_id() == id.as_str())
+            .ok_or_else(|| {
+                let valid_keys: Vec<&str> = command
+                    .get_arguments()
+                    .map(|arg| arg.get_id().as_str())
+                    .filter(|id| !COMMAND_LINE_ARGS.contains(id))
+                    .collect();
+                anyhow!(
+                    "Unknown key `{key}` in configuration file `{path}`. Valid keys are: {}",
+                    valid_keys.join(", ")
+                )
+            })?;
+
+        if matches!(value, ConfigValue::List(_)) && arg.get_value_delimiter().is_none() {
+            return Err(anyhow!("`{key}` takes a single value in configuration file `{path}`"));
+        }
+        let value = value.to_arg(&key)?;
+
+        let env = arg
+            .get_env()
+            .ok_or_else(|| anyhow!("`{key}` cannot be set in a configuration file"))?;
+        if std::env::var_os(env).is_none() {
+            std::env::set_var(env, value);
+        }
+    }
+    Ok(())
+}
+
+/// Effective configuration resolved from the command line, the environment, the configuration
+/// file and the defaults, as a TOML configuration file.
+/// Secrets are redacted.
+pub fn print_config(command: &Command, matches: &ArgMatches) -> String {
+    let mut config = toml::Table::new();
+    for arg in command.get_arguments() {
+        let id = arg.get_id().as_str();
+        if COMMAND_LINE_ARGS.contains(&id) {
+            continue;
+        }
+        let Some(values) = matches.get_raw(id) else {
+            continue;
+        };
+        let mut values: Vec<toml::Value> = values
+            .map(|value| {
+                let value = value.to_string_lossy();
+                if SECRET_ARGS.contains(&id) {
+                    toml::Value::String("<redacted>".to_string())
+                } else if let Ok(value) = value.parse::<bool>() {
+                    toml::Value::Boolean(value)
+                } else if let Ok(value) = value.parse::<i64>() {
+                    toml::Value::Integer(value)
+                } else if let Ok(value) = value.parse::<f64>() {
+                    toml::Value::Float(value)
+                } else {
+                    toml::Value::String(value.to_string())
+                }
+            })
+            .collect();
+
+        let value = match arg.get_value_delimiter() {
+            Some(_) => toml::Value::Array(values),
+            None => match values.pop() {
+                Some(value) => value,
+                None => continue,
+            },
+        };
+        config.insert(id.to_string(), value);
+    }
+    config.to_string()
+}
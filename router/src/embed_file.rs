@@ -0,0 +1,464 @@
+/// `embed` subcommand: embed a JSONL corpus to disk without starting a server
+use crate::{Info, ModelType};
+use anyhow::{anyhow, Context, Result};
+use arrow_array::{ArrayRef, FixedSizeListArray, Float32Array, RecordBatch, StringArray};
+use arrow_ipc::writer::{write_message, DictionaryTracker, IpcDataGenerator, IpcWriteOptions};
+use arrow_ipc::MessageHeader;
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use futures::future::try_join_all;
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use text_embeddings_core::infer::Infer;
+use text_embeddings_core::queue::Priority;
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct EmbedFileArgs {
+    /// JSONL file holding one document per line, or `-` to read the documents from stdin.
+    /// Lines are JSON objects holding the text in `--text-field`, or JSON strings
+    #[clap(long)]
+    pub input: String,
+
+    /// File the embeddings are written to, in the order of the documents.
+    /// `.arrow` and `.arrows` files are written in the Arrow IPC streaming format, with an
+    /// `embedding` column of fixed size lists. Other files are written as JSONL
+    #[clap(long)]
+    pub output: PathBuf,
+
+    /// Field of the documents holding the text to embed
+    #[clap(default_value = "text", long)]
+    pub text_field: String,
+
+    /// Field of the documents copied to the `id` column of the output
+    #[clap(long)]
+    pub id_field: Option<String>,
+
+    /// Truncate the documents longer than the maximum input length of the model instead of
+    /// failing
+    #[clap(long)]
+    pub truncate: bool,
+
+    #[clap(default_value = "true", long, action = clap::ArgAction::Set)]
+    pub normalize: bool,
+
+    /// Truncate the embeddings to their first `dimensions` values, for Matryoshka models
+    #[clap(long)]
+    pub dimensions: Option<usize>,
+
+    /// Number of documents embedded and written to the output at once
+    #[clap(default_value = "1024", long)]
+    pub chunk_size: usize,
+
+    /// Continue an interrupted run: the documents already written to `--output` are skipped
+    #[clap(long)]
+    pub resume: bool,
+}
+
+/// Embed the documents of `args.input` and write their embeddings to `args.output`
+pub(crate) async fn embed_file(infer: &Infer, info: &Info, args: EmbedFileArgs) -> Result<()> {
+    if !matches!(info.model_type, ModelType::Embedding(_)) {
+        return Err(anyhow!("`embed` requires an embedding model"));
+    }
+    if args.chunk_size == 0 {
+        return Err(anyhow!("`--chunk-size` must be > 0"));
+    }
+    if args.output.exists() && !args.resume {
+        return Err(anyhow!(
+            "`{}` already exists. Use `--resume` to continue embedding it, or remove it",
+            args.output.display()
+        ));
+    }
+
+    let with_ids = args.id_field.is_some();
+    let arrow = matches!(
+        args.output.extension().and_then(|ext| ext.to_str()),
+        Some("arrow" | "arrows")
+    );
+    let mut output = if arrow {
+        Output::Arrow(ArrowOutput::open(&args.output, with_ids)?)
+    } else {
+        Output::Json(JsonOutput::open(&args.output, with_ids)?)
+    };
+    let skip = output.rows();
+    if skip > 0 {
+        tracing::info!("Resuming after the {skip} documents of {}", args.output.display());
+    }
+
+    let (mut reader, progress): (Box<dyn BufRead>, _) = if args.input == "-" {
+        let progress = ProgressBar::new_spinner();
+        progress.enable_steady_tick(Duration::from_millis(100));
+        (Box::new(BufReader::new(io::stdin())), progress)
+    } else {
+        let file = File::open(&args.input)
+            .with_context(|| format!("Could not open `{}`", args.input))?;
+        let progress = ProgressBar::new(file.metadata()?.len()).with_style(
+            ProgressStyle::with_template(
+                "{bar:40} {bytes}/{total_bytes} {msg} [{elapsed_precise}<{eta_precise}]",
+            )?,
+        );
+        (Box::new(BufReader::new(file)), progress)
+    };
+
+    let mut line = String::new();
+    let mut line_number = 0;
+    let mut documents = 0;
+    let mut end = false;
+    while !end {
+        let first_line = line_number + 1;
+        let mut texts = Vec::with_capacity(args.chunk_size);
+        let mut ids = Vec::with_capacity(args.chunk_size);
+        while texts.len() < args.chunk_size {
+            line.clear();
+            let read = reader.read_line(&mut line)?;
+            if read == 0 {
+                end = true;
+                break;
+            }
+            line_number += 1;
+            progress.inc(read as u64);
+
+            // Blank lines are not documents
+            if line.trim().is_empty() {
+                continue;
+            }
+            documents += 1;
+            if documents <= skip {
+                continue;
+            }
+            let (text, id) = parse_document(&line, &args.text_field, args.id_field.as_deref())
+                .with_context(|| format!("Invalid document on line {line_number}"))?;
+            texts.push(text);
+            ids.push(id);
+        }
+        if texts.is_empty() {
+            continue;
+        }
+
+        let (truncate, normalize, dimensions) = (args.truncate, args.normalize, args.dimensions);
+        let embeddings = try_join_all(texts.into_iter().map(|text| async move {
+            let permit = infer.acquire_permit().await;
+            let response = infer
+                .embed_pooled(
+                    text,
+                    truncate,
+                    None,
+                    normalize,
+                    dimensions,
+                    None,
+                    Priority::Normal,
+                    permit,
+                )
+                .await?;
+            Ok::<_, anyhow::Error>(response.results)
+        }))
+        .await
+        .with_context(|| {
+            format!("Could not embed the documents of lines {first_line}-{line_number}")
+        })?;
+
+        output.write(ids, embeddings)?;
+        progress.set_message(format!("{documents} documents"));
+    }
+
+    output.finish()?;
+    progress.finish();
+    tracing::info!("Wrote the embeddings of {documents} documents to {}", args.output.display());
+    Ok(())
+}
+
+/// Text and id of a JSONL line
+fn parse_document(
+    line: &str,
+    text_field: &str,
+    id_field: Option<&str>,
+) -> Result<(String, Option<String>)> {
+    let document: serde_json::Value = serde_json::from_str(line)?;
+    if let serde_json::Value::String(text) = document {
+        return Ok((text, None));
+    }
+    let text = document
+        .get(text_field)
+        .and_then(|text| text.as_str())
+        .ok_or_else(|| anyhow!("`{text_field}` is not a string field of the document"))?;
+    let id = id_field
+        .and_then(|field| document.get(field))
+        .map(|id| match id {
+            serde_json::Value::String(id) => id.clone(),
+            id => id.to_string(),
+        });
+    Ok((text.to_string(), id))
+}
+
+enum Output {
+    Json(JsonOutput),
+    Arrow(ArrowOutput),
+}
+
+impl Output {
+    /// Number of documents already written
+    fn rows(&self) -> u64 {
+        match self {
+            Output::Json(output) => output.rows,
+            Output::Arrow(output) => output.rows,
+        }
+    }
+
+    /// Write the embeddings of a chunk of documents and flush them to disk
+    fn write(&mut self, ids: Vec<Option<String>>, embeddings: Vec<Vec<f32>>) -> Result<()> {
+        match self {
+            Output::Json(output) => output.write(ids, embeddings),
+            Output::Arrow(output) => output.write(ids, embeddings),
+        }
+    }
+
+    fn finish(self) -> Result<()> {
+        match self {
+            Output::Json(mut output) => Ok(output.writer.flush()?),
+            Output::Arrow(output) => output.finish(),
+        }
+    }
+}
+
+struct JsonOutput {
+    writer: BufWriter<File>,
+    with_ids: bool,
+    rows: u64,
+}
+
+#[derive(Serialize)]
+struct JsonRow<'a> {
+    /// `Some(None)` is written as `null` when the document has no id
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<Option<String>>,
+    embedding: &'a [f32],
+}
+
+impl JsonOutput {
+    /// Open `path` for appending, dropping the incomplete last line of an interrupted run
+    fn open(path: &Path, with_ids: bool) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)
+            .with_context(|| format!("Could not open `{}`", path.display()))?;
+
+        let mut rows = 0;
+        let mut end = 0;
+        let mut offset = 0;
+        let mut buffer = [0; 64 * 1024];
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            for (i, byte) in buffer[..read].iter().enumerate() {
+                if *byte == b'\n' {
+                    rows += 1;
+                    end = offset + i as u64 + 1;
+                }
+            }
+            offset += read as u64;
+        }
+        file.set_len(end)?;
+        file.seek(SeekFrom::Start(end))?;
+
+        Ok(Self {
+            writer: BufWriter::new(file),
+            with_ids,
+            rows,
+        })
+    }
+
+    fn write(&mut self, ids: Vec<Option<String>>, embeddings: Vec<Vec<f32>>) -> Result<()> {
+        for (id, embedding) in ids.into_iter().zip(&embeddings) {
+            let row = JsonRow {
+                id: self.with_ids.then_some(id),
+                embedding,
+            };
+            serde_json::to_writer(&mut self.writer, &row)?;
+            self.writer.write_all(b"\n")?;
+        }
+        self.rows += embeddings.len() as u64;
+        Ok(self.writer.flush()?)
+    }
+}
+
+/// Arrow IPC stream written message by message, so that the stream of an interrupted run can be
+/// continued after its last complete record batch
+struct ArrowOutput {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    with_ids: bool,
+    /// Written with the first record batch, once the embedding dimension is known
+    schema: Option<SchemaRef>,
+    options: IpcWriteOptions,
+    rows: u64,
+}
+
+impl ArrowOutput {
+    fn open(path: &Path, with_ids: bool) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)
+            .with_context(|| format!("Could not open `{}`", path.display()))?;
+
+        let (schema, end, rows) = read_stream(&mut file)
+            .with_context(|| format!("`{}` is not an Arrow IPC stream", path.display()))?;
+        if let Some(schema) = &schema {
+            if schema.field_with_name("id").is_ok() != with_ids {
+                return Err(anyhow!(
+                    "`{}` was written with a different `--id-field`",
+                    path.display()
+                ));
+            }
+        }
+        // The end of stream marker is written again by `finish`
+        file.set_len(end)?;
+        file.seek(SeekFrom::Start(end))?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            writer: BufWriter::new(file),
+            with_ids,
+            schema,
+            options: IpcWriteOptions::default(),
+            rows,
+        })
+    }
+
+    fn write(&mut self, ids: Vec<Option<String>>, embeddings: Vec<Vec<f32>>) -> Result<()> {
+        let generator = IpcDataGenerator::default();
+        let dimension = embeddings[0].len();
+
+        let schema = match &self.schema {
+            Some(schema) => schema.clone(),
+            None => {
+                let mut fields = Vec::with_capacity(2);
+                if self.with_ids {
+                    fields.push(Field::new("id", DataType::Utf8, true));
+                }
+                fields.push(Field::new(
+                    "embedding",
+                    DataType::FixedSizeList(
+                        Arc::new(Field::new("item", DataType::Float32, false)),
+                        dimension as i32,
+                    ),
+                    false,
+                ));
+                let schema = Arc::new(Schema::new(fields));
+                let encoded = generator.schema_to_bytes(&schema, &self.options);
+                write_message(&mut self.writer, encoded, &self.options)?;
+                self.schema = Some(schema.clone());
+                schema
+            }
+        };
+
+        let (field, expected_dimension) = match schema.field_with_name("embedding")?.data_type() {
+            DataType::FixedSizeList(field, size) => (field.clone(), *size as usize),
+            data_type => return Err(anyhow!("Unexpected `embedding` column of type {data_type}")),
+        };
+        if embeddings.iter().any(|embedding| embedding.len() != expected_dimension) {
+            return Err(anyhow!(
+                "`{}` holds embeddings of {expected_dimension} dimensions but the model returned \
+                 {dimension}",
+                self.path.display()
+            ));
+        }
+
+        let rows = embeddings.len();
+        let values = Float32Array::from_iter_values(embeddings.into_iter().flatten());
+        let mut columns: Vec<ArrayRef> = Vec::with_capacity(2);
+        if self.with_ids {
+            columns.push(Arc::new(StringArray::from(ids)));
+        }
+        columns.push(Arc::new(FixedSizeListArray::try_new(
+            field,
+            expected_dimension as i32,
+            Arc::new(values),
+            None,
+        )?));
+        let batch = RecordBatch::try_new(schema, columns)?;
+
+        let mut dictionary_tracker = DictionaryTracker::new(false);
+        let (_, encoded) =
+            generator.encoded_batch(&batch, &mut dictionary_tracker, &self.options)?;
+        write_message(&mut self.writer, encoded, &self.options)?;
+        self.rows += rows as u64;
+        Ok(self.writer.flush()?)
+    }
+
+    fn finish(mut self) -> Result<()> {
+        if self.schema.is_some() {
+            // End of stream marker
+            self.writer.write_all(&[0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0])?;
+        }
+        Ok(self.writer.flush()?)
+    }
+}
+
+/// Schema of the Arrow IPC stream of `file`, offset of the end of its last complete message and
+/// number of rows of its record batches
+fn read_stream(file: &mut File) -> Result<(Option<SchemaRef>, u64, u64)> {
+    let mut reader = BufReader::new(file);
+    let mut schema = None;
+    let mut end = 0;
+    let mut rows = 0;
+    let mut offset = 0;
+
+    loop {
+        let mut length = [0; 4];
+        if reader.read_exact(&mut length).is_err() {
+            break;
+        }
+        offset += 4;
+        // Messages start with a continuation marker since Arrow 0.15
+        if length == [0xff; 4] {
+            if reader.read_exact(&mut length).is_err() {
+                break;
+            }
+            offset += 4;
+        }
+        let length = i32::from_le_bytes(length);
+        if length <= 0 {
+            // End of stream
+            break;
+        }
+
+        let mut metadata = vec![0; length as usize];
+        if reader.read_exact(&mut metadata).is_err() {
+            break;
+        }
+        let message = arrow_ipc::root_as_message(&metadata)
+            .map_err(|err| anyhow!("Invalid message: {err}"))?;
+        let body_length = message.bodyLength() as u64;
+        if io::copy(&mut (&mut reader).take(body_length), &mut io::sink())? < body_length {
+            break;
+        }
+        offset += length as u64 + body_length;
+
+        match message.header_type() {
+            MessageHeader::Schema => {
+                let message_schema = message
+                    .header_as_schema()
+                    .ok_or_else(|| anyhow!("Invalid schema message"))?;
+                schema = Some(Arc::new(arrow_ipc::convert::fb_to_schema(message_schema)));
+            }
+            MessageHeader::RecordBatch => {
+                let batch = message
+                    .header_as_record_batch()
+                    .ok_or_else(|| anyhow!("Invalid record batch message"))?;
+                rows += batch.length() as u64;
+            }
+            header => return Err(anyhow!("Unexpected {header:?} message")),
+        }
+        end = offset;
+    }
+    Ok((schema, end, rows))
+}
@@ -0,0 +1,94 @@
+/// GPU telemetry exported with the Prometheus metrics and used by the health routes
+use anyhow::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Interval between two reads of the GPU counters
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Reads the memory, utilization, clocks and temperature of every GPU in the background.
+/// The devices are labelled with their NVML index, which does not take `CUDA_VISIBLE_DEVICES`
+/// into account.
+#[derive(Debug, Clone)]
+pub(crate) struct GpuMonitor {
+    low_memory: Arc<AtomicBool>,
+}
+
+impl GpuMonitor {
+    /// Start the monitor if NVML is available.
+    /// The GPUs are reported as unhealthy when one of them has less than `min_free_memory`
+    /// bytes free.
+    pub(crate) fn start(min_free_memory: Option<u64>) -> Result<Option<Self>> {
+        #[cfg(feature = "nvml")]
+        {
+            let nvml = match nvml_wrapper::Nvml::init() {
+                Ok(nvml) => nvml,
+                Err(err) => {
+                    if min_free_memory.is_some() {
+                        anyhow::bail!("`--min-free-gpu-memory` requires NVML: {err}");
+                    }
+                    tracing::warn!("GPU metrics are disabled: could not initialize NVML: {err}");
+                    return Ok(None);
+                }
+            };
+
+            let monitor = Self {
+                low_memory: Arc::new(AtomicBool::new(false)),
+            };
+            let low_memory = monitor.low_memory.clone();
+            std::thread::spawn(move || loop {
+                match poll(&nvml, min_free_memory) {
+                    Ok(low) => low_memory.store(low, Ordering::Relaxed),
+                    Err(err) => tracing::warn!("Could not read the GPU metrics: {err}"),
+                }
+                std::thread::sleep(POLL_INTERVAL);
+            });
+            Ok(Some(monitor))
+        }
+        #[cfg(not(feature = "nvml"))]
+        {
+            if min_free_memory.is_some() {
+                anyhow::bail!("`--min-free-gpu-memory` is only supported on CUDA builds");
+            }
+            Ok(None)
+        }
+    }
+
+    /// `false` if a GPU had less free memory than the threshold at the last read
+    pub(crate) fn is_healthy(&self) -> bool {
+        !self.low_memory.load(Ordering::Relaxed)
+    }
+}
+
+/// Export the counters of every device.
+/// Returns `true` if one of them has less than `min_free_memory` bytes free.
+#[cfg(feature = "nvml")]
+fn poll(
+    nvml: &nvml_wrapper::Nvml,
+    min_free_memory: Option<u64>,
+) -> Result<bool, nvml_wrapper::error::NvmlError> {
+    use nvml_wrapper::enum_wrappers::device::{Clock, TemperatureSensor};
+
+    let mut low_memory = false;
+    for index in 0..nvml.device_count()? {
+        let device = nvml.device_by_index(index)?;
+        let label = index.to_string();
+
+        let memory = device.memory_info()?;
+        metrics::gauge!("te_gpu_memory_used_bytes", memory.used as f64, "device" => label.clone());
+        metrics::gauge!("te_gpu_memory_free_bytes", memory.free as f64, "device" => label.clone());
+        if min_free_memory.map_or(false, |min| memory.free < min) {
+            tracing::warn!("GPU {index} has only {} MB of free memory", memory.free >> 20);
+            low_memory = true;
+        }
+
+        let utilization = device.utilization_rates()?;
+        metrics::gauge!("te_gpu_utilization", utilization.gpu as f64, "device" => label.clone());
+        let sm_clock = device.clock_info(Clock::SM)?;
+        metrics::gauge!("te_gpu_sm_clock_mhz", sm_clock as f64, "device" => label.clone());
+        let temperature = device.temperature(TemperatureSensor::Gpu)?;
+        metrics::gauge!("te_gpu_temperature_celsius", temperature as f64, "device" => label);
+    }
+    Ok(low_memory)
+}
@@ -1,28 +1,38 @@
 use crate::grpc::pb::tei::v1::{
-    EmbedAllRequest, EmbedAllResponse, EncodeRequest, EncodeResponse, RerankStreamRequest,
-    SimpleToken, TokenEmbedding,
+    DecodeRequest, DecodeResponse, EmbedAllRequest, EmbedAllResponse, EmbedSparseRequest,
+    EmbedSparseResponse, EncodeRequest, EncodeResponse, RerankStreamRequest, SimpleToken,
+    SparseValue, TokenCountResponse, TokenEmbedding,
 };
 use crate::grpc::{
     EmbedRequest, EmbedResponse, InfoRequest, InfoResponse, PredictRequest, PredictResponse,
     Prediction, Rank, RerankRequest, RerankResponse,
 };
+use crate::auth::ApiKeys;
+use crate::tls::TlsConfig;
+use crate::usage::UsageCounters;
 use crate::ResponseMetadata;
-use crate::{grpc, shutdown, ErrorResponse, ErrorType, Info, ModelType};
+use crate::{grpc, shutdown, ErrorResponse, ErrorType, Info, ModelType, Models};
 use futures::future::join_all;
 use metrics_exporter_prometheus::PrometheusBuilder;
+use opentelemetry::global;
+use opentelemetry::propagation::Extractor;
 use std::net::SocketAddr;
 use std::time::{Duration, Instant};
 use text_embeddings_core::infer::Infer;
+use text_embeddings_core::queue::Priority;
+use text_embeddings_core::tokenization::TruncationDirection;
+use tokio::net::UnixListener;
 use tokio::sync::{mpsc, oneshot, OwnedSemaphorePermit};
-use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::wrappers::{UnboundedReceiverStream, UnixListenerStream};
 use tokio_stream::StreamExt;
-use tonic::codegen::http::HeaderMap;
+use tonic::codegen::http::{self, HeaderMap};
 use tonic::metadata::MetadataMap;
 use tonic::server::NamedService;
 use tonic::transport::Server;
 use tonic::{Code, Extensions, Request, Response, Status, Streaming};
 use tonic_health::ServingStatus;
 use tracing::{instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 
 impl From<&ResponseMetadata> for grpc::Metadata {
     fn from(value: &ResponseMetadata) -> Self {
@@ -37,26 +47,71 @@ impl From<&ResponseMetadata> for grpc::Metadata {
     }
 }
 
-#[derive(Debug, Clone)]
+/// API key of a call, set once it is authorized by the interceptor
+#[derive(Clone)]
+struct ApiKey(String);
+
+/// Tenant of a call in the usage counters, set by the interceptor
+#[derive(Clone)]
+struct Tenant(String);
+
+/// Counts the prompt tokens of a call towards the quotas of its API key and the usage of its
+/// tenant
+#[derive(Clone)]
+struct TokenRecorder {
+    api_key: Option<(ApiKeys, String)>,
+    usage: Option<(UsageCounters, String)>,
+}
+
+impl TokenRecorder {
+    /// Count a successful request, or message of a stream, with `tokens` prompt tokens
+    fn record(&self, tokens: usize) {
+        if let Some((api_keys, key)) = &self.api_key {
+            api_keys.record_tokens(key, tokens as u64);
+        }
+        if let Some((usage, tenant)) = &self.usage {
+            usage.record(tenant.clone(), tokens as u64);
+        }
+    }
+}
+
+#[derive(Clone)]
 struct TextEmbeddingsService {
-    infer: Infer,
-    info: Info,
+    models: Models,
+    api_keys: Option<ApiKeys>,
+    usage: UsageCounters,
     max_parallel_stream_requests: usize,
 }
 
 impl TextEmbeddingsService {
-    fn new(infer: Infer, info: Info) -> Self {
+    fn new(models: Models, api_keys: Option<ApiKeys>, usage: UsageCounters) -> Self {
         let max_parallel_stream_requests = std::env::var("GRPC_MAX_PARALLEL_STREAM_REQUESTS")
             .ok()
             .and_then(|s| s.parse::<usize>().ok())
             .unwrap_or(1024);
         Self {
-            infer,
-            info,
+            models,
+            api_keys,
+            usage,
             max_parallel_stream_requests,
         }
     }
 
+    /// Counts the prompt tokens of the messages of `request`. Streams count every message
+    fn token_recorder<T>(&self, request: &Request<T>) -> TokenRecorder {
+        let key = request.extensions().get::<ApiKey>();
+        let tenant = request.extensions().get::<Tenant>();
+        TokenRecorder {
+            api_key: self.api_keys.clone().zip(key.map(|key| key.0.clone())),
+            usage: tenant.map(|tenant| (self.usage.clone(), tenant.0.clone())),
+        }
+    }
+
+    /// Model serving a call. It is resolved for every call so that reloads are picked up
+    fn model(&self) -> (Infer, Info) {
+        self.models.default_model()
+    }
+
     #[instrument(
         skip_all,
         fields(
@@ -70,6 +125,7 @@ impl TextEmbeddingsService {
     )]
     async fn embed_pooled_inner(
         &self,
+        infer: &Infer,
         request: EmbedRequest,
         permit: OwnedSemaphorePermit,
     ) -> Result<(EmbedResponse, ResponseMetadata), Status> {
@@ -77,9 +133,17 @@ impl TextEmbeddingsService {
         let start_time = Instant::now();
 
         let compute_chars = request.inputs.chars().count();
-        let response = self
-            .infer
-            .embed_pooled(request.inputs, request.truncate, request.normalize, permit)
+        let response = infer
+            .embed_pooled(
+                request.inputs,
+                request.truncate,
+                truncation_direction(request.truncation_direction)?,
+                request.normalize,
+                request.dimensions.map(|d| d as usize),
+                None,
+                Priority::Normal,
+                permit,
+            )
             .await
             .map_err(ErrorResponse::from)?;
 
@@ -90,7 +154,8 @@ impl TextEmbeddingsService {
             response.metadata.tokenization,
             response.metadata.queue,
             response.metadata.inference,
-        );
+        )
+        .with_batch_id(response.metadata.batch_id);
         response_metadata.record_span(&span);
         response_metadata.record_metrics();
 
@@ -100,6 +165,7 @@ impl TextEmbeddingsService {
             EmbedResponse {
                 embeddings: response.results,
                 metadata: Some(grpc::Metadata::from(&response_metadata)),
+                tokens_dropped: response.metadata.dropped_tokens as u32,
             },
             response_metadata,
         ))
@@ -118,6 +184,7 @@ impl TextEmbeddingsService {
     )]
     async fn embed_all_inner(
         &self,
+        infer: &Infer,
         request: EmbedAllRequest,
         permit: OwnedSemaphorePermit,
     ) -> Result<(EmbedAllResponse, ResponseMetadata), Status> {
@@ -125,9 +192,14 @@ impl TextEmbeddingsService {
         let start_time = Instant::now();
 
         let compute_chars = request.inputs.chars().count();
-        let response = self
-            .infer
-            .embed_all(request.inputs, request.truncate, permit)
+        let response = infer
+            .embed_all(
+                request.inputs,
+                request.truncate,
+                truncation_direction(request.truncation_direction)?,
+                Priority::Normal,
+                permit,
+            )
             .await
             .map_err(ErrorResponse::from)?;
 
@@ -138,7 +210,8 @@ impl TextEmbeddingsService {
             response.metadata.tokenization,
             response.metadata.queue,
             response.metadata.inference,
-        );
+        )
+        .with_batch_id(response.metadata.batch_id);
         response_metadata.record_span(&span);
         response_metadata.record_metrics();
 
@@ -159,6 +232,87 @@ impl TextEmbeddingsService {
         ))
     }
 
+    #[instrument(
+        skip_all,
+        fields(
+            compute_chars,
+            compute_tokens,
+            total_time,
+            tokenization_time,
+            queue_time,
+            inference_time,
+        )
+    )]
+    async fn embed_sparse_inner(
+        &self,
+        infer: &Infer,
+        info: &Info,
+        request: EmbedSparseRequest,
+        permit: OwnedSemaphorePermit,
+    ) -> Result<(EmbedSparseResponse, ResponseMetadata), Status> {
+        let span = Span::current();
+        let start_time = Instant::now();
+
+        match &info.model_type {
+            ModelType::Embedding(embedding) if embedding.pooling == "splade" => Ok(()),
+            _ => {
+                metrics::increment_counter!("te_request_failure", "err" => "model_type");
+                let message = "model does not use SPLADE pooling".to_string();
+                tracing::error!("{message}");
+                Err(Status::new(Code::FailedPrecondition, message))
+            }
+        }?;
+
+        let compute_chars = request.inputs.chars().count();
+        let response = infer
+            .embed_pooled(
+                request.inputs,
+                request.truncate,
+                truncation_direction(request.truncation_direction)?,
+                false,
+                None,
+                None,
+                Priority::Normal,
+                permit,
+            )
+            .await
+            .map_err(ErrorResponse::from)?;
+
+        let response_metadata = ResponseMetadata::new(
+            compute_chars,
+            response.metadata.prompt_tokens,
+            start_time,
+            response.metadata.tokenization,
+            response.metadata.queue,
+            response.metadata.inference,
+        )
+        .with_batch_id(response.metadata.batch_id);
+        response_metadata.record_span(&span);
+        response_metadata.record_metrics();
+
+        tracing::info!("Success");
+
+        // Keep the non-zero values
+        let sparse_embeddings = response
+            .results
+            .into_iter()
+            .enumerate()
+            .filter(|(_, value)| *value != 0.0)
+            .map(|(index, value)| SparseValue {
+                index: index as u32,
+                value,
+            })
+            .collect();
+
+        Ok((
+            EmbedSparseResponse {
+                sparse_embeddings,
+                metadata: Some(grpc::Metadata::from(&response_metadata)),
+            },
+            response_metadata,
+        ))
+    }
+
     #[instrument(
         skip_all,
         fields(
@@ -172,6 +326,8 @@ impl TextEmbeddingsService {
     )]
     async fn predict_inner(
         &self,
+        infer: &Infer,
+        info: &Info,
         request: PredictRequest,
         permit: OwnedSemaphorePermit,
     ) -> Result<(PredictResponse, ResponseMetadata), Status> {
@@ -179,13 +335,20 @@ impl TextEmbeddingsService {
         let start_time = Instant::now();
 
         let compute_chars = request.inputs.chars().count();
-        let response = self
-            .infer
-            .predict(request.inputs, request.truncate, request.raw_scores, permit)
+        let response = infer
+            .predict(
+                request.inputs,
+                request.truncate,
+                truncation_direction(request.truncation_direction)?,
+                request.raw_scores,
+                None,
+                Priority::Normal,
+                permit,
+            )
             .await
             .map_err(ErrorResponse::from)?;
 
-        let id2label = match &self.info.model_type {
+        let id2label = match &info.model_type {
             ModelType::Classifier(classifier) => &classifier.id2label,
             ModelType::Reranker(classifier) => &classifier.id2label,
             _ => panic!(),
@@ -198,7 +361,8 @@ impl TextEmbeddingsService {
             response.metadata.tokenization,
             response.metadata.queue,
             response.metadata.inference,
-        );
+        )
+        .with_batch_id(response.metadata.batch_id);
 
         let mut predictions = Vec::with_capacity(response.results.len());
         for (i, s) in response.results.into_iter().enumerate() {
@@ -234,10 +398,13 @@ impl TextEmbeddingsService {
     }
 
     #[instrument(skip_all)]
-    async fn tokenize_inner(&self, request: EncodeRequest) -> Result<EncodeResponse, Status> {
+    async fn tokenize_inner(
+        &self,
+        infer: &Infer,
+        request: EncodeRequest,
+    ) -> Result<EncodeResponse, Status> {
         let inputs = request.inputs;
-        let encoding = self
-            .infer
+        let encoding = infer
             .tokenize(inputs.clone(), request.add_special_tokens)
             .await
             .map_err(ErrorResponse::from)?;
@@ -277,26 +444,28 @@ impl TextEmbeddingsService {
 #[tonic::async_trait]
 impl grpc::info_server::Info for TextEmbeddingsService {
     async fn info(&self, _request: Request<InfoRequest>) -> Result<Response<InfoResponse>, Status> {
-        let model_type = match self.info.model_type {
+        let (_, info) = self.model();
+        let model_type = match info.model_type {
             ModelType::Classifier(_) => grpc::ModelType::Classifier,
             ModelType::Embedding(_) => grpc::ModelType::Embedding,
             ModelType::Reranker(_) => grpc::ModelType::Reranker,
         };
 
         Ok(Response::new(InfoResponse {
-            version: self.info.version.to_string(),
-            sha: self.info.sha.map(|s| s.to_string()),
-            docker_label: self.info.docker_label.map(|s| s.to_string()),
-            model_id: self.info.model_id.clone(),
-            model_sha: self.info.model_sha.clone(),
-            model_dtype: self.info.model_dtype.clone(),
+            version: info.version.to_string(),
+            sha: info.sha.map(|s| s.to_string()),
+            docker_label: info.docker_label.map(|s| s.to_string()),
+            model_id: info.model_id.clone(),
+            model_sha: info.model_sha.clone(),
+            model_dtype: info.model_dtype.clone(),
             model_type: model_type.into(),
-            max_concurrent_requests: self.info.max_concurrent_requests as u32,
-            max_input_length: self.info.max_input_length as u32,
-            max_batch_tokens: self.info.max_batch_tokens as u32,
-            max_batch_requests: self.info.max_batch_requests.map(|v| v as u32),
-            max_client_batch_size: self.info.max_client_batch_size as u32,
-            tokenization_workers: self.info.tokenization_workers as u32,
+            max_concurrent_requests: info.max_concurrent_requests as u32,
+            max_input_length: info.max_input_length as u32,
+            max_batch_tokens: info.max_batch_tokens as u32,
+            max_batch_requests: info.max_batch_requests.map(|v| v as u32),
+            max_client_batch_size: info.max_client_batch_size as u32,
+            tokenization_workers: info.tokenization_workers as u32,
+            embedding_dimension: info.metadata.embedding_dimension.map(|v| v as u32),
         }))
     }
 }
@@ -310,13 +479,13 @@ impl grpc::embed_server::Embed for TextEmbeddingsService {
     ) -> Result<Response<EmbedResponse>, Status> {
         metrics::increment_counter!("te_request_count", "method" => "single");
 
-        let permit = self
-            .infer
-            .try_acquire_permit()
-            .map_err(ErrorResponse::from)?;
+        let (infer, _) = self.model();
+        let permit = infer.try_acquire_permit().map_err(ErrorResponse::from)?;
 
+        let tokens = self.token_recorder(&request);
         let request = request.into_inner();
-        let (response, metadata) = self.embed_pooled_inner(request, permit).await?;
+        let (response, metadata) = self.embed_pooled_inner(&infer, request, permit).await?;
+        tokens.record(metadata.compute_tokens);
         let headers = HeaderMap::from(metadata);
 
         metrics::increment_counter!("te_request_success", "method" => "single");
@@ -335,6 +504,7 @@ impl grpc::embed_server::Embed for TextEmbeddingsService {
         &self,
         request: Request<Streaming<EmbedRequest>>,
     ) -> Result<Response<Self::EmbedStreamStream>, Status> {
+        let tokens = self.token_recorder(&request);
         let mut request_stream = request.into_inner();
 
         // Create bounded channel to have an upper bound of spawned tasks
@@ -351,17 +521,22 @@ impl grpc::embed_server::Embed for TextEmbeddingsService {
         tokio::spawn(async move {
             while let Some((request, mut sender)) = embed_receiver.recv().await {
                 // Wait on permit before spawning the task to avoid creating more tasks than needed
-                let permit = local.infer.acquire_permit().await;
+                let (infer, _) = local.model();
+                let permit = infer.acquire_permit().await;
 
                 // Required for the async move below
                 let task_local = local.clone();
+                let task_tokens = tokens.clone();
 
                 // Create async task for this specific input
                 tokio::spawn(async move {
                     // Select on closed to cancel work if the stream was closed
                     tokio::select! {
-                    response = task_local.embed_pooled_inner(request, permit) => {
-                        let _ = sender.send(response.map(|(r, _m)| r));
+                    response = task_local.embed_pooled_inner(&infer, request, permit) => {
+                        let _ = sender.send(response.map(|(r, m)| {
+                            task_tokens.record(m.compute_tokens);
+                            r
+                        }));
                     }
                     _ = sender.closed() => {}
                     }
@@ -423,13 +598,13 @@ impl grpc::embed_server::Embed for TextEmbeddingsService {
     ) -> Result<Response<EmbedAllResponse>, Status> {
         metrics::increment_counter!("te_request_count", "method" => "single");
 
-        let permit = self
-            .infer
-            .try_acquire_permit()
-            .map_err(ErrorResponse::from)?;
+        let (infer, _) = self.model();
+        let permit = infer.try_acquire_permit().map_err(ErrorResponse::from)?;
 
+        let tokens = self.token_recorder(&request);
         let request = request.into_inner();
-        let (response, metadata) = self.embed_all_inner(request, permit).await?;
+        let (response, metadata) = self.embed_all_inner(&infer, request, permit).await?;
+        tokens.record(metadata.compute_tokens);
         let headers = HeaderMap::from(metadata);
 
         metrics::increment_counter!("te_request_success", "method" => "single");
@@ -448,6 +623,7 @@ impl grpc::embed_server::Embed for TextEmbeddingsService {
         &self,
         request: Request<Streaming<EmbedAllRequest>>,
     ) -> Result<Response<Self::EmbedAllStreamStream>, Status> {
+        let tokens = self.token_recorder(&request);
         let mut request_stream = request.into_inner();
 
         // Create bounded channel to have an upper bound of spawned tasks
@@ -464,17 +640,141 @@ impl grpc::embed_server::Embed for TextEmbeddingsService {
         tokio::spawn(async move {
             while let Some((request, mut sender)) = embed_receiver.recv().await {
                 // Wait on permit before spawning the task to avoid creating more tasks than needed
-                let permit = local.infer.acquire_permit().await;
+                let (infer, _) = local.model();
+                let permit = infer.acquire_permit().await;
+
+                // Required for the async move below
+                let task_local = local.clone();
+                let task_tokens = tokens.clone();
+
+                // Create async task for this specific input
+                tokio::spawn(async move {
+                    // Select on closed to cancel work if the stream was closed
+                    tokio::select! {
+                    response = task_local.embed_all_inner(&infer, request, permit) => {
+                        let _ = sender.send(response.map(|(r, m)| {
+                            task_tokens.record(m.compute_tokens);
+                            r
+                        }));
+                    }
+                    _ = sender.closed() => {}
+                    }
+                });
+            }
+        });
+
+        // Intermediate channels
+        // Required to keep the order of the requests
+        let (intermediate_sender, mut intermediate_receiver) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            // Iterate on input
+            while let Some(request) = request_stream.next().await {
+                // Create return channel
+                let (result_sender, result_receiver) = oneshot::channel();
+                // Push to intermediate channel and preserve ordering
+                intermediate_sender
+                    .send(result_receiver)
+                    .expect("`intermediate_receiver` was dropped. This is a bug.");
+
+                match request {
+                    Ok(request) => embed_sender
+                        .send((request, result_sender))
+                        .await
+                        .expect("`embed_receiver` was dropped. This is a bug."),
+                    Err(status) => {
+                        // Request is malformed
+                        let _ = result_sender.send(Err(status));
+                    }
+                };
+            }
+        });
+
+        // Final channel for the outputs
+        let (response_sender, response_receiver) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            while let Some(result_receiver) = intermediate_receiver.recv().await {
+                // Select on closed to cancel work if the stream was closed
+                tokio::select! {
+                response = result_receiver => {
+                    let _ = response_sender.send(response.expect("`result_sender` was dropped. This is a bug."));
+                }
+                _ = response_sender.closed() => {}
+                }
+            }
+        });
+
+        Ok(Response::new(UnboundedReceiverStream::new(
+            response_receiver,
+        )))
+    }
+
+    #[instrument(skip_all)]
+    async fn embed_sparse(
+        &self,
+        request: Request<EmbedSparseRequest>,
+    ) -> Result<Response<EmbedSparseResponse>, Status> {
+        metrics::increment_counter!("te_request_count", "method" => "single");
+
+        let (infer, info) = self.model();
+        let permit = infer.try_acquire_permit().map_err(ErrorResponse::from)?;
+
+        let tokens = self.token_recorder(&request);
+        let request = request.into_inner();
+        let (response, metadata) = self.embed_sparse_inner(&infer, &info, request, permit).await?;
+        tokens.record(metadata.compute_tokens);
+        let headers = HeaderMap::from(metadata);
+
+        metrics::increment_counter!("te_request_success", "method" => "single");
+
+        Ok(Response::from_parts(
+            MetadataMap::from_headers(headers),
+            response,
+            Extensions::default(),
+        ))
+    }
+
+    type EmbedSparseStreamStream = UnboundedReceiverStream<Result<EmbedSparseResponse, Status>>;
+
+    #[instrument(skip_all)]
+    async fn embed_sparse_stream(
+        &self,
+        request: Request<Streaming<EmbedSparseRequest>>,
+    ) -> Result<Response<Self::EmbedSparseStreamStream>, Status> {
+        let tokens = self.token_recorder(&request);
+        let mut request_stream = request.into_inner();
+
+        // Create bounded channel to have an upper bound of spawned tasks
+        // We will have at most `max_parallel_stream_requests` messages from this stream in the queue
+        let (embed_sender, mut embed_receiver) = mpsc::channel::<(
+            EmbedSparseRequest,
+            oneshot::Sender<Result<EmbedSparseResponse, Status>>,
+        )>(self.max_parallel_stream_requests);
+
+        // Required for the async move below
+        let local = self.clone();
+
+        // Background task that uses the bounded channel
+        tokio::spawn(async move {
+            while let Some((request, mut sender)) = embed_receiver.recv().await {
+                // Wait on permit before spawning the task to avoid creating more tasks than needed
+                let (infer, info) = local.model();
+                let permit = infer.acquire_permit().await;
 
                 // Required for the async move below
                 let task_local = local.clone();
+                let task_tokens = tokens.clone();
 
                 // Create async task for this specific input
                 tokio::spawn(async move {
                     // Select on closed to cancel work if the stream was closed
                     tokio::select! {
-                    response = task_local.embed_all_inner(request, permit) => {
-                        let _ = sender.send(response.map(|(r, _m)| r));
+                    response = task_local.embed_sparse_inner(&infer, &info, request, permit) => {
+                        let _ = sender.send(response.map(|(r, m)| {
+                            task_tokens.record(m.compute_tokens);
+                            r
+                        }));
                     }
                     _ = sender.closed() => {}
                     }
@@ -539,13 +839,13 @@ impl grpc::predict_server::Predict for TextEmbeddingsService {
     ) -> Result<Response<PredictResponse>, Status> {
         metrics::increment_counter!("te_request_count", "method" => "single");
 
-        let permit = self
-            .infer
-            .try_acquire_permit()
-            .map_err(ErrorResponse::from)?;
+        let (infer, info) = self.model();
+        let permit = infer.try_acquire_permit().map_err(ErrorResponse::from)?;
 
+        let tokens = self.token_recorder(&request);
         let request = request.into_inner();
-        let (response, metadata) = self.predict_inner(request, permit).await?;
+        let (response, metadata) = self.predict_inner(&infer, &info, request, permit).await?;
+        tokens.record(metadata.compute_tokens);
         let headers = HeaderMap::from(metadata);
 
         metrics::increment_counter!("te_request_success", "method" => "single");
@@ -564,6 +864,7 @@ impl grpc::predict_server::Predict for TextEmbeddingsService {
         &self,
         request: Request<Streaming<PredictRequest>>,
     ) -> Result<Response<Self::PredictStreamStream>, Status> {
+        let tokens = self.token_recorder(&request);
         let mut request_stream = request.into_inner();
 
         // Create bounded channel to have an upper bound of spawned tasks
@@ -580,17 +881,22 @@ impl grpc::predict_server::Predict for TextEmbeddingsService {
         tokio::spawn(async move {
             while let Some((request, mut sender)) = predict_receiver.recv().await {
                 // Wait on permit before spawning the task to avoid creating more tasks than needed
-                let permit = local.infer.acquire_permit().await;
+                let (infer, info) = local.model();
+                let permit = infer.acquire_permit().await;
 
                 // Required for the async move below
                 let task_local = local.clone();
+                let task_tokens = tokens.clone();
 
                 // Create async task for this specific input
                 tokio::spawn(async move {
                     // Select on closed to cancel work if the stream was closed
                     tokio::select! {
-                    response = task_local.predict_inner(request, permit) => {
-                        let _ = sender.send(response.map(|(r, _m)| r));
+                    response = task_local.predict_inner(&infer, &info, request, permit) => {
+                        let _ = sender.send(response.map(|(r, m)| {
+                            task_tokens.record(m.compute_tokens);
+                            r
+                        }));
                     }
                     _ = sender.closed() => {}
                     }
@@ -666,7 +972,9 @@ impl grpc::rerank_server::Rerank for TextEmbeddingsService {
         let span = Span::current();
         let start_time = Instant::now();
 
+        let tokens = self.token_recorder(&request);
         let request = request.into_inner();
+        let (infer, info) = self.model();
 
         if request.texts.is_empty() {
             let message = "`texts` cannot be empty".to_string();
@@ -679,7 +987,7 @@ impl grpc::rerank_server::Rerank for TextEmbeddingsService {
             Err(err)?;
         }
 
-        match &self.info.model_type {
+        match &info.model_type {
             ModelType::Classifier(_) => {
                 metrics::increment_counter!("te_request_failure", "err" => "model_type");
                 let message = "model is not a re-ranker model".to_string();
@@ -695,6 +1003,8 @@ impl grpc::rerank_server::Rerank for TextEmbeddingsService {
             }
         }?;
 
+        let truncation_direction = truncation_direction(request.truncation_direction)?;
+
         // Closure for rerank
         let rerank_inner = move |query: String,
                                  text: String,
@@ -704,7 +1014,15 @@ impl grpc::rerank_server::Rerank for TextEmbeddingsService {
             let permit = infer.acquire_permit().await;
 
             let response = infer
-                .predict((query, text), truncate, raw_scores, permit)
+                .predict(
+                    (query, text),
+                    truncate,
+                    truncation_direction,
+                    raw_scores,
+                    None,
+                    Priority::Normal,
+                    permit,
+                )
                 .await
                 .map_err(ErrorResponse::from)?;
 
@@ -722,10 +1040,10 @@ impl grpc::rerank_server::Rerank for TextEmbeddingsService {
         metrics::increment_counter!("te_request_count", "method" => "batch");
 
         let batch_size = request.texts.len();
-        if batch_size > self.info.max_client_batch_size {
+        if batch_size > info.max_client_batch_size {
             let message = format!(
                 "batch size {batch_size} > maximum allowed batch size {}",
-                self.info.max_client_batch_size
+                info.max_client_batch_size
             );
             tracing::error!("{message}");
             let err = ErrorResponse {
@@ -742,7 +1060,7 @@ impl grpc::rerank_server::Rerank for TextEmbeddingsService {
 
         for text in &request.texts {
             total_compute_chars += text.chars().count();
-            let local_infer = self.infer.clone();
+            let local_infer = infer.clone();
             futures.push(rerank_inner(
                 request.query.clone(),
                 text.clone(),
@@ -793,6 +1111,7 @@ impl grpc::rerank_server::Rerank for TextEmbeddingsService {
         ranks.sort_by(|x, y| x.score.partial_cmp(&y.score).unwrap());
         ranks.reverse();
 
+        tokens.record(total_compute_tokens);
         let batch_size = batch_size as u64;
 
         metrics::increment_counter!("te_request_success", "method" => "batch");
@@ -841,9 +1160,10 @@ impl grpc::rerank_server::Rerank for TextEmbeddingsService {
     ) -> Result<Response<RerankResponse>, Status> {
         let span = Span::current();
         let start_time = Instant::now();
+        let (infer, info) = self.model();
 
         // Check model type
-        match &self.info.model_type {
+        match &info.model_type {
             ModelType::Classifier(_) => {
                 metrics::increment_counter!("te_request_failure", "err" => "model_type");
                 let message = "model is not a re-ranker model".to_string();
@@ -864,11 +1184,20 @@ impl grpc::rerank_server::Rerank for TextEmbeddingsService {
                                  query: String,
                                  text: String,
                                  truncate: bool,
+                                 truncation_direction: Option<TruncationDirection>,
                                  raw_scores: bool,
                                  infer: Infer,
                                  permit: OwnedSemaphorePermit| async move {
             let response = infer
-                .predict((query, text.clone()), truncate, raw_scores, permit)
+                .predict(
+                    (query, text.clone()),
+                    truncate,
+                    truncation_direction,
+                    raw_scores,
+                    None,
+                    Priority::Normal,
+                    permit,
+                )
                 .await
                 .map_err(ErrorResponse::from)?;
 
@@ -887,36 +1216,45 @@ impl grpc::rerank_server::Rerank for TextEmbeddingsService {
 
         metrics::increment_counter!("te_request_count", "method" => "batch");
 
+        let tokens = self.token_recorder(&request);
         let mut request_stream = request.into_inner();
 
         // Create bounded channel to have an upper bound of spawned tasks
         // We will have at most `max_parallel_stream_requests` messages from this stream in the queue
         let (rerank_sender, mut rerank_receiver) = mpsc::channel::<(
-            (usize, String, String, bool, bool),
+            (usize, String, String, bool, Option<TruncationDirection>, bool),
             oneshot::Sender<
                 Result<(usize, usize, Duration, Duration, Duration, f32, String), ErrorResponse>,
             >,
         )>(self.max_parallel_stream_requests);
 
-        // Required for the async move below
-        let local_infer = self.infer.clone();
-
         // Background task that uses the bounded channel
         tokio::spawn(async move {
-            while let Some(((index, query, text, truncate, raw_scores), mut sender)) =
-                rerank_receiver.recv().await
+            while let Some((
+                (index, query, text, truncate, truncation_direction, raw_scores),
+                mut sender,
+            )) = rerank_receiver.recv().await
             {
                 // Wait on permit before spawning the task to avoid creating more tasks than needed
-                let permit = local_infer.acquire_permit().await;
+                let permit = infer.acquire_permit().await;
 
                 // Required for the async move below
-                let task_infer = local_infer.clone();
+                let task_infer = infer.clone();
 
                 // Create async task for this specific input
                 tokio::spawn(async move {
                     // Select on closed to cancel work if the stream was closed
                     tokio::select! {
-                    result = rerank_inner(index, query, text, truncate, raw_scores, task_infer, permit) => {
+                    result = rerank_inner(
+                        index,
+                        query,
+                        text,
+                        truncate,
+                        truncation_direction,
+                        raw_scores,
+                        task_infer,
+                        permit,
+                    ) => {
                         let _ = sender.send(result);
                     }
                     _ = sender.closed() => {}
@@ -954,6 +1292,7 @@ impl grpc::rerank_server::Rerank for TextEmbeddingsService {
 
             total_compute_chars += request.query.chars().count();
             total_compute_chars += request.text.chars().count();
+            let truncation_direction = truncation_direction(request.truncation_direction)?;
 
             rerank_sender
                 .send((
@@ -962,6 +1301,7 @@ impl grpc::rerank_server::Rerank for TextEmbeddingsService {
                         request.query,
                         request.text,
                         request.truncate,
+                        truncation_direction,
                         raw_scores.unwrap(),
                     ),
                     result_sender,
@@ -990,6 +1330,7 @@ impl grpc::rerank_server::Rerank for TextEmbeddingsService {
                 .expect("`result_sender` was dropped. This is a bug.")?;
 
             total_compute_tokens += r.1;
+            tokens.record(r.1);
             total_tokenization_time += r.2.as_nanos() as u64;
             total_queue_time += r.3.as_nanos() as u64;
             total_inference_time += r.4.as_nanos() as u64;
@@ -1070,10 +1411,43 @@ impl grpc::tokenize_server::Tokenize for TextEmbeddingsService {
         request: Request<EncodeRequest>,
     ) -> Result<Response<EncodeResponse>, Status> {
         let request = request.into_inner();
-        let tokens = self.tokenize_inner(request).await?;
+        let (infer, _) = self.model();
+        let tokens = self.tokenize_inner(&infer, request).await?;
         Ok(Response::new(tokens))
     }
 
+    #[instrument(skip_all)]
+    async fn token_count(
+        &self,
+        request: Request<EncodeRequest>,
+    ) -> Result<Response<TokenCountResponse>, Status> {
+        let request = request.into_inner();
+        let encoding = self
+            .model()
+            .0
+            .tokenize(request.inputs, request.add_special_tokens)
+            .await
+            .map_err(ErrorResponse::from)?;
+        Ok(Response::new(TokenCountResponse {
+            count: encoding.len() as u32,
+        }))
+    }
+
+    #[instrument(skip_all)]
+    async fn decode(
+        &self,
+        request: Request<DecodeRequest>,
+    ) -> Result<Response<DecodeResponse>, Status> {
+        let request = request.into_inner();
+        let text = self
+            .model()
+            .0
+            .decode(request.ids, request.skip_special_tokens)
+            .await
+            .map_err(ErrorResponse::from)?;
+        Ok(Response::new(DecodeResponse { text }))
+    }
+
     type TokenizeStreamStream = UnboundedReceiverStream<Result<EncodeResponse, Status>>;
 
     async fn tokenize_stream(
@@ -1097,12 +1471,13 @@ impl grpc::tokenize_server::Tokenize for TextEmbeddingsService {
             while let Some((request, mut sender)) = encode_receiver.recv().await {
                 // Required for the async move below
                 let task_local = local.clone();
+                let (infer, _) = local.model();
 
                 // Create async task for this specific input
                 tokio::spawn(async move {
                     // Select on closed to cancel work if the stream was closed
                     tokio::select! {
-                        response = task_local.tokenize_inner(request) => {
+                        response = task_local.tokenize_inner(&infer, request) => {
                             let _ = sender.send(response);
                         }
                     _ = sender.closed() => {}
@@ -1160,13 +1535,23 @@ impl grpc::tokenize_server::Tokenize for TextEmbeddingsService {
 }
 
 pub async fn run(
-    infer: Infer,
-    info: Info,
+    models: Models,
+    api_keys: Option<ApiKeys>,
+    usage: UsageCounters,
+    tls: Option<TlsConfig>,
     addr: SocketAddr,
-    prom_builder: PrometheusBuilder,
+    uds: Option<UnixListener>,
+    prom_builder: Option<PrometheusBuilder>,
 ) -> Result<(), anyhow::Error> {
-    prom_builder.install()?;
-    tracing::info!("Serving Prometheus metrics: 0.0.0.0:9000");
+    if models.all().len() > 1 {
+        tracing::warn!("The gRPC server only serves the default model");
+    }
+
+    // The metrics are served by the HTTP server when both servers run
+    if let Some(prom_builder) = prom_builder {
+        prom_builder.install()?;
+        tracing::info!("Serving Prometheus metrics: 0.0.0.0:9000");
+    }
 
     // Liveness service
     let (mut health_reporter, health_service) = tonic_health::server::health_reporter();
@@ -1190,64 +1575,73 @@ pub async fn run(
         .set_not_serving::<grpc::PredictServer<TextEmbeddingsService>>()
         .await;
 
-    // Backend health watcher
-    let mut health_watcher = infer.health_watcher();
-
-    // Clone model_type and move it to the task
-    let health_watcher_model_type = info.model_type.clone();
-
     // Update services health
-    tokio::spawn(async move {
-        while health_watcher.changed().await.is_ok() {
-            let health = *health_watcher.borrow_and_update();
-            let status = match health {
-                true => ServingStatus::Serving,
-                false => ServingStatus::NotServing,
-            };
+    // The backend health of the default model is watched again when it is reloaded
+    let health_models = models.clone();
+    let health_task = async move {
+        loop {
+            let (infer, info) = health_models.default_model();
+            let mut health_watcher = infer.health_watcher();
+            drop(infer);
+
+            while health_watcher.changed().await.is_ok() {
+                let health = *health_watcher.borrow_and_update();
+                let status = match health {
+                    true => ServingStatus::Serving,
+                    false => ServingStatus::NotServing,
+                };
 
-            // Match on model type and set the health of the correct service(s)
-            //
-            // If Reranker, we have both a predict and rerank service
-            //
-            // This logic hints back to the user that if they try using the wrong service
-            // given the model type, it will always return an error.
-            //
-            // For example if the model type is `Embedding`, sending requests to `Rerank` will
-            // always return an `UNIMPLEMENTED` Status and both the `Rerank` and `Predict` services
-            // will have a `NOT_SERVING` ServingStatus.
-            match health_watcher_model_type {
-                ModelType::Classifier(_) => {
-                    health_reporter
-                        .set_service_status(
-                            <grpc::PredictServer<TextEmbeddingsService>>::NAME,
-                            status,
-                        )
-                        .await
-                }
-                ModelType::Embedding(_) => {
-                    health_reporter
-                        .set_service_status(
-                            <grpc::EmbedServer<TextEmbeddingsService>>::NAME,
-                            status,
-                        )
-                        .await
-                }
-                ModelType::Reranker(_) => {
-                    // Reranker has both a predict and rerank service
-                    health_reporter
-                        .set_service_status(
-                            <grpc::PredictServer<TextEmbeddingsService>>::NAME,
-                            status,
-                        )
-                        .await;
-                    health_reporter
-                        .set_service_status(
-                            <grpc::RerankServer<TextEmbeddingsService>>::NAME,
-                            status,
-                        )
-                        .await;
-                }
-            };
+                // Match on model type and set the health of the correct service(s)
+                //
+                // If Reranker, we have both a predict and rerank service
+                //
+                // This logic hints back to the user that if they try using the wrong service
+                // given the model type, it will always return an error.
+                //
+                // For example if the model type is `Embedding`, sending requests to `Rerank`
+                // will always return an `UNIMPLEMENTED` Status and both the `Rerank` and
+                // `Predict` services will have a `NOT_SERVING` ServingStatus.
+                match info.model_type {
+                    ModelType::Classifier(_) => {
+                        health_reporter
+                            .set_service_status(
+                                <grpc::PredictServer<TextEmbeddingsService>>::NAME,
+                                status,
+                            )
+                            .await
+                    }
+                    ModelType::Embedding(_) => {
+                        health_reporter
+                            .set_service_status(
+                                <grpc::EmbedServer<TextEmbeddingsService>>::NAME,
+                                status,
+                            )
+                            .await
+                    }
+                    ModelType::Reranker(_) => {
+                        // Reranker has both a predict and rerank service
+                        health_reporter
+                            .set_service_status(
+                                <grpc::PredictServer<TextEmbeddingsService>>::NAME,
+                                status,
+                            )
+                            .await;
+                        health_reporter
+                            .set_service_status(
+                                <grpc::RerankServer<TextEmbeddingsService>>::NAME,
+                                status,
+                            )
+                            .await;
+                    }
+                };
+            }
+        }
+    };
+    // The task holds the models so it must stop for them to drain
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = health_task => {},
+            _ = shutdown::shutdown_signal() => {},
         }
     });
 
@@ -1258,24 +1652,122 @@ pub async fn run(
         .build()?;
 
     // Main service
-    let service = TextEmbeddingsService::new(infer, info);
+    let service = TextEmbeddingsService::new(models.clone(), api_keys.clone(), usage.clone());
+
+    // Check the API key and identify the tenant of the calls to the inference services.
+    // The prompt tokens are counted towards the token quotas and the usage counters by the
+    // service methods
+    let check_api_key = move |mut request: Request<()>| -> Result<Request<()>, Status> {
+        let key = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(|value| value.trim().to_string());
+        if let Some(api_keys) = &api_keys {
+            api_keys.authorize(key.as_deref())?;
+            if let Some(key) = &key {
+                request.extensions_mut().insert(ApiKey(key.clone()));
+            }
+        }
+        let tenant = usage.tenant(&request.metadata().clone().into_headers(), key.as_deref());
+        request.extensions_mut().insert(Tenant(tenant));
+        Ok(request)
+    };
 
     // Create gRPC server
     tracing::info!("Starting gRPC server: {}", &addr);
-    Server::builder()
-        .add_service(health_service)
-        .add_service(reflection_service)
-        .add_service(grpc::InfoServer::new(service.clone()))
-        .add_service(grpc::TokenizeServer::new(service.clone()))
-        .add_service(grpc::EmbedServer::new(service.clone()))
-        .add_service(grpc::PredictServer::new(service.clone()))
-        .add_service(grpc::RerankServer::new(service))
-        .serve_with_shutdown(addr, shutdown::shutdown_signal())
-        .await?;
+    // Clients can also set a shorter deadline with the `grpc-timeout` header
+    let mut builder = Server::builder().trace_fn(request_span);
+    if let Some(timeout) = models.args.request_timeout {
+        builder = builder.timeout(timeout);
+    }
+    let add_services = |mut builder: Server| {
+        builder
+            .add_service(health_service.clone())
+            .add_service(reflection_service.clone())
+            .add_service(grpc::InfoServer::new(service.clone()))
+            .add_service(grpc::TokenizeServer::with_interceptor(
+                service.clone(),
+                check_api_key.clone(),
+            ))
+            .add_service(grpc::EmbedServer::with_interceptor(
+                service.clone(),
+                check_api_key.clone(),
+            ))
+            .add_service(grpc::PredictServer::with_interceptor(
+                service.clone(),
+                check_api_key.clone(),
+            ))
+            .add_service(grpc::RerankServer::with_interceptor(
+                service.clone(),
+                check_api_key.clone(),
+            ))
+    };
+
+    // The Unix socket is served in plain text
+    let uds_server = uds.map(|listener| {
+        add_services(builder.clone()).serve_with_incoming_shutdown(
+            UnixListenerStream::new(listener),
+            shutdown::shutdown_signal(),
+        )
+    });
+    let uds_server = async move {
+        if let Some(uds_server) = uds_server {
+            uds_server.await?;
+        }
+        Ok::<(), anyhow::Error>(())
+    };
+
+    if let Some(tls) = tls {
+        // Certificates are only loaded at startup
+        builder = builder.tls_config(tls.tonic_config()?)?;
+    }
+    let tcp_server = add_services(builder).serve_with_shutdown(addr, shutdown::shutdown_signal());
+    let tcp_server = async move { tcp_server.await.map_err(anyhow::Error::from) };
 
+    tokio::try_join!(tcp_server, uds_server)?;
     Ok(())
 }
 
+/// Root span of a gRPC request, continuing the trace of the client if it sent a `traceparent`
+/// header
+fn request_span(request: &http::Request<()>) -> Span {
+    let span = tracing::info_span!("grpc_request", path = %request.uri().path());
+    let context = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(request.headers()))
+    });
+    span.set_parent(context);
+    span
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl<'a> Extractor for HeaderExtractor<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// Convert the truncation direction of a request. `None` uses the `--truncation-direction` of the
+/// server
+fn truncation_direction(value: Option<i32>) -> Result<Option<TruncationDirection>, Status> {
+    value
+        .map(|value| match grpc::TruncationDirection::try_from(value) {
+            Ok(grpc::TruncationDirection::Right) => Ok(TruncationDirection::Right),
+            Ok(grpc::TruncationDirection::Left) => Ok(TruncationDirection::Left),
+            Err(_) => Err(Status::new(
+                Code::InvalidArgument,
+                format!("unknown truncation direction {value}"),
+            )),
+        })
+        .transpose()
+}
+
 impl From<ErrorResponse> for Status {
     fn from(value: ErrorResponse) -> Self {
         let code = match value.error_type {
@@ -1284,6 +1776,10 @@ impl From<ErrorResponse> for Status {
             ErrorType::Overloaded => Code::ResourceExhausted,
             ErrorType::Validation => Code::InvalidArgument,
             ErrorType::Tokenizer => Code::FailedPrecondition,
+            ErrorType::Unauthorized => Code::Unauthenticated,
+            ErrorType::QuotaExceeded => Code::ResourceExhausted,
+            ErrorType::Timeout => Code::DeadlineExceeded,
+            ErrorType::NotFound => Code::NotFound,
         };
 
         Status::new(code, value.error)
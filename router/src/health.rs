@@ -0,0 +1,68 @@
+/// Liveness and readiness of the served models
+use crate::Models;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The process is reported as not live after this many consecutive failed probes
+const MAX_FAILED_PROBES: usize = 3;
+
+/// Result of the last probes of the models
+#[derive(Debug, Clone)]
+pub(crate) struct HealthState {
+    live: Arc<AtomicBool>,
+    ready: Arc<AtomicBool>,
+}
+
+impl HealthState {
+    /// The models are loaded and warmed up before the servers start
+    pub(crate) fn new() -> Self {
+        Self {
+            live: Arc::new(AtomicBool::new(true)),
+            ready: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    /// `false` once the backend of a model has failed or not answered several probes in a row.
+    /// The device context is then considered broken and the process should be restarted.
+    pub(crate) fn is_live(&self) -> bool {
+        self.live.load(Ordering::Relaxed)
+    }
+
+    /// `false` if the last probe of a model failed
+    pub(crate) fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Relaxed)
+    }
+}
+
+/// Run a single token through every model each `interval`.
+/// A probe that does not complete within `interval` fails: a wedged device never answers.
+pub(crate) async fn probe_models(models: Models, interval: Duration) {
+    let mut failed_probes = 0;
+    loop {
+        tokio::time::sleep(interval).await;
+
+        let mut healthy = true;
+        for (infer, info) in models.all() {
+            match tokio::time::timeout(interval, infer.health()).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    tracing::error!("Health probe of model {} failed", info.model_id);
+                    healthy = false;
+                }
+                Err(_) => {
+                    tracing::error!("Health probe of model {} timed out", info.model_id);
+                    healthy = false;
+                }
+            }
+        }
+
+        failed_probes = if healthy { 0 } else { failed_probes + 1 };
+        metrics::gauge!("te_health_failed_probes", failed_probes as f64);
+        models.health.ready.store(healthy, Ordering::Relaxed);
+        models
+            .health
+            .live
+            .store(failed_probes < MAX_FAILED_PROBES, Ordering::Relaxed);
+    }
+}
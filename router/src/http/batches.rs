@@ -0,0 +1,464 @@
+/// Background embedding jobs of the `/v1/batches` routes
+use crate::auth::ApiKeys;
+use crate::http::types::{BatchInput, BatchRequestCounts, BatchResponse, BatchResult, BatchStatus};
+use crate::storage;
+use crate::usage::UsageCounters;
+use crate::{ErrorResponse, ErrorType};
+use futures::future::join_all;
+use reqwest::redirect::Policy;
+use reqwest::Url;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use text_embeddings_core::infer::Infer;
+use text_embeddings_core::queue::Priority;
+use text_embeddings_core::tokenization::TruncationDirection;
+
+/// Input file of a job
+pub(crate) enum BatchSource {
+    /// JSONL file uploaded with the request
+    Jsonl(String),
+    /// `http(s)` URL or `s3://`, `gs://` or `az://` URI of a JSONL file
+    Url(String),
+}
+
+/// Client the prompt tokens of a job are charged to
+pub(crate) struct BatchOwner {
+    /// API key the job was submitted with, if the API key authentication is enabled
+    pub api_key: Option<(ApiKeys, String)>,
+    pub usage: UsageCounters,
+    pub tenant: String,
+}
+
+impl BatchOwner {
+    /// Check that the key has `tokens` prompt tokens left in its quota
+    fn check_tokens(&self, tokens: u64) -> Result<(), String> {
+        match &self.api_key {
+            Some((api_keys, key)) => api_keys.check_tokens(key, tokens).map_err(|err| err.error),
+            None => Ok(()),
+        }
+    }
+
+    fn record_tokens(&self, tokens: u64) {
+        if let Some((api_keys, key)) = &self.api_key {
+            api_keys.record_tokens(key, tokens);
+        }
+        self.usage.record_tokens(self.tenant.clone(), tokens);
+    }
+}
+
+/// Finished jobs and their results are removed after this delay
+const FINISHED_JOB_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Maximum number of jobs kept at the same time, finished jobs included until they expire
+const MAX_LIVE_JOBS: usize = 100;
+
+/// Maximum number of redirects followed when downloading an input file
+const MAX_REDIRECTS: usize = 10;
+
+struct BatchJob {
+    response: BatchResponse,
+    results: Vec<BatchResult>,
+    finished_at: Option<Instant>,
+}
+
+/// Jobs submitted to this process. Results are kept in memory for `FINISHED_JOB_TTL`, unless
+/// they are written to an object store.
+#[derive(Clone)]
+pub(crate) struct BatchJobs {
+    jobs: Arc<Mutex<HashMap<String, BatchJob>>>,
+    /// Hosts and buckets the input files can be read from and the results written to
+    allowed_hosts: Arc<Vec<String>>,
+    /// Maximum size of a downloaded input file in bytes
+    max_input_size: usize,
+}
+
+impl BatchJobs {
+    pub(crate) fn new(allowed_hosts: Vec<String>, max_input_size: usize) -> Self {
+        Self {
+            jobs: Arc::new(Mutex::new(HashMap::new())),
+            allowed_hosts: Arc::new(allowed_hosts),
+            max_input_size,
+        }
+    }
+
+    /// Check that the input file of a job can be read from `url`
+    pub(crate) fn check_url(&self, url: &str) -> Result<(), String> {
+        let parsed = Url::parse(url).map_err(|err| format!("Invalid URL {url}: {err}"))?;
+        if !matches!(parsed.scheme(), "http" | "https") && !storage::is_object_store_uri(url) {
+            return Err(format!("Only http(s), s3, gs and az URLs are supported. Given: {url}"));
+        }
+        if !is_allowed(&self.allowed_hosts, &parsed) {
+            return Err(format!(
+                "Files cannot be read from or written to {}. Allowed hosts and buckets are set \
+                 with `--batch-allowed-hosts`",
+                parsed.host_str().unwrap_or_default()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Check that the results of a job can be written to `url`
+    pub(crate) fn check_output_url(&self, url: &str) -> Result<(), String> {
+        if !storage::is_object_store_uri(url) {
+            return Err(format!("Results can only be written to s3, gs and az URLs. Given: {url}"));
+        }
+        self.check_url(url)
+    }
+
+    /// Create a job and start processing it in the background.
+    /// At most `max_in_flight` inputs of the job are queued at the same time so that interactive
+    /// requests keep most of the capacity.
+    /// The prompt tokens of the job are charged to `owner` as its inputs are embedded.
+    /// The results are written to `output_file_url` when the job completes, if set.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn create(
+        &self,
+        infer: Infer,
+        source: BatchSource,
+        output_file_url: Option<String>,
+        owner: BatchOwner,
+        truncate: bool,
+        truncation_direction: Option<TruncationDirection>,
+        normalize: bool,
+        max_in_flight: usize,
+        max_input_length: usize,
+    ) -> Result<BatchResponse, ErrorResponse> {
+        let mut jobs = self.jobs.lock().unwrap();
+        // Expired jobs are removed when a job is created
+        jobs.retain(|_, job| !job.is_expired());
+        if jobs.len() >= MAX_LIVE_JOBS {
+            metrics::increment_counter!("te_request_failure", "err" => "overloaded");
+            let message = format!(
+                "Too many batch jobs: at most {MAX_LIVE_JOBS} jobs are kept until an hour after \
+                 they finish"
+            );
+            tracing::error!("{message}");
+            return Err(ErrorResponse {
+                error: message,
+                error_type: ErrorType::Overloaded,
+            });
+        }
+
+        // Any client knowing the id of a job can read its results
+        let id = format!("batch_{:032x}", rand::random::<u128>());
+        let response = BatchResponse {
+            id: id.clone(),
+            object: "batch",
+            status: BatchStatus::Validating,
+            created_at: unix_timestamp(),
+            completed_at: None,
+            request_counts: BatchRequestCounts::default(),
+            output_file_url: output_file_url.clone(),
+            error: None,
+        };
+        jobs.insert(
+            id.clone(),
+            BatchJob {
+                response: response.clone(),
+                results: Vec::new(),
+                finished_at: None,
+            },
+        );
+        drop(jobs);
+        metrics::increment_counter!("te_batch_job_count");
+
+        let jobs = self.clone();
+        tokio::spawn(async move {
+            jobs.process(
+                id,
+                infer,
+                source,
+                output_file_url,
+                owner,
+                truncate,
+                truncation_direction,
+                normalize,
+                max_in_flight.max(1),
+                max_input_length,
+            )
+            .await
+        });
+
+        Ok(response)
+    }
+
+    pub(crate) fn get(&self, id: &str) -> Option<BatchResponse> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .get(id)
+            .filter(|job| !job.is_expired())
+            .map(|job| job.response.clone())
+    }
+
+    /// Results of a job serialized as JSONL. They are empty once written to an object store
+    pub(crate) fn results(&self, id: &str) -> Option<(BatchResponse, String)> {
+        let jobs = self.jobs.lock().unwrap();
+        let job = jobs.get(id).filter(|job| !job.is_expired())?;
+        Some((job.response.clone(), to_jsonl(&job.results)))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn process(
+        self,
+        id: String,
+        infer: Infer,
+        source: BatchSource,
+        output_file_url: Option<String>,
+        owner: BatchOwner,
+        truncate: bool,
+        truncation_direction: Option<TruncationDirection>,
+        normalize: bool,
+        max_in_flight: usize,
+        max_input_length: usize,
+    ) {
+        let inputs = match self.load_inputs(source).await {
+            Ok(inputs) => inputs,
+            Err(err) => {
+                self.fail(&id, err);
+                return;
+            }
+        };
+
+        // The whole job must fit in the token quota of the key before anything is embedded
+        let tokens = count_tokens(&infer, &inputs, truncate, max_input_length).await;
+        if let Err(err) = owner.check_tokens(tokens) {
+            self.fail(&id, err);
+            return;
+        }
+        // The job is counted as one request of its tenant
+        owner.usage.record(owner.tenant.clone(), 0);
+
+        let total = inputs.len();
+        self.update(&id, |job| {
+            job.response.status = BatchStatus::InProgress;
+            job.response.request_counts.total = total;
+            job.results.reserve(total);
+        });
+
+        for (offset, inputs) in (0..total)
+            .step_by(max_in_flight)
+            .zip(inputs.chunks(max_in_flight))
+        {
+            let futures = inputs.iter().map(|input| {
+                let infer = infer.clone();
+                async move {
+                    let permit = infer.acquire_permit().await;
+                    infer
+                        .embed_pooled(
+                            input.clone(),
+                            truncate,
+                            truncation_direction,
+                            normalize,
+                            None,
+                            None,
+                            Priority::Batch,
+                            permit,
+                        )
+                        .await
+                }
+            });
+            let results = join_all(futures).await;
+
+            let tokens = results
+                .iter()
+                .flatten()
+                .map(|response| response.metadata.prompt_tokens as u64)
+                .sum();
+            owner.record_tokens(tokens);
+
+            self.update(&id, |job| {
+                for (i, result) in results.into_iter().enumerate() {
+                    let result = match result {
+                        Ok(response) => {
+                            job.response.request_counts.completed += 1;
+                            BatchResult {
+                                index: offset + i,
+                                embedding: Some(response.results),
+                                error: None,
+                            }
+                        }
+                        Err(err) => {
+                            job.response.request_counts.failed += 1;
+                            BatchResult {
+                                index: offset + i,
+                                embedding: None,
+                                error: Some(err.to_string()),
+                            }
+                        }
+                    };
+                    job.results.push(result);
+                }
+            });
+        }
+
+        // The results written to the object store are not kept in memory
+        if let Some(output_file_url) = output_file_url {
+            let mut results = Vec::new();
+            self.update(&id, |job| results = std::mem::take(&mut job.results));
+            let jsonl = to_jsonl(&results);
+            drop(results);
+            if let Err(err) = storage::write_object(&output_file_url, jsonl.into_bytes()).await {
+                tracing::error!("Could not write {output_file_url}: {err}");
+                self.fail(&id, "Could not write the results".to_string());
+                return;
+            }
+        }
+
+        tracing::info!("Batch {id} completed");
+        self.update(&id, |job| {
+            job.response.status = BatchStatus::Completed;
+            job.response.completed_at = Some(unix_timestamp());
+            job.finished_at = Some(Instant::now());
+        });
+    }
+
+    fn fail(&self, id: &str, err: String) {
+        tracing::error!("Batch {id} failed: {err}");
+        self.update(id, |job| {
+            job.response.status = BatchStatus::Failed;
+            job.response.completed_at = Some(unix_timestamp());
+            job.response.error = Some(err);
+            job.finished_at = Some(Instant::now());
+        });
+    }
+
+    fn update(&self, id: &str, f: impl FnOnce(&mut BatchJob)) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(id) {
+            f(job)
+        }
+    }
+
+    /// Download the input file if needed and parse its lines.
+    /// The errors are returned to the client so the download errors are only logged.
+    async fn load_inputs(&self, source: BatchSource) -> Result<Vec<String>, String> {
+        let jsonl = match source {
+            BatchSource::Jsonl(jsonl) => jsonl,
+            BatchSource::Url(url) => {
+                self.check_url(&url)?;
+                let downloaded = if storage::is_object_store_uri(&url) {
+                    storage::read_object(&url, self.max_input_size)
+                        .await
+                        .map(|object| {
+                            object.map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+                        })
+                } else {
+                    self.download(&url).await.map_err(anyhow::Error::from)
+                };
+                match downloaded {
+                    Ok(Some(jsonl)) => jsonl,
+                    Ok(None) => {
+                        let max_input_size = self.max_input_size;
+                        return Err(format!("The input file is larger than {max_input_size} bytes"));
+                    }
+                    Err(err) => {
+                        tracing::error!("Could not download {url}: {err}");
+                        return Err("Could not download the input file".to_string());
+                    }
+                }
+            }
+        };
+        parse_inputs(&jsonl)
+    }
+
+    /// Download `url` without following redirects to other hosts.
+    /// Returns `None` if the file is larger than `max_input_size`.
+    async fn download(&self, url: &str) -> Result<Option<String>, reqwest::Error> {
+        let allowed_hosts = self.allowed_hosts.clone();
+        let client = reqwest::Client::builder()
+            .redirect(Policy::custom(move |attempt| {
+                if attempt.previous().len() >= MAX_REDIRECTS {
+                    attempt.error("too many redirects")
+                } else if is_allowed(&allowed_hosts, attempt.url()) {
+                    attempt.follow()
+                } else {
+                    attempt.error("redirect to a host that is not allowed")
+                }
+            }))
+            .build()?;
+
+        let mut response = client.get(url).send().await?.error_for_status()?;
+        if response
+            .content_length()
+            .map_or(false, |length| length > self.max_input_size as u64)
+        {
+            return Ok(None);
+        }
+        // The length header is optional
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            if body.len() + chunk.len() > self.max_input_size {
+                return Ok(None);
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Ok(Some(String::from_utf8_lossy(&body).into_owned()))
+    }
+}
+
+impl BatchJob {
+    fn is_expired(&self) -> bool {
+        self.finished_at
+            .map_or(false, |finished_at| finished_at.elapsed() >= FINISHED_JOB_TTL)
+    }
+}
+
+fn is_allowed(allowed_hosts: &[String], url: &Url) -> bool {
+    url.host_str().map_or(false, |host| {
+        allowed_hosts
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(host))
+    })
+}
+
+/// One JSON object per line
+fn to_jsonl(results: &[BatchResult]) -> String {
+    results
+        .iter()
+        .map(|result| serde_json::to_string(result).unwrap() + "\n")
+        .collect()
+}
+
+/// Prompt tokens the inputs of a job will be charged once truncated to `max_input_length`.
+/// The inputs that cannot be tokenized or are too long without `truncate` are not counted: they
+/// fail without being charged
+async fn count_tokens(
+    infer: &Infer,
+    inputs: &[String],
+    truncate: bool,
+    max_input_length: usize,
+) -> u64 {
+    let counts = join_all(inputs.iter().map(|input| infer.count_tokens(input.clone()))).await;
+    counts
+        .into_iter()
+        .flatten()
+        .filter(|count| truncate || count.tokens <= max_input_length)
+        .map(|count| count.tokens.min(max_input_length) as u64)
+        .sum()
+}
+
+/// Parse the lines of a JSONL input file
+fn parse_inputs(jsonl: &str) -> Result<Vec<String>, String> {
+    jsonl
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str::<BatchInput>(line)
+                .map(|line| line.input)
+                .map_err(|err| format!("Invalid line {}: {err}", i + 1))
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .and_then(|inputs| match inputs.is_empty() {
+            true => Err("The input file is empty".to_string()),
+            false => Ok(inputs),
+        })
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
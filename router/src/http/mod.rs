@@ -1,2 +1,5 @@
+mod batches;
+mod openapi;
+mod rate_limit;
 pub mod server;
 mod types;
@@ -0,0 +1,82 @@
+/// OpenAPI document of the loaded model
+use crate::Info;
+use utoipa::openapi::schema::{Array, Schema, SchemaType};
+use utoipa::openapi::{OpenApi, RefOr};
+
+/// Add the limits of the model described by `info` to the schemas of `openapi`, so that clients
+/// generated from the document and gateways can validate the payloads:
+/// - the number of values of the embeddings and the maximum of `dimensions`
+/// - the maximum number of inputs of a batch
+pub(crate) fn with_model_limits(mut openapi: OpenApi, info: &Info) -> OpenApi {
+    let embedding_dimension = info.metadata.embedding_dimension;
+    let max_batch_size = info.max_client_batch_size;
+
+    openapi.info.description = Some(format!(
+        "Serving `{}`. Inputs are truncated or rejected after {} tokens and batches hold at \
+         most {max_batch_size} inputs{}",
+        info.model_id,
+        info.max_input_length,
+        embedding_dimension
+            .map(|dimension| format!(". Embeddings have {dimension} values"))
+            .unwrap_or_default(),
+    ));
+
+    let Some(components) = openapi.components.as_mut() else {
+        return openapi;
+    };
+    let schemas = &mut components.schemas;
+
+    // Batches of inputs. Arrays of integers are the token ids of a single input
+    for name in ["Input", "OpenAICompatInput"] {
+        for_each_variant(schemas.get_mut(name), |array| {
+            let token_ids = matches!(
+                array.items.as_ref(),
+                RefOr::T(Schema::Object(item)) if item.schema_type == SchemaType::Integer
+            );
+            if !token_ids {
+                array.max_items = Some(max_batch_size);
+            }
+        });
+    }
+    if let Some(RefOr::T(Schema::Array(batch))) = schemas.get_mut("EmbedResponse") {
+        batch.max_items = Some(max_batch_size);
+    }
+
+    let Some(dimension) = embedding_dimension else {
+        return openapi;
+    };
+
+    // Embeddings
+    if let Some(RefOr::T(Schema::Array(batch))) = schemas.get_mut("EmbedResponse") {
+        if let RefOr::T(Schema::Array(embedding)) = batch.items.as_mut() {
+            embedding.max_items = Some(dimension);
+        }
+    }
+    for_each_variant(schemas.get_mut("OpenAICompatEmbeddingValues"), |array| {
+        array.max_items = Some(dimension);
+    });
+
+    // `dimensions` truncates the embeddings
+    for name in ["EmbedRequest", "OpenAICompatRequest"] {
+        if let Some(RefOr::T(Schema::Object(request))) = schemas.get_mut(name) {
+            if let Some(RefOr::T(Schema::Object(dimensions))) =
+                request.properties.get_mut("dimensions")
+            {
+                dimensions.maximum = Some(dimension as f64);
+            }
+        }
+    }
+
+    openapi
+}
+
+/// Apply `f` to the array variants of the `oneOf` schema `schema`
+fn for_each_variant(schema: Option<&mut RefOr<Schema>>, f: impl Fn(&mut Array)) {
+    if let Some(RefOr::T(Schema::OneOf(one_of))) = schema {
+        for variant in &mut one_of.items {
+            if let RefOr::T(Schema::Array(array)) = variant {
+                f(array);
+            }
+        }
+    }
+}
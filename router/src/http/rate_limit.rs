@@ -0,0 +1,103 @@
+/// Per client rate limiting and global concurrency limit of the HTTP server
+use lru::LruCache;
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// The bucket refilled the longest time ago is dropped when a new client is seen while this many
+/// clients are tracked
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Token buckets refilled with `rate` tokens per second up to `burst` tokens, one per client
+#[derive(Clone)]
+pub(crate) struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    /// Buckets are refilled when they are used, so the least recently used one is the one
+    /// refilled the longest time ago
+    buckets: Arc<Mutex<LruCache<String, Bucket>>>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(rate: f64, burst: u32) -> Self {
+        Self {
+            rate,
+            burst: burst.max(1) as f64,
+            buckets: Arc::new(Mutex::new(LruCache::new(
+                NonZeroUsize::new(MAX_TRACKED_CLIENTS).unwrap(),
+            ))),
+        }
+    }
+
+    /// Take a token from the bucket of `client`.
+    /// Returns the time after which a token will be available if the bucket is empty.
+    pub(crate) fn try_acquire(&self, client: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        let bucket = buckets.get_or_insert_mut(client.to_string(), || Bucket {
+            tokens: self.burst,
+            last_refill: now,
+        });
+        bucket.tokens = self.refilled(bucket, now);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        (bucket.tokens + elapsed * self.rate).min(self.burst)
+    }
+}
+
+/// Limits applied to every non public request
+#[derive(Clone)]
+pub(crate) struct RequestLimits {
+    pub rate_limiter: Option<RateLimiter>,
+    /// Identify clients by API key instead of IP address
+    pub by_api_key: bool,
+    /// Maximum number of requests processed at the same time
+    pub in_flight: Option<Arc<Semaphore>>,
+}
+
+impl RequestLimits {
+    /// Take a token from the bucket of the client and a permit of the requests in flight, to hold
+    /// until the request is answered.
+    /// Returns the reason and the time after which the client can retry if the request is refused.
+    pub(crate) fn try_acquire(
+        &self,
+        api_key: Option<&str>,
+        client_ip: IpAddr,
+    ) -> Result<Option<OwnedSemaphorePermit>, (&'static str, Duration)> {
+        if let Some(rate_limiter) = &self.rate_limiter {
+            let client = match api_key {
+                Some(api_key) if self.by_api_key => api_key.to_string(),
+                _ => client_ip.to_string(),
+            };
+            rate_limiter
+                .try_acquire(&client)
+                .map_err(|retry_after| ("Rate limit exceeded", retry_after))?;
+        }
+
+        match &self.in_flight {
+            Some(in_flight) => match in_flight.clone().try_acquire_owned() {
+                Ok(permit) => Ok(Some(permit)),
+                Err(_) => Err(("Too many requests in flight", Duration::from_secs(1))),
+            },
+            None => Ok(None),
+        }
+    }
+}
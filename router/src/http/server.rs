@@ -1,37 +1,84 @@
 /// HTTP Server logic
 use crate::http::types::{
-    EmbedAllRequest, EmbedAllResponse, EmbedRequest, EmbedResponse, Input, OpenAICompatEmbedding,
-    OpenAICompatErrorResponse, OpenAICompatRequest, OpenAICompatResponse, OpenAICompatUsage,
-    PredictInput, PredictRequest, PredictResponse, Prediction, Rank, RerankRequest, RerankResponse,
-    Sequence, SimpleToken, TokenizeRequest, TokenizeResponse,
+    Activation, BatchParams, BatchResponse, BatchStatus, Chunk, CountRequest, CountResponse,
+    CreateBatchRequest, DecodeRequest, DecodeResponse, EmbedAllRequest, EmbedAllResponse,
+    EmbedChunksRequest, EmbedChunksResponse, EmbedPartialResponse, EmbedRequest, EmbedResponse,
+    EmbedSparseRequest, EmbedSparseResponse, EmbedTruncationResponse, EncodingFormat, Input,
+    InputIds, InputTokenCount, InputTruncation, InputType, ModelMetadataResponse,
+    OpenAICompatEmbedding, OpenAICompatEmbeddingValues, OpenAICompatErrorResponse,
+    OpenAICompatInput, OpenAICompatRequest, OpenAICompatResponse, OpenAICompatUsage,
+    PartialEmbedding, Pooling, PredictInput, PredictRequest, PredictResponse, Prediction,
+    ProtobufEmbedResponse, Rank, ReloadRequest, RerankCompatDocument, RerankCompatRequest,
+    RerankCompatResponse, RerankCompatResult, RerankCompatText, RerankCompatUsage, RerankRequest,
+    RerankResponse, Sequence, SimilarityInput, SimilarityRequest, SimilarityResponse, SimpleToken,
+    SparseValue, StreamedEmbedding, TokenEmbeddings, TokenizeRequest, TokenizeResponse,
+    TruncationStrategy, UsageResponse, UsageWrapper, WsEmbedRequest, WsEmbedResponse,
+    ZeroShotRequest, ZeroShotResponse,
 };
+use crate::auth::{key_fingerprint, ApiKeys};
+use crate::http::batches::{BatchJobs, BatchOwner, BatchSource};
+use crate::http::openapi;
+use crate::http::rate_limit::{RateLimiter, RequestLimits};
+use crate::logging::ACCESS_LOG_TARGET;
+use crate::tls::{self, TlsConfig};
+use crate::uds::UnixAccept;
+use crate::usage::{TenantUsage, UsageCounters};
 use crate::{
-    shutdown, ClassifierModel, EmbeddingModel, ErrorResponse, ErrorType, Info, ModelType,
-    ResponseMetadata,
+    shutdown, ClassifierModel, EmbeddingModel, ErrorResponse, ErrorType, HttpArgs, Info,
+    ModelMetadata, ModelType, Models, ProblemType, RateLimitArgs, RateLimitKey, ResponseMetadata,
+    TokenizerMetadata,
 };
 use anyhow::Context;
-use axum::extract::Extension;
+use axum::body::StreamBody;
+use axum::extract::ws::{self, WebSocket, WebSocketUpgrade};
+use axum::extract::{ConnectInfo, DefaultBodyLimit, Extension, MatchedPath, Path, Query, State};
 use axum::http::HeaderValue;
-use axum::http::{HeaderMap, Method, StatusCode};
+use axum::http::{HeaderMap, Method, Request, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
 use axum::routing::{get, post};
 use axum::{http, Json, Router};
+use axum_server::tls_rustls::RustlsConfig;
 use axum_tracing_opentelemetry::middleware::OtelAxumLayer;
 use futures::future::join_all;
+use futures::stream::{FuturesUnordered, StreamExt};
+use futures::SinkExt;
 use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
-use std::env;
-use std::net::SocketAddr;
+use prost::Message;
+use serde::Serialize;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
-use text_embeddings_backend::BackendError;
+use text_embeddings_backend::{BackendError, Pool};
 use text_embeddings_core::infer::{
-    AllEmbeddingsInferResponse, Infer, PooledEmbeddingsInferResponse,
+    AllEmbeddingsInferResponse, ClassificationInferResponse, Infer, InferMetadata,
+    PooledEmbeddingsInferResponse,
 };
+use text_embeddings_core::queue::Priority;
+use text_embeddings_core::tokenization::{EncodingInput, TokenCount, TruncationDirection};
 use text_embeddings_core::TextEmbeddingsError;
-use tokio::sync::OwnedSemaphorePermit;
+use tokio::net::UnixListener;
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use tower_http::compression::predicate::{NotForContentType, Predicate, SizeAbove};
+use tower_http::compression::CompressionLayer;
 use tower_http::cors::{AllowOrigin, CorsLayer};
 use tracing::instrument;
 use utoipa::OpenApi;
 use utoipa_swagger_ui::SwaggerUi;
 
+/// Content type of the protobuf encoded embeddings
+const PROTOBUF_CONTENT_TYPE: &str = "application/x-protobuf";
+
+/// Content type of the embeddings streamed as one JSON object per line
+const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// Responses smaller than this number of bytes are not compressed
+const COMPRESSION_MIN_SIZE: u16 = 1024;
+
+/// Maximum number of requests of a WebSocket session processed at the same time
+const WS_MAX_IN_FLIGHT: usize = 64;
+
 ///Text Embeddings Inference endpoint info
 #[utoipa::path(
 get,
@@ -54,16 +101,71 @@ responses(
 example = json ! ({"error": "unhealthy", "error_type": "unhealthy"})),
 )
 )]
-#[instrument(skip(infer))]
+#[instrument(skip(models))]
 /// Health check method
-async fn health(infer: Extension<Infer>) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
-    match infer.health().await {
-        true => Ok(()),
-        false => Err(ErrorResponse {
+async fn health(models: Extension<Models>) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
+    for (infer, info) in models.all() {
+        if !infer.health().await {
+            tracing::error!("Model {} is unhealthy", info.model_id);
+            Err(ErrorResponse {
+                error: "unhealthy".to_string(),
+                error_type: ErrorType::Unhealthy,
+            })?;
+        }
+    }
+    if models.gpu.as_ref().map_or(false, |gpu| !gpu.is_healthy()) {
+        tracing::error!("GPU free memory is below `--min-free-gpu-memory`");
+        Err(ErrorResponse {
+            error: "unhealthy".to_string(),
+            error_type: ErrorType::Unhealthy,
+        })?;
+    }
+    Ok(())
+}
+
+#[utoipa::path(
+get,
+tag = "Text Embeddings Inference",
+path = "/health/live",
+responses(
+(status = 200, description = "The models answer the health probes"),
+(status = 503, description = "Several health probes failed in a row", body = ErrorResponse,
+example = json ! ({"error": "unhealthy", "error_type": "unhealthy"})),
+)
+)]
+#[instrument(skip(models))]
+/// Liveness check method. Does not run the models: the result of the last health probes is used
+async fn liveness(models: Extension<Models>) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
+    if !models.health.is_live() {
+        Err(ErrorResponse {
+            error: "unhealthy".to_string(),
+            error_type: ErrorType::Unhealthy,
+        })?;
+    }
+    Ok(())
+}
+
+#[utoipa::path(
+get,
+tag = "Text Embeddings Inference",
+path = "/health/ready",
+responses(
+(status = 200, description = "Ready to serve requests"),
+(status = 503, description = "The last health probe failed", body = ErrorResponse,
+example = json ! ({"error": "unhealthy", "error_type": "unhealthy"})),
+)
+)]
+#[instrument(skip(models))]
+/// Readiness check method. Does not run the models: the result of the last health probe is used
+async fn readiness(models: Extension<Models>) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
+    let gpu_healthy = models.gpu.as_ref().map_or(true, |gpu| gpu.is_healthy());
+    if !models.health.is_ready() || !gpu_healthy {
+        Err(ErrorResponse {
             error: "unhealthy".to_string(),
             error_type: ErrorType::Unhealthy,
-        })?,
+        })?;
     }
+    Ok(())
 }
 
 /// Get Predictions. Returns a 424 status code if the model is not a Sequence Classification model
@@ -74,14 +176,16 @@ path = "/predict",
 request_body = PredictRequest,
 responses(
 (status = 200, description = "Predictions", body = PredictResponse),
+(status = 404, description = "Model is not served", body = ErrorResponse,
+example = json ! ({"error": "Model gpt-4 is not served", "error_type": "not_found"})),
 (status = 424, description = "Prediction Error", body = ErrorResponse,
 example = json ! ({"error": "Inference failed", "error_type": "backend"})),
 (status = 429, description = "Model is overloaded", body = ErrorResponse,
 example = json ! ({"error": "Model is overloaded", "error_type": "overloaded"})),
 (status = 422, description = "Tokenization error", body = ErrorResponse,
 example = json ! ({"error": "Tokenization error", "error_type": "tokenizer"})),
-(status = 413, description = "Batch size error", body = ErrorResponse,
-example = json ! ({"error": "Batch size error", "error_type": "validation"})),
+(status = 408, description = "Request timed out", body = ErrorResponse,
+example = json ! ({"error": "Request did not complete within 1000ms", "error_type": "timeout"})),
 )
 )]
 #[instrument(
@@ -89,13 +193,27 @@ example = json ! ({"error": "Batch size error", "error_type": "validation"})),
     fields(total_time, tokenization_time, queue_time, inference_time,)
 )]
 async fn predict(
-    infer: Extension<Infer>,
-    info: Extension<Info>,
+    models: Extension<Models>,
     Json(req): Json<PredictRequest>,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    let timeout = models.timeout(req.timeout_ms);
+    let return_usage = req.return_usage;
+    let (headers, Json(response)) = with_timeout(timeout, process_predict(models, req)).await?;
+    Ok(with_usage(headers, response, return_usage))
+}
+
+async fn process_predict(
+    models: Extension<Models>,
+    req: PredictRequest,
 ) -> Result<(HeaderMap, Json<PredictResponse>), (StatusCode, Json<ErrorResponse>)> {
     let span = tracing::Span::current();
     let start_time = Instant::now();
 
+    let (infer, info) = models.get(req.model.as_deref(), req.adapter.as_deref())?;
+    let priority = req.priority.into();
+    let activation = req.activation.map(Into::into);
+    let truncation_direction = req.truncation_direction.map(Into::into);
+
     // Closure for predict
     let predict_inner = move |inputs: Sequence,
                               truncate: bool,
@@ -109,7 +227,15 @@ async fn predict(
         };
 
         let response = infer
-            .predict(inputs, truncate, raw_scores, permit)
+            .predict(
+                inputs,
+                truncate,
+                truncation_direction,
+                raw_scores,
+                activation,
+                priority,
+                permit,
+            )
             .await
             .map_err(ErrorResponse::from)?;
 
@@ -138,11 +264,12 @@ async fn predict(
         predictions.sort_by(|x, y| x.score.partial_cmp(&y.score).unwrap());
         predictions.reverse();
 
-        Ok::<(usize, Duration, Duration, Duration, Vec<Prediction>), ErrorResponse>((
+        Ok::<(usize, Duration, Duration, Duration, Option<u64>, Vec<Prediction>), ErrorResponse>((
             response.metadata.prompt_tokens,
             response.metadata.tokenization,
             response.metadata.queue,
             response.metadata.inference,
+            response.metadata.batch_id,
             predictions,
         ))
     };
@@ -153,15 +280,16 @@ async fn predict(
 
             let compute_chars = inputs.count_chars();
             let permit = infer.try_acquire_permit().map_err(ErrorResponse::from)?;
-            let (prompt_tokens, tokenization, queue, inference, predictions) = predict_inner(
-                inputs,
-                req.truncate,
-                req.raw_scores,
-                infer.0,
-                info.0,
-                Some(permit),
-            )
-            .await?;
+            let (prompt_tokens, tokenization, queue, inference, batch_id, predictions) =
+                predict_inner(
+                    inputs,
+                    req.truncate,
+                    req.raw_scores,
+                    infer,
+                    info,
+                    Some(permit),
+                )
+                .await?;
 
             metrics::increment_counter!("te_request_success", "method" => "single");
 
@@ -174,47 +302,40 @@ async fn predict(
                     tokenization,
                     queue,
                     inference,
-                ),
+                )
+                .with_batch_id(batch_id),
             )
         }
         PredictInput::Batch(inputs) => {
             metrics::increment_counter!("te_request_count", "method" => "batch");
 
             let batch_size = inputs.len();
-            if batch_size > info.max_client_batch_size {
-                let message = format!(
-                    "batch size {batch_size} > maximum allowed batch size {}",
-                    info.max_client_batch_size
-                );
-                tracing::error!("{message}");
-                let err = ErrorResponse {
-                    error: message,
-                    error_type: ErrorType::Validation,
-                };
-                metrics::increment_counter!("te_request_failure", "err" => "batch_size");
-                Err(err)?;
-            }
-
-            let mut futures = Vec::with_capacity(batch_size);
             let mut compute_chars = 0;
-
-            for input in inputs {
-                compute_chars += input.count_chars();
-                let local_infer = infer.clone();
-                let local_info = info.clone();
-                futures.push(predict_inner(
-                    input,
-                    req.truncate,
-                    req.raw_scores,
-                    local_infer.0,
-                    local_info.0,
-                    None,
-                ))
+            let mut results = Vec::with_capacity(batch_size);
+
+            // Batches larger than `--max-client-batch-size` are processed in chunks so that
+            // rerankers can score many pairs in a single request without flooding the queue
+            let chunk_size = info.max_client_batch_size.max(1);
+            let mut inputs = inputs.into_iter().peekable();
+            while inputs.peek().is_some() {
+                let mut futures = Vec::with_capacity(chunk_size);
+                for input in inputs.by_ref().take(chunk_size) {
+                    compute_chars += input.count_chars();
+                    let local_infer = infer.clone();
+                    let local_info = info.clone();
+                    futures.push(predict_inner(
+                        input,
+                        req.truncate,
+                        req.raw_scores,
+                        local_infer,
+                        local_info,
+                        None,
+                    ))
+                }
+                for result in join_all(futures).await {
+                    results.push(result?);
+                }
             }
-            let results = join_all(futures).await.into_iter().collect::<Result<
-                Vec<(usize, Duration, Duration, Duration, Vec<Prediction>)>,
-                ErrorResponse,
-            >>()?;
 
             let mut predictions = Vec::with_capacity(batch_size);
             let mut total_tokenization_time = 0;
@@ -227,7 +348,7 @@ async fn predict(
                 total_tokenization_time += r.1.as_nanos() as u64;
                 total_queue_time += r.2.as_nanos() as u64;
                 total_inference_time += r.3.as_nanos() as u64;
-                predictions.push(r.4);
+                predictions.push(r.5);
             }
             let batch_size = batch_size as u64;
 
@@ -266,6 +387,8 @@ path = "/rerank",
 request_body = RerankRequest,
 responses(
 (status = 200, description = "Ranks", body = RerankResponse),
+(status = 404, description = "Model is not served", body = ErrorResponse,
+example = json ! ({"error": "Model gpt-4 is not served", "error_type": "not_found"})),
 (status = 424, description = "Rerank Error", body = ErrorResponse,
 example = json ! ({"error": "Inference failed", "error_type": "backend"})),
 (status = 429, description = "Model is overloaded", body = ErrorResponse,
@@ -274,6 +397,8 @@ example = json ! ({"error": "Model is overloaded", "error_type": "overloaded"}))
 example = json ! ({"error": "Tokenization error", "error_type": "tokenizer"})),
 (status = 413, description = "Batch size error", body = ErrorResponse,
 example = json ! ({"error": "Batch size error", "error_type": "validation"})),
+(status = 408, description = "Request timed out", body = ErrorResponse,
+example = json ! ({"error": "Request did not complete within 1000ms", "error_type": "timeout"})),
 )
 )]
 #[instrument(
@@ -281,14 +406,174 @@ example = json ! ({"error": "Batch size error", "error_type": "validation"})),
     fields(total_time, tokenization_time, queue_time, inference_time,)
 )]
 async fn rerank(
-    infer: Extension<Infer>,
-    info: Extension<Info>,
+    models: Extension<Models>,
     Json(req): Json<RerankRequest>,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    let timeout = models.timeout(req.timeout_ms);
+    let return_usage = req.return_usage;
+    let (headers, Json(response)) = with_timeout(timeout, process_rerank(models, req)).await?;
+    Ok(with_usage(headers, response, return_usage))
+}
+
+async fn process_rerank(
+    models: Extension<Models>,
+    req: RerankRequest,
 ) -> Result<(HeaderMap, Json<RerankResponse>), (StatusCode, Json<ErrorResponse>)> {
     let span = tracing::Span::current();
     let start_time = Instant::now();
 
-    if req.texts.is_empty() {
+    let (infer, info) = models.get(req.model.as_deref(), req.adapter.as_deref())?;
+
+    let (scores, metadata) = rank_texts(
+        &infer,
+        &info,
+        &req.query,
+        &req.texts,
+        req.truncate,
+        req.truncation_direction.map(Into::into),
+        req.raw_scores,
+        req.priority.into(),
+        start_time,
+    )
+    .await?;
+
+    let ranks = scores
+        .into_iter()
+        .map(|(index, score)| {
+            let text = if req.return_text {
+                Some(req.texts[index].clone())
+            } else {
+                None
+            };
+            Rank { index, text, score }
+        })
+        .collect();
+
+    metadata.record_span(&span);
+    metadata.record_metrics();
+
+    let headers = HeaderMap::from(metadata);
+
+    tracing::info!("Success");
+
+    Ok((headers, Json(RerankResponse(ranks))))
+}
+
+/// Cohere and Jina compatible rerank route. Returns a 424 status code if the model is not a
+/// Sequence Classification model with a single class.
+#[utoipa::path(
+post,
+tag = "Text Embeddings Inference",
+path = "/v1/rerank",
+request_body = RerankCompatRequest,
+responses(
+(status = 200, description = "Ranks", body = RerankCompatResponse),
+(status = 404, description = "Model is not served", body = ErrorResponse,
+example = json ! ({"error": "Model gpt-4 is not served", "error_type": "not_found"})),
+(status = 424, description = "Rerank Error", body = ErrorResponse,
+example = json ! ({"error": "Inference failed", "error_type": "backend"})),
+(status = 429, description = "Model is overloaded", body = ErrorResponse,
+example = json ! ({"error": "Model is overloaded", "error_type": "overloaded"})),
+(status = 422, description = "Tokenization error", body = ErrorResponse,
+example = json ! ({"error": "Tokenization error", "error_type": "tokenizer"})),
+(status = 413, description = "Batch size error", body = ErrorResponse,
+example = json ! ({"error": "Batch size error", "error_type": "validation"})),
+(status = 408, description = "Request timed out", body = ErrorResponse,
+example = json ! ({"error": "Request did not complete within 1000ms", "error_type": "timeout"})),
+)
+)]
+#[instrument(
+    skip_all,
+    fields(total_time, tokenization_time, queue_time, inference_time,)
+)]
+async fn rerank_compat(
+    models: Extension<Models>,
+    Json(req): Json<RerankCompatRequest>,
+) -> Result<(HeaderMap, Json<RerankCompatResponse>), (StatusCode, Json<ErrorResponse>)> {
+    let timeout = models.timeout(req.timeout_ms);
+    with_timeout(timeout, process_rerank_compat(models, req)).await
+}
+
+async fn process_rerank_compat(
+    models: Extension<Models>,
+    req: RerankCompatRequest,
+) -> Result<(HeaderMap, Json<RerankCompatResponse>), (StatusCode, Json<ErrorResponse>)> {
+    let span = tracing::Span::current();
+    let start_time = Instant::now();
+
+    let (infer, info) = models.get(req.model.as_deref(), None)?;
+
+    let texts: Vec<String> = req
+        .documents
+        .into_iter()
+        .map(RerankCompatDocument::into_text)
+        .collect();
+
+    let (mut scores, metadata) = rank_texts(
+        &infer,
+        &info,
+        &req.query,
+        &texts,
+        req.truncate,
+        req.truncation_direction.map(Into::into),
+        false,
+        req.priority.into(),
+        start_time,
+    )
+    .await?;
+
+    if let Some(top_n) = req.top_n {
+        scores.truncate(top_n);
+    }
+
+    let results = scores
+        .into_iter()
+        .map(|(index, relevance_score)| {
+            let document = if req.return_documents {
+                Some(RerankCompatText {
+                    text: texts[index].clone(),
+                })
+            } else {
+                None
+            };
+            RerankCompatResult {
+                index,
+                relevance_score,
+                document,
+            }
+        })
+        .collect();
+
+    metadata.record_span(&span);
+    metadata.record_metrics();
+
+    let total_tokens = metadata.compute_tokens;
+    let headers = HeaderMap::from(metadata);
+
+    tracing::info!("Success");
+
+    let response = RerankCompatResponse {
+        model: info.model_id.clone(),
+        results,
+        usage: RerankCompatUsage { total_tokens },
+    };
+    Ok((headers, Json(response)))
+}
+
+/// Score each (query, text) pair with the re-ranker model.
+/// Returns the text indices and scores sorted by decreasing score.
+async fn rank_texts(
+    infer: &Infer,
+    info: &Info,
+    query: &str,
+    texts: &[String],
+    truncate: bool,
+    truncation_direction: Option<TruncationDirection>,
+    raw_scores: bool,
+    priority: Priority,
+    start_time: Instant,
+) -> Result<(Vec<(usize, f32)>, ResponseMetadata), ErrorResponse> {
+    if texts.is_empty() {
         let message = "`texts` cannot be empty".to_string();
         tracing::error!("{message}");
         let err = ErrorResponse {
@@ -330,7 +615,15 @@ async fn rerank(
         let permit = infer.acquire_permit().await;
 
         let response = infer
-            .predict((query, text), truncate, raw_scores, permit)
+            .predict(
+                (query, text),
+                truncate,
+                truncation_direction,
+                raw_scores,
+                None,
+                priority,
+                permit,
+            )
             .await
             .map_err(ErrorResponse::from)?;
 
@@ -345,105 +638,92 @@ async fn rerank(
         ))
     };
 
-    let (response, metadata) = {
-        metrics::increment_counter!("te_request_count", "method" => "batch");
-
-        let batch_size = req.texts.len();
-        if batch_size > info.max_client_batch_size {
-            let message = format!(
-                "batch size {batch_size} > maximum allowed batch size {}",
-                info.max_client_batch_size
-            );
-            tracing::error!("{message}");
-            let err = ErrorResponse {
-                error: message,
-                error_type: ErrorType::Validation,
-            };
-            metrics::increment_counter!("te_request_failure", "err" => "batch_size");
-            Err(err)?;
-        }
-
-        let mut futures = Vec::with_capacity(batch_size);
-        let query_chars = req.query.chars().count();
-        let mut compute_chars = query_chars * batch_size;
-
-        for text in &req.texts {
-            compute_chars += text.chars().count();
-            let local_infer = infer.clone();
-            futures.push(rerank_inner(
-                req.query.clone(),
-                text.clone(),
-                req.truncate,
-                req.raw_scores,
-                local_infer.0,
-            ))
-        }
-        let results = join_all(futures)
-            .await
-            .into_iter()
-            .collect::<Result<Vec<(usize, Duration, Duration, Duration, f32)>, ErrorResponse>>()?;
-
-        let mut ranks = Vec::with_capacity(batch_size);
-        let mut total_tokenization_time = 0;
-        let mut total_queue_time = 0;
-        let mut total_inference_time = 0;
-        let mut total_compute_tokens = 0;
-
-        for (index, r) in results.into_iter().enumerate() {
-            total_compute_tokens += r.0;
-            total_tokenization_time += r.1.as_nanos() as u64;
-            total_queue_time += r.2.as_nanos() as u64;
-            total_inference_time += r.3.as_nanos() as u64;
-            let text = if req.return_text {
-                Some(req.texts[index].clone())
-            } else {
-                None
-            };
+    metrics::increment_counter!("te_request_count", "method" => "batch");
 
-            let score = r.4;
-            // Check that s is not NaN or the partial_cmp below will panic
-            if score.is_nan() {
-                Err(ErrorResponse {
-                    error: "score is NaN".to_string(),
-                    error_type: ErrorType::Backend,
-                })?;
-            }
+    let batch_size = texts.len();
+    if batch_size > info.max_client_batch_size {
+        let message = format!(
+            "batch size {batch_size} > maximum allowed batch size {}",
+            info.max_client_batch_size
+        );
+        tracing::error!("{message}");
+        let err = ErrorResponse {
+            error: message,
+            error_type: ErrorType::Validation,
+        };
+        metrics::increment_counter!("te_request_failure", "err" => "batch_size");
+        Err(err)?;
+    }
 
-            ranks.push(Rank { index, text, score })
+    let mut futures = Vec::with_capacity(batch_size);
+    let query_chars = query.chars().count();
+    let mut compute_chars = query_chars * batch_size;
+
+    for text in texts {
+        compute_chars += text.chars().count();
+        futures.push(rerank_inner(
+            query.to_string(),
+            text.clone(),
+            truncate,
+            raw_scores,
+            infer.clone(),
+        ))
+    }
+    let results = join_all(futures)
+        .await
+        .into_iter()
+        .collect::<Result<Vec<(usize, Duration, Duration, Duration, f32)>, ErrorResponse>>()?;
+
+    let mut scores = Vec::with_capacity(batch_size);
+    let mut total_tokenization_time = 0;
+    let mut total_queue_time = 0;
+    let mut total_inference_time = 0;
+    let mut total_compute_tokens = 0;
+
+    for (index, r) in results.into_iter().enumerate() {
+        total_compute_tokens += r.0;
+        total_tokenization_time += r.1.as_nanos() as u64;
+        total_queue_time += r.2.as_nanos() as u64;
+        total_inference_time += r.3.as_nanos() as u64;
+
+        let score = r.4;
+        // Check that s is not NaN or the partial_cmp below will panic
+        if score.is_nan() {
+            Err(ErrorResponse {
+                error: "score is NaN".to_string(),
+                error_type: ErrorType::Backend,
+            })?;
         }
 
-        // Reverse sort
-        ranks.sort_by(|x, y| x.score.partial_cmp(&y.score).unwrap());
-        ranks.reverse();
-
-        let batch_size = batch_size as u64;
-
-        metrics::increment_counter!("te_request_success", "method" => "batch");
+        scores.push((index, score))
+    }
 
-        (
-            RerankResponse(ranks),
-            ResponseMetadata::new(
-                compute_chars,
-                total_compute_tokens,
-                start_time,
-                Duration::from_nanos(total_tokenization_time / batch_size),
-                Duration::from_nanos(total_queue_time / batch_size),
-                Duration::from_nanos(total_inference_time / batch_size),
-            ),
-        )
-    };
+    // Reverse sort
+    scores.sort_by(|x, y| x.1.partial_cmp(&y.1).unwrap());
+    scores.reverse();
 
-    metadata.record_span(&span);
-    metadata.record_metrics();
+    let batch_size = batch_size as u64;
 
-    let headers = HeaderMap::from(metadata);
+    metrics::increment_counter!("te_request_success", "method" => "batch");
 
-    tracing::info!("Success");
+    let metadata = ResponseMetadata::new(
+        compute_chars,
+        total_compute_tokens,
+        start_time,
+        Duration::from_nanos(total_tokenization_time / batch_size),
+        Duration::from_nanos(total_queue_time / batch_size),
+        Duration::from_nanos(total_inference_time / batch_size),
+    );
 
-    Ok((headers, Json(response)))
+    Ok((scores, metadata))
 }
 
 /// Get Embeddings. Returns a 424 status code if the model is not an embedding model.
+/// Embeddings are encoded with protobuf if the `Accept` header is `application/x-protobuf`.
+/// The response is an `EmbedTruncationResponse` if `return_truncation` is set and an
+/// `EmbedPartialResponse` if `partial` is set.
+/// Embeddings are streamed as `StreamedEmbedding` lines in the order they are computed if the
+/// `Accept` header is `application/x-ndjson`.
 #[utoipa::path(
 post,
 tag = "Text Embeddings Inference",
@@ -451,6 +731,8 @@ path = "/embed",
 request_body = EmbedRequest,
 responses(
 (status = 200, description = "Embeddings", body = EmbedResponse),
+(status = 404, description = "Model is not served", body = ErrorResponse,
+example = json ! ({"error": "Model gpt-4 is not served", "error_type": "not_found"})),
 (status = 424, description = "Embedding Error", body = ErrorResponse,
 example = json ! ({"error": "Inference failed", "error_type": "backend"})),
 (status = 429, description = "Model is overloaded", body = ErrorResponse,
@@ -459,6 +741,8 @@ example = json ! ({"error": "Model is overloaded", "error_type": "overloaded"}))
 example = json ! ({"error": "Tokenization error", "error_type": "tokenizer"})),
 (status = 413, description = "Batch size error", body = ErrorResponse,
 example = json ! ({"error": "Batch size error", "error_type": "validation"})),
+(status = 408, description = "Request timed out", body = ErrorResponse,
+example = json ! ({"error": "Request did not complete within 1000ms", "error_type": "timeout"})),
 )
 )]
 #[instrument(
@@ -466,55 +750,508 @@ example = json ! ({"error": "Batch size error", "error_type": "validation"})),
     fields(total_time, tokenization_time, queue_time, inference_time,)
 )]
 async fn embed(
-    infer: Extension<Infer>,
-    info: Extension<Info>,
+    models: Extension<Models>,
+    usage: Extension<UsageCounters>,
+    api_keys: Extension<Option<ApiKeys>>,
+    request_headers: HeaderMap,
     Json(req): Json<EmbedRequest>,
-) -> Result<(HeaderMap, Json<EmbedResponse>), (StatusCode, Json<ErrorResponse>)> {
-    let span = tracing::Span::current();
-    let start_time = Instant::now();
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    let accepts = |content_type: &str| {
+        request_headers
+            .get(http::header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .map_or(false, |accept| accept.contains(content_type))
+    };
+    if accepts(NDJSON_CONTENT_TYPE) {
+        return stream_embed(models, usage.0, api_keys.0, &request_headers, req);
+    }
 
-    let (response, metadata) = match req.inputs {
-        Input::Single(input) => {
-            metrics::increment_counter!("te_request_count", "method" => "single");
+    let timeout = models.timeout(req.timeout_ms);
+    let return_truncation = req.return_truncation;
+    let return_usage = req.return_usage;
+    let partial = req.partial;
+    let (mut headers, results) = with_timeout(timeout, process_embed(models, req)).await?;
 
-            let compute_chars = input.chars().count();
+    if partial {
+        let response = EmbedPartialResponse(
+            results
+                .into_iter()
+                .map(|result| partial_embedding(result, return_truncation))
+                .collect(),
+        );
+        return Ok(with_usage(headers, response, return_usage));
+    }
+    // Every input was embedded
+    let (embeddings, truncation): (Vec<_>, Vec<_>) = results.into_iter().flatten().unzip();
+    let response = EmbedResponse(embeddings);
+
+    if accepts(PROTOBUF_CONTENT_TYPE) {
+        let body = ProtobufEmbedResponse::from(response).encode_to_vec();
+        headers.insert(
+            http::header::CONTENT_TYPE,
+            HeaderValue::from_static(PROTOBUF_CONTENT_TYPE),
+        );
+        Ok((headers, body).into_response())
+    } else if return_truncation {
+        let response = EmbedTruncationResponse {
+            embeddings: response.0,
+            truncation,
+        };
+        Ok(with_usage(headers, response, return_usage))
+    } else {
+        Ok(with_usage(headers, response, return_usage))
+    }
+}
 
-            let permit = infer.try_acquire_permit().map_err(ErrorResponse::from)?;
-            let response = infer
-                .embed_pooled(input, req.truncate, req.normalize, permit)
-                .await
-                .map_err(ErrorResponse::from)?;
+/// Usage of a request, from the `x-compute-tokens` header of its response
+fn request_usage(headers: &HeaderMap) -> OpenAICompatUsage {
+    let prompt_tokens = headers
+        .get("x-compute-tokens")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0);
+    OpenAICompatUsage {
+        prompt_tokens,
+        total_tokens: prompt_tokens,
+    }
+}
 
-            metrics::increment_counter!("te_request_success", "method" => "single");
+/// JSON response, wrapped in a `UsageWrapper` if the request has `return_usage`
+fn with_usage<T: Serialize>(headers: HeaderMap, response: T, return_usage: bool) -> Response {
+    if return_usage {
+        let usage = request_usage(&headers);
+        let response = UsageWrapper {
+            data: response,
+            usage,
+        };
+        (headers, Json(response)).into_response()
+    } else {
+        (headers, Json(response)).into_response()
+    }
+}
 
-            (
-                EmbedResponse(vec![response.results]),
-                ResponseMetadata::new(
-                    compute_chars,
-                    response.metadata.prompt_tokens,
-                    start_time,
-                    response.metadata.tokenization,
-                    response.metadata.queue,
-                    response.metadata.inference,
-                ),
-            )
-        }
-        Input::Batch(inputs) => {
-            metrics::increment_counter!("te_request_count", "method" => "batch");
+/// Embedding and truncation of an input, or the reason it could not be embedded
+type EmbedOutput = Result<(Vec<f32>, InputTruncation), ErrorResponse>;
 
-            if inputs.is_empty() {
-                let message = "`inputs` cannot be empty".to_string();
-                tracing::error!("{message}");
-                let err = ErrorResponse {
-                    error: message,
-                    error_type: ErrorType::Validation,
-                };
-                metrics::increment_counter!("te_request_failure", "err" => "validation");
-                Err(err)?;
-            }
+fn partial_embedding(result: EmbedOutput, return_truncation: bool) -> PartialEmbedding {
+    match result {
+        Ok((embedding, truncation)) => PartialEmbedding {
+            embedding: Some(embedding),
+            error: None,
+            truncation: return_truncation.then_some(truncation),
+        },
+        Err(err) => PartialEmbedding {
+            embedding: None,
+            error: Some(err),
+            truncation: None,
+        },
+    }
+}
 
-            let batch_size = inputs.len();
-            if batch_size > info.max_client_batch_size {
+/// Stream the embeddings of `req` as NDJSON lines as soon as the batch of their input is
+/// computed. The status is sent before the inputs are embedded: inputs that could not be embedded
+/// get an error line instead of failing the request, as with `partial`.
+/// As the headers are sent before the token count is known, the tokens of every line are counted
+/// towards the quota of the API key and the usage of the tenant when the line is sent
+fn stream_embed(
+    models: Extension<Models>,
+    usage: UsageCounters,
+    api_keys: Option<ApiKeys>,
+    request_headers: &HeaderMap,
+    req: EmbedRequest,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    let (infer, info) = models.get(req.model.as_deref(), req.adapter.as_deref())?;
+
+    let prompt = get_prompt(&info, req.prompt_name.as_deref())?;
+    let inputs = match req.inputs.with_prompt(prompt) {
+        Input::Single(input) => vec![input],
+        Input::Batch(inputs) => inputs,
+    };
+    metrics::increment_counter!("te_request_count", "method" => "stream");
+
+    if inputs.is_empty() {
+        let message = "`inputs` cannot be empty".to_string();
+        tracing::error!("{message}");
+        let err = ErrorResponse {
+            error: message,
+            error_type: ErrorType::Validation,
+        };
+        metrics::increment_counter!("te_request_failure", "err" => "validation");
+        Err(err)?;
+    }
+
+    let batch_size = inputs.len();
+    if batch_size > info.max_client_batch_size {
+        let message = format!(
+            "batch size {batch_size} > maximum allowed batch size {}",
+            info.max_client_batch_size
+        );
+        tracing::error!("{message}");
+        let err = ErrorResponse {
+            error: message,
+            error_type: ErrorType::Validation,
+        };
+        metrics::increment_counter!("te_request_failure", "err" => "batch_size");
+        Err(err)?;
+    }
+
+    let api_key = bearer_token(request_headers);
+    let tenant = usage.tenant(request_headers, api_key.as_deref());
+    usage.record(tenant.clone(), 0);
+
+    // The deadline applies to the whole stream
+    let deadline = models
+        .timeout(req.timeout_ms)
+        .map(|timeout| (tokio::time::Instant::now() + timeout, timeout));
+    let max_input_length = info.max_input_length;
+
+    let embeddings: FuturesUnordered<_> = inputs
+        .into_iter()
+        .enumerate()
+        .map(move |(index, input)| {
+            let local_infer = infer.clone();
+            async move {
+                let embed = async move {
+                    let response = match req.truncation_strategy {
+                        TruncationStrategy::Head => {
+                            let permit = local_infer.acquire_permit().await;
+                            local_infer
+                                .embed_pooled(
+                                    input,
+                                    req.truncate,
+                                    req.truncation_direction.map(Into::into),
+                                    req.normalize,
+                                    req.dimensions,
+                                    req.pooling.map(Into::into),
+                                    req.priority.into(),
+                                    permit,
+                                )
+                                .await
+                        }
+                        TruncationStrategy::SlidingWindowMean => {
+                            embed_sliding_window_mean(
+                                local_infer,
+                                input,
+                                req.normalize,
+                                req.dimensions,
+                                req.pooling.map(Into::into),
+                                req.priority.into(),
+                                max_input_length,
+                            )
+                            .await
+                        }
+                    };
+                    response
+                        .map(|r| {
+                            let truncation = InputTruncation::from(&r.metadata);
+                            (r.results, truncation)
+                        })
+                        .map_err(ErrorResponse::from)
+                };
+                let result = match deadline {
+                    Some((deadline, timeout)) => tokio::time::timeout_at(deadline, embed)
+                        .await
+                        .unwrap_or_else(|_| Err(timeout_error(timeout))),
+                    None => embed.await,
+                };
+                let tokens = result
+                    .as_ref()
+                    .map_or(0, |(_, truncation)| truncation.tokens_processed);
+                let embedding = StreamedEmbedding {
+                    index,
+                    result: partial_embedding(result, req.return_truncation),
+                };
+                (tokens as u64, embedding)
+            }
+        })
+        .collect();
+
+    let lines = embeddings.map(move |(tokens, embedding)| {
+        if let (Some(api_keys), Some(key)) = (&api_keys, &api_key) {
+            api_keys.record_tokens(key, tokens);
+        }
+        usage.record_tokens(tenant.clone(), tokens);
+        let mut line = serde_json::to_vec(&embedding)?;
+        line.push(b'\n');
+        Ok::<_, serde_json::Error>(line)
+    });
+    let headers = [(
+        http::header::CONTENT_TYPE,
+        HeaderValue::from_static(NDJSON_CONTENT_TYPE),
+    )];
+    Ok((headers, StreamBody::new(lines)).into_response())
+}
+
+/// Embed inputs sent as `WsEmbedRequest` frames on a single WebSocket connection, without the
+/// overhead of an HTTP request for every query. Requests are processed concurrently and every
+/// one of them is answered with a `WsEmbedResponse` frame as soon as it completes.
+/// Every frame counts as a request in the API key quotas, the usage counters, the rate limits
+/// and `--max-in-flight-requests`. Up to 64 requests of a connection are processed at the same
+/// time: further frames are read once one of them completes.
+#[utoipa::path(
+get,
+tag = "Text Embeddings Inference",
+path = "/ws",
+responses(
+(status = 101, description = "Switching to the WebSocket protocol"),
+)
+)]
+async fn embed_ws(
+    models: Extension<Models>,
+    usage: Extension<UsageCounters>,
+    api_keys: Extension<Option<ApiKeys>>,
+    limits: Extension<RequestLimits>,
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    let api_key = bearer_token(&headers);
+    let session = WsSession {
+        tenant: usage.tenant(&headers, api_key.as_deref()),
+        models: models.0,
+        usage: usage.0,
+        api_keys: api_keys.0,
+        limits: limits.0,
+        client_ip: client_addr.ip(),
+        api_key,
+    };
+    upgrade.on_upgrade(move |socket| session.run(socket))
+}
+
+/// WebSocket connection of `/ws`, with the client identity of its upgrade request
+#[derive(Clone)]
+struct WsSession {
+    models: Models,
+    usage: UsageCounters,
+    api_keys: Option<ApiKeys>,
+    limits: RequestLimits,
+    client_ip: IpAddr,
+    api_key: Option<String>,
+    tenant: String,
+}
+
+impl WsSession {
+    async fn run(self, socket: WebSocket) {
+        let (mut sender, mut receiver) = socket.split();
+
+        // Responses are sent in the order the requests complete
+        let (response_tx, mut response_rx) = mpsc::channel::<WsEmbedResponse>(WS_MAX_IN_FLIGHT);
+        let in_flight = Arc::new(Semaphore::new(WS_MAX_IN_FLIGHT));
+        let writer = tokio::spawn(async move {
+            while let Some(response) = response_rx.recv().await {
+                let Ok(text) = serde_json::to_string(&response) else {
+                    continue;
+                };
+                if sender.send(ws::Message::Text(text)).await.is_err() {
+                    // The client closed the connection
+                    break;
+                }
+            }
+        });
+
+        while let Some(Ok(message)) = receiver.next().await {
+            let request = match message {
+                ws::Message::Text(text) => serde_json::from_str::<WsEmbedRequest>(&text),
+                ws::Message::Binary(bytes) => serde_json::from_slice::<WsEmbedRequest>(&bytes),
+                ws::Message::Close(_) => break,
+                // Pings are answered by the WebSocket implementation
+                ws::Message::Ping(_) | ws::Message::Pong(_) => continue,
+            };
+            match request {
+                Ok(request) => {
+                    // Stop reading frames until a request of the session completes
+                    let Ok(session_permit) = in_flight.clone().acquire_owned().await else {
+                        break;
+                    };
+                    let permit = match self.admit() {
+                        Ok(permit) => permit,
+                        Err(err) => {
+                            let _ = response_tx
+                                .send(WsEmbedResponse {
+                                    id: request.id,
+                                    results: Vec::new(),
+                                    error: Some(err),
+                                    usage: None,
+                                })
+                                .await;
+                            continue;
+                        }
+                    };
+                    let session = self.clone();
+                    let response_tx = response_tx.clone();
+                    tokio::spawn(async move {
+                        let _permits = (session_permit, permit);
+                        let _ = response_tx.send(session.embed(request).await).await;
+                    });
+                }
+                Err(err) => {
+                    let message = format!("Invalid request: {err}");
+                    tracing::error!("{message}");
+                    metrics::increment_counter!("te_request_failure", "err" => "validation");
+                    let _ = response_tx
+                        .send(WsEmbedResponse {
+                            id: None,
+                            results: Vec::new(),
+                            error: Some(ErrorResponse {
+                                error: message,
+                                error_type: ErrorType::Validation,
+                            }),
+                            usage: None,
+                        })
+                        .await;
+                }
+            }
+        }
+
+        // The writer stops once the in-flight requests are answered
+        drop(response_tx);
+        let _ = writer.await;
+    }
+
+    /// Check the API key and the rate limit of a frame as if it was a request, and take a permit
+    /// of the requests in flight to hold until it is answered
+    fn admit(&self) -> Result<Option<OwnedSemaphorePermit>, ErrorResponse> {
+        if let Some(api_keys) = &self.api_keys {
+            api_keys.authorize(self.api_key.as_deref())?;
+        }
+        self.limits
+            .try_acquire(self.api_key.as_deref(), self.client_ip)
+            .map_err(|(message, _)| {
+                metrics::increment_counter!("te_request_failure", "err" => "rate_limited");
+                tracing::error!("{message}");
+                ErrorResponse {
+                    error: message.to_string(),
+                    error_type: ErrorType::Overloaded,
+                }
+            })
+    }
+
+    #[instrument(
+        skip_all,
+        fields(total_time, tokenization_time, queue_time, inference_time,)
+    )]
+    async fn embed(self, request: WsEmbedRequest) -> WsEmbedResponse {
+        let id = request.id;
+        let req = request.request;
+        let timeout = self.models.timeout(req.timeout_ms);
+        let return_truncation = req.return_truncation;
+        let return_usage = req.return_usage;
+        let models = Extension(self.models);
+        match with_timeout(timeout, process_embed(models, req)).await {
+            Ok((headers, results)) => {
+                let tokens = headers
+                    .get("x-compute-tokens")
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.parse().ok());
+                if let Some(tokens) = tokens {
+                    if let (Some(api_keys), Some(key)) = (&self.api_keys, &self.api_key) {
+                        api_keys.record_tokens(key, tokens);
+                    }
+                    self.usage.record(self.tenant, tokens);
+                }
+                WsEmbedResponse {
+                    id,
+                    results: results
+                        .into_iter()
+                        .map(|result| partial_embedding(result, return_truncation))
+                        .collect(),
+                    error: None,
+                    usage: return_usage.then(|| request_usage(&headers)),
+                }
+            }
+            Err((_, Json(err))) => WsEmbedResponse {
+                id,
+                results: Vec::new(),
+                error: Some(err),
+                usage: None,
+            },
+        }
+    }
+}
+
+/// Embedding and truncation of every input. Inputs that could not be embedded are errors if the
+/// request is `partial` and fail the request otherwise
+async fn process_embed(
+    models: Extension<Models>,
+    req: EmbedRequest,
+) -> Result<(HeaderMap, Vec<EmbedOutput>), (StatusCode, Json<ErrorResponse>)> {
+    let span = tracing::Span::current();
+    let start_time = Instant::now();
+
+    let (infer, info) = models.get(req.model.as_deref(), req.adapter.as_deref())?;
+
+    let prompt = get_prompt(&info, req.prompt_name.as_deref())?;
+    let (results, metadata) = match req.inputs.with_prompt(prompt) {
+        Input::Single(input) => {
+            metrics::increment_counter!("te_request_count", "method" => "single");
+
+            let compute_chars = input.count_chars();
+
+            let permit = infer.try_acquire_permit().map_err(ErrorResponse::from)?;
+            let response = match req.truncation_strategy {
+                TruncationStrategy::Head => {
+                    infer
+                        .embed_pooled(
+                            input,
+                            req.truncate,
+                            req.truncation_direction.map(Into::into),
+                            req.normalize,
+                            req.dimensions,
+                            req.pooling.map(Into::into),
+                            req.priority.into(),
+                            permit,
+                        )
+                        .await
+                }
+                TruncationStrategy::SlidingWindowMean => {
+                    // Every window acquires its own permit
+                    drop(permit);
+                    embed_sliding_window_mean(
+                        infer,
+                        input,
+                        req.normalize,
+                        req.dimensions,
+                        req.pooling.map(Into::into),
+                        req.priority.into(),
+                        info.max_input_length,
+                    )
+                    .await
+                }
+            }
+            .map_err(ErrorResponse::from)?;
+
+            metrics::increment_counter!("te_request_success", "method" => "single");
+
+            let truncation = InputTruncation::from(&response.metadata);
+            (
+                vec![Ok((response.results, truncation))],
+                ResponseMetadata::new(
+                    compute_chars,
+                    response.metadata.prompt_tokens,
+                    start_time,
+                    response.metadata.tokenization,
+                    response.metadata.queue,
+                    response.metadata.inference,
+                )
+                .with_batch_id(response.metadata.batch_id),
+            )
+        }
+        Input::Batch(inputs) => {
+            metrics::increment_counter!("te_request_count", "method" => "batch");
+
+            if inputs.is_empty() {
+                let message = "`inputs` cannot be empty".to_string();
+                tracing::error!("{message}");
+                let err = ErrorResponse {
+                    error: message,
+                    error_type: ErrorType::Validation,
+                };
+                metrics::increment_counter!("te_request_failure", "err" => "validation");
+                Err(err)?;
+            }
+
+            let batch_size = inputs.len();
+            if batch_size > info.max_client_batch_size {
                 let message = format!(
                     "batch size {batch_size} > maximum allowed batch size {}",
                     info.max_client_batch_size
@@ -532,41 +1269,82 @@ async fn embed(
             let mut compute_chars = 0;
 
             for input in inputs {
-                compute_chars += input.chars().count();
+                compute_chars += input.count_chars();
 
                 let local_infer = infer.clone();
+                let max_input_length = info.max_input_length;
                 futures.push(async move {
-                    let permit = local_infer.acquire_permit().await;
-                    local_infer
-                        .embed_pooled(input, req.truncate, req.normalize, permit)
-                        .await
+                    match req.truncation_strategy {
+                        TruncationStrategy::Head => {
+                            let permit = local_infer.acquire_permit().await;
+                            local_infer
+                                .embed_pooled(
+                                    input,
+                                    req.truncate,
+                                    req.truncation_direction.map(Into::into),
+                                    req.normalize,
+                                    req.dimensions,
+                                    req.pooling.map(Into::into),
+                                    req.priority.into(),
+                                    permit,
+                                )
+                                .await
+                        }
+                        TruncationStrategy::SlidingWindowMean => {
+                            embed_sliding_window_mean(
+                                local_infer,
+                                input,
+                                req.normalize,
+                                req.dimensions,
+                                req.pooling.map(Into::into),
+                                req.priority.into(),
+                                max_input_length,
+                            )
+                            .await
+                        }
+                    }
                 })
             }
-            let results = join_all(futures)
-                .await
-                .into_iter()
-                .collect::<Result<Vec<PooledEmbeddingsInferResponse>, TextEmbeddingsError>>()
-                .map_err(ErrorResponse::from)?;
+            let mut results = join_all(futures).await;
+            if !req.partial {
+                // The first input that could not be embedded fails the request
+                if let Some(i) = results.iter().position(Result::is_err) {
+                    if let Err(err) = results.swap_remove(i) {
+                        Err(ErrorResponse::from(err))?;
+                    }
+                }
+            }
 
-            let mut embeddings = Vec::with_capacity(batch_size);
+            let mut outputs = Vec::with_capacity(batch_size);
+            let mut embedded = 0;
             let mut total_tokenization_time = 0;
             let mut total_queue_time = 0;
             let mut total_inference_time = 0;
             let mut total_compute_tokens = 0;
 
-            for r in results {
+            for result in results {
+                let r = match result {
+                    Ok(r) => r,
+                    Err(err) => {
+                        outputs.push(Err(ErrorResponse::from(err)));
+                        continue;
+                    }
+                };
+                embedded += 1;
                 total_tokenization_time += r.metadata.tokenization.as_nanos() as u64;
                 total_queue_time += r.metadata.queue.as_nanos() as u64;
                 total_inference_time += r.metadata.inference.as_nanos() as u64;
                 total_compute_tokens += r.metadata.prompt_tokens;
-                embeddings.push(r.results);
+                let truncation = InputTruncation::from(&r.metadata);
+                outputs.push(Ok((r.results, truncation)));
             }
-            let batch_size = batch_size as u64;
+            // Timings are averaged over the embedded inputs
+            let batch_size = embedded.max(1) as u64;
 
             metrics::increment_counter!("te_request_success", "method" => "batch");
 
             (
-                EmbedResponse(embeddings),
+                outputs,
                 ResponseMetadata::new(
                     compute_chars,
                     total_compute_tokens,
@@ -586,18 +1364,20 @@ async fn embed(
 
     tracing::info!("Success");
 
-    Ok((headers, Json(response)))
+    Ok((headers, results))
 }
 
-/// Get all Embeddings without Pooling.
-/// Returns a 424 status code if the model is not an embedding model.
+/// Get Sparse Embeddings. Returns a 424 status code if the model is not an embedding model with
+/// SPLADE pooling.
 #[utoipa::path(
 post,
 tag = "Text Embeddings Inference",
-path = "/embed_all",
-request_body = EmbedAllRequest,
+path = "/embed_sparse",
+request_body = EmbedSparseRequest,
 responses(
-(status = 200, description = "Embeddings", body = EmbedAllResponse),
+(status = 200, description = "Sparse Embeddings", body = EmbedSparseResponse),
+(status = 404, description = "Model is not served", body = ErrorResponse,
+example = json ! ({"error": "Model gpt-4 is not served", "error_type": "not_found"})),
 (status = 424, description = "Embedding Error", body = ErrorResponse,
 example = json ! ({"error": "Inference failed", "error_type": "backend"})),
 (status = 429, description = "Model is overloaded", body = ErrorResponse,
@@ -606,36 +1386,61 @@ example = json ! ({"error": "Model is overloaded", "error_type": "overloaded"}))
 example = json ! ({"error": "Tokenization error", "error_type": "tokenizer"})),
 (status = 413, description = "Batch size error", body = ErrorResponse,
 example = json ! ({"error": "Batch size error", "error_type": "validation"})),
+(status = 408, description = "Request timed out", body = ErrorResponse,
+example = json ! ({"error": "Request did not complete within 1000ms", "error_type": "timeout"})),
 )
 )]
 #[instrument(
     skip_all,
     fields(total_time, tokenization_time, queue_time, inference_time,)
 )]
-async fn embed_all(
-    infer: Extension<Infer>,
-    info: Extension<Info>,
-    Json(req): Json<EmbedAllRequest>,
-) -> Result<(HeaderMap, Json<EmbedAllResponse>), (StatusCode, Json<ErrorResponse>)> {
+async fn embed_sparse(
+    models: Extension<Models>,
+    Json(req): Json<EmbedSparseRequest>,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    let timeout = models.timeout(req.timeout_ms);
+    let return_usage = req.return_usage;
+    let (headers, Json(response)) = with_timeout(timeout, process_embed_sparse(models, req)).await?;
+    Ok(with_usage(headers, response, return_usage))
+}
+
+async fn process_embed_sparse(
+    models: Extension<Models>,
+    req: EmbedSparseRequest,
+) -> Result<(HeaderMap, Json<EmbedSparseResponse>), (StatusCode, Json<ErrorResponse>)> {
     let span = tracing::Span::current();
     let start_time = Instant::now();
 
-    let (response, metadata) = match req.inputs {
+    let (infer, info) = models.get(req.model.as_deref(), req.adapter.as_deref())?;
+
+    check_splade(&info)?;
+
+    let prompt = get_prompt(&info, req.prompt_name.as_deref())?;
+    let (response, metadata) = match req.inputs.with_prompt(prompt) {
         Input::Single(input) => {
             metrics::increment_counter!("te_request_count", "method" => "single");
 
-            let compute_chars = input.chars().count();
+            let compute_chars = input.count_chars();
 
             let permit = infer.try_acquire_permit().map_err(ErrorResponse::from)?;
             let response = infer
-                .embed_all(input, req.truncate, permit)
+                .embed_pooled(
+                    input,
+                    req.truncate,
+                    req.truncation_direction.map(Into::into),
+                    false,
+                    None,
+                    None,
+                    req.priority.into(),
+                    permit,
+                )
                 .await
                 .map_err(ErrorResponse::from)?;
 
             metrics::increment_counter!("te_request_success", "method" => "single");
 
             (
-                EmbedAllResponse(vec![response.results]),
+                EmbedSparseResponse(vec![SparseValue::sparsify(response.results)]),
                 ResponseMetadata::new(
                     compute_chars,
                     response.metadata.prompt_tokens,
@@ -643,7 +1448,8 @@ async fn embed_all(
                     response.metadata.tokenization,
                     response.metadata.queue,
                     response.metadata.inference,
-                ),
+                )
+                .with_batch_id(response.metadata.batch_id),
             )
         }
         Input::Batch(inputs) => {
@@ -679,18 +1485,29 @@ async fn embed_all(
             let mut compute_chars = 0;
 
             for input in inputs {
-                compute_chars += input.chars().count();
+                compute_chars += input.count_chars();
 
                 let local_infer = infer.clone();
                 futures.push(async move {
                     let permit = local_infer.acquire_permit().await;
-                    local_infer.embed_all(input, req.truncate, permit).await
+                    local_infer
+                        .embed_pooled(
+                            input,
+                            req.truncate,
+                            req.truncation_direction.map(Into::into),
+                            false,
+                            None,
+                            None,
+                            req.priority.into(),
+                            permit,
+                        )
+                        .await
                 })
             }
             let results = join_all(futures)
                 .await
                 .into_iter()
-                .collect::<Result<Vec<AllEmbeddingsInferResponse>, TextEmbeddingsError>>()
+                .collect::<Result<Vec<PooledEmbeddingsInferResponse>, TextEmbeddingsError>>()
                 .map_err(ErrorResponse::from)?;
 
             let mut embeddings = Vec::with_capacity(batch_size);
@@ -704,14 +1521,14 @@ async fn embed_all(
                 total_queue_time += r.metadata.queue.as_nanos() as u64;
                 total_inference_time += r.metadata.inference.as_nanos() as u64;
                 total_compute_tokens += r.metadata.prompt_tokens;
-                embeddings.push(r.results);
+                embeddings.push(SparseValue::sparsify(r.results));
             }
             let batch_size = batch_size as u64;
 
             metrics::increment_counter!("te_request_success", "method" => "batch");
 
             (
-                EmbedAllResponse(embeddings),
+                EmbedSparseResponse(embeddings),
                 ResponseMetadata::new(
                     compute_chars,
                     total_compute_tokens,
@@ -734,78 +1551,884 @@ async fn embed_all(
     Ok((headers, Json(response)))
 }
 
-/// OpenAI compatible route. Returns a 424 status code if the model is not an embedding model.
+/// Sparse embeddings are only produced by models using SPLADE pooling
+fn check_splade(info: &Info) -> Result<(), ErrorResponse> {
+    match &info.model_type {
+        ModelType::Embedding(embedding) if embedding.pooling == "splade" => Ok(()),
+        _ => {
+            metrics::increment_counter!("te_request_failure", "err" => "model_type");
+            let message = "model does not use SPLADE pooling".to_string();
+            Err(TextEmbeddingsError::Backend(BackendError::Inference(
+                message,
+            )))
+        }
+    }
+    .map_err(|err| {
+        tracing::error!("{err}");
+        ErrorResponse::from(err)
+    })
+}
+
+/// Get Embeddings of overlapping chunks of inputs longer than the model maximum input length.
+/// Returns a 424 status code if the model is not an embedding model.
 #[utoipa::path(
 post,
 tag = "Text Embeddings Inference",
-path = "/embeddings",
-request_body = OpenAICompatRequest,
+path = "/embed_chunks",
+request_body = EmbedChunksRequest,
 responses(
-(status = 200, description = "Embeddings", body = OpenAICompatResponse),
-(status = 424, description = "Embedding Error", body = OpenAICompatErrorResponse,
-example = json ! ({"message": "Inference failed", "type": "backend"})),
-(status = 429, description = "Model is overloaded", body = OpenAICompatErrorResponse,
-example = json ! ({"message": "Model is overloaded", "type": "overloaded"})),
-(status = 422, description = "Tokenization error", body = OpenAICompatErrorResponse,
-example = json ! ({"message": "Tokenization error", "type": "tokenizer"})),
-(status = 413, description = "Batch size error", body = OpenAICompatErrorResponse,
-example = json ! ({"message": "Batch size error", "type": "validation"})),
+(status = 200, description = "Chunk Embeddings", body = EmbedChunksResponse),
+(status = 404, description = "Model is not served", body = ErrorResponse,
+example = json ! ({"error": "Model gpt-4 is not served", "error_type": "not_found"})),
+(status = 424, description = "Embedding Error", body = ErrorResponse,
+example = json ! ({"error": "Inference failed", "error_type": "backend"})),
+(status = 429, description = "Model is overloaded", body = ErrorResponse,
+example = json ! ({"error": "Model is overloaded", "error_type": "overloaded"})),
+(status = 422, description = "Tokenization error", body = ErrorResponse,
+example = json ! ({"error": "Tokenization error", "error_type": "tokenizer"})),
+(status = 413, description = "Batch size error", body = ErrorResponse,
+example = json ! ({"error": "Batch size error", "error_type": "validation"})),
+(status = 408, description = "Request timed out", body = ErrorResponse,
+example = json ! ({"error": "Request did not complete within 1000ms", "error_type": "timeout"})),
 )
 )]
 #[instrument(
     skip_all,
     fields(total_time, tokenization_time, queue_time, inference_time,)
 )]
-async fn openai_embed(
-    infer: Extension<Infer>,
-    info: Extension<Info>,
-    Json(req): Json<OpenAICompatRequest>,
-) -> Result<(HeaderMap, Json<OpenAICompatResponse>), (StatusCode, Json<OpenAICompatErrorResponse>)>
-{
+async fn embed_chunks(
+    models: Extension<Models>,
+    Json(req): Json<EmbedChunksRequest>,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    let timeout = models.timeout(req.timeout_ms);
+    let return_usage = req.return_usage;
+    let (headers, Json(response)) = with_timeout(timeout, process_embed_chunks(models, req)).await?;
+    Ok(with_usage(headers, response, return_usage))
+}
+
+async fn process_embed_chunks(
+    models: Extension<Models>,
+    req: EmbedChunksRequest,
+) -> Result<(HeaderMap, Json<EmbedChunksResponse>), (StatusCode, Json<ErrorResponse>)> {
     let span = tracing::Span::current();
     let start_time = Instant::now();
 
-    let (embeddings, metadata) = match req.input {
-        Input::Single(input) => {
-            metrics::increment_counter!("te_request_count", "method" => "single");
+    let (infer, info) = models.get(req.model.as_deref(), req.adapter.as_deref())?;
 
-            let compute_chars = input.chars().count();
+    let method = match &req.inputs {
+        Input::Single(_) => "single",
+        Input::Batch(_) => "batch",
+    };
+    metrics::increment_counter!("te_request_count", "method" => method);
 
-            let permit = infer.try_acquire_permit().map_err(ErrorResponse::from)?;
-            let response = infer
-                .embed_pooled(input, false, true, permit)
-                .await
-                .map_err(ErrorResponse::from)?;
+    let inputs = match req.inputs {
+        Input::Single(input) => vec![input],
+        Input::Batch(inputs) => inputs,
+    };
+    // Chunks are slices of the text of the inputs
+    let inputs = inputs
+        .into_iter()
+        .map(InputType::into_text)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(ErrorResponse::from)?;
+    if inputs.is_empty() {
+        let message = "`inputs` cannot be empty".to_string();
+        tracing::error!("{message}");
+        let err = ErrorResponse {
+            error: message,
+            error_type: ErrorType::Validation,
+        };
+        metrics::increment_counter!("te_request_failure", "err" => "validation");
+        Err(err)?;
+    }
 
-            metrics::increment_counter!("te_request_success", "method" => "single");
+    let batch_size = inputs.len();
+    if batch_size > info.max_client_batch_size {
+        let message = format!(
+            "batch size {batch_size} > maximum allowed batch size {}",
+            info.max_client_batch_size
+        );
+        tracing::error!("{message}");
+        let err = ErrorResponse {
+            error: message,
+            error_type: ErrorType::Validation,
+        };
+        metrics::increment_counter!("te_request_failure", "err" => "batch_size");
+        Err(err)?;
+    }
 
-            (
-                vec![OpenAICompatEmbedding {
-                    object: "embedding",
-                    embedding: response.results,
-                    index: 0,
-                }],
-                ResponseMetadata::new(
-                    compute_chars,
-                    response.metadata.prompt_tokens,
-                    start_time,
-                    response.metadata.tokenization,
-                    response.metadata.queue,
-                    response.metadata.inference,
-                ),
-            )
+    let chunk_size = req.chunk_size.unwrap_or(info.max_input_length);
+    if req.chunk_overlap >= chunk_size {
+        let message = format!(
+            "`chunk_overlap` {} must be smaller than `chunk_size` {chunk_size}",
+            req.chunk_overlap
+        );
+        tracing::error!("{message}");
+        let err = ErrorResponse {
+            error: message,
+            error_type: ErrorType::Validation,
+        };
+        metrics::increment_counter!("te_request_failure", "err" => "validation");
+        Err(err)?;
+    }
+
+    let mut futures = Vec::with_capacity(batch_size);
+    let mut compute_chars = 0;
+
+    for input in inputs {
+        compute_chars += input.chars().count();
+
+        futures.push(embed_input_chunks(
+            infer.clone(),
+            input,
+            chunk_size,
+            req.chunk_overlap,
+            req.normalize,
+            None,
+            None,
+            req.priority.into(),
+            info.max_input_length,
+        ))
+    }
+    let results = join_all(futures)
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>, TextEmbeddingsError>>()
+        .map_err(ErrorResponse::from)?;
+
+    let mut chunks = Vec::with_capacity(batch_size);
+    let mut total_tokenization_time = 0;
+    let mut total_queue_time = 0;
+    let mut total_inference_time = 0;
+    let mut total_compute_tokens = 0;
+    let mut total_chunks = 0;
+
+    for input_chunks in results {
+        let mut local_chunks = Vec::with_capacity(input_chunks.len());
+        for (start, stop, r) in input_chunks {
+            total_tokenization_time += r.metadata.tokenization.as_nanos() as u64;
+            total_queue_time += r.metadata.queue.as_nanos() as u64;
+            total_inference_time += r.metadata.inference.as_nanos() as u64;
+            total_compute_tokens += r.metadata.prompt_tokens;
+            total_chunks += 1;
+            local_chunks.push(Chunk {
+                embedding: r.results,
+                start,
+                stop,
+            });
         }
-        Input::Batch(inputs) => {
-            metrics::increment_counter!("te_request_count", "method" => "batch");
+        chunks.push(local_chunks);
+    }
+    let total_chunks = total_chunks.max(1);
 
-            if inputs.is_empty() {
-                let message = "`inputs` cannot be empty".to_string();
-                tracing::error!("{message}");
-                let err = ErrorResponse {
-                    error: message,
-                    error_type: ErrorType::Validation,
-                };
-                metrics::increment_counter!("te_request_failure", "err" => "validation");
+    metrics::increment_counter!("te_request_success", "method" => method);
+
+    let metadata = ResponseMetadata::new(
+        compute_chars,
+        total_compute_tokens,
+        start_time,
+        Duration::from_nanos(total_tokenization_time / total_chunks),
+        Duration::from_nanos(total_queue_time / total_chunks),
+        Duration::from_nanos(total_inference_time / total_chunks),
+    );
+
+    metadata.record_span(&span);
+    metadata.record_metrics();
+
+    let headers = HeaderMap::from(metadata);
+
+    tracing::info!("Success");
+
+    Ok((headers, Json(EmbedChunksResponse(chunks))))
+}
+
+/// Embed overlapping chunks of `input` of at most `chunk_size` tokens.
+/// Returns the byte offsets of every chunk in `input` with its embedding.
+#[allow(clippy::too_many_arguments)]
+async fn embed_input_chunks(
+    infer: Infer,
+    input: String,
+    chunk_size: usize,
+    chunk_overlap: usize,
+    normalize: bool,
+    dimensions: Option<usize>,
+    pooling: Option<Pool>,
+    priority: Priority,
+    max_input_length: usize,
+) -> Result<Vec<(usize, usize, PooledEmbeddingsInferResponse)>, TextEmbeddingsError> {
+    let encoding = infer.tokenize(input.clone(), true).await?;
+
+    // The special tokens are added to every chunk
+    let special_tokens_mask = encoding.get_special_tokens_mask();
+    let num_special_tokens = special_tokens_mask.iter().filter(|&&m| m == 1).count();
+    let chunk_size = chunk_size.min(max_input_length.saturating_sub(num_special_tokens));
+    let step = chunk_size.saturating_sub(chunk_overlap).max(1);
+
+    let offsets: Vec<(usize, usize)> = encoding
+        .get_offsets()
+        .iter()
+        .zip(special_tokens_mask)
+        .filter(|(_, special)| **special == 0)
+        .map(|(offsets, _)| *offsets)
+        .collect();
+
+    let mut spans = chunk_spans(&offsets, chunk_size, step);
+    if spans.is_empty() {
+        // The input only contains special tokens
+        spans.push((0, input.len()));
+    }
+
+    let futures = spans.into_iter().map(|(start, stop)| {
+        // The token offsets are byte offsets
+        let text = input[start..stop].to_string();
+        let infer = infer.clone();
+        async move {
+            let permit = infer.acquire_permit().await;
+            // Re-tokenization can merge tokens differently at the chunk boundaries
+            infer
+                .embed_pooled(text, true, None, normalize, dimensions, pooling, priority, permit)
+                .await
+                .map(|response| (start, stop, response))
+        }
+    });
+    join_all(futures).await.into_iter().collect()
+}
+
+/// Embed `input` as the mean of the embeddings of windows overlapping by half their length
+async fn embed_sliding_window_mean(
+    infer: Infer,
+    input: InputType,
+    normalize: bool,
+    dimensions: Option<usize>,
+    pooling: Option<Pool>,
+    priority: Priority,
+    max_input_length: usize,
+) -> Result<PooledEmbeddingsInferResponse, TextEmbeddingsError> {
+    let windows = embed_input_chunks(
+        infer,
+        input.into_text()?,
+        max_input_length,
+        max_input_length / 2,
+        false,
+        dimensions,
+        pooling,
+        priority,
+        max_input_length,
+    )
+    .await?;
+
+    let num_windows = windows.len() as f32;
+    let mut results = vec![0.0; windows[0].2.results.len()];
+    let mut metadata = InferMetadata {
+        prompt_tokens: 0,
+        // Every part of the input is embedded
+        dropped_tokens: 0,
+        tokenization: Duration::ZERO,
+        queue: Duration::ZERO,
+        inference: Duration::ZERO,
+        // The windows can be computed in different batches
+        batch_id: None,
+    };
+    for (_, _, window) in windows {
+        for (r, v) in results.iter_mut().zip(window.results) {
+            *r += v / num_windows;
+        }
+        metadata.prompt_tokens += window.metadata.prompt_tokens;
+        metadata.tokenization += window.metadata.tokenization;
+        metadata.queue = metadata.queue.max(window.metadata.queue);
+        metadata.inference += window.metadata.inference;
+    }
+
+    if normalize {
+        let norm = results.iter().map(|v| v * v).sum::<f32>().sqrt();
+        for v in results.iter_mut() {
+            *v /= norm;
+        }
+    }
+
+    Ok(PooledEmbeddingsInferResponse { results, metadata })
+}
+
+/// Byte spans of the chunks of at most `chunk_size` tokens starting every `step` tokens
+fn chunk_spans(offsets: &[(usize, usize)], chunk_size: usize, step: usize) -> Vec<(usize, usize)> {
+    let mut spans = Vec::new();
+    let mut start = 0;
+    while start < offsets.len() {
+        let stop = (start + chunk_size).min(offsets.len());
+        spans.push((offsets[start].0, offsets[stop - 1].1));
+        if stop == offsets.len() {
+            break;
+        }
+        start += step;
+    }
+    spans
+}
+
+/// Number of chunks `embed_input_chunks` splits an input of `count` tokens into
+fn chunk_count(
+    count: TokenCount,
+    chunk_size: usize,
+    chunk_overlap: usize,
+    max_input_length: usize,
+) -> usize {
+    // The special tokens are added to every chunk
+    let tokens = count.tokens - count.special_tokens;
+    let chunk_size = chunk_size
+        .min(max_input_length.saturating_sub(count.special_tokens))
+        .max(1);
+    let step = chunk_size.saturating_sub(chunk_overlap).max(1);
+    if tokens <= chunk_size {
+        return 1;
+    }
+    1 + (tokens - chunk_size + step - 1) / step
+}
+
+/// Prompt prepended to the inputs: the `prompt_name` prompt or the model default prompt
+fn get_prompt<'a>(
+    info: &'a Info,
+    prompt_name: Option<&str>,
+) -> Result<Option<&'a str>, ErrorResponse> {
+    let prompt_name = match prompt_name.or(info.default_prompt_name.as_deref()) {
+        Some(prompt_name) => prompt_name,
+        None => return Ok(None),
+    };
+    match info.prompts.get(prompt_name) {
+        Some(prompt) => Ok(Some(prompt)),
+        None => {
+            let message = format!("Prompt `{prompt_name}` is not defined by the model");
+            tracing::error!("{message}");
+            metrics::increment_counter!("te_request_failure", "err" => "validation");
+            Err(ErrorResponse {
+                error: message,
+                error_type: ErrorType::Validation,
+            })
+        }
+    }
+}
+
+/// Reload a served model. The new revision is warmed up before replacing the current instance,
+/// which finishes its in-flight requests in the background.
+/// Only served with `--admin-api-key`, which the request must send as a bearer token.
+#[utoipa::path(
+post,
+tag = "Text Embeddings Inference",
+path = "/admin/reload",
+request_body = ReloadRequest,
+responses(
+(status = 200, description = "Reloaded model info", body = Info),
+(status = 401, description = "Invalid admin API key", body = ErrorResponse,
+example = json ! ({"error": "Invalid admin API key", "error_type": "unauthorized"})),
+(status = 424, description = "Reload Error", body = ErrorResponse,
+example = json ! ({"error": "Could not reload model", "error_type": "backend"})),
+(status = 413, description = "Model is not served", body = ErrorResponse,
+example = json ! ({"error": "Model is not served", "error_type": "validation"})),
+)
+)]
+#[instrument(skip_all)]
+async fn reload(
+    models: Extension<Models>,
+    Json(req): Json<ReloadRequest>,
+) -> Result<Json<Info>, (StatusCode, Json<ErrorResponse>)> {
+    let info = models.reload(req.model.as_deref(), req.revision).await?;
+    Ok(Json(info))
+}
+
+/// Requests and prompt tokens of every tenant since the start of the server.
+/// Only served with `--admin-api-key`, which the request must send as a bearer token.
+#[utoipa::path(
+get,
+tag = "Text Embeddings Inference",
+path = "/admin/usage",
+responses(
+(status = 200, description = "Usage of every tenant", body = UsageResponse),
+(status = 401, description = "Invalid admin API key", body = ErrorResponse,
+example = json ! ({"error": "Invalid admin API key", "error_type": "unauthorized"})),
+)
+)]
+#[instrument(skip_all)]
+async fn usage(usage: Extension<UsageCounters>) -> Json<UsageResponse> {
+    Json(UsageResponse {
+        tenants: usage.snapshot(),
+    })
+}
+
+/// Metadata of the loaded models and adapters.
+/// Only served with `--admin-api-key`, which the request must send as a bearer token.
+#[utoipa::path(
+get,
+tag = "Text Embeddings Inference",
+path = "/admin/model",
+responses(
+(status = 200, description = "Metadata of every loaded model", body = ModelMetadataResponse),
+(status = 401, description = "Invalid admin API key", body = ErrorResponse,
+example = json ! ({"error": "Invalid admin API key", "error_type": "unauthorized"})),
+)
+)]
+#[instrument(skip_all)]
+async fn model_metadata(models: Extension<Models>) -> Json<ModelMetadataResponse> {
+    Json(ModelMetadataResponse {
+        models: models
+            .all()
+            .into_iter()
+            .map(|(_, info)| info.metadata)
+            .collect(),
+    })
+}
+
+/// Get cosine similarities between a source sentence and a list of sentences.
+/// Returns a 424 status code if the model is not an embedding model.
+#[utoipa::path(
+post,
+tag = "Text Embeddings Inference",
+path = "/similarity",
+request_body = SimilarityRequest,
+responses(
+(status = 200, description = "Cosine similarities", body = SimilarityResponse),
+(status = 404, description = "Model is not served", body = ErrorResponse,
+example = json ! ({"error": "Model gpt-4 is not served", "error_type": "not_found"})),
+(status = 424, description = "Embedding Error", body = ErrorResponse,
+example = json ! ({"error": "Inference failed", "error_type": "backend"})),
+(status = 429, description = "Model is overloaded", body = ErrorResponse,
+example = json ! ({"error": "Model is overloaded", "error_type": "overloaded"})),
+(status = 422, description = "Tokenization error", body = ErrorResponse,
+example = json ! ({"error": "Tokenization error", "error_type": "tokenizer"})),
+(status = 413, description = "Batch size error", body = ErrorResponse,
+example = json ! ({"error": "Batch size error", "error_type": "validation"})),
+(status = 408, description = "Request timed out", body = ErrorResponse,
+example = json ! ({"error": "Request did not complete within 1000ms", "error_type": "timeout"})),
+)
+)]
+#[instrument(
+    skip_all,
+    fields(total_time, tokenization_time, queue_time, inference_time,)
+)]
+async fn similarity(
+    models: Extension<Models>,
+    Json(req): Json<SimilarityRequest>,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    let (infer, info) = models.get(req.model.as_deref(), None)?;
+    let timeout = models.timeout(req.timeout_ms);
+    let return_usage = req.return_usage;
+    let (headers, Json(response)) =
+        with_timeout(timeout, process_similarity(infer, info, req)).await?;
+    Ok(with_usage(headers, response, return_usage))
+}
+
+async fn process_similarity(
+    infer: Infer,
+    info: Info,
+    req: SimilarityRequest,
+) -> Result<(HeaderMap, Json<SimilarityResponse>), (StatusCode, Json<ErrorResponse>)> {
+    let span = tracing::Span::current();
+    let start_time = Instant::now();
+
+    metrics::increment_counter!("te_request_count", "method" => "batch");
+
+    if req.inputs.sentences.is_empty() {
+        let message = "`sentences` cannot be empty".to_string();
+        tracing::error!("{message}");
+        let err = ErrorResponse {
+            error: message,
+            error_type: ErrorType::Validation,
+        };
+        metrics::increment_counter!("te_request_failure", "err" => "validation");
+        Err(err)?;
+    }
+
+    // The source sentence is embedded in the same batch as the sentences
+    let batch_size = req.inputs.sentences.len() + 1;
+    if batch_size > info.max_client_batch_size {
+        let message = format!(
+            "batch size {batch_size} > maximum allowed batch size {}",
+            info.max_client_batch_size
+        );
+        tracing::error!("{message}");
+        let err = ErrorResponse {
+            error: message,
+            error_type: ErrorType::Validation,
+        };
+        metrics::increment_counter!("te_request_failure", "err" => "batch_size");
+        Err(err)?;
+    }
+
+    let mut futures = Vec::with_capacity(batch_size);
+    let mut compute_chars = 0;
+
+    for input in std::iter::once(req.inputs.source_sentence).chain(req.inputs.sentences) {
+        compute_chars += input.chars().count();
+
+        let local_infer = infer.clone();
+        futures.push(async move {
+            let permit = local_infer.acquire_permit().await;
+            local_infer
+                .embed_pooled(
+                    input,
+                    req.truncate,
+                    req.truncation_direction.map(Into::into),
+                    true,
+                    None,
+                    None,
+                    req.priority.into(),
+                    permit,
+                )
+                .await
+        })
+    }
+    let results = join_all(futures)
+        .await
+        .into_iter()
+        .collect::<Result<Vec<PooledEmbeddingsInferResponse>, TextEmbeddingsError>>()
+        .map_err(ErrorResponse::from)?;
+
+    let mut embeddings = Vec::with_capacity(batch_size);
+    let mut total_tokenization_time = 0;
+    let mut total_queue_time = 0;
+    let mut total_inference_time = 0;
+    let mut total_compute_tokens = 0;
+
+    for r in results {
+        total_tokenization_time += r.metadata.tokenization.as_nanos() as u64;
+        total_queue_time += r.metadata.queue.as_nanos() as u64;
+        total_inference_time += r.metadata.inference.as_nanos() as u64;
+        total_compute_tokens += r.metadata.prompt_tokens;
+        embeddings.push(r.results);
+    }
+    let batch_size = batch_size as u64;
+
+    // Embeddings are normalized so the cosine similarity is the dot product
+    let source = &embeddings[0];
+    let similarities = embeddings[1..]
+        .iter()
+        .map(|e| source.iter().zip(e).map(|(a, b)| a * b).sum())
+        .collect();
+
+    metrics::increment_counter!("te_request_success", "method" => "batch");
+
+    let metadata = ResponseMetadata::new(
+        compute_chars,
+        total_compute_tokens,
+        start_time,
+        Duration::from_nanos(total_tokenization_time / batch_size),
+        Duration::from_nanos(total_queue_time / batch_size),
+        Duration::from_nanos(total_inference_time / batch_size),
+    );
+
+    metadata.record_span(&span);
+    metadata.record_metrics();
+
+    let headers = HeaderMap::from(metadata);
+
+    tracing::info!("Success");
+
+    Ok((headers, Json(SimilarityResponse(similarities))))
+}
+
+/// Zero-shot classification with a Natural Language Inference (NLI) model.
+/// Each candidate label is inserted in `hypothesis_template` and scored against the input.
+/// Returns a 424 status code if the model is not an NLI classifier.
+#[utoipa::path(
+post,
+tag = "Text Embeddings Inference",
+path = "/zero-shot",
+request_body = ZeroShotRequest,
+responses(
+(status = 200, description = "Label scores", body = ZeroShotResponse),
+(status = 404, description = "Model is not served", body = ErrorResponse,
+example = json ! ({"error": "Model gpt-4 is not served", "error_type": "not_found"})),
+(status = 424, description = "Prediction Error", body = ErrorResponse,
+example = json ! ({"error": "Inference failed", "error_type": "backend"})),
+(status = 429, description = "Model is overloaded", body = ErrorResponse,
+example = json ! ({"error": "Model is overloaded", "error_type": "overloaded"})),
+(status = 422, description = "Tokenization error", body = ErrorResponse,
+example = json ! ({"error": "Tokenization error", "error_type": "tokenizer"})),
+(status = 413, description = "Batch size error", body = ErrorResponse,
+example = json ! ({"error": "Batch size error", "error_type": "validation"})),
+(status = 408, description = "Request timed out", body = ErrorResponse,
+example = json ! ({"error": "Request did not complete within 1000ms", "error_type": "timeout"})),
+)
+)]
+#[instrument(
+    skip_all,
+    fields(total_time, tokenization_time, queue_time, inference_time,)
+)]
+async fn zero_shot(
+    models: Extension<Models>,
+    Json(req): Json<ZeroShotRequest>,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    let (infer, info) = models.get(req.model.as_deref(), None)?;
+    let timeout = models.timeout(req.timeout_ms);
+    let return_usage = req.return_usage;
+    let (headers, Json(response)) =
+        with_timeout(timeout, process_zero_shot(infer, info, req)).await?;
+    Ok(with_usage(headers, response, return_usage))
+}
+
+async fn process_zero_shot(
+    infer: Infer,
+    info: Info,
+    req: ZeroShotRequest,
+) -> Result<(HeaderMap, Json<ZeroShotResponse>), (StatusCode, Json<ErrorResponse>)> {
+    let span = tracing::Span::current();
+    let start_time = Instant::now();
+
+    metrics::increment_counter!("te_request_count", "method" => "batch");
+
+    let validation_error = if req.candidate_labels.is_empty() {
+        Some("`candidate_labels` cannot be empty".to_string())
+    } else if !req.hypothesis_template.contains("{}") {
+        Some("`hypothesis_template` must contain `{}`".to_string())
+    } else {
+        None
+    };
+    if let Some(message) = validation_error {
+        tracing::error!("{message}");
+        let err = ErrorResponse {
+            error: message,
+            error_type: ErrorType::Validation,
+        };
+        metrics::increment_counter!("te_request_failure", "err" => "validation");
+        Err(err)?;
+    }
+
+    let batch_size = req.candidate_labels.len();
+    if batch_size > info.max_client_batch_size {
+        let message = format!(
+            "batch size {batch_size} > maximum allowed batch size {}",
+            info.max_client_batch_size
+        );
+        tracing::error!("{message}");
+        let err = ErrorResponse {
+            error: message,
+            error_type: ErrorType::Validation,
+        };
+        metrics::increment_counter!("te_request_failure", "err" => "batch_size");
+        Err(err)?;
+    }
+
+    // Same label lookup as the `transformers` zero-shot classification pipeline
+    let entailment_id = match &info.model_type {
+        ModelType::Classifier(classifier) => classifier
+            .label2id
+            .iter()
+            .find(|(label, _)| label.to_lowercase().starts_with("entail"))
+            .map(|(_, id)| (*id, classifier.id2label.len())),
+        _ => None,
+    };
+    let (entailment_id, contradiction_id) = match entailment_id {
+        Some((id, num_labels)) if num_labels > 1 => {
+            let contradiction_id = if id == 0 { num_labels - 1 } else { 0 };
+            (id, contradiction_id)
+        }
+        _ => {
+            metrics::increment_counter!("te_request_failure", "err" => "model_type");
+            let message = "model is not a Natural Language Inference model".to_string();
+            tracing::error!("{message}");
+            Err(ErrorResponse::from(TextEmbeddingsError::Backend(
+                BackendError::Inference(message),
+            )))?
+        }
+    };
+
+    let truncate = req.truncate;
+    let truncation_direction = req.truncation_direction.map(Into::into);
+    let priority: Priority = req.priority.into();
+    let mut futures = Vec::with_capacity(batch_size);
+    let mut compute_chars = 0;
+
+    for label in &req.candidate_labels {
+        let hypothesis = req.hypothesis_template.replace("{}", label);
+        compute_chars += req.inputs.chars().count() + hypothesis.chars().count();
+
+        let local_infer = infer.clone();
+        let premise = req.inputs.clone();
+        futures.push(async move {
+            let permit = local_infer.acquire_permit().await;
+            local_infer
+                .predict(
+                    (premise, hypothesis),
+                    truncate,
+                    truncation_direction,
+                    true,
+                    None,
+                    priority,
+                    permit,
+                )
+                .await
+        })
+    }
+    let results = join_all(futures)
+        .await
+        .into_iter()
+        .collect::<Result<Vec<ClassificationInferResponse>, TextEmbeddingsError>>()
+        .map_err(ErrorResponse::from)?;
+
+    let mut logits = Vec::with_capacity(batch_size);
+    let mut total_tokenization_time = 0;
+    let mut total_queue_time = 0;
+    let mut total_inference_time = 0;
+    let mut total_compute_tokens = 0;
+
+    for r in results {
+        total_tokenization_time += r.metadata.tokenization.as_nanos() as u64;
+        total_queue_time += r.metadata.queue.as_nanos() as u64;
+        total_inference_time += r.metadata.inference.as_nanos() as u64;
+        total_compute_tokens += r.metadata.prompt_tokens;
+        logits.push((r.results[entailment_id], r.results[contradiction_id]));
+    }
+    let batch_size = batch_size as u64;
+
+    let scores: Vec<f32> = if req.multi_label || logits.len() == 1 {
+        // Softmax over entailment and contradiction for each label
+        logits
+            .iter()
+            .map(|(entailment, contradiction)| 1.0 / (1.0 + (contradiction - entailment).exp()))
+            .collect()
+    } else {
+        // Softmax of the entailment logits over all labels
+        let max = logits
+            .iter()
+            .map(|(entailment, _)| *entailment)
+            .fold(f32::NEG_INFINITY, f32::max);
+        let exps: Vec<f32> = logits
+            .iter()
+            .map(|(entailment, _)| (entailment - max).exp())
+            .collect();
+        let sum: f32 = exps.iter().sum();
+        exps.into_iter().map(|v| v / sum).collect()
+    };
+
+    let mut predictions = Vec::with_capacity(scores.len());
+    for (label, score) in req.candidate_labels.into_iter().zip(scores) {
+        // Check that score is not NaN or the partial_cmp below will panic
+        if score.is_nan() {
+            Err(ErrorResponse {
+                error: "score is NaN".to_string(),
+                error_type: ErrorType::Backend,
+            })?;
+        }
+        predictions.push(Prediction { score, label });
+    }
+    // Reverse sort
+    predictions.sort_by(|x, y| x.score.partial_cmp(&y.score).unwrap());
+    predictions.reverse();
+
+    metrics::increment_counter!("te_request_success", "method" => "batch");
+
+    let metadata = ResponseMetadata::new(
+        compute_chars,
+        total_compute_tokens,
+        start_time,
+        Duration::from_nanos(total_tokenization_time / batch_size),
+        Duration::from_nanos(total_queue_time / batch_size),
+        Duration::from_nanos(total_inference_time / batch_size),
+    );
+
+    metadata.record_span(&span);
+    metadata.record_metrics();
+
+    let headers = HeaderMap::from(metadata);
+
+    tracing::info!("Success");
+
+    Ok((headers, Json(ZeroShotResponse(predictions))))
+}
+
+/// Get all Embeddings without Pooling.
+/// Returns a 424 status code if the model is not an embedding model.
+#[utoipa::path(
+post,
+tag = "Text Embeddings Inference",
+path = "/embed_all",
+request_body = EmbedAllRequest,
+responses(
+(status = 200, description = "Embeddings", body = EmbedAllResponse),
+(status = 404, description = "Model is not served", body = ErrorResponse,
+example = json ! ({"error": "Model gpt-4 is not served", "error_type": "not_found"})),
+(status = 424, description = "Embedding Error", body = ErrorResponse,
+example = json ! ({"error": "Inference failed", "error_type": "backend"})),
+(status = 429, description = "Model is overloaded", body = ErrorResponse,
+example = json ! ({"error": "Model is overloaded", "error_type": "overloaded"})),
+(status = 422, description = "Tokenization error", body = ErrorResponse,
+example = json ! ({"error": "Tokenization error", "error_type": "tokenizer"})),
+(status = 413, description = "Batch size error", body = ErrorResponse,
+example = json ! ({"error": "Batch size error", "error_type": "validation"})),
+(status = 408, description = "Request timed out", body = ErrorResponse,
+example = json ! ({"error": "Request did not complete within 1000ms", "error_type": "timeout"})),
+)
+)]
+#[instrument(
+    skip_all,
+    fields(total_time, tokenization_time, queue_time, inference_time,)
+)]
+async fn embed_all(
+    models: Extension<Models>,
+    Json(req): Json<EmbedAllRequest>,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    let timeout = models.timeout(req.timeout_ms);
+    let return_usage = req.return_usage;
+    let (headers, Json(response)) = with_timeout(timeout, process_embed_all(models, req)).await?;
+    Ok(with_usage(headers, response, return_usage))
+}
+
+async fn process_embed_all(
+    models: Extension<Models>,
+    req: EmbedAllRequest,
+) -> Result<(HeaderMap, Json<EmbedAllResponse>), (StatusCode, Json<ErrorResponse>)> {
+    let span = tracing::Span::current();
+    let start_time = Instant::now();
+
+    let (infer, info) = models.get(req.model.as_deref(), req.adapter.as_deref())?;
+
+    let prompt = get_prompt(&info, req.prompt_name.as_deref())?;
+    let (response, metadata) = match req.inputs.with_prompt(prompt) {
+        Input::Single(input) => {
+            metrics::increment_counter!("te_request_count", "method" => "single");
+
+            let compute_chars = input.count_chars();
+
+            let permit = infer.try_acquire_permit().map_err(ErrorResponse::from)?;
+            let response = infer
+                .embed_all(
+                    input,
+                    req.truncate,
+                    req.truncation_direction.map(Into::into),
+                    req.priority.into(),
+                    permit,
+                )
+                .await
+                .map_err(ErrorResponse::from)?;
+
+            metrics::increment_counter!("te_request_success", "method" => "single");
+
+            let metadata = ResponseMetadata::new(
+                compute_chars,
+                response.metadata.prompt_tokens,
+                start_time,
+                response.metadata.tokenization,
+                response.metadata.queue,
+                response.metadata.inference,
+            )
+            .with_batch_id(response.metadata.batch_id);
+            (
+                embed_all_response(
+                    vec![response],
+                    req.return_offsets,
+                    req.return_special_tokens_mask,
+                ),
+                metadata,
+            )
+        }
+        Input::Batch(inputs) => {
+            metrics::increment_counter!("te_request_count", "method" => "batch");
+
+            if inputs.is_empty() {
+                let message = "`inputs` cannot be empty".to_string();
+                tracing::error!("{message}");
+                let err = ErrorResponse {
+                    error: message,
+                    error_type: ErrorType::Validation,
+                };
+                metrics::increment_counter!("te_request_failure", "err" => "validation");
                 Err(err)?;
             }
 
@@ -828,53 +2451,286 @@ async fn openai_embed(
             let mut compute_chars = 0;
 
             for input in inputs {
-                compute_chars += input.chars().count();
+                compute_chars += input.count_chars();
 
                 let local_infer = infer.clone();
                 futures.push(async move {
                     let permit = local_infer.acquire_permit().await;
-                    local_infer.embed_pooled(input, false, true, permit).await
+                    local_infer
+                        .embed_all(
+                            input,
+                            req.truncate,
+                            req.truncation_direction.map(Into::into),
+                            req.priority.into(),
+                            permit,
+                        )
+                        .await
                 })
             }
             let results = join_all(futures)
                 .await
                 .into_iter()
-                .collect::<Result<Vec<PooledEmbeddingsInferResponse>, TextEmbeddingsError>>()
+                .collect::<Result<Vec<AllEmbeddingsInferResponse>, TextEmbeddingsError>>()
                 .map_err(ErrorResponse::from)?;
 
-            let mut embeddings = Vec::with_capacity(batch_size);
             let mut total_tokenization_time = 0;
             let mut total_queue_time = 0;
             let mut total_inference_time = 0;
             let mut total_compute_tokens = 0;
 
-            for (i, r) in results.into_iter().enumerate() {
+            for r in &results {
                 total_tokenization_time += r.metadata.tokenization.as_nanos() as u64;
                 total_queue_time += r.metadata.queue.as_nanos() as u64;
                 total_inference_time += r.metadata.inference.as_nanos() as u64;
                 total_compute_tokens += r.metadata.prompt_tokens;
-                embeddings.push(OpenAICompatEmbedding {
-                    object: "embedding",
-                    embedding: r.results,
-                    index: i,
-                });
             }
             let batch_size = batch_size as u64;
 
-            metrics::increment_counter!("te_request_success", "method" => "batch");
+            metrics::increment_counter!("te_request_success", "method" => "batch");
+
+            (
+                embed_all_response(results, req.return_offsets, req.return_special_tokens_mask),
+                ResponseMetadata::new(
+                    compute_chars,
+                    total_compute_tokens,
+                    start_time,
+                    Duration::from_nanos(total_tokenization_time / batch_size),
+                    Duration::from_nanos(total_queue_time / batch_size),
+                    Duration::from_nanos(total_inference_time / batch_size),
+                ),
+            )
+        }
+    };
+
+    metadata.record_span(&span);
+    metadata.record_metrics();
+
+    let headers = HeaderMap::from(metadata);
+
+    tracing::info!("Success");
+
+    Ok((headers, Json(response)))
+}
+
+/// Token embeddings with their offsets and special tokens mask if requested
+fn embed_all_response(
+    results: Vec<AllEmbeddingsInferResponse>,
+    return_offsets: bool,
+    return_special_tokens_mask: bool,
+) -> EmbedAllResponse {
+    if !return_offsets && !return_special_tokens_mask {
+        return EmbedAllResponse::Embeddings(results.into_iter().map(|r| r.results).collect());
+    }
+    let tokens = results
+        .into_iter()
+        .map(|r| TokenEmbeddings {
+            embeddings: r.results,
+            offsets: return_offsets.then_some(r.offsets),
+            special_tokens_mask: return_special_tokens_mask.then_some(r.special_tokens_mask),
+        })
+        .collect();
+    EmbedAllResponse::Tokens(tokens)
+}
+
+/// OpenAI compatible route. Returns a 424 status code if the model is not an embedding model.
+#[utoipa::path(
+post,
+tag = "Text Embeddings Inference",
+path = "/embeddings",
+request_body = OpenAICompatRequest,
+responses(
+(status = 200, description = "Embeddings", body = OpenAICompatResponse),
+(status = 404, description = "Model is not served", body = OpenAICompatErrorResponse,
+example = json ! ({"message": "Model gpt-4 is not served", "type": "not_found"})),
+(status = 424, description = "Embedding Error", body = OpenAICompatErrorResponse,
+example = json ! ({"message": "Inference failed", "type": "backend"})),
+(status = 429, description = "Model is overloaded", body = OpenAICompatErrorResponse,
+example = json ! ({"message": "Model is overloaded", "type": "overloaded"})),
+(status = 422, description = "Tokenization error", body = OpenAICompatErrorResponse,
+example = json ! ({"message": "Tokenization error", "type": "tokenizer"})),
+(status = 413, description = "Batch size error", body = OpenAICompatErrorResponse,
+example = json ! ({"message": "Batch size error", "type": "validation"})),
+(status = 408, description = "Request timed out", body = OpenAICompatErrorResponse,
+example = json ! ({"message": "Request did not complete within 1000ms", "type": "timeout"})),
+)
+)]
+#[instrument(
+    skip_all,
+    fields(total_time, tokenization_time, queue_time, inference_time,)
+)]
+async fn openai_embed(
+    models: Extension<Models>,
+    Json(req): Json<OpenAICompatRequest>,
+) -> Result<(HeaderMap, Json<OpenAICompatResponse>), (StatusCode, Json<OpenAICompatErrorResponse>)>
+{
+    let timeout = models.timeout(req.timeout_ms);
+    with_timeout(timeout, process_openai_embed(models, req)).await
+}
+
+/// Embed `input` with the `encoding_format` of an OpenAI request.
+/// Also returns the scale of the `int8` and `uint8` formats
+async fn openai_embedding(
+    infer: &Infer,
+    input: EncodingInput,
+    dimensions: Option<usize>,
+    encoding_format: EncodingFormat,
+    priority: Priority,
+    permit: OwnedSemaphorePermit,
+) -> Result<(OpenAICompatEmbeddingValues, Option<f32>, InferMetadata), TextEmbeddingsError> {
+    match encoding_format.quantization() {
+        // Quantized by the backend
+        Some(quantization) => {
+            let response = infer
+                .embed_quantized(
+                    input,
+                    false,
+                    None,
+                    dimensions,
+                    None,
+                    quantization,
+                    priority,
+                    permit,
+                )
+                .await?;
+            let (embedding, scale) =
+                OpenAICompatEmbeddingValues::quantized(response.results, encoding_format);
+            Ok((embedding, scale, response.metadata))
+        }
+        None => {
+            let response = infer
+                .embed_pooled(input, false, None, true, dimensions, None, priority, permit)
+                .await?;
+            let embedding = OpenAICompatEmbeddingValues::new(response.results, encoding_format);
+            Ok((embedding, None, response.metadata))
+        }
+    }
+}
+
+async fn process_openai_embed(
+    models: Extension<Models>,
+    req: OpenAICompatRequest,
+) -> Result<(HeaderMap, Json<OpenAICompatResponse>), (StatusCode, Json<OpenAICompatErrorResponse>)>
+{
+    let span = tracing::Span::current();
+    let start_time = Instant::now();
+
+    let (infer, info) = models.get(req.model.as_deref(), req.adapter.as_deref())?;
+
+    let encoding_format = req.encoding_format;
+    let dimensions = req.dimensions;
+    let priority = req.priority.into();
+    let prompt = get_prompt(&info, req.prompt_name.as_deref())?;
+    let (inputs, is_batch) = req.input.with_prompt(prompt).into_inputs();
+
+    let (embeddings, metadata) = if !is_batch {
+        metrics::increment_counter!("te_request_count", "method" => "single");
+
+        let input = inputs.into_iter().next().unwrap();
+        let compute_chars = input.count_chars();
+
+        let permit = infer.try_acquire_permit().map_err(ErrorResponse::from)?;
+        let (embedding, scale, response_metadata) =
+            openai_embedding(&infer, input, dimensions, encoding_format, priority, permit)
+                .await
+                .map_err(ErrorResponse::from)?;
+
+        metrics::increment_counter!("te_request_success", "method" => "single");
+
+        (
+            vec![OpenAICompatEmbedding::new(embedding, scale, 0)],
+            ResponseMetadata::new(
+                compute_chars,
+                response_metadata.prompt_tokens,
+                start_time,
+                response_metadata.tokenization,
+                response_metadata.queue,
+                response_metadata.inference,
+            )
+            .with_batch_id(response_metadata.batch_id),
+        )
+    } else {
+        metrics::increment_counter!("te_request_count", "method" => "batch");
+
+        if inputs.is_empty() {
+            let message = "`inputs` cannot be empty".to_string();
+            tracing::error!("{message}");
+            let err = ErrorResponse {
+                error: message,
+                error_type: ErrorType::Validation,
+            };
+            metrics::increment_counter!("te_request_failure", "err" => "validation");
+            Err(err)?;
+        }
+
+        let batch_size = inputs.len();
+        if batch_size > info.max_client_batch_size {
+            let message = format!(
+                "batch size {batch_size} > maximum allowed batch size {}",
+                info.max_client_batch_size
+            );
+            tracing::error!("{message}");
+            let err = ErrorResponse {
+                error: message,
+                error_type: ErrorType::Validation,
+            };
+            metrics::increment_counter!("te_request_failure", "err" => "batch_size");
+            Err(err)?;
+        }
+
+        let mut futures = Vec::with_capacity(batch_size);
+        let mut compute_chars = 0;
+
+        for input in inputs {
+            compute_chars += input.count_chars();
+
+            let local_infer = infer.clone();
+            futures.push(async move {
+                let permit = local_infer.acquire_permit().await;
+                openai_embedding(
+                    &local_infer,
+                    input,
+                    dimensions,
+                    encoding_format,
+                    priority,
+                    permit,
+                )
+                .await
+            })
+        }
+        let results = join_all(futures)
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, TextEmbeddingsError>>()
+            .map_err(ErrorResponse::from)?;
+
+        let mut embeddings = Vec::with_capacity(batch_size);
+        let mut total_tokenization_time = 0;
+        let mut total_queue_time = 0;
+        let mut total_inference_time = 0;
+        let mut total_compute_tokens = 0;
 
-            (
-                embeddings,
-                ResponseMetadata::new(
-                    compute_chars,
-                    total_compute_tokens,
-                    start_time,
-                    Duration::from_nanos(total_tokenization_time / batch_size),
-                    Duration::from_nanos(total_queue_time / batch_size),
-                    Duration::from_nanos(total_inference_time / batch_size),
-                ),
-            )
+        for (i, (embedding, scale, metadata)) in results.into_iter().enumerate() {
+            total_tokenization_time += metadata.tokenization.as_nanos() as u64;
+            total_queue_time += metadata.queue.as_nanos() as u64;
+            total_inference_time += metadata.inference.as_nanos() as u64;
+            total_compute_tokens += metadata.prompt_tokens;
+            embeddings.push(OpenAICompatEmbedding::new(embedding, scale, i));
         }
+        let batch_size = batch_size as u64;
+
+        metrics::increment_counter!("te_request_success", "method" => "batch");
+
+        (
+            embeddings,
+            ResponseMetadata::new(
+                compute_chars,
+                total_compute_tokens,
+                start_time,
+                Duration::from_nanos(total_tokenization_time / batch_size),
+                Duration::from_nanos(total_queue_time / batch_size),
+                Duration::from_nanos(total_inference_time / batch_size),
+            ),
+        )
     };
 
     metadata.record_span(&span);
@@ -905,17 +2761,22 @@ path = "/tokenize",
 request_body = TokenizeRequest,
 responses(
 (status = 200, description = "Tokenized ids", body = TokenizeResponse),
+(status = 404, description = "Model is not served", body = OpenAICompatErrorResponse,
+example = json ! ({"message": "Model gpt-4 is not served", "type": "not_found"})),
 (status = 422, description = "Tokenization error", body = OpenAICompatErrorResponse,
 example = json ! ({"message": "Tokenization error", "type": "tokenizer"})),
 )
 )]
 #[instrument(skip_all)]
 async fn tokenize(
-    infer: Extension<Infer>,
-    info: Extension<Info>,
+    models: Extension<Models>,
     Json(req): Json<TokenizeRequest>,
 ) -> Result<Json<TokenizeResponse>, (StatusCode, Json<ErrorResponse>)> {
-    let tokenize_inner = move |input: String, add_special_tokens: bool, infer: Infer| async move {
+    let (infer, info) = models.get(req.model.as_deref(), None)?;
+    let tokenize_inner = move |input: InputType,
+                               add_special_tokens: bool,
+                               infer: Infer| async move {
+        let input = input.into_text().map_err(ErrorResponse::from)?;
         let encoding = infer
             .tokenize(input.clone(), add_special_tokens)
             .await
@@ -953,7 +2814,7 @@ async fn tokenize(
     };
 
     let tokens = match req.inputs {
-        Input::Single(input) => vec![tokenize_inner(input, req.add_special_tokens, infer.0).await?],
+        Input::Single(input) => vec![tokenize_inner(input, req.add_special_tokens, infer).await?],
         Input::Batch(inputs) => {
             if inputs.is_empty() {
                 let message = "`inputs` cannot be empty".to_string();
@@ -986,7 +2847,7 @@ async fn tokenize(
                 futures.push(tokenize_inner(
                     input,
                     req.add_special_tokens,
-                    infer.0.clone(),
+                    infer.clone(),
                 ));
             }
 
@@ -999,6 +2860,347 @@ async fn tokenize(
     Ok(Json(TokenizeResponse(tokens)))
 }
 
+/// Count the tokens of inputs without running the model, to split documents ahead of time.
+/// Inputs are counted as they would be encoded by `/embed`, preprocessing and prompt included.
+#[utoipa::path(
+post,
+tag = "Text Embeddings Inference",
+path = "/count",
+request_body = CountRequest,
+responses(
+(status = 200, description = "Token counts", body = CountResponse),
+(status = 404, description = "Model is not served", body = ErrorResponse,
+example = json ! ({"error": "Model gpt-4 is not served", "error_type": "not_found"})),
+(status = 422, description = "Tokenization error", body = ErrorResponse,
+example = json ! ({"error": "Tokenization error", "error_type": "tokenizer"})),
+(status = 413, description = "Batch size error", body = ErrorResponse,
+example = json ! ({"error": "Batch size error", "error_type": "validation"})),
+)
+)]
+#[instrument(skip_all)]
+async fn count(
+    models: Extension<Models>,
+    Json(req): Json<CountRequest>,
+) -> Result<Json<CountResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let (infer, info) = models.get(req.model.as_deref(), None)?;
+    let prompt = get_prompt(&info, req.prompt_name.as_deref())?;
+    let inputs = match req.inputs.with_prompt(prompt) {
+        Input::Single(input) => vec![input],
+        Input::Batch(inputs) => inputs,
+    };
+    if inputs.is_empty() {
+        let message = "`inputs` cannot be empty".to_string();
+        tracing::error!("{message}");
+        let err = ErrorResponse {
+            error: message,
+            error_type: ErrorType::Validation,
+        };
+        metrics::increment_counter!("te_request_failure", "err" => "validation");
+        Err(err)?;
+    }
+
+    let batch_size = inputs.len();
+    if batch_size > info.max_client_batch_size {
+        let message = format!(
+            "batch size {batch_size} > maximum allowed batch size {}",
+            info.max_client_batch_size
+        );
+        tracing::error!("{message}");
+        let err = ErrorResponse {
+            error: message,
+            error_type: ErrorType::Validation,
+        };
+        metrics::increment_counter!("te_request_failure", "err" => "batch_size");
+        Err(err)?;
+    }
+
+    let chunk_size = req.chunk_size.unwrap_or(info.max_input_length);
+    if req.chunk_overlap >= chunk_size {
+        let message = format!(
+            "`chunk_overlap` {} must be smaller than `chunk_size` {chunk_size}",
+            req.chunk_overlap
+        );
+        tracing::error!("{message}");
+        let err = ErrorResponse {
+            error: message,
+            error_type: ErrorType::Validation,
+        };
+        metrics::increment_counter!("te_request_failure", "err" => "validation");
+        Err(err)?;
+    }
+
+    let futures = inputs.into_iter().map(|input| infer.count_tokens(input));
+    let counts = join_all(futures)
+        .await
+        .into_iter()
+        .map(|result| {
+            let count = result?;
+            Ok(InputTokenCount {
+                tokens: count.tokens,
+                truncated: count.tokens > info.max_input_length,
+                chunks: chunk_count(count, chunk_size, req.chunk_overlap, info.max_input_length),
+            })
+        })
+        .collect::<Result<Vec<_>, TextEmbeddingsError>>()
+        .map_err(ErrorResponse::from)?;
+    Ok(Json(CountResponse(counts)))
+}
+
+/// Decode input ids
+#[utoipa::path(
+post,
+tag = "Text Embeddings Inference",
+path = "/decode",
+request_body = DecodeRequest,
+responses(
+(status = 200, description = "Decoded ids", body = DecodeResponse),
+(status = 404, description = "Model is not served", body = ErrorResponse,
+example = json ! ({"error": "Model gpt-4 is not served", "error_type": "not_found"})),
+(status = 422, description = "Tokenization error", body = ErrorResponse,
+example = json ! ({"error": "Tokenization error", "error_type": "tokenizer"})),
+(status = 413, description = "Batch size error", body = ErrorResponse,
+example = json ! ({"error": "Batch size error", "error_type": "validation"})),
+)
+)]
+#[instrument(skip_all)]
+async fn decode(
+    models: Extension<Models>,
+    Json(req): Json<DecodeRequest>,
+) -> Result<Json<DecodeResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let (infer, info) = models.get(req.model.as_deref(), None)?;
+    let texts = match req.ids {
+        InputIds::Single(ids) => {
+            let text = infer
+                .decode(ids, req.skip_special_tokens)
+                .await
+                .map_err(ErrorResponse::from)?;
+            vec![text]
+        }
+        InputIds::Batch(ids) => {
+            if ids.is_empty() {
+                let message = "`ids` cannot be empty".to_string();
+                tracing::error!("{message}");
+                let err = ErrorResponse {
+                    error: message,
+                    error_type: ErrorType::Validation,
+                };
+                metrics::increment_counter!("te_request_failure", "err" => "validation");
+                Err(err)?;
+            }
+
+            let batch_size = ids.len();
+            if batch_size > info.max_client_batch_size {
+                let message = format!(
+                    "batch size {batch_size} > maximum allowed batch size {}",
+                    info.max_client_batch_size
+                );
+                tracing::error!("{message}");
+                let err = ErrorResponse {
+                    error: message,
+                    error_type: ErrorType::Validation,
+                };
+                metrics::increment_counter!("te_request_failure", "err" => "batch_size");
+                Err(err)?;
+            }
+
+            let futures = ids
+                .into_iter()
+                .map(|ids| infer.decode(ids, req.skip_special_tokens));
+
+            join_all(futures)
+                .await
+                .into_iter()
+                .collect::<Result<Vec<String>, _>>()
+                .map_err(ErrorResponse::from)?
+        }
+    };
+    Ok(Json(DecodeResponse(texts)))
+}
+
+/// Create a background embedding job. The request body is either a JSONL file with one
+/// `{"input": "..."}` object per line sent with the `application/jsonl` content type, or a JSON
+/// object pointing to such a file on one of the `--batch-allowed-hosts` or in one of their
+/// buckets. The results are kept in memory, or written to `output_file_url` if set.
+/// At most 100 jobs are kept at the same time.
+/// Jobs are processed with a limited concurrency so that interactive requests are not delayed.
+/// The prompt tokens of a job are charged to the API key and tenant that submitted it. A job that
+/// would exceed the token quota of the key fails before any input is embedded.
+/// Finished jobs are removed after an hour.
+#[utoipa::path(
+post,
+tag = "Text Embeddings Inference",
+path = "/v1/batches",
+params(BatchParams),
+request_body = CreateBatchRequest,
+responses(
+(status = 200, description = "Batch job", body = BatchResponse),
+(status = 404, description = "Model is not served", body = ErrorResponse,
+example = json ! ({"error": "Model gpt-4 is not served", "error_type": "not_found"})),
+(status = 413, description = "Invalid request", body = ErrorResponse,
+example = json ! ({"error": "Invalid request", "error_type": "validation"})),
+(status = 429, description = "Too many batch jobs", body = ErrorResponse,
+example = json ! ({"error": "Too many batch jobs", "error_type": "overloaded"})),
+)
+)]
+#[instrument(skip_all)]
+async fn create_batch(
+    batches: Extension<BatchJobs>,
+    models: Extension<Models>,
+    usage: Extension<UsageCounters>,
+    api_keys: Extension<Option<ApiKeys>>,
+    Query(params): Query<BatchParams>,
+    headers: HeaderMap,
+    body: String,
+) -> Result<Json<BatchResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let (infer, info) = models.get(params.model.as_deref(), None)?;
+    let is_json = headers
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .map(|content_type| content_type.starts_with("application/json"))
+        .unwrap_or(false);
+
+    let source = if is_json {
+        let req: CreateBatchRequest = serde_json::from_str(&body).map_err(|err| {
+            let message = format!("Invalid request: {err}");
+            tracing::error!("{message}");
+            metrics::increment_counter!("te_request_failure", "err" => "validation");
+            ErrorResponse {
+                error: message,
+                error_type: ErrorType::Validation,
+            }
+        })?;
+        batches.check_url(&req.input_file_url).map_err(|message| {
+            tracing::error!("{message}");
+            metrics::increment_counter!("te_request_failure", "err" => "validation");
+            ErrorResponse {
+                error: message,
+                error_type: ErrorType::Validation,
+            }
+        })?;
+        BatchSource::Url(req.input_file_url)
+    } else {
+        BatchSource::Jsonl(body)
+    };
+    if let Some(output_file_url) = &params.output_file_url {
+        batches.check_output_url(output_file_url).map_err(|message| {
+            tracing::error!("{message}");
+            metrics::increment_counter!("te_request_failure", "err" => "validation");
+            ErrorResponse {
+                error: message,
+                error_type: ErrorType::Validation,
+            }
+        })?;
+    }
+
+    let api_key = bearer_token(&headers);
+    let owner = BatchOwner {
+        tenant: usage.tenant(&headers, api_key.as_deref()),
+        api_key: api_keys.0.zip(api_key),
+        usage: usage.0,
+    };
+
+    let batch = batches.create(
+        infer,
+        source,
+        params.output_file_url,
+        owner,
+        params.truncate,
+        params.truncation_direction.map(Into::into),
+        params.normalize,
+        info.max_client_batch_size,
+        info.max_input_length,
+    )?;
+    Ok(Json(batch))
+}
+
+/// Get the status and progress of a background embedding job
+#[utoipa::path(
+get,
+tag = "Text Embeddings Inference",
+path = "/v1/batches/{id}",
+params(("id" = String, Path, description = "Batch job id")),
+responses(
+(status = 200, description = "Batch job", body = BatchResponse),
+(status = 404, description = "Unknown batch job", body = ErrorResponse,
+example = json ! ({"error": "Batch batch_0 not found", "error_type": "validation"})),
+)
+)]
+#[instrument(skip_all)]
+async fn get_batch(
+    batches: Extension<BatchJobs>,
+    Path(id): Path<String>,
+) -> Result<Json<BatchResponse>, (StatusCode, Json<ErrorResponse>)> {
+    match batches.get(&id) {
+        Some(batch) => Ok(Json(batch)),
+        None => Err(batch_not_found(&id)),
+    }
+}
+
+/// Download the results of a completed background embedding job as a JSONL file with one
+/// `{"index": 0, "embedding": [...]}` or `{"index": 0, "error": "..."}` object per input.
+/// The results written to an `output_file_url` are not served
+#[utoipa::path(
+get,
+tag = "Text Embeddings Inference",
+path = "/v1/batches/{id}/results",
+params(("id" = String, Path, description = "Batch job id")),
+responses(
+(status = 200, description = "Batch job results", body = String,
+content_type = "application/jsonl"),
+(status = 404, description = "Unknown batch job", body = ErrorResponse,
+example = json ! ({"error": "Batch batch_0 not found", "error_type": "validation"})),
+(status = 409, description = "Batch job is not completed", body = ErrorResponse,
+example = json ! ({"error": "Batch batch_0 is not completed", "error_type": "validation"})),
+)
+)]
+#[instrument(skip_all)]
+async fn get_batch_results(
+    batches: Extension<BatchJobs>,
+    Path(id): Path<String>,
+) -> Result<(HeaderMap, String), (StatusCode, Json<ErrorResponse>)> {
+    match batches.results(&id) {
+        Some((batch, results))
+            if batch.status == BatchStatus::Completed && batch.output_file_url.is_none() =>
+        {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                http::header::CONTENT_TYPE,
+                HeaderValue::from_static("application/jsonl"),
+            );
+            Ok((headers, results))
+        }
+        Some((batch, _)) => {
+            let message = match batch.output_file_url {
+                Some(output_file_url) => {
+                    format!("The results of batch {id} are written to {output_file_url}")
+                }
+                None => format!("Batch {id} is not completed"),
+            };
+            tracing::error!("{message}");
+            Err((
+                StatusCode::CONFLICT,
+                Json(ErrorResponse {
+                    error: message,
+                    error_type: ErrorType::Validation,
+                }),
+            ))
+        }
+        None => Err(batch_not_found(&id)),
+    }
+}
+
+fn batch_not_found(id: &str) -> (StatusCode, Json<ErrorResponse>) {
+    let message = format!("Batch {id} not found");
+    tracing::error!("{message}");
+    (
+        StatusCode::NOT_FOUND,
+        Json(ErrorResponse {
+            error: message,
+            error_type: ErrorType::Validation,
+        }),
+    )
+}
+
 /// Prometheus metrics scrape endpoint
 #[utoipa::path(
 get,
@@ -1012,9 +3214,14 @@ async fn metrics(prom_handle: Extension<PrometheusHandle>) -> String {
 
 /// Serving method
 pub async fn run(
-    infer: Infer,
-    info: Info,
+    models: Models,
+    api_keys: Option<ApiKeys>,
+    usage_counters: UsageCounters,
+    tls: Option<TlsConfig>,
+    rate_limit: RateLimitArgs,
+    http_args: HttpArgs,
     addr: SocketAddr,
+    uds: Option<UnixListener>,
     prom_builder: PrometheusBuilder,
 ) -> Result<(), anyhow::Error> {
     // OpenAPI documentation
@@ -1023,41 +3230,107 @@ pub async fn run(
     paths(
     get_model_info,
     health,
+    liveness,
+    readiness,
     predict,
     rerank,
+    rerank_compat,
     embed,
     embed_all,
+    embed_sparse,
+    embed_chunks,
     openai_embed,
+    similarity,
+    zero_shot,
     tokenize,
+    count,
+    decode,
+    create_batch,
+    get_batch,
+    get_batch_results,
+    embed_ws,
+    reload,
+    usage,
+    model_metadata,
     metrics,
     ),
     components(
     schemas(
     PredictInput,
+    InputType,
     Input,
     Info,
     ModelType,
     ClassifierModel,
+    ProblemType,
     EmbeddingModel,
     PredictRequest,
+    Activation,
     Prediction,
     PredictResponse,
+    OpenAICompatInput,
+    EncodingFormat,
     OpenAICompatRequest,
+    OpenAICompatEmbeddingValues,
     OpenAICompatEmbedding,
     OpenAICompatUsage,
     OpenAICompatResponse,
     EmbedAllRequest,
     EmbedAllResponse,
+    TokenEmbeddings,
+    EmbedSparseRequest,
+    SparseValue,
+    EmbedSparseResponse,
+    EmbedChunksRequest,
+    Chunk,
+    EmbedChunksResponse,
     RerankRequest,
     Rank,
     RerankResponse,
+    RerankCompatDocument,
+    RerankCompatRequest,
+    RerankCompatText,
+    RerankCompatResult,
+    RerankCompatUsage,
+    RerankCompatResponse,
+    ReloadRequest,
+    TenantUsage,
+    UsageResponse,
+    ModelMetadata,
+    TokenizerMetadata,
+    ModelMetadataResponse,
+    crate::http::types::Priority,
+    crate::http::types::TruncationDirection,
+    CreateBatchRequest,
+    BatchStatus,
+    BatchRequestCounts,
+    BatchResponse,
+    SimilarityInput,
+    SimilarityRequest,
+    SimilarityResponse,
+    ZeroShotRequest,
+    ZeroShotResponse,
+    Pooling,
     EmbedRequest,
     EmbedResponse,
+    InputTruncation,
+    EmbedTruncationResponse,
+    PartialEmbedding,
+    EmbedPartialResponse,
+    StreamedEmbedding,
+    WsEmbedRequest,
+    WsEmbedResponse,
     ErrorResponse,
     OpenAICompatErrorResponse,
     TokenizeRequest,
     TokenizeResponse,
     SimpleToken,
+    CountRequest,
+    InputTokenCount,
+    CountResponse,
+    InputIds,
+    DecodeRequest,
+    DecodeResponse,
     ErrorType,
     )
     ),
@@ -1073,42 +3346,68 @@ pub async fn run(
     )]
     struct ApiDoc;
 
+    let info = models.default_model().1;
+
     // CORS allowed origins
-    // map to go inside the option and then map to parse from String to HeaderValue
-    // Finally, convert to AllowOrigin
-    let allow_origin: Option<AllowOrigin> =
-        env::var("CORS_ALLOW_ORIGIN").ok().map(|cors_allow_origin| {
-            let cors_allow_origin = cors_allow_origin.split(',');
-            AllowOrigin::list(
-                cors_allow_origin.map(|origin| origin.parse::<HeaderValue>().unwrap()),
-            )
-        });
+    let allow_origin = match http_args.cors_allow_origin {
+        Some(origins) => AllowOrigin::list(
+            origins
+                .iter()
+                .map(|origin| {
+                    origin
+                        .trim()
+                        .parse::<HeaderValue>()
+                        .with_context(|| format!("Invalid CORS origin `{origin}`"))
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+        ),
+        None => AllowOrigin::any(),
+    };
 
     let prom_handle = prom_builder
         .install_recorder()
         .context("failed to install metrics recorder")?;
 
     // CORS layer
-    let allow_origin = allow_origin.unwrap_or(AllowOrigin::any());
     let cors_layer = CorsLayer::new()
         .allow_methods([Method::GET, Method::POST])
-        .allow_headers([http::header::CONTENT_TYPE])
+        .allow_headers([http::header::CONTENT_TYPE, http::header::AUTHORIZATION])
         .allow_origin(allow_origin);
 
+
     // Create router
     let app = Router::new()
-        .merge(SwaggerUi::new("/docs").url("/api-doc/openapi.json", ApiDoc::openapi()))
+        .merge(SwaggerUi::new("/docs").url(
+            "/api-doc/openapi.json",
+            openapi::with_model_limits(ApiDoc::openapi(), &info),
+        ))
         // Base routes
         .route("/info", get(get_model_info))
         .route("/embed", post(embed))
         .route("/embed_all", post(embed_all))
+        .route("/embed_sparse", post(embed_sparse))
+        .route("/embed_chunks", post(embed_chunks))
+        .route("/similarity", post(similarity))
+        .route("/zero-shot", post(zero_shot))
         .route("/predict", post(predict))
+        .route("/ws", get(embed_ws))
         .route("/rerank", post(rerank))
+        .route("/v1/rerank", post(rerank_compat))
+        // Background jobs
+        .route("/v1/batches", post(create_batch))
+        .route("/v1/batches/:id", get(get_batch))
+        .route("/v1/batches/:id/results", get(get_batch_results))
         .route("/tokenize", post(tokenize))
+        .route("/count", post(count))
+        .route("/decode", post(decode))
         // OpenAI compat route
         .route("/embeddings", post(openai_embed))
+        .route("/v1/embeddings", post(openai_embed))
         // Base Health route
         .route("/health", get(health))
+        // Kubernetes probe routes
+        .route("/health/live", get(liveness))
+        .route("/health/ready", get(readiness))
         // Inference API health route
         .route("/", get(health))
         // AWS Sagemaker health route
@@ -1116,6 +3415,21 @@ pub async fn run(
         // Prometheus metrics route
         .route("/metrics", get(metrics));
 
+    // Admin routes are only served with their own key
+    let app = match http_args.admin_api_key {
+        Some(admin_api_key) => app.merge(
+            Router::new()
+                .route("/admin/reload", post(reload))
+                .route("/admin/usage", get(usage))
+                .route("/admin/model", get(model_metadata))
+                .route_layer(middleware::from_fn_with_state(
+                    Arc::<str>::from(admin_api_key),
+                    authorize_admin,
+                )),
+        ),
+        None => app,
+    };
+
     // Set default routes
     let app = match &info.model_type {
         ModelType::Classifier(_) => {
@@ -1136,22 +3450,366 @@ pub async fn run(
     };
 
     let app = app
-        .layer(Extension(infer))
-        .layer(Extension(info))
+        // Only the matched routes are recorded
+        .route_layer(middleware::from_fn(record_route_duration))
+        // Routes without a `model` field are served by the default model
+        .layer(middleware::from_fn_with_state(
+            models.clone(),
+            default_model_extensions,
+        ))
+        .layer(Extension(models))
+        .layer(Extension(BatchJobs::new(
+            http_args.batch_allowed_hosts,
+            http_args.payload_limit,
+        )))
         .layer(Extension(prom_handle.clone()))
+        // Only the requests accepted by the API key check below are counted
+        .layer(middleware::from_fn_with_state(
+            usage_counters.clone(),
+            record_usage,
+        ))
+        .layer(Extension(usage_counters))
+        // The frames of the WebSocket sessions are authorized one by one
+        .layer(Extension(api_keys.clone()));
+
+    // Rate limit clients and shed requests when too many are in flight.
+    // Applied after the API key check below so that only authorized keys get a bucket
+    let limits = RequestLimits {
+        rate_limiter: rate_limit.per_second.map(|rate| {
+            let burst = rate_limit.burst.unwrap_or(rate.ceil() as u32);
+            RateLimiter::new(rate, burst)
+        }),
+        by_api_key: rate_limit.by == RateLimitKey::ApiKey,
+        in_flight: rate_limit
+            .max_in_flight_requests
+            .map(|max| Arc::new(Semaphore::new(max))),
+    };
+    let app = if limits.rate_limiter.is_some() || limits.in_flight.is_some() {
+        app.layer(middleware::from_fn_with_state(limits.clone(), limit_requests))
+    } else {
+        app
+    };
+    // The frames of the WebSocket sessions are limited one by one
+    let app = app.layer(Extension(limits));
+
+    // Check the API key of the inference routes
+    let app = match api_keys {
+        Some(api_keys) => app.layer(middleware::from_fn_with_state(api_keys, authorize)),
+        None => app,
+    };
+
+    // Also logs the requests rejected by the limits above
+    let app = if http_args.json_access_log {
+        app.layer(middleware::from_fn(log_access))
+    } else {
+        app
+    };
+
+    // Embeddings serialized as JSON compress well. Streamed embeddings are not compressed: the
+    // encoder would hold them back until its buffer is full
+    let app = if http_args.compress_responses {
+        let predicate = SizeAbove::new(COMPRESSION_MIN_SIZE)
+            .and(NotForContentType::const_new(NDJSON_CONTENT_TYPE));
+        app.layer(CompressionLayer::new().compress_when(predicate))
+    } else {
+        app
+    };
+
+    let app = app
+        .layer(DefaultBodyLimit::max(http_args.payload_limit))
         .layer(OtelAxumLayer::default())
         .layer(cors_layer);
 
+    // Clients of the Unix socket are identified by the loopback address
+    let uds_server = uds.map(|listener| {
+        let client_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        axum::Server::builder(UnixAccept(listener))
+            .serve(
+                app.clone()
+                    .layer(Extension(ConnectInfo(client_addr)))
+                    .into_make_service(),
+            )
+            .with_graceful_shutdown(shutdown::shutdown_signal())
+    });
+    let uds_server = async move {
+        if let Some(uds_server) = uds_server {
+            uds_server.await?;
+        }
+        Ok::<(), anyhow::Error>(())
+    };
+
     // Run server
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        // Wait until all requests are finished to shut down
-        .with_graceful_shutdown(shutdown::shutdown_signal())
-        .await?;
+    let tcp_server = async move {
+        match tls {
+            Some(tls) => {
+                let rustls_config = RustlsConfig::from_config(tls.rustls_config()?);
+                tls::reload_on_sighup(tls, rustls_config.clone())?;
+
+                // Wait until all requests are finished to shut down
+                let handle = axum_server::Handle::new();
+                let shutdown_handle = handle.clone();
+                tokio::spawn(async move {
+                    shutdown::shutdown_signal().await;
+                    shutdown_handle.graceful_shutdown(None);
+                });
 
+                axum_server::bind_rustls(addr, rustls_config)
+                    .handle(handle)
+                    .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                    .await?;
+            }
+            None => {
+                axum::Server::bind(&addr)
+                    .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                    // Wait until all requests are finished to shut down
+                    .with_graceful_shutdown(shutdown::shutdown_signal())
+                    .await?;
+            }
+        }
+        Ok::<(), anyhow::Error>(())
+    };
+
+    tokio::try_join!(tcp_server, uds_server)?;
     Ok(())
 }
 
+/// Fail with a 408 status code if `future` does not complete within `timeout`.
+/// Dropping `future` removes its entries that are still waiting in the queue.
+async fn with_timeout<T, E: From<ErrorResponse>>(
+    timeout: Option<Duration>,
+    future: impl Future<Output = Result<T, E>>,
+) -> Result<T, E> {
+    let Some(timeout) = timeout else {
+        return future.await;
+    };
+    tokio::time::timeout(timeout, future)
+        .await
+        .unwrap_or_else(|_| Err(timeout_error(timeout).into()))
+}
+
+fn timeout_error(timeout: Duration) -> ErrorResponse {
+    metrics::increment_counter!("te_request_failure", "err" => "timeout");
+    let message = format!("Request did not complete within {}ms", timeout.as_millis());
+    tracing::error!("{message}");
+    ErrorResponse {
+        error: message,
+        error_type: ErrorType::Timeout,
+    }
+}
+
+/// Record the latency of every route with its status code
+async fn record_route_duration<B>(request: Request<B>, next: Next<B>) -> Response {
+    let start = Instant::now();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_default();
+
+    let response = next.run(request).await;
+    metrics::histogram!(
+        "te_http_request_duration",
+        start.elapsed().as_secs_f64(),
+        "route" => route,
+        "status" => response.status().as_u16().to_string()
+    );
+    response
+}
+
+/// Log the client, route, token count and timings of every non public request with the
+/// `ACCESS_LOG_TARGET` target. The token count and timings are read from the response headers.
+async fn log_access<B>(
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if is_public_route(&request) {
+        return next.run(request).await;
+    }
+
+    let start = Instant::now();
+    let method = request.method().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| request.uri().path().to_string());
+    let api_key = bearer_token(request.headers()).map(|key| key_fingerprint(&key));
+
+    let response = next.run(request).await;
+    let header = |name: &str| -> Option<u64> {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+    };
+    tracing::info!(
+        target: ACCESS_LOG_TARGET,
+        client = %client_addr.ip(),
+        api_key = api_key.as_deref(),
+        method = %method,
+        route = %route,
+        status = response.status().as_u16(),
+        input_tokens = header("x-compute-tokens"),
+        queue_time_ms = header("x-queue-time"),
+        inference_time_ms = header("x-inference-time"),
+        total_time_ms = start.elapsed().as_millis() as u64,
+        batch_id = header("x-batch-id"),
+        "access"
+    );
+    response
+}
+
+/// Add the current default model `Infer` and `Info` to the request extensions.
+/// They are looked up for every request as the model can be reloaded.
+async fn default_model_extensions<B>(
+    State(models): State<Models>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let (infer, info) = models.default_model();
+    request.extensions_mut().insert(infer);
+    request.extensions_mut().insert(info);
+    next.run(request).await
+}
+
+/// Reject the requests without a valid `Authorization: Bearer <key>` header and count the prompt
+/// tokens of the accepted ones towards the quota of their key.
+/// The health, metrics, info and documentation routes are public.
+async fn authorize<B>(
+    State(api_keys): State<ApiKeys>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    if is_public_route(&request) {
+        return Ok(next.run(request).await);
+    }
+
+    // The admin routes are checked against the admin key by `authorize_admin`
+    if request.uri().path().starts_with("/admin/") {
+        return Ok(next.run(request).await);
+    }
+
+    let key = bearer_token(request.headers());
+    api_keys.authorize(key.as_deref())?;
+
+    let response = next.run(request).await;
+    let tokens = response
+        .headers()
+        .get("x-compute-tokens")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok());
+    if let (Some(key), Some(tokens)) = (key, tokens) {
+        api_keys.record_tokens(&key, tokens);
+    }
+    Ok(response)
+}
+
+/// Check the `--admin-api-key` of the admin routes
+async fn authorize_admin<B>(
+    State(admin_api_key): State<Arc<str>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    if bearer_token(request.headers()).as_deref() != Some(&*admin_api_key) {
+        metrics::increment_counter!("te_request_failure", "err" => "unauthorized");
+        let message = "Invalid admin API key".to_string();
+        tracing::error!("{message}");
+        Err(ErrorResponse {
+            error: message,
+            error_type: ErrorType::Unauthorized,
+        })?;
+    }
+    Ok(next.run(request).await)
+}
+
+/// Count the successful inference requests and their prompt tokens for the tenant of the request
+async fn record_usage<B>(
+    State(usage): State<UsageCounters>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let api_key = bearer_token(request.headers());
+    let tenant = usage.tenant(request.headers(), api_key.as_deref());
+
+    let response = next.run(request).await;
+    // Only the inference routes report their token count
+    let tokens = response
+        .headers()
+        .get("x-compute-tokens")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok());
+    if let (true, Some(tokens)) = (response.status().is_success(), tokens) {
+        usage.record(tenant, tokens);
+    }
+    response
+}
+
+/// Reject the requests of clients over their rate limit and the requests received while
+/// `max_in_flight_requests` requests are being processed, with a `Retry-After` header
+async fn limit_requests<B>(
+    State(limits): State<RequestLimits>,
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, (StatusCode, HeaderMap, Json<ErrorResponse>)> {
+    if is_public_route(&request) {
+        return Ok(next.run(request).await);
+    }
+
+    let api_key = bearer_token(request.headers());
+    // Held until the response is sent
+    let _permit = limits
+        .try_acquire(api_key.as_deref(), client_addr.ip())
+        .map_err(|(message, retry_after)| too_many_requests(message, retry_after))?;
+
+    Ok(next.run(request).await)
+}
+
+fn too_many_requests(
+    message: &str,
+    retry_after: Duration,
+) -> (StatusCode, HeaderMap, Json<ErrorResponse>) {
+    metrics::increment_counter!("te_request_failure", "err" => "rate_limited");
+    tracing::error!("{message}");
+
+    // `Retry-After` is a number of seconds
+    let mut headers = HeaderMap::new();
+    let retry_after = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+    headers.insert(http::header::RETRY_AFTER, HeaderValue::from(retry_after));
+
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        headers,
+        Json(ErrorResponse {
+            error: message.to_string(),
+            error_type: ErrorType::Overloaded,
+        }),
+    )
+}
+
+/// The health, metrics, info and documentation routes are neither authenticated nor limited
+fn is_public_route<B>(request: &Request<B>) -> bool {
+    let path = request.uri().path();
+    path.starts_with("/docs")
+        || path.starts_with("/api-doc")
+        || (request.method() == Method::GET
+            && matches!(
+                path,
+                "/" | "/health" | "/health/live" | "/health/ready" | "/ping" | "/metrics" | "/info"
+            ))
+}
+
+/// Token of an `Authorization: Bearer <token>` header
+fn bearer_token(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string())
+}
+
 impl From<&ErrorType> for StatusCode {
     fn from(value: &ErrorType) -> Self {
         match value {
@@ -1160,6 +3818,10 @@ impl From<&ErrorType> for StatusCode {
             ErrorType::Overloaded => StatusCode::TOO_MANY_REQUESTS,
             ErrorType::Tokenizer => StatusCode::UNPROCESSABLE_ENTITY,
             ErrorType::Validation => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorType::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorType::QuotaExceeded => StatusCode::TOO_MANY_REQUESTS,
+            ErrorType::Timeout => StatusCode::REQUEST_TIMEOUT,
+            ErrorType::NotFound => StatusCode::NOT_FOUND,
         }
     }
 }
@@ -1,16 +1,23 @@
-use crate::ErrorType;
+use crate::usage::TenantUsage;
+use crate::{ErrorResponse, ErrorType, ModelMetadata};
+use base64::prelude::{Engine, BASE64_STANDARD};
 use serde::de::{SeqAccess, Visitor};
 use serde::{de, Deserialize, Deserializer, Serialize};
 use serde_json::json;
 use std::fmt::Formatter;
+use text_embeddings_backend::{EmbeddingQuantization, QuantizedEmbedding};
+use text_embeddings_core::infer::{ClassifierActivation, InferMetadata};
 use text_embeddings_core::tokenization::EncodingInput;
+use text_embeddings_core::TextEmbeddingsError;
 use utoipa::openapi::{RefOr, Schema};
-use utoipa::ToSchema;
+use utoipa::{IntoParams, ToSchema};
 
 #[derive(Debug)]
 pub(crate) enum Sequence {
     Single(String),
     Pair(String, String),
+    /// Already tokenized input
+    Ids(Vec<u32>),
 }
 
 impl Sequence {
@@ -18,6 +25,7 @@ impl Sequence {
         match self {
             Sequence::Single(s) => s.chars().count(),
             Sequence::Pair(s1, s2) => s1.chars().count() + s2.chars().count(),
+            Sequence::Ids(_) => 0,
         }
     }
 }
@@ -27,6 +35,7 @@ impl From<Sequence> for EncodingInput {
         match value {
             Sequence::Single(s) => Self::Single(s),
             Sequence::Pair(s1, s2) => Self::Dual(s1, s2),
+            Sequence::Ids(ids) => Self::Ids(ids),
         }
     }
 }
@@ -46,7 +55,16 @@ impl<'de> Deserialize<'de> for PredictInput {
         #[serde(untagged)]
         enum Internal {
             Single(String),
+            Id(u32),
             Multiple(Vec<String>),
+            Ids(Vec<u32>),
+        }
+
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum BatchItem {
+            Multiple(Vec<String>),
+            Ids(Vec<u32>),
         }
 
         struct PredictInputVisitor;
@@ -57,8 +75,10 @@ impl<'de> Deserialize<'de> for PredictInput {
             fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
                 formatter.write_str(
                     "a string, \
-                    a pair of strings [string, string] \
-                    or a batch of mixed strings and pairs [[string], [string, string], ...]",
+                    a pair of strings [string, string], \
+                    token ids [int, ...] \
+                    or a batch of mixed strings, pairs and token ids \
+                    [[string], [string, string], [int, ...], ...]",
                 )
             }
 
@@ -114,8 +134,17 @@ impl<'de> Deserialize<'de> for PredictInput {
                             return Ok(PredictInput::Single(Sequence::Single(value)));
                         }
                     }
+                    // Input is a single sequence of token ids
+                    Internal::Id(id) => {
+                        let mut ids = vec![id];
+                        while let Some(id) = seq.next_element()? {
+                            ids.push(id);
+                        }
+                        return Ok(PredictInput::Single(Sequence::Ids(ids)));
+                    }
                     // Input is a batch
                     Internal::Multiple(value) => sequence_from_vec(value),
+                    Internal::Ids(ids) => Ok(Sequence::Ids(ids)),
                 }?;
 
                 let mut batch = Vec::with_capacity(32);
@@ -123,9 +152,12 @@ impl<'de> Deserialize<'de> for PredictInput {
                 batch.push(s);
 
                 // Iterate on all sequences
-                while let Some(value) = seq.next_element::<Vec<String>>()? {
+                while let Some(value) = seq.next_element::<BatchItem>()? {
                     // Validate sequence
-                    let s = sequence_from_vec(value)?;
+                    let s = match value {
+                        BatchItem::Multiple(value) => sequence_from_vec(value)?,
+                        BatchItem::Ids(ids) => Sequence::Ids(ids),
+                    };
                     // Push to batch
                     batch.push(s);
                 }
@@ -157,6 +189,14 @@ impl<'__s> ToSchema<'__s> for PredictInput {
                         .min_items(Some(2))
                         .max_items(Some(2)),
                 )
+                .item(
+                    utoipa::openapi::ArrayBuilder::new()
+                        .items(
+                            utoipa::openapi::ObjectBuilder::new()
+                                .schema_type(utoipa::openapi::SchemaType::Integer),
+                        )
+                        .description(Some("Token ids")),
+                )
                 .item(
                     utoipa::openapi::ArrayBuilder::new().items(
                         utoipa::openapi::OneOfBuilder::new()
@@ -180,12 +220,20 @@ impl<'__s> ToSchema<'__s> for PredictInput {
                                     .min_items(Some(2))
                                     .max_items(Some(2)),
                             )
+                            .item(
+                                utoipa::openapi::ArrayBuilder::new()
+                                    .items(
+                                        utoipa::openapi::ObjectBuilder::new()
+                                            .schema_type(utoipa::openapi::SchemaType::Integer),
+                                    )
+                                    .description(Some("Token ids")),
+                            )
                     ).description(Some("A batch")),
                 )
                 .description(Some(
                     "Model input. \
-                Can be either a single string, a pair of strings or a batch of mixed single and pairs \
-                of strings.",
+                Can be either a single string, a pair of strings, token ids or a batch of mixed \
+                strings, pairs of strings and token ids.",
                 ))
                 .example(Some(json!("What is Deep Learning?")))
                 .into(),
@@ -199,9 +247,56 @@ pub(crate) struct PredictRequest {
     #[serde(default)]
     #[schema(default = "false", example = "false")]
     pub truncate: bool,
+    /// Side of the inputs that is cut when `truncate` is set. Defaults to `--truncation-direction`
+    #[schema(nullable = true, example = "right", default = "null")]
+    pub truncation_direction: Option<TruncationDirection>,
     #[serde(default)]
     #[schema(default = "false", example = "false")]
     pub raw_scores: bool,
+    /// Activation applied on the scores. Defaults to the activation of the model.
+    /// Ignored if `raw_scores` is true
+    #[serde(default)]
+    #[schema(nullable = true, example = "softmax", default = "null")]
+    pub activation: Option<Activation>,
+    /// Model loaded with `--model-id` to use for this request. Defaults to the first model
+    #[schema(nullable = true, example = "null", default = "null")]
+    pub model: Option<String>,
+    /// LoRA adapter loaded with `--lora-adapters` to use for this request
+    #[schema(nullable = true, example = "null", default = "null")]
+    pub adapter: Option<String>,
+    /// Queue lane of the request. Requests waiting for too long are moved to the next lane
+    #[serde(default)]
+    #[schema(default = "normal", example = "high")]
+    pub priority: Priority,
+    /// Deadline of the request in milliseconds. Defaults to `--request-timeout-ms`, which it
+    /// cannot exceed
+    #[schema(nullable = true, example = "null", default = "null")]
+    pub timeout_ms: Option<u64>,
+    /// Return the response as the `data` of an object with the `usage` of the request, as the
+    /// OpenAI compatible routes do
+    #[serde(default)]
+    #[schema(default = "false", example = "false")]
+    pub return_usage: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Activation {
+    /// Softmax over all labels. Falls back to a sigmoid if the model only has one label
+    Softmax,
+    Sigmoid,
+    /// Raw scores
+    Identity,
+}
+
+impl From<Activation> for ClassifierActivation {
+    fn from(value: Activation) -> Self {
+        match value {
+            Activation::Softmax => Self::Softmax,
+            Activation::Sigmoid => Self::Sigmoid,
+            Activation::Identity => Self::Identity,
+        }
+    }
 }
 
 #[derive(Serialize, ToSchema)]
@@ -228,12 +323,34 @@ pub(crate) struct RerankRequest {
     #[serde(default)]
     #[schema(default = "false", example = "false")]
     pub truncate: bool,
+    /// Side of the inputs that is cut when `truncate` is set. Defaults to `--truncation-direction`
+    #[schema(nullable = true, example = "right", default = "null")]
+    pub truncation_direction: Option<TruncationDirection>,
     #[serde(default)]
     #[schema(default = "false", example = "false")]
     pub raw_scores: bool,
     #[serde(default)]
     #[schema(default = "false", example = "false")]
     pub return_text: bool,
+    /// Model loaded with `--model-id` to use for this request. Defaults to the first model
+    #[schema(nullable = true, example = "null", default = "null")]
+    pub model: Option<String>,
+    /// LoRA adapter loaded with `--lora-adapters` to use for this request
+    #[schema(nullable = true, example = "null", default = "null")]
+    pub adapter: Option<String>,
+    /// Queue lane of the request. Requests waiting for too long are moved to the next lane
+    #[serde(default)]
+    #[schema(default = "normal", example = "high")]
+    pub priority: Priority,
+    /// Deadline of the request in milliseconds. Defaults to `--request-timeout-ms`, which it
+    /// cannot exceed
+    #[schema(nullable = true, example = "null", default = "null")]
+    pub timeout_ms: Option<u64>,
+    /// Return the response as the `data` of an object with the `usage` of the request, as the
+    /// OpenAI compatible routes do
+    #[serde(default)]
+    #[schema(default = "false", example = "false")]
+    pub return_usage: bool,
 }
 
 #[derive(Serialize, ToSchema)]
@@ -250,22 +367,396 @@ pub(crate) struct Rank {
 #[derive(Serialize, ToSchema)]
 pub(crate) struct RerankResponse(pub Vec<Rank>);
 
+#[derive(Deserialize, ToSchema)]
+#[serde(untagged)]
+pub(crate) enum RerankCompatDocument {
+    Text(String),
+    Object { text: String },
+}
+
+impl RerankCompatDocument {
+    pub(crate) fn into_text(self) -> String {
+        match self {
+            RerankCompatDocument::Text(text) => text,
+            RerankCompatDocument::Object { text } => text,
+        }
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct RerankCompatRequest {
+    #[schema(example = "What is Deep Learning?")]
+    pub query: String,
+    #[schema(example = json!(["Deep Learning is ..."]))]
+    pub documents: Vec<RerankCompatDocument>,
+    #[schema(nullable = true, example = "null", default = "null")]
+    pub top_n: Option<usize>,
+    #[serde(default)]
+    #[schema(default = "false", example = "false")]
+    pub return_documents: bool,
+    #[serde(default)]
+    #[schema(default = "false", example = "false")]
+    pub truncate: bool,
+    /// Side of the inputs that is cut when `truncate` is set. Defaults to `--truncation-direction`
+    #[schema(nullable = true, example = "right", default = "null")]
+    pub truncation_direction: Option<TruncationDirection>,
+    #[schema(nullable = true, example = "null")]
+    pub model: Option<String>,
+    /// Queue lane of the request. Requests waiting for too long are moved to the next lane
+    #[serde(default)]
+    #[schema(default = "normal", example = "high")]
+    pub priority: Priority,
+    /// Deadline of the request in milliseconds. Defaults to `--request-timeout-ms`, which it
+    /// cannot exceed
+    #[schema(nullable = true, example = "null", default = "null")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct RerankCompatText {
+    #[schema(example = "Deep Learning is ...")]
+    pub text: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct RerankCompatResult {
+    #[schema(example = "0")]
+    pub index: usize,
+    #[schema(example = "1.0")]
+    pub relevance_score: f32,
+    #[schema(nullable = true, default = "null")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub document: Option<RerankCompatText>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct RerankCompatUsage {
+    #[schema(example = "512")]
+    pub total_tokens: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct RerankCompatResponse {
+    #[schema(example = "BAAI/bge-reranker-large")]
+    pub model: String,
+    pub results: Vec<RerankCompatResult>,
+    pub usage: RerankCompatUsage,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct UsageResponse {
+    pub tenants: Vec<TenantUsage>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ModelMetadataResponse {
+    /// Default model first, then the other models and the LoRA adapters
+    pub models: Vec<ModelMetadata>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct ReloadRequest {
+    /// Model to reload. Defaults to the default model
+    #[schema(nullable = true, example = "null", default = "null")]
+    pub model: Option<String>,
+    /// Revision to load. Defaults to the `main` branch
+    #[schema(nullable = true, example = "refs/pr/2", default = "null")]
+    pub revision: Option<String>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct SimilarityInput {
+    /// The string that you wish to compare the other strings with
+    #[schema(example = "What is Deep Learning?")]
+    pub source_sentence: String,
+    /// A list of strings which will be compared against the source_sentence
+    #[schema(example = json!(["What is Machine Learning?"]))]
+    pub sentences: Vec<String>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct SimilarityRequest {
+    pub inputs: SimilarityInput,
+    #[serde(default)]
+    #[schema(default = "false", example = "false")]
+    pub truncate: bool,
+    /// Side of the inputs that is cut when `truncate` is set. Defaults to `--truncation-direction`
+    #[schema(nullable = true, example = "right", default = "null")]
+    pub truncation_direction: Option<TruncationDirection>,
+    /// Model loaded with `--model-id` to use for this request. Defaults to the first model
+    #[schema(nullable = true, example = "null", default = "null")]
+    pub model: Option<String>,
+    /// Queue lane of the request. Requests waiting for too long are moved to the next lane
+    #[serde(default)]
+    #[schema(default = "normal", example = "high")]
+    pub priority: Priority,
+    /// Deadline of the request in milliseconds. Defaults to `--request-timeout-ms`, which it
+    /// cannot exceed
+    #[schema(nullable = true, example = "null", default = "null")]
+    pub timeout_ms: Option<u64>,
+    /// Return the response as the `data` of an object with the `usage` of the request, as the
+    /// OpenAI compatible routes do
+    #[serde(default)]
+    #[schema(default = "false", example = "false")]
+    pub return_usage: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+#[schema(example = json!([0.0, 1.0, 0.5]))]
+pub(crate) struct SimilarityResponse(pub Vec<f32>);
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct ZeroShotRequest {
+    #[schema(example = "I have a problem with my iphone that needs to be resolved asap!")]
+    pub inputs: String,
+    #[schema(example = json!(["urgent", "not urgent", "phone"]))]
+    pub candidate_labels: Vec<String>,
+    /// Hypothesis paired with `inputs` for each label. `{}` is replaced with the label
+    #[serde(default = "default_hypothesis_template")]
+    #[schema(default = "This example is {}.", example = "This example is {}.")]
+    pub hypothesis_template: String,
+    /// Score each label independently instead of normalizing the scores over all labels
+    #[serde(default)]
+    #[schema(default = "false", example = "false")]
+    pub multi_label: bool,
+    #[serde(default)]
+    #[schema(default = "false", example = "false")]
+    pub truncate: bool,
+    /// Side of the inputs that is cut when `truncate` is set. Defaults to `--truncation-direction`
+    #[schema(nullable = true, example = "right", default = "null")]
+    pub truncation_direction: Option<TruncationDirection>,
+    /// Model loaded with `--model-id` to use for this request. Defaults to the first model
+    #[schema(nullable = true, example = "null", default = "null")]
+    pub model: Option<String>,
+    /// Queue lane of the request. Requests waiting for too long are moved to the next lane
+    #[serde(default)]
+    #[schema(default = "normal", example = "high")]
+    pub priority: Priority,
+    /// Deadline of the request in milliseconds. Defaults to `--request-timeout-ms`, which it
+    /// cannot exceed
+    #[schema(nullable = true, example = "null", default = "null")]
+    pub timeout_ms: Option<u64>,
+    /// Return the response as the `data` of an object with the `usage` of the request, as the
+    /// OpenAI compatible routes do
+    #[serde(default)]
+    #[schema(default = "false", example = "false")]
+    pub return_usage: bool,
+}
+
+fn default_hypothesis_template() -> String {
+    "This example is {}.".to_string()
+}
+
+/// Labels sorted by decreasing score
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ZeroShotResponse(pub Vec<Prediction>);
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(untagged)]
+pub(crate) enum InputType {
+    String(String),
+    /// Already tokenized input
+    Ids(Vec<u32>),
+}
+
+impl InputType {
+    pub(crate) fn count_chars(&self) -> usize {
+        match self {
+            InputType::String(s) => s.chars().count(),
+            InputType::Ids(_) => 0,
+        }
+    }
+
+    /// Text of the input, for the features that need to re-tokenize it
+    pub(crate) fn into_text(self) -> Result<String, TextEmbeddingsError> {
+        match self {
+            InputType::String(s) => Ok(s),
+            InputType::Ids(_) => Err(TextEmbeddingsError::Validation(
+                "token ids are not supported here, send the text of the input instead".to_string(),
+            )),
+        }
+    }
+}
+
+impl From<InputType> for EncodingInput {
+    fn from(value: InputType) -> Self {
+        match value {
+            InputType::String(s) => Self::Single(s),
+            InputType::Ids(ids) => Self::Ids(ids),
+        }
+    }
+}
+
 #[derive(Deserialize, ToSchema)]
 #[serde(untagged)]
 pub(crate) enum Input {
+    Single(InputType),
+    Batch(Vec<InputType>),
+}
+
+#[derive(Deserialize, ToSchema)]
+#[serde(untagged)]
+pub(crate) enum OpenAICompatInput {
     Single(String),
     Batch(Vec<String>),
+    Ids(Vec<u32>),
+    BatchIds(Vec<Vec<u32>>),
+}
+
+impl InputType {
+    fn with_prompt(self, prompt: &str) -> Self {
+        match self {
+            InputType::String(input) => InputType::String(format!("{prompt}{input}")),
+            // Token ids are sent as is
+            InputType::Ids(ids) => InputType::Ids(ids),
+        }
+    }
+}
+
+impl Input {
+    /// Prepend `prompt` to every text input. Token ids are left untouched.
+    pub(crate) fn with_prompt(self, prompt: Option<&str>) -> Self {
+        match prompt {
+            None => self,
+            Some(prompt) => match self {
+                Input::Single(input) => Input::Single(input.with_prompt(prompt)),
+                Input::Batch(inputs) => Input::Batch(
+                    inputs
+                        .into_iter()
+                        .map(|input| input.with_prompt(prompt))
+                        .collect(),
+                ),
+            },
+        }
+    }
+}
+
+impl OpenAICompatInput {
+    /// Prepend `prompt` to every text input. Token ids are left untouched.
+    pub(crate) fn with_prompt(self, prompt: Option<&str>) -> Self {
+        match (prompt, self) {
+            (Some(prompt), OpenAICompatInput::Single(input)) => {
+                OpenAICompatInput::Single(format!("{prompt}{input}"))
+            }
+            (Some(prompt), OpenAICompatInput::Batch(inputs)) => OpenAICompatInput::Batch(
+                inputs
+                    .into_iter()
+                    .map(|input| format!("{prompt}{input}"))
+                    .collect(),
+            ),
+            (_, input) => input,
+        }
+    }
+
+    /// Model inputs and whether the request is a batch
+    pub(crate) fn into_inputs(self) -> (Vec<EncodingInput>, bool) {
+        match self {
+            OpenAICompatInput::Single(input) => (vec![input.into()], false),
+            OpenAICompatInput::Ids(ids) => (vec![ids.into()], false),
+            OpenAICompatInput::Batch(inputs) => {
+                (inputs.into_iter().map(EncodingInput::from).collect(), true)
+            }
+            OpenAICompatInput::BatchIds(inputs) => {
+                (inputs.into_iter().map(EncodingInput::from).collect(), true)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum EncodingFormat {
+    #[default]
+    Float,
+    Base64,
+    Int8,
+    Uint8,
+    Binary,
+    Ubinary,
 }
 
 #[derive(Deserialize, ToSchema)]
 pub(crate) struct OpenAICompatRequest {
-    pub input: Input,
-    #[allow(dead_code)]
+    pub input: OpenAICompatInput,
     #[schema(nullable = true, example = "null")]
     pub model: Option<String>,
     #[allow(dead_code)]
     #[schema(nullable = true, example = "null")]
     pub user: Option<String>,
+    #[serde(default)]
+    #[schema(default = "float", example = "float")]
+    pub encoding_format: EncodingFormat,
+    #[schema(nullable = true, example = "null", default = "null")]
+    pub dimensions: Option<usize>,
+    /// Name of the prompt from the model `config_sentence_transformers.json` prepended to the
+    /// inputs. Defaults to the model `default_prompt_name`
+    #[schema(nullable = true, example = "query", default = "null")]
+    pub prompt_name: Option<String>,
+    /// LoRA adapter loaded with `--lora-adapters` to use for this request
+    #[schema(nullable = true, example = "null", default = "null")]
+    pub adapter: Option<String>,
+    /// Queue lane of the request. Requests waiting for too long are moved to the next lane
+    #[serde(default)]
+    #[schema(default = "normal", example = "high")]
+    pub priority: Priority,
+    /// Deadline of the request in milliseconds. Defaults to `--request-timeout-ms`, which it
+    /// cannot exceed
+    #[schema(nullable = true, example = "null", default = "null")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(untagged)]
+pub(crate) enum OpenAICompatEmbeddingValues {
+    Float(Vec<f32>),
+    /// Little-endian float32 values encoded in base64
+    Base64(String),
+    Int8(Vec<i8>),
+    Uint8(Vec<u8>),
+}
+
+impl EncodingFormat {
+    /// Quantization applied by the backend for this format
+    pub(crate) fn quantization(&self) -> Option<EmbeddingQuantization> {
+        match self {
+            EncodingFormat::Float | EncodingFormat::Base64 => None,
+            EncodingFormat::Int8 | EncodingFormat::Uint8 => Some(EmbeddingQuantization::Int8),
+            EncodingFormat::Binary | EncodingFormat::Ubinary => Some(EmbeddingQuantization::Binary),
+        }
+    }
+}
+
+impl OpenAICompatEmbeddingValues {
+    /// Encode `embedding` with the `float` or `base64` format
+    pub(crate) fn new(embedding: Vec<f32>, encoding_format: EncodingFormat) -> Self {
+        match encoding_format {
+            EncodingFormat::Base64 => {
+                let bytes: Vec<u8> = embedding.iter().flat_map(|v| v.to_le_bytes()).collect();
+                Self::Base64(BASE64_STANDARD.encode(bytes))
+            }
+            _ => Self::Float(embedding),
+        }
+    }
+
+    /// Encode an embedding quantized by the backend with `encoding_format`.
+    /// Also returns the scale of the `int8` and `uint8` formats.
+    pub(crate) fn quantized(
+        embedding: QuantizedEmbedding,
+        encoding_format: EncodingFormat,
+    ) -> (Self, Option<f32>) {
+        match (embedding, encoding_format) {
+            (QuantizedEmbedding::Int8 { values, scale }, EncodingFormat::Uint8) => {
+                let values = values.into_iter().map(|v| (v as i16 + 128) as u8).collect();
+                (Self::Uint8(values), Some(scale))
+            }
+            (QuantizedEmbedding::Int8 { values, scale }, _) => (Self::Int8(values), Some(scale)),
+            (QuantizedEmbedding::Binary(values), EncodingFormat::Binary) => {
+                let values = values.into_iter().map(|v| (v as i16 - 128) as i8).collect();
+                (Self::Int8(values), None)
+            }
+            (QuantizedEmbedding::Binary(values), _) => (Self::Uint8(values), None),
+        }
+    }
 }
 
 #[derive(Serialize, ToSchema)]
@@ -273,9 +764,28 @@ pub(crate) struct OpenAICompatEmbedding {
     #[schema(example = "embedding")]
     pub object: &'static str,
     #[schema(example = json!([0.0, 1.0, 2.0]))]
-    pub embedding: Vec<f32>,
+    pub embedding: OpenAICompatEmbeddingValues,
     #[schema(example = "0")]
     pub index: usize,
+    /// Multiply `int8` values, or `uint8` values minus 128, by this scale to recover the embedding
+    #[schema(nullable = true, example = "null", default = "null")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scale: Option<f32>,
+}
+
+impl OpenAICompatEmbedding {
+    pub(crate) fn new(
+        embedding: OpenAICompatEmbeddingValues,
+        scale: Option<f32>,
+        index: usize,
+    ) -> Self {
+        Self {
+            object: "embedding",
+            embedding,
+            index,
+            scale,
+        }
+    }
 }
 
 #[derive(Serialize, ToSchema)]
@@ -286,6 +796,13 @@ pub(crate) struct OpenAICompatUsage {
     pub total_tokens: usize,
 }
 
+/// Response of a request with `return_usage`
+#[derive(Serialize)]
+pub(crate) struct UsageWrapper<T> {
+    pub data: T,
+    pub usage: OpenAICompatUsage,
+}
+
 #[derive(Serialize, ToSchema)]
 pub(crate) struct OpenAICompatResponse {
     #[schema(example = "list")]
@@ -296,15 +813,137 @@ pub(crate) struct OpenAICompatResponse {
     pub usage: OpenAICompatUsage,
 }
 
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum TruncationStrategy {
+    /// Cut the inputs on the side of `truncation_direction` when `truncate` is set
+    #[default]
+    Head,
+    /// Average the embeddings of windows overlapping by half their length
+    SlidingWindowMean,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum TruncationDirection {
+    /// Keep the last tokens
+    Left,
+    /// Keep the first tokens
+    Right,
+}
+
+impl From<TruncationDirection> for text_embeddings_core::tokenization::TruncationDirection {
+    fn from(value: TruncationDirection) -> Self {
+        match value {
+            TruncationDirection::Left => Self::Left,
+            TruncationDirection::Right => Self::Right,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Priority {
+    /// Interactive requests, batched first
+    High,
+    #[default]
+    Normal,
+    /// Bulk ingestion, batched last
+    Batch,
+}
+
+impl From<Priority> for text_embeddings_core::queue::Priority {
+    fn from(value: Priority) -> Self {
+        match value {
+            Priority::High => Self::High,
+            Priority::Normal => Self::Normal,
+            Priority::Batch => Self::Batch,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Pooling {
+    Cls,
+    Mean,
+    WeightedMean,
+    LastToken,
+    /// Only supported by SPLADE models
+    Splade,
+}
+
+impl From<Pooling> for text_embeddings_backend::Pool {
+    fn from(value: Pooling) -> Self {
+        match value {
+            Pooling::Cls => Self::Cls,
+            Pooling::Mean => Self::Mean,
+            Pooling::WeightedMean => Self::WeightedMean,
+            Pooling::LastToken => Self::LastToken,
+            Pooling::Splade => Self::Splade,
+        }
+    }
+}
+
 #[derive(Deserialize, ToSchema)]
 pub(crate) struct EmbedRequest {
     pub inputs: Input,
     #[serde(default)]
     #[schema(default = "false", example = "false")]
     pub truncate: bool,
+    /// Side of the inputs that is cut when `truncate` is set. Defaults to `--truncation-direction`
+    #[schema(nullable = true, example = "right", default = "null")]
+    pub truncation_direction: Option<TruncationDirection>,
     #[serde(default = "default_normalize")]
     #[schema(default = "true", example = "true")]
     pub normalize: bool,
+    /// Truncate the embeddings to their first `dimensions` values before normalization.
+    /// Only useful for Matryoshka Representation Learning models.
+    #[serde(alias = "truncate_dim")]
+    #[schema(nullable = true, example = "null", default = "null")]
+    pub dimensions: Option<usize>,
+    /// Pooling method of the token embeddings. Defaults to the pooling of the model.
+    /// Cannot be overridden for models with a `2_Dense` projection
+    #[schema(nullable = true, example = "mean", default = "null")]
+    pub pooling: Option<Pooling>,
+    /// How inputs longer than the model maximum input length are handled.
+    /// `sliding_window_mean` embeds every part of the inputs and ignores `truncate`.
+    #[serde(default)]
+    #[schema(default = "head", example = "sliding_window_mean")]
+    pub truncation_strategy: TruncationStrategy,
+    /// Name of the prompt from the model `config_sentence_transformers.json` prepended to the
+    /// inputs. Defaults to the model `default_prompt_name`
+    #[schema(nullable = true, example = "query", default = "null")]
+    pub prompt_name: Option<String>,
+    /// Model loaded with `--model-id` to use for this request. Defaults to the first model
+    #[schema(nullable = true, example = "null", default = "null")]
+    pub model: Option<String>,
+    /// LoRA adapter loaded with `--lora-adapters` to use for this request
+    #[schema(nullable = true, example = "null", default = "null")]
+    pub adapter: Option<String>,
+    /// Queue lane of the request. Requests waiting for too long are moved to the next lane
+    #[serde(default)]
+    #[schema(default = "normal", example = "high")]
+    pub priority: Priority,
+    /// Deadline of the request in milliseconds. Defaults to `--request-timeout-ms`, which it
+    /// cannot exceed
+    #[schema(nullable = true, example = "null", default = "null")]
+    pub timeout_ms: Option<u64>,
+    /// Return how every input was truncated with the embeddings, as an
+    /// `EmbedTruncationResponse`. Not supported by protobuf responses
+    #[serde(default)]
+    #[schema(default = "false", example = "true")]
+    pub return_truncation: bool,
+    /// Embed the valid inputs of a batch and return an error for each of the others instead of
+    /// failing the whole request. The response is then an `EmbedPartialResponse`
+    #[serde(default)]
+    #[schema(default = "false", example = "true")]
+    pub partial: bool,
+    /// Return the response as the `data` of an object with the `usage` of the request, as the
+    /// OpenAI compatible routes do. Ignored by the streamed and protobuf responses
+    #[serde(default)]
+    #[schema(default = "false", example = "false")]
+    pub return_usage: bool,
 }
 
 fn default_normalize() -> bool {
@@ -315,17 +954,297 @@ fn default_normalize() -> bool {
 #[schema(example = json!([[0.0, 1.0, 2.0]]))]
 pub(crate) struct EmbedResponse(pub Vec<Vec<f32>>);
 
+#[derive(Serialize, ToSchema)]
+pub(crate) struct InputTruncation {
+    /// The input was longer than the model maximum input length and was cut
+    #[schema(example = "true")]
+    pub truncated: bool,
+    /// Number of tokens of the input that were embedded
+    #[schema(example = 512)]
+    pub tokens_processed: usize,
+    /// Number of tokens of the input that were cut
+    #[schema(example = 128)]
+    pub tokens_dropped: usize,
+}
+
+impl From<&InferMetadata> for InputTruncation {
+    fn from(value: &InferMetadata) -> Self {
+        Self {
+            truncated: value.dropped_tokens > 0,
+            tokens_processed: value.prompt_tokens,
+            tokens_dropped: value.dropped_tokens,
+        }
+    }
+}
+
+/// `EmbedResponse` sent when `return_truncation` is set
+#[derive(Serialize, ToSchema)]
+pub(crate) struct EmbedTruncationResponse {
+    #[schema(example = json!([[0.0, 1.0, 2.0]]))]
+    pub embeddings: Vec<Vec<f32>>,
+    /// Truncation of the inputs, in the order of the embeddings
+    pub truncation: Vec<InputTruncation>,
+}
+
+/// Result of an input of a `partial` request: either `embedding` or `error` is set
+#[derive(Serialize, ToSchema)]
+pub(crate) struct PartialEmbedding {
+    #[schema(nullable = true, example = json!([0.0, 1.0, 2.0]))]
+    pub embedding: Option<Vec<f32>>,
+    #[schema(nullable = true, example = "null")]
+    pub error: Option<ErrorResponse>,
+    /// Truncation of the input, if it was embedded and `return_truncation` is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub truncation: Option<InputTruncation>,
+}
+
+/// `EmbedResponse` sent when `partial` is set, in the order of the inputs
+#[derive(Serialize, ToSchema)]
+#[schema(example = json!([
+    {"embedding": [0.0, 1.0, 2.0], "error": null},
+    {"embedding": null, "error": {"error": "`inputs` cannot be empty", "error_type": "validation"}}
+]))]
+pub(crate) struct EmbedPartialResponse(pub Vec<PartialEmbedding>);
+
+/// Line of an `/embed` response streamed as `application/x-ndjson`
+#[derive(Serialize, ToSchema)]
+#[schema(example = json!({"index": 0, "embedding": [0.0, 1.0, 2.0], "error": null}))]
+pub(crate) struct StreamedEmbedding {
+    /// Index of the input in the request
+    #[schema(example = 0)]
+    pub index: usize,
+    #[serde(flatten)]
+    pub result: PartialEmbedding,
+}
+
+/// Frame sent to `/ws` to embed inputs
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct WsEmbedRequest {
+    /// Identifier of the request, sent back with its response
+    #[schema(nullable = true, example = 1, default = "null")]
+    pub id: Option<u64>,
+    #[serde(flatten)]
+    pub request: EmbedRequest,
+}
+
+/// Frame sent by `/ws` for every `WsEmbedRequest`, in the order the requests complete
+#[derive(Serialize, ToSchema)]
+#[schema(example = json!({
+    "id": 1, "results": [{"embedding": [0.0, 1.0, 2.0], "error": null}], "error": null
+}))]
+pub(crate) struct WsEmbedResponse {
+    /// `id` of the request
+    #[schema(nullable = true, example = 1)]
+    pub id: Option<u64>,
+    /// Result of every input, as in an `EmbedPartialResponse`. Empty if the request failed
+    pub results: Vec<PartialEmbedding>,
+    /// Why the request failed
+    #[schema(nullable = true, example = "null")]
+    pub error: Option<ErrorResponse>,
+    /// Prompt tokens of the request. Only sent for the requests with `return_usage`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "null")]
+    pub usage: Option<OpenAICompatUsage>,
+}
+
+/// `EmbedResponse` sent when the request accepts `application/x-protobuf`.
+/// The embeddings are concatenated in a single packed array. Equivalent to:
+///
+/// ```protobuf
+/// message EmbedResponse {
+///     uint32 dimensions = 1;
+///     repeated float embeddings = 2;
+/// }
+/// ```
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct ProtobufEmbedResponse {
+    #[prost(uint32, tag = "1")]
+    pub dimensions: u32,
+    #[prost(float, repeated, tag = "2")]
+    pub embeddings: Vec<f32>,
+}
+
+impl From<EmbedResponse> for ProtobufEmbedResponse {
+    fn from(value: EmbedResponse) -> Self {
+        let dimensions = value.0.first().map_or(0, |embedding| embedding.len());
+        Self {
+            dimensions: dimensions as u32,
+            embeddings: value.0.into_iter().flatten().collect(),
+        }
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct EmbedSparseRequest {
+    pub inputs: Input,
+    #[serde(default)]
+    #[schema(default = "false", example = "false")]
+    pub truncate: bool,
+    /// Side of the inputs that is cut when `truncate` is set. Defaults to `--truncation-direction`
+    #[schema(nullable = true, example = "right", default = "null")]
+    pub truncation_direction: Option<TruncationDirection>,
+    /// Name of the prompt from the model `config_sentence_transformers.json` prepended to the
+    /// inputs. Defaults to the model `default_prompt_name`
+    #[schema(nullable = true, example = "query", default = "null")]
+    pub prompt_name: Option<String>,
+    /// Model loaded with `--model-id` to use for this request. Defaults to the first model
+    #[schema(nullable = true, example = "null", default = "null")]
+    pub model: Option<String>,
+    /// LoRA adapter loaded with `--lora-adapters` to use for this request
+    #[schema(nullable = true, example = "null", default = "null")]
+    pub adapter: Option<String>,
+    /// Queue lane of the request. Requests waiting for too long are moved to the next lane
+    #[serde(default)]
+    #[schema(default = "normal", example = "high")]
+    pub priority: Priority,
+    /// Deadline of the request in milliseconds. Defaults to `--request-timeout-ms`, which it
+    /// cannot exceed
+    #[schema(nullable = true, example = "null", default = "null")]
+    pub timeout_ms: Option<u64>,
+    /// Return the response as the `data` of an object with the `usage` of the request, as the
+    /// OpenAI compatible routes do
+    #[serde(default)]
+    #[schema(default = "false", example = "false")]
+    pub return_usage: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct SparseValue {
+    #[schema(example = "2023")]
+    pub index: usize,
+    #[schema(example = "1.5")]
+    pub value: f32,
+}
+
+impl SparseValue {
+    /// Keep the non-zero values of a dense embedding
+    pub(crate) fn sparsify(embedding: Vec<f32>) -> Vec<Self> {
+        embedding
+            .into_iter()
+            .enumerate()
+            .filter(|(_, value)| *value != 0.0)
+            .map(|(index, value)| Self { index, value })
+            .collect()
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct EmbedSparseResponse(pub Vec<Vec<SparseValue>>);
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct EmbedChunksRequest {
+    pub inputs: Input,
+    /// Maximum number of tokens of a chunk. Defaults to the model maximum input length
+    #[schema(nullable = true, example = "256", default = "null")]
+    pub chunk_size: Option<usize>,
+    /// Number of tokens shared by consecutive chunks
+    #[serde(default)]
+    #[schema(default = "0", example = "32")]
+    pub chunk_overlap: usize,
+    #[serde(default = "default_normalize")]
+    #[schema(default = "true", example = "true")]
+    pub normalize: bool,
+    /// Model loaded with `--model-id` to use for this request. Defaults to the first model
+    #[schema(nullable = true, example = "null", default = "null")]
+    pub model: Option<String>,
+    /// LoRA adapter loaded with `--lora-adapters` to use for this request
+    #[schema(nullable = true, example = "null", default = "null")]
+    pub adapter: Option<String>,
+    /// Queue lane of the request. Requests waiting for too long are moved to the next lane
+    #[serde(default)]
+    #[schema(default = "normal", example = "high")]
+    pub priority: Priority,
+    /// Deadline of the request in milliseconds. Defaults to `--request-timeout-ms`, which it
+    /// cannot exceed
+    #[schema(nullable = true, example = "null", default = "null")]
+    pub timeout_ms: Option<u64>,
+    /// Return the response as the `data` of an object with the `usage` of the request, as the
+    /// OpenAI compatible routes do
+    #[serde(default)]
+    #[schema(default = "false", example = "false")]
+    pub return_usage: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct Chunk {
+    #[schema(example = json!([0.0, 1.0, 2.0]))]
+    pub embedding: Vec<f32>,
+    /// Byte offset of the start of the chunk in the UTF-8 encoded input
+    #[schema(example = 0)]
+    pub start: usize,
+    /// Byte offset of the end of the chunk in the UTF-8 encoded input
+    #[schema(example = 1024)]
+    pub stop: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+#[schema(example = json!([[{"embedding": [0.0, 1.0, 2.0], "start": 0, "stop": 1024}]]))]
+pub(crate) struct EmbedChunksResponse(pub Vec<Vec<Chunk>>);
+
 #[derive(Deserialize, ToSchema)]
 pub(crate) struct EmbedAllRequest {
     pub inputs: Input,
     #[serde(default)]
     #[schema(default = "false", example = "false")]
     pub truncate: bool,
+    /// Side of the inputs that is cut when `truncate` is set. Defaults to `--truncation-direction`
+    #[schema(nullable = true, example = "right", default = "null")]
+    pub truncation_direction: Option<TruncationDirection>,
+    /// Name of the prompt from the model `config_sentence_transformers.json` prepended to the
+    /// inputs. Defaults to the model `default_prompt_name`
+    #[schema(nullable = true, example = "query", default = "null")]
+    pub prompt_name: Option<String>,
+    /// Model loaded with `--model-id` to use for this request. Defaults to the first model
+    #[schema(nullable = true, example = "null", default = "null")]
+    pub model: Option<String>,
+    /// LoRA adapter loaded with `--lora-adapters` to use for this request
+    #[schema(nullable = true, example = "null", default = "null")]
+    pub adapter: Option<String>,
+    /// Queue lane of the request. Requests waiting for too long are moved to the next lane
+    #[serde(default)]
+    #[schema(default = "normal", example = "high")]
+    pub priority: Priority,
+    /// Deadline of the request in milliseconds. Defaults to `--request-timeout-ms`, which it
+    /// cannot exceed
+    #[schema(nullable = true, example = "null", default = "null")]
+    pub timeout_ms: Option<u64>,
+    /// Return the character offsets of every token in its input, prompt included
+    #[serde(default)]
+    #[schema(default = "false", example = "false")]
+    pub return_offsets: bool,
+    /// Return the mask of the special tokens added by the tokenizer
+    #[serde(default)]
+    #[schema(default = "false", example = "false")]
+    pub return_special_tokens_mask: bool,
+    /// Return the response as the `data` of an object with the `usage` of the request, as the
+    /// OpenAI compatible routes do
+    #[serde(default)]
+    #[schema(default = "false", example = "false")]
+    pub return_usage: bool,
 }
 
 #[derive(Serialize, ToSchema)]
+pub(crate) struct TokenEmbeddings {
+    #[schema(example = json!([[0.0, 1.0, 2.0]]))]
+    pub embeddings: Vec<Vec<f32>>,
+    /// Start and stop character offsets. Special tokens have empty offsets
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = json!([[0, 0]]))]
+    pub offsets: Option<Vec<(usize, usize)>>,
+    /// 1 for the special tokens added by the tokenizer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = json!([1]))]
+    pub special_tokens_mask: Option<Vec<u32>>,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(untagged)]
 #[schema(example = json!([[[0.0, 1.0, 2.0]]]))]
-pub(crate) struct EmbedAllResponse(pub Vec<Vec<Vec<f32>>>);
+pub(crate) enum EmbedAllResponse {
+    Embeddings(Vec<Vec<Vec<f32>>>),
+    /// Returned if `return_offsets` or `return_special_tokens_mask` is set
+    Tokens(Vec<TokenEmbeddings>),
+}
 
 #[derive(Serialize, ToSchema)]
 pub(crate) struct OpenAICompatErrorResponse {
@@ -341,6 +1260,9 @@ pub(crate) struct TokenizeRequest {
     #[serde(default = "default_add_special_tokens")]
     #[schema(default = "true", example = "true")]
     pub add_special_tokens: bool,
+    /// Model loaded with `--model-id` to use for this request. Defaults to the first model
+    #[schema(nullable = true, example = "null", default = "null")]
+    pub model: Option<String>,
 }
 
 fn default_add_special_tokens() -> bool {
@@ -364,3 +1286,149 @@ pub(crate) struct SimpleToken {
 #[derive(Serialize, ToSchema)]
 #[schema(example = json!([[{"id": 0, "text": "test", "special": false, "start": 0, "stop": 2}]]))]
 pub(crate) struct TokenizeResponse(pub Vec<Vec<SimpleToken>>);
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct CountRequest {
+    pub inputs: Input,
+    /// Name of the prompt from the model `config_sentence_transformers.json` prepended to the
+    /// inputs. Defaults to the model `default_prompt_name`
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub prompt_name: Option<String>,
+    /// Maximum number of tokens of a chunk, as in `/embed_chunks`. Defaults to the model maximum
+    /// input length
+    #[schema(nullable = true, example = "256", default = "null")]
+    pub chunk_size: Option<usize>,
+    /// Number of tokens shared by consecutive chunks, as in `/embed_chunks`
+    #[serde(default)]
+    #[schema(default = "0", example = "32")]
+    pub chunk_overlap: usize,
+    /// Model loaded with `--model-id` to use for this request. Defaults to the first model
+    #[schema(nullable = true, example = "null", default = "null")]
+    pub model: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct InputTokenCount {
+    /// Number of tokens of the input, special tokens included
+    #[schema(example = 1024)]
+    pub tokens: usize,
+    /// The input is longer than the model maximum input length: it is truncated when `truncate`
+    /// is set and rejected otherwise
+    #[schema(example = "true")]
+    pub truncated: bool,
+    /// Number of chunks `/embed_chunks` splits the input into
+    #[schema(example = 2)]
+    pub chunks: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+#[schema(example = json!([{"tokens": 1024, "truncated": true, "chunks": 2}]))]
+pub(crate) struct CountResponse(pub Vec<InputTokenCount>);
+
+#[derive(Deserialize, ToSchema)]
+#[serde(untagged)]
+pub(crate) enum InputIds {
+    Single(Vec<u32>),
+    Batch(Vec<Vec<u32>>),
+}
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct DecodeRequest {
+    pub ids: InputIds,
+    #[serde(default = "default_skip_special_tokens")]
+    #[schema(default = "true", example = "true")]
+    pub skip_special_tokens: bool,
+    /// Model loaded with `--model-id` to use for this request. Defaults to the first model
+    #[schema(nullable = true, example = "null", default = "null")]
+    pub model: Option<String>,
+}
+
+fn default_skip_special_tokens() -> bool {
+    true
+}
+
+#[derive(Serialize, ToSchema)]
+#[schema(example = json!(["test"]))]
+pub(crate) struct DecodeResponse(pub Vec<String>);
+
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct CreateBatchRequest {
+    /// `http(s)` URL or `s3://`, `gs://` or `az://` URI of a JSONL file with one
+    /// `{"input": "..."}` object per line. Its host or bucket must be one of the
+    /// `--batch-allowed-hosts`
+    #[schema(example = "https://example.com/corpus.jsonl")]
+    pub input_file_url: String,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct BatchParams {
+    /// Truncate the inputs that are longer than the maximum supported size
+    #[serde(default)]
+    pub truncate: bool,
+    /// Side of the inputs that is cut when `truncate` is set. Defaults to `--truncation-direction`
+    pub truncation_direction: Option<TruncationDirection>,
+    /// Normalize the embeddings. Defaults to `true`
+    #[serde(default = "default_normalize")]
+    pub normalize: bool,
+    /// Model loaded with `--model-id` to use for this job. Defaults to the first model
+    pub model: Option<String>,
+    /// `s3://`, `gs://` or `az://` URI the JSONL results are written to when the job completes,
+    /// instead of being kept in memory. Its bucket must be one of the `--batch-allowed-hosts`
+    pub output_file_url: Option<String>,
+}
+
+/// A line of a batch input file
+#[derive(Deserialize)]
+pub(crate) struct BatchInput {
+    pub input: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum BatchStatus {
+    Validating,
+    InProgress,
+    Completed,
+    Failed,
+}
+
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+pub(crate) struct BatchRequestCounts {
+    #[schema(example = "1000")]
+    pub total: usize,
+    #[schema(example = "250")]
+    pub completed: usize,
+    #[schema(example = "0")]
+    pub failed: usize,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub(crate) struct BatchResponse {
+    #[schema(example = "batch_8c5f0e3b0a6d4f1e9b2c7a1d3e5f7a9b")]
+    pub id: String,
+    #[schema(example = "batch")]
+    pub object: &'static str,
+    pub status: BatchStatus,
+    /// Unix timestamp in seconds
+    #[schema(example = "1711115037")]
+    pub created_at: u64,
+    #[schema(nullable = true, example = "null", default = "null")]
+    pub completed_at: Option<u64>,
+    pub request_counts: BatchRequestCounts,
+    /// Object the results are written to
+    #[schema(nullable = true, example = "s3://my-bucket/results.jsonl", default = "null")]
+    pub output_file_url: Option<String>,
+    #[schema(nullable = true, example = "null", default = "null")]
+    pub error: Option<String>,
+}
+
+/// A line of a batch result file
+#[derive(Debug, Serialize)]
+pub(crate) struct BatchResult {
+    pub index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub embedding: Option<Vec<f32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
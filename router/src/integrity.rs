@@ -0,0 +1,63 @@
+/// Verification of the model weights against the checksums of the Hugging Face Hub
+use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Extensions of the weight files
+const WEIGHT_EXTENSIONS: [&str; 4] = ["safetensors", "bin", "gguf", "onnx"];
+
+/// Check the SHA-256 of the weight files of `model_root`.
+/// Files of the Hub cache link to blobs named after their ETag, which the Hub sets to the SHA-256
+/// of the content for large files. Files of local directories have no checksum to compare to.
+pub(crate) fn verify_weights(model_root: &Path) -> Result<()> {
+    let mut verified = 0;
+    for path in weight_files(model_root)? {
+        let Some(expected) = hub_sha256(&path) else {
+            tracing::warn!("Cannot verify {path:?}: it does not come from the Hub cache");
+            continue;
+        };
+        let actual = sha256_file(&path)?;
+        if actual != expected {
+            return Err(anyhow!(
+                "Checksum mismatch for {path:?}: the Hub expects {expected} but the file has \
+                 {actual}. Remove it from the cache to download it again"
+            ));
+        }
+        verified += 1;
+    }
+    tracing::info!("Verified the checksums of {verified} weight files");
+    Ok(())
+}
+
+/// SHA-256 of the blob `path` links to, if its name is one
+fn hub_sha256(path: &Path) -> Option<String> {
+    let blob = fs::read_link(path).ok()?;
+    let name = blob.file_name()?.to_str()?;
+    (name.len() == 64 && name.bytes().all(|b| b.is_ascii_hexdigit())).then(|| name.to_string())
+}
+
+fn weight_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            files.extend(weight_files(&path)?);
+        } else if path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map_or(false, |ext| WEIGHT_EXTENSIONS.contains(&ext))
+        {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+pub(crate) fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
@@ -1,6 +1,19 @@
 /// Text Embedding Inference Webserver
+mod auth;
+mod bench;
+mod check;
+pub mod config;
+mod embed_file;
+mod gpu;
+mod health;
+mod integrity;
 mod logging;
+mod offline;
 mod prometheus;
+mod registry;
+mod tls;
+mod uds;
+mod usage;
 
 #[cfg(feature = "http")]
 mod http;
@@ -8,337 +21,1043 @@ mod http;
 #[cfg(feature = "grpc")]
 mod grpc;
 mod shutdown;
+mod storage;
 
+use crate::auth::ApiKeys;
+use crate::gpu::GpuMonitor;
+use crate::health::HealthState;
+use crate::tls::TlsConfig;
+use crate::usage::UsageCounters;
 use ::http::HeaderMap;
 use anyhow::{anyhow, Context, Result};
 use hf_hub::api::tokio::ApiBuilder;
-use hf_hub::{Repo, RepoType};
-use serde::Deserialize;
+use hf_hub::Cache;
 use serde::Serialize;
 use std::collections::HashMap;
-use std::fs;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::path::Path;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
 use std::time::{Duration, Instant};
 use text_embeddings_backend::DType;
-use text_embeddings_core::download::{download_artifacts, download_pool_config};
+use text_embeddings_core::cache::EmbeddingCache;
+use text_embeddings_core::download::{download_lora_adapter, download_model};
 use text_embeddings_core::infer::Infer;
-use text_embeddings_core::queue::Queue;
-use text_embeddings_core::tokenization::Tokenization;
+use text_embeddings_core::model::ModelOptions;
+use text_embeddings_core::tokenization::{Preprocessing, UnicodeNormalization};
 use text_embeddings_core::TextEmbeddingsError;
-use tokenizers::decoders::metaspace::PrependScheme;
-use tokenizers::pre_tokenizers::sequence::Sequence;
-use tokenizers::{PreTokenizerWrapper, Tokenizer};
 use tracing::Span;
 
+pub use bench::{BenchArgs, LengthDistribution};
+pub use embed_file::EmbedFileArgs;
 pub use logging::init_logging;
 
+/// Arguments of `run`, set by name so that two arguments of the same type cannot be swapped
+/// silently. `run_command` only reads the arguments of the model
+#[derive(Debug, Clone)]
+pub struct RunArgs {
+    /// Models served by the router. The first one is the default model
+    pub model_ids: Vec<String>,
+    pub revision: Option<String>,
+    /// Defaults to the number of physical CPU cores
+    pub tokenization_workers: Option<usize>,
+    pub tokenization_queue_size: Option<usize>,
+    pub dtype: Option<DType>,
+    pub pooling: Option<text_embeddings_backend::Pool>,
+    pub truncation_direction: TruncationDirection,
+    pub max_input_length: Option<usize>,
+    pub preprocessing: Vec<PreprocessingStep>,
+    pub max_concurrent_requests: usize,
+    pub max_batch_tokens: usize,
+    pub calibrate_max_batch_tokens: bool,
+    pub max_batch_requests: Option<usize>,
+    pub max_padding_ratio: Option<f32>,
+    pub padding_bucket_size: Option<usize>,
+    pub sub_batch_tokens: Option<usize>,
+    /// Maximum number of inputs of a request
+    pub max_client_batch_size: usize,
+    pub hf_api_token: Option<String>,
+    pub model_registry_token: Option<String>,
+    /// Address the servers listen on. Defaults to `0.0.0.0`
+    pub hostname: Option<String>,
+    pub port: u16,
+    /// Prefix of the sockets of the Python backends
+    pub uds_path: Option<String>,
+    pub huggingface_hub_cache: Option<String>,
+    pub offline: bool,
+    pub otlp_endpoint: Option<String>,
+    pub tensorrt_engine_cache: Option<String>,
+    pub openvino_device: Option<String>,
+    pub data_parallel: bool,
+    pub numa: text_embeddings_backend::NumaPolicy,
+    pub strict_load: bool,
+    pub deterministic: bool,
+    /// In MB
+    pub min_free_gpu_memory: Option<u64>,
+    /// In seconds. The models are not probed if 0
+    pub health_probe_interval: u64,
+    /// In seconds
+    pub shutdown_timeout: u64,
+    /// LoRA adapters of the default model
+    pub lora_adapters: Vec<String>,
+    pub grpc_port: u16,
+    /// Unix socket the servers also listen on
+    pub listen_uds_path: Option<String>,
+    pub listen_uds_mode: Option<String>,
+    /// Entries of the in-memory embedding cache. No cache if 0
+    pub embedding_cache_size: usize,
+    /// In seconds
+    pub embedding_cache_ttl: Option<u64>,
+    pub embedding_cache_redis_url: Option<String>,
+    /// Keys without quotas, in addition to the keys of `api_key_file`
+    pub api_keys: Vec<String>,
+    pub api_key_file: Option<String>,
+    pub admin_api_key: Option<String>,
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    pub tls_client_ca_path: Option<String>,
+    pub rate_limit_per_second: Option<f64>,
+    pub rate_limit_burst: Option<u32>,
+    pub rate_limit_by: RateLimitKey,
+    pub max_in_flight_requests: Option<usize>,
+    /// Default deadline of the inference requests
+    pub request_timeout_ms: Option<u64>,
+    pub cors_allow_origin: Option<Vec<String>>,
+    pub payload_limit: usize,
+    pub batch_allowed_hosts: Vec<String>,
+    pub compress_responses: bool,
+    pub json_access_log: bool,
+    pub usage_tenant_header: Option<String>,
+}
+
 /// Create entrypoint
-#[allow(clippy::too_many_arguments)]
-pub async fn run(
-    model_id: String,
-    revision: Option<String>,
-    tokenization_workers: Option<usize>,
-    dtype: Option<DType>,
-    pooling: Option<text_embeddings_backend::Pool>,
-    max_concurrent_requests: usize,
-    max_batch_tokens: usize,
-    max_batch_requests: Option<usize>,
-    max_client_batch_size: usize,
-    hf_api_token: Option<String>,
-    hostname: Option<String>,
-    port: u16,
-    uds_path: Option<String>,
-    huggingface_hub_cache: Option<String>,
-    otlp_endpoint: Option<String>,
-) -> Result<()> {
-    let model_id_path = Path::new(&model_id);
-    let model_root = if model_id_path.exists() && model_id_path.is_dir() {
-        // Using a local model
-        model_id_path.to_path_buf()
-    } else {
-        let mut builder = ApiBuilder::new()
-            .with_progress(false)
-            .with_token(hf_api_token);
+pub async fn run(args: RunArgs) -> Result<()> {
+    let RunArgs {
+        model_ids,
+        revision,
+        tokenization_workers,
+        tokenization_queue_size,
+        dtype,
+        pooling,
+        truncation_direction,
+        max_input_length,
+        preprocessing,
+        max_concurrent_requests,
+        max_batch_tokens,
+        calibrate_max_batch_tokens,
+        max_batch_requests,
+        max_padding_ratio,
+        padding_bucket_size,
+        sub_batch_tokens,
+        max_client_batch_size,
+        hf_api_token,
+        model_registry_token,
+        hostname,
+        port,
+        uds_path,
+        huggingface_hub_cache,
+        offline,
+        otlp_endpoint,
+        tensorrt_engine_cache,
+        openvino_device,
+        data_parallel,
+        numa,
+        strict_load,
+        deterministic,
+        min_free_gpu_memory,
+        health_probe_interval,
+        shutdown_timeout,
+        lora_adapters,
+        grpc_port,
+        listen_uds_path,
+        listen_uds_mode,
+        embedding_cache_size,
+        embedding_cache_ttl,
+        embedding_cache_redis_url,
+        api_keys,
+        api_key_file,
+        admin_api_key,
+        tls_cert_path,
+        tls_key_path,
+        tls_client_ca_path,
+        rate_limit_per_second,
+        rate_limit_burst,
+        rate_limit_by,
+        max_in_flight_requests,
+        request_timeout_ms,
+        cors_allow_origin,
+        payload_limit,
+        batch_allowed_hosts,
+        compress_responses,
+        json_access_log,
+        usage_tenant_header,
+    } = args;
+
+    if max_padding_ratio.map_or(false, |ratio| !(0.0..=1.0).contains(&ratio)) {
+        return Err(anyhow!("`--max-padding-ratio` must be between 0 and 1"));
+    }
+    if padding_bucket_size == Some(0) {
+        return Err(anyhow!("`--padding-bucket-size` must be > 0"));
+    }
+    if sub_batch_tokens == Some(0) {
+        return Err(anyhow!("`--sub-batch-tokens` must be > 0"));
+    }
+    if rate_limit_per_second.map_or(false, |rate| rate <= 0.0) {
+        return Err(anyhow!("`--rate-limit-per-second` must be > 0"));
+    }
+    let rate_limit = RateLimitArgs {
+        per_second: rate_limit_per_second,
+        burst: rate_limit_burst,
+        by: rate_limit_by,
+        max_in_flight_requests,
+    };
+    let http_args = HttpArgs {
+        cors_allow_origin,
+        payload_limit,
+        batch_allowed_hosts,
+        compress_responses,
+        json_access_log,
+        admin_api_key,
+    };
+
+    let api_keys = ApiKeys::load(api_keys, api_key_file)?;
+    if rate_limit_by == RateLimitKey::ApiKey && api_keys.is_none() {
+        return Err(anyhow!("`--rate-limit-by api-key` requires `--api-key` or `--api-key-file`"));
+    }
+    // Shared by the HTTP and gRPC servers
+    let usage = UsageCounters::new(usage_tenant_header);
 
-        if let Some(cache_dir) = huggingface_hub_cache {
-            builder = builder.with_cache_dir(cache_dir.into());
+    let tls = match (tls_cert_path, tls_key_path) {
+        (Some(cert_path), Some(key_path)) => Some(TlsConfig {
+            cert_path,
+            key_path,
+            client_ca_path: tls_client_ca_path,
+        }),
+        (None, None) => {
+            if tls_client_ca_path.is_some() {
+                return Err(anyhow!(
+                    "`--tls-client-ca-path` requires `--tls-cert-path` and `--tls-key-path`"
+                ));
+            }
+            None
+        }
+        _ => {
+            return Err(anyhow!(
+                "`--tls-cert-path` and `--tls-key-path` must be set together"
+            ))
         }
+    };
 
-        let api = builder.build().unwrap();
-        let api_repo = api.repo(Repo::with_revision(
-            model_id.clone(),
-            RepoType::Model,
-            revision.clone().unwrap_or("main".to_string()),
-        ));
-
-        // Optionally download the pooling config.
-        if pooling.is_none() {
-            // If a pooling config exist, download it
-            let _ = download_pool_config(&api_repo).await;
+    let embedding_cache_ttl = embedding_cache_ttl.map(Duration::from_secs);
+    let embedding_cache = match (
+        embedding_cache_redis_url,
+        NonZeroUsize::new(embedding_cache_size),
+    ) {
+        (Some(url), _) => {
+            #[cfg(feature = "redis")]
+            {
+                tracing::info!("Connecting to the Redis embedding cache");
+                Some(
+                    EmbeddingCache::redis(&url, embedding_cache_ttl)
+                        .await
+                        .context("Could not connect to the Redis embedding cache")?,
+                )
+            }
+            #[cfg(not(feature = "redis"))]
+            {
+                let _ = url;
+                return Err(anyhow!(
+                    "`--embedding-cache-redis-url` requires the `redis` feature"
+                ));
+            }
         }
+        (None, Some(capacity)) => Some(EmbeddingCache::memory(capacity, embedding_cache_ttl)),
+        (None, None) => None,
+    };
 
-        // Download model from the Hub
-        download_artifacts(&api_repo)
-            .await
-            .context("Could not download model artifacts")?
+    let args = ModelArgs {
+        tokenization_workers,
+        tokenization_queue_size,
+        dtype,
+        pooling,
+        truncation_direction: truncation_direction.into(),
+        max_input_length,
+        preprocessing: preprocessing_from_steps(&preprocessing)?,
+        max_concurrent_requests,
+        max_batch_tokens,
+        calibrate_max_batch_tokens,
+        max_batch_requests,
+        max_padding_ratio,
+        padding_bucket_size,
+        sub_batch_tokens,
+        max_client_batch_size,
+        hf_api_token,
+        model_registry_token,
+        uds_path: uds_path.unwrap_or("/tmp/text-embeddings-inference-server".to_string()),
+        huggingface_hub_cache,
+        offline,
+        otlp_endpoint,
+        tensorrt_engine_cache,
+        openvino_device,
+        data_parallel,
+        numa,
+        strict_load,
+        deterministic,
+        embedding_cache,
+        request_timeout: request_timeout_ms.map(Duration::from_millis),
     };
 
-    // Load config
-    let config_path = model_root.join("config.json");
-    let config = fs::read_to_string(config_path).context("`config.json` not found")?;
-    let config: ModelConfig =
-        serde_json::from_str(&config).context("Failed to parse `config.json`")?;
-
-    // Set model type from config
-    let backend_model_type = {
-        // Check if the model is a classifier
-        let mut classifier = false;
-        for arch in &config.architectures {
-            if arch.ends_with("Classification") {
-                classifier = true;
-                break;
-            }
+    let default_model_id = model_ids.first().cloned();
+
+    let mut models = Vec::with_capacity(model_ids.len());
+    for (i, model_id) in model_ids.into_iter().enumerate() {
+        tracing::info!("Loading model {model_id}");
+
+        // Each Python backend needs its own socket
+        let uds_path = match i {
+            0 => args.uds_path.clone(),
+            i => format!("{}-{i}", args.uds_path),
+        };
+
+        models.push(load_model(model_id, revision.clone(), None, uds_path, &args).await?);
+    }
+
+    // Adapters are merged into their own copy of the default model
+    let mut adapters = Vec::with_capacity(lora_adapters.len());
+    if let Some(model_id) = default_model_id {
+        for (i, adapter_id) in lora_adapters.into_iter().enumerate() {
+            tracing::info!("Loading LoRA adapter {adapter_id}");
+            let uds_path = format!("{}-adapter-{i}", args.uds_path);
+            adapters.push(
+                load_model(
+                    model_id.clone(),
+                    revision.clone(),
+                    Some(adapter_id),
+                    uds_path,
+                    &args,
+                )
+                .await?,
+            );
         }
+    }
+    let gpu = GpuMonitor::start(min_free_gpu_memory.map(|mb| mb << 20))?;
+    let models = Models::new(models, adapters, args, gpu)?;
+    let probe = (health_probe_interval > 0).then(|| {
+        let interval = Duration::from_secs(health_probe_interval);
+        tokio::spawn(health::probe_models(models.clone(), interval))
+    });
+    let shutdown_timeout = Duration::from_secs(shutdown_timeout);
 
-        if classifier {
-            if pooling.is_some() {
-                tracing::warn!(
-                    "`--pooling` arg is set but model is a classifier. Ignoring `--pooling` arg."
-                );
-            }
-            text_embeddings_backend::ModelType::Classifier
-        } else {
-            // Set pooling
-            let pool = match pooling {
-                Some(pool) => pool,
-                None => {
-                    // Load pooling config
-                    let config_path = model_root.join("1_Pooling/config.json");
-                    let config = fs::read_to_string(config_path).context("The `--pooling` arg is not set and we could not find a pooling configuration (`1_Pooling/config.json`) for this model.")?;
-                    let config: PoolConfig = serde_json::from_str(&config)
-                        .context("Failed to parse `1_Pooling/config.json`")?;
-                    if config.pooling_mode_cls_token {
-                        text_embeddings_backend::Pool::Cls
-                    } else if config.pooling_mode_mean_tokens {
-                        text_embeddings_backend::Pool::Mean
-                    } else {
-                        return Err(anyhow!("Pooling config {config:?} is not supported"));
-                    }
-                }
-            };
-            text_embeddings_backend::ModelType::Embedding(pool)
+    let addr = match hostname.unwrap_or("0.0.0.0".to_string()).parse() {
+        Ok(ip) => SocketAddr::new(ip, port),
+        Err(_) => {
+            tracing::warn!("Invalid hostname, defaulting to 0.0.0.0");
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port)
         }
     };
 
-    // Info model type
-    let model_type = match &backend_model_type {
-        text_embeddings_backend::ModelType::Classifier => {
-            let id2label = config
-                .id2label
-                .context("`config.json` does not contain `id2label`")?;
-            let n_classes = id2label.len();
-            let classifier_model = ClassifierModel {
-                id2label,
-                label2id: config
-                    .label2id
-                    .context("`config.json` does not contain `label2id`")?,
-            };
-            if n_classes > 1 {
-                ModelType::Classifier(classifier_model)
-            } else {
-                ModelType::Reranker(classifier_model)
-            }
+    let max_input_length = models
+        .all()
+        .iter()
+        .map(|(_, info)| info.max_input_length)
+        .max()
+        .unwrap();
+    let prom_builder = prometheus::prometheus_builer(max_input_length)?;
+
+    #[cfg(not(any(feature = "http", feature = "grpc")))]
+    compile_error!("Either feature `http` or `grpc` must be enabled.");
+
+    let uds = listen_uds_path
+        .as_deref()
+        .map(|path| uds::bind(path, listen_uds_mode.as_deref()))
+        .transpose()?;
+
+    // When both features are enabled, the gRPC server listens on `grpc_port` and the Prometheus
+    // metrics are served by the HTTP server
+    #[cfg(all(feature = "http", feature = "grpc"))]
+    {
+        let grpc_addr = SocketAddr::new(addr.ip(), grpc_port);
+        let grpc_uds = listen_uds_path
+            .as_ref()
+            .map(|path| uds::bind(&format!("{path}-grpc"), listen_uds_mode.as_deref()))
+            .transpose()?;
+        let grpc_models = models.clone();
+        let http_models = models.clone();
+        let grpc_api_keys = api_keys.clone();
+        let grpc_usage = usage.clone();
+        let grpc_tls = tls.clone();
+        let mut grpc_server = tokio::spawn(async move {
+            grpc::server::run(
+                grpc_models,
+                grpc_api_keys,
+                grpc_usage,
+                grpc_tls,
+                grpc_addr,
+                grpc_uds,
+                None,
+            )
+            .await
+        });
+        let mut http_server = tokio::spawn(async move {
+            http::server::run(
+                http_models,
+                api_keys,
+                usage,
+                tls,
+                rate_limit,
+                http_args,
+                addr,
+                uds,
+                prom_builder,
+            )
+            .await
+        });
+        tracing::info!("Ready");
+        // Stop as soon as one of the servers stops
+        tokio::select! {
+            result = &mut http_server => result??,
+            result = &mut grpc_server => result??,
+            _ = shutdown::timeout_after_signal(shutdown_timeout) => {}
         }
-        text_embeddings_backend::ModelType::Embedding(pool) => {
-            ModelType::Embedding(EmbeddingModel {
-                pooling: pool.to_string(),
-            })
+        // The other server holds the models until it is dropped
+        http_server.abort();
+        grpc_server.abort();
+    }
+
+    #[cfg(all(feature = "http", not(feature = "grpc")))]
+    {
+        let _ = grpc_port;
+        let http_models = models.clone();
+        let mut server = tokio::spawn(async move {
+            http::server::run(
+                http_models,
+                api_keys,
+                usage,
+                tls,
+                rate_limit,
+                http_args,
+                addr,
+                uds,
+                prom_builder,
+            )
+            .await
+        });
+        tracing::info!("Ready");
+        tokio::select! {
+            result = &mut server => result??,
+            _ = shutdown::timeout_after_signal(shutdown_timeout) => server.abort(),
         }
+    }
+
+    #[cfg(all(feature = "grpc", not(feature = "http")))]
+    {
+        let _ = (grpc_port, rate_limit, http_args);
+        let grpc_models = models.clone();
+        let mut server = tokio::spawn(async move {
+            grpc::server::run(grpc_models, api_keys, usage, tls, addr, uds, Some(prom_builder))
+                .await
+        });
+        tracing::info!("Ready");
+        tokio::select! {
+            result = &mut server => result??,
+            _ = shutdown::timeout_after_signal(shutdown_timeout) => server.abort(),
+        }
+    }
+
+    // The servers no longer accept requests: wait for the requests that are still running, then
+    // release the backends and their device memory
+    if let Some(probe) = probe {
+        probe.abort();
+    }
+    // A model drains once the requests release their clone of it
+    let instances = models.all();
+    drop(models);
+    for (infer, info) in instances {
+        if tokio::time::timeout(shutdown_timeout, infer.shutdown())
+            .await
+            .is_err()
+        {
+            tracing::warn!("Model {} did not drain within the shutdown timeout", info.model_id);
+        }
+    }
+    tracing::info!("Shutdown complete");
+
+    Ok(())
+}
+
+/// Commands running a single model without starting a server
+#[derive(Debug, clap::Subcommand)]
+pub enum Command {
+    /// Embed the documents of a JSONL file and write their embeddings to disk, without starting
+    /// a server
+    Embed(EmbedFileArgs),
+    /// Measure the latency and the throughput of the model on synthetic inputs, for a sweep of
+    /// input lengths, batch sizes and concurrency levels
+    Bench(BenchArgs),
+    /// Check whether each backend supports the architecture, the pooling, the dtype and the
+    /// position embeddings of the model, from its configuration files only
+    Check,
+}
+
+/// Command entrypoint: load the model, then run `command`. `check` does not load the model
+pub async fn run_command(args: RunArgs, command: Command) -> Result<()> {
+    let RunArgs {
+        model_ids,
+        revision,
+        tokenization_workers,
+        tokenization_queue_size,
+        dtype,
+        pooling,
+        truncation_direction,
+        max_input_length,
+        preprocessing,
+        max_concurrent_requests,
+        max_batch_tokens,
+        max_batch_requests,
+        hf_api_token,
+        model_registry_token,
+        uds_path,
+        huggingface_hub_cache,
+        offline,
+        otlp_endpoint,
+        tensorrt_engine_cache,
+        openvino_device,
+        numa,
+        strict_load,
+        deterministic,
+        ..
+    } = args;
+    let [model_id] = <[String; 1]>::try_from(model_ids)
+        .map_err(|_| anyhow!("Commands load a single model: `--model-id` cannot be repeated"))?;
+    let max_client_batch_size = match &command {
+        Command::Check => {
+            return check::check(
+                &model_id,
+                revision,
+                dtype,
+                pooling,
+                hf_api_token,
+                huggingface_hub_cache,
+                offline,
+            )
+            .await
+        }
+        Command::Embed(args) => args.chunk_size,
+        Command::Bench(args) => args.batch_sizes.iter().copied().max().unwrap_or(1),
     };
 
-    // Load tokenizer
-    let tokenizer_path = model_root.join("tokenizer.json");
-    let mut tokenizer = Tokenizer::from_file(tokenizer_path).expect(
-        "tokenizer.json not found. text-embeddings-inference only supports fast tokenizers",
-    );
-    // See https://github.com/huggingface/tokenizers/pull/1357
-    if let Some(pre_tokenizer) = tokenizer.get_pre_tokenizer() {
-        if let PreTokenizerWrapper::Metaspace(m) = pre_tokenizer {
-            // We are forced to clone since `Tokenizer` does not have a `get_mut` for `pre_tokenizer`
-            let mut m = m.clone();
-            m.set_prepend_scheme(PrependScheme::First);
-            tokenizer.with_pre_tokenizer(PreTokenizerWrapper::Metaspace(m));
-        } else if let PreTokenizerWrapper::Sequence(s) = pre_tokenizer {
-            let pre_tokenizers = s.get_pre_tokenizers();
-            // Check if we have a Metaspace pre tokenizer in the sequence
-            let has_metaspace = pre_tokenizers
-                .iter()
-                .any(|t| matches!(t, PreTokenizerWrapper::Metaspace(_)));
+    let model_args = ModelArgs {
+        tokenization_workers,
+        tokenization_queue_size,
+        dtype,
+        pooling,
+        truncation_direction: truncation_direction.into(),
+        max_input_length,
+        preprocessing: preprocessing_from_steps(&preprocessing)?,
+        max_concurrent_requests,
+        max_batch_tokens,
+        calibrate_max_batch_tokens: false,
+        max_batch_requests,
+        max_padding_ratio: None,
+        padding_bucket_size: None,
+        sub_batch_tokens: None,
+        max_client_batch_size,
+        hf_api_token,
+        model_registry_token,
+        uds_path: uds_path.unwrap_or("/tmp/text-embeddings-inference-server".to_string()),
+        huggingface_hub_cache,
+        offline,
+        otlp_endpoint,
+        tensorrt_engine_cache,
+        openvino_device,
+        data_parallel: false,
+        numa,
+        strict_load,
+        deterministic,
+        embedding_cache: None,
+        request_timeout: None,
+    };
 
-            if has_metaspace {
-                let mut new_pre_tokenizers = Vec::with_capacity(s.get_pre_tokenizers().len());
+    tracing::info!("Loading model {model_id}");
+    let uds_path = model_args.uds_path.clone();
+    let (infer, info) = load_model(model_id, revision, None, uds_path, &model_args).await?;
 
-                for pre_tokenizer in pre_tokenizers {
-                    if let PreTokenizerWrapper::WhitespaceSplit(_) = pre_tokenizer {
-                        // Remove WhitespaceSplit
-                        // This will be done by the Metaspace pre tokenizer
-                        continue;
-                    }
+    let result = match command {
+        Command::Embed(args) => embed_file::embed_file(&infer, &info, args).await,
+        Command::Bench(args) => bench::bench(&infer, &info, args).await,
+        Command::Check => unreachable!("`check` does not load the model"),
+    };
+    infer.shutdown().await;
+    result
+}
 
-                    let mut pre_tokenizer = pre_tokenizer.clone();
+/// Arguments shared by all the models loaded by this process
+#[derive(Debug, Clone)]
+struct ModelArgs {
+    tokenization_workers: Option<usize>,
+    /// Inputs waiting for a tokenizer worker
+    tokenization_queue_size: Option<usize>,
+    dtype: Option<DType>,
+    pooling: Option<text_embeddings_backend::Pool>,
+    /// Default truncation direction of the requests
+    truncation_direction: text_embeddings_core::tokenization::TruncationDirection,
+    /// Overrides the maximum input length of the model configuration
+    max_input_length: Option<usize>,
+    preprocessing: Preprocessing,
+    max_concurrent_requests: usize,
+    max_batch_tokens: usize,
+    /// Probe the largest batch the device can run at startup
+    calibrate_max_batch_tokens: bool,
+    max_batch_requests: Option<usize>,
+    /// Maximum share of padding tokens in the batches of padded models
+    max_padding_ratio: Option<f32>,
+    /// Padded lengths of the batches of padded models are rounded up to this multiple
+    padding_bucket_size: Option<usize>,
+    /// Batches of embedding models are run in sub-batches of at most this number of tokens
+    sub_batch_tokens: Option<usize>,
+    max_client_batch_size: usize,
+    hf_api_token: Option<String>,
+    /// Bearer token of the HTTP registries
+    model_registry_token: Option<String>,
+    uds_path: String,
+    huggingface_hub_cache: Option<String>,
+    /// Resolve the model files from local directories and the Hub cache only
+    offline: bool,
+    otlp_endpoint: Option<String>,
+    tensorrt_engine_cache: Option<String>,
+    openvino_device: Option<String>,
+    data_parallel: bool,
+    numa: text_embeddings_backend::NumaPolicy,
+    /// Verify the checksums of the weights and the tensor names of the checkpoint
+    strict_load: bool,
+    /// Bit-exact outputs across runs on the same hardware
+    deterministic: bool,
+    embedding_cache: Option<EmbeddingCache>,
+    /// Default deadline of the inference requests
+    request_timeout: Option<Duration>,
+}
 
-                    if let PreTokenizerWrapper::Metaspace(ref mut m) = pre_tokenizer {
-                        m.set_prepend_scheme(PrependScheme::First);
-                    }
-                    new_pre_tokenizers.push(pre_tokenizer);
-                }
-                tokenizer.with_pre_tokenizer(PreTokenizerWrapper::Sequence(Sequence::new(
-                    new_pre_tokenizers,
-                )));
+/// Side of the inputs that is cut when they are longer than the maximum input length
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum TruncationDirection {
+    /// Keep the last tokens
+    Left,
+    /// Keep the first tokens
+    Right,
+}
+
+impl From<TruncationDirection> for text_embeddings_core::tokenization::TruncationDirection {
+    fn from(value: TruncationDirection) -> Self {
+        match value {
+            TruncationDirection::Left => Self::Left,
+            TruncationDirection::Right => Self::Right,
+        }
+    }
+}
+
+/// Preprocessing step of the texts of the inputs, applied before tokenization
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum PreprocessingStep {
+    /// Unicode NFC normalization
+    Nfc,
+    /// Unicode NFKC normalization, which also folds compatibility characters
+    Nfkc,
+    /// Replace the runs of whitespace with a single space and trim the text
+    CollapseWhitespace,
+    /// Remove the control characters that are not whitespace
+    StripControl,
+    /// Lowercase the text
+    Lowercase,
+}
+
+fn preprocessing_from_steps(steps: &[PreprocessingStep]) -> Result<Preprocessing> {
+    let mut preprocessing = Preprocessing::default();
+    for step in steps {
+        let unicode_normalization = match step {
+            PreprocessingStep::Nfc => UnicodeNormalization::Nfc,
+            PreprocessingStep::Nfkc => UnicodeNormalization::Nfkc,
+            PreprocessingStep::CollapseWhitespace => {
+                preprocessing.collapse_whitespace = true;
+                continue;
+            }
+            PreprocessingStep::StripControl => {
+                preprocessing.strip_control = true;
+                continue;
             }
+            PreprocessingStep::Lowercase => {
+                preprocessing.lowercase = true;
+                continue;
+            }
+        };
+        if preprocessing
+            .unicode_normalization
+            .is_some_and(|form| form != unicode_normalization)
+        {
+            return Err(anyhow!("`--preprocessing` cannot hold both `nfc` and `nfkc`"));
         }
+        preprocessing.unicode_normalization = Some(unicode_normalization);
     }
+    Ok(preprocessing)
+}
 
-    tokenizer.with_padding(None);
+/// How the rate limiter identifies clients
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum RateLimitKey {
+    /// Client IP address
+    Ip,
+    /// API key of the `Authorization` header, once it is authorized
+    ApiKey,
+}
 
-    // Position IDs offset. Used for Roberta and camembert.
-    let position_offset = if &config.model_type == "xlm-roberta"
-        || &config.model_type == "camembert"
-        || &config.model_type == "roberta"
-    {
-        config.pad_token_id + 1
-    } else {
-        0
-    };
-    let max_input_length = config.max_position_embeddings - position_offset;
+/// Limits applied to the inference routes of the HTTP server
+#[derive(Debug, Clone)]
+struct RateLimitArgs {
+    /// Requests per second allowed for each client. Unlimited if not set
+    per_second: Option<f64>,
+    /// Requests a client can send at once. Defaults to `per_second`
+    burst: Option<u32>,
+    by: RateLimitKey,
+    /// Requests processed at the same time over all clients. Unlimited if not set
+    max_in_flight_requests: Option<usize>,
+}
 
-    let tokenization_workers = tokenization_workers.unwrap_or_else(num_cpus::get_physical);
+/// Options of the HTTP server
+#[derive(Debug, Clone)]
+struct HttpArgs {
+    /// Origins allowed to make cross-origin requests. All origins are allowed if not set
+    cors_allow_origin: Option<Vec<String>>,
+    /// Maximum size of the request bodies in bytes
+    payload_limit: usize,
+    /// Hosts the input files of the batch jobs can be downloaded from
+    batch_allowed_hosts: Vec<String>,
+    /// Compress large responses with gzip or zstd depending on the `Accept-Encoding` header
+    compress_responses: bool,
+    /// Write a JSON line to stdout for every inference request
+    json_access_log: bool,
+    /// Key of the admin routes. They are not served if not set
+    admin_api_key: Option<String>,
+}
 
-    // Tokenization logic
-    let tokenization = Tokenization::new(
+/// Download and load a model with its own tokenizer, queue and backend.
+/// The weights of `lora_adapter` are merged into the model weights.
+async fn load_model(
+    model_id: String,
+    revision: Option<String>,
+    lora_adapter: Option<String>,
+    uds_path: String,
+    args: &ModelArgs,
+) -> Result<(Infer, Info)> {
+    let ModelArgs {
         tokenization_workers,
-        tokenizer,
+        tokenization_queue_size,
+        dtype,
+        pooling,
+        truncation_direction,
         max_input_length,
-        position_offset,
-    );
+        preprocessing,
+        max_concurrent_requests,
+        max_batch_tokens,
+        calibrate_max_batch_tokens,
+        max_batch_requests,
+        max_padding_ratio,
+        padding_bucket_size,
+        sub_batch_tokens,
+        max_client_batch_size,
+        hf_api_token,
+        model_registry_token,
+        uds_path: _,
+        huggingface_hub_cache,
+        offline,
+        otlp_endpoint,
+        tensorrt_engine_cache,
+        openvino_device,
+        data_parallel,
+        numa,
+        strict_load,
+        deterministic,
+        embedding_cache,
+        request_timeout: _,
+    } = args.clone();
+
+    let lora_adapter_root = match &lora_adapter {
+        Some(adapter_id) => Some(
+            download_lora_adapter_root(
+                adapter_id,
+                hf_api_token.clone(),
+                model_registry_token.clone(),
+                huggingface_hub_cache.clone(),
+                offline,
+            )
+            .await?,
+        ),
+        None => None,
+    };
+
+    let model_id_path = Path::new(&model_id);
+    let model_root = if model_id_path.exists() && model_id_path.is_dir() {
+        // Using a local model
+        if offline {
+            offline::check_model_files(
+                &model_id,
+                |file| offline::local_file(model_id_path, file),
+                pooling.is_some(),
+            )?;
+        }
+        model_id_path.to_path_buf()
+    } else if offline {
+        // Using a model of the Hub cache
+        offline::cached_model_root(
+            &model_id,
+            revision.as_deref(),
+            huggingface_hub_cache,
+            pooling.is_some(),
+        )?
+    } else if let Some(registry) = registry::registry(
+        &model_id,
+        huggingface_hub_cache.clone(),
+        model_registry_token,
+    )? {
+        // Using a model of another registry, selected by the scheme of the model id
+        registry
+            .download(&model_id, revision.as_deref())
+            .await
+            .context("Could not download model artifacts")?
+    } else {
+        download_model(
+            &model_id,
+            revision.as_deref(),
+            hf_api_token,
+            huggingface_hub_cache,
+            pooling.is_none(),
+        )
+        .await
+        .context("Could not download model artifacts")?
+    };
+
+    if strict_load {
+        integrity::verify_weights(&model_root).context("Could not verify the model weights")?;
+    }
 
     // Get dtype
     let dtype = dtype.unwrap_or({
-        #[cfg(any(feature = "accelerate", feature = "mkl", feature = "mkl-dynamic"))]
+        #[cfg(any(
+            feature = "accelerate",
+            feature = "mkl",
+            feature = "mkl-dynamic",
+            feature = "ort",
+            feature = "wgpu"
+        ))]
         {
             DType::Float32
         }
-        #[cfg(not(any(feature = "accelerate", feature = "mkl", feature = "mkl-dynamic")))]
+        #[cfg(not(any(
+            feature = "accelerate",
+            feature = "mkl",
+            feature = "mkl-dynamic",
+            feature = "ort",
+            feature = "wgpu"
+        )))]
         {
             DType::Float16
         }
     });
 
-    // Create backend
-    tracing::info!("Starting model backend");
-    let backend = text_embeddings_backend::Backend::new(
-        model_root,
-        dtype.clone(),
-        backend_model_type,
-        uds_path.unwrap_or("/tmp/text-embeddings-inference-server".to_string()),
-        otlp_endpoint.clone(),
-    )
-    .context("Could not create backend")?;
-    backend
-        .health()
-        .await
-        .context("Model backend is not healthy")?;
-
-    let max_batch_requests = backend
-        .max_batch_size
-        .map(|s| {
-            tracing::warn!("Backend does not support a batch size > {s}");
-            tracing::warn!("forcing `max_batch_requests={s}`");
-            s
-        })
-        .or(max_batch_requests);
+    // Snapshots of the Hub cache are named after the commit they were downloaded from
+    let commit_sha = model_root
+        .parent()
+        .filter(|parent| parent.file_name().map_or(false, |name| name == "snapshots"))
+        .and_then(|_| model_root.file_name())
+        .map(|name| name.to_string_lossy().to_string());
 
-    // Queue logic
-    let queue = Queue::new(
-        backend.padded_model,
+    let tokenization_workers = tokenization_workers.unwrap_or_else(num_cpus::get_physical);
+    let options = ModelOptions {
+        dtype: dtype.clone(),
+        pooling,
+        truncation_direction,
+        max_input_length,
+        preprocessing: preprocessing.clone(),
+        tokenization_workers,
+        tokenization_queue_size,
+        max_concurrent_requests,
         max_batch_tokens,
+        calibrate_max_batch_tokens,
         max_batch_requests,
-        max_concurrent_requests,
-    );
+        max_padding_ratio,
+        padding_bucket_size,
+        sub_batch_tokens,
+        max_client_batch_size,
+        uds_path,
+        otlp_endpoint,
+        tensorrt_engine_cache,
+        openvino_device,
+        data_parallel,
+        numa,
+        lora_adapter: lora_adapter_root,
+        strict_load,
+        deterministic,
+    };
+    let model = text_embeddings_core::model::load_model(model_root, &model_id, &options).await?;
 
-    // Create infer task
-    let infer = Infer::new(tokenization, queue, max_concurrent_requests, backend);
+    let pooling = match &model.model_type {
+        text_embeddings_backend::ModelType::Classifier => None,
+        text_embeddings_backend::ModelType::Embedding(pool) => Some(pool.to_string()),
+    };
+    let model_type = match &model.model_type {
+        text_embeddings_backend::ModelType::Classifier => {
+            let classifier_model = ClassifierModel {
+                id2label: model
+                    .config
+                    .id2label
+                    .clone()
+                    .context("`config.json` does not contain `id2label`")?,
+                label2id: model
+                    .config
+                    .label2id
+                    .clone()
+                    .context("`config.json` does not contain `label2id`")?,
+                problem_type: model.config.problem_type.map(ProblemType::from),
+            };
+            if model.config.is_reranker() {
+                ModelType::Reranker(classifier_model)
+            } else {
+                ModelType::Classifier(classifier_model)
+            }
+        }
+        text_embeddings_backend::ModelType::Embedding(pool) => {
+            ModelType::Embedding(EmbeddingModel {
+                pooling: pool.to_string(),
+            })
+        }
+    };
+
+    let metadata = ModelMetadata {
+        model_id: model_id.clone(),
+        revision: revision.clone(),
+        commit_sha,
+        lora_adapter: lora_adapter.clone(),
+        architectures: model.config.architectures.clone(),
+        model_type: model.config.model_type.clone(),
+        dtype: dtype.to_string(),
+        pooling,
+        max_input_length: model.max_input_length,
+        embedding_dimension: model.embedding_dimension,
+        tokenizer: TokenizerMetadata {
+            model: model.tokenizer.model,
+            vocab_size: model.tokenizer.vocab_size,
+            special_tokens: model.tokenizer.special_tokens,
+        },
+    };
+
+    // Entries of different models, revisions and adapters can share the same store
+    let infer = match embedding_cache {
+        Some(cache) => {
+            let mut prefix = format!("{model_id}@{}", revision.as_deref().unwrap_or("main"));
+            if let Some(adapter_id) = &lora_adapter {
+                prefix = format!("{prefix}+{adapter_id}");
+            }
+            if !preprocessing.is_empty() {
+                prefix = format!("{prefix}#{preprocessing}");
+            }
+            model.infer.with_cache(cache.with_prefix(prefix))
+        }
+        None => model.infer,
+    };
 
     // Endpoint info
     let info = Info {
         model_id,
         model_sha: revision,
+        lora_adapter,
         model_dtype: dtype.to_string(),
         model_type,
+        prompts: model.st_config.prompts,
+        default_prompt_name: model.st_config.default_prompt_name,
         max_concurrent_requests,
-        max_input_length,
-        max_batch_tokens,
+        max_input_length: model.max_input_length,
+        max_batch_tokens: model.max_batch_tokens,
         tokenization_workers,
-        max_batch_requests,
+        max_batch_requests: model.max_batch_requests,
         max_client_batch_size,
         version: env!("CARGO_PKG_VERSION"),
         sha: option_env!("VERGEN_GIT_SHA"),
         docker_label: option_env!("DOCKER_LABEL"),
+        metadata,
     };
 
-    let addr = match hostname.unwrap_or("0.0.0.0".to_string()).parse() {
-        Ok(ip) => SocketAddr::new(ip, port),
-        Err(_) => {
-            tracing::warn!("Invalid hostname, defaulting to 0.0.0.0");
-            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port)
-        }
-    };
-
-    let prom_builder = prometheus::prometheus_builer(info.max_input_length)?;
-
-    #[cfg(all(feature = "grpc", feature = "http"))]
-    compile_error!("Features `http` and `grpc` cannot be enabled at the same time.");
-
-    #[cfg(not(any(feature = "http", feature = "grpc")))]
-    compile_error!("Either feature `http` or `grpc` must be enabled.");
+    Ok((infer, info))
+}
 
-    #[cfg(feature = "http")]
+/// Download a LoRA adapter from the Hub or another registry, or use a local adapter directory
+async fn download_lora_adapter_root(
+    adapter_id: &str,
+    hf_api_token: Option<String>,
+    model_registry_token: Option<String>,
+    huggingface_hub_cache: Option<String>,
+    offline: bool,
+) -> Result<PathBuf> {
+    let adapter_path = Path::new(adapter_id);
+    if adapter_path.exists() && adapter_path.is_dir() {
+        return Ok(adapter_path.to_path_buf());
+    }
+    if offline {
+        let cache = huggingface_hub_cache
+            .map(|dir| Cache::new(dir.into()))
+            .unwrap_or_default();
+        let adapter_path = cache
+            .model(adapter_id.to_string())
+            .get("adapter_model.safetensors")
+            .ok_or_else(|| {
+                anyhow!(
+                    "LoRA adapter {adapter_id} cannot be loaded offline. Missing files:\n- \
+                     adapter_model.safetensors"
+                )
+            })?;
+        return Ok(adapter_path.parent().unwrap().to_path_buf());
+    }
+    if let Some(registry) =
+        registry::registry(adapter_id, huggingface_hub_cache.clone(), model_registry_token)?
     {
-        let server =
-            tokio::spawn(async move { http::server::run(infer, info, addr, prom_builder).await });
-        tracing::info!("Ready");
-        server.await??;
+        return registry
+            .download(adapter_id, None)
+            .await
+            .with_context(|| format!("Could not download LoRA adapter {adapter_id}"));
     }
 
-    #[cfg(feature = "grpc")]
-    {
-        let server =
-            tokio::spawn(async move { grpc::server::run(infer, info, addr, prom_builder).await });
-        tracing::info!("Ready");
-        server.await??;
+    let mut builder = ApiBuilder::new()
+        .with_progress(false)
+        .with_token(hf_api_token);
+
+    if let Some(cache_dir) = huggingface_hub_cache {
+        builder = builder.with_cache_dir(cache_dir.into());
     }
 
-    Ok(())
+    let api = builder.build().unwrap();
+    download_lora_adapter(&api.model(adapter_id.to_string()))
+        .await
+        .with_context(|| format!("Could not download LoRA adapter {adapter_id}"))
 }
 
-#[derive(Debug, Deserialize)]
-pub struct ModelConfig {
-    pub architectures: Vec<String>,
-    pub model_type: String,
-    #[serde(alias = "n_positions")]
-    pub max_position_embeddings: usize,
-    pub pad_token_id: usize,
-    pub id2label: Option<HashMap<String, String>>,
-    pub label2id: Option<HashMap<String, usize>>,
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[cfg_attr(feature = "http", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum ProblemType {
+    SingleLabelClassification,
+    MultiLabelClassification,
+    Regression,
 }
 
-#[derive(Debug, Clone, PartialEq, Deserialize)]
-pub struct PoolConfig {
-    pooling_mode_cls_token: bool,
-    pooling_mode_mean_tokens: bool,
-    pooling_mode_max_tokens: bool,
-    pooling_mode_mean_sqrt_len_tokens: bool,
+impl From<text_embeddings_core::model::ProblemType> for ProblemType {
+    fn from(value: text_embeddings_core::model::ProblemType) -> Self {
+        match value {
+            text_embeddings_core::model::ProblemType::SingleLabelClassification => {
+                ProblemType::SingleLabelClassification
+            }
+            text_embeddings_core::model::ProblemType::MultiLabelClassification => {
+                ProblemType::MultiLabelClassification
+            }
+            text_embeddings_core::model::ProblemType::Regression => ProblemType::Regression,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize)]
@@ -355,6 +1074,11 @@ pub struct ClassifierModel {
     pub id2label: HashMap<String, String>,
     #[cfg_attr(feature = "http", schema(example = json!({"LABEL": 0})))]
     pub label2id: HashMap<String, usize>,
+    #[cfg_attr(
+        feature = "http",
+        schema(nullable = true, example = "multi_label_classification", default = "null")
+    )]
+    pub problem_type: Option<ProblemType>,
 }
 
 #[derive(Clone, Debug, Serialize)]
@@ -377,9 +1101,21 @@ pub struct Info {
         schema(nullable = true, example = "fca14538aa9956a46526bd1d0d11d69e19b5a101")
     )]
     pub model_sha: Option<String>,
+    #[cfg_attr(
+        feature = "http",
+        schema(nullable = true, example = "null", default = "null")
+    )]
+    pub lora_adapter: Option<String>,
     #[cfg_attr(feature = "http", schema(example = "float16"))]
     pub model_dtype: String,
     pub model_type: ModelType,
+    #[cfg_attr(feature = "http", schema(example = json!({"query": "query: "})))]
+    pub prompts: HashMap<String, String>,
+    #[cfg_attr(
+        feature = "http",
+        schema(nullable = true, example = "query", default = "null")
+    )]
+    pub default_prompt_name: Option<String>,
     /// Router Parameters
     #[cfg_attr(feature = "http", schema(example = "128"))]
     pub max_concurrent_requests: usize,
@@ -403,6 +1139,68 @@ pub struct Info {
     pub sha: Option<&'static str>,
     #[cfg_attr(feature = "http", schema(nullable = true, example = "null"))]
     pub docker_label: Option<&'static str>,
+    /// Returned by `/admin/model`
+    #[serde(skip)]
+    pub metadata: ModelMetadata,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[cfg_attr(feature = "http", derive(utoipa::ToSchema))]
+pub struct ModelMetadata {
+    #[cfg_attr(feature = "http", schema(example = "thenlper/gte-base"))]
+    pub model_id: String,
+    /// Revision set with `--revision`
+    #[cfg_attr(
+        feature = "http",
+        schema(nullable = true, example = "main", default = "null")
+    )]
+    pub revision: Option<String>,
+    /// Commit of the Hub snapshot the model was loaded from
+    #[cfg_attr(
+        feature = "http",
+        schema(nullable = true, example = "fca14538aa9956a46526bd1d0d11d69e19b5a101")
+    )]
+    pub commit_sha: Option<String>,
+    #[cfg_attr(
+        feature = "http",
+        schema(nullable = true, example = "null", default = "null")
+    )]
+    pub lora_adapter: Option<String>,
+    #[cfg_attr(feature = "http", schema(example = json!(["BertModel"])))]
+    pub architectures: Vec<String>,
+    /// `model_type` of `config.json`
+    #[cfg_attr(feature = "http", schema(example = "bert"))]
+    pub model_type: String,
+    #[cfg_attr(feature = "http", schema(example = "float16"))]
+    pub dtype: String,
+    /// Pooling of embedding models
+    #[cfg_attr(
+        feature = "http",
+        schema(nullable = true, example = "cls", default = "null")
+    )]
+    pub pooling: Option<String>,
+    #[cfg_attr(feature = "http", schema(example = "512"))]
+    pub max_input_length: usize,
+    /// Size of the embeddings of embedding models
+    #[cfg_attr(
+        feature = "http",
+        schema(nullable = true, example = "768", default = "null")
+    )]
+    pub embedding_dimension: Option<usize>,
+    pub tokenizer: TokenizerMetadata,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[cfg_attr(feature = "http", derive(utoipa::ToSchema))]
+pub struct TokenizerMetadata {
+    /// Tokenization algorithm
+    #[cfg_attr(feature = "http", schema(example = "WordPiece"))]
+    pub model: String,
+    /// Size of the vocabulary, including the added tokens
+    #[cfg_attr(feature = "http", schema(example = "30522"))]
+    pub vocab_size: usize,
+    #[cfg_attr(feature = "http", schema(example = json!(["[CLS]", "[SEP]"])))]
+    pub special_tokens: Vec<String>,
 }
 
 #[derive(Serialize)]
@@ -413,6 +1211,10 @@ pub enum ErrorType {
     Overloaded,
     Validation,
     Tokenizer,
+    Unauthorized,
+    QuotaExceeded,
+    Timeout,
+    NotFound,
 }
 
 #[derive(Serialize)]
@@ -437,6 +1239,222 @@ impl From<TextEmbeddingsError> for ErrorResponse {
     }
 }
 
+/// Models served by this process. The first model is the default model.
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "http"), allow(dead_code))]
+struct Models {
+    models: Arc<Vec<RwLock<(Infer, Info)>>>,
+    /// Copies of the default model with a LoRA adapter merged
+    adapters: Arc<Vec<RwLock<(Infer, Info)>>>,
+    args: Arc<ModelArgs>,
+    /// Number of reloads. The lock also prevents concurrent reloads.
+    reloads: Arc<tokio::sync::Mutex<usize>>,
+    gpu: Option<GpuMonitor>,
+    health: HealthState,
+}
+
+impl Models {
+    fn new(
+        models: Vec<(Infer, Info)>,
+        adapters: Vec<(Infer, Info)>,
+        args: ModelArgs,
+        gpu: Option<GpuMonitor>,
+    ) -> Result<Self> {
+        if models.is_empty() {
+            return Err(anyhow!("At least one `--model-id` must be given"));
+        }
+        for (i, (_, info)) in models.iter().enumerate() {
+            if models[..i].iter().any(|(_, other)| other.model_id == info.model_id) {
+                return Err(anyhow!("Model {} is given twice", info.model_id));
+            }
+        }
+        for (i, (_, info)) in adapters.iter().enumerate() {
+            if adapters[..i].iter().any(|(_, other)| other.lora_adapter == info.lora_adapter) {
+                return Err(anyhow!(
+                    "LoRA adapter {} is given twice",
+                    info.lora_adapter.as_deref().unwrap_or_default()
+                ));
+            }
+        }
+        Ok(Self {
+            models: Arc::new(models.into_iter().map(RwLock::new).collect()),
+            adapters: Arc::new(adapters.into_iter().map(RwLock::new).collect()),
+            args: Arc::new(args),
+            reloads: Arc::new(tokio::sync::Mutex::new(0)),
+            gpu,
+            health: HealthState::new(),
+        })
+    }
+
+    fn default_model(&self) -> (Infer, Info) {
+        self.models[0].read().unwrap().clone()
+    }
+
+    fn all(&self) -> Vec<(Infer, Info)> {
+        self.models
+            .iter()
+            .map(|model| model.read().unwrap().clone())
+            .chain(self.adapters.iter().map(|adapter| adapter.read().unwrap().clone()))
+            .collect()
+    }
+
+    #[cfg(feature = "http")]
+    fn position(&self, model: &str) -> Result<usize, ErrorResponse> {
+        self.models
+            .iter()
+            .position(|m| m.read().unwrap().1.model_id == model)
+            .ok_or_else(|| {
+                metrics::increment_counter!("te_request_failure", "err" => "model");
+                let message = format!("Model {model} is not served");
+                tracing::error!("{message}");
+                ErrorResponse {
+                    error: message,
+                    error_type: ErrorType::NotFound,
+                }
+            })
+    }
+
+    /// Select the model serving a request: `model` if set, the default model otherwise.
+    /// `adapter` selects a LoRA adapter merged into the default model.
+    #[cfg(feature = "http")]
+    fn get(
+        &self,
+        model: Option<&str>,
+        adapter: Option<&str>,
+    ) -> Result<(Infer, Info), ErrorResponse> {
+        if let Some(adapter) = adapter {
+            let index = match model {
+                Some(model) => self.position(model)?,
+                None => 0,
+            };
+            if index != 0 {
+                metrics::increment_counter!("te_request_failure", "err" => "adapter");
+                let message = "LoRA adapters are only loaded for the default model".to_string();
+                tracing::error!("{message}");
+                return Err(ErrorResponse {
+                    error: message,
+                    error_type: ErrorType::Validation,
+                });
+            }
+            return self
+                .adapters
+                .iter()
+                .map(|adapter| adapter.read().unwrap().clone())
+                .find(|(_, info)| info.lora_adapter.as_deref() == Some(adapter))
+                .ok_or_else(|| {
+                    metrics::increment_counter!("te_request_failure", "err" => "adapter");
+                    let message = format!("LoRA adapter {adapter} is not loaded");
+                    tracing::error!("{message}");
+                    ErrorResponse {
+                        error: message,
+                        error_type: ErrorType::Validation,
+                    }
+                });
+        }
+
+        match model {
+            Some(model) => {
+                let index = self.position(model)?;
+                Ok(self.models[index].read().unwrap().clone())
+            }
+            None => Ok(self.default_model()),
+        }
+    }
+
+    /// Deadline of a request: `timeout_ms` if set, `--request-timeout-ms` otherwise.
+    /// Clients can only shorten `--request-timeout-ms`.
+    #[cfg(feature = "http")]
+    fn timeout(&self, timeout_ms: Option<u64>) -> Option<Duration> {
+        match (timeout_ms.map(Duration::from_millis), self.args.request_timeout) {
+            (Some(timeout), Some(max)) => Some(timeout.min(max)),
+            (timeout, max) => timeout.or(max),
+        }
+    }
+
+    /// Load `revision` of a served model and swap it with the current instance once it is warm.
+    /// The current instance finishes its in-flight requests in the background.
+    /// The LoRA adapters are merged again into the new weights of the default model.
+    #[cfg(feature = "http")]
+    async fn reload(
+        &self,
+        model: Option<&str>,
+        revision: Option<String>,
+    ) -> Result<Info, ErrorResponse> {
+        let mut reloads = self.reloads.lock().await;
+
+        let index = match model {
+            Some(model) => self.position(model)?,
+            None => 0,
+        };
+        let model_id = self.models[index].read().unwrap().1.model_id.clone();
+
+        // The Python backend of the current instance still listens on its socket
+        *reloads += 1;
+        let uds_path = format!("{}-reload-{}", self.args.uds_path, *reloads);
+
+        tracing::info!("Reloading model {model_id}");
+        // `load_model` runs a forward pass on the backend which warms it up
+        let reload_error = |err: anyhow::Error| {
+            let message = format!("Could not reload model: {err:#}");
+            tracing::error!("{message}");
+            ErrorResponse {
+                error: message,
+                error_type: ErrorType::Backend,
+            }
+        };
+        let (infer, info) = load_model(
+            model_id.clone(),
+            revision.clone(),
+            None,
+            uds_path.clone(),
+            &self.args,
+        )
+        .await
+        .map_err(reload_error)?;
+
+        // The adapters are merged into the default model so they are loaded again as well.
+        // Nothing is swapped until all of them are warm
+        let mut adapters = Vec::new();
+        if index == 0 {
+            for (i, adapter) in self.adapters.iter().enumerate() {
+                let adapter_id = adapter.read().unwrap().1.lora_adapter.clone();
+                let adapter_id = adapter_id.unwrap_or_default();
+                tracing::info!("Reloading LoRA adapter {adapter_id}");
+                let uds_path = format!("{uds_path}-adapter-{i}");
+                adapters.push(
+                    load_model(
+                        model_id.clone(),
+                        revision.clone(),
+                        Some(adapter_id),
+                        uds_path,
+                        &self.args,
+                    )
+                    .await
+                    .map_err(reload_error)?,
+                );
+            }
+        }
+
+        let mut previous = vec![std::mem::replace(
+            &mut *self.models[index].write().unwrap(),
+            (infer, info.clone()),
+        )];
+        for (adapter, reloaded) in self.adapters.iter().zip(adapters) {
+            previous.push(std::mem::replace(&mut *adapter.write().unwrap(), reloaded));
+        }
+        tracing::info!("Model {} swapped", info.model_id);
+
+        tokio::spawn(async move {
+            for (previous, _) in previous {
+                previous.shutdown().await;
+            }
+            tracing::info!("Previous model instance drained");
+        });
+
+        Ok(info)
+    }
+}
+
 struct ResponseMetadata {
     compute_chars: usize,
     compute_tokens: usize,
@@ -444,6 +1462,7 @@ struct ResponseMetadata {
     tokenization_time: Duration,
     queue_time: Duration,
     inference_time: Duration,
+    batch_id: Option<u64>,
 }
 
 impl ResponseMetadata {
@@ -462,9 +1481,16 @@ impl ResponseMetadata {
             tokenization_time,
             queue_time,
             inference_time,
+            batch_id: None,
         }
     }
 
+    /// Batch the request was computed in. Only known for requests with a single input
+    fn with_batch_id(mut self, batch_id: Option<u64>) -> Self {
+        self.batch_id = batch_id;
+        self
+    }
+
     fn record_span(&self, span: &Span) {
         // Tracing metadata
         span.record("compute_chars", self.compute_chars);
@@ -548,6 +1574,9 @@ impl From<ResponseMetadata> for HeaderMap {
                 .parse()
                 .unwrap(),
         );
+        if let Some(batch_id) = value.batch_id {
+            headers.insert("x-batch-id", batch_id.to_string().parse().unwrap());
+        }
         headers
     }
 }
@@ -5,8 +5,12 @@ use opentelemetry::{global, KeyValue};
 use opentelemetry_otlp::WithExportConfig;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::filter::Directive;
 use tracing_subscriber::{EnvFilter, Layer};
 
+/// Target of the access log events written with `--json-access-log`
+pub(crate) const ACCESS_LOG_TARGET: &str = "access_log";
+
 /// Init logging using env variables LOG_LEVEL and LOG_FORMAT:
 ///     - otlp_endpoint is an optional URL to an Open Telemetry collector
 ///     - LOG_LEVEL may be TRACE, DEBUG, INFO, WARN or ERROR (default to INFO)
@@ -54,8 +58,10 @@ pub fn init_logging(otlp_endpoint: Option<&String>, json_output: bool) -> bool {
     }
 
     // Filter events with LOG_LEVEL
-    let env_filter =
-        EnvFilter::try_from_env("LOG_LEVEL").unwrap_or_else(|_| EnvFilter::new("info"));
+    // The access log is only written when enabled, whatever the level
+    let env_filter = EnvFilter::try_from_env("LOG_LEVEL")
+        .unwrap_or_else(|_| EnvFilter::new("info"))
+        .add_directive(format!("{ACCESS_LOG_TARGET}=info").parse::<Directive>().unwrap());
 
     tracing_subscriber::registry()
         .with(env_filter)
@@ -1,7 +1,8 @@
 use anyhow::Result;
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches, Parser};
 use opentelemetry::global;
 use text_embeddings_backend::DType;
+use text_embeddings_router::{Command, PreprocessingStep, RateLimitKey, TruncationDirection};
 use veil::Redact;
 
 /// App Configuration
@@ -12,10 +13,20 @@ struct Args {
     /// Can be a MODEL_ID as listed on <https://hf.co/models> like
     /// `thenlper/gte-base`.
     /// Or it can be a local directory containing the necessary files
-    /// as saved by `save_pretrained(...)` methods of transformers
-    #[clap(default_value = "thenlper/gte-base", long, env)]
+    /// as saved by `save_pretrained(...)` methods of transformers.
+    /// Or it can be the URI of a model of another registry, selected by its scheme:
+    /// `modelscope://<org>/<model>` on ModelScope, `https://` URL of a directory holding the same
+    /// files or of a `.tar`, `.tar.gz` or `.tgz` archive of it, or `s3://`, `gs://` or `az://` URI
+    /// of a directory of an object store, which requires the `object-store` feature.
+    /// The files are copied to the Hugging Face Hub cache
+    ///
+    /// Can be repeated to serve several models from the same process. Requests are routed with
+    /// their `model` field and the first model is used by default. Requests for a model that is
+    /// not served get a 404. The tokenization, queue and inference metrics are labelled with their
+    /// `model`.
+    #[clap(default_value = "thenlper/gte-base", long, env, value_delimiter = ',')]
     #[redact(partial)]
-    model_id: String,
+    model_id: Vec<String>,
 
     /// The actual revision of the model if you're referring to a model
     /// on the hub. You can use a specific commit id or a branch like `refs/pr/2`.
@@ -28,6 +39,13 @@ struct Args {
     #[clap(long, env)]
     tokenization_workers: Option<usize>,
 
+    /// Optionally control the number of inputs waiting for a tokenizer worker.
+    /// Requests wait for a free slot once the queue is full, so a burst of large documents does not
+    /// pile up in memory.
+    /// Default to 4 times the number of tokenizer workers
+    #[clap(long, env)]
+    tokenization_queue_size: Option<usize>,
+
     /// The dtype to be forced upon the model.
     #[clap(long, env, value_enum)]
     dtype: Option<DType>,
@@ -41,6 +59,26 @@ struct Args {
     #[clap(long, env, value_enum)]
     pooling: Option<text_embeddings_backend::Pool>,
 
+    /// Side of the inputs that is cut when `truncate` is set and they are longer than the maximum
+    /// input length. `left` keeps the last tokens, as needed by the queries of decoder-based
+    /// embedders and by some rerankers. Requests can override it with `truncation_direction`
+    #[clap(default_value = "right", long, env, value_enum)]
+    truncation_direction: TruncationDirection,
+
+    /// Optionally override the maximum number of tokens of an input, which defaults to the
+    /// `max_position_embeddings` of the model configuration.
+    /// A lower value bounds the latency of the requests. A higher value is only supported by the
+    /// models whose position embeddings extrapolate to longer sequences, such as ALiBi
+    #[clap(long, env)]
+    max_input_length: Option<usize>,
+
+    /// Optionally preprocess the texts of the inputs before tokenization, with steps separated by
+    /// commas. The steps run in a fixed order: `strip-control`, the Unicode normalization,
+    /// `lowercase` and `collapse-whitespace`.
+    /// The offsets returned by `/tokenize` refer to the text that was sent
+    #[clap(long, env, value_delimiter = ',', value_enum)]
+    preprocessing: Vec<PreprocessingStep>,
+
     /// The maximum amount of concurrent requests for this particular deployment.
     /// Having a low limit will refuse clients requests instead of having them
     /// wait for too long and is usually good to handle backpressure correctly.
@@ -58,14 +96,45 @@ struct Args {
     /// Overall this number should be the largest possible until the model is compute bound.
     /// Since the actual memory overhead depends on the model implementation,
     /// text-embeddings-inference cannot infer this number automatically.
+    ///
+    /// A batch of this size is run at startup, so a value that does not fit on the device fails
+    /// before the server accepts requests.
     #[clap(default_value = "16384", long, env)]
     max_batch_tokens: usize,
 
+    /// Probe the largest batch the device can run at startup and use it as `max_batch_tokens`.
+    /// Batches of sequences of the maximum input length are run with a doubling size until
+    /// the forward fails. `--max-batch-tokens` is then the upper bound of the probe
+    #[clap(long, env)]
+    calibrate_max_batch_tokens: bool,
+
     /// Optionally control the maximum number of individual requests in a batch
     #[clap(long, env)]
     max_batch_requests: Option<usize>,
 
-    /// Control the maximum number of inputs that a client can send in a single request
+    /// Maximum share of padding tokens in a batch, between 0 and 1.
+    /// Only used by backends that pad the batches (CPU, Metal or models that cannot use the flash
+    /// attention path). Requests are batched with the queued requests closest in length.
+    /// Not capped if not set
+    #[clap(long, env)]
+    max_padding_ratio: Option<f32>,
+
+    /// Round the padded length of the batches up to a multiple of this number of tokens, for
+    /// example `32`.
+    /// Only used by backends that pad the batches. Fewer distinct shapes let the kernel caches of
+    /// oneDNN or Metal hit, at the cost of some padding
+    #[clap(long, env)]
+    padding_bucket_size: Option<usize>,
+
+    /// Run the batches of embedding models in sub-batches of at most this number of tokens,
+    /// shortest inputs first, and answer the requests of a sub-batch as soon as it is computed.
+    /// Lowers the latency of short inputs batched with long documents, at the cost of throughput.
+    /// Batches are not split if not set
+    #[clap(long, env)]
+    sub_batch_tokens: Option<usize>,
+
+    /// Control the maximum number of inputs that a client can send in a single request.
+    /// Larger `/predict` batches are processed in chunks of this size
     #[clap(default_value = "32", long, env)]
     max_client_batch_size: usize,
 
@@ -74,6 +143,12 @@ struct Args {
     #[redact(partial)]
     hf_api_token: Option<String>,
 
+    /// Bearer token sent to the `modelscope://`, `http://` and `https://` registries of
+    /// `--model-id` and `--lora-adapters`
+    #[clap(long, env)]
+    #[redact(partial)]
+    model_registry_token: Option<String>,
+
     /// The IP address to listen on
     #[clap(default_value = "0.0.0.0", long, env)]
     hostname: String,
@@ -92,43 +167,343 @@ struct Args {
     #[clap(long, env)]
     huggingface_hub_cache: Option<String>,
 
+    /// Do not access the network to load the models. Models are resolved from local directories
+    /// or from the Hugging Face Hub cache, and loading fails with the list of the missing files
+    /// if they are incomplete.
+    #[clap(long, env)]
+    offline: bool,
+
     /// Outputs the logs in JSON format (useful for telemetry)
     #[clap(long, env)]
     json_output: bool,
 
+    /// Export the traces of the requests to this OpenTelemetry collector.
+    /// The traces of the HTTP and gRPC clients are continued if they send a `traceparent` header.
+    /// Every request has spans for its tokenization and the batch it is computed in.
     #[clap(long, env)]
     otlp_endpoint: Option<String>,
+
+    /// Run the model with the TensorRT execution provider of the ONNX Runtime backend and cache
+    /// the built engines in this directory.
+    /// Engines are built for `max_batch_requests` (or `max_client_batch_size`) and the model
+    /// maximum input length.
+    #[clap(long, env)]
+    tensorrt_engine_cache: Option<String>,
+
+    /// Run the model with the OpenVINO execution provider of the ONNX Runtime backend on this
+    /// device (for example `CPU`, `GPU` or `GPU.1`).
+    #[clap(long, env)]
+    openvino_device: Option<String>,
+
+    /// Load a model replica on every visible CUDA device and dispatch each batch to the least
+    /// loaded replica.
+    #[clap(long, env)]
+    data_parallel: bool,
+
+    /// Place the model replicas running on CPU on the NUMA nodes of the machine.
+    /// `bind` pins a single replica to the CPUs of the first node. `replica-per-node` loads a
+    /// replica pinned to the CPUs of every node, so that the GEMMs of a replica only read memory
+    /// local to its node.
+    /// Only available on Linux
+    #[clap(default_value = "none", long, env, value_enum)]
+    numa: text_embeddings_backend::NumaPolicy,
+
+    /// Verify the SHA-256 of the weights downloaded from the Hub and fail if the checkpoint has
+    /// tensors the model does not use or lacks tensors it needs, listing the tensor names.
+    /// Only available with the Candle backend
+    #[clap(long, env)]
+    strict_load: bool,
+
+    /// Make the outputs bit-exact across runs on the same hardware and software versions.
+    /// Every batch holds a single input, so that outputs do not depend on the other requests in
+    /// flight, TF32 matmuls and the cuBLAS and MKL kernels with non-deterministic reductions are
+    /// disabled, and the server fails to start if the same input does not give the same bits
+    /// twice. Lowers the throughput, especially on GPUs.
+    /// Only available with the Candle backend
+    #[clap(long, env)]
+    deterministic: bool,
+
+    /// Report the server as unhealthy on `/health` when a GPU has less free memory than this
+    /// many MB. The memory is read every 5 seconds with NVML, which also exports the GPU
+    /// memory, utilization, SM clock and temperature with the Prometheus metrics.
+    /// Only available on CUDA builds
+    #[clap(long, env)]
+    min_free_gpu_memory: Option<u64>,
+
+    /// Run a single token through the models every this many seconds.
+    /// `/health/ready` fails when the last probe failed and `/health/live` fails after 3 failed
+    /// probes in a row, so that a wedged device is restarted. A probe fails if it does not
+    /// complete within the interval. Set to 0 to disable the probe
+    #[clap(default_value = "10", long, env)]
+    health_probe_interval: u64,
+
+    /// Seconds given to the requests in flight to complete after a SIGTERM or Ctrl+C.
+    /// New connections are refused as soon as the signal is received. The requests still running
+    /// after the timeout are dropped, then the queued batches are drained and the models are
+    /// unloaded
+    #[clap(default_value = "30", long, env)]
+    shutdown_timeout: u64,
+
+    /// PEFT LoRA adapters to merge into the default model.
+    /// Can be MODEL_IDs as listed on <https://hf.co/models> or local directories containing
+    /// `adapter_config.json` and `adapter_model.safetensors`.
+    ///
+    /// Each adapter is served by its own copy of the model and is selected with the `adapter`
+    /// request field.
+    #[clap(long, env, value_delimiter = ',')]
+    lora_adapters: Vec<String>,
+
+    /// The port the gRPC server listens on when the router is built with both the `http` and
+    /// `grpc` features. Ignored otherwise.
+    #[clap(default_value = "50051", long, env)]
+    grpc_port: u16,
+
+    /// Also serve the requests on this Unix socket, e.g. for sidecars on the same host.
+    /// `@name` binds the abstract socket `name` on Linux. When the router is built with both the
+    /// `http` and `grpc` features, the gRPC server listens on `<PATH>-grpc`.
+    /// Clients of the socket are identified by the loopback address
+    #[clap(long, env)]
+    listen_uds_path: Option<String>,
+
+    /// Octal permissions of the socket file of `--listen-uds-path`, e.g. `660`
+    #[clap(long, env)]
+    listen_uds_mode: Option<String>,
+
+    /// Cache up to this number of pooled embeddings in memory. Identical inputs sent with the
+    /// same parameters are then served without tokenization or inference.
+    /// Set to 0 to disable the cache.
+    #[clap(default_value = "0", long, env)]
+    embedding_cache_size: usize,
+
+    /// Time in seconds after which a cached embedding expires.
+    /// Cached embeddings never expire if not set.
+    #[clap(long, env)]
+    embedding_cache_ttl: Option<u64>,
+
+    /// Store the cached embeddings in Redis instead of in memory, for example
+    /// `redis://127.0.0.1:6379`. The store can be shared by several replicas of the router.
+    /// Requires the `redis` feature.
+    #[clap(long, env)]
+    #[redact(partial)]
+    embedding_cache_redis_url: Option<String>,
+
+    /// API keys allowed to call the inference routes with an `Authorization: Bearer <key>`
+    /// header. The health, metrics, info and documentation routes stay public.
+    /// Authentication is disabled if neither `--api-key` nor `--api-key-file` is set.
+    #[clap(long, env, value_delimiter = ',')]
+    #[redact(partial)]
+    api_key: Vec<String>,
+
+    /// JSON file holding additional API keys with optional per-key quotas, for example
+    /// `[{"key": "...", "max_requests_per_minute": 60, "max_tokens_per_minute": 100000}]`.
+    /// Requests exceeding a quota are rejected with a 429 status code. `/v1/batches` jobs exceeding
+    /// the token quota fail before any input is embedded.
+    #[clap(long, env)]
+    api_key_file: Option<String>,
+
+    /// Serve the `/admin/reload`, `/admin/usage` and `/admin/model` routes to the clients sending
+    /// this key in an `Authorization: Bearer <key>` header. The routes are not served if not set.
+    #[clap(long, env)]
+    #[redact(partial)]
+    admin_api_key: Option<String>,
+
+    /// Serve HTTPS (and gRPC over TLS) with this PEM encoded certificate chain.
+    /// Requires `--tls-key-path`. The HTTP server reloads the certificate and key when the
+    /// process receives a SIGHUP.
+    #[clap(long, env)]
+    tls_cert_path: Option<String>,
+
+    /// PEM encoded private key of `--tls-cert-path`
+    #[clap(long, env)]
+    tls_key_path: Option<String>,
+
+    /// Require clients to present a certificate signed by one of the CAs in this PEM file
+    /// (mutual TLS)
+    #[clap(long, env)]
+    tls_client_ca_path: Option<String>,
+
+    /// Allow each client this number of requests per second on average with a token bucket.
+    /// Requests over the limit are rejected with a 429 status code and a `Retry-After` header.
+    /// The health, metrics, info and documentation routes are not limited. Every frame of a `/ws`
+    /// connection counts as a request.
+    #[clap(long, env)]
+    rate_limit_per_second: Option<f64>,
+
+    /// Number of requests a client can send at once before being rate limited.
+    /// Defaults to `rate_limit_per_second`.
+    #[clap(long, env)]
+    rate_limit_burst: Option<u32>,
+
+    /// How clients are identified by the rate limiter. `api-key` requires `--api-key` or
+    /// `--api-key-file` as only the authorized keys are limited.
+    #[clap(default_value = "ip", long, env, value_enum)]
+    rate_limit_by: RateLimitKey,
+
+    /// Reject requests with a 429 status code as soon as this number of requests are already
+    /// being processed, instead of queueing them. Every frame of a `/ws` connection counts as a
+    /// request.
+    #[clap(long, env)]
+    max_in_flight_requests: Option<usize>,
+
+    /// Default deadline of the inference requests in milliseconds. Requests that are not
+    /// completed in time fail with a 408 status code and are removed from the queue.
+    /// Can be shortened per request with the `timeout_ms` field.
+    #[clap(long, env)]
+    request_timeout_ms: Option<u64>,
+
+    /// Origins allowed to make cross-origin requests to the HTTP server, separated by commas.
+    /// All origins are allowed if not set.
+    #[clap(long, env, value_delimiter = ',')]
+    cors_allow_origin: Option<Vec<String>>,
+
+    /// Maximum size in bytes of the request payloads. Larger requests are rejected with a 413
+    /// status code.
+    #[clap(default_value = "2000000", long, env)]
+    payload_limit: usize,
+
+    /// Hosts the input files of the `/v1/batches` jobs can be downloaded from, separated by
+    /// commas. The downloaded files are limited to `--payload-limit` bytes.
+    /// The buckets of `s3://`, `gs://` and `az://` URIs are listed the same way; the input files
+    /// are read from and the results written to them with the credentials of the server, which
+    /// requires the `object-store` feature.
+    /// Jobs can only be created by uploading their input file if not set.
+    #[clap(long, env, value_delimiter = ',')]
+    batch_allowed_hosts: Vec<String>,
+
+    /// Compress the HTTP responses larger than 1KB with gzip or zstd, depending on the
+    /// `Accept-Encoding` header of the request.
+    #[clap(long, env)]
+    compress_responses: bool,
+
+    /// Log an event with the `access_log` target for every inference request, with the client IP
+    /// address, a fingerprint of its API key, the route, the status, the input token count, the
+    /// queue, inference and total times in ms and the id of the batch it was computed in.
+    /// Events are written as JSON lines with `--json-output`. Only available for the HTTP server
+    #[clap(long, env)]
+    json_access_log: bool,
+
+    /// Header identifying the tenant of a request, e.g. `x-tenant-id`, in the request and prompt
+    /// token counters of `/admin/usage` (see `--admin-api-key`).
+    /// The header is ignored for the requests sending an API key: their tenant is a fingerprint
+    /// of the key.
+    /// The gRPC calls are counted too, with the header read from their metadata
+    #[clap(long, env)]
+    usage_tenant_header: Option<String>,
+
+    /// TOML or YAML file holding arguments keyed by their name, e.g. `max_batch_tokens = 32768`.
+    /// The command line takes precedence over the environment variables, which take precedence
+    /// over the file
+    #[clap(long, env)]
+    config: Option<String>,
+
+    /// Print the effective configuration as a TOML file and exit. Secrets are redacted
+    #[clap(long)]
+    print_config: bool,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
+    // The configuration file sets the environment variables of the arguments it holds
+    let config = Args::command().ignore_errors(true).get_matches();
+    if let Some(path) = config.get_one::<String>("config") {
+        text_embeddings_router::config::apply_config_file(&Args::command(), path)?;
+    }
+
     // Pattern match configuration
-    let args: Args = Args::parse();
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    if args.print_config {
+        let config = text_embeddings_router::config::print_config(&Args::command(), &matches);
+        print!("{config}");
+        return Ok(());
+    }
 
+    // The libraries read their environment when they are initialized and the environment must
+    // not be modified once other threads may read it: set it before the runtime starts
+    if args.deterministic {
+        text_embeddings_backend::set_deterministic_env();
+    }
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(serve(args))
+}
+
+async fn serve(args: Args) -> Result<()> {
     // Initialize logging and telemetry
     let global_tracer =
         text_embeddings_router::init_logging(args.otlp_endpoint.as_ref(), args.json_output);
 
     tracing::info!("{args:?}");
 
-    text_embeddings_router::run(
-        args.model_id,
-        args.revision,
-        args.tokenization_workers,
-        args.dtype,
-        args.pooling,
-        args.max_concurrent_requests,
-        args.max_batch_tokens,
-        args.max_batch_requests,
-        args.max_client_batch_size,
-        args.hf_api_token,
-        Some(args.hostname),
-        args.port,
-        Some(args.uds_path),
-        args.huggingface_hub_cache,
-        args.otlp_endpoint,
-    )
-    .await?;
+    let run_args = text_embeddings_router::RunArgs {
+        model_ids: args.model_id,
+        revision: args.revision,
+        tokenization_workers: args.tokenization_workers,
+        tokenization_queue_size: args.tokenization_queue_size,
+        dtype: args.dtype,
+        pooling: args.pooling,
+        truncation_direction: args.truncation_direction,
+        max_input_length: args.max_input_length,
+        preprocessing: args.preprocessing,
+        max_concurrent_requests: args.max_concurrent_requests,
+        max_batch_tokens: args.max_batch_tokens,
+        calibrate_max_batch_tokens: args.calibrate_max_batch_tokens,
+        max_batch_requests: args.max_batch_requests,
+        max_padding_ratio: args.max_padding_ratio,
+        padding_bucket_size: args.padding_bucket_size,
+        sub_batch_tokens: args.sub_batch_tokens,
+        max_client_batch_size: args.max_client_batch_size,
+        hf_api_token: args.hf_api_token,
+        model_registry_token: args.model_registry_token,
+        hostname: Some(args.hostname),
+        port: args.port,
+        uds_path: Some(args.uds_path),
+        huggingface_hub_cache: args.huggingface_hub_cache,
+        offline: args.offline,
+        otlp_endpoint: args.otlp_endpoint,
+        tensorrt_engine_cache: args.tensorrt_engine_cache,
+        openvino_device: args.openvino_device,
+        data_parallel: args.data_parallel,
+        numa: args.numa,
+        strict_load: args.strict_load,
+        deterministic: args.deterministic,
+        min_free_gpu_memory: args.min_free_gpu_memory,
+        health_probe_interval: args.health_probe_interval,
+        shutdown_timeout: args.shutdown_timeout,
+        lora_adapters: args.lora_adapters,
+        grpc_port: args.grpc_port,
+        listen_uds_path: args.listen_uds_path,
+        listen_uds_mode: args.listen_uds_mode,
+        embedding_cache_size: args.embedding_cache_size,
+        embedding_cache_ttl: args.embedding_cache_ttl,
+        embedding_cache_redis_url: args.embedding_cache_redis_url,
+        api_keys: args.api_key,
+        api_key_file: args.api_key_file,
+        admin_api_key: args.admin_api_key,
+        tls_cert_path: args.tls_cert_path,
+        tls_key_path: args.tls_key_path,
+        tls_client_ca_path: args.tls_client_ca_path,
+        rate_limit_per_second: args.rate_limit_per_second,
+        rate_limit_burst: args.rate_limit_burst,
+        rate_limit_by: args.rate_limit_by,
+        max_in_flight_requests: args.max_in_flight_requests,
+        request_timeout_ms: args.request_timeout_ms,
+        cors_allow_origin: args.cors_allow_origin,
+        payload_limit: args.payload_limit,
+        batch_allowed_hosts: args.batch_allowed_hosts,
+        compress_responses: args.compress_responses,
+        json_access_log: args.json_access_log,
+        usage_tenant_header: args.usage_tenant_header,
+    };
+
+    match args.command {
+        Some(command) => text_embeddings_router::run_command(run_args, command).await?,
+        None => text_embeddings_router::run(run_args).await?,
+    }
 
     if global_tracer {
         // Shutdown tracer
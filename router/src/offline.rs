@@ -0,0 +1,115 @@
+/// Resolution of the model files without network access
+use crate::registry::safetensors_shards;
+use anyhow::{anyhow, Result};
+use hf_hub::{Cache, Repo, RepoType};
+use std::fs;
+use std::path::{Path, PathBuf};
+use text_embeddings_core::slow_tokenizer::SLOW_TOKENIZER_FILES;
+
+/// Files that are loaded if present
+const OPTIONAL_FILES: [&str; 2] = ["config_sentence_transformers.json", "2_Dense/config.json"];
+
+/// Snapshot of `model_id` at `revision` in the Hub cache
+pub(crate) fn cached_model_root(
+    model_id: &str,
+    revision: Option<&str>,
+    cache_dir: Option<String>,
+    pooling_set: bool,
+) -> Result<PathBuf> {
+    let cache = cache_dir
+        .map(|dir| Cache::new(dir.into()))
+        .unwrap_or_default();
+    let repo = cache.repo(Repo::with_revision(
+        model_id.to_string(),
+        RepoType::Model,
+        revision.unwrap_or("main").to_string(),
+    ));
+
+    check_model_files(model_id, |file| repo.get(file), pooling_set)?;
+    let config_path = repo.get("config.json").expect("checked above");
+    Ok(config_path.parent().unwrap().to_path_buf())
+}
+
+/// Model files of the local directory `model_root`
+pub(crate) fn local_file(model_root: &Path, file: &str) -> Option<PathBuf> {
+    Some(model_root.join(file)).filter(|path| path.exists())
+}
+
+/// Fail with the list of the missing files if the model cannot be loaded from the files that
+/// `lookup` finds locally
+pub(crate) fn check_model_files(
+    model_id: &str,
+    lookup: impl Fn(&str) -> Option<PathBuf>,
+    pooling_set: bool,
+) -> Result<()> {
+    let mut missing = Vec::new();
+    if lookup("config.json").is_none() {
+        missing.push("config.json".to_string());
+    }
+    // The slow tokenizer of the models without `tokenizer.json` is converted at startup
+    let slow_tokenizer = SLOW_TOKENIZER_FILES
+        .iter()
+        .any(|files| files.iter().all(|file| lookup(file).is_some()));
+    if lookup("tokenizer.json").is_none() && !slow_tokenizer {
+        missing.push("tokenizer.json".to_string());
+    }
+
+    let weights: &[&str] = if cfg!(feature = "ort") {
+        &["model.onnx", "onnx/model.onnx"]
+    } else {
+        &["model.safetensors", "model.safetensors.index.json", "pytorch_model.bin", "model.gguf"]
+    };
+    match weights.iter().find_map(|file| lookup(file).map(|path| (*file, path))) {
+        None => missing.push(format!("weights: one of {}", weights.join(", "))),
+        Some(("model.safetensors.index.json", index_path)) => {
+            missing.extend(missing_shards(&index_path, &lookup)?);
+        }
+        Some(_) => {}
+    }
+
+    // Classifiers do not pool, embedding models need a pooling config if `--pooling` is not set
+    if !pooling_set
+        && !is_classifier(lookup("config.json"))
+        && lookup("1_Pooling/config.json").is_none()
+    {
+        missing.push("1_Pooling/config.json (or set `--pooling`)".to_string());
+    }
+
+    if !missing.is_empty() {
+        return Err(anyhow!(
+            "Model {model_id} cannot be loaded offline. Missing files:\n- {}",
+            missing.join("\n- ")
+        ));
+    }
+
+    for file in OPTIONAL_FILES {
+        if lookup(file).is_none() {
+            tracing::info!("`{file}` is not available offline and will not be used");
+        }
+    }
+    Ok(())
+}
+
+/// Shards listed in `model.safetensors.index.json` that are not available
+fn missing_shards(
+    index_path: &Path,
+    lookup: &impl Fn(&str) -> Option<PathBuf>,
+) -> Result<Vec<String>> {
+    Ok(safetensors_shards(index_path)?
+        .into_iter()
+        .filter(|shard| lookup(shard).is_none())
+        .collect())
+}
+
+fn is_classifier(config_path: Option<PathBuf>) -> bool {
+    config_path
+        .and_then(|path| fs::read_to_string(path).ok())
+        .and_then(|config| serde_json::from_str::<serde_json::Value>(&config).ok())
+        .and_then(|config| config["architectures"].as_array().cloned())
+        .map_or(false, |architectures| {
+            architectures
+                .iter()
+                .filter_map(|arch| arch.as_str())
+                .any(|arch| arch.ends_with("Classification"))
+        })
+}
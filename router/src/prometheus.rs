@@ -28,10 +28,15 @@ pub(crate) fn prometheus_builer(max_input_length: usize) -> Result<PrometheusBui
     let batch_tokens_matcher = Matcher::Full(String::from("te_batch_next_tokens"));
     let batch_tokens_buckets: Vec<f64> = (0..21).map(|x| 2.0_f64.powi(x)).collect();
 
+    // Padding and token budget ratio buckets
+    let ratio_matcher = Matcher::Suffix(String::from("ratio"));
+    let ratio_buckets: Vec<f64> = (1..=20).map(|x| x as f64 * 0.05).collect();
+
     // Prometheus handler
     PrometheusBuilder::new()
         .set_buckets_for_metric(duration_matcher, &duration_buckets)?
         .set_buckets_for_metric(input_length_matcher, &input_length_buckets)?
         .set_buckets_for_metric(batch_size_matcher, &batch_size_buckets)?
-        .set_buckets_for_metric(batch_tokens_matcher, &batch_tokens_buckets)
+        .set_buckets_for_metric(batch_tokens_matcher, &batch_tokens_buckets)?
+        .set_buckets_for_metric(ratio_matcher, &ratio_buckets)
 }
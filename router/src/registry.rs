@@ -0,0 +1,320 @@
+/// Registries providing the files of the models whose id starts with a URI scheme.
+/// Model ids without a scheme are downloaded from the Hugging Face Hub.
+use crate::storage;
+use anyhow::{anyhow, Context, Result};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use reqwest::{Client, StatusCode};
+use std::fs;
+use std::path::{Path, PathBuf};
+use text_embeddings_core::slow_tokenizer::{SLOW_TOKENIZER_CONFIG_FILES, SLOW_TOKENIZER_FILES};
+use tokio::io::AsyncWriteExt;
+
+/// Files API of ModelScope
+const MODELSCOPE_ENDPOINT: &str = "https://modelscope.cn/api/v1/models";
+
+/// Source of the files of the models, selected by the scheme of their id
+pub(crate) trait ModelRegistry: Send + Sync {
+    /// Copy the files of `model_id` to the cache and return the local directory holding them
+    fn download<'a>(
+        &'a self,
+        model_id: &'a str,
+        revision: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<PathBuf>>;
+}
+
+/// Registry handling the scheme of `model_id`, or `None` if the id has no scheme.
+/// `token` is sent as a bearer token to the HTTP registries.
+pub(crate) fn registry(
+    model_id: &str,
+    cache_dir: Option<String>,
+    token: Option<String>,
+) -> Result<Option<Box<dyn ModelRegistry>>> {
+    let Some((scheme, _)) = model_id.split_once("://") else {
+        return Ok(None);
+    };
+    let cache_dir = cache_dir
+        .map(PathBuf::from)
+        .unwrap_or_else(|| hf_hub::Cache::default().path().clone())
+        .join("registries");
+
+    let registry: Box<dyn ModelRegistry> = match scheme {
+        "s3" | "gs" | "az" | "abfs" | "abfss" => Box::new(ObjectStoreRegistry { cache_dir }),
+        "modelscope" => Box::new(ModelScopeRegistry {
+            files: FileDownloader::new(token),
+            cache_dir,
+        }),
+        "http" | "https" => Box::new(HttpRegistry {
+            files: FileDownloader::new(token),
+            cache_dir,
+        }),
+        scheme => {
+            return Err(anyhow!(
+                "Unsupported scheme `{scheme}://`. Supported schemes are `s3://`, `gs://`, \
+                 `az://`, `modelscope://`, `http://` and `https://`"
+            ))
+        }
+    };
+    Ok(Some(registry))
+}
+
+/// Amazon S3, Google Cloud Storage and Azure Blob Storage
+struct ObjectStoreRegistry {
+    cache_dir: PathBuf,
+}
+
+impl ModelRegistry for ObjectStoreRegistry {
+    fn download<'a>(
+        &'a self,
+        model_id: &'a str,
+        _revision: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<PathBuf>> {
+        storage::download_model(model_id, &self.cache_dir).boxed()
+    }
+}
+
+/// `modelscope://<org>/<model>` on ModelScope. The revision defaults to `master`
+struct ModelScopeRegistry {
+    files: FileDownloader,
+    cache_dir: PathBuf,
+}
+
+impl ModelRegistry for ModelScopeRegistry {
+    fn download<'a>(
+        &'a self,
+        model_id: &'a str,
+        revision: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<PathBuf>> {
+        async move {
+            let name = model_id.trim_start_matches("modelscope://");
+            let revision = revision.unwrap_or("master");
+            let root = self
+                .cache_dir
+                .join("modelscope")
+                .join(relative_path(name))
+                .join(revision);
+            let url = |file: &str| {
+                format!("{MODELSCOPE_ENDPOINT}/{name}/repo?Revision={revision}&FilePath={file}")
+            };
+            self.files.download_model(&root, url).await?;
+            Ok(root)
+        }
+        .boxed()
+    }
+}
+
+/// `https://` URL of a directory holding the files of the model, or of a `.tar`, `.tar.gz` or
+/// `.tgz` archive of this directory
+struct HttpRegistry {
+    files: FileDownloader,
+    cache_dir: PathBuf,
+}
+
+impl ModelRegistry for HttpRegistry {
+    fn download<'a>(
+        &'a self,
+        model_id: &'a str,
+        _revision: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<PathBuf>> {
+        async move {
+            let (scheme, location) = model_id.split_once("://").unwrap();
+            let root = self.cache_dir.join(scheme).join(relative_path(location));
+
+            if [".tar", ".tar.gz", ".tgz"]
+                .iter()
+                .any(|extension| model_id.ends_with(extension))
+            {
+                return self.files.download_archive(model_id, &root).await;
+            }
+
+            let base_url = model_id.trim_end_matches('/');
+            self.files
+                .download_model(&root, |file| format!("{base_url}/{file}"))
+                .await?;
+            Ok(root)
+        }
+        .boxed()
+    }
+}
+
+/// Downloads the files of a model one by one over HTTP
+struct FileDownloader {
+    client: Client,
+    token: Option<String>,
+}
+
+impl FileDownloader {
+    fn new(token: Option<String>) -> Self {
+        Self {
+            client: Client::new(),
+            token,
+        }
+    }
+
+    /// Download the files the router loads to `root`, for a model or a LoRA adapter. `url` gives
+    /// the URL of a file from its path relative to the model directory.
+    /// Files that are already in `root` are not downloaded again.
+    async fn download_model(&self, root: &Path, url: impl Fn(&str) -> String) -> Result<()> {
+        let fetch = |file: &str| self.fetch(url(file), root.join(file));
+
+        if fetch("adapter_config.json").await? {
+            if !fetch("adapter_model.safetensors").await? {
+                return Err(anyhow!("`adapter_model.safetensors` not found"));
+            }
+            return Ok(());
+        }
+
+        if !fetch("config.json").await? {
+            return Err(anyhow!("`config.json` not found"));
+        }
+        // The slow tokenizer of the models without `tokenizer.json` is converted at startup
+        if !fetch("tokenizer.json").await? {
+            let mut found = false;
+            'vocabulary: for files in SLOW_TOKENIZER_FILES {
+                for file in files {
+                    if !fetch(file).await? {
+                        continue 'vocabulary;
+                    }
+                }
+                found = true;
+                break;
+            }
+            if !found {
+                return Err(anyhow!("`tokenizer.json` not found"));
+            }
+            for file in SLOW_TOKENIZER_CONFIG_FILES {
+                fetch(file).await?;
+            }
+        }
+        for file in [
+            "1_Pooling/config.json",
+            "config_sentence_transformers.json",
+            "2_Dense/config.json",
+        ] {
+            fetch(file).await?;
+        }
+        if root.join("2_Dense/config.json").exists() && !fetch("2_Dense/model.safetensors").await? {
+            fetch("2_Dense/pytorch_model.bin").await?;
+        }
+
+        let weights_found = if cfg!(feature = "ort") {
+            fetch("model.onnx").await? || fetch("onnx/model.onnx").await?
+        } else if fetch("model.safetensors").await? {
+            true
+        } else if fetch("model.safetensors.index.json").await? {
+            for shard in safetensors_shards(&root.join("model.safetensors.index.json"))? {
+                if !fetch(&shard).await? {
+                    return Err(anyhow!("Shard `{shard}` not found"));
+                }
+            }
+            true
+        } else {
+            fetch("pytorch_model.bin").await?
+        };
+        if !weights_found {
+            return Err(anyhow!("No model weights found"));
+        }
+        Ok(())
+    }
+
+    /// Download and unpack the archive at `url` to `root` and return the directory of the
+    /// archive holding `config.json`
+    async fn download_archive(&self, url: &str, root: &Path) -> Result<PathBuf> {
+        let complete = root.join(".te-complete");
+        if !complete.exists() {
+            let archive = PathBuf::from(format!("{}.archive", root.display()));
+            if !self.fetch(url.to_string(), archive.clone()).await? {
+                return Err(anyhow!("`{url}` not found"));
+            }
+
+            let gzip = !url.ends_with(".tar");
+            let unpack_root = root.to_path_buf();
+            tokio::task::spawn_blocking(move || -> Result<()> {
+                let file = fs::File::open(&archive)?;
+                let reader: Box<dyn std::io::Read> = if gzip {
+                    Box::new(flate2::read::GzDecoder::new(file))
+                } else {
+                    Box::new(file)
+                };
+                tar::Archive::new(reader)
+                    .unpack(&unpack_root)
+                    .context("Could not unpack the model archive")?;
+                fs::remove_file(&archive)?;
+                Ok(())
+            })
+            .await??;
+            fs::write(&complete, "")?;
+        }
+
+        // Archives often hold the model directory instead of its files
+        if root.join("config.json").exists() {
+            return Ok(root.to_path_buf());
+        }
+        let directories: Vec<PathBuf> = fs::read_dir(root)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_dir())
+            .collect();
+        match directories.as_slice() {
+            [directory] if directory.join("config.json").exists() => Ok(directory.clone()),
+            _ => Err(anyhow!("`config.json` not found in `{url}`")),
+        }
+    }
+
+    /// Stream `url` to `path`. Returns `false` if the registry does not have the file
+    async fn fetch(&self, url: String, path: PathBuf) -> Result<bool> {
+        if path.exists() {
+            return Ok(true);
+        }
+
+        let mut request = self.client.get(&url);
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        let mut response = response
+            .error_for_status()
+            .with_context(|| format!("Could not download `{url}`"))?;
+
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        // Written next to `path` first so that an interrupted download is not mistaken for a
+        // cached file
+        let partial = PathBuf::from(format!("{}.part", path.display()));
+        let mut file = tokio::fs::File::create(&partial).await?;
+        while let Some(chunk) = response.chunk().await? {
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+        tokio::fs::rename(&partial, &path).await?;
+
+        tracing::info!("Downloaded `{url}`");
+        Ok(true)
+    }
+}
+
+/// Files of the shards listed in `model.safetensors.index.json`
+pub(crate) fn safetensors_shards(index_path: &Path) -> Result<Vec<String>> {
+    let index: serde_json::Value = serde_json::from_str(&fs::read_to_string(index_path)?)?;
+    let mut shards: Vec<String> = index["weight_map"]
+        .as_object()
+        .into_iter()
+        .flat_map(|weight_map| weight_map.values())
+        .filter_map(|shard| shard.as_str().map(str::to_string))
+        .collect();
+    shards.sort();
+    shards.dedup();
+    Ok(shards)
+}
+
+/// Cache directory of a location such as `host/path/to/model`
+fn relative_path(location: &str) -> PathBuf {
+    location
+        .split(['/', '?', '&', '='])
+        .filter(|part| !part.is_empty() && *part != "." && *part != "..")
+        .map(|part| part.replace(':', "_"))
+        .collect()
+}
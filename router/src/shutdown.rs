@@ -1,3 +1,4 @@
+use std::time::Duration;
 use tokio::signal;
 
 /// Shutdown signal handler
@@ -25,5 +26,11 @@ pub(crate) async fn shutdown_signal() {
     }
 
     tracing::info!("signal received, starting graceful shutdown");
-    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// Resolves `timeout` after the shutdown signal, when the requests still in flight are dropped
+pub(crate) async fn timeout_after_signal(timeout: Duration) {
+    shutdown_signal().await;
+    tokio::time::sleep(timeout).await;
+    tracing::warn!("Shutdown timeout reached, dropping the requests in flight");
 }
@@ -0,0 +1,199 @@
+/// Models and files stored in Amazon S3, Google Cloud Storage or Azure Blob Storage
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+
+/// Name of the file recording the downloaded objects in the local copy of a model
+#[cfg(feature = "object-store")]
+const MANIFEST: &str = ".te-manifest.json";
+
+/// Copy the objects under `uri` to `cache_dir` and return the local directory holding them.
+/// Credentials are read from the usual `AWS_*`, `GOOGLE_*` and `AZURE_*` environment variables.
+/// Objects are only downloaded again if their size or ETag changed, and the SHA-256 of the
+/// cached files is checked against the one computed while they were downloaded.
+pub(crate) async fn download_model(uri: &str, cache_dir: &Path) -> Result<PathBuf> {
+    #[cfg(feature = "object-store")]
+    {
+        use crate::integrity::sha256_file;
+        use anyhow::{anyhow, Context};
+        use futures::TryStreamExt;
+        use object_store::ObjectMeta;
+
+        let url = url::Url::parse(uri).with_context(|| format!("Invalid model URI `{uri}`"))?;
+        let (store, prefix) = object_store(&url)?;
+
+        let root = cache_dir
+            .join(url.scheme())
+            .join(url.host_str().unwrap_or_default())
+            .join(prefix.as_ref());
+
+        let objects: Vec<ObjectMeta> = store.list(Some(&prefix)).try_collect().await?;
+        if objects.is_empty() {
+            return Err(anyhow!("No object found under `{uri}`"));
+        }
+
+        let manifest_path = root.join(MANIFEST);
+        let mut manifest: Manifest = std::fs::read_to_string(&manifest_path)
+            .ok()
+            .and_then(|manifest| serde_json::from_str(&manifest).ok())
+            .unwrap_or_default();
+
+        for meta in objects {
+            let relative = meta.location.as_ref()[prefix.as_ref().len()..].trim_start_matches('/');
+            let local = root.join(relative);
+
+            if let Some(cached) = manifest.objects.get(relative) {
+                if cached.size == meta.size
+                    && cached.e_tag == meta.e_tag
+                    && local.is_file()
+                    && sha256_file(&local)? == cached.sha256
+                {
+                    continue;
+                }
+                tracing::warn!("Cached copy of `{}` is outdated or corrupted", meta.location);
+            }
+
+            tracing::info!("Downloading `{}` ({} bytes)", meta.location, meta.size);
+            let sha256 = download_object(store.as_ref(), &meta, &local)
+                .await
+                .with_context(|| format!("Could not download `{}`", meta.location))?;
+            manifest.objects.insert(
+                relative.to_string(),
+                CachedObject {
+                    size: meta.size,
+                    e_tag: meta.e_tag.clone(),
+                    sha256,
+                },
+            );
+            // Written after every object so that an interrupted download resumes where it stopped
+            std::fs::write(&manifest_path, serde_json::to_string(&manifest)?)?;
+        }
+
+        Ok(root)
+    }
+    #[cfg(not(feature = "object-store"))]
+    {
+        let _ = cache_dir;
+        anyhow::bail!("Loading `{uri}` requires a build with the `object-store` feature")
+    }
+}
+
+/// Whether `uri` is an `s3://`, `gs://` or `az://` URI
+#[cfg_attr(not(feature = "http"), allow(dead_code))]
+pub(crate) fn is_object_store_uri(uri: &str) -> bool {
+    uri.split_once("://")
+        .map_or(false, |(scheme, _)| matches!(scheme, "s3" | "gs" | "az" | "abfs" | "abfss"))
+}
+
+/// Read the object at `uri`. Returns `None` if it is larger than `max_size` bytes.
+/// Credentials are read from the same environment variables as for the models.
+#[cfg_attr(not(feature = "http"), allow(dead_code))]
+pub(crate) async fn read_object(uri: &str, max_size: usize) -> Result<Option<Vec<u8>>> {
+    #[cfg(feature = "object-store")]
+    {
+        use anyhow::Context;
+
+        let url = url::Url::parse(uri).with_context(|| format!("Invalid URI `{uri}`"))?;
+        let (store, path) = object_store(&url)?;
+        let object = store.get(&path).await?;
+        if object.meta.size > max_size {
+            return Ok(None);
+        }
+        Ok(Some(object.bytes().await?.to_vec()))
+    }
+    #[cfg(not(feature = "object-store"))]
+    {
+        let _ = max_size;
+        anyhow::bail!("Reading `{uri}` requires a build with the `object-store` feature")
+    }
+}
+
+/// Write `data` to the object at `uri`, replacing it if it exists
+#[cfg_attr(not(feature = "http"), allow(dead_code))]
+pub(crate) async fn write_object(uri: &str, data: Vec<u8>) -> Result<()> {
+    #[cfg(feature = "object-store")]
+    {
+        use anyhow::Context;
+
+        let url = url::Url::parse(uri).with_context(|| format!("Invalid URI `{uri}`"))?;
+        let (store, path) = object_store(&url)?;
+        store.put(&path, data.into()).await?;
+        Ok(())
+    }
+    #[cfg(not(feature = "object-store"))]
+    {
+        let _ = data;
+        anyhow::bail!("Writing `{uri}` requires a build with the `object-store` feature")
+    }
+}
+
+/// Client of the bucket or container of `url`, and the path of `url` in it
+#[cfg(feature = "object-store")]
+fn object_store(
+    url: &url::Url,
+) -> Result<(Box<dyn object_store::ObjectStore>, object_store::path::Path)> {
+    use object_store::aws::AmazonS3Builder;
+    use object_store::azure::MicrosoftAzureBuilder;
+    use object_store::gcp::GoogleCloudStorageBuilder;
+    use object_store::path::Path as ObjectPath;
+    use object_store::ObjectStore;
+
+    let uri = url.as_str();
+    let store: Box<dyn ObjectStore> = match url.scheme() {
+        "s3" => Box::new(AmazonS3Builder::from_env().with_url(uri).build()?),
+        "gs" => Box::new(GoogleCloudStorageBuilder::from_env().with_url(uri).build()?),
+        _ => Box::new(MicrosoftAzureBuilder::from_env().with_url(uri).build()?),
+    };
+    let path = ObjectPath::from_url_path(url.path())?;
+    Ok((store, path))
+}
+
+/// Objects copied to the local directory of a model, keyed by their path relative to it
+#[cfg(feature = "object-store")]
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+struct Manifest {
+    objects: std::collections::HashMap<String, CachedObject>,
+}
+
+#[cfg(feature = "object-store")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct CachedObject {
+    size: usize,
+    e_tag: Option<String>,
+    sha256: String,
+}
+
+/// Stream an object to `local` and return its SHA-256.
+/// The object is written next to `local` first and only moved in place once complete.
+#[cfg(feature = "object-store")]
+async fn download_object(
+    store: &dyn object_store::ObjectStore,
+    meta: &object_store::ObjectMeta,
+    local: &Path,
+) -> Result<String> {
+    use futures::StreamExt;
+    use sha2::{Digest, Sha256};
+    use tokio::io::AsyncWriteExt;
+
+    if let Some(parent) = local.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let partial = PathBuf::from(format!("{}.part", local.display()));
+    let mut file = tokio::fs::File::create(&partial).await?;
+
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    let mut stream = store.get(&meta.location).await?.into_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        hasher.update(&chunk);
+        size += chunk.len();
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+
+    if size != meta.size {
+        anyhow::bail!("Received {size} bytes instead of {}", meta.size);
+    }
+    tokio::fs::rename(&partial, local).await?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
@@ -0,0 +1,122 @@
+/// TLS termination of the HTTP and gRPC servers
+use anyhow::{Context, Result};
+
+/// Certificate paths given with `--tls-cert-path`, `--tls-key-path` and `--tls-client-ca-path`
+#[derive(Debug, Clone)]
+pub(crate) struct TlsConfig {
+    /// PEM encoded certificate chain
+    pub cert_path: String,
+    /// PEM encoded private key
+    pub key_path: String,
+    /// PEM encoded CA certificates used to verify client certificates (mTLS)
+    pub client_ca_path: Option<String>,
+}
+
+impl TlsConfig {
+    /// Build the TLS config of the HTTP server
+    #[cfg(feature = "http")]
+    pub(crate) fn rustls_config(&self) -> Result<std::sync::Arc<rustls::ServerConfig>> {
+        use anyhow::anyhow;
+        use rustls::server::AllowAnyAuthenticatedClient;
+        use rustls::{Certificate, PrivateKey, RootCertStore};
+        use std::fs::File;
+        use std::io::BufReader;
+
+        let load_certs = |path: &str| -> Result<Vec<Certificate>> {
+            let file = File::open(path).with_context(|| format!("Could not open `{path}`"))?;
+            let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+                .with_context(|| format!("Failed to parse certificates from `{path}`"))?;
+            if certs.is_empty() {
+                return Err(anyhow!("`{path}` does not contain any certificate"));
+            }
+            Ok(certs.into_iter().map(Certificate).collect())
+        };
+
+        let certs = load_certs(&self.cert_path)?;
+
+        let file = File::open(&self.key_path)
+            .with_context(|| format!("Could not open `{}`", self.key_path))?;
+        let mut reader = BufReader::new(file);
+        let key = loop {
+            match rustls_pemfile::read_one(&mut reader)
+                .with_context(|| format!("Failed to parse `{}`", self.key_path))?
+            {
+                Some(rustls_pemfile::Item::RSAKey(key))
+                | Some(rustls_pemfile::Item::PKCS8Key(key))
+                | Some(rustls_pemfile::Item::ECKey(key)) => break PrivateKey(key),
+                Some(_) => continue,
+                None => return Err(anyhow!("`{}` does not contain a private key", self.key_path)),
+            }
+        };
+
+        let builder = rustls::ServerConfig::builder().with_safe_defaults();
+        let builder = match &self.client_ca_path {
+            Some(client_ca_path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in load_certs(client_ca_path)? {
+                    roots
+                        .add(&cert)
+                        .map_err(|err| anyhow!("Invalid client CA certificate: {err}"))?;
+                }
+                builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder
+            .with_single_cert(certs, key)
+            .context("Invalid TLS certificate or key")?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        Ok(std::sync::Arc::new(config))
+    }
+
+    /// Build the TLS config of the gRPC server
+    #[cfg(feature = "grpc")]
+    pub(crate) fn tonic_config(&self) -> Result<tonic::transport::ServerTlsConfig> {
+        use std::fs;
+        use tonic::transport::{Certificate, Identity, ServerTlsConfig};
+
+        let cert = fs::read(&self.cert_path)
+            .with_context(|| format!("Could not read `{}`", self.cert_path))?;
+        let key = fs::read(&self.key_path)
+            .with_context(|| format!("Could not read `{}`", self.key_path))?;
+
+        let mut config = ServerTlsConfig::new().identity(Identity::from_pem(cert, key));
+        if let Some(client_ca_path) = &self.client_ca_path {
+            let client_ca = fs::read(client_ca_path)
+                .with_context(|| format!("Could not read `{client_ca_path}`"))?;
+            config = config.client_ca_root(Certificate::from_pem(client_ca));
+        }
+        Ok(config)
+    }
+}
+
+/// Reload the certificates of the HTTP server every time the process receives a SIGHUP
+#[cfg(feature = "http")]
+pub(crate) fn reload_on_sighup(
+    tls: TlsConfig,
+    rustls_config: axum_server::tls_rustls::RustlsConfig,
+) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup =
+            signal(SignalKind::hangup()).context("failed to install SIGHUP handler")?;
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                tracing::info!("SIGHUP received, reloading TLS certificates");
+                match tls.rustls_config() {
+                    Ok(config) => rustls_config.reload_from_config(config),
+                    // Keep serving with the previous certificates
+                    Err(err) => tracing::error!("Could not reload TLS certificates: {err:#}"),
+                }
+            }
+        });
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (tls, rustls_config);
+    }
+    Ok(())
+}
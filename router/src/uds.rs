@@ -0,0 +1,71 @@
+/// Unix domain sockets the servers listen on in addition to their TCP port
+use anyhow::{anyhow, Context, Result};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use tokio::net::UnixListener;
+
+/// Bind the socket `path`, or the abstract socket `name` if `path` is `@name` (Linux only).
+/// A socket file left at `path` by a previous process is replaced. `mode` holds the octal
+/// permissions of the socket file, e.g. `660` to only allow the clients of the same group.
+pub(crate) fn bind(path: &str, mode: Option<&str>) -> Result<UnixListener> {
+    let mode = mode
+        .map(|mode| {
+            u32::from_str_radix(mode, 8)
+                .map_err(|_| anyhow!("`--listen-uds-mode` must be an octal mode such as `660`"))
+        })
+        .transpose()?;
+
+    if let Some(name) = path.strip_prefix('@') {
+        if mode.is_some() {
+            return Err(anyhow!("`--listen-uds-mode` does not apply to abstract sockets"));
+        }
+        return bind_abstract(name);
+    }
+
+    let path = Path::new(path);
+    if path.exists() {
+        fs::remove_file(path).with_context(|| format!("Could not remove {path:?}"))?;
+    }
+    let listener = UnixListener::bind(path).with_context(|| format!("Could not bind {path:?}"))?;
+    if let Some(mode) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+            .with_context(|| format!("Could not set the permissions of {path:?}"))?;
+    }
+    Ok(listener)
+}
+
+#[cfg(target_os = "linux")]
+fn bind_abstract(name: &str) -> Result<UnixListener> {
+    use std::os::linux::net::SocketAddrExt;
+
+    let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+    let listener = std::os::unix::net::UnixListener::bind_addr(&addr)
+        .with_context(|| format!("Could not bind abstract socket `@{name}`"))?;
+    listener.set_nonblocking(true)?;
+    Ok(UnixListener::from_std(listener)?)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_abstract(name: &str) -> Result<UnixListener> {
+    Err(anyhow!("Abstract socket `@{name}` is only supported on Linux"))
+}
+
+/// Connections of a Unix socket for the HTTP server
+#[cfg(feature = "http")]
+pub(crate) struct UnixAccept(pub(crate) UnixListener);
+
+#[cfg(feature = "http")]
+impl hyper::server::accept::Accept for UnixAccept {
+    type Conn = tokio::net::UnixStream;
+    type Error = std::io::Error;
+
+    fn poll_accept(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Result<Self::Conn, Self::Error>>> {
+        self.0
+            .poll_accept(cx)
+            .map(|accepted| Some(accepted.map(|(stream, _)| stream)))
+    }
+}
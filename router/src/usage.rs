@@ -0,0 +1,95 @@
+/// Per tenant request and prompt token counters of the HTTP and gRPC servers
+use crate::auth::key_fingerprint;
+use http::HeaderMap;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Tenants seen after this many are counted together as `other`
+const MAX_TRACKED_TENANTS: usize = 10_000;
+
+#[derive(Serialize)]
+#[cfg_attr(feature = "http", derive(utoipa::ToSchema))]
+pub(crate) struct TenantUsage {
+    /// Value of the `--usage-tenant-header` header, fingerprint of the API key or `anonymous`
+    #[cfg_attr(feature = "http", schema(example = "team-a"))]
+    pub tenant: String,
+    #[cfg_attr(feature = "http", schema(example = "42"))]
+    pub requests: u64,
+    #[cfg_attr(feature = "http", schema(example = "5120"))]
+    pub prompt_tokens: u64,
+}
+
+/// Counters since the start of the process, keyed by tenant.
+/// They are not exported to Prometheus: a label per tenant would have an unbounded cardinality
+#[derive(Clone)]
+pub(crate) struct UsageCounters {
+    /// Header identifying the tenant of the requests without an API key
+    tenant_header: Option<String>,
+    tenants: Arc<Mutex<HashMap<String, (u64, u64)>>>,
+}
+
+impl UsageCounters {
+    pub(crate) fn new(tenant_header: Option<String>) -> Self {
+        Self {
+            tenant_header,
+            tenants: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Fingerprint of `api_key`, value of the tenant header or `anonymous`.
+    /// The tenant header is ignored when an API key is sent: it would let the holder of a key
+    /// bill its usage to another tenant
+    pub(crate) fn tenant(&self, headers: &HeaderMap, api_key: Option<&str>) -> String {
+        if let Some(api_key) = api_key {
+            return key_fingerprint(api_key);
+        }
+        self.tenant_header
+            .as_ref()
+            .and_then(|name| headers.get(name.as_str()))
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+            .unwrap_or_else(|| "anonymous".to_string())
+    }
+
+    /// Count a successful request of `tenant` with `prompt_tokens` tokens
+    pub(crate) fn record(&self, tenant: String, prompt_tokens: u64) {
+        self.add(tenant, 1, prompt_tokens)
+    }
+
+    /// Count `prompt_tokens` more tokens of a request of `tenant` that was already counted, e.g.
+    /// the tokens of a streamed line
+    pub(crate) fn record_tokens(&self, tenant: String, prompt_tokens: u64) {
+        self.add(tenant, 0, prompt_tokens)
+    }
+
+    fn add(&self, tenant: String, requests: u64, prompt_tokens: u64) {
+        let mut tenants = self.tenants.lock().unwrap();
+        let tenant = if tenants.len() >= MAX_TRACKED_TENANTS && !tenants.contains_key(&tenant) {
+            "other".to_string()
+        } else {
+            tenant
+        };
+
+        let (tenant_requests, tenant_tokens) = tenants.entry(tenant).or_default();
+        *tenant_requests += requests;
+        *tenant_tokens += prompt_tokens;
+    }
+
+    /// Counters of every tenant, sorted by tenant
+    pub(crate) fn snapshot(&self) -> Vec<TenantUsage> {
+        let mut usage: Vec<TenantUsage> = self
+            .tenants
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(tenant, (requests, prompt_tokens))| TenantUsage {
+                tenant: tenant.clone(),
+                requests: *requests,
+                prompt_tokens: *prompt_tokens,
+            })
+            .collect();
+        usage.sort_by(|a, b| a.tenant.cmp(&b.tenant));
+        usage
+    }
+}
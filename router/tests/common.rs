@@ -1,8 +1,10 @@
+#![allow(dead_code)]
+
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use std::time::Duration;
-use text_embeddings_backend::DType;
-use text_embeddings_router::run;
+use text_embeddings_backend::{DType, NumaPolicy, Pool};
+use text_embeddings_router::{run, RateLimitKey, RunArgs, TruncationDirection};
 use tokio::time::Instant;
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -44,24 +46,95 @@ async fn check_health(port: u16, timeout: Duration) -> Result<()> {
 }
 
 pub async fn start_server(model_id: String, revision: Option<String>, dtype: DType) -> Result<()> {
+    launch_server(model_id, revision, dtype, None, None).await
+}
+
+pub async fn start_server_with_admin_key(
+    model_id: String,
+    revision: Option<String>,
+    dtype: DType,
+    admin_api_key: Option<String>,
+) -> Result<()> {
+    launch_server(model_id, revision, dtype, None, admin_api_key).await
+}
+
+pub async fn start_server_with_pooling(
+    model_id: String,
+    revision: Option<String>,
+    dtype: DType,
+    pooling: Pool,
+) -> Result<()> {
+    launch_server(model_id, revision, dtype, Some(pooling), None).await
+}
+
+async fn launch_server(
+    model_id: String,
+    revision: Option<String>,
+    dtype: DType,
+    pooling: Option<Pool>,
+    admin_api_key: Option<String>,
+) -> Result<()> {
     let server_task = tokio::spawn({
-        run(
-            model_id,
+        run(RunArgs {
+            model_ids: vec![model_id],
             revision,
-            Some(1),
-            Some(dtype),
-            None,
-            4,
-            1024,
-            None,
-            32,
-            None,
-            None,
-            8090,
-            None,
-            None,
-            None,
-        )
+            tokenization_workers: Some(1),
+            tokenization_queue_size: None,
+            dtype: Some(dtype),
+            pooling,
+            truncation_direction: TruncationDirection::Right,
+            max_input_length: None,
+            preprocessing: vec![],
+            max_concurrent_requests: 4,
+            max_batch_tokens: 1024,
+            calibrate_max_batch_tokens: false,
+            max_batch_requests: None,
+            max_padding_ratio: None,
+            padding_bucket_size: None,
+            sub_batch_tokens: None,
+            max_client_batch_size: 32,
+            hf_api_token: None,
+            model_registry_token: None,
+            hostname: None,
+            port: 8090,
+            uds_path: None,
+            huggingface_hub_cache: None,
+            offline: false,
+            otlp_endpoint: None,
+            tensorrt_engine_cache: None,
+            openvino_device: None,
+            data_parallel: false,
+            numa: NumaPolicy::None,
+            strict_load: false,
+            deterministic: false,
+            min_free_gpu_memory: None,
+            health_probe_interval: 0,
+            shutdown_timeout: 30,
+            lora_adapters: vec![],
+            grpc_port: 50051,
+            listen_uds_path: None,
+            listen_uds_mode: None,
+            embedding_cache_size: 0,
+            embedding_cache_ttl: None,
+            embedding_cache_redis_url: None,
+            api_keys: vec![],
+            api_key_file: None,
+            admin_api_key,
+            tls_cert_path: None,
+            tls_key_path: None,
+            tls_client_ca_path: None,
+            rate_limit_per_second: None,
+            rate_limit_burst: None,
+            rate_limit_by: RateLimitKey::Ip,
+            max_in_flight_requests: None,
+            request_timeout_ms: None,
+            cors_allow_origin: None,
+            payload_limit: 2_000_000,
+            batch_allowed_hosts: vec![],
+            compress_responses: false,
+            json_access_log: false,
+            usage_tenant_header: None,
+        })
     });
 
     tokio::select! {
@@ -0,0 +1,121 @@
+mod common;
+
+use crate::common::{start_server, Score};
+use anyhow::Result;
+use insta::internals::YamlMatcher;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::time::Duration;
+use text_embeddings_backend::DType;
+
+#[derive(Deserialize, Debug)]
+pub struct Batch {
+    id: String,
+    object: String,
+    status: String,
+    request_counts: SnapshotRequestCounts,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct SnapshotRequestCounts {
+    total: usize,
+    completed: usize,
+    failed: usize,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BatchResult {
+    index: usize,
+    embedding: Vec<Score>,
+}
+
+#[tokio::test]
+#[cfg(feature = "http")]
+async fn test_batches() -> Result<()> {
+    start_server(
+        "sentence-transformers/all-MiniLM-L6-v2".to_string(),
+        None,
+        DType::Float32,
+    )
+    .await?;
+
+    let client = reqwest::Client::new();
+
+    let res = client
+        .post("http://0.0.0.0:8090/v1/batches")
+        .header("Content-Type", "application/jsonl")
+        .body("{\"input\": \"test\"}\n{\"input\": \"other\"}\n")
+        .send()
+        .await?;
+    let batch = res.json::<Batch>().await?;
+    assert_eq!(batch.object, "batch");
+    assert!(batch.id.starts_with("batch_"));
+
+    let mut batch = batch;
+    for _ in 0..60 {
+        if batch.status == "completed" {
+            break;
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        batch = client
+            .get(format!("http://0.0.0.0:8090/v1/batches/{}", batch.id))
+            .send()
+            .await?
+            .json::<Batch>()
+            .await?;
+    }
+    assert_eq!(batch.status, "completed");
+    let matcher = YamlMatcher::<SnapshotRequestCounts>::new();
+    insta::assert_yaml_snapshot!("request_counts", batch.request_counts, &matcher);
+
+    let results = client
+        .get(format!("http://0.0.0.0:8090/v1/batches/{}/results", batch.id))
+        .send()
+        .await?
+        .text()
+        .await?;
+    let results = results
+        .lines()
+        .map(serde_json::from_str::<BatchResult>)
+        .collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(results.len(), 2);
+
+    // Batch results are the embeddings of the inputs
+    for (i, (result, input)) in results.iter().zip(["test", "other"]).enumerate() {
+        assert_eq!(result.index, i);
+        let res = client
+            .post("http://0.0.0.0:8090/embed")
+            .json(&json!({ "inputs": input }))
+            .send()
+            .await?;
+        let embeddings = res.json::<Vec<Vec<Score>>>().await?;
+        assert_eq!(result.embedding, embeddings[0]);
+    }
+
+    // No host is allowed to serve input files by default
+    let res = client
+        .post("http://0.0.0.0:8090/v1/batches")
+        .json(&json!({ "input_file_url": "http://169.254.169.254/latest/meta-data" }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+    // Results can only be written to an object store
+    let res = client
+        .post("http://0.0.0.0:8090/v1/batches")
+        .query(&[("output_file_url", "https://example.com/results.jsonl")])
+        .header("Content-Type", "application/jsonl")
+        .body("{\"input\": \"test\"}\n")
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+    let res = client
+        .get("http://0.0.0.0:8090/v1/batches/batch_0")
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    Ok(())
+}
@@ -0,0 +1,61 @@
+mod common;
+
+use crate::common::start_server;
+use anyhow::Result;
+use insta::internals::YamlMatcher;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use text_embeddings_backend::DType;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct SnapshotCount {
+    tokens: usize,
+    truncated: bool,
+    chunks: usize,
+}
+
+#[tokio::test]
+#[cfg(feature = "http")]
+async fn test_count() -> Result<()> {
+    start_server(
+        "sentence-transformers/all-MiniLM-L6-v2".to_string(),
+        None,
+        DType::Float32,
+    )
+    .await?;
+
+    let client = reqwest::Client::new();
+
+    let inputs = ["test", "test test test"];
+    let res = client
+        .post("http://0.0.0.0:8090/count")
+        .json(&json!({ "inputs": inputs, "chunk_size": 2 }))
+        .send()
+        .await?;
+    let counts = res.json::<Vec<SnapshotCount>>().await?;
+    let matcher = YamlMatcher::<Vec<SnapshotCount>>::new();
+    insta::assert_yaml_snapshot!("counts", counts, &matcher);
+
+    // The chunk counts are the number of chunks of `/embed_chunks`
+    let res = client
+        .post("http://0.0.0.0:8090/embed_chunks")
+        .json(&json!({ "inputs": inputs, "chunk_size": 2 }))
+        .send()
+        .await?;
+    let chunks = res.json::<Vec<Vec<Value>>>().await?;
+    for (count, chunks) in counts.iter().zip(&chunks) {
+        assert_eq!(count.chunks, chunks.len());
+    }
+
+    // Inputs longer than the maximum input length are counted in full
+    let res = client
+        .post("http://0.0.0.0:8090/count")
+        .json(&json!({ "inputs": "test ".repeat(600) }))
+        .send()
+        .await?;
+    let counts = res.json::<Vec<SnapshotCount>>().await?;
+    assert_eq!(counts[0].tokens, 602);
+    assert!(counts[0].truncated);
+
+    Ok(())
+}
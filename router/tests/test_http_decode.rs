@@ -0,0 +1,58 @@
+mod common;
+
+use crate::common::start_server;
+use anyhow::Result;
+use insta::internals::YamlMatcher;
+use serde::Deserialize;
+use serde_json::json;
+use text_embeddings_backend::DType;
+
+#[derive(Deserialize, Debug)]
+pub struct Token {
+    id: u32,
+}
+
+#[tokio::test]
+#[cfg(feature = "http")]
+async fn test_decode() -> Result<()> {
+    start_server(
+        "sentence-transformers/all-MiniLM-L6-v2".to_string(),
+        None,
+        DType::Float32,
+    )
+    .await?;
+
+    let client = reqwest::Client::new();
+
+    let res = client
+        .post("http://0.0.0.0:8090/tokenize")
+        .json(&json!({ "inputs": ["test me", "hello world"] }))
+        .send()
+        .await?;
+    let tokens = res.json::<Vec<Vec<Token>>>().await?;
+    let ids: Vec<Vec<u32>> = tokens
+        .iter()
+        .map(|tokens| tokens.iter().map(|token| token.id).collect())
+        .collect();
+
+    // Decoding the ids of `/tokenize` gives the inputs back
+    let res = client
+        .post("http://0.0.0.0:8090/decode")
+        .json(&json!({ "ids": ids }))
+        .send()
+        .await?;
+    let texts = res.json::<Vec<String>>().await?;
+    let matcher = YamlMatcher::<Vec<String>>::new();
+    insta::assert_yaml_snapshot!("texts", texts, &matcher);
+
+    let res = client
+        .post("http://0.0.0.0:8090/decode")
+        .json(&json!({ "ids": ids[0], "skip_special_tokens": false }))
+        .send()
+        .await?;
+    let texts = res.json::<Vec<String>>().await?;
+    let matcher = YamlMatcher::<Vec<String>>::new();
+    insta::assert_yaml_snapshot!("texts_special_tokens", texts, &matcher);
+
+    Ok(())
+}
@@ -0,0 +1,90 @@
+mod common;
+
+use crate::common::{start_server, Score};
+use anyhow::Result;
+use insta::internals::YamlMatcher;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use text_embeddings_backend::DType;
+
+#[derive(Deserialize, Debug)]
+pub struct SnapshotChunk {
+    embedding: Vec<Score>,
+    start: usize,
+    stop: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct SnapshotSpan {
+    start: usize,
+    stop: usize,
+}
+
+#[tokio::test]
+#[cfg(feature = "http")]
+async fn test_embed_chunks() -> Result<()> {
+    start_server(
+        "sentence-transformers/all-MiniLM-L6-v2".to_string(),
+        None,
+        DType::Float32,
+    )
+    .await?;
+
+    let client = reqwest::Client::new();
+
+    let request = json!({
+        "inputs": "test test test",
+        "chunk_size": 2,
+    });
+    let res = client
+        .post("http://0.0.0.0:8090/embed_chunks")
+        .json(&request)
+        .send()
+        .await?;
+
+    let chunks = res.json::<Vec<Vec<SnapshotChunk>>>().await?;
+    let spans: Vec<SnapshotSpan> = chunks[0]
+        .iter()
+        .map(|chunk| SnapshotSpan {
+            start: chunk.start,
+            stop: chunk.stop,
+        })
+        .collect();
+    let matcher = YamlMatcher::<Vec<SnapshotSpan>>::new();
+    insta::assert_yaml_snapshot!("spans", spans, &matcher);
+
+    // The offsets are byte offsets
+    let input = "héllo wörld, ünïcode tëxt";
+    let request = json!({
+        "inputs": input,
+        "chunk_size": 2,
+        "chunk_overlap": 1,
+    });
+    let res = client
+        .post("http://0.0.0.0:8090/embed_chunks")
+        .json(&request)
+        .send()
+        .await?;
+
+    let chunks = res.json::<Vec<Vec<SnapshotChunk>>>().await?;
+    assert!(chunks[0].len() > 1);
+    assert_eq!(chunks[0][0].start, 0);
+    assert_eq!(chunks[0].last().unwrap().stop, input.len());
+
+    for chunk in &chunks[0] {
+        let text = input
+            .get(chunk.start..chunk.stop)
+            .expect("chunk offsets are not on character boundaries");
+
+        // Each chunk is embedded on its own
+        let res = client
+            .post("http://0.0.0.0:8090/embed")
+            .json(&json!({ "inputs": text }))
+            .send()
+            .await?;
+        let embeddings = res.json::<Vec<Vec<Score>>>().await?;
+        assert_eq!(embeddings[0], chunk.embedding);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,91 @@
+mod common;
+
+use crate::common::{start_server, Score};
+use anyhow::Result;
+use insta::internals::YamlMatcher;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use text_embeddings_backend::DType;
+
+#[derive(Deserialize, Debug)]
+pub struct PartialEmbedding {
+    embedding: Option<Vec<Score>>,
+    error: Option<Error>,
+    truncation: Option<SnapshotTruncation>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Error {
+    error: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SnapshotTruncation {
+    truncated: bool,
+    tokens_processed: usize,
+    tokens_dropped: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct SnapshotResult {
+    error: Option<String>,
+    truncation: Option<SnapshotTruncation>,
+}
+
+#[tokio::test]
+#[cfg(feature = "http")]
+async fn test_embed_partial() -> Result<()> {
+    start_server(
+        "sentence-transformers/all-MiniLM-L6-v2".to_string(),
+        None,
+        DType::Float32,
+    )
+    .await?;
+
+    let client = reqwest::Client::new();
+
+    // Invalid inputs get an error instead of failing the request
+    let request = json!({
+        "inputs": ["test", "", "test"],
+        "partial": true,
+        "return_truncation": true,
+    });
+    let res = client
+        .post("http://0.0.0.0:8090/embed")
+        .json(&request)
+        .send()
+        .await?;
+    assert!(res.status().is_success());
+
+    let results = res.json::<Vec<PartialEmbedding>>().await?;
+    let snapshot: Vec<SnapshotResult> = results
+        .iter()
+        .map(|result| SnapshotResult {
+            error: result.error.as_ref().map(|error| error.error.clone()),
+            truncation: result.truncation.clone(),
+        })
+        .collect();
+    let matcher = YamlMatcher::<Vec<SnapshotResult>>::new();
+    insta::assert_yaml_snapshot!("results", snapshot, &matcher);
+
+    // The valid inputs are embedded as without `partial`
+    let res = client
+        .post("http://0.0.0.0:8090/embed")
+        .json(&json!({ "inputs": "test" }))
+        .send()
+        .await?;
+    let embeddings = res.json::<Vec<Vec<Score>>>().await?;
+    assert_eq!(results[0].embedding.as_ref(), Some(&embeddings[0]));
+    assert!(results[1].embedding.is_none());
+    assert_eq!(results[2].embedding.as_ref(), Some(&embeddings[0]));
+
+    // The request fails on the first invalid input without `partial`
+    let res = client
+        .post("http://0.0.0.0:8090/embed")
+        .json(&json!({ "inputs": ["test", ""] }))
+        .send()
+        .await?;
+    assert!(!res.status().is_success());
+
+    Ok(())
+}
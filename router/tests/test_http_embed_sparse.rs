@@ -0,0 +1,72 @@
+mod common;
+
+use crate::common::{start_server_with_pooling, Score};
+use anyhow::Result;
+use insta::internals::YamlMatcher;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use text_embeddings_backend::{DType, Pool};
+
+#[derive(Deserialize, Debug)]
+pub struct SparseValue {
+    index: usize,
+    value: Score,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct SnapshotError {
+    error: String,
+}
+
+#[tokio::test]
+#[cfg(feature = "http")]
+async fn test_embed_sparse() -> Result<()> {
+    start_server_with_pooling(
+        "naver/efficient-splade-VI-BT-large-query".to_string(),
+        None,
+        DType::Float32,
+        Pool::Splade,
+    )
+    .await?;
+
+    let client = reqwest::Client::new();
+
+    let res = client
+        .post("http://0.0.0.0:8090/embed_sparse")
+        .json(&json!({ "inputs": [] }))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    let error = res.json::<SnapshotError>().await?;
+    let matcher = YamlMatcher::<SnapshotError>::new();
+    insta::assert_yaml_snapshot!("empty_inputs", error, &matcher);
+
+    let res = client
+        .post("http://0.0.0.0:8090/embed_sparse")
+        .json(&json!({ "inputs": ["test", "other"] }))
+        .send()
+        .await?;
+    let sparse = res.json::<Vec<Vec<SparseValue>>>().await?;
+    assert_eq!(sparse.len(), 2);
+
+    // Sparse embeddings hold the non-zero values of the dense SPLADE embeddings
+    let res = client
+        .post("http://0.0.0.0:8090/embed")
+        .json(&json!({ "inputs": ["test", "other"], "normalize": false }))
+        .send()
+        .await?;
+    let dense = res.json::<Vec<Vec<Score>>>().await?;
+    let zero: Score = serde_json::from_value(json!(0.0))?;
+    for (sparse, dense) in sparse.iter().zip(&dense) {
+        assert!(!sparse.is_empty());
+        let mut expected: Vec<&Score> = dense.iter().collect();
+        for value in sparse {
+            assert_eq!(&value.value, expected[value.index]);
+            expected[value.index] = &zero;
+        }
+        assert!(expected.into_iter().all(|value| *value == zero));
+    }
+
+    Ok(())
+}
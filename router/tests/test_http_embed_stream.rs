@@ -0,0 +1,108 @@
+mod common;
+
+use crate::common::{start_server_with_admin_key, Score};
+use anyhow::Result;
+use insta::internals::YamlMatcher;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use text_embeddings_backend::DType;
+
+#[derive(Deserialize, Debug)]
+pub struct StreamedEmbedding {
+    index: usize,
+    embedding: Vec<Score>,
+    truncation: SnapshotTruncation,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SnapshotTruncation {
+    truncated: bool,
+    tokens_processed: u64,
+    tokens_dropped: u64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Usage {
+    tenants: Vec<TenantUsage>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TenantUsage {
+    tenant: String,
+    requests: u64,
+    prompt_tokens: u64,
+}
+
+#[tokio::test]
+#[cfg(feature = "http")]
+async fn test_embed_stream() -> Result<()> {
+    start_server_with_admin_key(
+        "sentence-transformers/all-MiniLM-L6-v2".to_string(),
+        None,
+        DType::Float32,
+        Some("admin".to_string()),
+    )
+    .await?;
+
+    let client = reqwest::Client::new();
+
+    let request = json!({
+        "inputs": ["test", "test test"],
+        "return_truncation": true,
+    });
+    let res = client
+        .post("http://0.0.0.0:8090/embed")
+        .header("Accept", "application/x-ndjson")
+        .json(&request)
+        .send()
+        .await?;
+    assert_eq!(res.headers()["content-type"].to_str()?, "application/x-ndjson");
+
+    // Lines are sent in the order the inputs are embedded
+    let mut lines = res
+        .text()
+        .await?
+        .lines()
+        .map(serde_json::from_str::<StreamedEmbedding>)
+        .collect::<Result<Vec<_>, _>>()?;
+    lines.sort_by_key(|line| line.index);
+    assert_eq!(lines.len(), 2);
+
+    let truncation: Vec<SnapshotTruncation> =
+        lines.iter().map(|line| line.truncation.clone()).collect();
+    let matcher = YamlMatcher::<Vec<SnapshotTruncation>>::new();
+    insta::assert_yaml_snapshot!("truncation", truncation, &matcher);
+
+    // The stream is a single request and every line counts its tokens
+    let usage = client
+        .get("http://0.0.0.0:8090/admin/usage")
+        .bearer_auth("admin")
+        .send()
+        .await?
+        .json::<Usage>()
+        .await?;
+    let anonymous = usage
+        .tenants
+        .iter()
+        .find(|tenant| tenant.tenant == "anonymous")
+        .expect("the stream was not counted");
+    assert_eq!(anonymous.requests, 1);
+    let streamed_tokens: u64 = lines
+        .iter()
+        .map(|line| line.truncation.tokens_processed)
+        .sum();
+    assert_eq!(anonymous.prompt_tokens, streamed_tokens);
+
+    // The streamed embeddings are the embeddings of `/embed`
+    let res = client
+        .post("http://0.0.0.0:8090/embed")
+        .json(&json!({ "inputs": ["test", "test test"] }))
+        .send()
+        .await?;
+    let embeddings = res.json::<Vec<Vec<Score>>>().await?;
+    for (line, embedding) in lines.iter().zip(&embeddings) {
+        assert_eq!(&line.embedding, embedding);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,131 @@
+mod common;
+
+use crate::common::{start_server, Score};
+use anyhow::Result;
+use base64::Engine;
+use insta::internals::YamlMatcher;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use text_embeddings_backend::DType;
+
+#[derive(Deserialize, Debug)]
+pub struct OpenAIResponse {
+    data: Vec<OpenAIEmbedding>,
+    usage: SnapshotUsage,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct OpenAIEmbedding {
+    embedding: Value,
+    index: usize,
+    scale: Option<f32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct SnapshotUsage {
+    prompt_tokens: usize,
+    total_tokens: usize,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Token {
+    id: u32,
+}
+
+fn scores(values: impl IntoIterator<Item = f32>) -> Vec<Score> {
+    values
+        .into_iter()
+        .map(|value| serde_json::from_value(json!(value)).unwrap())
+        .collect()
+}
+
+#[tokio::test]
+#[cfg(feature = "http")]
+async fn test_openai_embeddings() -> Result<()> {
+    start_server(
+        "sentence-transformers/all-MiniLM-L6-v2".to_string(),
+        None,
+        DType::Float32,
+    )
+    .await?;
+
+    let client = reqwest::Client::new();
+    let embed = |request: Value| {
+        let client = client.clone();
+        async move {
+            let res = client
+                .post("http://0.0.0.0:8090/v1/embeddings")
+                .json(&request)
+                .send()
+                .await?;
+            Ok::<_, anyhow::Error>(res.json::<OpenAIResponse>().await?)
+        }
+    };
+
+    let response = embed(json!({ "input": "test" })).await?;
+    let float: Vec<f32> = serde_json::from_value(response.data[0].embedding.clone())?;
+
+    // Token ids are embedded as is
+    let res = client
+        .post("http://0.0.0.0:8090/tokenize")
+        .json(&json!({ "inputs": "test" }))
+        .send()
+        .await?;
+    let tokens = res.json::<Vec<Vec<Token>>>().await?;
+    let ids: Vec<u32> = tokens[0].iter().map(|token| token.id).collect();
+
+    let response = embed(json!({ "input": [ids.clone(), ids] })).await?;
+    let matcher = YamlMatcher::<SnapshotUsage>::new();
+    insta::assert_yaml_snapshot!("usage_ids", response.usage, &matcher);
+    assert_eq!(response.data.len(), 2);
+    for (i, embedding) in response.data.iter().enumerate() {
+        assert_eq!(embedding.index, i);
+        let values: Vec<f32> = serde_json::from_value(embedding.embedding.clone())?;
+        assert_eq!(scores(values), scores(float.clone()));
+    }
+
+    // Base64 embeddings are the little-endian float32 values
+    let response = embed(json!({ "input": "test", "encoding_format": "base64" })).await?;
+    let encoded: String = serde_json::from_value(response.data[0].embedding.clone())?;
+    let bytes = base64::engine::general_purpose::STANDARD.decode(encoded)?;
+    let decoded = bytes
+        .chunks_exact(4)
+        .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()));
+    assert_eq!(scores(decoded), scores(float.clone()));
+
+    // Int8 embeddings are recovered with their scale
+    let response = embed(json!({ "input": "test", "encoding_format": "int8" })).await?;
+    let scale = response.data[0].scale.expect("int8 embeddings have a scale");
+    let quantized: Vec<i8> = serde_json::from_value(response.data[0].embedding.clone())?;
+    let dequantized = quantized.into_iter().map(|value| value as f32 * scale);
+    assert_eq!(scores(dequantized), scores(float.clone()));
+
+    // Embeddings are cut to `dimensions` and normalized again
+    let response = embed(json!({ "input": "test", "dimensions": 16 })).await?;
+    let truncated: Vec<f32> = serde_json::from_value(response.data[0].embedding.clone())?;
+    let norm = float[..16].iter().map(|v| v * v).sum::<f32>().sqrt();
+    let expected = float[..16].iter().map(|v| v / norm);
+    assert_eq!(scores(truncated), scores(expected));
+
+    // Requests are routed by `model` and get a 404 for a model that is not served
+    let response = embed(json!({
+        "input": "test",
+        "model": "sentence-transformers/all-MiniLM-L6-v2"
+    }))
+    .await?;
+    let named: Vec<f32> = serde_json::from_value(response.data[0].embedding.clone())?;
+    assert_eq!(scores(named), scores(float));
+    for (route, request) in [
+        ("v1/embeddings", json!({ "input": "test", "model": "gpt-4" })),
+        ("embed", json!({ "inputs": "test", "model": "gpt-4" })),
+    ] {
+        let res = client
+            .post(format!("http://0.0.0.0:8090/{route}"))
+            .json(&request)
+            .send()
+            .await?;
+        assert_eq!(res.status(), reqwest::StatusCode::NOT_FOUND);
+    }
+
+    Ok(())
+}
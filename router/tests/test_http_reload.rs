@@ -0,0 +1,71 @@
+mod common;
+
+use crate::common::start_server_with_admin_key;
+use anyhow::Result;
+use futures::future::join_all;
+use reqwest::StatusCode;
+use serde_json::json;
+use std::time::Duration;
+use text_embeddings_backend::DType;
+
+#[tokio::test]
+#[cfg(feature = "http")]
+async fn test_reload() -> Result<()> {
+    start_server_with_admin_key(
+        "sentence-transformers/all-MiniLM-L6-v2".to_string(),
+        None,
+        DType::Float32,
+        Some("admin".to_string()),
+    )
+    .await?;
+
+    let client = reqwest::Client::new();
+
+    let res = client
+        .post("http://0.0.0.0:8090/admin/reload")
+        .json(&json!({}))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+    let reload = client
+        .post("http://0.0.0.0:8090/admin/reload")
+        .bearer_auth("admin")
+        .json(&json!({}))
+        .send();
+    tokio::pin!(reload);
+
+    // Keep sending requests until the reload is done so that some of them are in flight or
+    // waiting for a permit when the model is swapped
+    let request = json!({
+        "inputs": vec!["test"; 8],
+    });
+    let mut embeds = Vec::new();
+    let reload = loop {
+        tokio::select! {
+            res = &mut reload => break res?,
+            _ = tokio::time::sleep(Duration::from_millis(10)) => {
+                let embed = client
+                    .post("http://0.0.0.0:8090/embed")
+                    .json(&request)
+                    .send();
+                embeds.push(tokio::spawn(embed));
+            }
+        }
+    };
+    assert_eq!(reload.status(), StatusCode::OK);
+
+    for res in join_all(embeds).await {
+        assert_eq!(res??.status(), StatusCode::OK);
+    }
+
+    // The reloaded model serves the new requests
+    let res = client
+        .post("http://0.0.0.0:8090/embed")
+        .json(&json!({"inputs": "test"}))
+        .send()
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    Ok(())
+}
@@ -0,0 +1,100 @@
+mod common;
+
+use crate::common::{start_server, Score};
+use anyhow::Result;
+use insta::internals::YamlMatcher;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use text_embeddings_backend::DType;
+
+#[derive(Deserialize, Debug)]
+pub struct RerankCompatResponse {
+    results: Vec<RerankCompatResult>,
+    usage: RerankCompatUsage,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RerankCompatResult {
+    index: usize,
+    relevance_score: Score,
+    document: SnapshotDocument,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SnapshotDocument {
+    text: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct SnapshotResult {
+    index: usize,
+    document: SnapshotDocument,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RerankCompatUsage {
+    total_tokens: usize,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Rank {
+    index: usize,
+    score: Score,
+}
+
+#[tokio::test]
+#[cfg(feature = "http")]
+async fn test_rerank_compat() -> Result<()> {
+    start_server(
+        "BAAI/bge-reranker-base".to_string(),
+        Some("refs/pr/5".to_string()),
+        DType::Float32,
+    )
+    .await?;
+
+    // Documents are strings or objects with a `text`
+    let request = json!({
+        "query": "test",
+        "documents": ["test", {"text": "other"}, "test"],
+        "top_n": 2,
+        "return_documents": true
+    });
+
+    let client = reqwest::Client::new();
+    let res = client
+        .post("http://0.0.0.0:8090/v1/rerank")
+        .json(&request)
+        .send()
+        .await?;
+
+    let response = res.json::<RerankCompatResponse>().await?;
+    let results: Vec<SnapshotResult> = response
+        .results
+        .iter()
+        .map(|result| SnapshotResult {
+            index: result.index,
+            document: result.document.clone(),
+        })
+        .collect();
+    let matcher = YamlMatcher::<Vec<SnapshotResult>>::new();
+    insta::assert_yaml_snapshot!("results", results, &matcher);
+    assert!(response.usage.total_tokens > 0);
+
+    // The scores are the scores of `/rerank`
+    let request = json!({
+        "query": "test",
+        "texts": ["test", "other", "test"],
+    });
+    let res = client
+        .post("http://0.0.0.0:8090/rerank")
+        .json(&request)
+        .send()
+        .await?;
+    let ranks = res.json::<Vec<Rank>>().await?;
+    for (result, rank) in response.results.iter().zip(&ranks) {
+        assert_eq!(result.index, rank.index);
+        assert_eq!(result.relevance_score, rank.score);
+    }
+
+    Ok(())
+}
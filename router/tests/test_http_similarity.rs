@@ -0,0 +1,68 @@
+mod common;
+
+use crate::common::{start_server, Score};
+use anyhow::Result;
+use insta::internals::YamlMatcher;
+use serde_json::json;
+use text_embeddings_backend::DType;
+
+#[tokio::test]
+#[cfg(feature = "http")]
+async fn test_similarity() -> Result<()> {
+    start_server(
+        "sentence-transformers/all-MiniLM-L6-v2".to_string(),
+        None,
+        DType::Float32,
+    )
+    .await?;
+
+    let client = reqwest::Client::new();
+
+    // A sentence is identical to itself
+    let request = json!({
+        "inputs": {
+            "source_sentence": "test",
+            "sentences": ["test", "test"],
+        }
+    });
+    let res = client
+        .post("http://0.0.0.0:8090/similarity")
+        .json(&request)
+        .send()
+        .await?;
+
+    let similarities = res.json::<Vec<Score>>().await?;
+    let matcher = YamlMatcher::<Vec<Score>>::new();
+    insta::assert_yaml_snapshot!("similarities_identical", similarities, &matcher);
+
+    // Similarities are the cosine similarities of the normalized embeddings of `/embed`
+    let request = json!({
+        "inputs": {
+            "source_sentence": "test",
+            "sentences": ["other", "test test"],
+        }
+    });
+    let res = client
+        .post("http://0.0.0.0:8090/similarity")
+        .json(&request)
+        .send()
+        .await?;
+    let similarities = res.json::<Vec<Score>>().await?;
+
+    let res = client
+        .post("http://0.0.0.0:8090/embed")
+        .json(&json!({ "inputs": ["test", "other", "test test"] }))
+        .send()
+        .await?;
+    let embeddings = res.json::<Vec<Vec<f32>>>().await?;
+    let expected: Vec<Score> = embeddings[1..]
+        .iter()
+        .map(|embedding| {
+            let cosine: f32 = embeddings[0].iter().zip(embedding).map(|(a, b)| a * b).sum();
+            serde_json::from_value(json!(cosine)).unwrap()
+        })
+        .collect();
+    assert_eq!(similarities, expected);
+
+    Ok(())
+}
@@ -0,0 +1,126 @@
+mod common;
+
+use crate::common::{start_server_with_admin_key, Score};
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::json;
+use std::time::Duration;
+use text_embeddings_backend::DType;
+
+#[derive(Deserialize, Debug)]
+pub struct RequestUsage {
+    prompt_tokens: u64,
+    total_tokens: u64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct EmbedWithUsage {
+    data: Vec<Vec<Score>>,
+    usage: RequestUsage,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Usage {
+    tenants: Vec<TenantUsage>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TenantUsage {
+    tenant: String,
+    requests: u64,
+    prompt_tokens: u64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Batch {
+    id: String,
+    status: String,
+}
+
+#[tokio::test]
+#[cfg(feature = "http")]
+async fn test_usage() -> Result<()> {
+    start_server_with_admin_key(
+        "sentence-transformers/all-MiniLM-L6-v2".to_string(),
+        None,
+        DType::Float32,
+        Some("admin".to_string()),
+    )
+    .await?;
+
+    let client = reqwest::Client::new();
+
+    let res = client
+        .post("http://0.0.0.0:8090/embed")
+        .json(&json!({ "inputs": ["test", "test test"] }))
+        .send()
+        .await?;
+    let embeddings = res.json::<Vec<Vec<Score>>>().await?;
+
+    // The response is wrapped with the prompt tokens of the request
+    let res = client
+        .post("http://0.0.0.0:8090/embed")
+        .json(&json!({ "inputs": ["test", "test test"], "return_usage": true }))
+        .send()
+        .await?;
+    let response = res.json::<EmbedWithUsage>().await?;
+    assert_eq!(response.data, embeddings);
+    // Both inputs have a [CLS] and a [SEP] token
+    assert_eq!(response.usage.prompt_tokens, 7);
+    assert_eq!(response.usage.total_tokens, response.usage.prompt_tokens);
+
+    let usage = client
+        .get("http://0.0.0.0:8090/admin/usage")
+        .bearer_auth("admin")
+        .send()
+        .await?
+        .json::<Usage>()
+        .await?;
+    let anonymous = usage
+        .tenants
+        .iter()
+        .find(|tenant| tenant.tenant == "anonymous")
+        .expect("the requests were not counted");
+    assert_eq!(anonymous.requests, 2);
+    assert_eq!(anonymous.prompt_tokens, 2 * response.usage.prompt_tokens);
+
+    // A batch job is counted as one request with the prompt tokens of its inputs
+    let mut batch = client
+        .post("http://0.0.0.0:8090/v1/batches")
+        .header("Content-Type", "application/jsonl")
+        .body("{\"input\": \"test\"}\n{\"input\": \"test test\"}\n")
+        .send()
+        .await?
+        .json::<Batch>()
+        .await?;
+    for _ in 0..60 {
+        if batch.status == "completed" {
+            break;
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        batch = client
+            .get(format!("http://0.0.0.0:8090/v1/batches/{}", batch.id))
+            .send()
+            .await?
+            .json::<Batch>()
+            .await?;
+    }
+    assert_eq!(batch.status, "completed");
+
+    let usage = client
+        .get("http://0.0.0.0:8090/admin/usage")
+        .bearer_auth("admin")
+        .send()
+        .await?
+        .json::<Usage>()
+        .await?;
+    let anonymous = usage
+        .tenants
+        .iter()
+        .find(|tenant| tenant.tenant == "anonymous")
+        .unwrap();
+    assert_eq!(anonymous.requests, 3);
+    assert_eq!(anonymous.prompt_tokens, 3 * response.usage.prompt_tokens);
+
+    Ok(())
+}
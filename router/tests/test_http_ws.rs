@@ -0,0 +1,129 @@
+mod common;
+
+use crate::common::{start_server_with_admin_key, Score};
+use anyhow::Result;
+use futures::{SinkExt, StreamExt};
+use insta::internals::YamlMatcher;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use text_embeddings_backend::DType;
+use tokio_tungstenite::tungstenite::Message;
+
+#[derive(Deserialize, Debug)]
+pub struct WsEmbedResponse {
+    id: Option<u64>,
+    results: Vec<PartialEmbedding>,
+    error: Option<Error>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PartialEmbedding {
+    embedding: Option<Vec<Score>>,
+    error: Option<Error>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Error {
+    error: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct SnapshotResponse {
+    id: Option<u64>,
+    errors: Vec<Option<String>>,
+    error: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Usage {
+    tenants: Vec<TenantUsage>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TenantUsage {
+    tenant: String,
+    requests: u64,
+}
+
+#[tokio::test]
+#[cfg(feature = "http")]
+async fn test_ws() -> Result<()> {
+    start_server_with_admin_key(
+        "sentence-transformers/all-MiniLM-L6-v2".to_string(),
+        None,
+        DType::Float32,
+        Some("admin".to_string()),
+    )
+    .await?;
+
+    let (mut socket, _) = tokio_tungstenite::connect_async("ws://0.0.0.0:8090/ws").await?;
+
+    let frames = [
+        json!({ "id": 1, "inputs": "test" }).to_string(),
+        json!({ "id": 2, "inputs": ["test", ""], "partial": true }).to_string(),
+        "not a request".to_string(),
+    ];
+    for frame in frames {
+        socket.send(Message::Text(frame)).await?;
+    }
+
+    // Every frame is answered, in the order the requests complete
+    let mut responses = Vec::new();
+    while responses.len() < 3 {
+        match socket.next().await.expect("the connection was closed")? {
+            Message::Text(text) => responses.push(serde_json::from_str::<WsEmbedResponse>(&text)?),
+            Message::Ping(_) | Message::Pong(_) => continue,
+            message => panic!("unexpected message: {message:?}"),
+        }
+    }
+    socket.close(None).await?;
+    responses.sort_by_key(|response| response.id);
+
+    // Invalid frames are answered with an error without an id
+    assert_eq!(responses[0].id, None);
+    let error = responses[0].error.as_ref().expect("invalid frames are answered");
+    assert!(error.error.starts_with("Invalid request"));
+
+    let snapshot: Vec<SnapshotResponse> = responses[1..]
+        .iter()
+        .map(|response| SnapshotResponse {
+            id: response.id,
+            errors: response
+                .results
+                .iter()
+                .map(|result| result.error.as_ref().map(|error| error.error.clone()))
+                .collect(),
+            error: response.error.as_ref().map(|error| error.error.clone()),
+        })
+        .collect();
+    let matcher = YamlMatcher::<Vec<SnapshotResponse>>::new();
+    insta::assert_yaml_snapshot!("responses", snapshot, &matcher);
+
+    // The embeddings are the embeddings of `/embed`
+    let client = reqwest::Client::new();
+    let res = client
+        .post("http://0.0.0.0:8090/embed")
+        .json(&json!({ "inputs": "test" }))
+        .send()
+        .await?;
+    let embeddings = res.json::<Vec<Vec<Score>>>().await?;
+    assert_eq!(responses[1].results[0].embedding.as_ref(), Some(&embeddings[0]));
+    assert_eq!(responses[2].results[0].embedding.as_ref(), Some(&embeddings[0]));
+
+    // Every frame counts as a request, the `/embed` request above included
+    let usage = client
+        .get("http://0.0.0.0:8090/admin/usage")
+        .bearer_auth("admin")
+        .send()
+        .await?
+        .json::<Usage>()
+        .await?;
+    let anonymous = usage
+        .tenants
+        .iter()
+        .find(|tenant| tenant.tenant == "anonymous")
+        .expect("the frames were not counted");
+    assert_eq!(anonymous.requests, 3);
+
+    Ok(())
+}
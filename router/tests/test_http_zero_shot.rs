@@ -0,0 +1,92 @@
+mod common;
+
+use crate::common::{start_server, Score};
+use anyhow::Result;
+use insta::internals::YamlMatcher;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use text_embeddings_backend::DType;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct SnapshotPrediction {
+    score: Score,
+    label: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Prediction {
+    score: f32,
+    label: String,
+}
+
+#[tokio::test]
+#[cfg(feature = "http")]
+async fn test_zero_shot() -> Result<()> {
+    start_server(
+        "cross-encoder/nli-distilroberta-base".to_string(),
+        None,
+        DType::Float32,
+    )
+    .await?;
+
+    let client = reqwest::Client::new();
+
+    // Identical labels share the probability mass
+    let request = json!({
+        "inputs": "I have a problem with my iphone that needs to be resolved asap!",
+        "candidate_labels": ["phone", "phone"],
+    });
+    let res = client
+        .post("http://0.0.0.0:8090/zero-shot")
+        .json(&request)
+        .send()
+        .await?;
+    let predictions = res.json::<Vec<SnapshotPrediction>>().await?;
+    let matcher = YamlMatcher::<Vec<SnapshotPrediction>>::new();
+    insta::assert_yaml_snapshot!("predictions_identical", predictions, &matcher);
+
+    // Scores are normalized over the labels and sorted by decreasing score
+    let request = json!({
+        "inputs": "I have a problem with my iphone that needs to be resolved asap!",
+        "candidate_labels": ["urgent", "not urgent", "phone", "tablet"],
+    });
+    let res = client
+        .post("http://0.0.0.0:8090/zero-shot")
+        .json(&request)
+        .send()
+        .await?;
+    let predictions = res.json::<Vec<Prediction>>().await?;
+    assert_eq!(predictions.len(), 4);
+    let sum: f32 = predictions.iter().map(|prediction| prediction.score).sum();
+    assert!((sum - 1.0).abs() < 1e-4);
+    assert!(predictions
+        .windows(2)
+        .all(|pair| pair[0].score >= pair[1].score));
+
+    // Labels are scored independently with `multi_label`
+    let request = json!({
+        "inputs": "I have a problem with my iphone that needs to be resolved asap!",
+        "candidate_labels": ["urgent", "phone"],
+        "multi_label": true,
+    });
+    let res = client
+        .post("http://0.0.0.0:8090/zero-shot")
+        .json(&request)
+        .send()
+        .await?;
+    let predictions = res.json::<Vec<Prediction>>().await?;
+    for prediction in &predictions {
+        let res = client
+            .post("http://0.0.0.0:8090/zero-shot")
+            .json(&json!({
+                "inputs": "I have a problem with my iphone that needs to be resolved asap!",
+                "candidate_labels": [prediction.label],
+            }))
+            .send()
+            .await?;
+        let single = res.json::<Vec<Prediction>>().await?;
+        assert!((single[0].score - prediction.score).abs() < 1e-4);
+    }
+
+    Ok(())
+}